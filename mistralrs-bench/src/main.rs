@@ -58,6 +58,7 @@ async fn run_bench(
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        typical_p: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
@@ -67,6 +68,16 @@ async fn run_bench(
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        xtc_params: None,
+        banned_strings: None,
+        no_repeat_ngram_size: None,
+        token_healing: false,
+        guidance_scale: None,
+        negative_prompt: None,
+        penalty_alpha: None,
+        seed: None,
+        stop_regexes: None,
+        include_stop_str_in_output: false,
     };
     let sender = mistralrs.get_sender(None).unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -85,7 +96,10 @@ async fn run_bench(
         logits_processors: None,
         return_raw_logits: false,
         web_search_options: None,
+        adapters: None,
         model_id: None,
+        tenant: None,
+        deadline: None,
     }));
 
     let mut usages = Vec::new();
@@ -248,7 +262,10 @@ async fn warmup_run(mistralrs: Arc<MistralRs>) {
         logits_processors: None,
         return_raw_logits: false,
         web_search_options: None,
+        adapters: None,
         model_id: None,
+        tenant: None,
+        deadline: None,
     }));
 
     if sender.send(req.clone()).await.is_err() {
@@ -301,26 +318,26 @@ struct Args {
     in_situ_quant: Option<String>,
 
     /// GPU memory to allocate for KV cache with PagedAttention in MBs.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     #[arg(long = "pa-gpu-mem")]
     paged_attn_gpu_mem: Option<usize>,
 
     /// Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
     /// If this is not set and the device is CUDA, it will default to `0.9`.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     #[arg(long = "pa-gpu-mem-usage")]
     paged_attn_gpu_mem_usage: Option<f32>,
 
     /// Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold).
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     /// This is the default setting, and it defaults to the `max-seq-len` specified in after the model type.
     #[arg(long = "pa-ctxt-len")]
     paged_ctxt_len: Option<usize>,
 
-    /// PagedAttention KV cache type (auto or f8e4m3).
+    /// PagedAttention KV cache type (auto, f8e4m3 or int8).
     /// Defaults to `auto`.
     #[arg(long = "pa-cache-type", value_parser = parse_cache_type)]
     cache_type: Option<PagedCacheType>,
@@ -330,11 +347,11 @@ struct Args {
     #[arg(long = "pa-blk-size")]
     paged_attn_block_size: Option<usize>,
 
-    /// Disable PagedAttention on CUDA. Because PagedAttention is already disabled on Metal, this is only applicable on CUDA.
+    /// Disable PagedAttention on CUDA and Metal.
     #[arg(long = "no-paged-attn", default_value_t = false)]
     no_paged_attn: bool,
 
-    /// Enable PagedAttention on Metal. Because PagedAttention is already enabled on CUDA, this is only applicable on Metal.
+    /// Enable PagedAttention. Because PagedAttention is already enabled on CUDA and Metal, this only overrides `--no-paged-attn`.
     #[arg(long = "paged-attn", default_value_t = false)]
     paged_attn: bool,
 }
@@ -414,13 +431,12 @@ async fn main() -> anyhow::Result<()> {
         DeviceMapSetting::Auto(auto_device_map_params)
     };
 
-    let no_paged_attn = if device.is_cuda() || mistralrs_core::distributed::use_nccl() {
-        args.no_paged_attn
-    } else if device.is_metal() {
-        !args.paged_attn
-    } else {
-        true
-    };
+    let no_paged_attn =
+        if device.is_cuda() || device.is_metal() || mistralrs_core::distributed::use_nccl() {
+            args.no_paged_attn && !args.paged_attn
+        } else {
+            true
+        };
 
     let cache_config = match (
         args.paged_attn_block_size,
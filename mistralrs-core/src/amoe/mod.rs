@@ -95,6 +95,14 @@ pub trait AnyMoeBaseModelMixin {
 pub trait MlpLayer: Send + Sync + AnyMoeTrainableLayer {
     fn forward(&self, xs: &Tensor) -> Result<Tensor>;
     fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>>;
+    /// The layers of [`MlpLayer::get_isq_layers`] with the module name of each relative to the
+    /// MLP (e.g. `gate_proj`), or `None` for layers which are not a module of the checkpoint.
+    fn get_named_isq_layers(&mut self) -> Vec<(&mut Arc<dyn QuantMethod>, Option<&'static str>)> {
+        self.get_isq_layers()
+            .into_iter()
+            .map(|layer| (layer, None))
+            .collect()
+    }
     fn clone(&self) -> Box<dyn MlpLayer>;
     /// WARNING: The deltas are not a struct but are instead assumed to
     /// be correctly ordered! for that model and it's implementation details
@@ -0,0 +1,118 @@
+//! Strings which may not appear in the output of a request.
+
+use candle_core::{Device, Result, Tensor};
+use llguidance::toktrie::TokEnv;
+
+use crate::sampler::CustomLogitsProcessor;
+
+struct BannedString {
+    bytes: Vec<u8>,
+    /// `completing[k]` holds the tokens which complete the string when the text before them ends
+    /// with its first `k` bytes. `completing[0]` holds the tokens which contain the whole string.
+    completing: Vec<Vec<u32>>,
+}
+
+/// Bans the tokens which would complete one of the banned strings. This works on the bytes of the
+/// tokens rather than on an encoding of each string, so a string is banned however it would be
+/// tokenized. Strings are matched exactly, including case and whitespace.
+pub(crate) struct BannedStrings {
+    strings: Vec<BannedString>,
+    tok_env: TokEnv,
+}
+
+impl BannedStrings {
+    pub(crate) fn new(strings: &[String], tok_env: TokEnv) -> Self {
+        let tok_trie = tok_env.tok_trie();
+        let tokens = (0..tok_trie.vocab_size() as u32)
+            .map(|tok| tok_trie.token(tok))
+            .collect::<Vec<_>>();
+        let strings = Self::build(strings, &tokens);
+        Self { strings, tok_env }
+    }
+
+    fn build(strings: &[String], tokens: &[&[u8]]) -> Vec<BannedString> {
+        strings
+            .iter()
+            .filter(|string| !string.is_empty())
+            .map(|string| {
+                let bytes = string.as_bytes().to_vec();
+                let completing = (0..bytes.len())
+                    .map(|k| {
+                        let rest = &bytes[k..];
+                        tokens
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, token)| {
+                                if k == 0 {
+                                    token.windows(rest.len()).any(|window| window == rest)
+                                } else {
+                                    token.starts_with(rest)
+                                }
+                            })
+                            .map(|(tok, _)| tok as u32)
+                            .collect()
+                    })
+                    .collect();
+                BannedString { bytes, completing }
+            })
+            .collect()
+    }
+
+    /// The tokens which would complete a banned string after `text`.
+    fn banned_tokens<'a>(
+        strings: &'a [BannedString],
+        text: &'a [u8],
+    ) -> impl Iterator<Item = u32> + 'a {
+        strings.iter().flat_map(move |string| {
+            (0..string.bytes.len())
+                .filter(move |k| *k == 0 || text.ends_with(&string.bytes[..*k]))
+                .flat_map(move |k| string.completing[k].iter().copied())
+        })
+    }
+}
+
+impl CustomLogitsProcessor for BannedStrings {
+    fn apply(&self, logits: &Tensor, context: &[u32]) -> Result<Tensor> {
+        // Each token has at least one byte, so this is enough text to end with any prefix
+        let max_len = self
+            .strings
+            .iter()
+            .map(|s| s.bytes.len())
+            .max()
+            .unwrap_or(0);
+        let text = self
+            .tok_env
+            .tok_trie()
+            .decode(&context[context.len().saturating_sub(max_len)..]);
+
+        let mut logits_vec = logits.to_vec1::<f32>()?;
+        for tok in Self::banned_tokens(&self.strings, &text) {
+            if let Some(logit) = logits_vec.get_mut(tok as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        Tensor::from_vec(logits_vec, logits.shape(), &Device::Cpu)?.to_device(logits.device())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BannedStrings;
+    use std::collections::HashSet;
+
+    #[test]
+    fn bans_every_tokenization() {
+        let tokens: [&[u8]; 6] = [b"he", b"hel", b"lo", b"o", b" hello", b"l"];
+        let strings = BannedStrings::build(&["hello".to_string()], &tokens);
+        let banned =
+            |text: &[u8]| BannedStrings::banned_tokens(&strings, text).collect::<HashSet<_>>();
+
+        // A token containing the string is always banned
+        assert_eq!(banned(b"say "), HashSet::from([4]));
+        // As are the ones which would complete it
+        assert_eq!(banned(b"hel"), HashSet::from([2, 4]));
+        assert_eq!(banned(b"hell"), HashSet::from([3, 4]));
+        // But not the ones which would only continue it
+        assert_eq!(banned(b"he"), HashSet::from([4]));
+    }
+}
@@ -0,0 +1,184 @@
+//! Contrastive search: the next token is the one of the `top_k` most likely tokens with the best
+//! `(1 - penalty_alpha) * probability - penalty_alpha * similarity`, where the similarity is the
+//! largest cosine similarity of the last hidden state after the token to those of the previous
+//! tokens. This avoids the repetition of greedy decoding without sampling.
+
+use candle_core::{DType, IndexOp, Result, Tensor, D};
+
+use crate::{
+    pipeline::{InputProcessorOutput, Pipeline},
+    sequence::Sequence,
+};
+
+/// A copy of a sequence which is run to compute the hidden states of its tokens and of the
+/// candidates for the next token.
+pub(crate) struct ContrastiveSearch {
+    pub(crate) seq: Box<Sequence>,
+    penalty_alpha: f32,
+    top_k: usize,
+    /// The hidden states of the tokens of `seq` which were run, `(seq_len, hidden_size)`.
+    context: Option<Tensor>,
+}
+
+impl ContrastiveSearch {
+    pub(crate) fn new(seq: Sequence, penalty_alpha: f32, top_k: usize) -> Self {
+        Self {
+            seq: Box::new(seq),
+            penalty_alpha,
+            top_k,
+            context: None,
+        }
+    }
+
+    /// Run the model on the prompt of `seq`, or on its last token after its cache, and return the
+    /// hidden states of the tokens which were run. The cache of `seq` is only updated if
+    /// `keep_cache` is set.
+    fn run<P: Pipeline + ?Sized>(
+        pipeline: &mut P,
+        seq: &mut Sequence,
+        is_prompt: bool,
+        keep_cache: bool,
+    ) -> Result<Tensor> {
+        let seqs = &mut [seq];
+        mistralrs_quant::set_lora_batch(vec![seqs[0].adapters().map(|a| a.to_vec())]);
+        let inputs = pipeline.get_processor().inputs_processor().process_inputs(
+            pipeline.tokenizer(),
+            seqs,
+            is_prompt,
+            pipeline.get_metadata().is_xlora,
+            &pipeline.device(),
+            pipeline.get_metadata().no_kv_cache,
+            None,
+            false,
+            pipeline.get_input_processor_config(),
+            None,
+            pipeline.device_mapper(),
+        );
+        let InputProcessorOutput { inputs, .. } = inputs.map_err(candle_core::Error::msg)?;
+
+        if is_prompt {
+            pipeline.set_none_cache(seqs, false, false, false);
+        } else {
+            pipeline.clone_in_cache(seqs);
+        }
+        let hidden_states = pipeline.forward_inputs_hidden_states(inputs)?;
+        if keep_cache {
+            pipeline.clone_out_cache(seqs);
+        }
+        hidden_states.i(0)
+    }
+
+    /// Mask `logits` to the candidate with the best contrastive score, after running the tokens
+    /// which were generated since the last step. This uses the model cache, so the caches of the
+    /// sequences of the batch must have been cloned out of it.
+    pub(crate) fn apply<P: Pipeline + ?Sized>(
+        &mut self,
+        pipeline: &mut P,
+        logits: &Tensor,
+    ) -> Result<Tensor> {
+        let hidden_states = Self::run(pipeline, &mut self.seq, self.context.is_none(), true)?;
+        let context = match self.context.take() {
+            Some(context) => Tensor::cat(&[context, hidden_states], 0)?,
+            None => hidden_states,
+        };
+
+        let probs = candle_nn::ops::softmax_last_dim(&logits.to_dtype(DType::F32)?)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let candidates = top_k(&probs, self.top_k);
+        let mut candidate_states = Vec::with_capacity(candidates.len());
+        for &token in &candidates {
+            self.seq.add_tmp_tok(token);
+            let hidden_states = Self::run(pipeline, &mut self.seq, false, false);
+            self.seq.remove_tmp_tok(1);
+            candidate_states.push(hidden_states?.i(0)?);
+        }
+        let similarity = max_cosine_similarity(&Tensor::stack(&candidate_states, 0)?, &context)?;
+        self.context = Some(context);
+
+        let best = best_candidate(
+            &candidates
+                .iter()
+                .map(|&token| probs[token as usize])
+                .collect::<Vec<_>>(),
+            &similarity,
+            self.penalty_alpha,
+        );
+        keep_only(logits, candidates[best])
+    }
+}
+
+/// The `k` most likely tokens, the most likely first.
+fn top_k(probs: &[f32], k: usize) -> Vec<u32> {
+    let mut tokens = (0..probs.len() as u32).collect::<Vec<_>>();
+    tokens.sort_by(|a, b| probs[*b as usize].total_cmp(&probs[*a as usize]));
+    tokens.truncate(k.max(1));
+    tokens
+}
+
+/// For each row of `candidates`, its largest cosine similarity to the rows of `context`.
+fn max_cosine_similarity(candidates: &Tensor, context: &Tensor) -> Result<Vec<f32>> {
+    let normalize = |xs: &Tensor| -> Result<Tensor> {
+        let xs = xs.to_dtype(DType::F32)?;
+        let norm = xs
+            .sqr()?
+            .sum_keepdim(D::Minus1)?
+            .sqrt()?
+            .clamp(1e-8f32, f32::MAX)?;
+        xs.broadcast_div(&norm)
+    };
+    normalize(candidates)?
+        .matmul(&normalize(context)?.t()?)?
+        .max(D::Minus1)?
+        .to_vec1()
+}
+
+/// The index of the candidate with the best contrastive score.
+fn best_candidate(probs: &[f32], similarity: &[f32], penalty_alpha: f32) -> usize {
+    probs
+        .iter()
+        .zip(similarity)
+        .map(|(p, s)| (1. - penalty_alpha) * p - penalty_alpha * s)
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+/// `logits` with every token but `token` masked out.
+fn keep_only(logits: &Tensor, token: u32) -> Result<Tensor> {
+    let vocab_size = logits.dim(D::Minus1)?;
+    let mut mask = vec![f32::NEG_INFINITY; vocab_size];
+    mask[token as usize] = 0.;
+    let mask = Tensor::from_vec(mask, vocab_size, logits.device())?.to_dtype(logits.dtype())?;
+    logits.broadcast_add(&mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{best_candidate, keep_only, max_cosine_similarity, top_k};
+
+    #[test]
+    fn penalizes_candidates_similar_to_the_context() {
+        assert_eq!(top_k(&[0.1, 0.5, 0.4], 2), [1, 2]);
+
+        let context = Tensor::new(&[[1f32, 0.], [0., 2.]], &Device::Cpu).unwrap();
+        let candidates = Tensor::new(&[[3f32, 0.], [0., -1.]], &Device::Cpu).unwrap();
+        let similarity = max_cosine_similarity(&candidates, &context).unwrap();
+        assert!((similarity[0] - 1.).abs() < 1e-5);
+        assert!(similarity[1].abs() < 1e-5);
+
+        // Without a penalty the most likely candidate wins, with one the dissimilar candidate does
+        assert_eq!(best_candidate(&[0.5, 0.4], &similarity, 0.), 0);
+        assert_eq!(best_candidate(&[0.5, 0.4], &similarity, 0.6), 1);
+    }
+
+    #[test]
+    fn keeps_only_the_chosen_token() {
+        let logits = Tensor::new(&[[1f32, 2., 3.]], &Device::Cpu).unwrap();
+        let masked = keep_only(&logits, 1).unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(masked, [[f32::NEG_INFINITY, 2., f32::NEG_INFINITY]]);
+    }
+}
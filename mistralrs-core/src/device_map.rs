@@ -44,6 +44,27 @@ impl DeviceMapMetadata {
             host_layers: None,
         }
     }
+    /// Place the given number of repeating layers on each device, in order. The CPU layers are
+    /// always placed after the GPU layers, and any layers which are not given to a device are
+    /// placed on the CPU.
+    pub fn from_device_layers(device_layers: impl IntoIterator<Item = (Device, usize)>) -> Self {
+        let mut gpu_layers = Vec::new();
+        let mut host_layers = None;
+        for (device, layers) in device_layers {
+            match device.location() {
+                DeviceLocation::Cpu => *host_layers.get_or_insert(0) += layers,
+                DeviceLocation::Cuda { gpu_id } | DeviceLocation::Metal { gpu_id } => gpu_layers
+                    .push(DeviceLayerMapMetadata {
+                        ordinal: gpu_id,
+                        layers,
+                    }),
+            }
+        }
+        Self {
+            device_layers: Some(gpu_layers),
+            host_layers,
+        }
+    }
     /// A device mapper to not map device.
     pub fn dummy() -> Self {
         Self {
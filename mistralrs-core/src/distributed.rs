@@ -55,6 +55,8 @@ pub fn nccl_daemon_replicator(request_sender: Sender<Request>) {
 
                     req = match req {
                         Request::ReIsq(x) => Request::ReIsq(x),
+                        Request::Cancel(id) => Request::Cancel(id),
+                        Request::Drain => Request::Drain,
                         Request::Terminate => Request::Terminate,
                         Request::Detokenize(mut x) => {
                             let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
@@ -76,6 +78,40 @@ pub fn nccl_daemon_replicator(request_sender: Sender<Request>) {
                             resp.unwrap();
                             continue;
                         }
+                        Request::LoraAdapter(mut x) => {
+                            let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+                            x.response = sender;
+                            let req = Request::LoraAdapter(x);
+
+                            request_sender.send(req).await.unwrap();
+                            // Any error is also reported by the master rank.
+                            let _ = receiver.recv().await.unwrap();
+                            continue;
+                        }
+                        Request::Requantize(mut x) => {
+                            let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+                            x.response = sender;
+                            let req = Request::Requantize(x);
+
+                            request_sender.send(req).await.unwrap();
+                            // Any error is also reported by the master rank.
+                            let _ = receiver.recv().await.unwrap();
+                            continue;
+                        }
+                        // Only the master rank writes the exported model.
+                        Request::Export(_) => continue,
+                        // Collecting an imatrix is not supported with tensor parallelism.
+                        Request::CalibratedIsq(_) => continue,
+                        // Embedding, reranker and speech models are not loaded with tensor
+                        // parallelism.
+                        Request::Embedding(_)
+                        | Request::Rerank(_)
+                        | Request::Transcription(_)
+                        | Request::Synthesis(_) => continue,
+                        // Only the master rank reports its stats.
+                        Request::Stats(_) => continue,
+                        // Sessions are not supported with tensor parallelism.
+                        Request::SnapshotSession(_) | Request::ResumeSession(_) => continue,
                         Request::Normal(mut x) => {
                             let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
                             x.is_streaming = false;
@@ -117,6 +153,8 @@ pub fn ring_daemon_replicator(request_sender: Sender<Request>) {
 
                     req = match req {
                         Request::ReIsq(x) => Request::ReIsq(x),
+                        Request::Cancel(id) => Request::Cancel(id),
+                        Request::Drain => Request::Drain,
                         Request::Terminate => Request::Terminate,
                         Request::Detokenize(mut x) => {
                             let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
@@ -138,6 +176,40 @@ pub fn ring_daemon_replicator(request_sender: Sender<Request>) {
                             resp.unwrap();
                             continue;
                         }
+                        Request::LoraAdapter(mut x) => {
+                            let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+                            x.response = sender;
+                            let req = Request::LoraAdapter(x);
+
+                            request_sender.send(req).await.unwrap();
+                            // Any error is also reported by the master rank.
+                            let _ = receiver.recv().await.unwrap();
+                            continue;
+                        }
+                        Request::Requantize(mut x) => {
+                            let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+                            x.response = sender;
+                            let req = Request::Requantize(x);
+
+                            request_sender.send(req).await.unwrap();
+                            // Any error is also reported by the master rank.
+                            let _ = receiver.recv().await.unwrap();
+                            continue;
+                        }
+                        // Only the master rank writes the exported model.
+                        Request::Export(_) => continue,
+                        // Collecting an imatrix is not supported with tensor parallelism.
+                        Request::CalibratedIsq(_) => continue,
+                        // Embedding, reranker and speech models are not loaded with tensor
+                        // parallelism.
+                        Request::Embedding(_)
+                        | Request::Rerank(_)
+                        | Request::Transcription(_)
+                        | Request::Synthesis(_) => continue,
+                        // Only the master rank reports its stats.
+                        Request::Stats(_) => continue,
+                        // Sessions are not supported with tensor parallelism.
+                        Request::SnapshotSession(_) | Request::ResumeSession(_) => continue,
                         Request::Normal(mut x) => {
                             let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
                             x.is_streaming = false;
@@ -20,13 +20,13 @@ enum PositionEmbeddingType {
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Config {
     vocab_size: usize,
-    hidden_size: usize,
+    pub(crate) hidden_size: usize,
     num_hidden_layers: usize,
     num_attention_heads: usize,
     intermediate_size: usize,
     pub hidden_act: Activation,
     hidden_dropout_prob: f64,
-    max_position_embeddings: usize,
+    pub(crate) max_position_embeddings: usize,
     type_vocab_size: usize,
     initializer_range: f64,
     layer_norm_eps: f64,
@@ -36,7 +36,23 @@ pub struct Config {
     #[serde(default)]
     use_cache: bool,
     classifier_dropout: Option<f64>,
-    model_type: Option<String>,
+    pub(crate) model_type: Option<String>,
+}
+
+impl Config {
+    /// RoBERTa models number positions after the padding index.
+    fn position_offset(&self) -> usize {
+        match self.model_type.as_deref() {
+            Some("roberta" | "xlm-roberta") => self.pad_token_id + 1,
+            _ => 0,
+        }
+    }
+
+    /// The longest input which has a position embedding for each of its tokens.
+    pub(crate) fn max_seq_len(&self) -> usize {
+        self.max_position_embeddings
+            .saturating_sub(self.position_offset())
+    }
 }
 
 // https://github.com/huggingface/transformers/blob/6eedfa6dd15dc1e22a55ae036f681914e5a0d9a1/src/transformers/models/bert/modeling_bert.py#L180
@@ -45,6 +61,8 @@ struct BertEmbeddings {
     position_embeddings: Option<Embedding>,
     token_type_embeddings: Embedding,
     layer_norm: LayerNorm,
+    /// RoBERTa models number positions after the padding index.
+    position_offset: u32,
     span: tracing::Span,
 }
 
@@ -75,6 +93,7 @@ impl BertEmbeddings {
             position_embeddings: Some(position_embeddings),
             token_type_embeddings,
             layer_norm,
+            position_offset: config.position_offset() as u32,
             span: tracing::span!(tracing::Level::TRACE, "embeddings"),
         })
     }
@@ -87,7 +106,9 @@ impl BertEmbeddings {
         let mut embeddings = (&input_embeddings + token_type_embeddings)?;
         if let Some(position_embeddings) = &self.position_embeddings {
             // TODO: Proper absolute positions?
-            let position_ids = (0..seq_len as u32).collect::<Vec<_>>();
+            let position_ids = (0..seq_len as u32)
+                .map(|i| i + self.position_offset)
+                .collect::<Vec<_>>();
             let position_ids = Tensor::new(&position_ids[..], input_ids.device())?;
             embeddings = embeddings.broadcast_add(&position_embeddings.forward(&position_ids)?)?
         }
@@ -369,13 +390,10 @@ impl BertModel {
             2 => attention_mask.unsqueeze(1)?.unsqueeze(1)?,
             _ => candle_core::bail!("Wrong shape for input_ids or attention_mask"),
         };
-        let attention_mask = attention_mask.to_dtype(dtype)?;
+        // Computed in F32, as `0 * -inf` would be NaN in half precision.
+        let attention_mask = attention_mask.to_dtype(DType::F32)?;
         // torch.finfo(dtype).min
-        (attention_mask.ones_like()? - &attention_mask)?.broadcast_mul(
-            &Tensor::try_from(f32::MIN)?
-                .to_dtype(dtype)?
-                .to_device(attention_mask.device())?,
-        )
+        ((attention_mask.ones_like()? - &attention_mask)? * f32::MIN as f64)?.to_dtype(dtype)
     }
 
     pub fn forward(
@@ -438,3 +456,54 @@ impl BertPipeline {
         Ok(Self { model, tokenizer })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::{BertModel, Config};
+
+    fn config(model_type: &str) -> Config {
+        serde_json::from_str(&format!(
+            r#"{{
+                "model_type": "{model_type}",
+                "vocab_size": 8,
+                "hidden_size": 4,
+                "num_hidden_layers": 1,
+                "num_attention_heads": 1,
+                "intermediate_size": 8,
+                "hidden_act": "gelu",
+                "hidden_dropout_prob": 0.0,
+                "max_position_embeddings": 10,
+                "type_vocab_size": 1,
+                "initializer_range": 0.02,
+                "layer_norm_eps": 1e-5,
+                "pad_token_id": 1
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn roberta_max_seq_len_excludes_the_position_offset() -> anyhow::Result<()> {
+        assert_eq!(config("bert").max_seq_len(), 10);
+        // Positions start after the padding index 1
+        let config = config("xlm-roberta");
+        assert_eq!(config.max_seq_len(), 8);
+
+        let dev = Device::Cpu;
+        let model = BertModel::load(VarBuilder::zeros(DType::F32, &dev), &config)?;
+        let forward = |seq_len: usize| -> candle_core::Result<Tensor> {
+            let input_ids = Tensor::zeros((1, seq_len), DType::U32, &dev)?;
+            model.forward(
+                &input_ids,
+                &input_ids.zeros_like()?,
+                Some(&input_ids.ones_like()?),
+            )
+        };
+        assert!(forward(config.max_seq_len()).is_ok());
+        assert!(forward(config.max_seq_len() + 1).is_err());
+        Ok(())
+    }
+}
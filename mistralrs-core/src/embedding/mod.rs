@@ -1 +1,107 @@
 pub mod bert;
+pub mod nomic_bert;
+mod reranker;
+
+use candle_core::{DType, IndexOp, Result, Tensor};
+use candle_nn::VarBuilder;
+use serde::{Deserialize, Serialize};
+
+use bert::BertModel;
+use nomic_bert::NomicBertModel;
+pub(crate) use reranker::RerankerModel;
+
+/// How the token states of an embedding model are reduced to a single vector per input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingPooling {
+    /// The state of the first (`[CLS]`) token, as used by BGE.
+    Cls,
+    /// The mean of the states of all non-padding tokens, as used by GTE, E5 and Nomic.
+    #[default]
+    Mean,
+}
+
+/// The pooling settings saved by sentence-transformers in `1_Pooling/config.json`.
+#[derive(Deserialize)]
+pub(crate) struct SentenceTransformersPoolingConfig {
+    #[serde(default)]
+    pooling_mode_cls_token: bool,
+    #[serde(default)]
+    pooling_mode_mean_tokens: bool,
+}
+
+impl SentenceTransformersPoolingConfig {
+    pub(crate) fn pooling(&self) -> Option<EmbeddingPooling> {
+        match (self.pooling_mode_cls_token, self.pooling_mode_mean_tokens) {
+            (true, false) => Some(EmbeddingPooling::Cls),
+            (false, true) => Some(EmbeddingPooling::Mean),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ModelType {
+    model_type: Option<String>,
+}
+
+pub(crate) enum EmbeddingModel {
+    Bert(BertModel),
+    NomicBert(NomicBertModel),
+}
+
+impl EmbeddingModel {
+    /// Load the model from `config`, returning it with the maximum sequence length.
+    pub(crate) fn load(vb: VarBuilder, config: &str) -> anyhow::Result<(Self, usize)> {
+        let ModelType { model_type } = serde_json::from_str(config)?;
+        match model_type.as_deref() {
+            Some("bert" | "roberta" | "xlm-roberta") | None => {
+                let config: bert::Config = serde_json::from_str(config)?;
+                Ok((
+                    Self::Bert(BertModel::load(vb, &config)?),
+                    config.max_seq_len(),
+                ))
+            }
+            Some("nomic_bert") => {
+                let config: nomic_bert::Config = serde_json::from_str(config)?;
+                Ok((
+                    Self::NomicBert(NomicBertModel::load(vb, &config)?),
+                    config.n_positions,
+                ))
+            }
+            Some(other) => anyhow::bail!("Unsupported embedding model type `{other}`."),
+        }
+    }
+
+    /// Compute the pooled embeddings of the (padded) `input_ids` of shape (b, t), as F32.
+    pub(crate) fn embed(
+        &self,
+        input_ids: &Tensor,
+        attention_mask: &Tensor,
+        pooling: EmbeddingPooling,
+        normalize: bool,
+    ) -> Result<Tensor> {
+        let token_type_ids = input_ids.zeros_like()?;
+        let hidden_states = match self {
+            Self::Bert(model) => model.forward(input_ids, &token_type_ids, Some(attention_mask))?,
+            Self::NomicBert(model) => model.forward(input_ids, &token_type_ids, attention_mask)?,
+        }
+        .to_dtype(DType::F32)?;
+
+        let pooled = match pooling {
+            EmbeddingPooling::Cls => hidden_states.i((.., 0))?,
+            EmbeddingPooling::Mean => {
+                let mask = attention_mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+                hidden_states
+                    .broadcast_mul(&mask)?
+                    .sum(1)?
+                    .broadcast_div(&mask.sum(1)?)?
+            }
+        };
+        if normalize {
+            pooled.broadcast_div(&pooled.sqr()?.sum_keepdim(1)?.sqrt()?)
+        } else {
+            Ok(pooled)
+        }
+    }
+}
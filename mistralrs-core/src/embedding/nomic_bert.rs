@@ -0,0 +1,275 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use candle_core::{DType, Result, Tensor, D};
+use candle_nn::{embedding, layer_norm, Embedding, LayerNorm, Linear, Module, VarBuilder};
+use serde::Deserialize;
+
+// https://huggingface.co/nomic-ai/nomic-bert-2048/blob/main/configuration_hf_nomic_bert.py
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Config {
+    vocab_size: usize,
+    n_embd: usize,
+    n_head: usize,
+    n_inner: usize,
+    n_layer: usize,
+    pub(crate) n_positions: usize,
+    type_vocab_size: usize,
+    layer_norm_epsilon: f64,
+    activation_function: String,
+    #[serde(default = "default_rotary_emb_base")]
+    rotary_emb_base: f64,
+    #[serde(default = "default_rotary_emb_fraction")]
+    rotary_emb_fraction: f64,
+    #[serde(default)]
+    rotary_emb_interleaved: bool,
+    #[serde(default)]
+    qkv_proj_bias: bool,
+    #[serde(default)]
+    mlp_fc1_bias: bool,
+    #[serde(default)]
+    mlp_fc2_bias: bool,
+    #[serde(default)]
+    prenorm: bool,
+}
+
+fn default_rotary_emb_base() -> f64 {
+    10000.
+}
+
+fn default_rotary_emb_fraction() -> f64 {
+    1.
+}
+
+fn linear_b(in_dim: usize, out_dim: usize, bias: bool, vb: VarBuilder) -> Result<Linear> {
+    if bias {
+        candle_nn::linear(in_dim, out_dim, vb)
+    } else {
+        candle_nn::linear_no_bias(in_dim, out_dim, vb)
+    }
+}
+
+struct RotaryEmbedding {
+    inv_freq: Vec<f32>,
+}
+
+impl RotaryEmbedding {
+    fn new(config: &Config, head_dim: usize) -> Self {
+        let rot_dim = (head_dim as f64 * config.rotary_emb_fraction) as usize;
+        let inv_freq = (0..rot_dim)
+            .step_by(2)
+            .map(|i| 1f32 / config.rotary_emb_base.powf(i as f64 / rot_dim as f64) as f32)
+            .collect();
+        Self { inv_freq }
+    }
+
+    fn rot_dim(&self) -> usize {
+        self.inv_freq.len() * 2
+    }
+
+    /// Rotate `xs` of shape (b, h, t, d).
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (_b, _h, seq_len, head_dim) = xs.dims4()?;
+        let inv_freq = Tensor::new(self.inv_freq.as_slice(), xs.device())?;
+        let positions = Tensor::arange(0u32, seq_len as u32, xs.device())?
+            .to_dtype(DType::F32)?
+            .reshape((seq_len, 1))?;
+        let freqs = positions.broadcast_mul(&inv_freq.reshape((1, self.inv_freq.len()))?)?;
+        let cos = freqs.cos()?.to_dtype(xs.dtype())?;
+        let sin = freqs.sin()?.to_dtype(xs.dtype())?;
+
+        let rot_dim = self.rot_dim();
+        let rot = candle_nn::rotary_emb::rope(
+            &xs.narrow(D::Minus1, 0, rot_dim)?.contiguous()?,
+            &cos,
+            &sin,
+        )?;
+        if rot_dim == head_dim {
+            Ok(rot)
+        } else {
+            Tensor::cat(
+                &[rot, xs.narrow(D::Minus1, rot_dim, head_dim - rot_dim)?],
+                D::Minus1,
+            )
+        }
+    }
+}
+
+struct NomicBertAttention {
+    wqkv: Linear,
+    out_proj: Linear,
+    rotary: RotaryEmbedding,
+    n_head: usize,
+    head_dim: usize,
+}
+
+impl NomicBertAttention {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let head_dim = config.n_embd / config.n_head;
+        Ok(Self {
+            wqkv: linear_b(
+                config.n_embd,
+                3 * config.n_embd,
+                config.qkv_proj_bias,
+                vb.pp("Wqkv"),
+            )?,
+            out_proj: linear_b(
+                config.n_embd,
+                config.n_embd,
+                config.qkv_proj_bias,
+                vb.pp("out_proj"),
+            )?,
+            rotary: RotaryEmbedding::new(config, head_dim),
+            n_head: config.n_head,
+            head_dim,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+        let qkv = self
+            .wqkv
+            .forward(xs)?
+            .reshape((b_sz, seq_len, 3, self.n_head, self.head_dim))?
+            .permute((2, 0, 3, 1, 4))?;
+        let q = self.rotary.forward(&qkv.get(0)?)?;
+        let k = self.rotary.forward(&qkv.get(1)?)?;
+        let v = qkv.get(2)?.contiguous()?;
+
+        let scores = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let probs = candle_nn::ops::softmax_last_dim(&scores.broadcast_add(attention_mask)?)?;
+        let out = probs.matmul(&v)?.transpose(1, 2)?.reshape((
+            b_sz,
+            seq_len,
+            self.n_head * self.head_dim,
+        ))?;
+        self.out_proj.forward(&out)
+    }
+}
+
+struct NomicBertGatedMlp {
+    fc11: Linear,
+    fc12: Linear,
+    fc2: Linear,
+}
+
+impl NomicBertGatedMlp {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            fc11: linear_b(
+                config.n_embd,
+                config.n_inner,
+                config.mlp_fc1_bias,
+                vb.pp("fc11"),
+            )?,
+            fc12: linear_b(
+                config.n_embd,
+                config.n_inner,
+                config.mlp_fc1_bias,
+                vb.pp("fc12"),
+            )?,
+            fc2: linear_b(
+                config.n_inner,
+                config.n_embd,
+                config.mlp_fc2_bias,
+                vb.pp("fc2"),
+            )?,
+        })
+    }
+}
+
+impl Module for NomicBertGatedMlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = (self.fc11.forward(xs)? * self.fc12.forward(xs)?.silu()?)?;
+        self.fc2.forward(&ys)
+    }
+}
+
+struct NomicBertBlock {
+    attn: NomicBertAttention,
+    mlp: NomicBertGatedMlp,
+    norm1: LayerNorm,
+    norm2: LayerNorm,
+}
+
+impl NomicBertBlock {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            attn: NomicBertAttention::load(vb.pp("attn"), config)?,
+            mlp: NomicBertGatedMlp::load(vb.pp("mlp"), config)?,
+            norm1: layer_norm(config.n_embd, config.layer_norm_epsilon, vb.pp("norm1"))?,
+            norm2: layer_norm(config.n_embd, config.layer_norm_epsilon, vb.pp("norm2"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let xs = self
+            .norm1
+            .forward(&(self.attn.forward(xs, attention_mask)? + xs)?)?;
+        self.norm2.forward(&(self.mlp.forward(&xs)? + xs)?)
+    }
+}
+
+// https://huggingface.co/nomic-ai/nomic-bert-2048/blob/main/modeling_hf_nomic_bert.py
+pub struct NomicBertModel {
+    word_embeddings: Embedding,
+    token_type_embeddings: Embedding,
+    emb_ln: LayerNorm,
+    layers: Vec<NomicBertBlock>,
+}
+
+impl NomicBertModel {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        if config.activation_function != "swiglu" {
+            candle_core::bail!(
+                "Unsupported Nomic BERT activation `{}`, only `swiglu` is supported.",
+                config.activation_function
+            );
+        }
+        if config.prenorm || config.rotary_emb_interleaved {
+            candle_core::bail!(
+                "Pre-norm and interleaved rotary Nomic BERT models are not supported."
+            );
+        }
+
+        let vb_emb = vb.pp("embeddings");
+        let layers = (0..config.n_layer)
+            .map(|i| NomicBertBlock::load(vb.pp(format!("encoder.layers.{i}")), config))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            word_embeddings: embedding(
+                config.vocab_size,
+                config.n_embd,
+                vb_emb.pp("word_embeddings"),
+            )?,
+            token_type_embeddings: embedding(
+                config.type_vocab_size,
+                config.n_embd,
+                vb_emb.pp("token_type_embeddings"),
+            )?,
+            emb_ln: layer_norm(config.n_embd, config.layer_norm_epsilon, vb.pp("emb_ln"))?,
+            layers,
+        })
+    }
+
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: &Tensor,
+    ) -> Result<Tensor> {
+        let xs = (self.word_embeddings.forward(input_ids)?
+            + self.token_type_embeddings.forward(token_type_ids)?)?;
+        let mut xs = self.emb_ln.forward(&xs)?;
+
+        // (b, t) -> (b, 1, 1, t), with 0 for attended tokens and the minimum value for padding.
+        let mask = attention_mask
+            .unsqueeze(1)?
+            .unsqueeze(1)?
+            .to_dtype(DType::F32)?;
+        let mask = ((mask.ones_like()? - &mask)? * f32::MIN as f64)?.to_dtype(xs.dtype())?;
+        for layer in &self.layers {
+            xs = layer.forward(&xs, &mask)?;
+        }
+        Ok(xs)
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, IndexOp, Result, Tensor};
+use candle_nn::{linear, Linear, Module, VarBuilder};
+use serde::Deserialize;
+
+use super::bert::{self, BertModel};
+
+#[derive(Deserialize)]
+struct ClassifierConfig {
+    #[serde(default)]
+    id2label: HashMap<String, String>,
+}
+
+enum ClassificationHead {
+    // https://github.com/huggingface/transformers/blob/main/src/transformers/models/bert/modeling_bert.py
+    Bert { pooler: Linear, classifier: Linear },
+    // https://github.com/huggingface/transformers/blob/main/src/transformers/models/roberta/modeling_roberta.py
+    Roberta { dense: Linear, out_proj: Linear },
+}
+
+impl ClassificationHead {
+    /// Map the `[CLS]` token states of shape (b, h) to logits of shape (b, 1).
+    fn forward(&self, cls: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Bert { pooler, classifier } => classifier.forward(&pooler.forward(cls)?.tanh()?),
+            Self::Roberta { dense, out_proj } => out_proj.forward(&dense.forward(cls)?.tanh()?),
+        }
+    }
+}
+
+/// A cross-encoder, which scores the relevance of a (query, document) pair with a single logit.
+pub(crate) struct RerankerModel {
+    model: BertModel,
+    head: ClassificationHead,
+}
+
+impl RerankerModel {
+    /// Load the model from `config`, returning it with the maximum sequence length.
+    pub(crate) fn load(vb: VarBuilder, config: &str) -> anyhow::Result<(Self, usize)> {
+        let ClassifierConfig { id2label } = serde_json::from_str(config)?;
+        if id2label.len() > 1 {
+            anyhow::bail!(
+                "Reranker models must have a single output label, this model has {}.",
+                id2label.len()
+            );
+        }
+        let config: bert::Config = serde_json::from_str(config)?;
+        let hidden_size = config.hidden_size;
+
+        let (model, head) = match config.model_type.as_deref() {
+            Some("bert") | None => (
+                BertModel::load(vb.pp("bert"), &config)?,
+                ClassificationHead::Bert {
+                    pooler: linear(hidden_size, hidden_size, vb.pp("bert.pooler.dense"))?,
+                    classifier: linear(hidden_size, 1, vb.pp("classifier"))?,
+                },
+            ),
+            Some("roberta" | "xlm-roberta") => (
+                BertModel::load(vb.pp("roberta"), &config)?,
+                ClassificationHead::Roberta {
+                    dense: linear(hidden_size, hidden_size, vb.pp("classifier.dense"))?,
+                    out_proj: linear(hidden_size, 1, vb.pp("classifier.out_proj"))?,
+                },
+            ),
+            Some(other) => anyhow::bail!("Unsupported reranker model type `{other}`."),
+        };
+        Ok((Self { model, head }, config.max_seq_len()))
+    }
+
+    /// Compute the relevance scores of the (padded) pairs in `input_ids` of shape (b, t), as F32
+    /// of shape (b,).
+    pub(crate) fn score(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: &Tensor,
+    ) -> Result<Tensor> {
+        let hidden_states = self
+            .model
+            .forward(input_ids, token_type_ids, Some(attention_mask))?;
+        self.head
+            .forward(&hidden_states.i((.., 0))?)?
+            .to_dtype(DType::F32)?
+            .squeeze(1)
+    }
+}
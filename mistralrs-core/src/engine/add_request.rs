@@ -1,13 +1,18 @@
 use crate::{
     pipeline::NormalCache,
     prefix_cacher::MatchingCache,
-    request::{DetokenizationRequest, NormalRequest, TokenizationRequest},
-    sequence::SeqStepType,
+    request::{
+        DetokenizationRequest, LoraAdapterAction, LoraAdapterRequest, NormalRequest,
+        SynthesisRequest, TokenizationRequest, TranscriptionRequest,
+    },
+    sequence::{AttentionSinkConfig, KvQuotaAction, SeqStepType},
     tools::{ToolCallingMatcher, ToolChoice},
-    ModelCategory, RequestMessage, Response,
+    Constraint, Draining, ModelCategory, ModelKind, QueueFull, RequestCanceled, RequestMessage,
+    Response,
 };
 use candle_core::Tensor;
 use either::Either;
+use indexmap::IndexMap;
 use std::{
     ops::Deref,
     sync::{atomic::Ordering, Arc},
@@ -16,14 +21,20 @@ use std::{
 use tracing::warn;
 
 use crate::{
-    get_mut_arcmutex, handle_seq_error,
+    banned_strings::BannedStrings,
+    contrastive::ContrastiveSearch,
+    get_mut_arcmutex,
+    guidance::Guidance,
+    handle_seq_error,
+    no_repeat_ngram::NoRepeatNgram,
     request::Request,
     sampler::Sampler,
-    sequence::{Sequence, SequenceGroup},
+    sequence::{Sequence, SequenceGroup, SequenceRecognizer},
+    token_healing::TokenHealing,
     StopTokens,
 };
 
-use super::{search_request, Engine, TERMINATE_ALL_NEXT_STEP};
+use super::{search_request, Engine, SEED, TERMINATE_ALL_NEXT_STEP};
 
 impl Engine {
     pub async fn handle_request(self: Arc<Self>, request: Request) {
@@ -46,8 +57,113 @@ impl Engine {
                     warn!("ISQ requantization failed: {e:?}");
                 }
             }
+            Request::Requantize(req) => {
+                let res = get_mut_arcmutex!(self.pipeline).requantize_model(req.isq_type);
+                req.response
+                    .send(res)
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            }
+            Request::CalibratedIsq(req) => {
+                let res = get_mut_arcmutex!(self.pipeline)
+                    .re_isq_model_calibrated(req.isq_type, &req.data);
+                req.response
+                    .send(res)
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            }
             Request::Tokenize(req) => self.tokenize_text(req).await,
             Request::Detokenize(req) => self.detokenize_text(req).await,
+            Request::LoraAdapter(req) => self.handle_lora_adapter_request(req).await,
+            Request::Export(req) => {
+                let res = get_mut_arcmutex!(self.pipeline).export_model(&req.path, req.format);
+                req.response
+                    .send(res)
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            }
+            Request::Embedding(req) => {
+                let res = get_mut_arcmutex!(self.pipeline).embed(&req.texts);
+                req.response
+                    .send(res)
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            }
+            Request::Rerank(req) => {
+                let res = get_mut_arcmutex!(self.pipeline).rerank(&req.query, &req.documents);
+                req.response
+                    .send(res)
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            }
+            Request::Transcription(TranscriptionRequest {
+                samples,
+                sample_rate,
+                language,
+                response,
+            }) => {
+                let res = tokio::task::block_in_place(|| {
+                    get_mut_arcmutex!(self.pipeline).transcribe(
+                        &samples,
+                        sample_rate,
+                        language.as_deref(),
+                        &mut |segment| {
+                            response
+                                .blocking_send(Ok(segment))
+                                .map_err(|_| anyhow::anyhow!("Receiver disconnected"))
+                        },
+                    )
+                });
+                if let Err(e) = res {
+                    response
+                        .send(Err(e))
+                        .await
+                        .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                }
+            }
+            Request::Synthesis(SynthesisRequest { text, response }) => {
+                let res = tokio::task::block_in_place(|| {
+                    get_mut_arcmutex!(self.pipeline).synthesize(&text, &mut |chunk| {
+                        response
+                            .blocking_send(Ok(chunk))
+                            .map_err(|_| anyhow::anyhow!("Receiver disconnected"))
+                    })
+                });
+                if let Err(e) = res {
+                    response
+                        .send(Err(e))
+                        .await
+                        .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                }
+            }
+            Request::Stats(req) => {
+                req.response
+                    .send(self.stats())
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            }
+            Request::SnapshotSession(req) => {
+                req.response
+                    .send(self.snapshot_session(req.request_id))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            }
+            Request::ResumeSession(req) => {
+                req.response
+                    .send(self.resume_session(&req.session))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            }
+            Request::Cancel(id) => {
+                let canceled = get_mut_arcmutex!(self.scheduler).cancel(id);
+                for responder in canceled {
+                    responder
+                        .send(Response::InternalError(Box::new(RequestCanceled)))
+                        .await
+                        .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                }
+            }
+            Request::Drain => self.draining.store(true, Ordering::SeqCst),
             Request::Terminate => (),
             Request::TerminateAllSeqsNextStep => {
                 TERMINATE_ALL_NEXT_STEP.store(true, Ordering::SeqCst)
@@ -56,6 +172,28 @@ impl Engine {
     }
 
     pub(super) async fn add_request(&self, request: NormalRequest) {
+        if self.draining.load(Ordering::SeqCst) {
+            request
+                .response
+                .send(Response::InternalError(Box::new(Draining)))
+                .await
+                .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            return;
+        }
+
+        if let Some(max_queue_len) = self.max_queue_len {
+            if get_mut_arcmutex!(self.scheduler).waiting_len() >= max_queue_len {
+                request
+                    .response
+                    .send(Response::InternalError(Box::new(QueueFull {
+                        max_queue_len,
+                    })))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                return;
+            }
+        }
+
         let is_chat = matches!(
             request.messages,
             RequestMessage::Chat { .. } | RequestMessage::VisionChat { .. }
@@ -118,6 +256,20 @@ impl Engine {
             }
         }
 
+        if let Some(adapters) = &request.adapters {
+            let loaded = get_mut_arcmutex!(self.pipeline).lora_adapter_ids();
+            if let Some((unknown, _)) = adapters.iter().find(|(id, _)| !loaded.contains(id)) {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        format!("LoRA adapter `{unknown}` is not loaded.").into(),
+                    ))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                return;
+            }
+        }
+
         let images = match request.messages {
             RequestMessage::VisionChat {
                 ref images,
@@ -162,6 +314,17 @@ impl Engine {
             _ => None,
         };
 
+        // Negative prompts of chat requests are formatted with the chat template
+        let chat_enable_thinking = match &request.messages {
+            RequestMessage::Chat {
+                enable_thinking, ..
+            }
+            | RequestMessage::VisionChat {
+                enable_thinking, ..
+            } => Some(*enable_thinking),
+            _ => None,
+        };
+
         let (mut prompt_tokens, prompt_text) = match request.messages {
             RequestMessage::Chat {
                 messages,
@@ -236,8 +399,34 @@ impl Engine {
             return;
         }
 
+        if let Some((max_tokens, action)) = self.kv_quota {
+            if prompt_tokens.len() > max_tokens {
+                match action {
+                    KvQuotaAction::Finish => {
+                        request
+                            .response
+                            .send(Response::ValidationError(
+                                format!("Prompt sequence length is greater than the KV quota of {max_tokens} tokens.").into(),
+                            ))
+                            .await
+                            .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                        return;
+                    }
+                    KvQuotaAction::Evict(AttentionSinkConfig { sinks, window }) => {
+                        let prompt_len = prompt_tokens.len();
+                        prompt_tokens.drain(sinks..prompt_len - window);
+                        warn!("Prompt for request {} was {} tokens over the KV quota. The {} tokens after the attention sinks were dropped.", request.id, prompt_len - max_tokens, prompt_len - prompt_tokens.len());
+                    }
+                }
+            }
+        }
+
         if prompt_tokens.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
-            if !self.truncate_sequence {
+            if let Some(AttentionSinkConfig { sinks, window }) = self.attention_sinks {
+                let prompt_len = prompt_tokens.len();
+                prompt_tokens.drain(sinks..prompt_len - window);
+                warn!("Prompt for request {} was {} tokens over the model maximum length. The {} tokens after the attention sinks were dropped.", request.id, prompt_len - get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len, prompt_len - prompt_tokens.len());
+            } else if !self.truncate_sequence {
                 request
                     .response
                     .send(Response::ValidationError(
@@ -271,6 +460,7 @@ impl Engine {
             .unwrap_or(-1);
         let topp = request.sampling_params.top_p.unwrap_or(1.0);
         let minp = request.sampling_params.min_p.unwrap_or(0.0);
+        let typicalp = request.sampling_params.typical_p.unwrap_or(1.0);
         let num_hidden_layers = get_mut_arcmutex!(self.pipeline)
             .get_metadata()
             .num_hidden_layers;
@@ -329,7 +519,8 @@ impl Engine {
                         .get_ids()
                         .to_vec();
 
-                    if toks.len() == 1 {
+                    // A stop token is not part of the completion, so it cannot be included
+                    if toks.len() == 1 && !request.sampling_params.include_stop_str_in_output {
                         if tok_env.as_ref().is_some_and(|tok_env| {
                             let tok_trie = tok_env.tok_trie();
                             tok_trie.has_extensions(tok_trie.token(toks[0]))
@@ -347,6 +538,34 @@ impl Engine {
             }
         };
 
+        let mut stop_regexes = Vec::new();
+        for stop_regex in request.sampling_params.stop_regexes.iter().flatten() {
+            let re = match regex::bytes::Regex::new(stop_regex) {
+                Ok(re) if !re.is_match(b"") => re,
+                Ok(_) => {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            format!("Stop regex `{stop_regex}` matches the empty string.").into(),
+                        ))
+                        .await
+                        .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                    return;
+                }
+                Err(e) => {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            format!("Invalid stop regex `{stop_regex}`: {e}").into(),
+                        ))
+                        .await
+                        .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                    return;
+                }
+            };
+            stop_regexes.push(re);
+        }
+
         let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
             request.sampling_params.n_choices,
             request.is_streaming,
@@ -356,6 +575,80 @@ impl Engine {
 
         let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
 
+        if request
+            .sampling_params
+            .logits_bias
+            .as_ref()
+            .is_some_and(|bias| bias.values().any(|b| !(-100.0..=100.0).contains(b)))
+        {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Logit bias values must be between -100 and 100.".into(),
+                ))
+                .await
+                .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            return;
+        }
+
+        let mut logits_processors = self.logits_processors.clone();
+        logits_processors.extend(request.logits_processors.unwrap_or_default());
+        if let Some(banned_strings) = request
+            .sampling_params
+            .banned_strings
+            .as_ref()
+            .filter(|banned_strings| !banned_strings.is_empty())
+        {
+            let tok_env = get_mut_arcmutex!(self.pipeline).get_metadata().tok_env();
+            let Some(tok_env) = tok_env else {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Banned strings require the pipeline to have a token trie".into(),
+                    ))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                return;
+            };
+            logits_processors.push(Arc::new(BannedStrings::new(banned_strings, tok_env)));
+        }
+        if let Some(size) = request
+            .sampling_params
+            .no_repeat_ngram_size
+            .filter(|size| *size > 0)
+        {
+            logits_processors.push(Arc::new(NoRepeatNgram::new(size)));
+        }
+
+        let mut healed_prefix = Vec::new();
+        if request.sampling_params.token_healing {
+            if !matches!(request.constraint, Constraint::None) {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Token healing cannot be used with a grammar.".into(),
+                    ))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                return;
+            }
+            let tok_env = get_mut_arcmutex!(self.pipeline).get_metadata().tok_env();
+            let Some(tok_env) = tok_env else {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Token healing requires the pipeline to have a token trie".into(),
+                    ))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                return;
+            };
+            if let Some((healing, removed)) = TokenHealing::heal(&mut prompt_tokens, &tok_env) {
+                logits_processors.push(Arc::new(healing));
+                healed_prefix = removed;
+            }
+        }
+
         let sampler = Sampler::new(
             Some(request.sampling_params.temperature.unwrap_or(1.0)),
             request.sampling_params.top_n_logprobs,
@@ -364,10 +657,13 @@ impl Engine {
             request.sampling_params.presence_penalty,
             request.sampling_params.repetition_penalty,
             request.sampling_params.dry_params,
+            request.sampling_params.xtc_params,
+            request.sampling_params.logits_bias,
             topk,
             topp,
             minp,
-            request.logits_processors.unwrap_or_default(),
+            typicalp,
+            logits_processors,
         );
         let sampler = handle_seq_error!(sampler, request.response);
 
@@ -382,7 +678,161 @@ impl Engine {
             return;
         }
 
+        let guidance = if let Some(scale) = request.sampling_params.guidance_scale {
+            let supported = matches!(seq_step_type, SeqStepType::PromptAndDecode)
+                && !self.no_kv_cache
+                && self.prefill_pipeline.is_none()
+                && {
+                    let pipeline = get_mut_arcmutex!(self.pipeline);
+                    let metadata = pipeline.get_metadata();
+                    metadata.cache_config.is_none()
+                        && !metadata.is_xlora
+                        && !matches!(metadata.kind, ModelKind::Speculative { .. })
+                };
+            if !supported {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Classifier-free guidance requires a text generation model with a KV cache, without PagedAttention, speculative decoding or a prefill pipeline.".into(),
+                    ))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                return;
+            }
+
+            let negative_prompt = request
+                .sampling_params
+                .negative_prompt
+                .clone()
+                .unwrap_or_default();
+            let negative_tokens = {
+                let pipeline = &*get_mut_arcmutex!(self.pipeline);
+                match chat_enable_thinking {
+                    Some(enable_thinking) => {
+                        let message = IndexMap::from([
+                            ("role".to_string(), Either::Left("user".to_string())),
+                            ("content".to_string(), Either::Left(negative_prompt)),
+                        ]);
+                        pipeline
+                            .get_processor()
+                            .process(
+                                pipeline,
+                                vec![message],
+                                true,
+                                true,
+                                enable_thinking,
+                                Vec::new(),
+                            )
+                            .map(|(toks, _)| toks)
+                    }
+                    None => match pipeline.tokenizer() {
+                        Some(tokenizer) => tokenizer
+                            .encode_fast(negative_prompt, true)
+                            .map(|encoding| encoding.get_ids().to_vec())
+                            .map_err(anyhow::Error::msg),
+                        None => Err(anyhow::Error::msg(
+                            "Classifier-free guidance requires the pipeline to have a tokenizer",
+                        )),
+                    },
+                }
+            };
+            let mut negative_tokens = handle_seq_error!(negative_tokens, request.response);
+            // An empty prompt starts with the first token of the prompt, usually BOS
+            if negative_tokens.is_empty() {
+                negative_tokens.push(prompt_tokens[0]);
+            }
+            Some((scale, negative_tokens))
+        } else {
+            None
+        };
+
+        let contrastive = if let Some(penalty_alpha) = request.sampling_params.penalty_alpha {
+            let top_k = request.sampling_params.top_k.unwrap_or(0);
+            if top_k < 2 || !(0. ..=1.).contains(&penalty_alpha) {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Contrastive search requires `top_k` to be greater than 1 and `penalty_alpha` to be between 0 and 1.".into(),
+                    ))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                return;
+            }
+            let supported = matches!(seq_step_type, SeqStepType::PromptAndDecode)
+                && images.is_none()
+                && audios.is_none()
+                && !self.no_kv_cache
+                && self.prefill_pipeline.is_none()
+                && {
+                    let pipeline = get_mut_arcmutex!(self.pipeline);
+                    let metadata = pipeline.get_metadata();
+                    metadata.cache_config.is_none()
+                        && !metadata.is_xlora
+                        && !matches!(metadata.kind, ModelKind::Speculative { .. })
+                };
+            if !supported {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Contrastive search requires a text generation model with a KV cache, without PagedAttention, speculative decoding or a prefill pipeline.".into(),
+                    ))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                return;
+            }
+            Some((penalty_alpha, top_k))
+        } else {
+            None
+        };
+
+        let prompt_logprobs = echo_prompt && request.return_logprobs;
+        if prompt_logprobs {
+            let supported = matches!(seq_step_type, SeqStepType::PromptAndDecode)
+                && !self.no_kv_cache
+                && self.prefill_pipeline.is_none()
+                && {
+                    let pipeline = get_mut_arcmutex!(self.pipeline);
+                    let metadata = pipeline.get_metadata();
+                    metadata.cache_config.is_none()
+                        && !metadata.is_xlora
+                        && !matches!(metadata.kind, ModelKind::Speculative { .. })
+                };
+            if !supported {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "The logprobs of an echoed prompt require a text generation model with a KV cache, without PagedAttention, speculative decoding or a prefill pipeline.".into(),
+                    ))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                return;
+            }
+        }
+
+        // The other choices start from the KV cache of the first one once it computed the prompt
+        let fork_prompt = request.sampling_params.n_choices > 1
+            && guidance.is_none()
+            && contrastive.is_none()
+            && matches!(seq_step_type, SeqStepType::PromptAndDecode)
+            && images.is_none()
+            && audios.is_none()
+            && request.adapters.is_none()
+            && !self.no_kv_cache
+            && {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                let metadata = pipeline.get_metadata();
+                !metadata.is_xlora && !matches!(metadata.kind, ModelKind::Speculative { .. })
+            }
+            && get_mut_arcmutex!(self.prefix_cacher).can_share_prompts();
+
+        // In deterministic mode, every sequence has its own generator
+        let seed = request
+            .sampling_params
+            .seed
+            .or(self.deterministic.then_some(SEED));
+
         // Add sequences
+        let mut seqs = Vec::new();
         for response_index in 0..request.sampling_params.n_choices {
             let factory = get_mut_arcmutex!(self.pipeline)
                 .get_metadata()
@@ -520,7 +970,141 @@ impl Engine {
                 seq_preallocated_cache,
                 request.return_raw_logits,
                 eos_toks,
+                request.adapters.clone(),
+                request.tenant.clone(),
+                request.deadline,
+                request.id,
+                self.attention_sinks,
+                self.kv_quota,
+            );
+            seq.set_healed_prefix(healed_prefix.clone());
+            seq.set_stop_regexes(
+                stop_regexes.clone(),
+                request.sampling_params.include_stop_str_in_output,
             );
+            if let Some((scale, negative_tokens)) = &guidance {
+                // Only the tokens and KV cache of the negative prompt are used
+                let negative = Sequence::new_waiting(
+                    negative_tokens.clone(),
+                    String::new(),
+                    *get_mut_arcmutex!(self.id).deref(),
+                    now.as_millis(),
+                    num_hidden_layers,
+                    request.response.clone(),
+                    sampler.clone(),
+                    Vec::new(),
+                    Vec::new(),
+                    None,
+                    false,
+                    false,
+                    group.clone(),
+                    response_index,
+                    now.as_secs(),
+                    SequenceRecognizer::None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    seq_step_type,
+                    None,
+                    None,
+                    false,
+                    Vec::new(),
+                    request.adapters.clone(),
+                    request.tenant.clone(),
+                    None,
+                    request.id,
+                    None,
+                    None,
+                );
+                seq.set_guidance(Guidance::new(negative, *scale));
+            }
+            if let Some((penalty_alpha, top_k)) = contrastive {
+                // Only the tokens and KV cache of the copy are used
+                let probe = Sequence::new_waiting(
+                    prompt_tokens.clone(),
+                    String::new(),
+                    *get_mut_arcmutex!(self.id).deref(),
+                    now.as_millis(),
+                    num_hidden_layers,
+                    request.response.clone(),
+                    sampler.clone(),
+                    Vec::new(),
+                    Vec::new(),
+                    None,
+                    false,
+                    false,
+                    group.clone(),
+                    response_index,
+                    now.as_secs(),
+                    SequenceRecognizer::None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    seq_step_type,
+                    None,
+                    None,
+                    false,
+                    Vec::new(),
+                    request.adapters.clone(),
+                    request.tenant.clone(),
+                    None,
+                    request.id,
+                    None,
+                    None,
+                );
+                seq.set_contrastive(ContrastiveSearch::new(probe, penalty_alpha, top_k));
+            }
+            if prompt_logprobs {
+                // Only the tokens of the copy are used
+                let prompt = Sequence::new_waiting(
+                    prompt_tokens.clone(),
+                    String::new(),
+                    *get_mut_arcmutex!(self.id).deref(),
+                    now.as_millis(),
+                    num_hidden_layers,
+                    request.response.clone(),
+                    sampler.clone(),
+                    Vec::new(),
+                    Vec::new(),
+                    None,
+                    false,
+                    false,
+                    group.clone(),
+                    response_index,
+                    now.as_secs(),
+                    SequenceRecognizer::None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    seq_step_type,
+                    None,
+                    None,
+                    false,
+                    Vec::new(),
+                    request.adapters.clone(),
+                    request.tenant.clone(),
+                    None,
+                    request.id,
+                    None,
+                    None,
+                );
+                seq.set_prompt_logprobs_seq(prompt);
+            }
+            if let Some(seed) = seed {
+                seq.set_seed(seed.wrapping_add(response_index as u64));
+            }
 
             // Only "track" a new sequence if it is a traditional one
             if matches!(seq_step_type, SeqStepType::PromptAndDecode) {
@@ -545,14 +1129,23 @@ impl Engine {
                 );
             }
 
-            let prefill_cache = handle_seq_error!(
-                get_mut_arcmutex!(self.prefix_cacher).search_for_matching_cache(
-                    seq.get_toks(),
-                    seq.image_hashes(),
-                    seq.audio_hashes(),
-                ),
-                request.response
-            );
+            // Prefix caches are only stored for sequences which use all loaded LoRA adapters.
+            // In deterministic mode, prompts are always computed in full.
+            let prefill_cache = if seq.adapters().is_some()
+                || self.deterministic
+                || (fork_prompt && response_index > 0)
+            {
+                None
+            } else {
+                handle_seq_error!(
+                    get_mut_arcmutex!(self.prefix_cacher).search_for_matching_cache(
+                        seq.get_toks(),
+                        seq.image_hashes(),
+                        seq.audio_hashes(),
+                    ),
+                    request.response
+                )
+            };
 
             seq = match prefill_cache.clone() {
                 Some(MatchingCache::Normal {
@@ -586,7 +1179,18 @@ impl Engine {
             };
 
             *get_mut_arcmutex!(self.id) += 1;
-            get_mut_arcmutex!(self.scheduler).add_seq(seq);
+            seqs.push(seq);
+        }
+
+        if fork_prompt {
+            let mut seqs = seqs.into_iter();
+            let mut first = seqs.next().unwrap();
+            first.set_prompt_forks(seqs.collect());
+            get_mut_arcmutex!(self.scheduler).add_seq(first);
+        } else {
+            for seq in seqs {
+                get_mut_arcmutex!(self.scheduler).add_seq(seq);
+            }
         }
     }
 
@@ -691,4 +1295,41 @@ impl Engine {
             .await
             .expect("Sender disconnected unexpectedly!");
     }
+
+    async fn handle_lora_adapter_request(&self, request: LoraAdapterRequest) {
+        let res = {
+            let mut pipeline = get_mut_arcmutex!(self.pipeline);
+            match &request.action {
+                LoraAdapterAction::Load(adapter_id, scale) => {
+                    pipeline.load_lora_adapter(adapter_id.clone(), *scale)
+                }
+                LoraAdapterAction::Unload(adapter_id) => pipeline.unload_lora_adapter(adapter_id),
+                LoraAdapterAction::Merge => pipeline.merge_lora_adapters(),
+                LoraAdapterAction::List => Ok(()),
+            }
+            .map(|()| pipeline.lora_adapter_infos())
+        };
+        if res.is_ok() && request.action != LoraAdapterAction::List {
+            // Cached prefixes were computed with the previous weights.
+            if let Err(e) = get_mut_arcmutex!(self.prefix_cacher).evict_all_caches() {
+                warn!("Failed to evict the prefix cache: {e:?}");
+            }
+        }
+        let res = res.map(|mut infos| {
+            let running = get_mut_arcmutex!(self.scheduler).running_lora_adapters();
+            for info in &mut infos {
+                info.active |= running.iter().any(|adapters| {
+                    adapters
+                        .as_ref()
+                        .is_none_or(|adapters| adapters.iter().any(|(name, _)| name == &info.name))
+                });
+            }
+            infos
+        });
+        request
+            .response
+            .send(res)
+            .await
+            .unwrap_or_else(|_| warn!("Receiver disconnected"));
+    }
 }
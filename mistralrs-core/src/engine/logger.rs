@@ -1,6 +1,6 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -14,6 +14,11 @@ pub struct IntervalLogger {
     total_new_seqs: Arc<AtomicUsize>,
     num_running: Arc<AtomicUsize>,
     num_waiting: Arc<AtomicUsize>,
+    /// The used and total PagedAttention KV cache blocks, 0 without PagedAttention.
+    kv_blocks_used: Arc<AtomicUsize>,
+    kv_blocks_total: Arc<AtomicUsize>,
+    /// The bits of the throughput (T/s) over the last interval.
+    throughput: Arc<AtomicU64>,
 }
 
 impl IntervalLogger {
@@ -25,6 +30,9 @@ impl IntervalLogger {
         let enable_logging = Arc::new(AtomicBool::new(false));
         let num_running = Arc::new(AtomicUsize::new(0));
         let num_waiting = Arc::new(AtomicUsize::new(0));
+        let kv_blocks_used = Arc::new(AtomicUsize::new(0));
+        let kv_blocks_total = Arc::new(AtomicUsize::new(0));
+        let throughput = Arc::new(AtomicU64::new(0f64.to_bits()));
 
         let t_prefix_cache_hits = prefix_cache_hits.clone();
         let t_tokens_processed = tokens_processed.clone();
@@ -32,24 +40,40 @@ impl IntervalLogger {
         let t_enable_logging = enable_logging.clone();
         let t_num_running = num_running.clone();
         let t_num_waiting = num_waiting.clone();
+        let t_kv_blocks_used = kv_blocks_used.clone();
+        let t_kv_blocks_total = kv_blocks_total.clone();
+        let t_throughput = throughput.clone();
         thread::spawn(move || {
             // Start the actual logging
             loop {
                 thread::sleep(interval);
+
+                // The throughput is always measured, as it is part of the stats of the engine
+                let tokens_processed = t_tokens_processed.swap(0, Ordering::Relaxed);
+                let throughput = tokens_processed as f64 / interval.as_secs_f64();
+                t_throughput.store(throughput.to_bits(), Ordering::Relaxed);
                 if !t_enable_logging.load(Ordering::Relaxed) {
                     continue;
                 }
 
                 let total_new_seqs = t_total_new_seqs.load(Ordering::Relaxed);
                 let prefix_cache_hits = t_prefix_cache_hits.load(Ordering::Relaxed);
-                let tokens_processed = t_tokens_processed.swap(0, Ordering::Relaxed);
                 let num_running = t_num_running.load(Ordering::Relaxed);
                 let num_waiting = t_num_waiting.load(Ordering::Relaxed);
+                let kv_blocks_used = t_kv_blocks_used.load(Ordering::Relaxed);
+                let kv_blocks_total = t_kv_blocks_total.load(Ordering::Relaxed);
 
                 if total_new_seqs != 0 && tokens_processed != 0 {
+                    let kv_cache_usage = if kv_blocks_total != 0 {
+                        format!(
+                            ", KV cache {:.2}% used",
+                            100. * kv_blocks_used as f64 / kv_blocks_total as f64
+                        )
+                    } else {
+                        String::new()
+                    };
                     info!(
-                        "Throughput (T/s) {:.2}, Prefix cache hitrate {:.2}%, {num_running} running, {num_waiting} waiting",
-                        tokens_processed as f64 / interval.as_secs_f64(),
+                        "Throughput (T/s) {throughput:.2}, Prefix cache hitrate {:.2}%, {num_running} running, {num_waiting} waiting{kv_cache_usage}",
                         100. * prefix_cache_hits as f64 / total_new_seqs as f64,
                     );
                 }
@@ -63,6 +87,9 @@ impl IntervalLogger {
             enable_logging,
             num_running,
             num_waiting,
+            kv_blocks_used,
+            kv_blocks_total,
+            throughput,
         }
     }
 
@@ -90,4 +117,33 @@ impl IntervalLogger {
     pub fn set_num_waiting(&self, waiting: usize) {
         self.num_waiting.store(waiting, Ordering::Relaxed);
     }
+
+    pub fn set_kv_cache_usage(&self, used_blocks: usize, total_blocks: usize) {
+        self.kv_blocks_used.store(used_blocks, Ordering::Relaxed);
+        self.kv_blocks_total.store(total_blocks, Ordering::Relaxed);
+    }
+
+    /// The tokens processed per second over the last interval.
+    pub fn throughput(&self) -> f64 {
+        f64::from_bits(self.throughput.load(Ordering::Relaxed))
+    }
+
+    /// The new sequences which reused a cached prefix, and those which did not.
+    pub fn prefix_cache_hits_and_misses(&self) -> (usize, usize) {
+        let total_new_seqs = self.total_new_seqs.load(Ordering::Relaxed);
+        let prefix_cache_hits = self.prefix_cache_hits.load(Ordering::Relaxed);
+        (
+            prefix_cache_hits,
+            total_new_seqs.saturating_sub(prefix_cache_hits),
+        )
+    }
+
+    /// The fraction of the new sequences which reused a cached prefix, 0 if there were none.
+    pub fn prefix_cache_hit_rate(&self) -> f64 {
+        let total_new_seqs = self.total_new_seqs.load(Ordering::Relaxed);
+        if total_new_seqs == 0 {
+            return 0.;
+        }
+        self.prefix_cache_hits.load(Ordering::Relaxed) as f64 / total_new_seqs as f64
+    }
 }
@@ -0,0 +1,142 @@
+#![allow(clippy::cast_precision_loss)]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::request::{HistogramBucket, LatencyHistogram};
+
+/// The bucket bounds of the latency histograms, in seconds.
+const LATENCY_BUCKETS: [f64; 17] = [
+    0.001, 0.005, 0.01, 0.02, 0.04, 0.06, 0.08, 0.1, 0.25, 0.5, 0.75, 1., 2.5, 5., 7.5, 10., 30.,
+];
+
+struct Histogram {
+    /// The latencies in each bucket, and above the last bucket.
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            counts: [0; LATENCY_BUCKETS.len() + 1],
+            sum: 0.,
+        }
+    }
+
+    fn observe(&mut self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.partition_point(|bound| *bound < secs);
+        self.counts[bucket] += 1;
+        self.sum += secs;
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let mut count = 0;
+        let buckets = LATENCY_BUCKETS
+            .iter()
+            .zip(self.counts)
+            .map(|(le, bucket_count)| {
+                count += bucket_count;
+                HistogramBucket { le: *le, count }
+            })
+            .collect();
+        LatencyHistogram {
+            buckets,
+            sum: self.sum,
+            count: self.counts.iter().sum(),
+        }
+    }
+}
+
+/// The token counters and latency histograms of an engine since it started, which are reported
+/// in its stats.
+pub(crate) struct EngineMetrics {
+    prompt_tokens: AtomicUsize,
+    generation_tokens: AtomicUsize,
+    time_to_first_token: Mutex<Histogram>,
+    time_per_output_token: Mutex<Histogram>,
+}
+
+impl EngineMetrics {
+    pub(crate) const fn new() -> Self {
+        Self {
+            prompt_tokens: AtomicUsize::new(0),
+            generation_tokens: AtomicUsize::new(0),
+            time_to_first_token: Mutex::new(Histogram::new()),
+            time_per_output_token: Mutex::new(Histogram::new()),
+        }
+    }
+
+    /// Record a sequence whose prompt was processed, generating its first token.
+    pub(crate) fn record_prompt(&self, prompt_tokens: usize, time_to_first_token: Duration) {
+        self.prompt_tokens
+            .fetch_add(prompt_tokens, Ordering::Relaxed);
+        self.generation_tokens.fetch_add(1, Ordering::Relaxed);
+        self.time_to_first_token
+            .lock()
+            .unwrap()
+            .observe(time_to_first_token);
+    }
+
+    /// Record a step which generated a token for each of `num_seqs` sequences.
+    pub(crate) fn record_decode_step(&self, num_seqs: usize, step_time: Duration) {
+        self.generation_tokens
+            .fetch_add(num_seqs, Ordering::Relaxed);
+        let mut histogram = self.time_per_output_token.lock().unwrap();
+        for _ in 0..num_seqs {
+            histogram.observe(step_time);
+        }
+    }
+
+    pub(crate) fn prompt_tokens(&self) -> usize {
+        self.prompt_tokens.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn generation_tokens(&self) -> usize {
+        self.generation_tokens.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn time_to_first_token(&self) -> LatencyHistogram {
+        self.time_to_first_token.lock().unwrap().snapshot()
+    }
+
+    pub(crate) fn time_per_output_token(&self) -> LatencyHistogram {
+        self.time_per_output_token.lock().unwrap().snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Histogram;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(90));
+        histogram.observe(Duration::from_secs(60));
+
+        let snapshot = histogram.snapshot();
+        let count_at = |le: f64| {
+            snapshot
+                .buckets
+                .iter()
+                .find(|bucket| bucket.le == le)
+                .unwrap()
+                .count
+        };
+        assert_eq!(count_at(0.001), 0);
+        // A latency equal to a bound is in its bucket
+        assert_eq!(count_at(0.005), 2);
+        assert_eq!(count_at(0.08), 2);
+        assert_eq!(count_at(0.1), 3);
+        assert_eq!(count_at(30.), 3);
+        assert_eq!(snapshot.count, 4);
+        assert!((snapshot.sum - 60.098).abs() < 1e-9);
+    }
+}
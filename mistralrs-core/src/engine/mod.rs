@@ -1,21 +1,22 @@
 use crate::{
     distributed,
     embedding::bert::BertPipeline,
+    kv_cache::move_seq_caches,
     pipeline::{
-        llg::{constraint_from_llg_grammar, llg_grammar_from_constraint},
-        text_models_inputs_processor::PagedAttentionMeta,
-        CacheBackendMetadata, CacheInstruction,
+        llg::cached_constraint, text_models_inputs_processor::PagedAttentionMeta,
+        CacheBackendMetadata, CacheInstruction, EitherCache,
     },
-    prefix_cacher::PrefixCacheManagerV2,
+    prefix_cacher::{model_fingerprint, MatchingCache, PrefixCacheManagerV2, SessionBlob},
     response::CompletionChoice,
     scheduler::{Scheduler, SchedulerOutput},
     search,
-    sequence::{SeqStepType, StopReason},
-    tools, CompletionResponse, SchedulerConfig, DEBUG,
+    sequence::{AttentionSinkConfig, KvQuotaAction, KvQuotaConfig, SeqStepType, StopReason},
+    tools, CompletionResponse, ModelKind, SchedulerConfig, SchedulingPolicy, DEBUG,
 };
 use interprocess::local_socket::{traits::Listener, ListenerOptions};
 use llguidance::ParserFactory;
 pub use logger::IntervalLogger;
+use metrics::EngineMetrics;
 use mistralrs_quant::RingConfig;
 use once_cell::sync::Lazy;
 use rand::SeedableRng;
@@ -25,6 +26,7 @@ use std::{
     io::{BufWriter, Write},
     net::TcpListener,
     ops::Deref,
+    path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -42,14 +44,15 @@ use tokio::{
 use crate::{
     get_mut_arcmutex, handle_pipeline_forward_error,
     pipeline::Pipeline,
-    request::Request,
-    response::{ChatCompletionResponse, Choice, ResponseMessage},
-    sequence::{SequenceRecognizer, SequenceState},
-    Constraint,
+    request::{EngineStats, Request},
+    response::{ChatCompletionResponse, Choice, DeadlineExceeded, Response, ResponseMessage},
+    sequence::{Sequence, SequenceRecognizer, SequenceState},
+    Constraint, CustomLogitsProcessor,
 };
 
 mod add_request;
 mod logger;
+mod metrics;
 mod search_request;
 
 pub enum EngineInstruction {
@@ -110,6 +113,12 @@ pub fn reset_engine_terminate_flag() {
     }
 }
 
+/// Whether the model is split across several processes, whose caches are not aligned with the
+/// prefix cache of this process.
+fn is_tensor_parallel() -> bool {
+    distributed::is_daemon() || mistralrs_quant::distributed::use_nccl() || cfg!(feature = "ring")
+}
+
 /// Engine instructions, per Engine (MistralRs) ID.
 pub static ENGINE_INSTRUCTIONS: Lazy<std::sync::Mutex<HashMap<usize, Option<EngineInstruction>>>> =
     Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
@@ -117,6 +126,9 @@ pub static ENGINE_INSTRUCTIONS: Lazy<std::sync::Mutex<HashMap<usize, Option<Engi
 pub struct Engine {
     rx: Arc<Mutex<Receiver<Request>>>,
     pipeline: Arc<Mutex<dyn Pipeline>>,
+    /// Runs the prompts if set, with `pipeline` running the completions. Both run in turn on the
+    /// engine loop, a prompt step is not overlapped with a completion step.
+    prefill_pipeline: Option<Arc<Mutex<dyn Pipeline>>>,
     bert_pipeline: Arc<Mutex<Option<BertPipeline>>>,
     search_callback: Option<Arc<search::SearchCallback>>,
     tool_callbacks: tools::ToolCallbacks,
@@ -128,8 +140,20 @@ pub struct Engine {
     prefix_cacher: Arc<Mutex<PrefixCacheManagerV2>>,
     is_debug: bool,
     disable_eos_stop: bool,
+    /// The most sequences waiting to run before new requests are rejected.
+    max_queue_len: Option<usize>,
+    attention_sinks: Option<AttentionSinkConfig>,
+    /// The most tokens each sequence may hold, and what happens once it holds them.
+    kv_quota: Option<(usize, KvQuotaAction)>,
+    /// Applied to every request, before the logits processors of the request.
+    logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+    /// Make the output of each request depend only on the request.
+    deterministic: bool,
+    /// Set by `Request::Drain`: new requests are rejected, and the engine stops once idle.
+    draining: AtomicBool,
     throughput_logging_enabled: bool,
     logger: IntervalLogger,
+    metrics: EngineMetrics,
     handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
 }
 
@@ -141,17 +165,69 @@ impl Drop for Engine {
     }
 }
 
+/// Start the other choices of `seq`, now that it computed their shared prompt, from its KV cache.
+/// A normal KV cache is copied, and the PagedAttention blocks of the prompt were shared with
+/// [`PrefixCacheManagerV2::share_blocks`]. The last token of the prompt is computed again by each
+/// choice, for its logits.
+fn start_prompt_forks(
+    seq: &mut Sequence,
+    prefix_cacher: &mut PrefixCacheManagerV2,
+) -> Vec<Sequence> {
+    let forks = seq.take_prompt_forks();
+    forks
+        .into_iter()
+        .map(|fork| {
+            let toks = fork.get_toks().to_vec();
+            let offset = toks.len() - 1;
+            let mut cache = seq.normal_cache().clone();
+            // The cache holds exactly the prompt unless tokens were evicted from it
+            let holds_prompt = cache.iter().any(Option::is_some)
+                && cache
+                    .iter()
+                    .flatten()
+                    .all(|layer| layer.current_seq_len() == toks.len());
+            if holds_prompt
+                && cache
+                    .iter_mut()
+                    .flatten()
+                    .all(|layer| layer.set_len(offset).is_ok())
+            {
+                return fork.prefill_v2_normal(cache, toks[offset..].to_vec(), offset);
+            }
+            match prefix_cacher.search_for_matching_cache(&toks, None, None) {
+                Ok(Some(MatchingCache::Paged {
+                    logical_blocks,
+                    physical_blocks,
+                    toks,
+                    offset,
+                    ..
+                })) => fork.prefill_v2_paged(logical_blocks, physical_blocks, toks, offset),
+                _ => fork,
+            }
+        })
+        .collect()
+}
+
 impl Engine {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         rx: Receiver<Request>,
         pipeline: Arc<Mutex<dyn Pipeline>>,
+        prefill_pipeline: Option<Arc<Mutex<dyn Pipeline>>>,
         config: SchedulerConfig,
         truncate_sequence: bool,
         mut no_kv_cache: bool,
         mut no_prefix_cache: bool,
         prefix_cache_n: usize,
+        prefix_cache_dir: Option<PathBuf>,
         disable_eos_stop: bool,
+        scheduling_policy: SchedulingPolicy,
+        mut prefill_chunk_size: Option<usize>,
+        max_queue_len: Option<usize>,
+        attention_sinks: Option<AttentionSinkConfig>,
+        kv_quota: Option<KvQuotaConfig>,
+        logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+        deterministic: bool,
         throughput_logging_enabled: bool,
         search_embedding_model: Option<BertEmbeddingModel>,
         search_callback: Option<Arc<search::SearchCallback>>,
@@ -173,12 +249,91 @@ impl Engine {
             None => None,
         };
 
-        let scheduler = config.into_scheduler();
+        if prefill_chunk_size.is_some()
+            && matches!(
+                get_mut_arcmutex!(pipeline).get_metadata().kind,
+                ModelKind::Speculative { .. }
+            )
+        {
+            tracing::warn!(
+                "Chunked prefill is not supported with speculative decoding, disabling it."
+            );
+            prefill_chunk_size = None;
+        }
+
+        if prefill_chunk_size.is_some()
+            && matches!(config, SchedulerConfig::PagedAttentionMeta { .. })
+        {
+            anyhow::bail!(
+                "Chunked prefill is not supported with PagedAttention, disable PagedAttention or do not set a prefill chunk size."
+            );
+        }
+
+        let prefill_pipeline = match prefill_pipeline {
+            Some(_) if matches!(config, SchedulerConfig::PagedAttentionMeta { .. }) => {
+                anyhow::bail!("A prefill pipeline is not supported with PagedAttention.");
+            }
+            Some(prefill_pipeline) => {
+                if get_mut_arcmutex!(prefill_pipeline).name() != get_mut_arcmutex!(pipeline).name()
+                {
+                    anyhow::bail!("The prefill pipeline must run the same model as the pipeline.");
+                }
+                Some(prefill_pipeline)
+            }
+            None => None,
+        };
+
+        let kv_quota = kv_quota.and_then(|kv_quota| {
+            let metadata = get_mut_arcmutex!(pipeline).get_metadata();
+            let Some(max_tokens) = kv_quota.max_tokens(&metadata) else {
+                tracing::warn!("The KV cache size of this model is unknown, ignoring the KV quota.");
+                return None;
+            };
+            if max_tokens == 0 {
+                tracing::warn!("The KV quota is smaller than one token or block, ignoring it.");
+                return None;
+            }
+            if let KvQuotaAction::Evict(sinks) = kv_quota.action {
+                if sinks.sinks + sinks.window >= max_tokens.min(metadata.max_seq_len) {
+                    tracing::warn!(
+                        "The {} attention sinks and window of {} tokens do not fit in the KV quota of {max_tokens} tokens, ignoring it.",
+                        sinks.sinks, sinks.window
+                    );
+                    return None;
+                }
+            }
+            tracing::info!("Limiting the KV cache of each sequence to {max_tokens} tokens.");
+            Some((max_tokens, kv_quota.action))
+        });
+
+        let scheduler = config.into_scheduler(scheduling_policy, prefill_chunk_size);
         let block_engine = get_mut_arcmutex!(scheduler).block_engine();
 
+        let mut prefix_cacher =
+            PrefixCacheManagerV2::new(prefix_cache_n, no_prefix_cache, block_engine.clone());
+        if let Some(prefix_cache_dir) = prefix_cache_dir.filter(|_| !no_prefix_cache) {
+            let pipeline = get_mut_arcmutex!(pipeline);
+            if block_engine.is_some() {
+                tracing::warn!("Persisting the prefix cache is not supported with PagedAttention.");
+            } else if is_tensor_parallel() {
+                tracing::warn!(
+                    "Persisting the prefix cache is not supported with tensor parallelism."
+                );
+            } else if let EitherCache::Normal(cache) = pipeline.cache() {
+                prefix_cacher.persist_in(prefix_cache_dir, model_fingerprint(&*pipeline));
+                match prefix_cacher.load(&cache.lock().unwrap().0, &pipeline.device()) {
+                    Ok(n_loaded) => tracing::info!("Loaded {n_loaded} prefix caches from disk."),
+                    Err(e) => tracing::warn!("Failed to load the prefix caches: {e}"),
+                }
+            } else {
+                tracing::warn!("Persisting the prefix cache is not supported for this model.");
+            }
+        }
+
         Ok(Self {
             rx: Arc::new(Mutex::new(rx)),
             pipeline,
+            prefill_pipeline,
             bert_pipeline: Arc::new(Mutex::new(bert_pipeline)),
             search_callback,
             tool_callbacks,
@@ -187,15 +342,18 @@ impl Engine {
             id: Arc::new(Mutex::new(0)),
             truncate_sequence,
             no_kv_cache,
-            prefix_cacher: Arc::new(Mutex::new(PrefixCacheManagerV2::new(
-                prefix_cache_n,
-                no_prefix_cache,
-                block_engine,
-            ))),
+            prefix_cacher: Arc::new(Mutex::new(prefix_cacher)),
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
+            max_queue_len,
+            attention_sinks,
+            kv_quota,
+            logits_processors,
+            deterministic,
+            draining: AtomicBool::new(false),
             throughput_logging_enabled,
             logger: IntervalLogger::new(Duration::from_secs(5)),
+            metrics: EngineMetrics::new(),
             handles: Arc::new(Mutex::new(Vec::new())),
         })
     }
@@ -250,13 +408,21 @@ impl Engine {
                 break 'lp;
             }
 
+            let timed_out = get_mut_arcmutex!(self.scheduler).remove_timed_out();
+            for responder in timed_out {
+                responder
+                    .send(Response::InternalError(Box::new(DeadlineExceeded)))
+                    .await
+                    .unwrap_or_else(|_| tracing::warn!("Receiver disconnected"));
+            }
+
             let scheduler_idle = {
                 let scheduler = get_mut_arcmutex!(self.scheduler);
                 scheduler.waiting_len() == 0 && scheduler.running_len() == 0
             };
 
             if scheduler_idle {
-                if should_terminate() {
+                if should_terminate() || self.draining.load(Ordering::SeqCst) {
                     self.replicate_request_to_daemons(&Request::Terminate);
                     break 'lp;
                 }
@@ -284,6 +450,7 @@ impl Engine {
             }
 
             let run_start = Instant::now();
+            let mut forks = Vec::new();
             let mut scheduler = get_mut_arcmutex!(self.scheduler);
             let scheduled = scheduler.schedule(&self.logger);
 
@@ -291,13 +458,24 @@ impl Engine {
                 SchedulerOutput::DefaultScheduler {
                     output: mut scheduled,
                 } => {
+                    if self.deterministic {
+                        // The sequences of each batch run in the order they arrived in
+                        scheduled.completion.sort_by_key(|seq| *seq.id());
+                        scheduled.prompt.sort_by_key(|seq| *seq.id());
+                    }
                     if !scheduled.completion.is_empty() {
                         let current_completion_ids: Vec<usize> =
                             scheduled.completion.iter().map(|seq| *seq.id()).collect();
                         let res = {
                             let mut pipeline = get_mut_arcmutex!(self.pipeline);
+                            // The negative prompts of classifier-free guidance and the candidates
+                            // of contrastive search replace the model cache after each step
                             let pre_op = if !self.no_kv_cache
-                                && last_completion_ids != current_completion_ids
+                                && (last_completion_ids != current_completion_ids
+                                    || scheduled
+                                        .completion
+                                        .iter()
+                                        .any(|seq| seq.has_guidance() || seq.has_contrastive()))
                             {
                                 CacheInstruction::In
                             } else {
@@ -334,7 +512,7 @@ impl Engine {
                                 .await
                         };
 
-                        handle_pipeline_forward_error!(
+                        let completion_exec_time = handle_pipeline_forward_error!(
                             "completion step",
                             res,
                             &mut scheduled.completion,
@@ -344,13 +522,19 @@ impl Engine {
                         );
 
                         self.logger.add_tokens_processed(scheduled.completion.len());
+                        self.metrics
+                            .record_decode_step(scheduled.completion.len(), completion_exec_time);
 
                         last_completion_ids = current_completion_ids;
                     }
 
                     if !scheduled.prompt.is_empty() {
+                        // The KV caches of the prompts are moved between the pipelines if the
+                        // prompts run on a prefill pipeline
+                        let prompt_pipeline =
+                            self.prefill_pipeline.as_ref().unwrap_or(&self.pipeline);
                         let prompt_exec_time = {
-                            let mut pipeline = get_mut_arcmutex!(self.pipeline);
+                            let mut pipeline = get_mut_arcmutex!(prompt_pipeline);
 
                             // Run the prompt seqs
                             let post_op = if !self.no_kv_cache {
@@ -376,23 +560,38 @@ impl Engine {
                             let pre_op = if scheduled.prompt[0].token_offset() != 0 {
                                 CacheInstruction::In
                             } else {
+                                // The preallocated caches are on the devices of `self.pipeline`
                                 CacheInstruction::Reset {
-                                    load_preallocated_cache: true,
+                                    load_preallocated_cache: self.prefill_pipeline.is_none(),
                                     reset_non_granular: false,
                                 }
                             };
 
-                            pipeline
-                                .step(
-                                    &mut scheduled.prompt,
-                                    true,
-                                    return_raw_logits,
-                                    &mut *get_mut_arcmutex!(self.prefix_cacher),
-                                    self.disable_eos_stop,
-                                    rng.clone(),
-                                    CacheBackendMetadata::DefaultInstructions { pre_op, post_op },
-                                )
-                                .await
+                            // Prefix caches are on the devices of `self.pipeline`
+                            let moved = if self.prefill_pipeline.is_some() {
+                                move_seq_caches(&*pipeline, &mut scheduled.prompt)
+                            } else {
+                                Ok(())
+                            };
+                            match moved {
+                                Ok(()) => {
+                                    pipeline
+                                        .step(
+                                            &mut scheduled.prompt,
+                                            true,
+                                            return_raw_logits,
+                                            &mut *get_mut_arcmutex!(self.prefix_cacher),
+                                            self.disable_eos_stop,
+                                            rng.clone(),
+                                            CacheBackendMetadata::DefaultInstructions {
+                                                pre_op,
+                                                post_op,
+                                            },
+                                        )
+                                        .await
+                                }
+                                Err(e) => Err(e),
+                            }
                         };
 
                         let prompt_exec_time = handle_pipeline_forward_error!(
@@ -404,6 +603,25 @@ impl Engine {
                             self.prefix_cacher
                         );
 
+                        if self.prefill_pipeline.is_some() {
+                            let mut prefilled = scheduled
+                                .prompt
+                                .iter_mut()
+                                .filter(|seq| !seq.is_partial_prefill())
+                                .map(|seq| &mut **seq)
+                                .collect::<Vec<_>>();
+                            let moved =
+                                move_seq_caches(&*get_mut_arcmutex!(self.pipeline), &mut prefilled);
+                            handle_pipeline_forward_error!(
+                                "KV cache transfer",
+                                moved,
+                                &mut prefilled,
+                                self.pipeline,
+                                'lp,
+                                self.prefix_cacher
+                            );
+                        }
+
                         let total_processed_tokens: usize = scheduled
                             .prompt
                             .iter()
@@ -412,6 +630,18 @@ impl Engine {
                         self.logger.add_tokens_processed(total_processed_tokens);
 
                         for seq in scheduled.prompt.iter_mut() {
+                            let previous_chunks_time = seq.total_prompt_time.unwrap_or(0);
+                            if seq.is_partial_prefill() {
+                                // The rest of the prompt is processed in the next steps
+                                seq.advance_prefill_chunk();
+                                seq.set_state(SequenceState::RunningPrompt);
+                                seq.total_prompt_time =
+                                    Some(previous_chunks_time + prompt_exec_time.as_millis());
+                                continue;
+                            }
+                            #[allow(clippy::cast_possible_truncation)]
+                            let prompt_exec_time = prompt_exec_time
+                                + Duration::from_millis(previous_chunks_time as u64);
                             match seq.sequence_stepping_type() {
                                 SeqStepType::OneShot => {
                                     seq.set_state(SequenceState::Done(StopReason::GeneratedImage))
@@ -420,6 +650,10 @@ impl Engine {
                                     seq.set_state(SequenceState::RunningCompletion)
                                 }
                             }
+                            forks.extend(start_prompt_forks(
+                                seq,
+                                &mut get_mut_arcmutex!(self.prefix_cacher),
+                            ));
                             let now = SystemTime::now()
                                 .duration_since(UNIX_EPOCH)
                                 .expect("Time travel has occurred!")
@@ -430,6 +664,11 @@ impl Engine {
                             seq.prompt_tok_per_sec = prompt_tok_per_sec;
                             seq.prompt_timestamp = Some(now);
                             seq.total_prompt_time = Some(prompt_exec_time.as_millis());
+                            #[allow(clippy::cast_possible_truncation)]
+                            let time_to_first_token =
+                                Duration::from_millis((now - seq.timestamp()) as u64);
+                            self.metrics
+                                .record_prompt(seq.prompt_tokens(), time_to_first_token);
                         }
                         last_completion_ids = vec![];
                     }
@@ -462,6 +701,28 @@ impl Engine {
                     }
                 }
                 SchedulerOutput::PagedAttention { mut output } => {
+                    // Swaps are executed even if nothing is scheduled, as the freed blocks may be
+                    // reused in the next step.
+                    if !output.blocks_to_offload.is_empty()
+                        || !output.blocks_to_swap_in.is_empty()
+                        || !output.blocks_to_swap_out.is_empty()
+                    {
+                        let pipeline = get_mut_arcmutex!(self.pipeline);
+                        if let Err(e) = pipeline
+                            .get_metadata()
+                            .cache_engine
+                            .as_ref()
+                            .expect("PagedAttention must have cache engines.")
+                            .execute_swap_ops(
+                                &output.blocks_to_offload,
+                                &output.blocks_to_swap_in,
+                                &output.blocks_to_swap_out,
+                            )
+                        {
+                            tracing::error!("Failed to swap PagedAttention KV cache blocks: {e}");
+                        }
+                    }
+
                     if !output.scheduled.is_empty() {
                         let is_prompt = get_mut_arcmutex!(output.scheduled[0]).is_prompt();
 
@@ -473,6 +734,9 @@ impl Engine {
 
                         let mut guards_mut =
                             guards.iter_mut().map(|seq| &mut **seq).collect::<Vec<_>>();
+                        if self.deterministic {
+                            guards_mut.sort_by_key(|seq| *seq.id());
+                        }
 
                         let res = {
                             let mut pipeline = get_mut_arcmutex!(self.pipeline);
@@ -509,7 +773,7 @@ impl Engine {
                                 .await
                         };
 
-                        handle_pipeline_forward_error!(
+                        let exec_time = handle_pipeline_forward_error!(
                             "step",
                             res,
                             &mut guards_mut,
@@ -517,6 +781,9 @@ impl Engine {
                             'lp,
                             self.prefix_cacher
                         );
+                        if !is_prompt {
+                            self.metrics.record_decode_step(guards.len(), exec_time);
+                        }
 
                         let total_processed_tokens: usize = guards
                             .iter()
@@ -556,7 +823,11 @@ impl Engine {
                         }
 
                         if is_prompt {
+                            // The prompts are computed, so later sequences can share their blocks
+                            let mut prefix_cacher = get_mut_arcmutex!(self.prefix_cacher);
                             for mut seq in guards {
+                                prefix_cacher.share_blocks(&seq);
+                                forks.extend(start_prompt_forks(&mut seq, &mut prefix_cacher));
                                 let now = SystemTime::now()
                                     .duration_since(UNIX_EPOCH)
                                     .expect("Time travel has occurred!")
@@ -567,28 +838,101 @@ impl Engine {
                                 seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                                 seq.prompt_timestamp = Some(now);
                                 seq.total_prompt_time = Some(now - seq.timestamp());
+                                #[allow(clippy::cast_possible_truncation)]
+                                let time_to_first_token =
+                                    Duration::from_millis((now - seq.timestamp()) as u64);
+                                self.metrics
+                                    .record_prompt(seq.prompt_tokens(), time_to_first_token);
                             }
                         }
                     }
                 }
             }
 
+            for seq in forks {
+                scheduler.add_seq(seq);
+            }
             scheduler.free_finished_sequence_groups();
         }
+
+        match get_mut_arcmutex!(self.prefix_cacher).save() {
+            Ok(0) => (),
+            Ok(n_saved) => tracing::info!("Saved {n_saved} prefix caches to disk."),
+            Err(e) => tracing::warn!("Failed to save the prefix caches: {e}"),
+        }
+    }
+
+    /// The KV cache of the last finished sequence of the request `request_id`.
+    fn snapshot_session(&self, request_id: usize) -> anyhow::Result<SessionBlob> {
+        if is_tensor_parallel() {
+            anyhow::bail!("Sessions are not supported with tensor parallelism.");
+        }
+        let fingerprint = model_fingerprint(&*get_mut_arcmutex!(self.pipeline));
+        get_mut_arcmutex!(self.prefix_cacher).snapshot_session(request_id, fingerprint)
+    }
+
+    /// Add the KV cache of `session` to the prefix cache.
+    fn resume_session(&self, session: &SessionBlob) -> anyhow::Result<()> {
+        if is_tensor_parallel() {
+            anyhow::bail!("Sessions are not supported with tensor parallelism.");
+        }
+        let pipeline = get_mut_arcmutex!(self.pipeline);
+        let EitherCache::Normal(cache) = pipeline.cache() else {
+            anyhow::bail!("Sessions are not supported for this model.");
+        };
+        let template = cache.lock().unwrap().0.clone();
+        get_mut_arcmutex!(self.prefix_cacher).resume_session(
+            session,
+            &template,
+            &pipeline.device(),
+            model_fingerprint(&*pipeline),
+        )
+    }
+
+    fn stats(&self) -> EngineStats {
+        let scheduler = get_mut_arcmutex!(self.scheduler);
+        let (kv_blocks_used, kv_blocks_total) = match scheduler.block_engine() {
+            Some(block_engine) => {
+                let block_engine = get_mut_arcmutex!(block_engine);
+                let total = block_engine.num_gpu_blocks();
+                (
+                    Some(total - block_engine.num_free_gpu_blocks()),
+                    Some(total),
+                )
+            }
+            None => (None, None),
+        };
+        let (prefix_cache_hits, prefix_cache_misses) = self.logger.prefix_cache_hits_and_misses();
+        EngineStats {
+            num_waiting: scheduler.waiting_len(),
+            num_running: scheduler.running_len(),
+            kv_blocks_used,
+            kv_blocks_total,
+            prefix_cache_hit_rate: self.logger.prefix_cache_hit_rate(),
+            tokens_per_sec: self.logger.throughput(),
+            prefix_cache_hits,
+            prefix_cache_misses,
+            kv_cache: scheduler.kv_cache_stats(),
+            prompt_tokens: self.metrics.prompt_tokens(),
+            generation_tokens: self.metrics.generation_tokens(),
+            time_to_first_token: self.metrics.time_to_first_token(),
+            time_per_output_token: self.metrics.time_per_output_token(),
+        }
     }
 
     fn build_sequence_recognizer(
         factory: &Option<Arc<ParserFactory>>,
         constraint: &Constraint,
     ) -> anyhow::Result<SequenceRecognizer> {
-        if let Some(grm) = llg_grammar_from_constraint(constraint)? {
-            let factory = factory
-                .as_ref()
-                .ok_or_else(|| anyhow::anyhow!("No token environment (llg_factory) found."))?;
-            let llg = constraint_from_llg_grammar(factory, grm)?;
-            Ok(SequenceRecognizer::Llguidance(Box::new(llg)))
-        } else {
-            Ok(SequenceRecognizer::None)
+        if matches!(constraint, Constraint::None) {
+            return Ok(SequenceRecognizer::None);
+        }
+        let factory = factory
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No token environment (llg_factory) found."))?;
+        match cached_constraint(factory, constraint)? {
+            Some(llg) => Ok(SequenceRecognizer::Llguidance(Box::new(llg))),
+            None => Ok(SequenceRecognizer::None),
         }
     }
 
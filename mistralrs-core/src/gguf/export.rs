@@ -0,0 +1,440 @@
+//! Conversion of a loaded model to GGUF, in the layout llama.cpp expects for the `llama`
+//! architecture. This is the counterpart of the conversion done by `convert_hf_to_gguf.py`.
+
+use std::{f32::consts::PI, path::PathBuf};
+
+use candle_core::{quantized::gguf_file::Value, Device, Result, Tensor};
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+use crate::{
+    layers::{Llama3RopeConfig, Llama3RopeType},
+    pipeline::in_memory::read_model_file_to_string,
+};
+
+/// Model types of `config.json` which can be written as the GGUF `llama` architecture.
+const SUPPORTED_MODEL_TYPES: &[&str] = &["llama", "mistral"];
+
+// https://github.com/ggml-org/llama.cpp/blob/master/include/llama.h
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+const TOKEN_TYPE_BYTE: i32 = 6;
+
+#[derive(Deserialize)]
+pub(crate) struct GgufExportConfig {
+    pub(crate) model_type: String,
+    hidden_size: usize,
+    intermediate_size: usize,
+    vocab_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    head_dim: Option<usize>,
+    rms_norm_eps: f64,
+    rope_theta: Option<f32>,
+    max_position_embeddings: usize,
+    rope_scaling: Option<Llama3RopeConfig>,
+}
+
+impl GgufExportConfig {
+    pub(crate) fn from_config(config: &str) -> Result<Self> {
+        let cfg: Self = serde_json::from_str(config).map_err(candle_core::Error::msg)?;
+        if !SUPPORTED_MODEL_TYPES.contains(&cfg.model_type.as_str()) {
+            candle_core::bail!(
+                "Exporting `{}` models to GGUF is not supported, supported model types are {SUPPORTED_MODEL_TYPES:?}.",
+                cfg.model_type
+            );
+        }
+        Ok(cfg)
+    }
+
+    fn num_key_value_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    fn head_dim(&self) -> usize {
+        self.head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads)
+    }
+
+    fn rope_theta(&self) -> f32 {
+        self.rope_theta.unwrap_or(10000.)
+    }
+
+    /// The number of heads to permute the rows of the layer `name` over, see [`permute_qk`].
+    pub(crate) fn permuted_heads(&self, name: &str) -> Option<usize> {
+        if name.ends_with("self_attn.q_proj") {
+            Some(self.num_attention_heads)
+        } else if name.ends_with("self_attn.k_proj") {
+            Some(self.num_key_value_heads())
+        } else {
+            None
+        }
+    }
+
+    /// The `general.*` and `llama.*` metadata.
+    pub(crate) fn metadata(&self) -> Vec<(String, Value)> {
+        let mut metadata = vec![
+            ("general.architecture", Value::String("llama".to_string())),
+            (
+                "llama.context_length",
+                Value::U32(self.max_position_embeddings as u32),
+            ),
+            (
+                "llama.embedding_length",
+                Value::U32(self.hidden_size as u32),
+            ),
+            (
+                "llama.block_count",
+                Value::U32(self.num_hidden_layers as u32),
+            ),
+            (
+                "llama.feed_forward_length",
+                Value::U32(self.intermediate_size as u32),
+            ),
+            (
+                "llama.attention.head_count",
+                Value::U32(self.num_attention_heads as u32),
+            ),
+            (
+                "llama.attention.head_count_kv",
+                Value::U32(self.num_key_value_heads() as u32),
+            ),
+            (
+                "llama.attention.key_length",
+                Value::U32(self.head_dim() as u32),
+            ),
+            (
+                "llama.attention.value_length",
+                Value::U32(self.head_dim() as u32),
+            ),
+            (
+                "llama.attention.layer_norm_rms_epsilon",
+                Value::F32(self.rms_norm_eps as f32),
+            ),
+            ("llama.rope.freq_base", Value::F32(self.rope_theta())),
+            (
+                "llama.rope.dimension_count",
+                Value::U32(self.head_dim() as u32),
+            ),
+            ("llama.vocab_size", Value::U32(self.vocab_size as u32)),
+        ];
+        if let Some(Llama3RopeConfig {
+            rope_type: Llama3RopeType::Linear,
+            factor,
+            ..
+        }) = &self.rope_scaling
+        {
+            metadata.push((
+                "llama.rope.scaling.type",
+                Value::String("linear".to_string()),
+            ));
+            metadata.push(("llama.rope.scaling.factor", Value::F32(*factor)));
+        }
+        metadata
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect()
+    }
+
+    /// llama.cpp applies Llama 3 RoPE scaling by dividing the frequencies by the factors in the
+    /// `rope_freqs.weight` tensor.
+    pub(crate) fn rope_freqs(&self, device: &Device) -> Result<Option<Tensor>> {
+        let Some(Llama3RopeConfig {
+            rope_type: Llama3RopeType::Llama3,
+            factor,
+            low_freq_factor,
+            high_freq_factor,
+            original_max_position_embeddings,
+        }) = &self.rope_scaling
+        else {
+            return Ok(None);
+        };
+        let (Some(low_freq_factor), Some(high_freq_factor), Some(original_max_position_embeddings)) = (
+            low_freq_factor,
+            high_freq_factor,
+            original_max_position_embeddings,
+        ) else {
+            candle_core::bail!("Llama 3 RoPE scaling requires `low_freq_factor`, `high_freq_factor` and `original_max_position_embeddings`.");
+        };
+        let low_freq_wavelen = *original_max_position_embeddings as f32 / low_freq_factor;
+        let high_freq_wavelen = *original_max_position_embeddings as f32 / high_freq_factor;
+
+        let head_dim = self.head_dim();
+        let factors = (0..head_dim)
+            .step_by(2)
+            .map(|i| {
+                let freq = 1. / self.rope_theta().powf(i as f32 / head_dim as f32);
+                let wavelen = 2. * PI / freq;
+                if wavelen < high_freq_wavelen {
+                    1.
+                } else if wavelen > low_freq_wavelen {
+                    *factor
+                } else {
+                    let smooth = (*original_max_position_embeddings as f32 / wavelen
+                        - low_freq_factor)
+                        / (high_freq_factor - low_freq_factor);
+                    1. / ((1. - smooth) / factor + smooth)
+                }
+            })
+            .collect::<Vec<f32>>();
+        let len = factors.len();
+        Ok(Some(Tensor::from_vec(factors, len, device)?))
+    }
+}
+
+/// The GGUF name of the safetensors tensor `name`, for example `blk.0.attn_q.weight` for
+/// `model.layers.0.self_attn.q_proj.weight`.
+pub(crate) fn gguf_tensor_name(name: &str) -> Option<String> {
+    match name {
+        "model.embed_tokens.weight" => return Some("token_embd.weight".to_string()),
+        "model.norm.weight" => return Some("output_norm.weight".to_string()),
+        "lm_head.weight" => return Some("output.weight".to_string()),
+        _ => (),
+    }
+    let (layer, rest) = name.strip_prefix("model.layers.")?.split_once('.')?;
+    let layer = layer.parse::<usize>().ok()?;
+    let (module, suffix) = rest.rsplit_once('.')?;
+    let module = match module {
+        "input_layernorm" => "attn_norm",
+        "post_attention_layernorm" => "ffn_norm",
+        "self_attn.q_proj" => "attn_q",
+        "self_attn.k_proj" => "attn_k",
+        "self_attn.v_proj" => "attn_v",
+        "self_attn.o_proj" => "attn_output",
+        "mlp.gate_proj" => "ffn_gate",
+        "mlp.up_proj" => "ffn_up",
+        "mlp.down_proj" => "ffn_down",
+        _ => return None,
+    };
+    Some(format!("blk.{layer}.{module}.{suffix}"))
+}
+
+/// Hugging Face checkpoints split the rotary dimensions of each head in halves, while llama.cpp
+/// interleaves them. Reorder the rows (the first dimension) of a Q or K projection to match.
+pub(crate) fn permute_qk(xs: &Tensor, n_head: usize) -> Result<Tensor> {
+    let mut dims = vec![n_head, 2, xs.dim(0)? / n_head / 2];
+    dims.extend_from_slice(&xs.dims()[1..]);
+    xs.reshape(dims)?
+        .transpose(1, 2)?
+        .contiguous()?
+        .reshape(xs.shape())
+}
+
+fn read_json(path: &PathBuf) -> Result<serde_json::Value> {
+    serde_json::from_str(&read_model_file_to_string(path)?).map_err(candle_core::Error::msg)
+}
+
+/// The `tokenizer.*` metadata: the vocabulary, BOS/EOS tokens and the chat template.
+pub(crate) fn tokenizer_metadata(
+    tokenizer: &Tokenizer,
+    model_type: &str,
+    template_filename: &Option<PathBuf>,
+    generation_config: Option<&PathBuf>,
+) -> Result<Vec<(String, Value)>> {
+    let json: serde_json::Value = serde_json::from_str(
+        &tokenizer
+            .to_string(false)
+            .map_err(candle_core::Error::msg)?,
+    )
+    .map_err(candle_core::Error::msg)?;
+    let model = &json["model"];
+
+    let vocab_size = tokenizer.get_vocab_size(true);
+    let tokens = (0..vocab_size as u32)
+        .map(|id| {
+            tokenizer
+                .id_to_token(id)
+                .unwrap_or_else(|| format!("[PAD{id}]"))
+        })
+        .collect::<Vec<_>>();
+    let added = tokenizer.get_added_tokens_decoder();
+
+    let mut metadata = Vec::new();
+    let ggml_model = match model["type"].as_str() {
+        Some("BPE") if model["byte_fallback"].as_bool() == Some(true) => {
+            // SentencePiece BPE sorts its vocabulary by merge priority, which llama.cpp reads
+            // from the scores.
+            metadata.push((
+                "tokenizer.ggml.scores",
+                Value::Array((0..vocab_size).map(|id| Value::F32(-(id as f32))).collect()),
+            ));
+            "llama"
+        }
+        Some("BPE") => {
+            let merges = model["merges"]
+                .as_array()
+                .map(|merges| {
+                    merges
+                        .iter()
+                        .filter_map(|merge| match merge {
+                            serde_json::Value::String(merge) => Some(merge.clone()),
+                            serde_json::Value::Array(pair) => Some(format!(
+                                "{} {}",
+                                pair.first()?.as_str()?,
+                                pair.get(1)?.as_str()?
+                            )),
+                            _ => None,
+                        })
+                        .map(Value::String)
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            metadata.push(("tokenizer.ggml.merges", Value::Array(merges)));
+            // The pre-tokenizer is selected by name rather than stored in the file.
+            let pre = match model_type {
+                "llama" => "llama-bpe",
+                "mistral" => "tekken",
+                _ => "default",
+            };
+            metadata.push(("tokenizer.ggml.pre", Value::String(pre.to_string())));
+            "gpt2"
+        }
+        Some("Unigram") => {
+            let scores = model["vocab"]
+                .as_array()
+                .map(|vocab| {
+                    vocab
+                        .iter()
+                        .map(|piece| Value::F32(piece[1].as_f64().unwrap_or(0.) as f32))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            metadata.push(("tokenizer.ggml.scores", Value::Array(scores)));
+            "llama"
+        }
+        other => {
+            candle_core::bail!("Exporting a `{other:?}` tokenizer to GGUF is not supported.")
+        }
+    };
+
+    let token_types = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| {
+            let ty = match added.get(&(id as u32)) {
+                Some(token) if token.special => TOKEN_TYPE_CONTROL,
+                Some(_) => TOKEN_TYPE_USER_DEFINED,
+                None if ggml_model == "llama"
+                    && token.len() == 6
+                    && token.starts_with("<0x")
+                    && token.ends_with('>') =>
+                {
+                    TOKEN_TYPE_BYTE
+                }
+                None => TOKEN_TYPE_NORMAL,
+            };
+            Value::I32(ty)
+        })
+        .collect::<Vec<_>>();
+    metadata.push(("tokenizer.ggml.token_type", Value::Array(token_types)));
+
+    // Prefer the IDs of the generation config, and fall back to the tokens named by the
+    // tokenizer config.
+    let gen_conf = generation_config.map(read_json).transpose()?;
+    let tokenizer_config = match template_filename {
+        Some(path) if path.extension().is_some_and(|ext| ext == "json") => Some(read_json(path)?),
+        _ => None,
+    };
+    let special_id = |id_key: &str, token_key: &str| {
+        let from_gen_conf = gen_conf.as_ref().and_then(|conf| match &conf[id_key] {
+            serde_json::Value::Array(ids) => ids.first()?.as_u64(),
+            id => id.as_u64(),
+        });
+        from_gen_conf.map(|id| id as u32).or_else(|| {
+            let token = &tokenizer_config.as_ref()?[token_key];
+            let token = token.as_str().or_else(|| token["content"].as_str())?;
+            tokenizer.token_to_id(token)
+        })
+    };
+    let bos = special_id("bos_token_id", "bos_token");
+    if let Some(bos) = bos {
+        metadata.push(("tokenizer.ggml.bos_token_id", Value::U32(bos)));
+    }
+    if let Some(eos) = special_id("eos_token_id", "eos_token") {
+        metadata.push(("tokenizer.ggml.eos_token_id", Value::U32(eos)));
+    }
+    if let Some(unk) = model["unk_token"]
+        .as_str()
+        .and_then(|token| tokenizer.token_to_id(token))
+        .or_else(|| model["unk_id"].as_u64().map(|id| id as u32))
+    {
+        metadata.push(("tokenizer.ggml.unknown_token_id", Value::U32(unk)));
+    }
+    // The post-processor decides whether BOS is added, so check it on an empty input.
+    let encoding = tokenizer
+        .encode("", true)
+        .map_err(candle_core::Error::msg)?;
+    let add_bos = bos.is_some_and(|bos| encoding.get_ids().first() == Some(&bos));
+    metadata.push(("tokenizer.ggml.add_bos_token", Value::Bool(add_bos)));
+
+    let chat_template = match template_filename {
+        Some(path) if path.extension().is_some_and(|ext| ext == "jinja") => {
+            Some(read_model_file_to_string(path)?)
+        }
+        _ => tokenizer_config
+            .as_ref()
+            .and_then(|conf| match &conf["chat_template"] {
+                serde_json::Value::String(template) => Some(template.clone()),
+                serde_json::Value::Array(templates) => templates
+                    .iter()
+                    .find(|t| t["name"] == "default")
+                    .or(templates.first())
+                    .and_then(|t| t["template"].as_str())
+                    .map(ToString::to_string),
+                _ => None,
+            }),
+    };
+    if let Some(chat_template) = chat_template {
+        metadata.push(("tokenizer.chat_template", Value::String(chat_template)));
+    }
+
+    metadata.push((
+        "tokenizer.ggml.tokens",
+        Value::Array(tokens.into_iter().map(Value::String).collect()),
+    ));
+    metadata.push((
+        "tokenizer.ggml.model",
+        Value::String(ggml_model.to_string()),
+    ));
+    Ok(metadata
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{gguf_tensor_name, permute_qk};
+
+    #[test]
+    fn maps_tensor_names() {
+        assert_eq!(
+            gguf_tensor_name("model.layers.3.self_attn.q_proj.weight").as_deref(),
+            Some("blk.3.attn_q.weight")
+        );
+        assert_eq!(
+            gguf_tensor_name("model.layers.0.post_attention_layernorm.weight").as_deref(),
+            Some("blk.0.ffn_norm.weight")
+        );
+        assert_eq!(
+            gguf_tensor_name("lm_head.weight").as_deref(),
+            Some("output.weight")
+        );
+        assert_eq!(
+            gguf_tensor_name("model.layers.0.mlp.experts.0.w1.weight"),
+            None
+        );
+    }
+
+    #[test]
+    fn interleaves_rotary_halves() {
+        // One head of dimension 4: rows [a0, a1, b0, b1] become [a0, b0, a1, b1].
+        let xs = Tensor::new(&[0f32, 1., 2., 3.], &Device::Cpu).unwrap();
+        let permuted = permute_qk(&xs, 1).unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(permuted, [0., 2., 1., 3.]);
+    }
+}
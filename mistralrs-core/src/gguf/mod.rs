@@ -1,11 +1,13 @@
 mod chat_template;
 mod content;
+mod export;
 mod gguf_tokenizer;
 use strum::EnumString;
 
 use anyhow::{Context, Result};
 pub(crate) use chat_template::get_gguf_chat_template;
 pub(crate) use content::Content;
+pub(crate) use export::{gguf_tensor_name, permute_qk, tokenizer_metadata, GgufExportConfig};
 pub(crate) use gguf_tokenizer::{convert_gguf_to_hf_tokenizer, GgufTokenizerConversion};
 use std::str::FromStr;
 
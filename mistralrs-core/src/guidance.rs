@@ -0,0 +1,106 @@
+//! Classifier-free guidance: the logits of a sequence are pushed away from the logits of the same
+//! completion after a negative prompt, which is run alongside the sequence.
+
+use candle_core::{DType, IndexOp, Result, Tensor, D};
+
+use crate::{
+    pipeline::{ForwardInputsResult, InputProcessorOutput, Pipeline},
+    sequence::Sequence,
+};
+
+/// The negative prompt of a sequence, followed by the tokens generated for the sequence.
+pub(crate) struct Guidance {
+    pub(crate) seq: Box<Sequence>,
+    scale: f32,
+    /// Whether the negative prompt was run, after which only the last generated token is.
+    prefilled: bool,
+}
+
+impl Guidance {
+    pub(crate) fn new(seq: Sequence, scale: f32) -> Self {
+        Self {
+            seq: Box::new(seq),
+            scale,
+            prefilled: false,
+        }
+    }
+
+    /// Run the model on the negative prompt, or on the last generated token after it, and combine
+    /// its logits with the `logits` of the sequence. This uses the model cache, so the caches of
+    /// the sequences of the batch must have been cloned out of it.
+    pub(crate) fn apply<P: Pipeline + ?Sized>(
+        &mut self,
+        pipeline: &mut P,
+        logits: &Tensor,
+    ) -> Result<Tensor> {
+        let seqs = &mut [&mut *self.seq];
+        mistralrs_quant::set_lora_batch(vec![seqs[0].adapters().map(|a| a.to_vec())]);
+        let inputs = pipeline.get_processor().inputs_processor().process_inputs(
+            pipeline.tokenizer(),
+            seqs,
+            !self.prefilled,
+            pipeline.get_metadata().is_xlora,
+            &pipeline.device(),
+            pipeline.get_metadata().no_kv_cache,
+            None,
+            false,
+            pipeline.get_input_processor_config(),
+            None,
+            pipeline.device_mapper(),
+        );
+        let InputProcessorOutput { inputs, .. } = inputs.map_err(candle_core::Error::msg)?;
+
+        if self.prefilled {
+            pipeline.clone_in_cache(seqs);
+        } else {
+            pipeline.set_none_cache(seqs, false, false, false);
+        }
+        let result = pipeline.forward_inputs(inputs, false)?;
+        pipeline.clone_out_cache(seqs);
+        self.prefilled = true;
+
+        let ForwardInputsResult::CausalGeneration { logits: negative } = result else {
+            candle_core::bail!("Classifier-free guidance requires a text generation model");
+        };
+        let negative = negative.i(0)?.to_device(logits.device())?;
+        combine(logits, &negative, self.scale)
+    }
+}
+
+/// `negative + scale * (logits - negative)` on the log probabilities, so a scale of 1 leaves the
+/// logits unchanged and larger scales move them further from the negative prompt.
+fn combine(logits: &Tensor, negative: &Tensor, scale: f32) -> Result<Tensor> {
+    let dtype = logits.dtype();
+    let logits = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+    let negative = candle_nn::ops::log_softmax(&negative.to_dtype(DType::F32)?, D::Minus1)?;
+    ((logits - &negative)?.affine(scale as f64, 0.)? + negative)?.to_dtype(dtype)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor, D};
+
+    use super::combine;
+
+    #[test]
+    fn moves_away_from_the_negative_prompt() {
+        let logits = Tensor::new(&[2f32, 1., 0.], &Device::Cpu).unwrap();
+        let negative = Tensor::new(&[2f32, 0., 0.], &Device::Cpu).unwrap();
+        let log_probs = |t: &Tensor| {
+            candle_nn::ops::log_softmax(t, D::Minus1)
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+        };
+
+        let unchanged = combine(&logits, &negative, 1.).unwrap();
+        for (a, b) in log_probs(&unchanged).iter().zip(log_probs(&logits)) {
+            assert!((a - b).abs() < 1e-5);
+        }
+
+        // The token which is more likely after the prompt than after the negative prompt gains
+        let guided = log_probs(&combine(&logits, &negative, 3.).unwrap());
+        assert!(guided[1] > log_probs(&logits)[1]);
+        assert!(guided[2] < log_probs(&logits)[2]);
+    }
+}
@@ -0,0 +1,257 @@
+use candle_core::{DType, Device, Result, Tensor, D};
+
+use crate::attention::SdpaParams;
+
+use super::SingleCache;
+
+/// The most elements of the attention matrix computed at once when collecting the scores.
+const MAX_ATTENTION_ELEMS: usize = 1 << 24;
+
+/// Settings of the bounded KV cache, which evicts the tokens with the lowest accumulated attention
+/// of each KV head (H2O, <https://arxiv.org/abs/2306.14048>) once a sequence holds more than
+/// `budget` tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct H2oConfig {
+    /// The most tokens kept in the KV cache of each layer.
+    pub budget: usize,
+    /// The number of most recent tokens which are never evicted.
+    pub recent: usize,
+}
+
+impl H2oConfig {
+    /// Keep at most `budget` tokens, half of which are the most recent tokens.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            recent: budget / 2,
+        }
+    }
+
+    /// Set the number of most recent tokens which are never evicted.
+    pub fn with_recent(mut self, recent: usize) -> Self {
+        self.recent = recent.min(self.budget);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct H2oCache {
+    pub k: SingleCache,
+    pub v: SingleCache,
+    /// The accumulated attention of each cached token, of shape (bs, num_kv_heads, seq_len). This
+    /// is only set if the model reports its attention, otherwise the oldest tokens are evicted.
+    pub scores: SingleCache,
+    pub config: H2oConfig,
+    /// The number of tokens evicted so far.
+    pub evicted: usize,
+}
+
+impl H2oCache {
+    pub fn new(k: SingleCache, v: SingleCache, config: H2oConfig) -> Self {
+        let scores = SingleCache::new(k.dim, k.max_seq_len, k.capacity_seq_len);
+        Self {
+            k,
+            v,
+            scores,
+            config,
+            evicted: 0,
+        }
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.k.current_seq_len()
+    }
+
+    pub fn reset(&mut self) {
+        self.k.reset();
+        self.v.reset();
+        self.scores.reset();
+        self.evicted = 0;
+    }
+
+    pub fn try_set_len(&self, len: usize) -> Result<()> {
+        if self.evicted > 0 {
+            candle_core::bail!(
+                "H2O KV cache has evicted {} tokens, so it cannot be reset to len {len}",
+                self.evicted
+            );
+        }
+        self.k.try_set_len(len)
+    }
+
+    pub fn set_len(&mut self, len: usize) -> Result<()> {
+        self.try_set_len(len)?;
+        self.k.set_len(len)?;
+        self.v.set_len(len)?;
+        self.scores.current_seq_len = self.scores.current_seq_len.min(len);
+        Ok(())
+    }
+
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<()> {
+        // Only evict before decoding a single token, which needs no mask. The mask of a prompt is
+        // built from the cache length before the tokens are added.
+        if k.dim(self.k.dim)? == 1 && self.k.current_seq_len >= self.config.budget {
+            self.evict(self.config.budget.saturating_sub(1))?;
+        }
+        self.k.append(k)?;
+        self.v.append(v)
+    }
+
+    /// Add the attention of the queries `q` (bs, num_heads, q_len, head_dim), which are the last
+    /// tokens of the cache, over the cached keys to the scores.
+    pub fn observe_attention(&mut self, q: &Tensor, sdpa_params: &SdpaParams) -> Result<()> {
+        let Some(k) = self.k.current_data()? else {
+            return Ok(());
+        };
+        let k = k.to_dtype(DType::F32)?;
+        let (b_sz, n_kv_heads, kv_len, head_dim) = k.dims4()?;
+        let (_, n_heads, q_len, _) = q.dims4()?;
+        let n_groups = n_heads / n_kv_heads;
+        let q = q.to_dtype(DType::F32)?.contiguous()?;
+
+        let chunk_len = (MAX_ATTENTION_ELEMS / (n_heads * kv_len)).max(1);
+        let mut observed = Tensor::zeros((b_sz, n_kv_heads, kv_len), DType::F32, q.device())?;
+        for start in (0..q_len).step_by(chunk_len) {
+            let len = chunk_len.min(q_len - start);
+            // The queries of a group attend to the same keys, so they are stacked for each KV head
+            let q =
+                q.narrow(2, start, len)?
+                    .reshape((b_sz, n_kv_heads, n_groups * len, head_dim))?;
+            let mut att = (q.matmul(&k.t()?)? * f64::from(sdpa_params.softmax_scale))?;
+            if let Some(softcap) = sdpa_params.softcap {
+                att = ((att / f64::from(softcap))?.tanh()? * f64::from(softcap))?;
+            }
+            let mut att = att.reshape((b_sz, n_kv_heads, n_groups, len, kv_len))?;
+            if q_len > 1 {
+                let offset = kv_len - q_len + start;
+                let mask = (0..len)
+                    .flat_map(|i| {
+                        (0..kv_len).map(move |j| {
+                            if j > offset + i {
+                                f32::NEG_INFINITY
+                            } else {
+                                0.
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                let mask = Tensor::from_vec(mask, (len, kv_len), q.device())?;
+                att = att.broadcast_add(&mask)?;
+            }
+            let att = candle_nn::ops::softmax_last_dim(&att.contiguous()?)?;
+            observed = (observed + att.sum(3)?.sum(2)?)?;
+        }
+
+        if self.scores.current_seq_len < kv_len {
+            let new = kv_len - self.scores.current_seq_len;
+            self.scores.append(&Tensor::zeros(
+                (b_sz, n_kv_heads, new),
+                DType::F32,
+                q.device(),
+            )?)?;
+        }
+        let scores = (self.scores.current_data()?.unwrap() + observed)?;
+        self.scores
+            .all_data
+            .as_ref()
+            .unwrap()
+            .slice_set(&scores, self.scores.dim, 0)?;
+        Ok(())
+    }
+
+    /// Evict tokens until `target` are left, keeping the most recent ones and those with the
+    /// highest scores of each KV head.
+    fn evict(&mut self, target: usize) -> Result<()> {
+        let len = self.k.current_seq_len;
+        let (Some(k), Some(v)) = (self.k.current_data()?, self.v.current_data()?) else {
+            return Ok(());
+        };
+        if len <= target {
+            return Ok(());
+        }
+        let dim = self.k.dim;
+        let recent = self.config.recent.min(target);
+
+        let scores = self
+            .scores
+            .current_data()?
+            .filter(|_| self.scores.current_seq_len == len);
+        let (k, v, scores) = match scores {
+            Some(scores) => {
+                let (b_sz, n_kv_heads, _) = scores.dims3()?;
+                let per_head = scores.to_device(&Device::Cpu)?.to_vec3::<f32>()?;
+                let indices = per_head
+                    .iter()
+                    .flatten()
+                    .flat_map(|scores| kept_indices(scores, len - recent, target - recent))
+                    .collect::<Vec<_>>();
+                let indices = Tensor::from_vec(indices, (b_sz, n_kv_heads, target), k.device())?;
+                let gather = |xs: &Tensor| {
+                    let (b_sz, n_kv_heads, _, head_dim) = xs.dims4()?;
+                    let indices = indices
+                        .unsqueeze(D::Minus1)?
+                        .broadcast_as((b_sz, n_kv_heads, target, head_dim))?
+                        .contiguous()?;
+                    xs.contiguous()?.gather(&indices, dim)
+                };
+                (
+                    gather(&k)?,
+                    gather(&v)?,
+                    Some(scores.contiguous()?.gather(&indices, dim)?),
+                )
+            }
+            // Without the attention of the model, evict the oldest tokens
+            None => (
+                k.narrow(dim, len - target, target)?.copy()?,
+                v.narrow(dim, len - target, target)?.copy()?,
+                None,
+            ),
+        };
+
+        self.k.all_data.as_ref().unwrap().slice_set(&k, dim, 0)?;
+        self.v.all_data.as_ref().unwrap().slice_set(&v, dim, 0)?;
+        self.k.current_seq_len = target;
+        self.v.current_seq_len = target;
+        match scores {
+            Some(scores) => {
+                self.scores
+                    .all_data
+                    .as_ref()
+                    .unwrap()
+                    .slice_set(&scores, dim, 0)?;
+                self.scores.current_seq_len = target;
+            }
+            None => self.scores.reset(),
+        }
+        self.evicted += len - target;
+        Ok(())
+    }
+}
+
+/// The indices of the `n_heavy` tokens with the highest scores among the first `n_old`, followed by
+/// all later tokens, in order.
+fn kept_indices(scores: &[f32], n_old: usize, n_heavy: usize) -> Vec<u32> {
+    let mut heavy = (0..n_old).collect::<Vec<_>>();
+    // The sort is stable, so older tokens are kept on ties
+    heavy.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+    heavy.truncate(n_heavy);
+    heavy.sort_unstable();
+    heavy
+        .into_iter()
+        .chain(n_old..scores.len())
+        .map(|i| i as u32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::kept_indices;
+
+    #[test]
+    fn keeps_heavy_hitters_and_recent_tokens() {
+        let scores = [0.5, 3., 0.1, 2., 0.2, 0.];
+        assert_eq!(kept_indices(&scores, 4, 2), vec![1, 3, 4, 5]);
+        assert_eq!(kept_indices(&scores, 6, 0), Vec::<u32>::new());
+        assert_eq!(kept_indices(&[1., 1., 1.], 3, 2), vec![0, 1]);
+    }
+}
@@ -1,18 +1,21 @@
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use candle_core::{Result, Tensor, D};
+use candle_core::{Device, Result, Tensor, D};
 
 use crate::{
+    attention::SdpaParams,
     get_mut_arcmutex,
     pipeline::{CacheManagerMixin, MetadataMixin},
     sequence::Sequence,
 };
 
 mod full_cache;
+mod h2o_cache;
 mod rotating_cache;
 mod single_cache;
 
 pub use full_cache::{EitherCache, LayerCaches};
+pub use h2o_cache::{H2oCache, H2oConfig};
 pub use rotating_cache::RotatingCache;
 pub use single_cache::SingleCache;
 
@@ -37,6 +40,7 @@ pub trait CacheManager<T: CacheManagerMixin + MetadataMixin + ?Sized> {
 pub enum KvCache {
     Normal { k: SingleCache, v: SingleCache },
     Rotating { k: RotatingCache, v: RotatingCache },
+    H2o(H2oCache),
 }
 
 impl KvCache {
@@ -56,6 +60,7 @@ impl KvCache {
         match self {
             Self::Normal { k, .. } => k.current_data(),
             Self::Rotating { k, .. } => k.current_data(),
+            Self::H2o(cache) => cache.k.current_data(),
         }
     }
 
@@ -63,6 +68,7 @@ impl KvCache {
         match self {
             Self::Normal { v, .. } => v.current_data(),
             Self::Rotating { v, .. } => v.current_data(),
+            Self::H2o(cache) => cache.v.current_data(),
         }
     }
 
@@ -80,6 +86,10 @@ impl KvCache {
                 let out_v = vc.append(&v)?;
                 (Some(out_k), Some(out_v))
             }
+            Self::H2o(cache) => {
+                cache.append(&k, &v)?;
+                (cache.k.current_data()?, cache.v.current_data()?)
+            }
         };
         let k = match out_k {
             None => {
@@ -87,6 +97,7 @@ impl KvCache {
                 match self {
                     Self::Normal { k, .. } => shape[k.dim] = 0,
                     Self::Rotating { k, .. } => shape[k.dim] = 0,
+                    Self::H2o(cache) => shape[cache.k.dim] = 0,
                 }
                 Tensor::zeros(shape, k.dtype(), k.device())?
             }
@@ -98,6 +109,7 @@ impl KvCache {
                 match self {
                     Self::Normal { v, .. } => shape[v.dim] = 0,
                     Self::Rotating { v, .. } => shape[v.dim] = 0,
+                    Self::H2o(cache) => shape[cache.v.dim] = 0,
                 }
                 Tensor::zeros(shape, v.dtype(), v.device())?
             }
@@ -110,6 +122,7 @@ impl KvCache {
         match self {
             Self::Normal { k, .. } => k.current_seq_len(),
             Self::Rotating { k, .. } => k.current_seq_len(),
+            Self::H2o(cache) => cache.current_seq_len(),
         }
     }
 
@@ -123,6 +136,7 @@ impl KvCache {
                 k.reset();
                 v.reset();
             }
+            Self::H2o(cache) => cache.reset(),
         }
     }
 
@@ -139,6 +153,7 @@ impl KvCache {
                 v.set_len(len)?;
                 Ok(())
             }
+            Self::H2o(cache) => cache.set_len(len),
         }
     }
 
@@ -154,12 +169,79 @@ impl KvCache {
                 v.try_set_len(len)?;
                 Ok(())
             }
+            Self::H2o(cache) => cache.try_set_len(len),
         }
     }
 
     pub fn is_rotating(&self) -> bool {
         matches!(self, Self::Rotating { .. })
     }
+
+    /// Add the attention of the queries `q` (bs, num_heads, q_len, head_dim) over the cached keys
+    /// to the scores of an H2O cache, which decide the tokens to evict. This does nothing for other
+    /// caches, so models call it after appending to the cache.
+    pub fn observe_attention(&mut self, q: &Tensor, sdpa_params: &SdpaParams) -> Result<()> {
+        match self {
+            Self::H2o(cache) => cache.observe_attention(q, sdpa_params),
+            Self::Normal { .. } | Self::Rotating { .. } => Ok(()),
+        }
+    }
+
+    /// Copy the cache to `device`.
+    pub fn to_device(&self, device: &Device) -> Result<Self> {
+        let to_device = |data: &mut Option<Tensor>| -> Result<()> {
+            *data = data.as_ref().map(|x| x.to_device(device)).transpose()?;
+            Ok(())
+        };
+        let mut cache = self.clone();
+        match &mut cache {
+            Self::Normal { k, v } => {
+                to_device(&mut k.all_data)?;
+                to_device(&mut v.all_data)?;
+            }
+            Self::Rotating { k, v } => {
+                to_device(&mut k.all_data)?;
+                to_device(&mut v.all_data)?;
+            }
+            Self::H2o(cache) => {
+                to_device(&mut cache.k.all_data)?;
+                to_device(&mut cache.v.all_data)?;
+                to_device(&mut cache.scores.all_data)?;
+            }
+        }
+        Ok(cache)
+    }
+}
+
+/// Move the KV caches of `seqs` to the devices of the layers of `pipeline`. This hands the
+/// sequences from a pipeline of a model to another pipeline of the same model on other devices.
+pub(crate) fn move_seq_caches<T: MetadataMixin + ?Sized>(
+    pipeline: &T,
+    seqs: &mut [&mut Sequence],
+) -> Result<()> {
+    let device = pipeline.device();
+    let mapper = pipeline.device_mapper();
+    let layer_device = |layer: usize| {
+        mapper
+            .and_then(|mapper| mapper.device_for(layer, false))
+            .unwrap_or(&device)
+    };
+    for seq in seqs.iter_mut() {
+        for (layer, cache) in seq.normal_cache().iter_mut().enumerate() {
+            if let Some(cache) = cache {
+                *cache = cache.to_device(layer_device(layer))?;
+            }
+        }
+        for (layer, cache) in seq.cache().iter_mut().enumerate() {
+            if let Some((k, v)) = cache {
+                *cache = Some((
+                    k.to_device(layer_device(layer))?,
+                    v.to_device(layer_device(layer))?,
+                ));
+            }
+        }
+    }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
@@ -225,6 +307,16 @@ impl NormalCache {
         }
         Arc::new(Mutex::new(Self(caches)))
     }
+
+    /// Bound the full attention layers with an H2O cache. Sliding window layers are already
+    /// bounded, so they are unchanged.
+    pub fn enable_h2o(&mut self, config: H2oConfig) {
+        for cache in self.0.iter_mut() {
+            if let KvCache::Normal { k, v } = cache {
+                *cache = KvCache::H2o(H2oCache::new(k.clone(), v.clone(), config));
+            }
+        }
+    }
 }
 
 pub struct NormalCacheManager;
@@ -238,12 +330,13 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
     ) {
         let mut new_k_cache = Vec::new();
         let mut new_v_cache = Vec::new();
+        let mut new_s_cache = Vec::new();
 
         for layer in 0..pipeline.get_metadata().num_hidden_layers {
             // Preallocate combined k and v caches across all sequences, avoiding Tensor::cat copies
             let batch_len = seqs.len();
             // Use the first sequence as template
-            let (first_k, first_v) = {
+            let (first_k, first_v, first_s) = {
                 let src_cache = if modify_draft_cache {
                     seqs[0].normal_draft_cache()
                 } else {
@@ -253,15 +346,25 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     // This is hit in gemma3n for the shared kv cache
                     new_k_cache.push(None);
                     new_v_cache.push(None);
+                    new_s_cache.push(None);
                     continue;
                 };
                 match cache {
-                    KvCache::Normal { k, v } => {
-                        (k.all_data.clone().unwrap(), v.all_data.clone().unwrap())
-                    }
-                    KvCache::Rotating { k, v } => {
-                        (k.all_data.clone().unwrap(), v.all_data.clone().unwrap())
-                    }
+                    KvCache::Normal { k, v } => (
+                        k.all_data.clone().unwrap(),
+                        v.all_data.clone().unwrap(),
+                        None,
+                    ),
+                    KvCache::Rotating { k, v } => (
+                        k.all_data.clone().unwrap(),
+                        v.all_data.clone().unwrap(),
+                        None,
+                    ),
+                    KvCache::H2o(cache) => (
+                        cache.k.all_data.clone().unwrap(),
+                        cache.v.all_data.clone().unwrap(),
+                        cache.scores.all_data.clone(),
+                    ),
                 }
             };
             // Build dims for batched cache
@@ -271,6 +374,11 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
             dims_v[0] *= batch_len;
             let batch_k = Tensor::zeros(dims_k.clone(), first_k.dtype(), first_k.device()).unwrap();
             let batch_v = Tensor::zeros(dims_v.clone(), first_v.dtype(), first_v.device()).unwrap();
+            let batch_s = first_s.as_ref().map(|first_s| {
+                let mut dims_s = first_s.dims().to_vec();
+                dims_s[0] *= batch_len;
+                Tensor::zeros(dims_s, first_s.dtype(), first_s.device()).unwrap()
+            });
             // Fill each sequence's cache slice
             for (i, seq) in seqs.iter_mut().enumerate() {
                 let src_cache = if modify_draft_cache {
@@ -289,6 +397,15 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     KvCache::Rotating { k, v } => {
                         (k.all_data.clone().unwrap(), v.all_data.clone().unwrap())
                     }
+                    KvCache::H2o(cache) => {
+                        if let (Some(batch_s), Some(src_s)) = (&batch_s, &cache.scores.all_data) {
+                            batch_s.slice_set(src_s, 0, i * src_s.dims()[0]).unwrap();
+                        }
+                        (
+                            cache.k.all_data.clone().unwrap(),
+                            cache.v.all_data.clone().unwrap(),
+                        )
+                    }
                 };
                 let offset = i * first_k.dims()[0];
                 batch_k.slice_set(&src_k, 0, offset).unwrap();
@@ -296,6 +413,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
             }
             new_k_cache.push(Some(batch_k));
             new_v_cache.push(Some(batch_v));
+            new_s_cache.push(batch_s);
         }
 
         let seq0_cache = if modify_draft_cache {
@@ -305,7 +423,11 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
         };
 
         let mut caches = Vec::new();
-        for (layer_idx, (k_cache, v_cache)) in new_k_cache.into_iter().zip(new_v_cache).enumerate()
+        for (layer_idx, ((k_cache, v_cache), s_cache)) in new_k_cache
+            .into_iter()
+            .zip(new_v_cache)
+            .zip(new_s_cache)
+            .enumerate()
         {
             // Use this for the various parameters. Assumes all seqs are from one model.
             let Some(cache_ref) = seq0_cache[layer_idx].as_ref() else {
@@ -379,6 +501,13 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                         },
                     });
                 }
+                KvCache::H2o(old) => {
+                    let mut cache = old.clone();
+                    cache.k.all_data = k_cache.map(|x| x.contiguous().unwrap());
+                    cache.v.all_data = v_cache.map(|x| x.contiguous().unwrap());
+                    cache.scores.all_data = s_cache;
+                    caches.push(KvCache::H2o(cache));
+                }
             }
         }
         *pipeline.cache().normal() = NormalCache(caches);
@@ -399,12 +528,24 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                 KvCache::Rotating { k, v } => {
                     (k.all_data.clone().unwrap(), v.all_data.clone().unwrap())
                 }
+                KvCache::H2o(cache) => (
+                    cache.k.all_data.clone().unwrap(),
+                    cache.v.all_data.clone().unwrap(),
+                ),
             };
 
             let k_caches = k_cache.chunk(seqs.len(), 0).unwrap();
             debug_assert_eq!(k_caches.len(), seqs.len());
             let v_caches = v_cache.chunk(seqs.len(), 0).unwrap();
             debug_assert_eq!(v_caches.len(), seqs.len());
+            let s_caches = match cache {
+                KvCache::H2o(cache) => cache
+                    .scores
+                    .all_data
+                    .as_ref()
+                    .map(|s| s.chunk(seqs.len(), 0).unwrap()),
+                KvCache::Normal { .. } | KvCache::Rotating { .. } => None,
+            };
 
             for (seq_i, seq) in seqs.iter_mut().enumerate() {
                 let output_cache = if modify_draft_cache {
@@ -461,6 +602,13 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                             },
                         });
                     }
+                    KvCache::H2o(cache) => {
+                        let mut seq_h2o = cache.clone();
+                        seq_h2o.k.all_data = Some(k);
+                        seq_h2o.v.all_data = Some(v);
+                        seq_h2o.scores.all_data = s_caches.as_ref().map(|s| s[seq_i].clone());
+                        *seq_cache = Some(KvCache::H2o(seq_h2o));
+                    }
                 }
             }
         }
@@ -575,6 +723,29 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     };
                     *layer = cache;
                 }
+                KvCache::H2o(old) => {
+                    let template_cache_dim = old.k.dim;
+                    let template_cache_msl = old.k.max_seq_len;
+
+                    let cache = H2oCache::new(
+                        SingleCache {
+                            all_data: Some(k_cache.zeros_like().unwrap()),
+                            dim: template_cache_dim,
+                            current_seq_len: 0,
+                            max_seq_len: template_cache_msl,
+                            capacity_seq_len: k_cache.dims()[template_cache_dim],
+                        },
+                        SingleCache {
+                            all_data: Some(v_cache.zeros_like().unwrap()),
+                            dim: template_cache_dim,
+                            current_seq_len: 0,
+                            max_seq_len: template_cache_msl,
+                            capacity_seq_len: k_cache.dims()[template_cache_dim],
+                        },
+                        old.config,
+                    );
+                    *layer = KvCache::H2o(cache);
+                }
             }
         }
     }
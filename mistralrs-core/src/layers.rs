@@ -51,6 +51,12 @@ pub fn embedding(
     } else {
         vb.get_with_hints((in_size, out_size), "weight", Default::default())?
     };
+    let embeddings = mistralrs_quant::apply_modules_to_save(
+        &vb,
+        embeddings,
+        (in_size, out_size),
+        Default::default(),
+    )?;
     Ok(Embedding::new(embeddings, out_size))
 }
 
@@ -178,12 +184,14 @@ pub fn conv1d_no_bias(
 
 pub fn linear(in_dim: usize, out_dim: usize, vb: ShardedVarBuilder) -> Result<Linear> {
     let ws = vb.get((out_dim, in_dim), "weight")?;
+    let ws = mistralrs_quant::merge_lora_weights(&vb, ws, in_dim, out_dim, Default::default())?;
     let bs = vb.get(out_dim, "bias")?;
     Ok(Linear::new(ws, Some(bs)))
 }
 
 pub fn linear_no_bias(in_dim: usize, out_dim: usize, vb: ShardedVarBuilder) -> Result<Linear> {
     let ws = vb.get((out_dim, in_dim), "weight")?;
+    let ws = mistralrs_quant::merge_lora_weights(&vb, ws, in_dim, out_dim, Default::default())?;
     Ok(Linear::new(ws, None))
 }
 
@@ -2466,6 +2474,9 @@ pub struct Mlp {
     pub down: Arc<dyn QuantMethod>,
     act: Activation,
     params: Vec<usize>,
+    /// The module names of `gate`, `up`, and `down`, which have none if they were split from a
+    /// merged module.
+    names: [Option<&'static str>; 3],
 }
 
 impl Mlp {
@@ -2504,6 +2515,7 @@ impl Mlp {
             )?,
             act: hidden_act,
             params: vec![hidden_size, intermediate_size],
+            names: [Some("gate_proj"), Some("up_proj"), Some("down_proj")],
         })
     }
 
@@ -2540,6 +2552,7 @@ impl Mlp {
             )?,
             act: hidden_act,
             params: vec![hidden_size, intermediate_size],
+            names: [None, None, Some("down_proj")],
         })
     }
 
@@ -2591,6 +2604,14 @@ impl MlpLayer for Mlp {
     fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
         vec![&mut self.gate, &mut self.up, &mut self.down]
     }
+    fn get_named_isq_layers(&mut self) -> Vec<(&mut Arc<dyn QuantMethod>, Option<&'static str>)> {
+        let [gate, up, down] = self.names;
+        vec![
+            (&mut self.gate, gate),
+            (&mut self.up, up),
+            (&mut self.down, down),
+        ]
+    }
     fn clone(&self) -> Box<dyn MlpLayer> {
         Box::new(Clone::clone(self))
     }
@@ -2624,6 +2645,7 @@ impl MlpLayer for Mlp {
             down,
             act: self.act,
             params: self.params.clone(),
+            names: self.names,
         }))
     }
 
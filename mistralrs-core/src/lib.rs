@@ -7,6 +7,7 @@ pub use engine::{
 };
 use hf_hub::Cache;
 pub use lora::Ordering;
+use pipeline::EitherCache;
 pub use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
 #[cfg(feature = "pyo3_macros")]
@@ -19,9 +20,13 @@ use std::{
     error::Error,
     fs::OpenOptions,
     io::Write,
-    sync::{atomic::AtomicBool, Arc, Mutex, RwLock},
+    path::PathBuf,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Mutex, RwLock,
+    },
     thread::{self, JoinHandle},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::{channel, Sender};
 use tracing::info;
@@ -45,15 +50,19 @@ pub use toml_selector::{get_toml_selected_model_device_map_params, get_toml_sele
 
 mod amoe;
 mod attention;
+mod banned_strings;
+mod contrastive;
 mod diffusion_models;
 pub mod distributed;
 mod embedding;
 mod gguf;
+mod guidance;
 pub mod layers;
 mod layers_masker;
 mod layers_utils;
 pub mod matformer;
 mod models;
+mod no_repeat_ngram;
 mod paged_attention;
 mod pipeline;
 mod prefix_cacher;
@@ -63,9 +72,11 @@ mod sampler;
 mod scheduler;
 mod sequence;
 mod speech_models;
+mod token_healing;
 mod toml_selector;
 mod tools;
 mod topology;
+pub mod uqff;
 mod utils;
 mod vision_models;
 mod xlora_models;
@@ -74,6 +85,7 @@ pub use amoe::{AnyMoeConfig, AnyMoeExpertType};
 pub use device_map::{
     DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, LayerDeviceMapper,
 };
+pub use embedding::EmbeddingPooling;
 pub use gguf::{GGUFArchitecture, GGUF_MULTI_FILE_DELIMITER};
 pub use mistralrs_audio::AudioInput;
 pub use mistralrs_mcp::{
@@ -86,37 +98,54 @@ pub use mistralrs_quant::{IsqType, MULTI_LORA_DELIMITER};
 pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig, PagedCacheType};
 pub use pipeline::{
     chat_template::ChatTemplate, parse_isq_value, AdapterPaths, AnyMoeLoader, AnyMoePipeline,
-    AutoDeviceMapParams, AutoLoader, AutoLoaderBuilder, DiffusionGenerationParams, DiffusionLoader,
-    DiffusionLoaderBuilder, DiffusionLoaderType, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig,
-    GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, Idefics2Loader,
-    IsqOrganization, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths,
-    LoraAdapterPaths, MistralLoader, MixtralLoader, Modalities, ModelKind, ModelPaths,
-    MultimodalPromptPrefixer, NormalLoader, NormalLoaderBuilder, NormalLoaderType,
-    NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig,
-    SpeculativeLoader, SpeculativePipeline, SpeechLoader, SpeechPipeline, Starcoder2Loader,
-    SupportedModality, TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType,
-    VisionSpecificConfig, UQFF_MULTI_FILE_DELIMITER,
+    AutoDeviceMapParams, AutoLoader, AutoLoaderBuilder, CalibrationData, DeviceMemoryEstimate,
+    DiffusionGenerationParams, DiffusionLoader, DiffusionLoaderBuilder, DiffusionLoaderType,
+    EmbeddingLoader, EmbeddingPipeline, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig,
+    GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, H2oConfig, Idefics2Loader,
+    InMemoryModelPaths, IsqOrganization, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader,
+    LocalModelPaths, LoraAdapterPaths, MemoryEstimate, MistralLoader, MixedPrecisionIsq,
+    MixtralLoader, Modalities, ModelKind, ModelPaths, MultimodalPromptPrefixer, NormalLoader,
+    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader, Phi3Loader,
+    Phi3VLoader, Qwen2Loader, RerankerLoader, RerankerPipeline, SharedWeights, SharedWeightsKey,
+    SlidingWindowOverride, SpeculativeConfig, SpeculativeLoader, SpeculativePipeline, SpeechLoader,
+    SpeechPipeline, Starcoder2Loader, SupportedModality, TokenSource, TranscriptionPipeline,
+    VisionLoader, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
+    UQFF_MULTI_FILE_DELIMITER,
 };
+pub use prefix_cacher::SessionBlob;
 pub use request::{
-    ApproximateUserLocation, Constraint, DetokenizationRequest, ImageGenerationResponseFormat,
-    LlguidanceGrammar, MessageContent, NormalRequest, Request, RequestMessage, SearchContextSize,
-    TokenizationRequest, WebSearchOptions, WebSearchUserLocation,
+    ApproximateUserLocation, CalibratedIsqRequest, Constraint, DetokenizationRequest,
+    EmbeddingRequest, EngineStats, ExportFormat, ExportRequest, HistogramBucket,
+    ImageGenerationResponseFormat, KvCacheStats, LatencyHistogram, LlguidanceGrammar,
+    LoraAdapterAction, LoraAdapterInfo, LoraAdapterRequest, MessageContent, NormalRequest,
+    RequantizeRequest, Request, RequestMessage, RerankRequest, ResumeSessionRequest,
+    SearchContextSize, SnapshotSessionRequest, StatsRequest, SynthesisRequest, TokenizationRequest,
+    TranscriptionRequest, WebSearchOptions, WebSearchUserLocation,
 };
 pub use response::*;
 pub use sampler::{
     CustomLogitsProcessor, DrySamplingParams, SamplingParams, StopTokens, TopLogprob,
+    XtcSamplingParams,
 };
-pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
+pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig, SchedulingPolicy};
 pub use search::{SearchCallback, SearchFunctionParameters, SearchResult};
+pub use sequence::{AttentionSinkConfig, KvQuota, KvQuotaAction, KvQuotaConfig};
 use serde::Serialize;
-pub use speech_models::{utils as speech_utils, SpeechGenerationConfig, SpeechLoaderType};
+pub use speech_models::{
+    utils as speech_utils, AudioChunk, SpeechGenerationConfig, SpeechLoaderType,
+    TranscriptionSegment,
+};
 use tokio::runtime::Runtime;
 use toml_selector::{TomlLoaderArgs, TomlSelector};
 pub use tools::{ToolCallResponse, ToolCallType, ToolCallbacks, ToolChoice};
-pub use topology::{LayerTopology, Topology};
+pub use topology::{LayerTopology, Topology, TopologyBuilder};
 pub use utils::debug::initialize_logging;
 pub use utils::memory_usage::MemoryUsage;
 pub use utils::normal::{ModelDType, TryIntoDType};
+pub use utils::progress::{
+    set_load_cancellation_token, set_load_progress_callback, CancellationToken, LoadEvent,
+    LoadProgressCallback,
+};
 pub use utils::{paged_attn_supported, using_flash_attn};
 
 // re-export llguidance for easier LlguidanceGrammar construction
@@ -133,7 +162,27 @@ pub struct EngineConfig {
     pub no_kv_cache: bool,
     pub no_prefix_cache: bool,
     pub prefix_cache_n: usize,
+    /// Persist the prefix cache in this directory across restarts, see
+    /// [`MistralRsBuilder::with_prefix_cache_dir`].
+    pub prefix_cache_dir: Option<PathBuf>,
     pub disable_eos_stop: bool,
+    pub scheduling_policy: SchedulingPolicy,
+    pub prefill_chunk_size: Option<usize>,
+    /// Runs the prompts on other devices than the pipeline, see
+    /// [`MistralRsBuilder::with_prefill_pipeline`].
+    pub prefill_pipeline: Option<Arc<tokio::sync::Mutex<dyn Pipeline>>>,
+    /// Reject new requests once this many sequences are waiting, see
+    /// [`MistralRsBuilder::with_max_queue_len`].
+    pub max_queue_len: Option<usize>,
+    /// Keep the attention sinks and the most recent tokens once a sequence reaches the maximum
+    /// length, see [`MistralRsBuilder::with_attention_sinks`].
+    pub attention_sinks: Option<AttentionSinkConfig>,
+    /// Bound the KV cache of each sequence, see [`MistralRsBuilder::with_kv_quota`].
+    pub kv_quota: Option<KvQuotaConfig>,
+    /// Applied to the logits of every request, see [`MistralRsBuilder::with_logits_processor`].
+    pub logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+    /// Reproducible generation, see [`MistralRsBuilder::with_deterministic`].
+    pub deterministic: bool,
     pub throughput_logging_enabled: bool,
     pub search_embedding_model: Option<BertEmbeddingModel>,
     pub search_callback: Option<Arc<SearchCallback>>,
@@ -148,7 +197,16 @@ impl Default for EngineConfig {
             no_kv_cache: false,
             no_prefix_cache: false,
             prefix_cache_n: 16,
+            prefix_cache_dir: None,
             disable_eos_stop: false,
+            scheduling_policy: SchedulingPolicy::default(),
+            prefill_chunk_size: None,
+            prefill_pipeline: None,
+            max_queue_len: None,
+            attention_sinks: None,
+            kv_quota: None,
+            logits_processors: Vec::new(),
+            deterministic: false,
             throughput_logging_enabled: true,
             search_embedding_model: None,
             search_callback: None,
@@ -207,6 +265,7 @@ pub struct MistralRs {
     id: String,
     creation_time: u64,
     next_request_id: Mutex<RefCell<usize>>,
+    draining: AtomicBool,
 }
 
 #[derive(Clone)]
@@ -217,7 +276,16 @@ struct RebootState {
     no_kv_cache: bool,
     no_prefix_cache: bool,
     prefix_cache_n: usize,
+    prefix_cache_dir: Option<PathBuf>,
     disable_eos_stop: bool,
+    scheduling_policy: SchedulingPolicy,
+    prefill_chunk_size: Option<usize>,
+    prefill_pipeline: Option<Arc<tokio::sync::Mutex<dyn Pipeline>>>,
+    max_queue_len: Option<usize>,
+    attention_sinks: Option<AttentionSinkConfig>,
+    kv_quota: Option<KvQuotaConfig>,
+    logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+    deterministic: bool,
     throughput_logging_enabled: bool,
     search_embedding_model: Option<BertEmbeddingModel>,
     search_callback: Option<Arc<search::SearchCallback>>,
@@ -230,6 +298,8 @@ struct RebootState {
 pub enum MistralRsError {
     EnginePoisoned,
     SenderPoisoned,
+    /// The engines are draining or have been shut down by [`MistralRs::drain`].
+    Draining,
 }
 
 impl std::fmt::Display for MistralRsError {
@@ -258,7 +328,17 @@ pub struct MistralRsBuilder {
     no_kv_cache: Option<bool>,
     no_prefix_cache: Option<bool>,
     prefix_cache_n: Option<usize>,
+    prefix_cache_dir: Option<PathBuf>,
+    h2o_config: Option<H2oConfig>,
     disable_eos_stop: Option<bool>,
+    scheduling_policy: Option<SchedulingPolicy>,
+    prefill_chunk_size: Option<usize>,
+    prefill_pipeline: Option<Arc<tokio::sync::Mutex<dyn Pipeline>>>,
+    max_queue_len: Option<usize>,
+    attention_sinks: Option<AttentionSinkConfig>,
+    kv_quota: Option<KvQuotaConfig>,
+    logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+    deterministic: bool,
     throughput_logging_enabled: bool,
     search_embedding_model: Option<BertEmbeddingModel>,
     search_callback: Option<Arc<SearchCallback>>,
@@ -285,7 +365,17 @@ impl MistralRsBuilder {
             no_kv_cache: None,
             no_prefix_cache: None,
             prefix_cache_n: None,
+            prefix_cache_dir: None,
+            h2o_config: None,
             disable_eos_stop: None,
+            scheduling_policy: None,
+            prefill_chunk_size: None,
+            prefill_pipeline: None,
+            max_queue_len: None,
+            attention_sinks: None,
+            kv_quota: None,
+            logits_processors: Vec::new(),
+            deterministic: false,
             throughput_logging_enabled: throughput_logging,
             search_embedding_model,
             search_callback: None,
@@ -318,10 +408,106 @@ impl MistralRsBuilder {
         self.prefix_cache_n = Some(prefix_cache_n);
         self
     }
+    /// Persist the prefix cache in `prefix_cache_dir`, so that prompts which share a prefix with
+    /// those of an earlier run, such as a system prompt, are fast after a restart. The caches are
+    /// written when the engine stops, for example with [`MistralRs::drain`], and loaded when it
+    /// starts if they were written by the same model. Only the caches of text-only sequences are
+    /// persisted, and only without PagedAttention or tensor parallelism.
+    pub fn with_prefix_cache_dir(mut self, prefix_cache_dir: PathBuf) -> Self {
+        self.prefix_cache_dir = Some(prefix_cache_dir);
+        self
+    }
+    pub fn with_opt_prefix_cache_dir(mut self, prefix_cache_dir: Option<PathBuf>) -> Self {
+        self.prefix_cache_dir = prefix_cache_dir;
+        self
+    }
+    /// Bound the KV cache of each sequence, evicting the tokens with the lowest attention once it
+    /// holds more than the budget. This is only used without PagedAttention and flash attention.
+    pub fn with_h2o_cache(mut self, h2o_config: H2oConfig) -> Self {
+        self.h2o_config = Some(h2o_config);
+        self
+    }
     pub fn with_disable_eos_stop(mut self, disable_eos_stop: bool) -> Self {
         self.disable_eos_stop = Some(disable_eos_stop);
         self
     }
+    /// Set how waiting sequences are ordered. [`SchedulingPolicy::FairShare`] balances the tokens
+    /// of the tenants set by `NormalRequest::tenant`.
+    pub fn with_scheduling_policy(mut self, scheduling_policy: SchedulingPolicy) -> Self {
+        self.scheduling_policy = Some(scheduling_policy);
+        self
+    }
+    /// Process prompts in chunks of at most `prefill_chunk_size` tokens, running the completions
+    /// of the other sequences between the chunks. This bounds the latency of the completions while
+    /// long prompts are processed. The engine fails to start if this is set with PagedAttention.
+    pub fn with_prefill_chunk_size(mut self, prefill_chunk_size: usize) -> Self {
+        self.prefill_chunk_size = Some(prefill_chunk_size);
+        self
+    }
+    pub fn with_opt_prefill_chunk_size(mut self, prefill_chunk_size: Option<usize>) -> Self {
+        self.prefill_chunk_size = prefill_chunk_size;
+        self
+    }
+    /// Run the prompts on `prefill_pipeline`, a pipeline of the same model loaded on other
+    /// devices, and the completions on the pipeline of this builder. The KV cache of each sequence
+    /// is moved to the devices of the pipeline after its prompt.
+    ///
+    /// This moves the compute and activation memory of the prompts off the decode devices. The
+    /// prompt and completion steps still run one after the other on the engine loop, so a long
+    /// prompt delays the completions of other sequences as it would on one device; use
+    /// [`with_prefill_chunk_size`](Self::with_prefill_chunk_size) to bound that delay. The engine
+    /// fails to start if this is set with PagedAttention.
+    pub fn with_prefill_pipeline(
+        mut self,
+        prefill_pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    ) -> Self {
+        self.prefill_pipeline = Some(prefill_pipeline);
+        self
+    }
+    /// Reject new requests with a [`QueueFull`] error while `max_queue_len` sequences are waiting
+    /// to run, instead of queueing them without bound.
+    pub fn with_max_queue_len(mut self, max_queue_len: usize) -> Self {
+        self.max_queue_len = Some(max_queue_len);
+        self
+    }
+    pub fn with_opt_max_queue_len(mut self, max_queue_len: Option<usize>) -> Self {
+        self.max_queue_len = max_queue_len;
+        self
+    }
+    /// Keep generating once a sequence reaches the maximum length of the model, instead of
+    /// stopping it, by keeping only its attention sinks and its most recent tokens in the context.
+    /// Prompts which are longer than the maximum length are shortened the same way.
+    pub fn with_attention_sinks(mut self, attention_sinks: AttentionSinkConfig) -> Self {
+        self.attention_sinks = Some(attention_sinks);
+        self
+    }
+    /// Bound the KV cache held by each sequence, so a single long request cannot take the whole KV
+    /// cache from the others. The action of the quota sets whether a sequence which reaches it is
+    /// finished or keeps only its attention sinks and most recent tokens.
+    pub fn with_kv_quota(mut self, kv_quota: KvQuotaConfig) -> Self {
+        self.kv_quota = Some(kv_quota);
+        self
+    }
+    pub fn with_opt_kv_quota(mut self, kv_quota: Option<KvQuotaConfig>) -> Self {
+        self.kv_quota = kv_quota;
+        self
+    }
+
+    /// Apply a custom logits processor to every request, before the processors of the request
+    /// itself. Processors run in the order they were added.
+    pub fn with_logits_processor(mut self, processor: Arc<dyn CustomLogitsProcessor>) -> Self {
+        self.logits_processors.push(processor);
+        self
+    }
+
+    /// Make the output of each request depend only on the request, for reproducible evaluations.
+    /// Requests without a seed are seeded with the same seed, every sequence samples on the CPU
+    /// with its own random number generator, the sequences of each batch run in the order they
+    /// arrived in, and prompts are always computed in full instead of reusing the prefix cache.
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
 
     /// Use a custom callback to gather search results.
     pub fn with_search_callback(mut self, search_callback: Arc<SearchCallback>) -> Self {
@@ -419,12 +605,21 @@ impl MistralRs {
                     let engine = Engine::new(
                         rx,
                         pipeline,
+                        config.prefill_pipeline,
                         method,
                         config.truncate_sequence,
                         config.no_kv_cache,
                         config.no_prefix_cache,
                         config.prefix_cache_n,
+                        config.prefix_cache_dir,
                         config.disable_eos_stop,
+                        config.scheduling_policy,
+                        config.prefill_chunk_size,
+                        config.max_queue_len,
+                        config.attention_sinks,
+                        config.kv_quota,
+                        config.logits_processors.clone(),
+                        config.deterministic,
                         config.throughput_logging_enabled,
                         config.search_embedding_model,
                         config.search_callback.clone(),
@@ -443,12 +638,21 @@ impl MistralRs {
                     let engine = Engine::new(
                         rx,
                         pipeline,
+                        config.prefill_pipeline,
                         method,
                         config.truncate_sequence,
                         config.no_kv_cache,
                         config.no_prefix_cache,
                         config.prefix_cache_n,
+                        config.prefix_cache_dir,
                         config.disable_eos_stop,
+                        config.scheduling_policy,
+                        config.prefill_chunk_size,
+                        config.max_queue_len,
+                        config.attention_sinks,
+                        config.kv_quota,
+                        config.logits_processors.clone(),
+                        config.deterministic,
                         config.throughput_logging_enabled,
                         config.search_embedding_model,
                         config.search_callback.clone(),
@@ -479,7 +683,17 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_dir,
+            h2o_config,
             disable_eos_stop,
+            scheduling_policy,
+            prefill_chunk_size,
+            prefill_pipeline,
+            max_queue_len,
+            attention_sinks,
+            kv_quota,
+            logits_processors,
+            deterministic,
             throughput_logging_enabled,
             search_embedding_model,
             search_callback,
@@ -497,6 +711,41 @@ impl MistralRs {
         let no_prefix_cache = no_prefix_cache.unwrap_or(false);
         let prefix_cache_n = prefix_cache_n.unwrap_or(16);
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
+        let scheduling_policy = scheduling_policy.unwrap_or_default();
+
+        if let Some(h2o_config) = h2o_config {
+            let pipeline = get_mut_arcmutex!(pipeline);
+            if pipeline.get_metadata().cache_config.is_some() {
+                warn!("The H2O KV cache is not supported with PagedAttention, ignoring it.");
+            } else if using_flash_attn() && pipeline.device().is_cuda() {
+                warn!("The H2O KV cache is not supported with flash attention, ignoring it.");
+            } else if let EitherCache::Normal(cache) = pipeline.cache() {
+                get_mut_arcmutex!(cache).enable_h2o(h2o_config);
+                info!(
+                    "Using an H2O KV cache with a budget of {} tokens, {} of which are recent.",
+                    h2o_config.budget, h2o_config.recent
+                );
+            } else {
+                warn!("The H2O KV cache is not supported by this model, ignoring it.");
+            }
+        }
+
+        let attention_sinks = attention_sinks.filter(|sinks| {
+            let max_seq_len = get_mut_arcmutex!(pipeline).get_metadata().max_seq_len;
+            if sinks.sinks + sinks.window >= max_seq_len {
+                warn!(
+                    "The {} attention sinks and window of {} tokens do not fit in the maximum length of {max_seq_len} tokens, ignoring them.",
+                    sinks.sinks, sinks.window
+                );
+                false
+            } else {
+                info!(
+                    "Using {} attention sinks with a window of {} tokens.",
+                    sinks.sinks, sinks.window
+                );
+                true
+            }
+        });
 
         // Initialize MCP client if configured
         if let Some(config) = &mcp_client_config {
@@ -542,7 +791,16 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_dir: prefix_cache_dir.clone(),
             disable_eos_stop,
+            scheduling_policy,
+            prefill_chunk_size,
+            prefill_pipeline: prefill_pipeline.clone(),
+            max_queue_len,
+            attention_sinks,
+            kv_quota,
+            logits_processors: logits_processors.clone(),
+            deterministic,
             throughput_logging_enabled,
             search_embedding_model: search_embedding_model.clone(),
             search_callback: search_callback.clone(),
@@ -557,7 +815,16 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_dir,
             disable_eos_stop,
+            scheduling_policy,
+            prefill_chunk_size,
+            prefill_pipeline,
+            max_queue_len,
+            attention_sinks,
+            kv_quota,
+            logits_processors,
+            deterministic,
             throughput_logging_enabled,
             search_embedding_model,
             search_callback,
@@ -623,7 +890,10 @@ impl MistralRs {
                     logits_processors: None,
                     return_raw_logits: false,
                     web_search_options: None,
+                    adapters: None,
                     model_id: None,
+                    tenant: None,
+                    deadline: None,
                 }));
                 info!("Beginning dummy run.");
                 let start = Instant::now();
@@ -661,6 +931,7 @@ impl MistralRs {
                 .expect("Time travel has occurred!")
                 .as_secs(),
             next_request_id: Mutex::new(RefCell::new(1)),
+            draining: AtomicBool::new(false),
         })
     }
 
@@ -683,7 +954,16 @@ impl MistralRs {
                 no_kv_cache: reboot_state.no_kv_cache,
                 no_prefix_cache: reboot_state.no_prefix_cache,
                 prefix_cache_n: reboot_state.prefix_cache_n,
+                prefix_cache_dir: reboot_state.prefix_cache_dir.clone(),
                 disable_eos_stop: reboot_state.disable_eos_stop,
+                scheduling_policy: reboot_state.scheduling_policy,
+                prefill_chunk_size: reboot_state.prefill_chunk_size,
+                prefill_pipeline: reboot_state.prefill_pipeline.clone(),
+                max_queue_len: reboot_state.max_queue_len,
+                attention_sinks: reboot_state.attention_sinks,
+                kv_quota: reboot_state.kv_quota,
+                logits_processors: reboot_state.logits_processors.clone(),
+                deterministic: reboot_state.deterministic,
                 throughput_logging_enabled: reboot_state.throughput_logging_enabled,
                 search_embedding_model: reboot_state.search_embedding_model.clone(),
                 search_callback: reboot_state.search_callback.clone(),
@@ -722,8 +1002,24 @@ impl MistralRs {
         }
     }
 
+    /// Whether the engine thread of a model is running. An engine which stopped is rebooted by
+    /// the next [`MistralRs::get_sender`].
+    pub fn is_engine_running(&self, model_id: &str) -> Result<bool, MistralRsError> {
+        self.engine_dead(model_id).map(|dead| !dead)
+    }
+
+    /// Whether the engines are draining or have been shut down by [`MistralRs::drain`].
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(atomic::Ordering::SeqCst)
+    }
+
     /// Get sender for a specific model. If model_id is None, uses default engine.
     pub fn get_sender(&self, model_id: Option<&str>) -> Result<Sender<Request>, MistralRsError> {
+        // Finished engines are not rebooted while draining
+        if self.draining.load(atomic::Ordering::SeqCst) {
+            return Err(MistralRsError::Draining);
+        }
+
         let resolved_model_id = match model_id {
             Some(id) => id.to_string(),
             None => {
@@ -815,7 +1111,16 @@ impl MistralRs {
             no_kv_cache: config.engine_config.no_kv_cache,
             no_prefix_cache: config.engine_config.no_prefix_cache,
             prefix_cache_n: config.engine_config.prefix_cache_n,
+            prefix_cache_dir: config.engine_config.prefix_cache_dir.clone(),
             disable_eos_stop: config.engine_config.disable_eos_stop,
+            scheduling_policy: config.engine_config.scheduling_policy,
+            prefill_chunk_size: config.engine_config.prefill_chunk_size,
+            prefill_pipeline: config.engine_config.prefill_pipeline.clone(),
+            max_queue_len: config.engine_config.max_queue_len,
+            attention_sinks: config.engine_config.attention_sinks,
+            kv_quota: config.engine_config.kv_quota,
+            logits_processors: config.engine_config.logits_processors.clone(),
+            deterministic: config.engine_config.deterministic,
             throughput_logging_enabled: config.engine_config.throughput_logging_enabled,
             search_embedding_model: config.engine_config.search_embedding_model.clone(),
             search_callback: config.engine_config.search_callback.clone(),
@@ -845,6 +1150,57 @@ impl MistralRs {
         Ok(())
     }
 
+    /// Move the model `other_model_id` (or the default model) of `other` into this instance under
+    /// `model_id`, keeping its engine running. It is removed from `other`.
+    pub fn add_model_from(
+        &self,
+        model_id: String,
+        other: &MistralRs,
+        other_model_id: Option<&str>,
+    ) -> Result<(), String> {
+        if std::ptr::eq(self, other) {
+            return Err("Cannot add a model from the same MistralRs instance".to_string());
+        }
+
+        let mut engines = self
+            .engines
+            .write()
+            .map_err(|_| "Failed to acquire write lock on engines")?;
+        if engines.contains_key(&model_id) {
+            return Err(format!("Model {model_id} is already registered"));
+        }
+
+        let mut other_default = other
+            .default_engine_id
+            .write()
+            .map_err(|_| "Failed to acquire write lock on default_engine_id")?;
+        let other_model_id = other_model_id
+            .map(ToString::to_string)
+            .or_else(|| other_default.clone())
+            .ok_or("The other MistralRs instance has no models")?;
+        let mut other_engines = other
+            .engines
+            .write()
+            .map_err(|_| "Failed to acquire write lock on engines")?;
+        let engine_instance = other_engines
+            .remove(&other_model_id)
+            .ok_or_else(|| format!("Model {other_model_id} not found"))?;
+        if other_default.as_ref() == Some(&other_model_id) {
+            *other_default = other_engines.keys().next().cloned();
+        }
+
+        // If there was no model, such as after the last one was unloaded, this is the default
+        let mut default_lock = self
+            .default_engine_id
+            .write()
+            .map_err(|_| "Failed to acquire write lock on default_engine_id")?;
+        if default_lock.is_none() {
+            *default_lock = Some(model_id.clone());
+        }
+        engines.insert(model_id, engine_instance);
+        Ok(())
+    }
+
     /// Remove a model engine from the MistralRs instance
     pub fn remove_model(&self, model_id: &str) -> Result<(), String> {
         let mut engines = self
@@ -878,6 +1234,131 @@ impl MistralRs {
         }
     }
 
+    /// Unload the model `model_id` (or the default model): terminate its engine, wait for the
+    /// engine thread to exit, and drop the pipeline so that its weights, KV cache and
+    /// PagedAttention blocks are freed. The device is then synchronized, so that the device
+    /// memory has been released when this returns.
+    ///
+    /// Unlike [`MistralRs::remove_model`], this may unload the last model. The memory is only
+    /// freed once nothing else holds the pipeline, which is logged if it is still shared.
+    pub async fn unload_model(&self, model_id: Option<&str>) -> Result<(), String> {
+        let engine_instance = {
+            let mut engines = self
+                .engines
+                .write()
+                .map_err(|_| "Failed to acquire write lock on engines")?;
+            let mut default_lock = self
+                .default_engine_id
+                .write()
+                .map_err(|_| "Failed to acquire write lock on default_engine_id")?;
+            let model_id = model_id
+                .map(ToString::to_string)
+                .or_else(|| default_lock.clone())
+                .ok_or("There is no model to unload")?;
+            let engine_instance = engines
+                .remove(&model_id)
+                .ok_or_else(|| format!("Model {model_id} not found"))?;
+            if default_lock.as_ref() == Some(&model_id) {
+                *default_lock = engines.keys().next().cloned();
+            }
+            engine_instance
+        };
+
+        let EngineInstance {
+            sender,
+            engine_handler,
+            reboot_state,
+            config,
+            category: _,
+        } = engine_instance;
+
+        // Requests which are still running are dropped along with the engine.
+        let _ = sender.send(Request::Terminate).await;
+        drop(sender);
+        tokio::task::spawn_blocking(move || engine_handler.join())
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|_| "The engine thread panicked")?;
+
+        let pipeline = reboot_state.pipeline;
+        if Arc::strong_count(&pipeline) > 1 {
+            warn!("The unloaded pipeline is still in use, so its memory will be freed once it is no longer used.");
+        }
+        drop(pipeline);
+        config.device.synchronize().map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    /// Shut down all engines without dropping requests: new requests are rejected, then this waits
+    /// for the sequences which are queued or running to finish before the engine threads exit.
+    /// Sequences which have not finished after `timeout` are dropped.
+    ///
+    /// Afterwards, [`MistralRs::get_sender`] fails with [`MistralRsError::Draining`].
+    pub async fn drain(&self, timeout: Duration) -> Result<(), String> {
+        self.draining.store(true, atomic::Ordering::SeqCst);
+
+        let senders = self
+            .engines
+            .read()
+            .map_err(|_| "Failed to acquire read lock on engines")?
+            .values()
+            .map(|engine| engine.sender.clone())
+            .collect::<Vec<_>>();
+        for sender in &senders {
+            let _ = sender.send(Request::Drain).await;
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let all_finished = self
+                .engines
+                .read()
+                .map_err(|_| "Failed to acquire read lock on engines")?
+                .values()
+                .all(|engine| engine.engine_handler.is_finished());
+            if all_finished {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!("Engines did not drain within {timeout:?}, dropping the remaining requests.");
+                for sender in &senders {
+                    let _ = sender.send(Request::Terminate).await;
+                }
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(senders);
+
+        let engines = {
+            let mut engines = self
+                .engines
+                .write()
+                .map_err(|_| "Failed to acquire write lock on engines")?;
+            let mut default_lock = self
+                .default_engine_id
+                .write()
+                .map_err(|_| "Failed to acquire write lock on default_engine_id")?;
+            *default_lock = None;
+            std::mem::take(&mut *engines)
+        };
+        for (_, engine_instance) in engines {
+            let EngineInstance {
+                sender,
+                engine_handler,
+                ..
+            } = engine_instance;
+            drop(sender);
+            tokio::task::spawn_blocking(move || engine_handler.join())
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|_| "The engine thread panicked")?;
+        }
+
+        Ok(())
+    }
+
     /// List all available model IDs
     pub fn list_models(&self) -> Result<Vec<String>, String> {
         let engines = self
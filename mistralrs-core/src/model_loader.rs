@@ -258,9 +258,13 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 }),
                 imatrix,
                 calibration_file,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 hf_cache_path,
                 matformer_config_path,
                 matformer_slice_name,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -301,9 +305,13 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                     }),
                     imatrix: imatrix.clone(),
                     calibration_file: calibration_file.clone(),
+                    calibration_data: None,
+                    mixed_precision_isq: None,
                     hf_cache_path: hf_cache_path.clone(),
                     matformer_config_path: matformer_config_path.clone(),
                     matformer_slice_name: matformer_slice_name.clone(),
+                    isq_skip: Vec::new(),
+                    sliding_window: None,
                 },
                 VisionSpecificConfig {
                     topology: Topology::from_option_path(topology)?,
@@ -316,10 +324,14 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                     }),
                     max_edge,
                     calibration_file,
+                    calibration_data: None,
+                    mixed_precision_isq: None,
                     imatrix,
                     hf_cache_path: hf_cache_path.clone(),
                     matformer_config_path,
                     matformer_slice_name,
+                    isq_skip: Vec::new(),
+                    sliding_window: None,
                 },
                 args.chat_template,
                 tokenizer_json,
@@ -364,10 +376,14 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 }),
                 max_edge,
                 calibration_file,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 imatrix,
                 hf_cache_path,
                 matformer_config_path,
                 matformer_slice_name,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -418,9 +434,13 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 }),
                 imatrix: None,
                 calibration_file: None,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 hf_cache_path,
                 matformer_config_path: None,
                 matformer_slice_name: None,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -463,9 +483,13 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 }),
                 imatrix: None,
                 calibration_file: None,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 hf_cache_path,
                 matformer_config_path: None,
                 matformer_slice_name: None,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -19,7 +19,8 @@ use crate::{
     pipeline::{
         extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        EitherCache, IsqModel, KvCache, NormalCache, NormalLoadingMetadata, NormalModel,
+        EitherCache, IsqModel, KvCache, NamedIsqLayer, NormalCache, NormalLoadingMetadata,
+        NormalModel,
     },
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
 };
@@ -572,6 +573,40 @@ impl Model {
 
         extract_logits(&xs, context_lens)
     }
+
+    /// The ISQ layers with their safetensors module names, from which [`IsqModel::get_layers`]
+    /// and [`IsqModel::get_layers_with_names`] are derived so that their orders match.
+    fn named_layers(&mut self) -> (Vec<NamedIsqLayer<'_>>, &dyn DeviceMapper) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None, Some("lm_head".to_string())));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((
+                &mut layer.self_attn.q_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.q_proj")),
+            ));
+            tensors.push((
+                &mut layer.self_attn.k_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.k_proj")),
+            ));
+            tensors.push((
+                &mut layer.self_attn.v_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.v_proj")),
+            ));
+            tensors.push((
+                &mut layer.self_attn.o_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.o_proj")),
+            ));
+            for (m, name) in layer.mlp.get_named_isq_layers() {
+                let name = name.map(|name| format!("model.layers.{i}.mlp.{name}"));
+                tensors.push((m, Some(i), name));
+            }
+        }
+        (tensors, &*self.mapper)
+    }
 }
 
 impl IsqModel for Model {
@@ -581,23 +616,19 @@ impl IsqModel for Model {
         Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
         &dyn DeviceMapper,
     ) {
-        let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
-        for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((&mut layer.self_attn.q_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.k_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.v_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
-            tensors.extend(
-                layer
-                    .mlp
-                    .get_isq_layers()
-                    .into_iter()
-                    .map(|m| (m, Some(i)))
-                    .collect::<Vec<_>>(),
-            );
-        }
-        (tensors, &*self.mapper)
+        let (layers, mapper) = self.named_layers();
+        let layers = layers.into_iter().map(|(layer, i, _)| (layer, i)).collect();
+        (layers, mapper)
+    }
+
+    fn get_layers_with_names(
+        &mut self,
+    ) -> candle_core::Result<Vec<(&mut Arc<dyn QuantMethod>, Option<String>)>> {
+        let (layers, _) = self.named_layers();
+        Ok(layers
+            .into_iter()
+            .map(|(layer, _, name)| (layer, name))
+            .collect())
     }
 
     fn residual_tensors(&self) -> Vec<(String, Tensor)> {
@@ -23,7 +23,8 @@ use crate::{
     pipeline::{
         extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        EitherCache, IsqModel, KvCache, NormalCache, NormalLoadingMetadata, NormalModel,
+        EitherCache, IsqModel, KvCache, NamedIsqLayer, NormalCache, NormalLoadingMetadata,
+        NormalModel,
     },
     serde_default_fn,
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
@@ -144,6 +145,7 @@ impl CausalSelfAttention {
             },
             None => {
                 let (k, v) = kv_cache.append(&k, &v)?;
+                kv_cache.observe_attention(&q, &self.sdpa_params)?;
 
                 Sdpa.run_attention(
                     &q,
@@ -500,6 +502,29 @@ impl Llama {
         context_lens: Vec<(usize, usize)>,
         metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let mut x = self.hidden_states(
+            input_ids,
+            input_embeds,
+            seqlen_offsets,
+            metadata,
+            flash_params,
+        )?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            x = x.to_dtype(t)?;
+        }
+        let xs = MatMul.qmethod_matmul(&x, &*self.lm_head)?;
+        extract_logits(&xs, context_lens)
+    }
+
+    /// The hidden states of all tokens, after the final norm.
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        input_embeds: Tensor,
+        seqlen_offsets: &[usize],
+        metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut x = input_embeds;
         let cache = &mut self.kv_cache.normal().0;
@@ -533,12 +558,7 @@ impl Llama {
             )?;
         }
         let x = x.to_device(&self.device)?;
-        let mut x = self.ln_f.forward(&x)?;
-        if let Some(t) = self.lm_head.quantized_act_type() {
-            x = x.to_dtype(t)?;
-        }
-        let xs = MatMul.qmethod_matmul(&x, &*self.lm_head)?;
-        extract_logits(&xs, context_lens)
+        self.ln_f.forward(&x)
     }
 
     pub fn residual_tensors_m(&self, uvb_m: UnVarBuilder) -> Vec<(String, Tensor)> {
@@ -553,6 +573,40 @@ impl Llama {
 
         uvb_m.to_safetensors()
     }
+
+    /// The ISQ layers with their safetensors module names, from which [`IsqModel::get_layers`]
+    /// and [`IsqModel::get_layers_with_names`] are derived so that their orders match.
+    fn named_layers(&mut self) -> (Vec<NamedIsqLayer<'_>>, &dyn DeviceMapper) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None, Some("lm_head".to_string())));
+        for (i, layer) in self.blocks.iter_mut().enumerate() {
+            tensors.push((
+                &mut layer.attn.q_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.q_proj")),
+            ));
+            tensors.push((
+                &mut layer.attn.k_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.k_proj")),
+            ));
+            tensors.push((
+                &mut layer.attn.v_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.v_proj")),
+            ));
+            tensors.push((
+                &mut layer.attn.o_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.o_proj")),
+            ));
+            for (m, name) in layer.mlp.get_named_isq_layers() {
+                let name = name.map(|name| format!("model.layers.{i}.mlp.{name}"));
+                tensors.push((m, Some(i), name));
+            }
+        }
+        (tensors, &*self.mapper)
+    }
 }
 
 impl IsqModel for Llama {
@@ -562,23 +616,19 @@ impl IsqModel for Llama {
         Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
         &dyn DeviceMapper,
     ) {
-        let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
-        for (i, layer) in self.blocks.iter_mut().enumerate() {
-            tensors.push((&mut layer.attn.q_proj, Some(i)));
-            tensors.push((&mut layer.attn.k_proj, Some(i)));
-            tensors.push((&mut layer.attn.v_proj, Some(i)));
-            tensors.push((&mut layer.attn.o_proj, Some(i)));
-            tensors.extend(
-                layer
-                    .mlp
-                    .get_isq_layers()
-                    .into_iter()
-                    .map(|m| (m, Some(i)))
-                    .collect::<Vec<_>>(),
-            );
-        }
-        (tensors, &*self.mapper)
+        let (layers, mapper) = self.named_layers();
+        let layers = layers.into_iter().map(|(layer, i, _)| (layer, i)).collect();
+        (layers, mapper)
+    }
+
+    fn get_layers_with_names(
+        &mut self,
+    ) -> candle_core::Result<Vec<(&mut Arc<dyn QuantMethod>, Option<String>)>> {
+        let (layers, _) = self.named_layers();
+        Ok(layers
+            .into_iter()
+            .map(|(layer, _, name)| (layer, name))
+            .collect())
     }
 
     fn residual_tensors(&self) -> Vec<(String, Tensor)> {
@@ -622,6 +672,20 @@ impl NormalModel for Llama {
             flash_params,
         )
     }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.hidden_states(
+            input_ids,
+            self.wte.forward(input_ids)?,
+            seqlen_offsets,
+            None,
+            flash_params,
+        )
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -20,7 +20,8 @@ use crate::{
     pipeline::{
         extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        EitherCache, IsqModel, KvCache, NormalCache, NormalLoadingMetadata, NormalModel,
+        EitherCache, IsqModel, KvCache, NamedIsqLayer, NormalCache, NormalLoadingMetadata,
+        NormalModel,
     },
     serde_default_fn,
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
@@ -221,6 +222,7 @@ impl Attention {
             },
             None => {
                 let (k, v) = kv_cache.append(&k, &v)?;
+                kv_cache.observe_attention(&q, &self.sdpa_params)?;
 
                 Sdpa.run_attention(
                     &q,
@@ -519,6 +521,28 @@ impl Model {
         context_lens: Vec<(usize, usize)>,
         metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let mut xs = self.hidden_states(
+            input_ids,
+            input_embeds,
+            seqlen_offsets,
+            metadata,
+            flash_params,
+        )?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+    }
+
+    /// The hidden states of all tokens, after the final norm.
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        input_embeds: Tensor,
+        seqlen_offsets: &[usize],
+        metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut xs = input_embeds;
         let cache = &mut self.cache.normal().0;
@@ -556,11 +580,41 @@ impl Model {
             )?;
         }
         let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if let Some(t) = self.lm_head.quantized_act_type() {
-            xs = xs.to_dtype(t)?;
+        xs.apply(&self.norm)
+    }
+
+    /// The ISQ layers with their safetensors module names, from which [`IsqModel::get_layers`]
+    /// and [`IsqModel::get_layers_with_names`] are derived so that their orders match.
+    fn named_layers(&mut self) -> (Vec<NamedIsqLayer<'_>>, &dyn DeviceMapper) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None, Some("lm_head".to_string())));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((
+                &mut layer.self_attn.q_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.q_proj")),
+            ));
+            tensors.push((
+                &mut layer.self_attn.k_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.k_proj")),
+            ));
+            tensors.push((
+                &mut layer.self_attn.v_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.v_proj")),
+            ));
+            tensors.push((
+                &mut layer.self_attn.o_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.o_proj")),
+            ));
+            for (m, name) in layer.mlp.get_named_isq_layers() {
+                let name = name.map(|name| format!("model.layers.{i}.mlp.{name}"));
+                tensors.push((m, Some(i), name));
+            }
         }
-        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+        (tensors, &*self.mapper)
     }
 }
 
@@ -571,23 +625,19 @@ impl IsqModel for Model {
         Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
         &dyn DeviceMapper,
     ) {
-        let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
-        for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((&mut layer.self_attn.q_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.k_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.v_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
-            tensors.extend(
-                layer
-                    .mlp
-                    .get_isq_layers()
-                    .into_iter()
-                    .map(|m| (m, Some(i)))
-                    .collect::<Vec<_>>(),
-            );
-        }
-        (tensors, &*self.mapper)
+        let (layers, mapper) = self.named_layers();
+        let layers = layers.into_iter().map(|(layer, i, _)| (layer, i)).collect();
+        (layers, mapper)
+    }
+
+    fn get_layers_with_names(
+        &mut self,
+    ) -> candle_core::Result<Vec<(&mut Arc<dyn QuantMethod>, Option<String>)>> {
+        let (layers, _) = self.named_layers();
+        Ok(layers
+            .into_iter()
+            .map(|(layer, _, name)| (layer, name))
+            .collect())
     }
 
     fn residual_tensors(&self) -> Vec<(String, Tensor)> {
@@ -644,6 +694,20 @@ impl NormalModel for Model {
             flash_params,
         )
     }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.hidden_states(
+            input_ids,
+            self.embed_tokens.forward(input_ids)?,
+            seqlen_offsets,
+            None,
+            flash_params,
+        )
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -23,7 +23,8 @@ use crate::{
     pipeline::{
         extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        EitherCache, IsqModel, KvCache, NormalCache, NormalLoadingMetadata, NormalModel,
+        EitherCache, IsqModel, KvCache, NamedIsqLayer, NormalCache, NormalLoadingMetadata,
+        NormalModel,
     },
     serde_default_fn,
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
@@ -216,6 +217,7 @@ impl Attention {
             },
             _ => {
                 let (k, v) = kv_cache.append(&k, &v)?;
+                kv_cache.observe_attention(&q, &self.sdpa_params)?;
 
                 Sdpa.run_attention(
                     &q,
@@ -305,6 +307,12 @@ impl MlpLayer for Mlp {
     fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
         vec![&mut self.gate_up_proj, &mut self.down_proj]
     }
+    fn get_named_isq_layers(&mut self) -> Vec<(&mut Arc<dyn QuantMethod>, Option<&'static str>)> {
+        vec![
+            (&mut self.gate_up_proj, Some("gate_up_proj")),
+            (&mut self.down_proj, Some("down_proj")),
+        ]
+    }
     fn clone(&self) -> Box<dyn MlpLayer> {
         Box::new(Clone::clone(self))
     }
@@ -593,6 +601,30 @@ impl Model {
         }
         extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
     }
+
+    /// The ISQ layers with their safetensors module names, from which [`IsqModel::get_layers`]
+    /// and [`IsqModel::get_layers_with_names`] are derived so that their orders match.
+    fn named_layers(&mut self) -> (Vec<NamedIsqLayer<'_>>, &dyn DeviceMapper) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None, Some("lm_head".to_string())));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((
+                &mut layer.self_attn.qkv_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.qkv_proj")),
+            ));
+            tensors.push((
+                &mut layer.self_attn.o_proj,
+                Some(i),
+                Some(format!("model.layers.{i}.self_attn.o_proj")),
+            ));
+            for (m, name) in layer.mlp.get_named_isq_layers() {
+                let name = name.map(|name| format!("model.layers.{i}.mlp.{name}"));
+                tensors.push((m, Some(i), name));
+            }
+        }
+        (tensors, &*self.mapper)
+    }
 }
 
 impl IsqModel for Model {
@@ -602,21 +634,19 @@ impl IsqModel for Model {
         Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
         &dyn DeviceMapper,
     ) {
-        let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
-        for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((&mut layer.self_attn.qkv_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
-            tensors.extend(
-                layer
-                    .mlp
-                    .get_isq_layers()
-                    .into_iter()
-                    .map(|m| (m, Some(i)))
-                    .collect::<Vec<_>>(),
-            );
-        }
-        (tensors, &*self.mapper)
+        let (layers, mapper) = self.named_layers();
+        let layers = layers.into_iter().map(|(layer, i, _)| (layer, i)).collect();
+        (layers, mapper)
+    }
+
+    fn get_layers_with_names(
+        &mut self,
+    ) -> candle_core::Result<Vec<(&mut Arc<dyn QuantMethod>, Option<String>)>> {
+        let (layers, _) = self.named_layers();
+        Ok(layers
+            .into_iter()
+            .map(|(layer, _, name)| (layer, name))
+            .collect())
     }
 
     fn residual_tensors(&self) -> Vec<(String, Tensor)> {
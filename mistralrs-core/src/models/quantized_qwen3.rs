@@ -396,6 +396,53 @@ impl ModelConfig::FromGGUF for ModelWeights {
 }
 
 impl ModelWeights {
+    /// The layers which a LoRA adapter can target, named as in the Hugging Face checkpoint.
+    /// The rows of the projections are in the Hugging Face layout, so no head counts are given.
+    #[allow(clippy::type_complexity)]
+    pub fn get_lora_layers(&mut self) -> Vec<(String, &mut Arc<dyn QuantMethod>, Option<usize>)> {
+        let mut layers = vec![("lm_head".to_string(), &mut self.output, None)];
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let prefix = format!("model.layers.{i}");
+            layers.push((
+                format!("{prefix}.self_attn.q_proj"),
+                &mut layer.attention_wq,
+                None,
+            ));
+            layers.push((
+                format!("{prefix}.self_attn.k_proj"),
+                &mut layer.attention_wk,
+                None,
+            ));
+            layers.push((
+                format!("{prefix}.self_attn.v_proj"),
+                &mut layer.attention_wv,
+                None,
+            ));
+            layers.push((
+                format!("{prefix}.self_attn.o_proj"),
+                &mut layer.attention_wo,
+                None,
+            ));
+            let mlp = &mut layer.mlp;
+            layers.push((
+                format!("{prefix}.mlp.gate_proj"),
+                &mut mlp.feed_forward_w1,
+                None,
+            ));
+            layers.push((
+                format!("{prefix}.mlp.down_proj"),
+                &mut mlp.feed_forward_w2,
+                None,
+            ));
+            layers.push((
+                format!("{prefix}.mlp.up_proj"),
+                &mut mlp.feed_forward_w3,
+                None,
+            ));
+        }
+        layers
+    }
+
     pub fn forward(
         &self,
         x: &Tensor,
@@ -0,0 +1,61 @@
+//! N-gram blocking: no n-gram of the context may appear twice.
+
+use candle_core::{Device, Result, Tensor};
+
+use crate::sampler::CustomLogitsProcessor;
+
+/// Bans the tokens which would complete an n-gram of `size` tokens which is already in the
+/// context, including the prompt. Unlike the penalties, this is a hard constraint, so an exact
+/// repeat can never be generated however likely it is.
+pub(crate) struct NoRepeatNgram {
+    size: usize,
+}
+
+impl NoRepeatNgram {
+    /// `size` must be at least 1.
+    pub(crate) fn new(size: usize) -> Self {
+        Self { size }
+    }
+
+    /// The tokens which follow the last `size - 1` tokens of `context` earlier in it.
+    fn banned_tokens(context: &[u32], size: usize) -> impl Iterator<Item = u32> + '_ {
+        let prefix = &context[(context.len() + 1).saturating_sub(size)..];
+        context
+            .windows(size)
+            .filter(move |ngram| ngram[..size - 1] == *prefix)
+            .map(move |ngram| ngram[size - 1])
+    }
+}
+
+impl CustomLogitsProcessor for NoRepeatNgram {
+    fn apply(&self, logits: &Tensor, context: &[u32]) -> Result<Tensor> {
+        let mut logits_vec = logits.to_vec1::<f32>()?;
+        for tok in Self::banned_tokens(context, self.size) {
+            if let Some(logit) = logits_vec.get_mut(tok as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        Tensor::from_vec(logits_vec, logits.shape(), &Device::Cpu)?.to_device(logits.device())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NoRepeatNgram;
+    use std::collections::HashSet;
+
+    #[test]
+    fn bans_repeated_ngrams() {
+        let banned = |context: &[u32], size| {
+            NoRepeatNgram::banned_tokens(context, size).collect::<HashSet<_>>()
+        };
+
+        // `1 2` was followed by 3 and 4, so either would repeat a trigram
+        assert_eq!(banned(&[1, 2, 3, 1, 2, 4, 1, 2], 3), HashSet::from([3, 4]));
+        assert!(banned(&[1, 2, 3, 1, 2, 4, 1, 5], 3).is_empty());
+        // Every token of the context is a unigram
+        assert_eq!(banned(&[1, 2, 1], 1), HashSet::from([1, 2]));
+        // The context is too short to complete an n-gram which repeats
+        assert!(banned(&[1], 3).is_empty());
+    }
+}
@@ -6,7 +6,8 @@ use std::{
     sync::{Arc, Mutex, MutexGuard},
 };
 
-use super::block_engine_sequence::BlockEngineSequence;
+use super::{block_engine_sequence::BlockEngineSequence, prefix_tree::PrefixTree};
+use crate::request::KvCacheStats;
 
 #[derive(Debug, Clone)]
 pub struct LogicalTokenBlock {
@@ -72,6 +73,10 @@ pub struct _PhysicalTokenBlock {
 }
 
 impl _PhysicalTokenBlock {
+    pub fn is_gpu(&self) -> bool {
+        self.is_gpu
+    }
+
     pub fn refcount(&self) -> usize {
         self.refcount
     }
@@ -127,6 +132,7 @@ impl Eq for PhysicalTokenBlock {}
 
 type BlockTable = Vec<Arc<PhysicalTokenBlock>>;
 struct GPUAllocator;
+struct CPUAllocator;
 
 struct GPUAllocatorWrapper(usize);
 impl Deref for GPUAllocatorWrapper {
@@ -187,6 +193,30 @@ impl Allocator<GPUAllocator> {
     }
 }
 
+impl Allocator<CPUAllocator> {
+    fn new(block_size: usize, num_blocks: usize) -> Self {
+        let mut free_blocks = Vec::new();
+        for id in 0..num_blocks {
+            free_blocks.push(Arc::new(PhysicalTokenBlock(Mutex::new(
+                _PhysicalTokenBlock {
+                    block_id: id,
+                    block_size,
+                    refcount: 0,
+                    is_gpu: false,
+                },
+            ))))
+        }
+        Allocator {
+            free_blocks,
+            _ghost: PhantomData,
+        }
+    }
+
+    fn get_num_free_blocks(&self) -> usize {
+        self.free_blocks.len()
+    }
+}
+
 #[derive(Debug)]
 pub enum AllocStatus {
     Ok,
@@ -204,19 +234,60 @@ pub struct BlockEngine {
     num_gpu_blocks: usize,
     block_size: usize,
     gpu_allocator: Allocator<GPUAllocator>,
+    cpu_allocator: Allocator<CPUAllocator>,
     pub block_tables: HashMap<SeqID, BlockTable>,
+    prefix_tree: PrefixTree,
+    /// The most shared prefix blocks which may be offloaded to host blocks.
+    num_offload_blocks: usize,
+    /// GPU blocks which are kept free when admitting new sequences.
+    watermark_blocks: usize,
+    /// Offloads (GPU to host) and restores (host to GPU) of shared prefix blocks which were not yet
+    /// executed by the cache engine.
+    blocks_to_offload: HashMap<usize, usize>,
+    blocks_to_restore: HashMap<usize, usize>,
+    /// Host blocks which were restored, which are only freed once the restores are executed so
+    /// they are not overwritten before.
+    restored_host_blocks: BlockTable,
+    num_cpu_blocks: usize,
+    // Counters since the block engine was created, see `KvCacheStats`
+    num_swapped_out: usize,
+    num_swapped_in: usize,
+    num_offloaded: usize,
+    num_restored: usize,
+    num_prefix_hits: usize,
+    num_prefix_misses: usize,
 }
 
 pub type BlockTables = HashMap<usize, BlockTable>;
 
 impl BlockEngine {
     #[must_use]
-    pub fn new(block_size: usize, num_gpu_blocks: usize) -> Self {
+    pub fn new(
+        block_size: usize,
+        num_gpu_blocks: usize,
+        num_cpu_blocks: usize,
+        num_offload_blocks: usize,
+        watermark_blocks: usize,
+    ) -> Self {
         Self {
             num_gpu_blocks,
             block_size,
             gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks),
+            cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks),
             block_tables: HashMap::new(),
+            prefix_tree: PrefixTree::default(),
+            num_offload_blocks,
+            watermark_blocks,
+            blocks_to_offload: HashMap::new(),
+            blocks_to_restore: HashMap::new(),
+            restored_host_blocks: Vec::new(),
+            num_cpu_blocks,
+            num_swapped_out: 0,
+            num_swapped_in: 0,
+            num_offloaded: 0,
+            num_restored: 0,
+            num_prefix_hits: 0,
+            num_prefix_misses: 0,
         }
     }
 
@@ -224,13 +295,219 @@ impl BlockEngine {
         self.block_size
     }
 
+    pub fn num_gpu_blocks(&self) -> usize {
+        self.num_gpu_blocks
+    }
+
+    pub fn num_free_gpu_blocks(&self) -> usize {
+        *self.gpu_allocator.get_num_free_blocks()
+    }
+
+    /// A snapshot of the use of the blocks. `fragmentation` is computed by the caller, which knows
+    /// the tokens of the sequences.
+    pub fn stats(&self, fragmentation: f64) -> KvCacheStats {
+        let gpu_blocks_free = self.num_free_gpu_blocks();
+        let gpu_blocks_cached = self.prefix_tree.num_unused(true);
+        KvCacheStats {
+            block_size: self.block_size,
+            gpu_blocks_total: self.num_gpu_blocks,
+            gpu_blocks_used: self.num_gpu_blocks - gpu_blocks_free - gpu_blocks_cached,
+            gpu_blocks_cached,
+            gpu_blocks_free,
+            cpu_blocks_total: self.num_cpu_blocks,
+            cpu_blocks_used: self.num_cpu_blocks - self.cpu_allocator.get_num_free_blocks(),
+            cpu_blocks_cached: self.prefix_tree.num_blocks(false),
+            blocks_swapped_out: self.num_swapped_out,
+            blocks_swapped_in: self.num_swapped_in,
+            blocks_offloaded: self.num_offloaded,
+            blocks_restored: self.num_restored,
+            prefix_block_hits: self.num_prefix_hits,
+            prefix_block_misses: self.num_prefix_misses,
+            fragmentation,
+        }
+    }
+
+    /// The free GPU blocks, including the shared prefix blocks which no sequence uses.
+    fn num_available_gpu_blocks(&self) -> usize {
+        *self.gpu_allocator.get_num_free_blocks() + self.prefix_tree.num_unused(true)
+    }
+
+    fn free_block(&mut self, block: Arc<PhysicalTokenBlock>) {
+        if block.deref_mut().is_gpu {
+            self.gpu_allocator.free_block(block)
+        } else {
+            let (block_id, refcount) = {
+                let block = block.deref_mut();
+                (block.block_id, block.refcount)
+            };
+            // A freed block may be reused before the pending offload to it is executed
+            if refcount == 1 {
+                self.blocks_to_offload
+                    .retain(|_, host_block_id| *host_block_id != block_id);
+            }
+            self.cpu_allocator.free_block(block)
+        }
+    }
+
+    /// Evict shared prefix blocks which no sequence uses until `n` GPU blocks are free. Within
+    /// the host offload budget, the least recently used blocks are offloaded to host blocks first,
+    /// so they are not recomputed if they are matched again.
+    fn reserve_gpu_blocks(&mut self, n: usize) {
+        let num_free = |this: &Self| *this.gpu_allocator.get_num_free_blocks();
+        if num_free(self) >= n {
+            return;
+        }
+        for key in self.prefix_tree.lru_unused_gpu() {
+            if num_free(self) >= n || !self.reserve_offload_block() {
+                break;
+            }
+            let host_block = self.cpu_allocator.allocate();
+            let gpu_block = self
+                .prefix_tree
+                .replace_block(key, host_block.clone())
+                .unwrap();
+            self.blocks_to_offload.insert(
+                gpu_block.deref_mut().block_id,
+                host_block.deref_mut().block_id,
+            );
+            self.num_offloaded += 1;
+            self.gpu_allocator.free_block(gpu_block);
+        }
+        let num_free = num_free(self);
+        if num_free < n {
+            for block in self.prefix_tree.evict(n - num_free, true) {
+                self.free_block(block);
+            }
+        }
+    }
+
+    /// If a shared prefix block can be offloaded to the host, evicting the least recently used
+    /// offloaded blocks if the offload budget or the host blocks are used up.
+    fn reserve_offload_block(&mut self) -> bool {
+        let has_room = |this: &Self| {
+            this.prefix_tree.num_blocks(false) < this.num_offload_blocks
+                && this.cpu_allocator.get_num_free_blocks() > 0
+        };
+        if self.num_offload_blocks == 0 {
+            return false;
+        }
+        if !has_room(self) {
+            for block in self.prefix_tree.evict(1, false) {
+                self.free_block(block);
+            }
+        }
+        has_room(self)
+    }
+
+    /// Take the offloads (GPU to host) and restores (host to GPU) of shared prefix blocks, which
+    /// must be executed in that order before the next forward pass.
+    pub fn take_offload_ops(&mut self) -> (HashMap<usize, usize>, HashMap<usize, usize>) {
+        for block in std::mem::take(&mut self.restored_host_blocks) {
+            self.cpu_allocator.free_block(block);
+        }
+        (
+            std::mem::take(&mut self.blocks_to_offload),
+            std::mem::take(&mut self.blocks_to_restore),
+        )
+    }
+
+    /// Share the first `n_blocks` blocks of the sequence `id`, which must be full and computed,
+    /// with later sequences which start with the same tokens.
+    pub fn share_prefix(
+        &mut self,
+        id: usize,
+        logical_blocks: &[LogicalTokenBlock],
+        n_blocks: usize,
+    ) {
+        let Some(table) = self.block_tables.get(&id) else {
+            return;
+        };
+        let blocks = logical_blocks
+            .iter()
+            .zip(table)
+            .take(n_blocks)
+            .take_while(|(logical, physical)| logical.is_full() && physical.deref_mut().is_gpu)
+            .map(|(logical, physical)| (logical.toks(), physical));
+        self.prefix_tree.insert(blocks);
+    }
+
+    /// The shared blocks for the longest prefix of the full blocks of `logical_blocks`, up to
+    /// `max_blocks`. The caller holds a reference to the returned blocks, which are released with
+    /// the sequence they are allocated to.
+    pub fn match_prefix(
+        &mut self,
+        logical_blocks: &[LogicalTokenBlock],
+        max_blocks: usize,
+    ) -> Vec<Arc<PhysicalTokenBlock>> {
+        let blocks = logical_blocks
+            .iter()
+            .take(max_blocks)
+            .take_while(|logical| logical.is_full())
+            .map(|logical| logical.toks())
+            .collect::<Vec<_>>();
+        let num_queried = blocks.len();
+        let matched = self.prefix_tree.match_prefix(blocks);
+        // Hold the matched blocks first, so they are not evicted to make room for the restores
+        for (_, block) in &matched {
+            block.deref_mut().increment_refcount();
+        }
+        let mut shared = Vec::with_capacity(matched.len());
+        let mut matched = matched.into_iter();
+        for (key, block) in matched.by_ref() {
+            if block.deref_mut().is_gpu {
+                shared.push(block);
+                continue;
+            }
+            self.reserve_gpu_blocks(1);
+            if *self.gpu_allocator.get_num_free_blocks() == 0 {
+                self.free_block(block);
+                break;
+            }
+            let gpu_block = self.gpu_allocator.allocate();
+            self.blocks_to_restore
+                .insert(block.deref_mut().block_id, gpu_block.deref_mut().block_id);
+            gpu_block.deref_mut().increment_refcount();
+            let host_block = self
+                .prefix_tree
+                .replace_block(key, gpu_block.clone())
+                .unwrap();
+            // The reference of the caller is released once the restore is executed
+            self.cpu_allocator.free_block(host_block);
+            self.restored_host_blocks.push(block);
+            self.num_restored += 1;
+            shared.push(gpu_block);
+        }
+        // The blocks after one which could not be restored are not shared
+        for (_, block) in matched {
+            self.free_block(block);
+        }
+        self.num_prefix_hits += shared.len();
+        self.num_prefix_misses += num_queried - shared.len();
+        shared
+    }
+
+    /// Release the shared prefix blocks of a sequence which finished before they were allocated
+    /// to it.
+    pub fn free_prefill_blocks(&mut self, seq: &mut impl BlockEngineSequence) {
+        for block in seq.take_physical_blocks_prefill().into_iter().flatten() {
+            self.gpu_allocator.free_block(block);
+        }
+    }
+
+    /// Release all shared prefix blocks. Sequences which use them keep their references.
+    pub fn clear_shared_prefixes(&mut self) {
+        for block in self.prefix_tree.clear() {
+            self.free_block(block);
+        }
+    }
+
     pub fn can_allocate(&self, seq: &mut impl BlockEngineSequence) -> AllocStatus {
         let num_required_blocks = seq.logical_token_blocks().len();
-        let num_free_gpu_blocks = self.gpu_allocator.get_num_free_blocks();
+        let num_free_gpu_blocks = self.num_available_gpu_blocks();
 
-        if self.num_gpu_blocks < num_required_blocks {
+        if self.num_gpu_blocks < num_required_blocks + self.watermark_blocks {
             AllocStatus::Impossible
-        } else if *num_free_gpu_blocks < num_required_blocks {
+        } else if num_free_gpu_blocks < num_required_blocks + self.watermark_blocks {
             AllocStatus::Later {
                 waitlisted_count: seq.increment_waitlist_count(),
             }
@@ -244,11 +521,13 @@ impl BlockEngine {
         if let Some(physical_blocks_prefill) = seq.take_physical_blocks_prefill() {
             let mut block_table = physical_blocks_prefill.clone();
             let n_extra_blocks = seq.logical_token_blocks().len() - block_table.len();
+            self.reserve_gpu_blocks(n_extra_blocks);
             for _ in 0..n_extra_blocks {
                 block_table.push(self.gpu_allocator.allocate());
             }
             self.block_tables.insert(seq.get_id(), block_table.clone());
         } else {
+            self.reserve_gpu_blocks(seq.logical_token_blocks().len());
             let mut block_table = Vec::new();
             for _logcical_idx in 0..seq.logical_token_blocks().len() {
                 block_table.push(self.gpu_allocator.allocate());
@@ -258,9 +537,8 @@ impl BlockEngine {
     }
 
     pub fn can_append_token_to_seq(&self, seq: &impl BlockEngineSequence) -> bool {
-        let free_blocks = self.gpu_allocator.get_num_free_blocks();
         // Physical blocks = logical blocks
-        seq.blocks_to_add_new_tok() <= *free_blocks
+        seq.blocks_to_add_new_tok() <= self.num_available_gpu_blocks()
     }
 
     pub fn free_sequence(&mut self, id: usize) {
@@ -268,7 +546,11 @@ impl BlockEngine {
         if let Some(block_table) = self.block_tables.get(&id) {
             // Free from block table
             for block in block_table {
-                self.gpu_allocator.free_block(block.clone())
+                if block.deref_mut().is_gpu {
+                    self.gpu_allocator.free_block(block.clone())
+                } else {
+                    self.cpu_allocator.free_block(block.clone())
+                }
             }
 
             self.block_tables.remove(&id);
@@ -281,6 +563,15 @@ impl BlockEngine {
         &mut self,
         sequence: &impl BlockEngineSequence,
     ) -> Option<(usize, usize)> {
+        let table = self.block_tables.get(&sequence.get_id())?;
+        // A new block, or a copy of the last block if it is shared
+        let needs_block = sequence.blocks_to_add_new_tok() == 1
+            || table
+                .last()
+                .is_some_and(|last| last.deref_mut().refcount != 1);
+        if needs_block {
+            self.reserve_gpu_blocks(1);
+        }
         let table = self.block_tables.get_mut(&sequence.get_id())?;
 
         match sequence.blocks_to_add_new_tok() {
@@ -308,4 +599,75 @@ impl BlockEngine {
             }
         }
     }
+
+    /// If the blocks of the sequence fit in the free host blocks.
+    pub fn can_swap_out(&self, seq: &impl BlockEngineSequence) -> bool {
+        self.block_tables
+            .get(&seq.get_id())
+            .is_some_and(|table| table.len() <= self.cpu_allocator.get_num_free_blocks())
+    }
+
+    /// If the blocks of a swapped out sequence, and a block for its next token, fit in the free
+    /// GPU blocks.
+    pub fn can_swap_in(&self, seq: &impl BlockEngineSequence) -> bool {
+        self.block_tables
+            .get(&seq.get_id())
+            .is_some_and(|table| table.len() < self.num_available_gpu_blocks())
+    }
+
+    /// Move the blocks of the sequence to host blocks. Returns the mapping of GPU to host blocks.
+    pub fn swap_out(&mut self, seq: &impl BlockEngineSequence) -> HashMap<usize, usize> {
+        let Some(table) = self.block_tables.remove(&seq.get_id()) else {
+            return HashMap::new();
+        };
+        let (table, mapping) = Self::swap(table, &mut self.gpu_allocator, &mut self.cpu_allocator);
+        self.block_tables.insert(seq.get_id(), table);
+        self.num_swapped_out += mapping.len();
+        mapping
+    }
+
+    /// Move the blocks of a swapped out sequence back to GPU blocks. Returns the mapping of host to
+    /// GPU blocks.
+    pub fn swap_in(&mut self, seq: &impl BlockEngineSequence) -> HashMap<usize, usize> {
+        let Some(table) = self.block_tables.remove(&seq.get_id()) else {
+            return HashMap::new();
+        };
+        self.reserve_gpu_blocks(table.len());
+        let (table, mapping) = Self::swap(table, &mut self.cpu_allocator, &mut self.gpu_allocator);
+        self.block_tables.insert(seq.get_id(), table);
+        self.num_swapped_in += mapping.len();
+        mapping
+    }
+
+    /// Copy each block of the table to a block of `dst`. Blocks which are shared with other
+    /// sequences stay allocated for them.
+    fn swap<S, D>(
+        table: BlockTable,
+        src: &mut Allocator<S>,
+        dst: &mut Allocator<D>,
+    ) -> (BlockTable, HashMap<usize, usize>) {
+        let mut mapping: HashMap<usize, Arc<PhysicalTokenBlock>> = HashMap::new();
+        let mut new_table = Vec::with_capacity(table.len());
+        for block in table {
+            let src_id = block.deref_mut().block_id;
+            let new_block = match mapping.get(&src_id) {
+                Some(new_block) => {
+                    new_block.deref_mut().increment_refcount();
+                    new_block.clone()
+                }
+                None => {
+                    let new_block = dst.allocate();
+                    mapping.insert(src_id, new_block.clone());
+                    new_block
+                }
+            };
+            new_table.push(new_block);
+            src.free_block(block);
+        }
+        let mapping = mapping
+            .into_iter()
+            .map(|(src_id, block)| (src_id, block.deref_mut().block_id))
+            .collect();
+        (new_table, mapping)
+    }
 }
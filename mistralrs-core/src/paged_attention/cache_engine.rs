@@ -17,15 +17,27 @@ pub enum PagedCacheType {
     #[default]
     Auto,
     F8E4M3,
+    /// Int8 with an absmax scale for each token and KV head, stored in the value cache blocks.
+    Int8,
 }
 
 impl PagedCacheType {
     pub fn to_dtype(&self, act_dtype: DType) -> DType {
         match self {
             PagedCacheType::F8E4M3 => DType::F8E4M3,
+            PagedCacheType::Int8 => DType::U8,
             PagedCacheType::Auto => act_dtype,
         }
     }
+
+    /// The rows after the values of each head in a value cache block, which hold the `f32` key
+    /// and value scales of its tokens. This is `INT8_SCALE_ROWS` in `mistralrs-paged-attn`.
+    pub(crate) fn value_scale_rows(&self) -> usize {
+        match self {
+            PagedCacheType::Int8 => 2 * std::mem::size_of::<f32>(),
+            PagedCacheType::Auto | PagedCacheType::F8E4M3 => 0,
+        }
+    }
 }
 
 impl FromStr for PagedCacheType {
@@ -34,8 +46,9 @@ impl FromStr for PagedCacheType {
         match s {
             "auto" => Ok(Self::Auto),
             "f8e4m3" => Ok(Self::F8E4M3),
+            "int8" => Ok(Self::Int8),
             other => Err(format!(
-                "Unexpected `PagedCacheType`, got `{other}` but expected `auto` and `f8e4m3`."
+                "Unexpected `PagedCacheType`, got `{other}` but expected `auto`, `f8e4m3` or `int8`."
             )),
         }
     }
@@ -45,6 +58,14 @@ impl FromStr for PagedCacheType {
 pub struct CacheConfig {
     pub block_size: usize,
     pub num_gpu_blocks: usize,
+    /// Blocks in host memory which preempted sequences are swapped out to, and cold shared prefix
+    /// blocks are offloaded to.
+    pub num_cpu_blocks: usize,
+    /// The most host blocks which may hold offloaded shared prefix blocks, included in
+    /// `num_cpu_blocks`.
+    pub num_offload_blocks: usize,
+    /// GPU blocks which are kept free when admitting new sequences.
+    pub watermark_blocks: usize,
     pub cache_type: PagedCacheType,
 }
 
@@ -52,6 +73,7 @@ pub type KVCache = (Tensor, Tensor);
 
 pub struct CacheEngine {
     gpu_cache: Arc<Mutex<Vec<KVCache>>>,
+    cpu_cache: Vec<KVCache>,
 }
 
 impl CacheEngine {
@@ -71,6 +93,7 @@ impl CacheEngine {
                 device,
                 layer_devices,
             )?)),
+            cpu_cache: Self::allocate_cpu_cache(model_config, cache_config, dtype)?,
         })
     }
 
@@ -91,8 +114,7 @@ impl CacheEngine {
     ) -> Result<Vec<KVCache>> {
         let key_block_shape =
             Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
+        let value_block_shape = Self::calculate_value_block_shape(model_config, cache_config);
         let mut gpu_cache = Vec::new();
 
         for device in layer_devices
@@ -194,6 +216,45 @@ impl CacheEngine {
         Ok(gpu_cache)
     }
 
+    fn allocate_cpu_cache(
+        model_config: &dyn ModelConfigLike,
+        cache_config: &CacheConfig,
+        dtype: DType,
+    ) -> Result<Vec<KVCache>> {
+        if cache_config.num_cpu_blocks == 0 {
+            return Ok(Vec::new());
+        }
+        let key_block_shape =
+            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
+        let value_block_shape = Self::calculate_value_block_shape(model_config, cache_config);
+        let mut cpu_cache = Vec::new();
+        for _ in 0..model_config.num_layers() {
+            let key_blocks = Tensor::zeros(
+                (
+                    cache_config.num_cpu_blocks,
+                    key_block_shape.0,
+                    key_block_shape.1,
+                    key_block_shape.2,
+                    key_block_shape.3,
+                ),
+                dtype,
+                &Device::Cpu,
+            )?;
+            let value_blocks = Tensor::zeros(
+                (
+                    cache_config.num_cpu_blocks,
+                    value_block_shape.0,
+                    value_block_shape.1,
+                    value_block_shape.2,
+                ),
+                dtype,
+                &Device::Cpu,
+            )?;
+            cpu_cache.push((key_blocks, value_blocks));
+        }
+        Ok(cpu_cache)
+    }
+
     fn calculate_key_block_shape(
         model_config: &dyn ModelConfigLike,
         dtype: DType,
@@ -211,12 +272,12 @@ impl CacheEngine {
 
     fn calculate_value_block_shape(
         model_config: &dyn ModelConfigLike,
-        block_size: usize,
+        cache_config: &CacheConfig,
     ) -> (usize, usize, usize) {
         (
             model_config.num_kv_heads(),
-            model_config.v_head_dim(),
-            block_size,
+            model_config.v_head_dim() + cache_config.cache_type.value_scale_rows(),
+            cache_config.block_size,
         )
     }
 }
@@ -229,10 +290,62 @@ impl CacheEngine {
         Ok(())
     }
 
+    /// Offload shared prefix blocks to the host, swap in the blocks of resumed sequences and
+    /// restored prefix blocks, then swap out those of preempted sequences, in the order the
+    /// scheduler allocated them. This must run before the freed blocks are written.
+    pub fn execute_swap_ops(
+        &self,
+        blocks_to_offload: &HashMap<usize, usize>,
+        blocks_to_swap_in: &HashMap<usize, usize>,
+        blocks_to_swap_out: &HashMap<usize, usize>,
+    ) -> Result<()> {
+        let gpu_cache = self.get_kv_cache();
+        if !blocks_to_offload.is_empty() {
+            Self::swap(&gpu_cache, &self.cpu_cache, blocks_to_offload)?;
+        }
+        if !blocks_to_swap_in.is_empty() {
+            Self::swap(&self.cpu_cache, &gpu_cache, blocks_to_swap_in)?;
+        }
+        if !blocks_to_swap_out.is_empty() {
+            Self::swap(&gpu_cache, &self.cpu_cache, blocks_to_swap_out)?;
+        }
+        Ok(())
+    }
+
+    fn swap(src: &[KVCache], dst: &[KVCache], src_to_dst: &HashMap<usize, usize>) -> Result<()> {
+        for ((src_k, src_v), (dst_k, dst_v)) in src.iter().zip(dst) {
+            for (src_block, dst_block) in src_to_dst {
+                let k = src_k.narrow(0, *src_block, 1)?.to_device(dst_k.device())?;
+                dst_k.slice_set(&k, 0, *dst_block)?;
+                let v = src_v.narrow(0, *src_block, 1)?.to_device(dst_v.device())?;
+                dst_v.slice_set(&v, 0, *dst_block)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn copy(&self, src_to_dst: &HashMap<usize, Vec<usize>>) -> Result<()> {
         #[cfg(any(all(feature = "cuda", target_family = "unix"), feature = "metal"))]
         {
             let mut gpu_cache = self.get_kv_cache();
+            // The int8 value blocks are larger than the key blocks as they hold the scales, so
+            // they are copied a block at a time.
+            if gpu_cache
+                .first()
+                .is_some_and(|(k, _)| k.dtype() == DType::U8)
+            {
+                for (k, v) in gpu_cache.iter() {
+                    for (src_block, dst_blocks) in src_to_dst {
+                        let src_k = k.narrow(0, *src_block, 1)?.copy()?;
+                        let src_v = v.narrow(0, *src_block, 1)?.copy()?;
+                        for dst_block in dst_blocks {
+                            k.slice_set(&src_k, 0, *dst_block)?;
+                            v.slice_set(&src_v, 0, *dst_block)?;
+                        }
+                    }
+                }
+                return Ok(());
+            }
             #[allow(clippy::map_identity)]
             let caches: (Vec<&mut Tensor>, Vec<&mut Tensor>) =
                 gpu_cache.iter_mut().map(|(a, b)| (a, b)).unzip();
@@ -249,3 +362,49 @@ impl CacheEngine {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device};
+
+    use super::{CacheConfig, CacheEngine, PagedCacheType};
+    use crate::paged_attention::ModelConfigMetadata;
+
+    #[test]
+    fn int8_value_blocks_hold_the_scales() -> candle_core::Result<()> {
+        assert_eq!("int8".parse::<PagedCacheType>(), Ok(PagedCacheType::Int8));
+
+        let config = ModelConfigMetadata {
+            max_seq_len: 128,
+            num_layers: 2,
+            hidden_size: 256,
+            num_kv_heads: 2,
+            num_attn_heads: 4,
+            sliding_window: None,
+            k_head_dim: 64,
+            v_head_dim: 64,
+        };
+        let cache_config = CacheConfig {
+            block_size: 16,
+            num_gpu_blocks: 3,
+            num_cpu_blocks: 1,
+            num_offload_blocks: 0,
+            watermark_blocks: 0,
+            cache_type: PagedCacheType::Int8,
+        };
+        let engine = CacheEngine::new(
+            &config,
+            &cache_config,
+            DType::BF16,
+            &Device::Cpu,
+            vec![None; 2],
+        )?;
+        let cache = engine.get_kv_cache();
+        let (k, v) = &cache[0];
+        assert_eq!(k.dtype(), DType::U8);
+        assert_eq!(k.dims(), &[3, 2, 4, 16, 16]);
+        // 8 rows of `block_size` bytes are the `f32` key and value scales of the 16 tokens
+        assert_eq!(v.dims(), &[3, 2, 64 + 8, 16]);
+        Ok(())
+    }
+}
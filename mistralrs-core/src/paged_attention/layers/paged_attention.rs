@@ -187,7 +187,10 @@ impl PagedAttention {
             (q, k, v)
         };
 
-        if let Some(collector) = &self.k_v_scale {
+        // The int8 cache computes a scale for each token as it is written.
+        let int8_cache = key_cache.as_ref().is_some_and(|k| k.dtype() == DType::U8);
+
+        if let Some(collector) = self.k_v_scale.as_ref().filter(|_| !int8_cache) {
             let collector = &mut *get_mut_arcmutex!(collector);
             if let KvScaleCalculator::InProgress {
                 k_scale,
@@ -206,7 +209,7 @@ impl PagedAttention {
             }
         }
 
-        let k_v_scale = if let Some(collector) = &self.k_v_scale {
+        let k_v_scale = if let Some(collector) = self.k_v_scale.as_ref().filter(|_| !int8_cache) {
             match &*get_mut_arcmutex!(collector) {
                 // Use in progress during collection
                 KvScaleCalculator::Done { k_scale, v_scale } => {
@@ -221,7 +224,7 @@ impl PagedAttention {
         } else {
             None
         };
-        assert!(k_v_scale.is_some());
+        assert!(int8_cache || k_v_scale.is_some());
 
         // key: Tensor,              // [num_tokens, num_heads, head_size]
         // value: Tensor,            // [num_tokens, num_heads, head_size]
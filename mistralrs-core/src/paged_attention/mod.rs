@@ -8,6 +8,8 @@ mod block_engine_sequence;
 mod cache_engine;
 mod config;
 mod layers;
+/// The radix tree of KV cache blocks which sequences with a common prefix share.
+mod prefix_tree;
 mod scheduler;
 pub const _PAD_SLOT_ID: i64 = -1;
 
@@ -32,6 +34,9 @@ pub struct PagedAttentionConfig {
     pub(crate) block_size: Option<usize>,
     pub(crate) mem_gpu: MemoryGpuConfig,
     pub(crate) cache_type: PagedCacheType,
+    pub(crate) swap_space: usize,
+    pub(crate) host_offload_bytes: usize,
+    pub(crate) watermark: f32,
 }
 
 impl PagedAttentionConfig {
@@ -44,8 +49,35 @@ impl PagedAttentionConfig {
             block_size,
             mem_gpu,
             cache_type,
+            swap_space: 0,
+            host_offload_bytes: 0,
+            watermark: 0.,
         })
     }
+
+    /// Host memory in MB to swap the KV cache of preempted sequences to. Without swap space,
+    /// preempted sequences are recomputed from their tokens when resumed.
+    pub fn with_swap_space(mut self, swap_space: usize) -> Self {
+        self.swap_space = swap_space;
+        self
+    }
+
+    /// Host memory in bytes to offload cold shared prefix blocks to. Blocks which no sequence uses
+    /// are moved to host memory instead of being evicted when the GPU blocks run out, and moved
+    /// back when a new sequence starts with their tokens. This keeps the KV cache of idle
+    /// conversations resident without holding GPU memory.
+    pub fn with_host_offload(mut self, host_offload_bytes: usize) -> Self {
+        self.host_offload_bytes = host_offload_bytes;
+        self
+    }
+
+    /// The fraction of the GPU blocks, in [0, 1), to keep free when admitting new sequences. This
+    /// leaves room for the running sequences to grow, so they are not preempted as soon as the KV
+    /// cache is full. Defaults to 0.
+    pub fn with_watermark(mut self, watermark: f32) -> Self {
+        self.watermark = watermark;
+        self
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -62,31 +94,30 @@ pub enum MemoryGpuConfig {
     ContextSize(usize),
 }
 
-// See `pagedattention.cu` CALL_V1_LAUNCHER_BLOCK_SIZE
-const SUPPORTED_BLOCK_SIZE: &[usize] = &[8, 16, 32];
+// See `pagedattention.cuh` CALL_V1_LAUNCHER_BLOCK_SIZE
+const SUPPORTED_BLOCK_SIZE: &[usize] = &[8, 16, 32, 64];
 
 const SIZE_IN_MB: usize = 1024 * 1024;
 
+// `$scale_rows` are the extra rows of each value cache head, see `PagedCacheType::value_scale_rows`
 macro_rules! mb_to_blocks {
-    ($mb_size:expr, $dtype_size:expr, $block_size:expr, $config:expr) => {
+    ($mb_size:expr, $dtype_size:expr, $scale_rows:expr, $block_size:expr, $config:expr) => {
         $mb_size
             / $dtype_size
             / $block_size
             / $config.num_kv_heads()
-            / ($config.k_head_dim().max($config.v_head_dim()))
+            / (2 * $config.k_head_dim().max($config.v_head_dim()) + $scale_rows)
             / $config.num_layers()
-            / 2
     };
 }
 
 macro_rules! ctxt_to_blocks {
-    ($context_len:expr, $dtype_size:expr, $block_size:expr, $config:expr) => {
+    ($context_len:expr, $dtype_size:expr, $scale_rows:expr, $block_size:expr, $config:expr) => {
         $context_len
             * $dtype_size
             * $config.num_kv_heads()
-            * ($config.k_head_dim().max($config.v_head_dim()))
+            * (2 * $config.k_head_dim().max($config.v_head_dim()) + $scale_rows)
             * $config.num_layers()
-            * 2
     };
 }
 
@@ -94,6 +125,9 @@ macro_rules! ctxt_to_blocks {
 #[allow(clippy::too_many_arguments)]
 pub fn calculate_cache_config(
     mem_gpu: MemoryGpuConfig,
+    swap_space: usize,
+    host_offload_bytes: usize,
+    watermark: f32,
     block_size: Option<usize>,
     dtype: DType,
     cache_type: PagedCacheType,
@@ -106,8 +140,12 @@ pub fn calculate_cache_config(
     if !SUPPORTED_BLOCK_SIZE.contains(&block_size) {
         anyhow::bail!("Block size must be in {SUPPORTED_BLOCK_SIZE:?}, got {block_size}");
     }
+    if !(0. ..1.).contains(&watermark) {
+        anyhow::bail!("Watermark must be in [0, 1), got {watermark}");
+    }
     let dtype = cache_type.to_dtype(dtype);
     let dtype_size = dtype.size_in_bytes();
+    let scale_rows = cache_type.value_scale_rows();
 
     let mut min_mem_gpu = usize::MAX;
     for dev in layer_devices {
@@ -123,7 +161,7 @@ pub fn calculate_cache_config(
                 (total * f - used) as usize
             }
             MemoryGpuConfig::ContextSize(toks) => {
-                ctxt_to_blocks!(toks, dtype_size, block_size, config) / SIZE_IN_MB
+                ctxt_to_blocks!(toks, dtype_size, scale_rows, block_size, config) / SIZE_IN_MB
             }
         };
         min_mem_gpu = min_mem_gpu.min(mem_gpu);
@@ -131,7 +169,7 @@ pub fn calculate_cache_config(
 
     // // Cap at kv cache for max seq len
     // let mem_for_toks =
-    //     ctxt_to_blocks!(config.max_seq_len(), dtype_size, block_size, config) / SIZE_IN_MB;
+    //     ctxt_to_blocks!(config.max_seq_len(), dtype_size, scale_rows, block_size, config) / SIZE_IN_MB;
     // let mem_gpu = min_mem_gpu.min(mem_for_toks);
 
     // Cap Metal GPU memory to the wired (non‑paged) allocation limit reported by the kernel (`iogpu.wired_limit_mb`).
@@ -158,19 +196,54 @@ To raise this cap run: `sudo sysctl -w iogpu.wired_limit_mb=<desired_mb>`.",
         min_mem_gpu
     };
 
-    let num_gpu_blocks = mb_to_blocks!(mem_gpu * SIZE_IN_MB, dtype_size, block_size, config);
+    let num_gpu_blocks = mb_to_blocks!(
+        mem_gpu * SIZE_IN_MB,
+        dtype_size,
+        scale_rows,
+        block_size,
+        config
+    );
     if num_gpu_blocks == 0 {
         anyhow::bail!("Num GPU blocks is 0. This means there is not enough memory. Either reduce the memory amount/utilization/context size or disable PagedAttention.");
     }
 
+    let num_swap_blocks = mb_to_blocks!(
+        swap_space * SIZE_IN_MB,
+        dtype_size,
+        scale_rows,
+        block_size,
+        config
+    );
+    let num_offload_blocks = mb_to_blocks!(
+        host_offload_bytes,
+        dtype_size,
+        scale_rows,
+        block_size,
+        config
+    );
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+    let watermark_blocks = (watermark * num_gpu_blocks as f32) as usize;
+
     if !silent {
         info!("Allocating {mem_gpu} MB for PagedAttention KV cache per GPU");
         info!("PagedAttention KV cache type is {dtype:?}");
         info!("Using PagedAttention with block size {block_size} and {num_gpu_blocks} GPU blocks: available context length is {} tokens", num_gpu_blocks*block_size);
+        if num_swap_blocks > 0 {
+            info!("Using {swap_space} MB of host memory ({num_swap_blocks} blocks) to swap out preempted sequences");
+        }
+        if num_offload_blocks > 0 {
+            info!(
+                "Using {} MB of host memory ({num_offload_blocks} blocks) to offload cold prefix blocks",
+                host_offload_bytes / SIZE_IN_MB
+            );
+        }
     }
     Ok(CacheConfig {
         block_size,
         num_gpu_blocks,
+        num_cpu_blocks: num_swap_blocks + num_offload_blocks,
+        num_offload_blocks,
+        watermark_blocks,
         cache_type,
     })
 }
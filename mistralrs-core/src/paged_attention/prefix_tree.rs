@@ -0,0 +1,336 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+};
+
+use super::PhysicalTokenBlock;
+
+/// Identifies a block by its tokens and the tokens of all the blocks before it.
+type NodeKey = u64;
+
+struct Node {
+    tokens: Vec<usize>,
+    parent: Option<NodeKey>,
+    block: Arc<PhysicalTokenBlock>,
+    n_children: usize,
+    last_access: u64,
+}
+
+impl Node {
+    fn is_unused(&self, on_gpu: bool) -> bool {
+        let block = self.block.deref_mut();
+        block.is_gpu() == on_gpu && block.refcount() == 1
+    }
+
+    /// Leaves on the host are always evictable, leaves on the GPU only when evicting GPU blocks.
+    fn is_evictable_leaf(&self, on_gpu: bool) -> bool {
+        let block = self.block.deref_mut();
+        self.n_children == 0 && block.refcount() == 1 && (on_gpu || !block.is_gpu())
+    }
+}
+
+/// A radix tree of full KV cache blocks, with one block per edge, in the style of SGLang's
+/// RadixAttention. Sequences whose tokens start with the same blocks share the physical blocks of
+/// the tree instead of computing them again, even while the sequence which computed them is still
+/// running.
+///
+/// The tree holds a reference to each of its blocks, so they outlive the sequences which computed
+/// them until they are evicted to make room for new blocks. Only full blocks are shared, which are
+/// never written to again, so a sequence copies a block before writing to it only if it is the
+/// last block of its table (see `BlockEngine::append_token_slot_to_seq`).
+///
+/// With a host offload budget, unused blocks are moved to host memory instead of being evicted,
+/// and moved back to the GPU when a later sequence matches them. A block is only used by a
+/// sequence if all the blocks before it are, so the blocks on the host never have descendants
+/// which are used.
+#[derive(Default)]
+pub(crate) struct PrefixTree {
+    nodes: HashMap<NodeKey, Node>,
+    clock: u64,
+}
+
+impl PrefixTree {
+    fn key(parent: Option<NodeKey>, tokens: &[usize]) -> NodeKey {
+        let mut hasher = DefaultHasher::new();
+        parent.hash(&mut hasher);
+        tokens.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// The nodes and physical blocks of the longest prefix of `blocks` in the tree. Each item of
+    /// `blocks` holds the tokens of one full block.
+    pub(crate) fn match_prefix<'a>(
+        &mut self,
+        blocks: impl IntoIterator<Item = &'a [usize]>,
+    ) -> Vec<(NodeKey, Arc<PhysicalTokenBlock>)> {
+        let now = self.tick();
+        let mut parent = None;
+        let mut matched = Vec::new();
+        for tokens in blocks {
+            let key = Self::key(parent, tokens);
+            match self.nodes.get_mut(&key) {
+                Some(node) if node.tokens == tokens => {
+                    node.last_access = now;
+                    matched.push((key, node.block.clone()));
+                    parent = Some(key);
+                }
+                _ => break,
+            }
+        }
+        matched
+    }
+
+    /// Add the full blocks of a sequence, given as their tokens and physical blocks, to the tree.
+    /// Blocks which are already in the tree keep their physical block. Returns the number of
+    /// blocks which were added.
+    pub(crate) fn insert<'a>(
+        &mut self,
+        blocks: impl IntoIterator<Item = (&'a [usize], &'a Arc<PhysicalTokenBlock>)>,
+    ) -> usize {
+        let now = self.tick();
+        let mut parent = None;
+        let mut n_added = 0;
+        for (tokens, block) in blocks {
+            let key = Self::key(parent, tokens);
+            match self.nodes.get_mut(&key) {
+                Some(node) if node.tokens == tokens => node.last_access = now,
+                // A hash collision, which is not shared
+                Some(_) => break,
+                None => {
+                    block.deref_mut().increment_refcount();
+                    self.nodes.insert(
+                        key,
+                        Node {
+                            tokens: tokens.to_vec(),
+                            parent,
+                            block: block.clone(),
+                            n_children: 0,
+                            last_access: now,
+                        },
+                    );
+                    if let Some(parent) = parent.and_then(|parent| self.nodes.get_mut(&parent)) {
+                        parent.n_children += 1;
+                    }
+                    n_added += 1;
+                }
+            }
+            parent = Some(key);
+        }
+        n_added
+    }
+
+    /// The number of blocks on the GPU (or the host, if `on_gpu` is false) which are only
+    /// referenced by the tree. A block used by a sequence is also used by the sequence for all the
+    /// blocks before it, so these can all be evicted.
+    pub(crate) fn num_unused(&self, on_gpu: bool) -> usize {
+        self.nodes
+            .values()
+            .filter(|node| node.is_unused(on_gpu))
+            .count()
+    }
+
+    /// The number of blocks of the tree on the GPU (or the host, if `on_gpu` is false).
+    pub(crate) fn num_blocks(&self, on_gpu: bool) -> usize {
+        self.nodes
+            .values()
+            .filter(|node| node.block.deref_mut().is_gpu() == on_gpu)
+            .count()
+    }
+
+    /// The nodes of the blocks on the GPU which are only referenced by the tree, least recently
+    /// used first.
+    pub(crate) fn lru_unused_gpu(&self) -> Vec<NodeKey> {
+        let mut unused = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.is_unused(true))
+            .map(|(key, node)| (node.last_access, *key))
+            .collect::<Vec<_>>();
+        unused.sort_unstable();
+        unused.into_iter().map(|(_, key)| key).collect()
+    }
+
+    /// Replace the physical block of a node, for example after it was moved to another device.
+    /// The tree takes the reference of the caller to `block`, and returns its reference to the old
+    /// block. Returns `None` if the node is not in the tree.
+    pub(crate) fn replace_block(
+        &mut self,
+        key: NodeKey,
+        block: Arc<PhysicalTokenBlock>,
+    ) -> Option<Arc<PhysicalTokenBlock>> {
+        self.nodes
+            .get_mut(&key)
+            .map(|node| std::mem::replace(&mut node.block, block))
+    }
+
+    /// Evict blocks which are only referenced by the tree, least recently used leaves first, until
+    /// `n` blocks on the GPU (or the host, if `on_gpu` is false) were evicted or there are no more
+    /// such leaves. Evicting GPU blocks also evicts the host blocks after them, but not the other
+    /// way around. Returns the evicted blocks, whose references the caller must release.
+    pub(crate) fn evict(&mut self, n: usize, on_gpu: bool) -> Vec<Arc<PhysicalTokenBlock>> {
+        let mut leaves = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.is_evictable_leaf(on_gpu))
+            .map(|(key, node)| Reverse((node.last_access, *key)))
+            .collect::<BinaryHeap<_>>();
+
+        let mut n_evicted = 0;
+        let mut evicted = Vec::new();
+        while n_evicted < n {
+            let Some(Reverse((_, key))) = leaves.pop() else {
+                break;
+            };
+            let node = self.nodes.remove(&key).unwrap();
+            if let Some(parent_key) = node.parent {
+                if let Some(parent) = self.nodes.get_mut(&parent_key) {
+                    parent.n_children -= 1;
+                    if parent.is_evictable_leaf(on_gpu) {
+                        leaves.push(Reverse((parent.last_access, parent_key)));
+                    }
+                }
+            }
+            if node.block.deref_mut().is_gpu() == on_gpu {
+                n_evicted += 1;
+            }
+            evicted.push(node.block);
+        }
+        evicted
+    }
+
+    /// Remove all blocks from the tree. Returns the blocks, whose references the caller must
+    /// release.
+    pub(crate) fn clear(&mut self) -> Vec<Arc<PhysicalTokenBlock>> {
+        self.nodes.drain().map(|(_, node)| node.block).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        paged_attention::{
+            block_engine::AllocStatus, BlockEngine, BlockEngineSequence, LogicalTokenBlock,
+            PhysicalTokenBlock,
+        },
+        sequence::util_append_token_to_blocks,
+    };
+
+    struct TestSeq {
+        id: usize,
+        blocks: Vec<LogicalTokenBlock>,
+        prefill: Option<Vec<Arc<PhysicalTokenBlock>>>,
+    }
+
+    impl BlockEngineSequence for TestSeq {
+        fn blocks_to_add_new_tok(&self) -> usize {
+            0
+        }
+        fn take_physical_blocks_prefill(&mut self) -> Option<Vec<Arc<PhysicalTokenBlock>>> {
+            self.prefill.take()
+        }
+        fn get_id(&self) -> usize {
+            self.id
+        }
+        fn logical_token_blocks(&self) -> &[LogicalTokenBlock] {
+            &self.blocks
+        }
+        fn increment_waitlist_count(&mut self) -> usize {
+            0
+        }
+    }
+
+    fn blocks(toks: &[usize]) -> Vec<LogicalTokenBlock> {
+        let mut blocks = Vec::new();
+        for tok in toks {
+            util_append_token_to_blocks(*tok, &mut blocks, 2);
+        }
+        blocks
+    }
+
+    fn seq(id: usize, toks: &[usize]) -> TestSeq {
+        TestSeq {
+            id,
+            blocks: blocks(toks),
+            prefill: None,
+        }
+    }
+
+    #[test]
+    fn shares_and_evicts_prefix_blocks() {
+        let mut engine = BlockEngine::new(2, 4, 0, 0, 0);
+        let mut a = seq(0, &[1, 2, 3, 4, 5]);
+        engine.allocate(&mut a);
+        engine.share_prefix(0, &a.blocks, 2);
+
+        // A sequence which diverges in the second block shares the first one
+        let shared = engine.match_prefix(&blocks(&[1, 2, 3, 9, 9]), 2);
+        assert_eq!(shared.len(), 1);
+        assert_eq!(
+            shared[0].deref_mut().block_id,
+            engine.block_tables[&0][0].deref_mut().block_id
+        );
+
+        // The shared blocks outlive the sequences which use them
+        engine.free_sequence(0);
+        engine.free_prefill_blocks(&mut TestSeq {
+            prefill: Some(shared),
+            ..seq(1, &[])
+        });
+        assert_eq!(engine.num_free_gpu_blocks(), 2);
+        let shared = engine.match_prefix(&blocks(&[1, 2, 3, 4, 5]), 2);
+        assert_eq!(shared.len(), 2);
+        engine.free_prefill_blocks(&mut TestSeq {
+            prefill: Some(shared),
+            ..seq(1, &[])
+        });
+
+        // Until the blocks are needed by a new sequence
+        let mut c = seq(2, &[7; 7]);
+        assert!(matches!(engine.can_allocate(&mut c), AllocStatus::Ok));
+        engine.allocate(&mut c);
+        assert_eq!(engine.num_free_gpu_blocks(), 0);
+        assert!(engine.match_prefix(&blocks(&[1, 2, 3, 4, 5]), 2).is_empty());
+    }
+
+    #[test]
+    fn offloads_and_restores_prefix_blocks() {
+        let mut engine = BlockEngine::new(2, 2, 1, 1, 0);
+        let mut a = seq(0, &[1, 2, 3, 4]);
+        engine.allocate(&mut a);
+        engine.share_prefix(0, &a.blocks, 2);
+        engine.free_sequence(0);
+
+        // A new sequence needs both GPU blocks: the first shared block is offloaded, as it is the
+        // only one which fits in the budget, and the second one is evicted
+        let mut b = seq(1, &[7; 3]);
+        engine.allocate(&mut b);
+        let (offloaded, restored) = engine.take_offload_ops();
+        assert_eq!(offloaded.len(), 1);
+        assert!(restored.is_empty());
+        engine.free_sequence(1);
+
+        // Matching the prefix restores the offloaded block to the GPU
+        let shared = engine.match_prefix(&blocks(&[1, 2, 3, 4]), 2);
+        assert_eq!(shared.len(), 1);
+        assert!(shared[0].deref_mut().is_gpu());
+        let (offloaded, restored) = engine.take_offload_ops();
+        assert!(offloaded.is_empty());
+        assert_eq!(
+            restored,
+            [(0, shared[0].deref_mut().block_id)].into_iter().collect()
+        );
+
+        let stats = engine.stats(0.);
+        assert_eq!((stats.blocks_offloaded, stats.blocks_restored), (1, 1));
+        assert_eq!((stats.prefix_block_hits, stats.prefix_block_misses), (1, 1));
+    }
+}
@@ -4,22 +4,26 @@
 
 type SrcBlockFrom = usize;
 type DstBlocksTo = Vec<usize>;
+type DstBlockTo = usize;
 
 use std::{
     collections::{HashMap, VecDeque},
     sync::{atomic::Ordering, Arc, Mutex},
 };
 
+use mistralrs_quant::LoraAdapterSelection;
 use tracing::warn;
 
 use crate::{
     engine::IntervalLogger,
     get_mut_arcmutex,
     paged_attention::BlockEngine,
-    scheduler::{Scheduler, SchedulerOutput},
+    request::KvCacheStats,
+    scheduler::{Scheduler, SchedulerOutput, SchedulingPolicy, TenantUsage},
     sequence::{Sequence, SequenceState, StopReason},
-    TERMINATE_ALL_NEXT_STEP,
+    Response, TERMINATE_ALL_NEXT_STEP,
 };
+use tokio::sync::mpsc::Sender;
 
 use super::{block_engine::AllocStatus, BlockEngineSequence, BlockTables, CacheConfig};
 
@@ -30,18 +34,33 @@ pub struct PagedAttentionSchedulerOutput {
     /// Either ALL prompt or ALL completion.
     pub scheduled: Vec<Arc<Mutex<Sequence>>>,
     pub blocks_to_copy: HashMap<SrcBlockFrom, DstBlocksTo>,
+    /// GPU to host blocks of the shared prefix blocks offloaded in this step. These are executed
+    /// before the swaps.
+    pub blocks_to_offload: HashMap<SrcBlockFrom, DstBlockTo>,
+    /// Host to GPU blocks of the sequences resumed, and the offloaded prefix blocks restored, in
+    /// this step.
+    pub blocks_to_swap_in: HashMap<SrcBlockFrom, DstBlockTo>,
+    /// GPU to host blocks of the sequences preempted in this step.
+    pub blocks_to_swap_out: HashMap<SrcBlockFrom, DstBlockTo>,
 }
 
 pub struct PagedAttentionSchedulerConfig {
     pub max_num_seqs: usize,
+    pub policy: SchedulingPolicy,
 }
 
 pub struct PagedAttentionScheduler {
     waiting: VecDeque<Arc<Mutex<Sequence>>>,
     running: VecDeque<Arc<Mutex<Sequence>>>,
+    /// Preempted sequences whose KV cache is in host memory.
+    swapped: VecDeque<Arc<Mutex<Sequence>>>,
     config: PagedAttentionSchedulerConfig,
     pub block_engine: Arc<tokio::sync::Mutex<BlockEngine>>,
     block_size: usize,
+    can_swap: bool,
+    blocks_to_swap_in: HashMap<SrcBlockFrom, DstBlockTo>,
+    blocks_to_swap_out: HashMap<SrcBlockFrom, DstBlockTo>,
+    usage: TenantUsage,
 }
 
 impl PagedAttentionScheduler {
@@ -49,12 +68,20 @@ impl PagedAttentionScheduler {
         Self {
             waiting: VecDeque::new(),
             running: VecDeque::new(),
+            swapped: VecDeque::new(),
             config,
             block_engine: Arc::new(tokio::sync::Mutex::new(BlockEngine::new(
                 cache_config.block_size,
                 cache_config.num_gpu_blocks,
+                cache_config.num_cpu_blocks,
+                cache_config.num_offload_blocks,
+                cache_config.watermark_blocks,
             ))),
             block_size: cache_config.block_size,
+            can_swap: cache_config.num_cpu_blocks > cache_config.num_offload_blocks,
+            blocks_to_swap_in: HashMap::new(),
+            blocks_to_swap_out: HashMap::new(),
+            usage: TenantUsage::default(),
         }
     }
 
@@ -62,7 +89,41 @@ impl PagedAttentionScheduler {
         let mut scheduled: VecDeque<Arc<Mutex<Sequence>>> = VecDeque::new();
         let mut for_waiting_again: VecDeque<Arc<Mutex<Sequence>>> = VecDeque::new();
         let mut did_ignore = false;
-        while !self.waiting.is_empty() {
+
+        if self.config.policy == SchedulingPolicy::FairShare {
+            let waiting = std::mem::take(&mut self.waiting);
+            self.waiting = self
+                .usage
+                .order(waiting, |seq| {
+                    let seq = get_mut_arcmutex!(seq);
+                    (seq.tenant().map(ToString::to_string), seq.len())
+                })
+                .into();
+        }
+
+        // Sequences whose context was shifted by their attention sinks run their kept tokens again,
+        // like preempted sequences
+        let (shifted, running): (VecDeque<_>, VecDeque<_>) = std::mem::take(&mut self.running)
+            .into_iter()
+            .partition(|seq| get_mut_arcmutex!(seq).take_context_shift());
+        self.running = running;
+        for seq in shifted.into_iter().rev() {
+            self._preempt_by_recompute(seq);
+        }
+
+        // Resume the swapped out sequences first, in the order they were preempted. New prompts
+        // are only admitted once all of them are running again, so they cannot be starved.
+        while let Some(seq) = self.swapped.front() {
+            if self.config.max_num_seqs == self.running.len() + 1
+                || !get_mut_arcmutex!(self.block_engine).can_swap_in(&*get_mut_arcmutex!(seq))
+            {
+                break;
+            }
+            let seq = self.swapped.pop_front().unwrap();
+            self._swap_in(seq);
+        }
+
+        while !self.waiting.is_empty() && self.swapped.is_empty() {
             let seq = self.waiting.front().unwrap().clone();
 
             if self.config.max_num_seqs == self.running.len() + 1 {
@@ -75,7 +136,7 @@ impl PagedAttentionScheduler {
                 AllocStatus::Later { waitlisted_count } => {
                     if waitlisted_count > WAITING_TIMEOUT {
                         if let Some(seq_to_preempt) = self.running.pop_back() {
-                            self._preempt_by_recompute(seq_to_preempt);
+                            self._preempt(seq_to_preempt);
                             if !matches!(
                                 get_mut_arcmutex!(self.block_engine)
                                     .can_allocate(&mut *get_mut_arcmutex!(seq)),
@@ -117,10 +178,16 @@ impl PagedAttentionScheduler {
                 _ => {}
             }
 
-            let new_seq_has_images = get_mut_arcmutex!(seq).has_images();
-            if !scheduled.is_empty()
-                && get_mut_arcmutex!(scheduled[0]).has_images() != new_seq_has_images
-            {
+            // The prompts of a step must all have images or not, and start after the same number
+            // of shared prefix tokens
+            let (new_seq_has_images, new_seq_offset) = {
+                let seq = get_mut_arcmutex!(seq);
+                (seq.has_images(), seq.token_offset())
+            };
+            if !scheduled.is_empty() && {
+                let first = get_mut_arcmutex!(scheduled[0]);
+                first.has_images() != new_seq_has_images || first.token_offset() != new_seq_offset
+            } {
                 let seq = self.waiting.pop_front().unwrap();
                 for_waiting_again.push_back(seq.clone());
                 continue;
@@ -140,13 +207,12 @@ impl PagedAttentionScheduler {
         self.waiting.extend(for_waiting_again);
 
         if !scheduled.is_empty() || did_ignore {
+            self.record_usage(scheduled.iter());
             logger.set_num_running(self.running.len());
-            logger.set_num_waiting(self.waiting.len());
+            logger.set_num_waiting(self.waiting.len() + self.swapped.len());
+            self.log_kv_cache_usage(logger);
 
-            return PagedAttentionSchedulerOutput {
-                scheduled: scheduled.into(),
-                blocks_to_copy: HashMap::new(),
-            };
+            return self.output(scheduled.into(), HashMap::new());
         }
 
         let mut blocks_to_copy = HashMap::new();
@@ -210,12 +276,30 @@ impl PagedAttentionScheduler {
             TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
         }
 
+        let running = self.running.clone();
+        self.record_usage(running.iter());
         logger.set_num_running(self.running.len());
-        logger.set_num_waiting(self.waiting.len());
+        logger.set_num_waiting(self.waiting.len() + self.swapped.len());
+        self.log_kv_cache_usage(logger);
 
+        self.output(running.into(), blocks_to_copy) // Clone should be cheap.
+    }
+
+    fn output(
+        &mut self,
+        scheduled: Vec<Arc<Mutex<Sequence>>>,
+        blocks_to_copy: HashMap<SrcBlockFrom, DstBlocksTo>,
+    ) -> PagedAttentionSchedulerOutput {
+        let (blocks_to_offload, blocks_to_restore) =
+            get_mut_arcmutex!(self.block_engine).take_offload_ops();
+        let mut blocks_to_swap_in = std::mem::take(&mut self.blocks_to_swap_in);
+        blocks_to_swap_in.extend(blocks_to_restore);
         PagedAttentionSchedulerOutput {
-            scheduled: self.running.clone().into(), // Clone should be cheap.
+            scheduled,
             blocks_to_copy,
+            blocks_to_offload,
+            blocks_to_swap_in,
+            blocks_to_swap_out: std::mem::take(&mut self.blocks_to_swap_out),
         }
     }
 
@@ -223,6 +307,8 @@ impl PagedAttentionScheduler {
         let mut to_free_ids = Vec::new();
         self.running.retain(|seq| {
             if get_mut_arcmutex!(seq).is_finished_paged_attn() {
+                get_mut_arcmutex!(self.block_engine)
+                    .free_prefill_blocks(&mut *get_mut_arcmutex!(seq));
                 to_free_ids.push(get_mut_arcmutex!(seq).get_id());
                 false
             } else {
@@ -255,7 +341,17 @@ impl PagedAttentionScheduler {
         {
             return self.running.remove(idx).unwrap();
         };
-        panic!("Attempted to remove sequence id {seq_id} but it is not running or waiting.");
+        // Remove it if it is swapped out
+        if let Some(idx) = self
+            .swapped
+            .iter()
+            .position(|other| get_mut_arcmutex!(other).get_id() == seq_id)
+        {
+            return self.swapped.remove(idx).unwrap();
+        };
+        panic!(
+            "Attempted to remove sequence id {seq_id} but it is not running, waiting or swapped."
+        );
     }
 
     fn _append_token_slot_to_seq(
@@ -279,17 +375,59 @@ impl PagedAttentionScheduler {
         self._free(seq_id);
     }
 
-    /// Preempt sequences by dropping their cache and recomputing later.
+    /// Preempt sequences by swapping their cache out to host memory if there is space, otherwise
+    /// by dropping their cache and recomputing later.
     fn _preempt(&mut self, seq: Arc<Mutex<Sequence>>) {
-        self._preempt_by_recompute(seq)
+        let can_swap_out = self.can_swap && {
+            let seq = get_mut_arcmutex!(seq);
+            seq.is_completion() && get_mut_arcmutex!(self.block_engine).can_swap_out(&*seq)
+        };
+        if can_swap_out {
+            self._preempt_by_swap(seq)
+        } else {
+            self._preempt_by_recompute(seq)
+        }
+    }
+
+    fn _preempt_by_swap(&mut self, seq: Arc<Mutex<Sequence>>) {
+        let mapping = get_mut_arcmutex!(self.block_engine).swap_out(&*get_mut_arcmutex!(seq));
+        self.blocks_to_swap_out.extend(mapping);
+        get_mut_arcmutex!(seq).set_state(SequenceState::Swapped);
+        self.swapped.push_back(seq);
+    }
+
+    fn _swap_in(&mut self, seq: Arc<Mutex<Sequence>>) {
+        let mapping = get_mut_arcmutex!(self.block_engine).swap_in(&*get_mut_arcmutex!(seq));
+        self.blocks_to_swap_in.extend(mapping);
+        get_mut_arcmutex!(seq).set_state(SequenceState::RunningCompletion);
+        self.running.push_back(seq);
     }
 
     fn _preempt_by_recompute(&mut self, seq: Arc<Mutex<Sequence>>) {
-        get_mut_arcmutex!(seq).set_state(SequenceState::Waiting);
-        self._free(get_mut_arcmutex!(seq).get_id());
+        {
+            let mut seq = get_mut_arcmutex!(seq);
+            seq.set_state(SequenceState::Waiting);
+            let mut block_engine = get_mut_arcmutex!(self.block_engine);
+            block_engine.free_sequence(seq.get_id());
+            // Recompute only the tokens after the longest shared prefix, keeping the last one
+            // for its logits
+            let shared = if seq.can_share_blocks() {
+                let max_blocks = seq.get_toks().len().saturating_sub(1) / self.block_size;
+                block_engine.match_prefix(seq.logical_token_blocks(), max_blocks)
+            } else {
+                Vec::new()
+            };
+            seq.reset_paged_prefill(shared);
+        }
         self.waiting.push_front(seq);
     }
 
+    fn log_kv_cache_usage(&self, logger: &IntervalLogger) {
+        let block_engine = get_mut_arcmutex!(self.block_engine);
+        let total = block_engine.num_gpu_blocks();
+        logger.set_kv_cache_usage(total - block_engine.num_free_gpu_blocks(), total);
+    }
+
     fn _allocate(&mut self, seq: &mut Sequence) {
         get_mut_arcmutex!(self.block_engine).allocate(seq)
     }
@@ -303,6 +441,33 @@ impl PagedAttentionScheduler {
             .make_contiguous()
             .sort_by_key(|seq| get_mut_arcmutex!(seq).timestamp());
         self.running.make_contiguous().reverse();
+        // The sequences of the tenants which consumed the most tokens are preempted first
+        if self.config.policy == SchedulingPolicy::FairShare {
+            self.running
+                .make_contiguous()
+                .sort_by_key(|seq| self.usage.get(get_mut_arcmutex!(seq).tenant()));
+        }
+    }
+
+    /// Count the tokens of this step for the tenants of the scheduled sequences.
+    fn record_usage<'a>(&mut self, scheduled: impl Iterator<Item = &'a Arc<Mutex<Sequence>>>) {
+        if self.config.policy != SchedulingPolicy::FairShare {
+            return;
+        }
+        let active = self
+            .waiting
+            .iter()
+            .chain(&self.running)
+            .chain(&self.swapped)
+            .map(|seq| get_mut_arcmutex!(seq).tenant().map(ToString::to_string))
+            .collect::<Vec<_>>();
+        self.usage
+            .retain(active.iter().map(|tenant| tenant.as_deref()));
+        for seq in scheduled {
+            let seq = get_mut_arcmutex!(seq);
+            let tokens = if seq.is_prompt() { seq.len() } else { 1 };
+            self.usage.record(seq.tenant(), tokens);
+        }
     }
 }
 
@@ -310,17 +475,59 @@ impl Scheduler for PagedAttentionScheduler {
     fn add_seq(&mut self, seq: Sequence) {
         self.waiting.push_back(Arc::new(Mutex::new(seq)));
     }
+    fn remove_timed_out(&mut self) -> Vec<Sender<Response>> {
+        let (timed_out, waiting): (Vec<_>, VecDeque<_>) = self
+            .waiting
+            .drain(..)
+            .partition(|seq| get_mut_arcmutex!(seq).timed_out_before_start());
+        self.waiting = waiting;
+        timed_out
+            .iter()
+            .map(|seq| get_mut_arcmutex!(seq).responder())
+            .collect()
+    }
+    fn cancel(&mut self, request_id: usize) -> Vec<Sender<Response>> {
+        let (canceled, waiting): (Vec<_>, VecDeque<_>) = self.waiting.drain(..).partition(|seq| {
+            let seq = get_mut_arcmutex!(seq);
+            seq.request_id() == request_id && seq.is_queued()
+        });
+        self.waiting = waiting;
+        for seq in &canceled {
+            get_mut_arcmutex!(self.block_engine).free_prefill_blocks(&mut *get_mut_arcmutex!(seq));
+        }
+        for seq in self
+            .running
+            .iter()
+            .chain(&self.waiting)
+            .chain(&self.swapped)
+        {
+            let mut seq = get_mut_arcmutex!(seq);
+            if seq.request_id() == request_id {
+                seq.request_cancel();
+            }
+        }
+        canceled
+            .iter()
+            .map(|seq| get_mut_arcmutex!(seq).responder())
+            .collect()
+    }
     fn schedule(&mut self, logger: &IntervalLogger) -> SchedulerOutput<'_> {
         SchedulerOutput::PagedAttention {
             output: self.schedule(logger),
         }
     }
     fn waiting_len(&self) -> usize {
-        self.waiting.len()
+        self.waiting.len() + self.swapped.len()
     }
     fn running_len(&self) -> usize {
         self.running.len()
     }
+    fn running_lora_adapters(&self) -> Vec<LoraAdapterSelection> {
+        self.running
+            .iter()
+            .map(|seq| get_mut_arcmutex!(seq).adapters().map(|a| a.to_vec()))
+            .collect()
+    }
     fn block_tables(&self) -> Option<BlockTables> {
         Some(get_mut_arcmutex!(self.block_engine).block_tables.clone())
     }
@@ -333,4 +540,33 @@ impl Scheduler for PagedAttentionScheduler {
     fn block_engine(&self) -> Option<Arc<tokio::sync::Mutex<BlockEngine>>> {
         Some(self.block_engine.clone())
     }
+    fn kv_cache_stats(&self) -> Option<KvCacheStats> {
+        #![allow(clippy::cast_precision_loss)]
+        let block_engine = get_mut_arcmutex!(self.block_engine);
+        let (num_tokens, num_slots) =
+            self.running
+                .iter()
+                .fold((0, 0), |(num_tokens, num_slots), seq| {
+                    let seq = get_mut_arcmutex!(seq);
+                    let num_blocks = block_engine
+                        .block_tables
+                        .get(&seq.get_id())
+                        .map_or(0, Vec::len);
+                    (
+                        num_tokens
+                            + seq
+                                .logical_token_blocks()
+                                .iter()
+                                .map(|block| block.num_tokens())
+                                .sum::<usize>(),
+                        num_slots + num_blocks * self.block_size,
+                    )
+                });
+        let fragmentation = if num_slots == 0 {
+            0.
+        } else {
+            (1. - num_tokens as f64 / num_slots as f64).max(0.)
+        };
+        Some(block_engine.stats(fragmentation))
+    }
 }
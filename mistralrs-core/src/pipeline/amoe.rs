@@ -26,13 +26,13 @@ use crate::{
     sampler::Sampler,
     sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer},
     utils::progress::NiceProgressBar,
-    DeviceMapSetting, Loader, ModelCategory, ModelKind, ModelPaths, PagedAttentionConfig, Pipeline,
-    Response, TokenSource, TryIntoDType,
+    CalibrationData, DeviceMapSetting, ExportFormat, Loader, LoraAdapterInfo, ModelCategory,
+    ModelKind, ModelPaths, PagedAttentionConfig, Pipeline, Response, TokenSource, TryIntoDType,
 };
 
 use super::{
     AnyMoePipelineMixin, CacheManagerMixin, EitherCache, ForwardInputsResult, IsqPipelineMixin,
-    MetadataMixin, PreProcessingMixin,
+    LoraPipelineMixin, MetadataMixin, PreProcessingMixin,
 };
 
 pub struct AnyMoeLoader {
@@ -210,6 +210,19 @@ impl IsqPipelineMixin for AnyMoePipeline {
     fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
         get_mut_arcmutex!(self.target).re_isq_model(dtype)
     }
+    fn requantize_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).requantize_model(dtype)
+    }
+    fn re_isq_model_calibrated(
+        &mut self,
+        dtype: IsqType,
+        data: &CalibrationData,
+    ) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).re_isq_model_calibrated(dtype, data)
+    }
+    fn export_model(&mut self, path: &Path, format: ExportFormat) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).export_model(path, format)
+    }
 }
 
 impl PreProcessingMixin for AnyMoePipeline {
@@ -273,6 +286,24 @@ impl Pipeline for AnyMoePipeline {
     }
 }
 
+impl LoraPipelineMixin for AnyMoePipeline {
+    fn load_lora_adapter(&mut self, adapter_id: String, scale: Option<f64>) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).load_lora_adapter(adapter_id, scale)
+    }
+    fn unload_lora_adapter(&mut self, adapter_id: &str) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).unload_lora_adapter(adapter_id)
+    }
+    fn merge_lora_adapters(&mut self) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).merge_lora_adapters()
+    }
+    fn lora_adapter_ids(&self) -> Vec<String> {
+        get_mut_arcmutex!(self.target).lora_adapter_ids()
+    }
+    fn lora_adapter_infos(&self) -> Vec<LoraAdapterInfo> {
+        get_mut_arcmutex!(self.target).lora_adapter_infos()
+    }
+}
+
 impl AnyMoePipelineMixin for AnyMoePipeline {
     // Training result is None if inference
     fn amoe_pre_train(
@@ -373,9 +404,12 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             None,
             None,
             None,
+            None,
+            None,
             -1,
             0.0,
             0.0,
+            1.0,
             vec![],
         )
         .map_err(candle_core::Error::msg)?;
@@ -590,5 +624,11 @@ fn new_dummy_seq(
         None,
         false,
         eos_toks,
+        None,
+        None,
+        None,
+        0,
+        None,
+        None,
     )
 }
@@ -2,8 +2,8 @@ use super::loaders::{DiffusionModelPaths, DiffusionModelPathsInner};
 use super::{
     AnyMoePipelineMixin, Cache, CacheManagerMixin, DiffusionLoaderType, DiffusionModel,
     DiffusionModelLoader, EitherCache, FluxLoader, ForwardInputsResult, GeneralMetadata,
-    IsqPipelineMixin, Loader, MetadataMixin, ModelCategory, ModelKind, ModelPaths,
-    PreProcessingMixin, Processor, TokenSource,
+    IsqPipelineMixin, Loader, LoraPipelineMixin, MetadataMixin, ModelCategory, ModelKind,
+    ModelPaths, PreProcessingMixin, Processor, TokenSource,
 };
 use crate::device_map::DeviceMapper;
 use crate::diffusion_models::processor::{DiffusionProcessor, ModelInputs};
@@ -340,4 +340,6 @@ impl Pipeline for DiffusionPipeline {
     }
 }
 
+impl LoraPipelineMixin for DiffusionPipeline {}
+
 impl AnyMoePipelineMixin for DiffusionPipeline {}
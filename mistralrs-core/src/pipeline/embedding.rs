@@ -0,0 +1,433 @@
+use super::text_models_inputs_processor::PagedAttentionMeta;
+use super::{
+    AdapterPaths, AnyMoePipelineMixin, Cache, CacheManagerMixin, EitherCache, ForwardInputsResult,
+    GeneralMetadata, InputProcessorOutput, InputsProcessor, InputsProcessorType, IsqPipelineMixin,
+    Loader, LoraPipelineMixin, MessagesAction, MetadataMixin, ModelCategory, ModelKind, ModelPaths,
+    PreProcessingMixin, Processor, TokenSource,
+};
+use crate::device_map::DeviceMapper;
+use crate::embedding::{EmbeddingModel, EmbeddingPooling, SentenceTransformersPoolingConfig};
+use crate::pipeline::{ChatTemplate, Modalities, SupportedModality};
+use crate::prefix_cacher::PrefixCacheManagerV2;
+use crate::sequence::Sequence;
+use crate::utils::tokens::get_token;
+use crate::{
+    api_get_file, DeviceMapSetting, MessageContent, PagedAttentionConfig, Pipeline, TryIntoDType,
+    GLOBAL_HF_CACHE,
+};
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use indexmap::IndexMap;
+use mistralrs_quant::IsqType;
+use rand_isaac::Isaac64Rng;
+use std::any::Any;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokenizers::{Encoding, PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+use tokio::sync::Mutex;
+use tracing::info;
+
+/// The number of texts run through the model at once.
+pub(super) const EMBEDDING_BATCH_SIZE: usize = 32;
+
+#[derive(Clone, Debug)]
+pub struct EmbeddingModelPaths {
+    pub(super) weights: Vec<PathBuf>,
+    pub(super) config: PathBuf,
+    pub(super) tokenizer: PathBuf,
+    pub(super) pooling_config: Option<PathBuf>,
+}
+
+/// Get the files of an encoder model (embedding or reranker) from the Hugging Face Hub, or a
+/// local directory.
+pub(super) fn get_model_paths(
+    model_id: &str,
+    revision: Option<String>,
+    token_source: &TokenSource,
+    silent: bool,
+) -> Result<EmbeddingModelPaths> {
+    let cache = GLOBAL_HF_CACHE.get().cloned().unwrap_or_default();
+    let api = ApiBuilder::from_cache(cache)
+        .with_progress(!silent)
+        .with_token(get_token(token_source)?)
+        .build()?;
+    let revision = revision.unwrap_or("main".to_string());
+    let api = api.repo(Repo::with_revision(
+        model_id.to_string(),
+        RepoType::Model,
+        revision,
+    ));
+    let model_id = std::path::Path::new(model_id);
+
+    let weight = api_get_file!(api, "model.safetensors", &model_id);
+    let config = api_get_file!(api, "config.json", &model_id);
+    let tokenizer = api_get_file!(api, "tokenizer.json", &model_id);
+    // Only present for models exported by sentence-transformers.
+    let pooling_config = if model_id.exists() {
+        Some(model_id.join("1_Pooling/config.json")).filter(|path| path.exists())
+    } else {
+        api.get("1_Pooling/config.json").ok()
+    };
+
+    Ok(EmbeddingModelPaths {
+        weights: vec![weight],
+        config,
+        tokenizer,
+        pooling_config,
+    })
+}
+
+/// Stack one field of the (padded) `encodings` into a tensor of shape (b, t).
+pub(super) fn stack_encodings(
+    encodings: &[Encoding],
+    field: impl Fn(&Encoding) -> &[u32],
+    device: &Device,
+) -> candle_core::Result<Tensor> {
+    let rows = encodings
+        .iter()
+        .map(|encoding| Tensor::new(field(encoding), device))
+        .collect::<candle_core::Result<Vec<_>>>()?;
+    Tensor::stack(&rows, 0)
+}
+
+/// Load a tokenizer which pads batches to the longest input and truncates to `max_seq_len`.
+pub(super) fn load_tokenizer(path: &Path, max_seq_len: usize) -> Result<Tokenizer> {
+    let mut tokenizer = Tokenizer::from_file(path).map_err(anyhow::Error::msg)?;
+    tokenizer.with_padding(Some(PaddingParams {
+        strategy: PaddingStrategy::BatchLongest,
+        ..Default::default()
+    }));
+    tokenizer
+        .with_truncation(Some(TruncationParams {
+            max_length: max_seq_len,
+            ..Default::default()
+        }))
+        .map_err(anyhow::Error::msg)?;
+    Ok(tokenizer)
+}
+
+impl ModelPaths for EmbeddingModelPaths {
+    fn get_config_filename(&self) -> &PathBuf {
+        &self.config
+    }
+    fn get_tokenizer_filename(&self) -> &PathBuf {
+        &self.tokenizer
+    }
+    fn get_weight_filenames(&self) -> &[PathBuf] {
+        &self.weights
+    }
+    fn get_template_filename(&self) -> &Option<PathBuf> {
+        unreachable!("Use `std::any::Any`.")
+    }
+    fn get_gen_conf_filename(&self) -> Option<&PathBuf> {
+        unreachable!("Use `std::any::Any`.")
+    }
+    fn get_preprocessor_config(&self) -> &Option<PathBuf> {
+        unreachable!("Use `std::any::Any`.")
+    }
+    fn get_processor_config(&self) -> &Option<PathBuf> {
+        unreachable!("Use `std::any::Any`.")
+    }
+    fn get_chat_template_explicit(&self) -> &Option<PathBuf> {
+        unreachable!("Use `std::any::Any`.")
+    }
+    fn get_adapter_paths(&self) -> &AdapterPaths {
+        unreachable!("Use `std::any::Any`.")
+    }
+}
+
+pub struct EmbeddingProcessor;
+
+impl Processor for EmbeddingProcessor {
+    fn process(
+        &self,
+        _pipeline: &dyn Pipeline,
+        _messages: Vec<IndexMap<String, MessageContent>>,
+        _add_generation_prompt: bool,
+        _add_special_tokens: bool,
+        _enable_thinking: Option<bool>,
+        _tools: Vec<crate::Tool>,
+    ) -> Result<(Vec<u32>, String)> {
+        anyhow::bail!(
+            "EmbeddingProcessor::process should not be used. It does not expect chat messages."
+        )
+    }
+    fn inputs_processor(&self) -> Arc<dyn InputsProcessor> {
+        Arc::new(EmbeddingInputsProcessor)
+    }
+    fn get_special_tokens(&self) -> &[&'static str] {
+        &[]
+    }
+    fn template_action(&self) -> MessagesAction {
+        // Just a default
+        MessagesAction::FlattenOnlyText
+    }
+}
+
+pub struct EmbeddingInputsProcessor;
+
+impl InputsProcessor for EmbeddingInputsProcessor {
+    fn get_type(&self) -> InputsProcessorType {
+        InputsProcessorType::Text
+    }
+
+    fn process_inputs(
+        &self,
+        _tokenizer: Option<Arc<Tokenizer>>,
+        _input_seqs: &mut [&mut Sequence],
+        _is_prompt: bool,
+        _is_xlora: bool,
+        _device: &Device,
+        _no_kv_cache: bool,
+        _last_n_context_len: Option<(usize, usize)>,
+        _return_raw_logits: bool,
+        _other_config: Option<Arc<dyn Any>>,
+        _paged_attn_metadata: Option<PagedAttentionMeta>,
+        _mapper: Option<&dyn DeviceMapper>,
+    ) -> Result<InputProcessorOutput> {
+        anyhow::bail!("Embedding models do not process sequences, use `Model::embed` instead.")
+    }
+}
+
+/// A dense embedding model (BERT, RoBERTa or Nomic BERT architecture), which maps texts to
+/// pooled vectors.
+pub struct EmbeddingPipeline {
+    model_id: String,
+    model: EmbeddingModel,
+    tokenizer: Arc<Tokenizer>,
+    device: Device,
+    pooling: EmbeddingPooling,
+    normalize: bool,
+    metadata: Arc<GeneralMetadata>,
+    dummy_cache: EitherCache,
+}
+
+pub struct EmbeddingLoader {
+    pub model_id: String,
+    /// Defaults to the pooling saved by sentence-transformers, or mean pooling.
+    pub pooling: Option<EmbeddingPooling>,
+    /// L2-normalize the embeddings.
+    pub normalize: bool,
+}
+
+impl Loader for EmbeddingLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        let paths: Box<dyn ModelPaths> = Box::new(get_model_paths(
+            &self.model_id,
+            revision,
+            &token_source,
+            silent,
+        )?);
+        self.load_model_from_path(
+            &paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            paged_attn_config,
+        )
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        _silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        _paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        let paths = &paths
+            .as_ref()
+            .as_any()
+            .downcast_ref::<EmbeddingModelPaths>()
+            .expect("Path downcast failed.");
+
+        if matches!(mapper, DeviceMapSetting::Map(_)) {
+            anyhow::bail!("Device mapping is not supported for embedding models.")
+        }
+        if in_situ_quant.is_some() {
+            anyhow::bail!("Embedding models do not support ISQ.")
+        }
+
+        #[cfg(feature = "cuda")]
+        if let Device::Cuda(dev) = &device {
+            unsafe { dev.disable_event_tracking() };
+        }
+
+        let mapper = DeviceMapSetting::dummy().into_mapper(usize::MAX, device, None)?;
+        let dtype = mapper.get_min_dtype(dtype)?;
+
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&paths.weights, dtype, device)? };
+        let (model, max_seq_len) =
+            EmbeddingModel::load(vb, &std::fs::read_to_string(&paths.config)?)?;
+
+        let pooling = match (self.pooling, &paths.pooling_config) {
+            (Some(pooling), _) => pooling,
+            (None, Some(pooling_config)) => {
+                let pooling_config: SentenceTransformersPoolingConfig =
+                    serde_json::from_str(&std::fs::read_to_string(pooling_config)?)?;
+                pooling_config.pooling().unwrap_or_default()
+            }
+            (None, None) => EmbeddingPooling::default(),
+        };
+        info!("Using {pooling:?} pooling for the embedding model.");
+
+        let tokenizer = load_tokenizer(&paths.tokenizer, max_seq_len)?;
+
+        Ok(Arc::new(Mutex::new(EmbeddingPipeline {
+            model_id: self.model_id.clone(),
+            model,
+            tokenizer: Arc::new(tokenizer),
+            device: device.clone(),
+            pooling,
+            normalize: self.normalize,
+            metadata: Arc::new(GeneralMetadata {
+                max_seq_len,
+                llg_factory: None,
+                is_xlora: false,
+                no_prefix_cache: true,
+                num_hidden_layers: 1, // FIXME(EricLBuehler): we know this is only for caching, so its OK.
+                eos_tok: vec![],
+                kind: ModelKind::Normal,
+                no_kv_cache: true, // NOTE(EricLBuehler): no cache for these.
+                activation_dtype: dtype,
+                sliding_window: None,
+                cache_config: None,
+                cache_engine: None,
+                model_metadata: None,
+                modalities: Modalities {
+                    input: vec![SupportedModality::Text],
+                    output: vec![SupportedModality::Embedding],
+                },
+            }),
+            dummy_cache: EitherCache::Full(Cache::new(0, false)),
+        })))
+    }
+
+    fn get_id(&self) -> String {
+        self.model_id.clone()
+    }
+
+    fn get_kind(&self) -> ModelKind {
+        ModelKind::Normal
+    }
+}
+
+impl PreProcessingMixin for EmbeddingPipeline {
+    fn get_processor(&self) -> Arc<dyn Processor> {
+        Arc::new(EmbeddingProcessor)
+    }
+    fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+        None
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        None
+    }
+}
+
+impl IsqPipelineMixin for EmbeddingPipeline {
+    fn re_isq_model(&mut self, _dtype: IsqType) -> Result<()> {
+        anyhow::bail!("Embedding models do not support ISQ.")
+    }
+}
+
+impl CacheManagerMixin for EmbeddingPipeline {
+    fn clone_in_cache(&self, _seqs: &mut [&mut Sequence]) {}
+    fn clone_out_cache(&self, _seqs: &mut [&mut Sequence]) {}
+    fn set_none_cache(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _reset_non_granular: bool,
+        _modify_draft_cache: bool,
+        _load_preallocated_cache: bool,
+    ) {
+    }
+    fn cache(&self) -> &EitherCache {
+        &self.dummy_cache
+    }
+}
+
+impl MetadataMixin for EmbeddingPipeline {
+    fn device(&self) -> Device {
+        self.device.clone()
+    }
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+    fn name(&self) -> String {
+        self.model_id.clone()
+    }
+    fn reset_non_granular_state(&self) {}
+    fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        Some(self.tokenizer.clone())
+    }
+    fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for EmbeddingPipeline {
+    fn forward_inputs(
+        &mut self,
+        _inputs: Box<dyn Any>,
+        _return_raw_logits: bool,
+    ) -> candle_core::Result<ForwardInputsResult> {
+        candle_core::bail!("`forward_inputs` is incompatible with `EmbeddingPipeline`");
+    }
+
+    async fn sample_causal_gen(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _logits: Vec<Tensor>,
+        _prefix_cacher: &mut PrefixCacheManagerV2,
+        _disable_eos_stop: bool,
+        _srng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<(), candle_core::Error> {
+        candle_core::bail!("`sample_causal_gen` is incompatible with `EmbeddingPipeline`");
+    }
+
+    fn category(&self) -> ModelCategory {
+        ModelCategory::Embedding
+    }
+
+    fn embed(&mut self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for chunk in texts.chunks(EMBEDDING_BATCH_SIZE) {
+            let encodings = self
+                .tokenizer
+                .encode_batch(chunk.to_vec(), true)
+                .map_err(anyhow::Error::msg)?;
+            let input_ids = stack_encodings(&encodings, Encoding::get_ids, &self.device)?;
+            let attention_mask =
+                stack_encodings(&encodings, Encoding::get_attention_mask, &self.device)?;
+
+            let pooled =
+                self.model
+                    .embed(&input_ids, &attention_mask, self.pooling, self.normalize)?;
+            embeddings.extend(pooled.to_vec2::<f32>()?);
+        }
+        Ok(embeddings)
+    }
+}
+
+impl LoraPipelineMixin for EmbeddingPipeline {}
+
+impl AnyMoePipelineMixin for EmbeddingPipeline {}
@@ -0,0 +1,327 @@
+//! Conversion of llama.cpp's GBNF grammars to the Lark syntax of llguidance, so they can be used
+//! as constraints like any other grammar.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+
+/// Convert a GBNF grammar, whose `root` rule is the start rule, to a Lark grammar.
+pub fn gbnf_to_lark(gbnf: &str) -> Result<String> {
+    let mut parser = Parser {
+        chars: gbnf.chars().collect(),
+        pos: 0,
+        names: HashMap::new(),
+    };
+    let mut rules = Vec::new();
+    loop {
+        parser.skip_space(true);
+        if parser.peek().is_none() {
+            break;
+        }
+        let name = parser.parse_name()?;
+        parser.skip_space(false);
+        if !parser.eat_str("::=") {
+            bail!("Expected `::=` after `{name}` {}", parser.location());
+        }
+        parser.skip_space(true);
+        let body = parser.parse_alternates(false)?;
+        if parser.peek().is_some_and(|c| c != '\n' && c != '\r') {
+            bail!(
+                "Unexpected `{}` {}",
+                parser.peek().unwrap(),
+                parser.location()
+            );
+        }
+        rules.push(format!("{}: {body}", parser.rule_name(&name)));
+    }
+    if !parser.names.contains_key("root") {
+        bail!("GBNF grammar has no `root` rule");
+    }
+    Ok(rules.join("\n"))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// The Lark rule name of each GBNF rule name.
+    names: HashMap<String, String>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let c = self.peek().context("Unexpected end of the GBNF grammar")?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let eaten = self.peek() == Some(c);
+        if eaten {
+            self.pos += 1;
+        }
+        eaten
+    }
+
+    fn eat_str(&mut self, s: &str) -> bool {
+        let eaten = self.chars[self.pos..].starts_with(&s.chars().collect::<Vec<_>>());
+        if eaten {
+            self.pos += s.chars().count();
+        }
+        eaten
+    }
+
+    fn location(&self) -> String {
+        let line = self.chars[..self.pos]
+            .iter()
+            .filter(|c| **c == '\n')
+            .count()
+            + 1;
+        format!("on line {line} of the GBNF grammar")
+    }
+
+    /// Skip spaces and comments, and newlines if `newlines` is set.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\n' | '\r' if newlines => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            bail!("Expected a rule name {}", self.location());
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// Lark rule names are lowercase, so GBNF names are mapped to unique lowercase names.
+    fn rule_name(&mut self, name: &str) -> String {
+        if let Some(rule_name) = self.names.get(name) {
+            return rule_name.clone();
+        }
+        let rule_name = if name == "root" {
+            "start".to_string()
+        } else {
+            let base = format!("r_{}", name.to_lowercase().replace('-', "_"));
+            let mut rule_name = base.clone();
+            let mut i = 1;
+            while self.names.values().any(|n| *n == rule_name) {
+                rule_name = format!("{base}_{i}");
+                i += 1;
+            }
+            rule_name
+        };
+        self.names.insert(name.to_string(), rule_name.clone());
+        rule_name
+    }
+
+    fn parse_alternates(&mut self, nested: bool) -> Result<String> {
+        let mut alternates = vec![self.parse_sequence(nested)?];
+        while self.eat('|') {
+            self.skip_space(true);
+            alternates.push(self.parse_sequence(nested)?);
+        }
+        Ok(alternates.join(" | "))
+    }
+
+    /// Newlines end the sequence, unless it is `nested` in parentheses.
+    fn parse_sequence(&mut self, nested: bool) -> Result<String> {
+        let mut items = Vec::new();
+        loop {
+            let atom = match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    let mut literal = String::new();
+                    while !self.eat('"') {
+                        literal.push(self.parse_char()?);
+                    }
+                    serde_json::to_string(&literal)?
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let mut class = String::from("[");
+                    if self.eat('^') {
+                        class.push('^');
+                    }
+                    while !self.eat(']') {
+                        class.push_str(&regex_char(self.parse_char()?));
+                        let is_range = self.peek() == Some('-')
+                            && self.chars.get(self.pos + 1).is_some_and(|c| *c != ']');
+                        if is_range {
+                            self.pos += 1;
+                            class.push('-');
+                            class.push_str(&regex_char(self.parse_char()?));
+                        }
+                    }
+                    class.push(']');
+                    format!("/{class}/")
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    "/(?s:.)/".to_string()
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let group = self.parse_alternates(true)?;
+                    if !self.eat(')') {
+                        bail!("Expected `)` {}", self.location());
+                    }
+                    format!("({group})")
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    let name = self.parse_name()?;
+                    self.rule_name(&name)
+                }
+                _ => break,
+            };
+            self.skip_space(nested);
+            let item = self.parse_quantifier(atom)?;
+            self.skip_space(nested);
+            items.push(item);
+        }
+        if items.is_empty() {
+            return Ok("\"\"".to_string());
+        }
+        Ok(items.join(" "))
+    }
+
+    fn parse_quantifier(&mut self, atom: String) -> Result<String> {
+        Ok(match self.peek() {
+            Some(op @ ('*' | '+' | '?')) => {
+                self.pos += 1;
+                format!("{atom}{op}")
+            }
+            Some('{') => {
+                self.pos += 1;
+                self.skip_space(false);
+                let min = self.parse_int()?;
+                self.skip_space(false);
+                let max = if self.eat(',') {
+                    self.skip_space(false);
+                    if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        Some(self.parse_int()?)
+                    } else {
+                        None
+                    }
+                } else {
+                    Some(min)
+                };
+                self.skip_space(false);
+                if !self.eat('}') {
+                    bail!("Expected `}}` {}", self.location());
+                }
+                match (min, max) {
+                    (0, Some(0)) => "\"\"".to_string(),
+                    (0, Some(1)) => format!("{atom}?"),
+                    (0, None) => format!("{atom}*"),
+                    (1, None) => format!("{atom}+"),
+                    (min, None) => format!("({atom} ~ {min} {atom}*)"),
+                    (min, Some(max)) if min == max => format!("{atom} ~ {min}"),
+                    (min, Some(max)) if min < max => format!("{atom} ~ {min}..{max}"),
+                    (min, Some(max)) => {
+                        bail!("Invalid repetition {{{min},{max}}} {}", self.location())
+                    }
+                }
+            }
+            _ => atom,
+        })
+    }
+
+    fn parse_int(&mut self) -> Result<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = self.chars[start..self.pos].iter().collect::<String>();
+        digits
+            .parse()
+            .with_context(|| format!("Expected a number {}", self.location()))
+    }
+
+    /// A character of a literal or character class, with its escapes resolved.
+    fn parse_char(&mut self) -> Result<char> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let hex = |parser: &mut Self, n: usize| -> Result<char> {
+            let digits = (0..n).map(|_| parser.next()).collect::<Result<String>>()?;
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .with_context(|| format!("Invalid escape `{digits}` {}", parser.location()))
+        };
+        Ok(match self.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'x' => hex(self, 2)?,
+            'u' => hex(self, 4)?,
+            'U' => hex(self, 8)?,
+            c => c,
+        })
+    }
+}
+
+/// A character in a regex character class, escaped unless it is alphanumeric.
+fn regex_char(c: char) -> String {
+    if c.is_ascii_alphanumeric() {
+        c.to_string()
+    } else {
+        format!("\\x{{{:X}}}", c as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::gbnf_to_lark;
+
+    #[test]
+    fn converts_gbnf() {
+        let gbnf = r#"
+# A list of yes/no answers
+root ::= answer ("," ws answer){0,3}
+answer ::= ("yes" | "no") [.!]?
+ws ::= [ \t\n]*
+"#;
+        assert_eq!(
+            gbnf_to_lark(gbnf).unwrap(),
+            [
+                r#"start: r_answer ("," r_ws r_answer) ~ 0..3"#,
+                r#"r_answer: ("yes" | "no") /[\x{2E}\x{21}]/?"#,
+                r#"r_ws: /[\x{20}\x{9}\x{A}]/*"#,
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn multiline_groups() {
+        let gbnf = "root ::= (\n  \"a\" |\n  \"b\"\n)+ item-2\nitem-2 ::= \"\\x41\"";
+        assert_eq!(
+            gbnf_to_lark(gbnf).unwrap(),
+            "start: (\"a\" | \"b\")+ r_item_2\nr_item_2: \"A\""
+        );
+        assert!(gbnf_to_lark("item ::= \"a\"").is_err());
+    }
+}
@@ -5,7 +5,7 @@ use super::{
 };
 use super::{
     AnyMoePipelineMixin, CacheManagerMixin, EitherCache, ForwardInputsResult, IsqPipelineMixin,
-    MetadataMixin, ModelCategory, PreProcessingMixin,
+    LoraPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
 };
 use crate::attention::ATTENTION_CHUNK_SIZE;
 use crate::device_map::DeviceMapper;
@@ -36,6 +36,7 @@ use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
 use std::any::Any;
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -74,6 +75,8 @@ pub struct GGMLLoader {
     tgt_non_granular_index: Option<usize>,
     jinja_explicit: Option<String>,
     lora_adapter_ids: Option<Vec<String>>,
+    lora_adapter_scales: HashMap<String, f64>,
+    xlora_classifier_path: Option<PathBuf>,
 }
 
 #[derive(Clone, Default)]
@@ -191,6 +194,8 @@ impl GGMLLoaderBuilder {
             quantized_model_id: Some(self.quantized_model_id),
             jinja_explicit: self.jinja_explicit,
             lora_adapter_ids: None,
+            lora_adapter_scales: HashMap::new(),
+            xlora_classifier_path: None,
         })
     }
 }
@@ -234,6 +239,8 @@ impl GGMLLoader {
             tgt_non_granular_index,
             jinja_explicit,
             lora_adapter_ids: None,
+            lora_adapter_scales: HashMap::new(),
+            xlora_classifier_path: None,
         }
     }
 }
@@ -418,7 +425,8 @@ impl Loader for GGMLLoader {
             self.quantized_model_id,
             Some(vec![self.quantized_filename.as_ref().unwrap().clone()]),
             silent,
-            false // Never loading UQFF
+            false, // Never loading UQFF
+            None
         );
         self.load_model_from_path(
             &paths?,
@@ -570,5 +578,7 @@ impl Pipeline for GGMLPipeline {
     }
 }
 
+impl LoraPipelineMixin for GGMLPipeline {}
+
 // TODO
 impl AnyMoePipelineMixin for GGMLPipeline {}
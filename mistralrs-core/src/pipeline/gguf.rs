@@ -1,12 +1,12 @@
 use super::llg::build_llg_factory;
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, PrettyName, QuantizationKind,
-    TokenSource,
+    get_lora_adapter_paths, get_model_paths, get_xlora_paths,
+    text_models_inputs_processor::ModelInputs, AdapterKind, CacheManager, GeneralMetadata, Loader,
+    LoraAdapterPaths, ModelKind, ModelPaths, PrettyName, QuantizationKind, TokenSource,
 };
 use super::{
     AnyMoePipelineMixin, CacheManagerMixin, EitherCache, ForwardInputsResult, IsqPipelineMixin,
-    MetadataMixin, ModelCategory, PreProcessingMixin,
+    LoraPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
 };
 use crate::attention::ATTENTION_CHUNK_SIZE;
 use crate::device_map::{self, DeviceMapper};
@@ -29,7 +29,9 @@ use crate::sequence::Sequence;
 use crate::utils::gguf_metadata::{ContentConfig, GgufDeviceMapLoaderInner};
 use crate::utils::model_config as ModelConfig;
 use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::varbuilder_utils::{from_mmaped_safetensors, DeviceForLoadTensor};
 use crate::xlora_models::NonGranularState;
+use crate::LoraAdapterInfo;
 use crate::{
     get_mut_arcmutex, get_paths_gguf, DeviceMapSetting, LocalModelPaths, PagedAttentionConfig,
     Pipeline, Topology, TryIntoDType,
@@ -46,16 +48,18 @@ use crate::{
     xlora_models::{XLoraQLlama, XLoraQPhi3},
 };
 use anyhow::{bail, Result};
-use candle_core::{Device, Tensor};
+use candle_core::{DType, Device, Tensor};
 use either::Either;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
-use mistralrs_quant::IsqType;
+use indexmap::IndexMap;
+use mistralrs_quant::{BatchedLoraLinear, IsqType, QuantMethod};
 use rand_isaac::Isaac64Rng;
 use std::any::Any;
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
 use tracing::{info, warn};
@@ -81,6 +85,11 @@ pub struct GGUFPipeline {
     non_granular_state: Option<NonGranularState>,
     metadata: Arc<GeneralMetadata>,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
+    silent: bool,
+    // For runtime LoRA adapters
+    lora_adapters: IndexMap<String, LoraAdapterPaths>,
+    token_source: TokenSource,
+    revision: Option<String>,
 }
 
 /// Loader for a GGUF model.
@@ -97,6 +106,10 @@ pub struct GGUFLoader {
     config: GGUFSpecificConfig,
     jinja_explicit: Option<String>,
     lora_adapter_ids: Option<Vec<String>>,
+    lora_adapter_scales: HashMap<String, f64>,
+    xlora_classifier_path: Option<PathBuf>,
+    token_source: RwLock<Option<TokenSource>>,
+    revision: RwLock<Option<String>>,
 }
 
 #[derive(Clone, Default)]
@@ -211,6 +224,10 @@ impl GGUFLoaderBuilder {
             config: self.config,
             jinja_explicit: self.jinja_explicit,
             lora_adapter_ids: None,
+            lora_adapter_scales: HashMap::new(),
+            xlora_classifier_path: None,
+            token_source: RwLock::new(None),
+            revision: RwLock::new(None),
         })
     }
 }
@@ -254,6 +271,10 @@ impl GGUFLoader {
             config,
             jinja_explicit,
             lora_adapter_ids: None,
+            lora_adapter_scales: HashMap::new(),
+            xlora_classifier_path: None,
+            token_source: RwLock::new(None),
+            revision: RwLock::new(None),
         }
     }
 }
@@ -274,12 +295,17 @@ impl Loader for GGUFLoader {
         let paths: anyhow::Result<Box<dyn ModelPaths>> = get_paths_gguf!(
             LocalModelPaths,
             &token_source,
-            revision,
+            revision.clone(),
             self,
             self.quantized_model_id.clone(),
             self.quantized_filenames.clone(),
             silent
         );
+        *self
+            .token_source
+            .write()
+            .expect("Failed to write to token source") = Some(token_source);
+        *self.revision.write().expect("Failed to write to revision") = revision;
         self.load_model_from_path(
             &paths?,
             dtype,
@@ -467,6 +493,9 @@ impl Loader for GGUFLoader {
             let model_config: &dyn ModelConfigLike = &model_config_metadata;
             let cache_config = calculate_cache_config(
                 paged_attn_config.mem_gpu,
+                paged_attn_config.swap_space,
+                paged_attn_config.host_offload_bytes,
+                paged_attn_config.watermark,
                 paged_attn_config.block_size,
                 internal_dtype,
                 paged_attn_config.cache_type,
@@ -578,6 +607,19 @@ impl Loader for GGUFLoader {
                 },
             }),
             mapper: pipeline_mapper,
+            silent,
+            lora_adapters: IndexMap::new(),
+            token_source: self
+                .token_source
+                .read()
+                .expect("Failed to read token source")
+                .clone()
+                .unwrap_or(TokenSource::CacheToken),
+            revision: self
+                .revision
+                .read()
+                .expect("Failed to read revision")
+                .clone(),
         })))
     }
 
@@ -792,5 +834,139 @@ impl Pipeline for GGUFPipeline {
     }
 }
 
+/// Undo the Hugging Face layout of a query or key projection, as done when converting to GGUF for
+/// the interleaved rotary embedding.
+fn permute_for_gguf_rope(weight: &Tensor, n_head: usize) -> candle_core::Result<Tensor> {
+    let (out_dim, in_dim) = weight.dims2()?;
+    weight
+        .reshape((n_head, 2, out_dim / n_head / 2, in_dim))?
+        .transpose(1, 2)?
+        .contiguous()?
+        .reshape((out_dim, in_dim))
+}
+
+impl GGUFPipeline {
+    /// The layers which LoRA adapters can target, with their number of heads if their rows are
+    /// permuted for the interleaved rotary embedding.
+    #[allow(clippy::type_complexity)]
+    fn lora_layers(&mut self) -> Result<Vec<(String, &mut Arc<dyn QuantMethod>, Option<usize>)>> {
+        match &mut self.model {
+            Model::Llama(model) => Ok(model.get_lora_layers()),
+            Model::Qwen(model) => Ok(model.get_lora_layers()),
+            Model::Qwen3(model) => Ok(model.get_lora_layers()),
+            Model::Phi2(_)
+            | Model::XLoraLlama(_)
+            | Model::XLoraPhi3(_)
+            | Model::Phi3(_)
+            | Model::Starcoder2(_)
+            | Model::Qwen3MoE(_) => {
+                bail!("This model does not support loading LoRA adapters at runtime.")
+            }
+        }
+    }
+
+    /// Add a LoRA adapter, unmerged, to each layer it targets.
+    fn add_batched_lora_adapter(
+        &mut self,
+        adapter_id: &str,
+        adapter: &LoraAdapterPaths,
+    ) -> Result<()> {
+        let weights = from_mmaped_safetensors(
+            vec![adapter.adapter_path.clone()],
+            Vec::new(),
+            Some(DType::F32),
+            &Device::Cpu,
+            vec![None],
+            self.silent,
+            None,
+            |_| true,
+            Arc::new(|_| DeviceForLoadTensor::Base),
+        )?;
+        let adapter = mistralrs_quant::LoraAdapter {
+            config: adapter.lora_config.clone(),
+            weights,
+        };
+        if adapter.config.use_dora {
+            bail!("DoRA adapters are not supported for GGUF models.");
+        }
+        if adapter.config.saves_modules() {
+            bail!("LoRA adapters with `modules_to_save` are not supported for GGUF models.");
+        }
+
+        let silent = self.silent;
+        let mut n_applied = 0;
+        for (name, layer, rope_heads) in self.lora_layers()? {
+            let Some((a, mut b, scale)) = mistralrs_quant::lora_weights(&adapter, &name)? else {
+                continue;
+            };
+            // Permuting the rows of `lora_B` permutes the rows of the delta weight.
+            if let Some(n_head) = rope_heads {
+                b = permute_for_gguf_rope(&b, n_head)?;
+            }
+            let (dtype, device) = layer.dtype_and_device();
+            let a = a.to_device(&device)?.to_dtype(dtype)?;
+            let b = b.to_device(&device)?.to_dtype(dtype)?;
+            *layer = BatchedLoraLinear::add_adapter(layer, adapter_id.to_string(), a, b, scale);
+            n_applied += 1;
+        }
+
+        if n_applied == 0 {
+            bail!("The LoRA adapter does not target any layers of this model.");
+        }
+        if !silent {
+            info!("Added LoRA adapter to {n_applied} layers.");
+        }
+        Ok(())
+    }
+}
+
+impl LoraPipelineMixin for GGUFPipeline {
+    fn load_lora_adapter(&mut self, adapter_id: String, scale: Option<f64>) -> Result<()> {
+        if self.lora_adapters.contains_key(&adapter_id) {
+            bail!("LoRA adapter `{adapter_id}` is already loaded.");
+        }
+        let mut adapter = get_lora_adapter_paths(
+            &adapter_id,
+            &self.token_source,
+            self.revision.clone().unwrap_or("main".to_string()),
+        )?;
+        adapter.lora_config.scale = scale;
+        self.add_batched_lora_adapter(&adapter_id, &adapter)?;
+        self.lora_adapters.insert(adapter_id, adapter);
+        Ok(())
+    }
+
+    fn unload_lora_adapter(&mut self, adapter_id: &str) -> Result<()> {
+        if !self.lora_adapters.contains_key(adapter_id) {
+            bail!("LoRA adapter `{adapter_id}` is not loaded.");
+        }
+        for (_, layer, _) in self.lora_layers()? {
+            *layer = BatchedLoraLinear::remove_adapter(layer, adapter_id);
+        }
+        self.lora_adapters.shift_remove(adapter_id);
+        Ok(())
+    }
+
+    fn merge_lora_adapters(&mut self) -> Result<()> {
+        // Quantized layers are dequantized, updated and quantized again to the same type.
+        for (_, layer, _) in self.lora_layers()? {
+            *layer = BatchedLoraLinear::merge_adapters(layer)?;
+        }
+        self.lora_adapters.clear();
+        Ok(())
+    }
+
+    fn lora_adapter_ids(&self) -> Vec<String> {
+        self.lora_adapters.keys().cloned().collect()
+    }
+
+    fn lora_adapter_infos(&self) -> Vec<LoraAdapterInfo> {
+        self.lora_adapters
+            .iter()
+            .map(|(id, paths)| paths.info(id.clone(), false))
+            .collect()
+    }
+}
+
 // TODO
 impl AnyMoePipelineMixin for GGUFPipeline {}
@@ -0,0 +1,201 @@
+//! Model files which are held in memory instead of on disk, for example weights fetched from an
+//! artifact store or decrypted in memory.
+//!
+//! [`InMemoryModelPaths`] registers each file under a virtual path. The loaders read files through
+//! [`read_model_file`], which resolves the virtual paths before falling back to the file system.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, LazyLock, RwLock,
+    },
+};
+
+use anyhow::Result;
+
+use super::{paths::AdapterPaths, LocalModelPaths, ModelPaths};
+
+static IN_MEMORY_FILES: LazyLock<RwLock<HashMap<PathBuf, Arc<[u8]>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+static NEXT_ROOT: AtomicUsize = AtomicUsize::new(0);
+
+/// The contents of `path` if it is registered by an [`InMemoryModelPaths`].
+pub(crate) fn in_memory_file(path: &Path) -> Option<Arc<[u8]>> {
+    IN_MEMORY_FILES.read().unwrap().get(path).cloned()
+}
+
+/// Read a model file, which may be held in memory by an [`InMemoryModelPaths`].
+pub(crate) fn read_model_file(path: impl AsRef<Path>) -> std::io::Result<Vec<u8>> {
+    match in_memory_file(path.as_ref()) {
+        Some(data) => Ok(data.to_vec()),
+        None => std::fs::read(path),
+    }
+}
+
+/// Read a model file as a string, which may be held in memory by an [`InMemoryModelPaths`].
+pub(crate) fn read_model_file_to_string(path: impl AsRef<Path>) -> std::io::Result<String> {
+    match in_memory_file(path.as_ref()) {
+        Some(data) => String::from_utf8(data.to_vec())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        None => std::fs::read_to_string(path),
+    }
+}
+
+/// Files registered under a virtual root, which are released when this is dropped.
+#[derive(Debug)]
+pub(crate) struct InMemoryFiles {
+    root: PathBuf,
+}
+
+impl InMemoryFiles {
+    pub(crate) fn register(files: HashMap<String, impl Into<Arc<[u8]>>>) -> Self {
+        let root = PathBuf::from(format!(
+            "<in-memory-{}>",
+            NEXT_ROOT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut registry = IN_MEMORY_FILES.write().unwrap();
+        for (name, data) in files {
+            registry.insert(root.join(name), data.into());
+        }
+        Self { root }
+    }
+
+    /// The virtual path of the file `name`, if it is registered.
+    pub(crate) fn path(&self, name: &str) -> Option<PathBuf> {
+        let path = self.root.join(name);
+        in_memory_file(&path).is_some().then_some(path)
+    }
+}
+
+impl Drop for InMemoryFiles {
+    fn drop(&mut self) {
+        IN_MEMORY_FILES
+            .write()
+            .unwrap()
+            .retain(|path, _| !path.starts_with(&self.root));
+    }
+}
+
+/// The files of a model, keyed by their names in the model repository (such as `config.json`,
+/// `tokenizer.json` and `model-00001-of-00002.safetensors`), held in memory.
+///
+/// `config.json` and `tokenizer.json` are required, and every `.safetensors` file is loaded as
+/// a weight shard. The chat template is read from `chat_template.jinja` or, if it is not given,
+/// `tokenizer_config.json`. `generation_config.json`, `preprocessor_config.json`,
+/// `processor_config.json` and `chat_template.json` are used if present.
+///
+/// The contents may be given as `Vec<u8>` or, to share them without copying, `Arc<[u8]>`. The
+/// files are released when this is dropped.
+#[derive(Debug)]
+pub struct InMemoryModelPaths {
+    _files: InMemoryFiles,
+    paths: LocalModelPaths<PathBuf>,
+}
+
+impl InMemoryModelPaths {
+    pub fn new(files: HashMap<String, impl Into<Arc<[u8]>>>) -> Result<Self> {
+        let names = files.keys().cloned().collect::<Vec<_>>();
+        let files = InMemoryFiles::register(files);
+        let path = |name: &str| files.path(name);
+
+        let Some(config_filename) = path("config.json") else {
+            anyhow::bail!("In-memory model files must contain `config.json`.");
+        };
+        let Some(tokenizer_filename) = path("tokenizer.json") else {
+            anyhow::bail!("In-memory model files must contain `tokenizer.json`.");
+        };
+        let mut filenames = names
+            .iter()
+            .filter(|name| name.ends_with(".safetensors"))
+            .filter_map(|name| path(name))
+            .collect::<Vec<_>>();
+        if filenames.is_empty() {
+            anyhow::bail!("In-memory model files must contain at least one `.safetensors` file.");
+        }
+        filenames.sort();
+
+        let paths = LocalModelPaths {
+            tokenizer_filename,
+            config_filename,
+            template_filename: path("chat_template.jinja")
+                .or_else(|| path("tokenizer_config.json")),
+            filenames,
+            adapter_paths: AdapterPaths::None,
+            gen_conf: path("generation_config.json"),
+            preprocessor_config: path("preprocessor_config.json"),
+            processor_config: path("processor_config.json"),
+            chat_template_json_filename: path("chat_template.json"),
+        };
+
+        Ok(Self {
+            _files: files,
+            paths,
+        })
+    }
+}
+
+impl ModelPaths for InMemoryModelPaths {
+    fn get_config_filename(&self) -> &PathBuf {
+        self.paths.get_config_filename()
+    }
+    fn get_tokenizer_filename(&self) -> &PathBuf {
+        self.paths.get_tokenizer_filename()
+    }
+    fn get_weight_filenames(&self) -> &[PathBuf] {
+        self.paths.get_weight_filenames()
+    }
+    fn get_template_filename(&self) -> &Option<PathBuf> {
+        self.paths.get_template_filename()
+    }
+    fn get_gen_conf_filename(&self) -> Option<&PathBuf> {
+        self.paths.get_gen_conf_filename()
+    }
+    fn get_preprocessor_config(&self) -> &Option<PathBuf> {
+        self.paths.get_preprocessor_config()
+    }
+    fn get_processor_config(&self) -> &Option<PathBuf> {
+        self.paths.get_processor_config()
+    }
+    fn get_chat_template_explicit(&self) -> &Option<PathBuf> {
+        self.paths.get_chat_template_explicit()
+    }
+    fn get_adapter_paths(&self) -> &AdapterPaths {
+        self.paths.get_adapter_paths()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{in_memory_file, read_model_file_to_string, InMemoryModelPaths};
+    use crate::ModelPaths;
+
+    #[test]
+    fn registers_and_releases_files() {
+        let files = HashMap::from([
+            ("config.json".to_string(), b"{}".to_vec()),
+            ("tokenizer.json".to_string(), b"{}".to_vec()),
+            ("model-2.safetensors".to_string(), vec![1]),
+            ("model-1.safetensors".to_string(), vec![0]),
+        ]);
+        let paths = InMemoryModelPaths::new(files).unwrap();
+        let config = paths.get_config_filename().clone();
+        let weights = paths.get_weight_filenames().to_vec();
+        assert_eq!(read_model_file_to_string(&config).unwrap(), "{}");
+        assert!(weights[0].ends_with("model-1.safetensors"));
+        assert!(paths.get_template_filename().is_none());
+
+        drop(paths);
+        assert!(in_memory_file(&config).is_none());
+    }
+
+    #[test]
+    fn requires_config() {
+        let files = HashMap::from([("tokenizer.json".to_string(), b"{}".to_vec())]);
+        assert!(InMemoryModelPaths::new(files).is_err());
+    }
+}
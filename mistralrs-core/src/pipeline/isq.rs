@@ -3,55 +3,21 @@ use std::{
     collections::{HashMap, HashSet},
     env,
     fs::File,
-    path::PathBuf,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::{atomic::AtomicUsize, Arc},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Instant,
 };
-/// Wrapper around a `Cow<'a, [u8]>` buffer that implements
-/// `safetensors::tensor::View`.
-///
-/// *Purpose*: lets us pass raw byte buffers to
-/// `safetensors::serialize_to_file` without cloning them into a `Vec<u8>` or
-/// converting to a higher‑level tensor type.  
-/// We expose the buffer as a 1‑D `u8` tensor of shape `[len]`.
-#[derive(Clone)]
-pub struct CowBytesView<'a> {
-    data: Cow<'a, [u8]>,
-    shape: [usize; 1],
-}
-
-impl<'a> CowBytesView<'a> {
-    /// Convenience constructor.
-    pub fn new(data: Cow<'a, [u8]>) -> Self {
-        let len = data.len();
-        Self { data, shape: [len] }
-    }
-}
-
-impl safetensors::tensor::View for CowBytesView<'_> {
-    fn dtype(&self) -> safetensors::tensor::Dtype {
-        // Serialize as raw bytes
-        safetensors::tensor::Dtype::U8
-    }
-
-    fn shape(&self) -> &[usize] {
-        &self.shape
-    }
-
-    fn data(&self) -> Cow<'_, [u8]> {
-        assert!(matches!(self.data, Cow::Borrowed(_)));
-        // Cloning a `Cow` is cheap (only clones the enum, not the data).
-        self.data.clone()
-    }
-
-    fn data_len(&self) -> usize {
-        self.data.len()
-    }
-}
 
 use anyhow::Result;
-use candle_core::{quantized, Context, Device, Tensor};
+use candle_core::{
+    quantized::{self, GgmlDType, QTensor},
+    Context, DType, Device, Tensor, D,
+};
 use indicatif::{MultiProgress, ParallelProgressIterator, ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use mistralrs_quant::{
@@ -61,13 +27,31 @@ use mistralrs_quant::{
 };
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 use tracing::{info, warn};
 
-use crate::{device_map::DeviceMapper, topology::LayerTopology, Topology};
+use crate::{
+    device_map::DeviceMapper,
+    gguf::{gguf_tensor_name, permute_qk, tokenizer_metadata, GgufExportConfig},
+    pipeline::in_memory::read_model_file,
+    topology::LayerTopology,
+    utils::progress::{check_load_cancelled, load_cancelled, report_load_event, LoadEvent},
+    Topology,
+};
 
 pub(crate) const UQFF_RESIDUAL_SAFETENSORS: &str = "residual.safetensors";
+/// Files which are embedded in the metadata of the first UQFF shard, so that it can be loaded
+/// without the rest of the model repository.
+const UQFF_EMBEDDED_FILES: &[&str] = &[
+    "config.json",
+    "tokenizer.json",
+    "tokenizer_config.json",
+    "chat_template.jinja",
+    "generation_config.json",
+    "processor_config.json",
+    "preprocessor_config.json",
+];
 // 10 GB max per file
 const MAX_UQFF_SIZE_BYTES: usize = 10 * 1024 * 1024 * 1024;
 pub const UQFF_MULTI_FILE_DELIMITER: &str = ";";
@@ -95,6 +79,7 @@ pub const UQFF_MULTI_FILE_DELIMITER: &str = ";";
 /// - `HQQ3`
 /// - `HQQ4`
 /// - `HQQ8`
+/// - `MARLIN4` (CUDA only)
 /// - `AFQ2`
 /// - `AFQ3`
 /// - `AFQ4`
@@ -128,16 +113,17 @@ pub fn parse_isq_value(s: &str, device: Option<&Device>) -> Result<IsqType, Stri
         "q8k" => IsqType::Q8K,
         "hqq8" => IsqType::HQQ8,
         "hqq4" => IsqType::HQQ4,
+        "hqq3" => IsqType::HQQ3,
+        "hqq2" => IsqType::HQQ2,
+        "hqq1" => IsqType::HQQ1,
         "fp8" => IsqType::F8E4M3,
+        "marlin4" => IsqType::MARLIN4,
         "afq8" => IsqType::AFQ8,
         "afq6" => IsqType::AFQ6,
         "afq4" => IsqType::AFQ4,
         "afq3" => IsqType::AFQ3,
         "afq2" => IsqType::AFQ2,
-        // "hqq3" => IsqType::HQQ3,
-        // "hqq2" => IsqType::HQQ2,
-        // "hqq1" => IsqType::HQQ1,
-        _ => return Err(format!("ISQ type {s} unknown, choose one of `2`, `3`, `4`, `6`, `8`, `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q8_1`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `Q8K`, `HQQ8`, `HQQ4`, `FP8`, `AFQ8`, `AFQ6`, `AFQ4`, `AFQ3`, `AFQ2`.")),
+        _ => return Err(format!("ISQ type {s} unknown, choose one of `2`, `3`, `4`, `6`, `8`, `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q8_1`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `Q8K`, `HQQ8`, `HQQ4`, `HQQ3`, `HQQ2`, `HQQ1`, `FP8`, `MARLIN4`, `AFQ8`, `AFQ6`, `AFQ4`, `AFQ3`, `AFQ2`.")),
     };
     #[cfg(feature = "cuda")]
     {
@@ -155,13 +141,19 @@ pub fn parse_isq_value(s: &str, device: Option<&Device>) -> Result<IsqType, Stri
                 | IsqType::Q6K
                 | IsqType::HQQ8
                 | IsqType::HQQ4
-                | IsqType::F8E4M3 // | IsqType::HQQ3
-                                  // | IsqType::HQQ2
-                                  // | IsqType::HQQ1
+                | IsqType::HQQ3
+                | IsqType::HQQ2
+                | IsqType::HQQ1
+                | IsqType::F8E4M3
+                | IsqType::MARLIN4
         ) {
-            return Err("ISQ type on CUDA must be one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `HQQ8`, `HQQ4`, `FP8`".to_string());
+            return Err("ISQ type on CUDA must be one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `HQQ8`, `HQQ4`, `HQQ3`, `HQQ2`, `HQQ1`, `FP8`, `MARLIN4`".to_string());
         }
     }
+    #[cfg(not(feature = "cuda"))]
+    if tp == IsqType::MARLIN4 {
+        return Err("ISQ type `MARLIN4` requires CUDA.".to_string());
+    }
     Ok(tp)
 }
 
@@ -176,6 +168,49 @@ pub enum IsqOrganization {
     MoeExpertsOnly,
 }
 
+/// Calibration data to collect an imatrix from before applying ISQ, given in memory instead of
+/// as a calibration file, for example samples of the requests a service receives.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum CalibrationData {
+    /// Texts, which are tokenized without special tokens.
+    Texts(Vec<String>),
+    /// Sequences of token IDs, without BOS.
+    Tokens(Vec<Vec<u32>>),
+}
+
+/// The calibration tokens from the calibration file or data, without BOS, along with a
+/// description of where they came from.
+pub(crate) fn calibration_tokens(
+    file: Option<&PathBuf>,
+    data: Option<&CalibrationData>,
+    tokenizer: &Tokenizer,
+) -> Result<Option<(Vec<u32>, String)>> {
+    let (text, source) = match (file, data) {
+        (None, None) => return Ok(None),
+        (None, Some(CalibrationData::Tokens(seqs))) => {
+            return Ok(Some((
+                seqs.concat(),
+                format!("{} calibration sequences", seqs.len()),
+            )))
+        }
+        (None, Some(CalibrationData::Texts(texts))) => (
+            texts.join("\n\n"),
+            format!("{} calibration texts", texts.len()),
+        ),
+        (Some(file), _) => (
+            std::fs::read_to_string(file)?,
+            format!("calibration file `{}`", file.display()),
+        ),
+    };
+    // Tokenize, don't add bos yet
+    let tokens = tokenizer
+        .encode_fast(text, false)
+        .map_err(anyhow::Error::msg)?
+        .get_ids()
+        .to_vec();
+    Ok(Some((tokens, source)))
+}
+
 impl FromStr for IsqOrganization {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -189,6 +224,96 @@ impl FromStr for IsqOrganization {
     }
 }
 
+/// Writes serialized layers to a UQFF (safetensors) file as they are produced. Space for the
+/// header is reserved at the start of the file and filled in by [`UqffShardWriter::finish`],
+/// once the offsets of all the layers are known.
+struct UqffShardWriter {
+    file: BufWriter<File>,
+    header_capacity: usize,
+    metadata: Option<HashMap<String, String>>,
+    /// Name and data offsets of each layer
+    tensors: Vec<(String, usize, usize)>,
+    /// Number of data bytes written
+    len: usize,
+}
+
+impl UqffShardWriter {
+    /// Upper bound on the length of the header entry of one layer.
+    const MAX_ENTRY_LEN: usize = 128;
+
+    /// Create a shard which holds at most `max_tensors` layers.
+    fn create(
+        path: &Path,
+        max_tensors: usize,
+        metadata: Option<HashMap<String, String>>,
+    ) -> candle_core::Result<Self> {
+        let metadata_len = match &metadata {
+            Some(metadata) => {
+                serde_json::to_string(metadata)
+                    .map_err(candle_core::Error::msg)?
+                    .len()
+                    + Self::MAX_ENTRY_LEN
+            }
+            None => 0,
+        };
+        let header_capacity =
+            (2 + metadata_len + max_tensors * Self::MAX_ENTRY_LEN).next_multiple_of(8);
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&vec![b' '; 8 + header_capacity])?;
+        Ok(Self {
+            file,
+            header_capacity,
+            metadata,
+            tensors: Vec::new(),
+            len: 0,
+        })
+    }
+
+    fn push(&mut self, name: String, data: &[u8]) -> candle_core::Result<()> {
+        self.file.write_all(data)?;
+        self.tensors.push((name, self.len, self.len + data.len()));
+        self.len += data.len();
+        Ok(())
+    }
+
+    /// Write the header, padded with spaces to the reserved length as safetensors allows.
+    fn finish(self) -> candle_core::Result<()> {
+        let mut header = serde_json::Map::new();
+        if let Some(metadata) = self.metadata {
+            header.insert(
+                "__metadata__".to_string(),
+                serde_json::to_value(metadata).map_err(candle_core::Error::msg)?,
+            );
+        }
+        for (name, start, end) in self.tensors {
+            header.insert(
+                name,
+                serde_json::json!({
+                    "dtype": "U8",
+                    "shape": [end - start],
+                    "data_offsets": [start, end],
+                }),
+            );
+        }
+        let mut header = serde_json::to_vec(&header).map_err(candle_core::Error::msg)?;
+        if header.len() > self.header_capacity {
+            candle_core::bail!(
+                "UQFF header of {} bytes exceeds the reserved {} bytes.",
+                header.len(),
+                self.header_capacity
+            );
+        }
+        header.resize(self.header_capacity, b' ');
+
+        let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&(self.header_capacity as u64).to_le_bytes())?;
+        file.write_all(&header)?;
+        Ok(())
+    }
+}
+
 pub struct UqffFullSer<'a> {
     pub tokenizer: &'a Tokenizer,
     pub template_filename: &'a Option<PathBuf>,
@@ -198,12 +323,291 @@ pub struct UqffFullSer<'a> {
     pub preprocessor_filename: &'a Option<PathBuf>,
 }
 
+impl UqffFullSer<'_> {
+    /// The files to embed in the UQFF metadata, keyed by their names in [`UQFF_EMBEDDED_FILES`].
+    fn embedded_files(&self) -> candle_core::Result<HashMap<String, String>> {
+        let read = |path: &PathBuf| {
+            String::from_utf8(read_model_file(path)?).map_err(candle_core::Error::msg)
+        };
+
+        let mut files = HashMap::new();
+        files.insert("config.json".to_string(), self.config.clone());
+        files.insert(
+            "tokenizer.json".to_string(),
+            serde_json::to_string(self.tokenizer).map_err(candle_core::Error::msg)?,
+        );
+        if let Some(template_filename) = self.template_filename {
+            let name = if template_filename.extension().map(|e| e.to_str()) == Some(Some("jinja")) {
+                "chat_template.jinja"
+            } else {
+                "tokenizer_config.json"
+            };
+            files.insert(name.to_string(), read(template_filename)?);
+        }
+        for (name, path) in [
+            ("generation_config.json", self.generation_config),
+            ("processor_config.json", self.processor_filename.as_ref()),
+            (
+                "preprocessor_config.json",
+                self.preprocessor_filename.as_ref(),
+            ),
+        ] {
+            if let Some(path) = path {
+                files.insert(name.to_string(), read(path)?);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Write the configuration, tokenizer, chat template and generation and processor configs
+    /// next to the serialized weights in `parent`.
+    pub(crate) fn write_files(self, parent: &Path) -> candle_core::Result<()> {
+        let config_out = parent.join("config.json");
+        let tokenizer_out = parent.join("tokenizer.json");
+        let tokenizer_cfg_out = parent.join("tokenizer_config.json");
+        let chat_template_jinja_out = parent.join("chat_template.jinja");
+        let gen_cfg_out = parent.join("generation_config.json");
+        let processor_out = parent.join("processor_config.json");
+        let preprocessor_out = parent.join("preprocessor_config.json");
+
+        let UqffFullSer {
+            tokenizer,
+            template_filename,
+            generation_config,
+            config,
+            processor_filename,
+            preprocessor_filename,
+        } = self;
+
+        info!("Serializing configuration to `{}`.", config_out.display());
+
+        std::fs::write(config_out, config)?;
+
+        info!("Serializing tokenizer to `{}`.", tokenizer_out.display());
+
+        serde_json::to_writer_pretty(File::create(&tokenizer_out)?, tokenizer)
+            .map_err(candle_core::Error::msg)?;
+
+        if let Some(template_filename) = template_filename {
+            let template = read_model_file(template_filename).map_err(candle_core::Error::msg)?;
+
+            if template_filename.extension().map(|e| e.to_str()) == Some(Some("jinja")) {
+                info!(
+                    "Serializing chat template to `{}`.",
+                    chat_template_jinja_out.display()
+                );
+                std::fs::write(&chat_template_jinja_out, template)
+                    .map_err(candle_core::Error::msg)?;
+            } else {
+                info!(
+                    "Serializing tokenizer config to `{}`.",
+                    tokenizer_cfg_out.display()
+                );
+                std::fs::write(&tokenizer_cfg_out, template).map_err(candle_core::Error::msg)?;
+            }
+        }
+
+        if let Some(generation_config) = generation_config {
+            info!(
+                "Serializing generation config to `{}`.",
+                gen_cfg_out.display()
+            );
+
+            let cfg = read_model_file(generation_config).map_err(candle_core::Error::msg)?;
+            std::fs::write(&gen_cfg_out, cfg).map_err(candle_core::Error::msg)?;
+        }
+
+        if let Some(processor_config) = processor_filename {
+            info!(
+                "Serializing processor config to `{}`.",
+                processor_out.display()
+            );
+
+            let cfg = read_model_file(processor_config).map_err(candle_core::Error::msg)?;
+            std::fs::write(&processor_out, cfg).map_err(candle_core::Error::msg)?;
+        }
+
+        if let Some(preprocessor_config) = preprocessor_filename {
+            info!(
+                "Serializing preprocessor config to `{}`.",
+                preprocessor_out.display()
+            );
+
+            let cfg = read_model_file(preprocessor_config).map_err(candle_core::Error::msg)?;
+            std::fs::write(&preprocessor_out, cfg).map_err(candle_core::Error::msg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the files embedded in the metadata of UQFF files, keyed by their names (such as
+/// `tokenizer.json`). UQFF files written before these were embedded have none.
+pub(crate) fn read_uqff_embedded_files(paths: &[PathBuf]) -> Result<HashMap<String, Arc<[u8]>>> {
+    #[derive(Deserialize)]
+    struct Header {
+        #[serde(rename = "__metadata__", default)]
+        metadata: HashMap<String, String>,
+    }
+
+    let mut files = HashMap::new();
+    for path in paths {
+        // Only the header is read: an 8 byte length, then the JSON header.
+        let mut file = File::open(path)?;
+        let mut len = [0u8; 8];
+        file.read_exact(&mut len)?;
+        let mut header = vec![0u8; u64::from_le_bytes(len) as usize];
+        file.read_exact(&mut header)?;
+        let header: Header = serde_json::from_slice(&header)?;
+        files.extend(
+            header
+                .metadata
+                .into_iter()
+                .filter(|(name, _)| UQFF_EMBEDDED_FILES.contains(&name.as_str()))
+                .map(|(name, contents)| (name, Arc::from(contents.into_bytes()))),
+        );
+    }
+    Ok(files)
+}
+
+/// Automatic mixed precision ISQ. The sensitivity of each layer is measured as its quantization
+/// error weighted by the imatrix, and the layers which benefit the most per extra byte are
+/// quantized to `high` instead of the ISQ type while the quantized layers fit in `budget_bytes`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MixedPrecisionIsq {
+    /// The higher precision type for the most sensitive layers.
+    pub high: IsqType,
+    /// The total size of the quantized layers, in bytes.
+    pub budget_bytes: usize,
+}
+
+/// The error of quantizing `layer` to `dtype`, with each input column weighted by the imatrix,
+/// along with the number of elements of its weight.
+fn quantization_error(
+    layer: &Arc<dyn QuantMethod>,
+    dtype: IsqType,
+    device: &Device,
+    imatrix: Option<&Vec<f32>>,
+    guard: QuantizeOntoGuard,
+) -> candle_core::Result<(f64, usize)> {
+    let weight = layer.dequantize_w()?.to_dtype(DType::F32)?;
+    let in_dim = weight.dim(D::Minus1)?;
+    let quantized = layer.clone().apply_isq(
+        Some(dtype),
+        device.clone(),
+        &AtomicUsize::new(0),
+        imatrix.cloned(),
+        guard,
+    )?;
+    let sq_err = (quantized
+        .dequantize_w()?
+        .to_dtype(DType::F32)?
+        .to_device(weight.device())?
+        - &weight)?
+        .sqr()?
+        .reshape(((), in_dim))?
+        .sum(0)?;
+    let err = match imatrix {
+        Some(imatrix) if imatrix.len() == in_dim => sq_err
+            .mul(&Tensor::new(imatrix.as_slice(), sq_err.device())?)?
+            .sum_all()?,
+        _ => sq_err.sum_all()?,
+    };
+    Ok((err.to_scalar::<f32>()? as f64, weight.elem_count()))
+}
+
+/// Choose the layers to upgrade to the higher precision type, given the `(low_size, high_size,
+/// low_error, high_error)` of each. Layers are upgraded in order of the reduction of the error
+/// per extra byte, as long as the total size stays within `budget`.
+fn select_upgrades(candidates: &[(usize, usize, f64, f64)], budget: usize) -> Vec<bool> {
+    let gain = |&(low_size, high_size, low_err, high_err): &(usize, usize, f64, f64)| {
+        (low_err - high_err) / high_size.saturating_sub(low_size).max(1) as f64
+    };
+    let mut order = (0..candidates.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| gain(&candidates[b]).total_cmp(&gain(&candidates[a])));
+
+    let mut total = candidates
+        .iter()
+        .map(|(low_size, ..)| low_size)
+        .sum::<usize>();
+    let mut upgrades = vec![false; candidates.len()];
+    for i in order {
+        let (low_size, high_size, low_err, high_err) = candidates[i];
+        let extra = high_size.saturating_sub(low_size);
+        if low_err > high_err && total + extra <= budget {
+            total += extra;
+            upgrades[i] = true;
+        }
+    }
+    upgrades
+}
+
+/// The ISQ type of each layer with mixed precision ISQ, see [`MixedPrecisionIsq`].
+fn assign_mixed_precision(
+    layers: &[(&mut Arc<dyn QuantMethod>, Option<usize>)],
+    devices_and_dtypes: &[(Device, Option<IsqType>)],
+    imatrix: &[Option<Vec<f32>>],
+    mixed_precision: MixedPrecisionIsq,
+) -> candle_core::Result<Vec<Option<IsqType>>> {
+    let guard = QuantizeOntoGuard::new();
+    let mut candidates = Vec::new();
+    for (((layer, _), (device, dtype)), imatrix) in
+        layers.iter().zip(devices_and_dtypes).zip(imatrix)
+    {
+        let Some(low) = *dtype else {
+            candidates.push((0, 0, 0., 0.));
+            continue;
+        };
+        let (low_err, numel) =
+            quantization_error(layer, low, device, imatrix.as_ref(), guard.clone())?;
+        let (high_err, _) = quantization_error(
+            layer,
+            mixed_precision.high,
+            device,
+            imatrix.as_ref(),
+            guard.clone(),
+        )?;
+        let size = |ty: IsqType| numel * DType::F32.size_in_bytes() / ty.pack_factor(DType::F32);
+        candidates.push((size(low), size(mixed_precision.high), low_err, high_err));
+        check_load_cancelled()?;
+    }
+
+    let total_low = candidates
+        .iter()
+        .map(|(low_size, ..)| low_size)
+        .sum::<usize>();
+    if total_low > mixed_precision.budget_bytes {
+        warn!(
+            "The quantized layers take {total_low} bytes, more than the mixed precision budget of {} bytes.",
+            mixed_precision.budget_bytes
+        );
+    }
+    let upgrades = select_upgrades(&candidates, mixed_precision.budget_bytes);
+    info!(
+        "Mixed precision ISQ: quantizing {} of {} layers to {:?}.",
+        upgrades.iter().filter(|x| **x).count(),
+        layers.len(),
+        mixed_precision.high
+    );
+    Ok(devices_and_dtypes
+        .iter()
+        .zip(upgrades)
+        .map(|((_, dtype), upgrade)| match dtype {
+            Some(_) if upgrade => Some(mixed_precision.high),
+            dtype => *dtype,
+        })
+        .collect())
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum ImatrixDataSource<'a> {
     File(&'a PathBuf),
     Collected,
 }
 
+/// A layer of [`IsqModel::get_layers`] with its index, and its safetensors module name if it is a
+/// module of the checkpoint.
+pub type NamedIsqLayer<'a> = (&'a mut Arc<dyn QuantMethod>, Option<usize>, Option<String>);
+
 pub trait IsqModel {
     /// Corresponds to `IsqOrganization::Default`
     #[allow(clippy::type_complexity)]
@@ -301,6 +705,19 @@ pub trait IsqModel {
         candle_core::bail!("This model does not support quantizing with an imatrix.");
     }
 
+    /// The layers of [`IsqModel::get_layers`], in the same order, with the safetensors module
+    /// name of each (e.g. `model.layers.0.self_attn.q_proj`). The name is `None` for layers which
+    /// are not a module of the checkpoint, such as the experts of AnyMoE or the halves of a merged
+    /// projection. This is used to apply LoRA adapters to an already loaded model and to export it.
+    ///
+    /// - Corresponds to `IsqOrganization::Default`
+    #[allow(clippy::type_complexity)]
+    fn get_layers_with_names(
+        &mut self,
+    ) -> candle_core::Result<Vec<(&mut Arc<dyn QuantMethod>, Option<String>)>> {
+        candle_core::bail!("This model does not name its layers, which is needed to apply LoRA adapters at runtime or export it.");
+    }
+
     /// Residual tensors for generating a UQFF file. Counterpart to [`get_layers`].
     fn residual_tensors(&self) -> Vec<(String, Tensor)>;
 
@@ -309,6 +726,60 @@ pub trait IsqModel {
         None
     }
 
+    /// Copy the layers of the model to the CPU, so that they can be quantized again later without
+    /// the loss of requantizing already quantized weights. Returns `None` if any layer is already
+    /// quantized.
+    fn offload_unquantized_layers(
+        &mut self,
+        organization: IsqOrganization,
+    ) -> candle_core::Result<Option<Vec<Arc<dyn QuantMethod>>>> {
+        let (layers, _) = match organization {
+            IsqOrganization::Default => self.get_layers(),
+            IsqOrganization::MoeExpertsOnly => self.get_layers_moe_experts_only(),
+        };
+        if layers
+            .iter()
+            .any(|(layer, _)| layer.unquant_weight_bias().is_none())
+        {
+            return Ok(None);
+        }
+        let n_quantized = AtomicUsize::new(0);
+        let guard = QuantizeOntoGuard::new();
+        layers
+            .into_iter()
+            .map(|(layer, _)| {
+                layer
+                    .clone()
+                    .apply_isq(None, Device::Cpu, &n_quantized, None, guard.clone())
+            })
+            .collect::<candle_core::Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    /// Replace the layers of the model with the ones returned by
+    /// [`offload_unquantized_layers`](IsqModel::offload_unquantized_layers).
+    fn restore_layers(
+        &mut self,
+        organization: IsqOrganization,
+        originals: &[Arc<dyn QuantMethod>],
+    ) -> candle_core::Result<()> {
+        let (layers, _) = match organization {
+            IsqOrganization::Default => self.get_layers(),
+            IsqOrganization::MoeExpertsOnly => self.get_layers_moe_experts_only(),
+        };
+        if layers.len() != originals.len() {
+            candle_core::bail!(
+                "Expected {} original layers, the model has {}.",
+                originals.len(),
+                layers.len()
+            );
+        }
+        for ((layer, _), original) in layers.into_iter().zip(originals) {
+            *layer = original.clone();
+        }
+        Ok(())
+    }
+
     /// Quantize the model in-situ.
     ///
     /// This function will also create a UQFF file, or, if the model supports it (residual tensors are returned),
@@ -321,12 +792,16 @@ pub trait IsqModel {
         topology: Option<&Topology>,
         silent: bool,
         imatrix_source: Option<ImatrixDataSource<'_>>,
+        mixed_precision: Option<MixedPrecisionIsq>,
         organization: IsqOrganization,
         write_artifacts: Option<&PathBuf>,
         full_ser: UqffFullSer<'_>,
         multi_progress: Arc<MultiProgress>,
     ) -> candle_core::Result<()> {
         {
+            if mixed_precision.is_some() && imatrix_source.is_none() {
+                candle_core::bail!("Mixed precision ISQ requires calibration data or an imatrix to measure the sensitivity of the layers.");
+            }
             let imatrix_to_weight = match imatrix_source {
                 Some(ImatrixDataSource::File(imatrix)) => {
                     let ext = imatrix.extension().ok_or(candle_core::Error::msg(
@@ -505,6 +980,17 @@ pub trait IsqModel {
                 };
                 devices_and_dtypes.push((device, dtype));
             }
+            if let Some(mixed_precision) = mixed_precision {
+                let dtypes = assign_mixed_precision(
+                    &tensors,
+                    &devices_and_dtypes,
+                    &imatrix_to_weight,
+                    mixed_precision,
+                )?;
+                for ((_, dtype), assigned) in devices_and_dtypes.iter_mut().zip(dtypes) {
+                    *dtype = assigned;
+                }
+            }
 
             let t_start = Instant::now();
 
@@ -539,6 +1025,7 @@ pub trait IsqModel {
                 .map_err(candle_core::Error::msg)?;
 
             let guard = QuantizeOntoGuard::new();
+            let n_done = AtomicUsize::new(0);
 
             pool.install(|| {
                 use indicatif::ParallelProgressIterator;
@@ -551,6 +1038,9 @@ pub trait IsqModel {
                         .zip(devices_and_dtypes)
                         .zip(imatrix_to_weight)
                         .for_each(|(((tensor, _), (device, dtype)), imatrix_weight)| {
+                            if load_cancelled() {
+                                return;
+                            }
                             **tensor = tensor
                                 .clone()
                                 .apply_isq(
@@ -562,6 +1052,10 @@ pub trait IsqModel {
                                 )
                                 .unwrap();
                             device.synchronize().unwrap();
+                            report_load_event(|| LoadEvent::Isq {
+                                quantized: n_done.fetch_add(1, Ordering::Relaxed) + 1,
+                                total: total_tensors,
+                            });
                         });
                 } else {
                     tensors
@@ -570,6 +1064,9 @@ pub trait IsqModel {
                         .zip(imatrix_to_weight)
                         .progress_with(bar)
                         .for_each(|(((tensor, _), (device, dtype)), imatrix_weight)| {
+                            if load_cancelled() {
+                                return;
+                            }
                             **tensor = tensor
                                 .clone()
                                 .apply_isq(
@@ -581,221 +1078,308 @@ pub trait IsqModel {
                                 )
                                 .unwrap();
                             device.synchronize().unwrap();
+                            report_load_event(|| LoadEvent::Isq {
+                                quantized: n_done.fetch_add(1, Ordering::Relaxed) + 1,
+                                total: total_tensors,
+                            });
                         });
                 }
             });
+            check_load_cancelled()?;
 
             if let Some(serialized) = write_artifacts {
-                info!(
-                    "Serializing {total_tensors} ISQ tensors to `{}`.",
-                    serialized.display()
-                );
+                self.serialize_uqff(serialized, silent, organization, full_ser)?;
+            }
+            let delta = Instant::now().duration_since(t_start).as_secs_f32();
+            info!("Applied in-situ quantization into {dtype:?} to {n_quantized:?} tensors out of {total_tensors} total tensors. Took {delta:.2}s", );
+        }
+        Ok(())
+    }
 
-                if serialized.extension().is_none_or(|ext| ext != "uqff") {
-                    candle_core::bail!("UQFF output path extension must be `.uqff`",);
-                }
+    /// Serialize the layers of the model to a UQFF file, along with the residual tensors,
+    /// configuration and tokenizer needed to load it.
+    fn serialize_uqff(
+        &mut self,
+        serialized: &PathBuf,
+        silent: bool,
+        organization: IsqOrganization,
+        full_ser: UqffFullSer<'_>,
+    ) -> candle_core::Result<()> {
+        let (tensors, _) = match organization {
+            IsqOrganization::Default => self.get_layers(),
+            IsqOrganization::MoeExpertsOnly => self.get_layers_moe_experts_only(),
+        };
+        let total_tensors = tensors.len();
 
-                let bar = ProgressBar::new(total_tensors as u64);
-                bar.set_style(
-                    ProgressStyle::default_bar()
-                        .template("[{elapsed_precise}] [{bar:40.red/magenta}] {pos}/{len} ({eta})")
-                        .unwrap()
-                        .progress_chars("#>-"),
-                );
+        info!(
+            "Serializing {total_tensors} ISQ tensors to `{}`.",
+            serialized.display()
+        );
 
-                #[cfg(not(feature = "metal"))]
-                let n_threads = 2;
-                #[cfg(feature = "metal")]
-                let n_threads = 1;
+        if serialized.extension().is_none_or(|ext| ext != "uqff") {
+            candle_core::bail!("UQFF output path extension must be `.uqff`",);
+        }
 
-                let pool = rayon::ThreadPoolBuilder::new()
-                    .num_threads(n_threads)
-                    .build()
-                    .map_err(candle_core::Error::msg)?;
+        let parent = serialized
+            .parent()
+            .context("Target UQFF path must have a filename!")?;
 
-                let quantized_values = pool.install(|| {
-                    if silent {
-                        tensors
-                            .par_iter()
-                            .enumerate()
-                            .filter(|(_, (layer, _))| layer.isq_serde_supported())
-                            .map(|(i, (layer, _))| {
-                                Ok((
-                                    i.to_string(),
-                                    match layer.serialize()? {
-                                        Cow::Borrowed(_) => unreachable!(),
-                                        Cow::Owned(owned) => owned,
-                                    },
-                                ))
-                            })
-                            .collect::<candle_core::Result<Vec<_>>>()
-                    } else {
-                        tensors
-                            .par_iter()
-                            .enumerate()
-                            .progress_with(bar)
-                            .filter(|(_, (layer, _))| layer.isq_serde_supported())
-                            .map(|(i, (layer, _))| {
-                                Ok((
-                                    i.to_string(),
-                                    match layer.serialize()? {
-                                        Cow::Borrowed(_) => unreachable!(),
-                                        Cow::Owned(owned) => owned,
-                                    },
-                                ))
-                            })
-                            .collect::<candle_core::Result<Vec<_>>>()
-                    }
-                });
-                let quantized_values = quantized_values?;
-
-                let parent = serialized
-                    .parent()
-                    .context("Target UQFF path must have a filename!")?;
-
-                std::fs::create_dir_all(parent)?;
-
-                let file_stem = serialized
-                    .file_stem()
-                    .context("Target UQFF path must have a file stem!")?
-                    .to_string_lossy()
-                    .to_string();
-
-                // Shard quantized values by cumulative byte size, max MAX_UQFF_SIZE_BYTES per file
-                let mut current_chunk = Vec::new();
-                let mut current_bytes: usize = 0;
-                let mut shard_index = 0;
-
-                // Every 10GB, flush the file. Then save any remaining tensors
-                for (name, tensor) in quantized_values.iter() {
-                    let tensor_bytes = tensor.len();
-                    if !current_chunk.is_empty()
-                        && current_bytes + tensor_bytes > MAX_UQFF_SIZE_BYTES
-                    {
-                        let mut shard_path = parent.to_path_buf();
-                        shard_path.push(format!("{file_stem}-{shard_index}.uqff"));
+        std::fs::create_dir_all(parent)?;
+
+        let file_stem = serialized
+            .file_stem()
+            .context("Target UQFF path must have a file stem!")?
+            .to_string_lossy()
+            .to_string();
+
+        let serializable = tensors
+            .iter()
+            .enumerate()
+            .filter(|(_, (layer, _))| layer.isq_serde_supported())
+            .collect::<Vec<_>>();
+
+        let bar = if silent {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(serializable.len() as u64)
+        };
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] [{bar:40.red/magenta}] {pos}/{len} ({eta})")
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+
+        #[cfg(not(feature = "metal"))]
+        let n_threads = 2;
+        #[cfg(feature = "metal")]
+        let n_threads = 1;
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(n_threads)
+            .build()
+            .map_err(candle_core::Error::msg)?;
+
+        // The first shard carries the configuration, tokenizer and templates in its metadata
+        let mut metadata = Some(full_ser.embedded_files()?);
+
+        // Serialize `n_threads` layers at a time and write them out straight away, so only those
+        // are held in memory. Start a new shard every MAX_UQFF_SIZE_BYTES.
+        let mut shard: Option<UqffShardWriter> = None;
+        let mut shard_index = 0;
+        let mut n_written = 0;
+        for batch in serializable.chunks(n_threads) {
+            let quantized_values = pool.install(|| {
+                batch
+                    .par_iter()
+                    .map(|(i, (layer, _))| {
+                        Ok((
+                            i.to_string(),
+                            match layer.serialize()? {
+                                Cow::Borrowed(_) => unreachable!(),
+                                Cow::Owned(owned) => owned,
+                            },
+                        ))
+                    })
+                    .collect::<candle_core::Result<Vec<_>>>()
+            })?;
+
+            for (name, data) in quantized_values {
+                if shard
+                    .as_ref()
+                    .is_some_and(|shard| shard.len + data.len() > MAX_UQFF_SIZE_BYTES)
+                {
+                    shard.take().unwrap().finish()?;
+                    shard_index += 1;
+                }
+                let shard = match &mut shard {
+                    Some(shard) => shard,
+                    None => {
+                        let shard_path = parent.join(format!("{file_stem}-{shard_index}.uqff"));
                         info!(
                             "Writing shard {} to `{}`",
                             shard_index,
                             shard_path.display()
                         );
-                        safetensors::serialize_to_file(current_chunk.clone(), None, &shard_path)?;
-                        shard_index += 1;
-                        current_chunk.clear();
-                        current_bytes = 0;
+                        shard.insert(UqffShardWriter::create(
+                            &shard_path,
+                            serializable.len() - n_written,
+                            metadata.take(),
+                        )?)
                     }
-                    current_bytes += tensor_bytes;
-                    current_chunk.push((name, CowBytesView::new(Cow::Borrowed(tensor))));
-                }
-
-                if !current_chunk.is_empty() {
-                    let mut shard_path = parent.to_path_buf();
-                    shard_path.push(format!("{file_stem}-{shard_index}.uqff"));
-                    info!(
-                        "Writing final shard {} to `{}`",
-                        shard_index,
-                        shard_path.display()
-                    );
-                    safetensors::serialize_to_file(current_chunk.clone(), None, &shard_path)?;
-                }
-
-                let residual = match organization {
-                    IsqOrganization::Default => self.residual_tensors(),
-                    IsqOrganization::MoeExpertsOnly => self
-                        .residual_tensors_moe_experts_only()
-                        .unwrap_or(self.residual_tensors()),
                 };
+                shard.push(name, &data)?;
+                n_written += 1;
+                bar.inc(1);
+            }
+        }
+        if let Some(shard) = shard {
+            shard.finish()?;
+        }
+        bar.finish();
 
-                let residual_out = parent.join(UQFF_RESIDUAL_SAFETENSORS);
-                let config_out = parent.join("config.json");
-                let tokenizer_out = parent.join("tokenizer.json");
-                let tokenizer_cfg_out = parent.join("tokenizer_config.json");
-                let chat_template_jinja_out = parent.join("chat_template.jinja");
-                let gen_cfg_out = parent.join("generation_config.json");
-                let processor_out = parent.join("processor_config.json");
-                let preprocessor_out = parent.join("preprocessor_config.json");
+        let residual = match organization {
+            IsqOrganization::Default => self.residual_tensors(),
+            IsqOrganization::MoeExpertsOnly => self
+                .residual_tensors_moe_experts_only()
+                .unwrap_or(self.residual_tensors()),
+        };
 
-                info!(
-                    "Serializing {} residual tensors to `{}`.",
-                    residual.len(),
-                    residual_out.display()
-                );
-
-                safetensors::serialize_to_file(residual, None, &residual_out)?;
+        let residual_out = parent.join(UQFF_RESIDUAL_SAFETENSORS);
 
-                let UqffFullSer {
-                    tokenizer,
-                    template_filename,
-                    generation_config,
-                    config,
-                    processor_filename,
-                    preprocessor_filename,
-                } = full_ser;
+        info!(
+            "Serializing {} residual tensors to `{}`.",
+            residual.len(),
+            residual_out.display()
+        );
 
-                info!("Serializing configuration to `{}`.", config_out.display());
+        safetensors::serialize_to_file(residual, None, &residual_out)?;
 
-                std::fs::write(config_out, config)?;
+        full_ser.write_files(parent)?;
+        Ok(())
+    }
 
-                info!("Serializing tokenizer to `{}`.", tokenizer_out.display());
+    /// Write the weights of the model to `dir/model.safetensors` in the layout of the original
+    /// checkpoint, along with the configuration and tokenizer needed to load it. All layers must
+    /// be unquantized.
+    fn serialize_safetensors(
+        &mut self,
+        dir: &Path,
+        full_ser: UqffFullSer<'_>,
+    ) -> candle_core::Result<()> {
+        let mut tensors = self.residual_tensors();
+        for (layer, name) in self.get_layers_with_names()? {
+            let Some(name) = name else {
+                candle_core::bail!(
+                    "Layer `{}` is not a module of the checkpoint, it cannot be exported to safetensors.",
+                    layer.name()
+                );
+            };
+            let Some((weight, bias)) = layer.unquant_weight_bias() else {
+                candle_core::bail!(
+                    "Layer `{name}` is quantized (`{}`), only unquantized models can be exported to safetensors.",
+                    layer.name()
+                );
+            };
+            tensors.push((format!("{name}.weight"), weight));
+            if let Some(bias) = bias {
+                tensors.push((format!("{name}.bias"), bias));
+            }
+        }
 
-                serde_json::to_writer_pretty(File::create(&tokenizer_out)?, tokenizer)
-                    .map_err(candle_core::Error::msg)?;
+        std::fs::create_dir_all(dir)?;
+        let out = dir.join("model.safetensors");
+        info!(
+            "Serializing {} tensors to `{}`.",
+            tensors.len(),
+            out.display()
+        );
+        safetensors::serialize_to_file(tensors, None, &out)?;
 
-                if let Some(template_filename) = template_filename {
-                    let template =
-                        std::fs::read(template_filename).map_err(candle_core::Error::msg)?;
+        full_ser.write_files(dir)?;
+        Ok(())
+    }
 
-                    if template_filename.extension().map(|e| e.to_str()) == Some(Some("jinja")) {
-                        info!(
-                            "Serializing chat template to `{}`.",
-                            chat_template_jinja_out.display()
-                        );
-                        std::fs::write(&chat_template_jinja_out, template)
-                            .map_err(candle_core::Error::msg)?;
-                    } else {
-                        info!(
-                            "Serializing tokenizer config to `{}`.",
-                            tokenizer_cfg_out.display()
-                        );
-                        std::fs::write(&tokenizer_cfg_out, template)
-                            .map_err(candle_core::Error::msg)?;
+    /// Write the weights of the model to a GGUF file at `path` in the layout llama.cpp expects,
+    /// along with the tokenizer and chat template. Layers are quantized to `quant` if it is given.
+    /// Otherwise, layers which are already GGML quantized keep their type and the others are
+    /// written in F16. Norms are always written in F32.
+    fn serialize_gguf(
+        &mut self,
+        path: &Path,
+        quant: Option<IsqType>,
+        full_ser: UqffFullSer<'_>,
+    ) -> candle_core::Result<()> {
+        let cfg = GgufExportConfig::from_config(&full_ser.config)?;
+        let quant = quant.map(GgmlDType::try_from).transpose()?;
+        // GGML types quantize rows in blocks, fall back to F16 for rows which don't fit them.
+        let quantize = |name: &str, xs: &Tensor, dtype: GgmlDType| {
+            let dtype = if xs.rank() == 1 {
+                GgmlDType::F32
+            } else if xs.dim(D::Minus1)? % dtype.block_size() != 0 {
+                warn!("Writing `{name}` in F16, its rows do not fit {dtype:?} blocks.");
+                GgmlDType::F16
+            } else {
+                dtype
+            };
+            QTensor::quantize(xs, dtype).map(Arc::new)
+        };
+        let gguf_name = |name: &str| {
+            gguf_tensor_name(name).with_context(|| format!("Tensor `{name}` has no GGUF name."))
+        };
+
+        let mut tensors = Vec::new();
+        for (name, tensor) in self.residual_tensors() {
+            let dtype = quant.unwrap_or(GgmlDType::F16);
+            tensors.push((gguf_name(&name)?, quantize(&name, &tensor, dtype)?));
+        }
+        for (layer, name) in self.get_layers_with_names()? {
+            let Some(name) = name else {
+                candle_core::bail!(
+                    "Layer `{}` is not a module of the checkpoint, it cannot be exported to GGUF.",
+                    layer.name()
+                );
+            };
+            let ggml_weight = layer.ggml_weight();
+            let dtype = quant
+                .or(ggml_weight.as_ref().map(|w| w.dtype()))
+                .unwrap_or(GgmlDType::F16);
+            let heads = cfg.permuted_heads(&name);
+            let weight = match ggml_weight {
+                Some(w) if w.dtype() == dtype && heads.is_none() => w,
+                _ => {
+                    let mut w = layer.dequantize_w()?;
+                    if let Some(heads) = heads {
+                        w = permute_qk(&w, heads)?;
                     }
+                    quantize(&name, &w, dtype)?
                 }
-
-                if let Some(generation_config) = generation_config {
-                    info!(
-                        "Serializing generation config to `{}`.",
-                        gen_cfg_out.display()
-                    );
-
-                    let cfg = std::fs::read(generation_config).map_err(candle_core::Error::msg)?;
-                    std::fs::write(&gen_cfg_out, cfg).map_err(candle_core::Error::msg)?;
-                }
-
-                if let Some(processor_config) = processor_filename {
-                    info!(
-                        "Serializing processor config to `{}`.",
-                        processor_out.display()
-                    );
-
-                    let cfg = std::fs::read(processor_config).map_err(candle_core::Error::msg)?;
-                    std::fs::write(&processor_out, cfg).map_err(candle_core::Error::msg)?;
+            };
+            tensors.push((gguf_name(&format!("{name}.weight"))?, weight));
+            if let Some((_, Some(mut bias))) = layer.unquant_weight_bias() {
+                if let Some(heads) = heads {
+                    bias = permute_qk(&bias, heads)?;
                 }
+                tensors.push((
+                    gguf_name(&format!("{name}.bias"))?,
+                    quantize(&name, &bias, GgmlDType::F32)?,
+                ));
+            }
+        }
+        if let Some(rope_freqs) = cfg.rope_freqs(&Device::Cpu)? {
+            tensors.push((
+                "rope_freqs.weight".to_string(),
+                quantize("rope_freqs", &rope_freqs, GgmlDType::F32)?,
+            ));
+        }
 
-                if let Some(preprocessor_config) = preprocessor_filename {
-                    info!(
-                        "Serializing preprocessor config to `{}`.",
-                        preprocessor_out.display()
-                    );
+        let mut metadata = cfg.metadata();
+        metadata.extend(tokenizer_metadata(
+            full_ser.tokenizer,
+            &cfg.model_type,
+            full_ser.template_filename,
+            full_ser.generation_config,
+        )?);
 
-                    let cfg =
-                        std::fs::read(preprocessor_config).map_err(candle_core::Error::msg)?;
-                    std::fs::write(&preprocessor_out, cfg).map_err(candle_core::Error::msg)?;
-                }
-            }
-            let delta = Instant::now().duration_since(t_start).as_secs_f32();
-            info!("Applied in-situ quantization into {dtype:?} to {n_quantized:?} tensors out of {total_tensors} total tensors. Took {delta:.2}s", );
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
         }
+        info!(
+            "Serializing {} tensors to `{}`.",
+            tensors.len(),
+            path.display()
+        );
+        let metadata = metadata
+            .iter()
+            .map(|(k, v)| (k.as_str(), v))
+            .collect::<Vec<_>>();
+        let tensors = tensors
+            .iter()
+            .map(|(name, w)| (name.as_str(), &**w))
+            .collect::<Vec<_>>();
+        let mut file = BufWriter::new(File::create(path)?);
+        quantized::gguf_file::write(&mut file, &metadata, &tensors)?;
+        file.flush()?;
         Ok(())
     }
 
@@ -1058,3 +1642,17 @@ pub(crate) trait IsqModelLoader {
         self.isq_layer_regexes(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::select_upgrades;
+
+    #[test]
+    fn upgrades_most_sensitive_layers_within_budget() {
+        // (low_size, high_size, low_error, high_error)
+        let candidates = [(10, 20, 1., 0.5), (10, 20, 8., 1.), (10, 20, 4., 1.)];
+        assert_eq!(select_upgrades(&candidates, 50), [false, true, true]);
+        assert_eq!(select_upgrades(&candidates, 40), [false, true, false]);
+        assert_eq!(select_upgrades(&candidates, 30), [false, false, false]);
+    }
+}
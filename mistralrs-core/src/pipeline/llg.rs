@@ -1,9 +1,14 @@
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, Weak},
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use llguidance::{api::TopLevelGrammar, ParserFactory};
+use once_cell::sync::Lazy;
 use tokenizers::Tokenizer;
 
+use super::gbnf::gbnf_to_lark;
 use crate::Constraint;
 
 pub fn build_llg_factory(tokenizer: Tokenizer) -> Result<Arc<ParserFactory>> {
@@ -17,8 +22,19 @@ pub fn llg_grammar_from_constraint(constraint: &Constraint) -> Result<Option<Top
     let grm = match constraint {
         Constraint::Regex(regex) => TopLevelGrammar::from_regex(regex),
         Constraint::Lark(lark) => TopLevelGrammar::from_lark(lark.clone()),
+        Constraint::Gbnf(gbnf) => TopLevelGrammar::from_lark(gbnf_to_lark(gbnf)?),
         Constraint::JsonSchema(value) => TopLevelGrammar::from_json_schema(value.clone()),
         Constraint::Llguidance(value) => value.clone(),
+        Constraint::Choice(choices) => {
+            if choices.is_empty() {
+                bail!("Guided choice requires at least one choice");
+            }
+            let alternates = choices
+                .iter()
+                .map(|choice| regex::escape(choice))
+                .collect::<Vec<_>>();
+            TopLevelGrammar::from_regex(&alternates.join("|"))
+        }
         Constraint::None => return Ok(None),
     };
     Ok(Some(grm))
@@ -31,3 +47,66 @@ pub fn constraint_from_llg_grammar(
     let parser = factory.create_parser(grm)?;
     Ok(llguidance::Matcher::new(Ok(parser)))
 }
+
+/// The number of compiled constraints which are kept for reuse.
+const CONSTRAINT_CACHE_SIZE: usize = 64;
+
+struct CachedConstraint {
+    factory: Weak<ParserFactory>,
+    key: String,
+    matcher: llguidance::Matcher,
+}
+
+/// Compiled constraints, least recently used first. The weak reference to the factory of each
+/// entry keeps its address from being reused by the factory of another tokenizer.
+static CONSTRAINT_CACHE: Lazy<Mutex<VecDeque<CachedConstraint>>> =
+    Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// Build a matcher for `constraint`, reusing the grammar compiled for an earlier sequence with the
+/// same constraint and tokenizer. Compiling a grammar, for example a regex to its automaton over
+/// the tokens, is much slower than copying a compiled one.
+pub fn cached_constraint(
+    factory: &Arc<ParserFactory>,
+    constraint: &Constraint,
+) -> Result<Option<llguidance::Matcher>> {
+    if matches!(constraint, Constraint::None) {
+        return Ok(None);
+    }
+    let key = serde_json::to_string(constraint)?;
+    let is_entry = |entry: &CachedConstraint| {
+        entry.key == key && std::ptr::eq(entry.factory.as_ptr(), Arc::as_ptr(factory))
+    };
+
+    {
+        let mut cache = CONSTRAINT_CACHE
+            .lock()
+            .expect("constraint cache was poisoned");
+        cache.retain(|entry| entry.factory.strong_count() > 0);
+        if let Some(i) = cache.iter().position(is_entry) {
+            let entry = cache.remove(i).unwrap();
+            let matcher = entry.matcher.deep_clone();
+            cache.push_back(entry);
+            return Ok(Some(matcher));
+        }
+    }
+
+    let Some(grm) = llg_grammar_from_constraint(constraint)? else {
+        return Ok(None);
+    };
+    let matcher = constraint_from_llg_grammar(factory, grm)?;
+
+    let mut cache = CONSTRAINT_CACHE
+        .lock()
+        .expect("constraint cache was poisoned");
+    if !cache.iter().any(is_entry) {
+        cache.push_back(CachedConstraint {
+            factory: Arc::downgrade(factory),
+            key,
+            matcher: matcher.deep_clone(),
+        });
+        if cache.len() > CONSTRAINT_CACHE_SIZE {
+            cache.pop_front();
+        }
+    }
+    Ok(Some(matcher))
+}
@@ -1,16 +1,25 @@
+use std::borrow::Cow;
 use std::fmt::{self, Display};
+use std::path::PathBuf;
 
+use crate::device_map::{self, DeviceMapper};
+use crate::matformer::MatformerSliceConfig;
 use crate::paged_attention::{
     calculate_cache_config, ModelConfigLike, DEFAULT_PAGED_ATTENTION_BLOCK_SIZE,
 };
 use crate::utils::debug::DeviceRepr;
-use crate::{DeviceLayerMapMetadata, DeviceMapMetadata, MemoryUsage, PagedAttentionConfig};
+use crate::utils::progress::{report_load_event, LoadEvent};
+use crate::{
+    DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, MemoryUsage, PagedAttentionConfig,
+    Topology, TryIntoDType,
+};
 use anyhow::{Context, Result};
 use candle_core::{DType, Device};
 use itertools::Itertools;
+use mistralrs_quant::{AfqLayer, GgufMatMul, HqqLayer, IsqType, QuantizedSerdeType};
 use tracing::{info, warn};
 
-use super::DeviceMappedModelLoader;
+use super::{DeviceMappedModelLoader, QuantizationConfigShim};
 
 #[derive(Clone, Debug)]
 pub(crate) enum NonMappedSubModel {
@@ -159,25 +168,16 @@ macro_rules! b_to_mb {
     };
 }
 
-#[allow(clippy::too_many_arguments)]
-/// Core logic for automatic device mapping
-pub fn get_device_layers(
+/// Size of the KV cache of one layer, either the PagedAttention blocks or a cache holding
+/// `max_seq_len` tokens for each of the `max_batch_size` sequences.
+fn kv_cache_bytes_per_layer(
     loader: &dyn DeviceMappedModelLoader,
     config: &str,
-    num_layers: usize,
-    mut layer_sizes_in_bytes: Vec<usize>,
-    non_mapped_size_in_bytes: usize,
-    total_model_size_in_bytes: usize,
     devices: &[Device],
     dtype: DType,
     params: &AutoDeviceMapParams,
     paged_attn_config: Option<&PagedAttentionConfig>,
-) -> Result<DeviceMapMetadata> {
-    let mapped_max = loader.mapped_max_act_size_elems(config, params)? * dtype.size_in_bytes();
-    let non_mapped_max =
-        loader.non_mapped_max_act_size_elems(config, params)? * dtype.size_in_bytes();
-
-    let mut remaining = total_model_size_in_bytes;
+) -> Result<usize> {
     let max_seq_len = match params {
         AutoDeviceMapParams::Text { max_seq_len, .. }
         | AutoDeviceMapParams::Vision { max_seq_len, .. } => *max_seq_len,
@@ -192,6 +192,9 @@ pub fn get_device_layers(
         Some(cfg) => {
             let cache = calculate_cache_config(
                 cfg.mem_gpu,
+                cfg.swap_space,
+                cfg.host_offload_bytes,
+                cfg.watermark,
                 Some(cfg.block_size.unwrap_or(DEFAULT_PAGED_ATTENTION_BLOCK_SIZE)),
                 dtype,
                 paged_attn_config
@@ -225,7 +228,30 @@ pub fn get_device_layers(
             key_shape.iter().product::<usize>() + val_shape.iter().product::<usize>()
         }
     };
-    let kv_cache_bytes = kv_cache_elems * dtype.size_in_bytes();
+    Ok(kv_cache_elems * dtype.size_in_bytes())
+}
+
+#[allow(clippy::too_many_arguments)]
+/// Core logic for automatic device mapping
+pub fn get_device_layers(
+    loader: &dyn DeviceMappedModelLoader,
+    config: &str,
+    num_layers: usize,
+    mut layer_sizes_in_bytes: Vec<usize>,
+    non_mapped_size_in_bytes: usize,
+    total_model_size_in_bytes: usize,
+    devices: &[Device],
+    dtype: DType,
+    params: &AutoDeviceMapParams,
+    paged_attn_config: Option<&PagedAttentionConfig>,
+) -> Result<DeviceMapMetadata> {
+    let mapped_max = loader.mapped_max_act_size_elems(config, params)? * dtype.size_in_bytes();
+    let non_mapped_max =
+        loader.non_mapped_max_act_size_elems(config, params)? * dtype.size_in_bytes();
+
+    let mut remaining = total_model_size_in_bytes;
+    let kv_cache_bytes =
+        kv_cache_bytes_per_layer(loader, config, devices, dtype, params, paged_attn_config)?;
 
     // prepare available memory per device, CPU fallback last
     let mut avail = Vec::new();
@@ -251,6 +277,7 @@ pub fn get_device_layers(
     let mut layer = 0;
     let avail_copy = avail.clone();
     let mut includes_cpu = false;
+    let mut placements = Vec::new();
     while remaining > 0 && !avail.is_empty() {
         let (cap, dev) = avail
             .pop()
@@ -308,6 +335,7 @@ pub fn get_device_layers(
             }
             count
         };
+        placements.push((dev.device_pretty_repr(), layers_on_dev));
         if !dev.is_cpu() {
             mappings.push(DeviceLayerMapMetadata {
                 ordinal,
@@ -341,5 +369,233 @@ pub fn get_device_layers(
             None,
         );
     }
+    for (device, layers) in placements {
+        report_load_event(|| LoadEvent::DeviceMap { device, layers });
+    }
     Ok(DeviceMapMetadata::from_num_device_layers(mappings))
 }
+
+/// The weight pack factor of the model: UQFF artifacts take priority over ISQ, and otherwise the
+/// quantization config of a prequantized model is used.
+pub(crate) fn weight_pack_factor(
+    config: &str,
+    dtype: DType,
+    in_situ_quant: Option<IsqType>,
+    from_uqff: Option<&[PathBuf]>,
+) -> Result<usize> {
+    if let Some(serialized) = from_uqff {
+        let ser_artifacts =
+            unsafe { candle_core::safetensors::MmapedSafetensors::multi(serialized)? };
+        let mut total_pack_factors = 0;
+        let total_tensors = ser_artifacts.tensors().len();
+        for (_, artifact) in ser_artifacts.tensors() {
+            let artifact = artifact.data();
+            // NOTE(EricLBuehler): isq type is ALWAYS byte 4 (5th) of the tensor.
+            let isq_type = artifact[mistralrs_quant::UQFF_QUANT_TYPE_OFFSET];
+            let pack_factor = match QuantizedSerdeType::try_from(isq_type as usize)? {
+                QuantizedSerdeType::Hqq => {
+                    HqqLayer::get_isq_type_from_uqff(Cow::Borrowed(artifact))?.pack_factor(dtype)
+                }
+                QuantizedSerdeType::Gguf => {
+                    GgufMatMul::get_isq_type_from_uqff(Cow::Borrowed(artifact))?.pack_factor(dtype)
+                }
+                QuantizedSerdeType::Fp8 => IsqType::F8E4M3.pack_factor(dtype),
+                QuantizedSerdeType::Unquant => 1,
+                QuantizedSerdeType::Afq => {
+                    AfqLayer::get_isq_type_from_uqff(Cow::Borrowed(artifact))?.pack_factor(dtype)
+                }
+            };
+            total_pack_factors += pack_factor;
+        }
+
+        Ok(total_pack_factors / total_tensors)
+    } else if let Some(isq) = in_situ_quant {
+        Ok(isq.pack_factor(dtype))
+    } else {
+        // Be sure to get the weight pack factor here; we might be loading a prequantized model.
+        QuantizationConfigShim::get_quant_config_pack_factor(config, dtype)
+    }
+}
+
+/// Returns the size of each layer, of the non-mapped part of the model, and of the whole model.
+pub(crate) fn model_sizes_in_bytes(
+    loader: &dyn DeviceMappedModelLoader,
+    config: &str,
+    dtype: DType,
+    weight_pack_factor: usize,
+    matformer_config: Option<&MatformerSliceConfig>,
+) -> Result<(Vec<usize>, usize, usize)> {
+    let layer_sizes_in_bytes =
+        loader.layer_sizes_in_bytes(config, dtype, weight_pack_factor, matformer_config)?;
+    let non_mapped_size_in_bytes =
+        loader.non_mapped_size_in_bytes(config, dtype, weight_pack_factor, matformer_config)?;
+    let layer_sizes_sum = layer_sizes_in_bytes.iter().sum::<usize>();
+    Ok((
+        layer_sizes_in_bytes,
+        non_mapped_size_in_bytes,
+        layer_sizes_sum + non_mapped_size_in_bytes,
+    ))
+}
+
+/// Projected memory usage of one device, in bytes.
+#[derive(Clone, Debug)]
+pub struct DeviceMemoryEstimate {
+    pub device: Device,
+    /// Number of repeating layers on this device.
+    pub layers: usize,
+    pub weights: usize,
+    pub kv_cache: usize,
+    /// Peak activation size for the auto device mapping params.
+    pub activations: usize,
+    /// Memory currently available on this device.
+    pub available: usize,
+}
+
+impl DeviceMemoryEstimate {
+    pub fn total(&self) -> usize {
+        self.weights + self.kv_cache + self.activations
+    }
+
+    /// Whether the projected usage fits in the memory currently available.
+    pub fn fits(&self) -> bool {
+        self.total() <= self.available
+    }
+}
+
+/// Projected memory usage of a model for each device it would be loaded on. The CPU entry, if
+/// any, is RAM and the others are VRAM.
+#[derive(Clone, Debug)]
+pub struct MemoryEstimate {
+    pub devices: Vec<DeviceMemoryEstimate>,
+}
+
+impl MemoryEstimate {
+    pub fn total(&self) -> usize {
+        self.devices.iter().map(DeviceMemoryEstimate::total).sum()
+    }
+
+    pub fn fits(&self) -> bool {
+        self.devices.iter().all(DeviceMemoryEstimate::fits)
+    }
+}
+
+impl Display for MemoryEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, dev) in self.devices.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{}: {} layers, {}MB weights, {}MB KV cache, {}MB activations, {}MB total ({}MB available)",
+                dev.device.device_pretty_repr(),
+                dev.layers,
+                b_to_mb!(dev.weights),
+                b_to_mb!(dev.kv_cache),
+                b_to_mb!(dev.activations),
+                b_to_mb!(dev.total()),
+                b_to_mb!(dev.available),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn device_estimate<'a>(
+    estimates: &'a mut Vec<DeviceMemoryEstimate>,
+    device: &Device,
+) -> Result<&'a mut DeviceMemoryEstimate> {
+    let i = match estimates.iter().position(|e| e.device.same_device(device)) {
+        Some(i) => i,
+        None => {
+            estimates.push(DeviceMemoryEstimate {
+                device: device.clone(),
+                layers: 0,
+                weights: 0,
+                kv_cache: 0,
+                activations: 0,
+                available: MemoryUsage.get_memory_available(device)?,
+            });
+            estimates.len() - 1
+        }
+    };
+    Ok(&mut estimates[i])
+}
+
+/// Project the memory used on each device when loading a model with these settings, without
+/// loading any weights. Automatic device mapping is resolved as it would be during loading.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn estimate_memory(
+    loader: &dyn DeviceMappedModelLoader,
+    config: &str,
+    dtype: &dyn TryIntoDType,
+    device: &Device,
+    mapper: DeviceMapSetting,
+    mut in_situ_quant: Option<IsqType>,
+    mut paged_attn_config: Option<&PagedAttentionConfig>,
+    from_uqff: Option<&[PathBuf]>,
+    matformer_config: Option<&MatformerSliceConfig>,
+    topology: Option<&Topology>,
+    params: AutoDeviceMapParams,
+) -> Result<MemoryEstimate> {
+    let available_devices = device_map::get_all_similar_devices(device)?;
+    let dtype = dtype.try_into_dtype(&available_devices.iter().collect::<Vec<_>>())?;
+
+    // Disable ISQ if we are loading a prequantized model.
+    if QuantizationConfigShim::get_quant_config_pack_factor(config, dtype)? != 1 {
+        in_situ_quant = None;
+    }
+    let weight_pack_factor = weight_pack_factor(config, dtype, in_situ_quant, from_uqff)?;
+    let (layer_sizes_in_bytes, non_mapped_size_in_bytes, total_model_size_in_bytes) =
+        model_sizes_in_bytes(loader, config, dtype, weight_pack_factor, matformer_config)?;
+    let num_layers = loader.num_layers(config)?;
+
+    let mapper = match mapper {
+        DeviceMapSetting::Auto(_) => DeviceMapSetting::Map(get_device_layers(
+            loader,
+            config,
+            num_layers,
+            layer_sizes_in_bytes.clone(),
+            non_mapped_size_in_bytes,
+            total_model_size_in_bytes,
+            &available_devices,
+            dtype,
+            &params,
+            paged_attn_config,
+        )?),
+        mapper => mapper,
+    }
+    .into_mapper(num_layers, device, topology)?;
+
+    // There is no CPU support for PagedAttention, so it is disabled if any layer is on the CPU.
+    if mapper.get_unique_devices().iter().any(Device::is_cpu) {
+        paged_attn_config = None;
+    }
+
+    let mapped_max = loader.mapped_max_act_size_elems(config, &params)? * dtype.size_in_bytes();
+    let non_mapped_max =
+        loader.non_mapped_max_act_size_elems(config, &params)? * dtype.size_in_bytes();
+    let kv_cache_bytes = kv_cache_bytes_per_layer(
+        loader,
+        config,
+        &available_devices,
+        dtype,
+        &params,
+        paged_attn_config,
+    )?;
+
+    let mut estimates = Vec::new();
+    let nm_estimate = device_estimate(&mut estimates, device)?;
+    nm_estimate.weights += non_mapped_size_in_bytes;
+    nm_estimate.activations = non_mapped_max;
+    for (layer, size) in layer_sizes_in_bytes.into_iter().enumerate() {
+        let layer_device = mapper.device_for(layer, false).unwrap_or(device);
+        let estimate = device_estimate(&mut estimates, layer_device)?;
+        estimate.layers += 1;
+        estimate.weights += size;
+        estimate.kv_cache += kv_cache_bytes;
+        estimate.activations = estimate.activations.max(mapped_max);
+    }
+
+    Ok(MemoryEstimate { devices: estimates })
+}
@@ -2,8 +2,8 @@ pub(crate) mod auto_device_map;
 mod diffusion_loaders;
 mod normal_loaders;
 mod vision_loaders;
-pub use auto_device_map::AutoDeviceMapParams;
 use auto_device_map::NonMappedSubModel;
+pub use auto_device_map::{AutoDeviceMapParams, DeviceMemoryEstimate, MemoryEstimate};
 
 use std::{
     fmt::{self, Debug},
@@ -318,6 +318,86 @@ impl ModelKind {
     }
 }
 
+/// MLX checkpoints store their quantization under `quantization`, and newer conversions also copy
+/// it to `quantization_config`. Only keep `quantization_config`, which the model configs read.
+pub(crate) fn normalize_quantization_config(config: String) -> Result<String> {
+    let mut value: serde_json::Value = serde_json::from_str(&config)?;
+    let Some(object) = value.as_object_mut() else {
+        return Ok(config);
+    };
+    let Some(quantization) = object.remove("quantization") else {
+        return Ok(config);
+    };
+    object.entry("quantization_config").or_insert(quantization);
+    Ok(serde_json::to_string(&value)?)
+}
+
+/// Overrides the sliding window attention of the `config.json` of a model, for checkpoints which
+/// ship wrong values or to run with full attention.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlidingWindowOverride {
+    /// Use full attention in all layers.
+    Disabled,
+    /// Use a sliding window of this many tokens in the layers which have one.
+    Size(usize),
+}
+
+/// Apply `sliding_window` to the config and, for multimodal models, to its text config.
+pub(crate) fn apply_sliding_window_override(
+    config: String,
+    sliding_window: Option<SlidingWindowOverride>,
+) -> Result<String> {
+    let Some(sliding_window) = sliding_window else {
+        return Ok(config);
+    };
+    let mut value: serde_json::Value = serde_json::from_str(&config)?;
+    let Some(object) = value.as_object_mut() else {
+        return Ok(config);
+    };
+
+    let mut applied = override_sliding_window(object, sliding_window);
+    if let Some(text_config) = object
+        .get_mut("text_config")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        applied |= override_sliding_window(text_config, sliding_window);
+    }
+    if !applied {
+        tracing::warn!(
+            "The model config has no sliding window, ignoring the sliding window override."
+        );
+        return Ok(config);
+    }
+    Ok(serde_json::to_string(&value)?)
+}
+
+/// Returns false if the config has no sliding window settings.
+fn override_sliding_window(
+    config: &mut serde_json::Map<String, serde_json::Value>,
+    sliding_window: SlidingWindowOverride,
+) -> bool {
+    let has_flag = config.contains_key("use_sliding_window");
+    if !has_flag && !config.contains_key("sliding_window") {
+        return false;
+    }
+    let (enabled, size) = match sliding_window {
+        // Models which always use their window get one covering the whole context
+        SlidingWindowOverride::Disabled => (
+            false,
+            config
+                .get("max_position_embeddings")
+                .cloned()
+                .unwrap_or(serde_json::Value::Null),
+        ),
+        SlidingWindowOverride::Size(size) => (true, size.into()),
+    };
+    if has_flag {
+        config.insert("use_sliding_window".to_string(), enabled.into());
+    }
+    config.insert("sliding_window".to_string(), size);
+    true
+}
+
 #[derive(Deserialize)]
 pub struct QuantizationConfigShim {
     quantization_config: Option<QuantizedConfig>,
@@ -459,6 +539,57 @@ pub trait Loader: Send + Sync {
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>>;
 
+    /// Project the memory used on each device by `load_model_from_hf` with the same arguments.
+    /// Only the model config (and the UQFF files, if any) are downloaded, and no weights are loaded.
+    #[allow(clippy::too_many_arguments)]
+    fn estimate_memory_from_hf(
+        &self,
+        _revision: Option<String>,
+        _token_source: TokenSource,
+        _dtype: &dyn TryIntoDType,
+        _device: &Device,
+        _silent: bool,
+        _mapper: DeviceMapSetting,
+        _in_situ_quant: Option<IsqType>,
+        _paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<MemoryEstimate> {
+        anyhow::bail!("Memory estimation is not supported for this model kind.")
+    }
+
     fn get_id(&self) -> String;
     fn get_kind(&self) -> ModelKind;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_sliding_window_override, SlidingWindowOverride};
+
+    #[test]
+    fn overrides_sliding_window() {
+        let config = r#"{"max_position_embeddings":8192,"sliding_window":4096,"use_sliding_window":true,"text_config":{"sliding_window":512}}"#;
+        let apply = |sliding_window| -> serde_json::Value {
+            serde_json::from_str(
+                &apply_sliding_window_override(config.to_string(), Some(sliding_window)).unwrap(),
+            )
+            .unwrap()
+        };
+
+        let disabled = apply(SlidingWindowOverride::Disabled);
+        assert_eq!(disabled["use_sliding_window"], false);
+        assert_eq!(disabled["sliding_window"], 8192);
+        assert!(disabled["text_config"]["sliding_window"].is_null());
+
+        let resized = apply(SlidingWindowOverride::Size(1024));
+        assert_eq!(resized["use_sliding_window"], true);
+        assert_eq!(resized["sliding_window"], 1024);
+        assert_eq!(resized["text_config"]["sliding_window"], 1024);
+
+        // Configs without a sliding window are left alone
+        let config = r#"{"max_position_embeddings":8192}"#;
+        assert_eq!(
+            apply_sliding_window_override(config.to_string(), Some(SlidingWindowOverride::Size(8)))
+                .unwrap(),
+            config
+        );
+    }
+}
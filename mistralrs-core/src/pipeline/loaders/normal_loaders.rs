@@ -50,6 +50,16 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> candle_core::Result<Tensor>;
+    /// The last hidden states of all tokens of `input_ids`, after the final norm, instead of the
+    /// logits. Contrastive search compares them, and only some models return them.
+    fn forward_hidden_states(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _flash_params: &FlashParams,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("This model does not return its hidden states.")
+    }
     #[allow(clippy::too_many_arguments)]
     fn xlora_forward(
         &self,
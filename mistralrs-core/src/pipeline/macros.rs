@@ -114,8 +114,18 @@ macro_rules! get_paths {
         $quantized_model_id:expr,
         $quantized_filename:expr,
         $silent:expr,
-        $loading_uqff:expr
+        $loading_uqff:expr,
+        $uqff_files:expr
     ) => {{
+        // Files embedded in the UQFF file take precedence over the model repository
+        let uqff_files: Option<&$crate::pipeline::in_memory::InMemoryFiles> = $uqff_files;
+        let embedded = |name: &str| {
+            let path = uqff_files.and_then(|files| files.path(name));
+            if path.is_some() {
+                info!("Using `{name}` embedded in the UQFF file");
+            }
+            path
+        };
         let api = {
             use $crate::GLOBAL_HF_CACHE;
             let cache = GLOBAL_HF_CACHE.get().cloned().unwrap_or_default();
@@ -137,12 +147,18 @@ macro_rules! get_paths {
         let tokenizer_filename = if let Some(ref p) = $this.tokenizer_json {
             info!("Using tokenizer.json at `{p}`");
             PathBuf::from_str(p)?
+        } else if let Some(p) = embedded("tokenizer.json") {
+            p
         } else {
             info!("Loading `tokenizer.json` at `{}`", $this.model_id);
             $crate::api_get_file!(api, "tokenizer.json", model_id)
         };
-        info!("Loading `config.json` at `{}`", $this.model_id);
-        let config_filename = $crate::api_get_file!(api, "config.json", model_id);
+        let config_filename = if let Some(p) = embedded("config.json") {
+            p
+        } else {
+            info!("Loading `config.json` at `{}`", $this.model_id);
+            $crate::api_get_file!(api, "config.json", model_id)
+        };
         let filenames = get_model_paths(
             revision.clone(),
             &$token_source,
@@ -156,13 +172,17 @@ macro_rules! get_paths {
             $this.model_id.clone(),
             $this.xlora_model_id.as_ref(),
             $this.lora_adapter_ids.as_ref(),
+            &$this.lora_adapter_scales,
             &$token_source,
             revision.clone(),
             $this.xlora_order.as_ref(),
+            $this.xlora_classifier_path.as_ref(),
         )?;
         let dir_list = $crate::api_dir_list!(api, model_id, false).collect::<Vec<_>>();
 
-        let gen_conf = if dir_list.contains(&"generation_config.json".to_string()) {
+        let gen_conf = if let Some(p) = embedded("generation_config.json") {
+            Some(p)
+        } else if dir_list.contains(&"generation_config.json".to_string()) {
             info!("Loading `generation_config.json` at `{}`", $this.model_id);
             Some($crate::api_get_file!(
                 api,
@@ -172,7 +192,9 @@ macro_rules! get_paths {
         } else {
             None
         };
-        let preprocessor_config = if dir_list.contains(&"preprocessor_config.json".to_string()) {
+        let preprocessor_config = if let Some(p) = embedded("preprocessor_config.json") {
+            Some(p)
+        } else if dir_list.contains(&"preprocessor_config.json".to_string()) {
             info!("Loading `preprocessor_config.json` at `{}`", $this.model_id);
             Some($crate::api_get_file!(
                 api,
@@ -182,7 +204,9 @@ macro_rules! get_paths {
         } else {
             None
         };
-        let processor_config = if dir_list.contains(&"processor_config.json".to_string()) {
+        let processor_config = if let Some(p) = embedded("processor_config.json") {
+            Some(p)
+        } else if dir_list.contains(&"processor_config.json".to_string()) {
             info!("Loading `processor_config.json` at `{}`", $this.model_id);
             Some($crate::api_get_file!(
                 api,
@@ -195,6 +219,10 @@ macro_rules! get_paths {
         let template_filename = if let Some(ref p) = $this.chat_template {
             info!("Using chat template file at `{p}`");
             Some(PathBuf::from_str(p)?)
+        } else if let Some(p) =
+            embedded("chat_template.jinja").or_else(|| embedded("tokenizer_config.json"))
+        {
+            Some(p)
         } else if dir_list.contains(&"chat_template.jinja".to_string()) {
             info!("Loading `chat_template.jinja` at `{}`", $this.model_id);
             Some($crate::api_get_file!(api, "chat_template.jinja", model_id))
@@ -226,6 +254,34 @@ macro_rules! get_paths {
     }};
 }
 
+/// Only fetch `config.json`, for when the weights are not needed.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! get_config_path {
+    ($token_source:expr, $revision:expr, $this:expr, $silent:expr) => {{
+        let api = {
+            use $crate::GLOBAL_HF_CACHE;
+            let cache = GLOBAL_HF_CACHE.get().cloned().unwrap_or_default();
+            let mut api = ApiBuilder::from_cache(cache)
+                .with_progress(!$silent)
+                .with_token(get_token($token_source)?);
+            if let Ok(x) = std::env::var("HF_HUB_CACHE") {
+                api = api.with_cache_dir(x.into());
+            }
+            api.build()?
+        };
+        let revision = $revision.unwrap_or("main".to_string());
+        let api = api.repo(Repo::with_revision(
+            $this.model_id.clone(),
+            RepoType::Model,
+            revision,
+        ));
+        let model_id = std::path::Path::new(&$this.model_id);
+        info!("Loading `config.json` at `{}`", $this.model_id);
+        $crate::api_get_file!(api, "config.json", model_id)
+    }};
+}
+
 #[doc(hidden)]
 #[macro_export]
 macro_rules! get_uqff_paths {
@@ -348,9 +404,11 @@ macro_rules! get_paths_gguf {
             this_model_id.clone(),
             $this.xlora_model_id.as_ref(),
             $this.lora_adapter_ids.as_ref(),
+            &$this.lora_adapter_scales,
             &$token_source,
             revision.clone(),
             $this.xlora_order.as_ref(),
+            $this.xlora_classifier_path.as_ref(),
         )?;
 
         let gen_conf = if dir_list.contains(&"generation_config.json".to_string()) {
@@ -438,6 +496,7 @@ macro_rules! normal_model_loader {
         $is_moqe:expr,
         $multi_progress:expr,
         $matformer_config:expr,
+        $share_weights:expr,
     ) => {{
         let regexes = if $loading_isq && $loading_uqff {
             // Dummy weights for the layers which will be overwritten...
@@ -464,6 +523,11 @@ macro_rules! normal_model_loader {
             get_device_for_tensor,
         )?;
 
+        let vb = match $share_weights {
+            Some(share_weights) => share_weights(vb),
+            None => vb,
+        };
+
         $loader.load(
             &$config,
             vb,
@@ -526,6 +590,7 @@ macro_rules! vision_normal_model_loader {
         $attention_mechanism:expr,
         $multi_progress:expr,
         $matformer_config:expr,
+        $share_weights:expr,
     ) => {{
         let regexes = if $loading_isq && $loading_uqff {
             // Dummy weights for the layers which will be overwritten...
@@ -548,6 +613,11 @@ macro_rules! vision_normal_model_loader {
             get_device_for_tensor,
         )?;
 
+        let vb = match $share_weights {
+            Some(share_weights) => share_weights(vb),
+            None => vb,
+        };
+
         $loader.load(
             &$config,
             vb,
@@ -592,6 +662,91 @@ macro_rules! vision_normal_model_loader_sharded {
     }};
 }
 
+#[doc(hidden)]
+#[macro_export]
+macro_rules! vision_lora_model_loader {
+    (
+        $paths:expr,
+        $dtype:expr,
+        $device:expr,
+        $layer_devices:expr,
+        $config:expr,
+        $loader:expr,
+        $silent:expr,
+        $mapper:expr,
+        $loading_isq:expr,
+        $real_device:expr,
+        $attention_mechanism:expr,
+        $multi_progress:expr,
+        $matformer_config:expr,
+        $share_weights:expr,
+    ) => {{
+        let $crate::pipeline::AdapterPaths::Lora(lora_adapter_paths) = $paths.get_adapter_paths()
+        else {
+            unreachable!()
+        };
+
+        let get_device_for_tensor =
+            $loader.get_device_for_tensor(&$config, &*$mapper, $loading_isq)?;
+
+        let vb = from_mmaped_safetensors(
+            $paths.get_weight_filenames().to_vec(),
+            Vec::new(),
+            $dtype,
+            $device,
+            $layer_devices,
+            $silent,
+            None,
+            |_| true,
+            get_device_for_tensor.clone(),
+        )?;
+
+        // The adapters are merged into both the language model and the vision tower, according
+        // to the full module names in their `target_modules`.
+        let _applied_loras = mistralrs_quant::AppliedLorasGuard::new();
+        for $crate::pipeline::LoraAdapterPaths {
+            adapter_path,
+            lora_config,
+        } in lora_adapter_paths
+        {
+            let lora_vb = from_mmaped_safetensors(
+                vec![adapter_path.clone()],
+                Vec::new(),
+                $dtype,
+                $device,
+                $layer_devices,
+                $silent,
+                None,
+                |_| true,
+                get_device_for_tensor.clone(),
+            )?;
+
+            mistralrs_quant::push_applied_lora(mistralrs_quant::LoraAdapter {
+                config: lora_config.clone(),
+                weights: lora_vb,
+            });
+        }
+
+        let vb = match $share_weights {
+            Some(share_weights) => share_weights(vb),
+            None => vb,
+        };
+
+        $loader.load(
+            &$config,
+            vb,
+            $crate::pipeline::NormalLoadingMetadata {
+                mapper: $mapper,
+                loading_isq: $loading_isq,
+                real_device: $real_device,
+                multi_progress: $multi_progress,
+                matformer_slicing_config: $matformer_config,
+            },
+            $attention_mechanism,
+        )?
+    }};
+}
+
 #[doc(hidden)]
 #[macro_export]
 macro_rules! xlora_model_loader {
@@ -679,11 +834,13 @@ macro_rules! lora_model_loader {
         $mapper:expr,
         $loading_isq:expr,
         $loading_uqff:expr,
+        $unmerged_lora:expr,
         $real_device:expr,
         $attention_mechanism:expr,
         $is_moqe:expr,
         $multi_progress:expr,
         $matformer_config:expr,
+        $share_weights:expr,
     ) => {{
         let $crate::pipeline::AdapterPaths::Lora(lora_adapter_paths) = $paths.get_adapter_paths()
         else {
@@ -715,11 +872,17 @@ macro_rules! lora_model_loader {
             get_device_for_tensor.clone(),
         )?;
 
+        let _applied_loras = mistralrs_quant::AppliedLorasGuard::new();
         for $crate::pipeline::LoraAdapterPaths {
             adapter_path,
             lora_config,
         } in lora_adapter_paths
         {
+            // Unmerged adapters are added after loading, DoRA adapters and adapters with
+            // `modules_to_save` are always merged.
+            if $unmerged_lora && !lora_config.needs_merge() {
+                continue;
+            }
             let lora_vb = from_mmaped_safetensors(
                 vec![adapter_path.clone()],
                 Vec::new(),
@@ -738,6 +901,11 @@ macro_rules! lora_model_loader {
             });
         }
 
+        let vb = match $share_weights {
+            Some(share_weights) => share_weights(vb),
+            None => vb,
+        };
+
         $loader.load(
             &$config,
             vb,
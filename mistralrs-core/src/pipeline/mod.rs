@@ -2,8 +2,11 @@ mod amoe;
 mod auto;
 pub mod chat_template;
 mod diffusion;
+mod embedding;
+mod gbnf;
 mod ggml;
 mod gguf;
+pub(crate) mod in_memory;
 mod inputs_processor;
 mod isq;
 pub(crate) mod llg;
@@ -12,10 +15,13 @@ mod macros;
 mod normal;
 mod paths;
 mod processing;
+mod reranker;
 mod response;
 mod sampling;
+mod shared_weights;
 mod speculative;
 mod speech;
+mod transcription;
 mod vision;
 
 pub use super::diffusion_models::DiffusionGenerationParams;
@@ -27,54 +33,69 @@ pub use amoe::{AnyMoeLoader, AnyMoePipeline};
 pub use auto::{AutoLoader, AutoLoaderBuilder};
 use chat_template::ChatTemplate;
 pub use diffusion::{DiffusionLoader, DiffusionLoaderBuilder};
+pub use embedding::{EmbeddingLoader, EmbeddingPipeline};
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
 pub use gguf::{GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig};
 use image::DynamicImage;
+pub use in_memory::InMemoryModelPaths;
 pub use inputs_processor::InputProcessorOutput;
-pub(crate) use isq::IsqModelLoader;
-pub use isq::{parse_isq_value, IsqModel, IsqOrganization, UQFF_MULTI_FILE_DELIMITER};
+pub use isq::{
+    parse_isq_value, CalibrationData, IsqModel, IsqOrganization, MixedPrecisionIsq, NamedIsqLayer,
+    UQFF_MULTI_FILE_DELIMITER,
+};
+pub(crate) use isq::{read_uqff_embedded_files, IsqModelLoader};
 use llguidance::toktrie::TokEnv;
 pub use loaders::{
     AdapterKind, AutoDeviceMapParams, AutoNormalLoader, AutoVisionLoader, DeepSeekV2Loader,
-    DeepSeekV3Loader, DeviceMappedModelLoader, DiffusionLoaderType, DiffusionModel,
-    DiffusionModelLoader, FluxLoader, GLM4Loader, Gemma2Loader, Gemma3Loader, Gemma3nLoader,
-    GemmaLoader, Idefics2Loader, Idefics3Loader, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader,
-    LocalModelPaths, MiniCpmOLoader, Mistral3Loader, MistralLoader, MixtralLoader, ModelKind,
-    ModelPaths, NormalLoaderType, NormalLoadingMetadata, NormalModel, NormalModelLoader,
-    Phi2Loader, Phi3Loader, Phi3VLoader, Phi3_5MoELoader, Phi4MMLoader, PrettyName,
-    QuantizationKind, Qwen2Loader, Qwen2VLLoader, Qwen2_5VLLoader, Qwen3Loader, Qwen3MoELoader,
-    Qwen3VLLoader, SmolLm3Loader, Starcoder2Loader, TokenSource, VLlama4Loader, VLlamaLoader,
-    VisionLoaderType, VisionModel, VisionModelLoader,
+    DeepSeekV3Loader, DeviceMappedModelLoader, DeviceMemoryEstimate, DiffusionLoaderType,
+    DiffusionModel, DiffusionModelLoader, FluxLoader, GLM4Loader, Gemma2Loader, Gemma3Loader,
+    Gemma3nLoader, GemmaLoader, Idefics2Loader, Idefics3Loader, LLaVALoader, LLaVANextLoader,
+    LlamaLoader, Loader, LocalModelPaths, MemoryEstimate, MiniCpmOLoader, Mistral3Loader,
+    MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoaderType, NormalLoadingMetadata,
+    NormalModel, NormalModelLoader, Phi2Loader, Phi3Loader, Phi3VLoader, Phi3_5MoELoader,
+    Phi4MMLoader, PrettyName, QuantizationKind, Qwen2Loader, Qwen2VLLoader, Qwen2_5VLLoader,
+    Qwen3Loader, Qwen3MoELoader, Qwen3VLLoader, SlidingWindowOverride, SmolLm3Loader,
+    Starcoder2Loader, TokenSource, VLlama4Loader, VLlamaLoader, VisionLoaderType, VisionModel,
+    VisionModelLoader,
 };
 use mistralrs_quant::IsqType;
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
-pub(crate) use paths::{get_chat_template, get_model_paths, get_xlora_paths};
+pub(crate) use paths::{
+    get_chat_template, get_lora_adapter_paths, get_model_paths, get_xlora_paths,
+};
 pub use paths::{AdapterPaths, LoraAdapterPaths};
 pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
 use rand_isaac::Isaac64Rng;
+pub use reranker::{RerankerLoader, RerankerPipeline};
+pub use shared_weights::{SharedWeights, SharedWeightsKey};
 pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
 pub use speech::{SpeechLoader, SpeechPipeline};
 use std::any::Any;
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokenizers::Tokenizer;
+pub use transcription::TranscriptionPipeline;
 pub use vision::{VisionLoader, VisionLoaderBuilder, VisionSpecificConfig};
 
 use anyhow::Result;
 use candle_core::{DType, Device, IndexOp, Tensor, Var};
 
 use crate::sequence::Sequence;
+use crate::speech_models::{AudioChunk, TranscriptionSegment};
+use crate::{CalibrationData, ExportFormat, LoraAdapterInfo};
 
 pub use self::inputs_processor::{
     text_models_inputs_processor, InputsProcessor, InputsProcessorType,
 };
 use self::text_models_inputs_processor::PagedAttentionMeta;
 pub use crate::kv_cache::{
-    Cache, CacheManager, EitherCache, KvCache, LayerCaches, NormalCache, NormalCacheType,
+    Cache, CacheManager, EitherCache, H2oCache, H2oConfig, KvCache, LayerCaches, NormalCache,
+    NormalCacheType,
 };
 
 #[derive(Clone, PartialEq, Eq)]
@@ -82,6 +103,7 @@ pub enum SupportedModality {
     Text,
     Audio,
     Vision,
+    Embedding,
 }
 
 impl Debug for SupportedModality {
@@ -90,6 +112,7 @@ impl Debug for SupportedModality {
             Self::Text => write!(f, "📝 Text"),
             Self::Audio => write!(f, "🔊 Audio"),
             Self::Vision => write!(f, "🖼️ Vision"),
+            Self::Embedding => write!(f, "🔢 Embedding"),
         }
     }
 }
@@ -148,6 +171,46 @@ pub trait PreProcessingMixin: MetadataMixin {
 
 pub trait IsqPipelineMixin {
     fn re_isq_model(&mut self, dtype: IsqType) -> Result<()>;
+    /// Collect an imatrix by running the model over the calibration `data`, and reapply ISQ to
+    /// the model with it.
+    fn re_isq_model_calibrated(&mut self, _dtype: IsqType, _data: &CalibrationData) -> Result<()> {
+        anyhow::bail!("This pipeline does not support collecting an imatrix.")
+    }
+    /// Quantize the model to `dtype` from its original weights, which are kept on the CPU the
+    /// first time this is called. Falls back to [`re_isq_model`](IsqPipelineMixin::re_isq_model)
+    /// if the model was already quantized when it was loaded.
+    fn requantize_model(&mut self, _dtype: IsqType) -> Result<()> {
+        anyhow::bail!("This pipeline does not support requantizing the model.")
+    }
+    /// Write the current weights of the model to `path`.
+    fn export_model(&mut self, _path: &Path, _format: ExportFormat) -> Result<()> {
+        anyhow::bail!("This pipeline does not support exporting the model.")
+    }
+}
+
+/// Implemented by pipelines which can apply LoRA adapters to an already loaded model.
+pub trait LoraPipelineMixin {
+    /// Fetch the LoRA adapter `adapter_id` and add it to the model. `scale` overrides the
+    /// `lora_alpha / r` scale of the adapter.
+    fn load_lora_adapter(&mut self, _adapter_id: String, _scale: Option<f64>) -> Result<()> {
+        anyhow::bail!("This pipeline does not support loading LoRA adapters at runtime.")
+    }
+    /// Remove the weights of a loaded LoRA adapter from the model.
+    fn unload_lora_adapter(&mut self, _adapter_id: &str) -> Result<()> {
+        anyhow::bail!("This pipeline does not support unloading LoRA adapters at runtime.")
+    }
+    /// Make the active LoRA adapters part of the base weights and drop all adapters.
+    fn merge_lora_adapters(&mut self) -> Result<()> {
+        anyhow::bail!("This pipeline does not support merging LoRA adapters.")
+    }
+    /// The ids of all loaded LoRA adapters, in load order.
+    fn lora_adapter_ids(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// All loaded LoRA adapters, in load order. Only merged adapters are marked as active.
+    fn lora_adapter_infos(&self) -> Vec<LoraAdapterInfo> {
+        Vec::new()
+    }
 }
 
 pub trait CacheManagerMixin {
@@ -248,6 +311,9 @@ pub enum ModelCategory {
     Diffusion,
     Audio,
     Speech,
+    Embedding,
+    Reranker,
+    Transcription,
 }
 
 impl std::fmt::Debug for ModelCategory {
@@ -258,6 +324,9 @@ impl std::fmt::Debug for ModelCategory {
             ModelCategory::Diffusion => write!(f, "ModelCategory::Diffusion"),
             ModelCategory::Audio => write!(f, "ModelCategory::Audio"),
             ModelCategory::Speech => write!(f, "ModelCategory::Speech"),
+            ModelCategory::Embedding => write!(f, "ModelCategory::Embedding"),
+            ModelCategory::Reranker => write!(f, "ModelCategory::Reranker"),
+            ModelCategory::Transcription => write!(f, "ModelCategory::Transcription"),
         }
     }
 }
@@ -270,8 +339,18 @@ impl PartialEq for ModelCategory {
             (Self::Audio, Self::Audio) => true,
             (Self::Speech, Self::Speech) => true,
             (Self::Diffusion, Self::Diffusion) => true,
+            (Self::Embedding, Self::Embedding) => true,
+            (Self::Reranker, Self::Reranker) => true,
+            (Self::Transcription, Self::Transcription) => true,
             (
-                Self::Text | Self::Vision { .. } | Self::Diffusion | Self::Audio | Self::Speech,
+                Self::Text
+                | Self::Vision { .. }
+                | Self::Diffusion
+                | Self::Audio
+                | Self::Speech
+                | Self::Embedding
+                | Self::Reranker
+                | Self::Transcription,
                 _,
             ) => false,
         }
@@ -368,6 +447,7 @@ pub trait Pipeline:
     + Sync
     + PreProcessingMixin
     + IsqPipelineMixin
+    + LoraPipelineMixin
     + CacheManagerMixin
     + MetadataMixin
     + AnyMoePipelineMixin
@@ -378,6 +458,15 @@ pub trait Pipeline:
         return_raw_logits: bool,
     ) -> Result<ForwardInputsResult, candle_core::Error>;
 
+    /// Run the model on `inputs` and return the last hidden states of all of their tokens,
+    /// `(batch, seq_len, hidden_size)`, instead of the logits. Only some text models support this.
+    fn forward_inputs_hidden_states(
+        &mut self,
+        _inputs: Box<dyn Any>,
+    ) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!("This model does not return its hidden states.")
+    }
+
     /// Returns the total of model execution time.
     #[allow(clippy::too_many_arguments)]
     async fn step(
@@ -390,6 +479,13 @@ pub trait Pipeline:
         rng: Arc<std::sync::Mutex<Isaac64Rng>>,
         backend_metadata: CacheBackendMetadata,
     ) -> Result<Duration, candle_core::Error> {
+        mistralrs_quant::set_lora_batch(
+            input_seqs
+                .iter()
+                .map(|seq| seq.adapters().map(|a| a.to_vec()))
+                .collect(),
+        );
+
         match backend_metadata {
             CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
                 let inputs_iter =
@@ -497,26 +593,63 @@ pub trait Pipeline:
                 match &logits[0] {
                     ForwardInputsResult::RawLogits { .. } => unreachable!(),
                     ForwardInputsResult::CausalGeneration { .. } => {
-                        self.sample_causal_gen(
-                            input_seqs,
-                            logits
-                                .into_iter()
-                                .map(|r| {
-                                    #[allow(irrefutable_let_patterns)]
-                                    let ForwardInputsResult::CausalGeneration { logits } = r
-                                    else {
-                                        unreachable!(
-                                            "All results must have same type, `CausalGeneration`"
-                                        )
-                                    };
-                                    logits
-                                })
-                                .collect::<Vec<_>>(),
-                            prefix_cacher,
-                            disable_eos_stop,
-                            rng,
-                        )
-                        .await?;
+                        // The prompts whose logprobs were requested run once more with the model
+                        // cache, which then gets the caches of the batch back
+                        let mut ran_prompts = false;
+                        for seq in input_seqs.iter_mut() {
+                            if seq.is_partial_prefill() {
+                                continue;
+                            }
+                            if let Some(mut prompt) = seq.take_prompt_logprobs_seq() {
+                                let logprobs = sampling::prompt_logprobs(self, &mut prompt)?;
+                                seq.set_prompt_logprobs(logprobs);
+                                ran_prompts = true;
+                            }
+                        }
+                        if ran_prompts {
+                            self.clone_in_cache(input_seqs);
+                        }
+
+                        // The sequences partway through a chunked prefill have no token to sample
+                        let (mut seqs, mut logits): (Vec<_>, Vec<_>) = input_seqs
+                            .iter_mut()
+                            .zip(logits)
+                            .filter(|(seq, _)| !seq.is_partial_prefill())
+                            .map(|(seq, r)| {
+                                #[allow(irrefutable_let_patterns)]
+                                let ForwardInputsResult::CausalGeneration { logits } = r
+                                else {
+                                    unreachable!(
+                                        "All results must have same type, `CausalGeneration`"
+                                    )
+                                };
+                                (&mut **seq, logits)
+                            })
+                            .unzip();
+                        // The caches of the batch were cloned out, so the negative prompts and
+                        // the candidates of contrastive search can use the model cache
+                        for (seq, logits) in seqs.iter_mut().zip(logits.iter_mut()) {
+                            if let Some(mut guidance) = seq.take_guidance() {
+                                let guided = guidance.apply(self, logits);
+                                seq.set_guidance(guidance);
+                                *logits = guided?;
+                            }
+                            if let Some(mut contrastive) = seq.take_contrastive() {
+                                let chosen = contrastive.apply(self, logits);
+                                seq.set_contrastive(contrastive);
+                                *logits = chosen?;
+                            }
+                        }
+                        if !seqs.is_empty() {
+                            self.sample_causal_gen(
+                                &mut seqs,
+                                logits,
+                                prefix_cacher,
+                                disable_eos_stop,
+                                rng,
+                            )
+                            .await?;
+                        }
                     }
                     ForwardInputsResult::Image { .. } => {
                         response::send_image_responses(
@@ -775,6 +908,38 @@ pub trait Pipeline:
     ) -> Result<(), candle_core::Error>;
 
     fn category(&self) -> ModelCategory;
+
+    /// Compute one embedding vector for each of `texts`. Only embedding models support this.
+    fn embed(&mut self, _texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        anyhow::bail!("This model does not support computing embeddings.")
+    }
+
+    /// Score the relevance of each of `documents` to `query`. Only reranker models support this.
+    fn rerank(&mut self, _query: &str, _documents: &[String]) -> Result<Vec<f32>> {
+        anyhow::bail!("This model does not support reranking.")
+    }
+
+    /// Transcribe mono `samples` at `sample_rate`, calling `on_segment` with each segment as soon
+    /// as it is decoded. Only transcription models support this.
+    fn transcribe(
+        &mut self,
+        _samples: &[f32],
+        _sample_rate: u32,
+        _language: Option<&str>,
+        _on_segment: &mut dyn FnMut(TranscriptionSegment) -> Result<()>,
+    ) -> Result<()> {
+        anyhow::bail!("This model does not support transcription.")
+    }
+
+    /// Speak `text`, calling `on_chunk` with each chunk of audio as soon as it is generated. Only
+    /// text-to-speech models support this.
+    fn synthesize(
+        &mut self,
+        _text: &str,
+        _on_chunk: &mut dyn FnMut(AudioChunk) -> Result<()>,
+    ) -> Result<()> {
+        anyhow::bail!("This model does not support speech synthesis.")
+    }
 }
 
 pub(crate) fn extract_logits(
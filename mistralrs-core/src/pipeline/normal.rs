@@ -1,13 +1,14 @@
-use super::isq::ImatrixDataSource;
+use super::isq::{calibration_tokens, CalibrationData, ImatrixDataSource, MixedPrecisionIsq};
 use super::llg::build_llg_factory;
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
-    TokenSource,
+    get_lora_adapter_paths, get_model_paths, get_xlora_paths,
+    text_models_inputs_processor::ModelInputs, AdapterKind, AdapterPaths, AutoDeviceMapParams,
+    CacheManager, GeneralMetadata, Loader, LoraAdapterPaths, MemoryEstimate, ModelKind, ModelPaths,
+    NormalModel, NormalModelLoader, TokenSource,
 };
 use super::{
     AnyMoePipelineMixin, CacheManagerMixin, EitherCache, ForwardInputsResult, IsqOrganization,
-    IsqPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
+    IsqPipelineMixin, LoraPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
 };
 use super::{
     AutoNormalLoader, DeepSeekV2Loader, DeepSeekV3Loader, GLM4Loader, Gemma2Loader, GemmaLoader,
@@ -22,10 +23,15 @@ use crate::kv_cache::{FullCacheManager, NormalCacheManager};
 use crate::lora::Ordering;
 use crate::paged_attention::{calculate_cache_config, AttentionImplementation, CacheEngine};
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
-use crate::pipeline::isq::UqffFullSer;
+use crate::pipeline::in_memory::{read_model_file_to_string, InMemoryFiles};
+use crate::pipeline::isq::{read_uqff_embedded_files, UqffFullSer};
 use crate::pipeline::loaders::auto_device_map;
-use crate::pipeline::loaders::QuantizationConfigShim;
+use crate::pipeline::loaders::{
+    apply_sliding_window_override, normalize_quantization_config, QuantizationConfigShim,
+    SlidingWindowOverride,
+};
 use crate::pipeline::sampling::sample_and_add_toks;
+use crate::pipeline::shared_weights::{SharedWeights, SharedWeightsKey};
 use crate::pipeline::text_models_inputs_processor::make_prompt_chunk;
 use crate::pipeline::{get_chat_template, Modalities, SupportedModality};
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
@@ -36,26 +42,28 @@ use crate::utils::varbuilder_utils::DeviceForLoadTensor;
 use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
 use crate::xlora_models::NonGranularState;
 use crate::{
-    api_dir_list, api_get_file, get_mut_arcmutex, get_paths, get_uqff_paths, lora_model_loader,
-    normal_model_loader, normal_model_loader_sharded, xlora_model_loader, DeviceMapSetting,
-    PagedAttentionConfig, Pipeline, Topology, TryIntoDType, GLOBAL_HF_CACHE,
+    api_dir_list, api_get_file, get_config_path, get_mut_arcmutex, get_paths, get_uqff_paths,
+    lora_model_loader, normal_model_loader, normal_model_loader_sharded, xlora_model_loader,
+    DeviceMapSetting, ExportFormat, LoraAdapterInfo, PagedAttentionConfig, Pipeline, Topology,
+    TryIntoDType, GLOBAL_HF_CACHE,
 };
-use anyhow::Result;
-use candle_core::{Device, Tensor, Var};
+use anyhow::{Context, Result};
+use candle_core::{DType, Device, Tensor, Var};
 use hf_hub::Cache;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use indexmap::IndexMap;
 use indicatif::MultiProgress;
 use mistralrs_quant::log::once_log_info;
-use mistralrs_quant::{AfqLayer, GgufMatMul, HqqLayer, IsqType, QuantizedSerdeType};
+use mistralrs_quant::{BatchedLoraLinear, IsqType, LoraAdapter, QuantMethod};
 use rand_isaac::Isaac64Rng;
 use regex_automata::meta::Regex;
 use std::any::Any;
-use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use std::time::Instant;
-use std::{env, fs};
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
 use tracing::{info, warn};
@@ -77,6 +85,13 @@ pub struct NormalPipeline {
     config: String,
     imatrix: Option<PathBuf>,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
+    // For runtime LoRA adapters
+    lora_adapters: IndexMap<String, LoraAdapterPaths>,
+    merged_lora_adapters: Vec<String>,
+    token_source: TokenSource,
+    revision: Option<String>,
+    // The unquantized layers, kept on the CPU for requantizing
+    original_layers: Option<Vec<Arc<dyn QuantMethod>>>,
 }
 
 /// A loader for a "normal" (non-quantized) model.
@@ -86,8 +101,10 @@ pub struct NormalLoader {
     config: NormalSpecificConfig,
     xlora_model_id: Option<String>,
     lora_adapter_ids: Option<Vec<String>>,
+    lora_adapter_scales: HashMap<String, f64>,
     kind: ModelKind,
     xlora_order: Option<Ordering>,
+    xlora_classifier_path: Option<PathBuf>,
     no_kv_cache: bool,
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
@@ -97,6 +114,7 @@ pub struct NormalLoader {
     from_uqff: RwLock<Option<Vec<PathBuf>>>,
     jinja_explicit: Option<String>,
     hf_cache_path: Option<PathBuf>,
+    shared_weights: Option<SharedWeights>,
 }
 
 #[derive(Default)]
@@ -106,14 +124,17 @@ pub struct NormalLoaderBuilder {
     config: NormalSpecificConfig,
     xlora_model_id: Option<String>,
     lora_adapter_ids: Option<Vec<String>>,
+    lora_adapter_scales: HashMap<String, f64>,
     kind: ModelKind,
     xlora_order: Option<Ordering>,
+    xlora_classifier_path: Option<PathBuf>,
     no_kv_cache: bool,
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
     jinja_explicit: Option<String>,
     hf_cache_path: Option<PathBuf>,
+    shared_weights: Option<SharedWeights>,
 }
 
 #[derive(Clone, Default)]
@@ -125,9 +146,18 @@ pub struct NormalSpecificConfig {
     pub from_uqff: Option<Vec<PathBuf>>,
     pub imatrix: Option<PathBuf>,
     pub calibration_file: Option<PathBuf>,
+    /// Calibration data given in memory, used instead of a calibration file.
+    pub calibration_data: Option<CalibrationData>,
+    /// Quantize the most sensitive layers to a higher precision type within a memory budget.
+    pub mixed_precision_isq: Option<MixedPrecisionIsq>,
     pub hf_cache_path: Option<PathBuf>,
     pub matformer_config_path: Option<PathBuf>,
     pub matformer_slice_name: Option<String>,
+    /// Regexes of the tensor names (such as `lm_head.weight`) which are kept at full precision
+    /// when applying ISQ.
+    pub isq_skip: Vec<String>,
+    /// Override the sliding window attention of the model config.
+    pub sliding_window: Option<SlidingWindowOverride>,
 }
 
 impl NormalLoaderBuilder {
@@ -192,6 +222,13 @@ impl NormalLoaderBuilder {
         )
     }
 
+    /// Load the X-LoRA classifier from this file instead of the `xlora_classifier.safetensors` of
+    /// the X-LoRA model.
+    pub fn with_xlora_classifier_path(mut self, xlora_classifier_path: PathBuf) -> Self {
+        self.xlora_classifier_path = Some(xlora_classifier_path);
+        self
+    }
+
     pub fn with_lora(mut self, lora_adapter_ids: Vec<String>) -> Self {
         self.kind = ModelKind::Adapter {
             adapter: AdapterKind::Lora,
@@ -200,11 +237,24 @@ impl NormalLoaderBuilder {
         self
     }
 
+    /// Override the `lora_alpha / r` scale of some of the LoRA adapters, keyed by adapter ID.
+    pub fn with_lora_scales(mut self, lora_adapter_scales: HashMap<String, f64>) -> Self {
+        self.lora_adapter_scales = lora_adapter_scales;
+        self
+    }
+
     pub fn hf_cache_path(mut self, hf_cache_path: PathBuf) -> Self {
         self.hf_cache_path = Some(hf_cache_path);
         self
     }
 
+    /// Reuse the weights of other pipelines of the same model, revision and dtype loaded with
+    /// this cache instead of loading them again.
+    pub fn with_shared_weights(mut self, shared_weights: SharedWeights) -> Self {
+        self.shared_weights = Some(shared_weights);
+        self
+    }
+
     /// If the loader type is not specified, loader type is automatically determined from the
     /// `architectures` array in the config.
     pub fn build(self, loader_tp: Option<NormalLoaderType>) -> anyhow::Result<Box<dyn Loader>> {
@@ -233,8 +283,10 @@ impl NormalLoaderBuilder {
             config: self.config,
             xlora_model_id: self.xlora_model_id,
             lora_adapter_ids: self.lora_adapter_ids,
+            lora_adapter_scales: self.lora_adapter_scales,
             kind: self.kind,
             xlora_order: self.xlora_order,
+            xlora_classifier_path: self.xlora_classifier_path,
             no_kv_cache: self.no_kv_cache,
             chat_template: self.chat_template,
             tokenizer_json: self.tokenizer_json,
@@ -244,6 +296,7 @@ impl NormalLoaderBuilder {
             revision: RwLock::new(None),
             from_uqff: RwLock::new(None),
             hf_cache_path: self.hf_cache_path,
+            shared_weights: self.shared_weights,
         }))
     }
 }
@@ -268,6 +321,14 @@ impl Loader for NormalLoader {
             .unwrap_or_default();
         GLOBAL_HF_CACHE.get_or_init(|| cache);
 
+        let uqff_files = if let Some(from_uqff) = self.config.from_uqff.clone() {
+            let from_uqff = get_uqff_paths!(&from_uqff, self, silent);
+            let embedded = InMemoryFiles::register(read_uqff_embedded_files(&from_uqff)?);
+            *self.from_uqff.write().unwrap() = Some(from_uqff);
+            Some(embedded)
+        } else {
+            None
+        };
         let paths: anyhow::Result<Box<dyn ModelPaths>> = get_paths!(
             LocalModelPaths,
             &token_source,
@@ -276,11 +337,9 @@ impl Loader for NormalLoader {
             None,
             None,
             silent,
-            self.config.from_uqff.is_some()
+            self.config.from_uqff.is_some(),
+            uqff_files.as_ref()
         );
-        if let Some(from_uqff) = self.config.from_uqff.clone() {
-            *self.from_uqff.write().unwrap() = Some(get_uqff_paths!(&from_uqff, self, silent));
-        }
         *self
             .token_source
             .write()
@@ -308,7 +367,9 @@ impl Loader for NormalLoader {
         mut in_situ_quant: Option<IsqType>,
         mut paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
-        let config = std::fs::read_to_string(paths.get_config_filename())?;
+        let config =
+            normalize_quantization_config(read_model_file_to_string(paths.get_config_filename())?)?;
+        let config = apply_sliding_window_override(config, self.config.sliding_window)?;
 
         if !self.inner.supports_paged_attention(&config)? {
             paged_attn_config = None;
@@ -355,102 +416,20 @@ impl Loader for NormalLoader {
 
             // ISQ or UQFF: quantized path
             // Match logic below where UQFF has priority
+            let weight_pack_factor = auto_device_map::weight_pack_factor(
+                &config,
+                dtype,
+                in_situ_quant,
+                self.from_uqff.read().unwrap().as_deref(),
+            )?;
             let (layer_sizes_in_bytes, non_mapped_size_in_bytes, total_model_size_in_bytes) =
-                if let Some(serialized) = &*self.from_uqff.read().unwrap() {
-                    let weight_pack_factor = {
-                        let ser_artifacts = unsafe {
-                            candle_core::safetensors::MmapedSafetensors::multi(serialized)?
-                        };
-                        let mut total_pack_factors = 0;
-                        let total_tensors = ser_artifacts.tensors().len();
-                        for (_, artifact) in ser_artifacts.tensors() {
-                            let artifact = artifact.data();
-                            // NOTE(EricLBuehler): isq type is ALWAYS byte 4 (5th) of the tensor.
-                            let isq_type = artifact[mistralrs_quant::UQFF_QUANT_TYPE_OFFSET];
-                            let pack_factor = match QuantizedSerdeType::try_from(isq_type as usize)?
-                            {
-                                QuantizedSerdeType::Hqq => {
-                                    HqqLayer::get_isq_type_from_uqff(Cow::Borrowed(artifact))?
-                                        .pack_factor(dtype)
-                                }
-                                QuantizedSerdeType::Gguf => {
-                                    GgufMatMul::get_isq_type_from_uqff(Cow::Borrowed(artifact))?
-                                        .pack_factor(dtype)
-                                }
-                                QuantizedSerdeType::Fp8 => IsqType::F8E4M3.pack_factor(dtype),
-                                QuantizedSerdeType::Unquant => 1,
-                                QuantizedSerdeType::Afq => {
-                                    AfqLayer::get_isq_type_from_uqff(Cow::Borrowed(artifact))?
-                                        .pack_factor(dtype)
-                                }
-                            };
-                            total_pack_factors += pack_factor;
-                        }
-
-                        total_pack_factors / total_tensors
-                    };
-
-                    let layer_sizes_in_bytes = self.inner.layer_sizes_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        None,
-                    )?;
-                    let non_mapped_size_in_bytes = self.inner.non_mapped_size_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        None,
-                    )?;
-                    let layer_sizes_sum = layer_sizes_in_bytes.iter().sum::<usize>();
-                    (
-                        layer_sizes_in_bytes,
-                        non_mapped_size_in_bytes,
-                        layer_sizes_sum + non_mapped_size_in_bytes,
-                    )
-                } else if let Some(isq) = in_situ_quant {
-                    let weight_pack_factor = isq.pack_factor(dtype);
-                    let layer_sizes_in_bytes = self.inner.layer_sizes_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        None,
-                    )?;
-                    let non_mapped_size_in_bytes = self.inner.non_mapped_size_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        None,
-                    )?;
-                    let layer_sizes_sum = layer_sizes_in_bytes.iter().sum::<usize>();
-                    (
-                        layer_sizes_in_bytes,
-                        non_mapped_size_in_bytes,
-                        layer_sizes_sum + non_mapped_size_in_bytes,
-                    )
-                } else {
-                    // Be sure to get the weight pack factor here; we might be loading a prequantized model.
-                    let weight_pack_factor =
-                        QuantizationConfigShim::get_quant_config_pack_factor(&config, dtype)?;
-                    let layer_sizes_in_bytes = self.inner.layer_sizes_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        None,
-                    )?;
-                    let non_mapped_size_in_bytes = self.inner.non_mapped_size_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        None,
-                    )?;
-                    let layer_sizes_sum = layer_sizes_in_bytes.iter().sum::<usize>();
-                    (
-                        layer_sizes_in_bytes,
-                        non_mapped_size_in_bytes,
-                        layer_sizes_sum + non_mapped_size_in_bytes,
-                    )
-                };
+                auto_device_map::model_sizes_in_bytes(
+                    &*self.inner,
+                    &config,
+                    dtype,
+                    weight_pack_factor,
+                    None,
+                )?;
 
             let new = auto_device_map::get_device_layers(
                 &*self.inner,
@@ -497,10 +476,25 @@ impl Loader for NormalLoader {
             once_log_info("FlashAttention is enabled.");
         }
 
+        // Layer names are only known while loading, so skipping layers requires immediate ISQ.
+        let calibrating =
+            self.config.calibration_file.is_some() || self.config.calibration_data.is_some();
+        let isq_skip = self
+            .config
+            .isq_skip
+            .iter()
+            .map(|skip| regex::Regex::new(skip))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if !isq_skip.is_empty()
+            && (self.config.imatrix.is_some() || calibrating || self.config.write_uqff.is_some())
+        {
+            anyhow::bail!("ISQ skip patterns cannot be used with an imatrix, a calibration file or when writing UQFF.");
+        }
+
         // Logic for ISQ here: if no calibration (i.e imatrix), then allow immediate ISQ. Otherwise, back to normal.
         let mut loading_isq = if self.config.imatrix.is_none()
-            && self.config.calibration_file.is_none()
-            && !device.is_cuda()
+            && !calibrating
+            && (!device.is_cuda() || !isq_skip.is_empty())
             && self.config.write_uqff.is_none()
             && in_situ_quant.is_some()
         {
@@ -514,7 +508,7 @@ impl Loader for NormalLoader {
             if predicates.is_empty() {
                 warn!("No predicates for this model and ISQ setting detected. ISQ will not be applied to any weights!");
             }
-            mistralrs_quant::set_immediate_isq(in_situ_quant, predicates);
+            mistralrs_quant::set_immediate_isq(in_situ_quant, predicates, isq_skip);
             false
         } else {
             in_situ_quant.is_some()
@@ -527,14 +521,31 @@ impl Loader for NormalLoader {
                 .any(|layer| layer.as_ref().is_some_and(|layer| layer.isq.is_some()));
         }
 
-        if self.config.imatrix.is_some() && self.config.calibration_file.is_some() {
+        if self.config.imatrix.is_some() && calibrating {
             anyhow::bail!(
-                "`imatrix` and `calibration_file` were both specified, this is not allowed."
+                "`imatrix` and a calibration file or calibration data were both specified, this is not allowed."
+            );
+        }
+        if self.config.calibration_file.is_some() && self.config.calibration_data.is_some() {
+            anyhow::bail!(
+                "`calibration_file` and `calibration_data` were both specified, this is not allowed."
+            );
+        }
+        if self.config.mixed_precision_isq.is_some()
+            && !calibrating
+            && self.config.imatrix.is_none()
+        {
+            anyhow::bail!(
+                "Mixed precision ISQ requires a calibration file, calibration data or an imatrix."
             );
         }
 
+        // LoRA adapters stay unmerged in the activation dtype on top of quantized base weights.
+        let unmerged_lora = !use_nccl
+            && (loading_isq || in_situ_quant.is_some() || self.config.from_uqff.is_some());
+
         // Load onto the regular device if not using isq or if the calibration file is specified
-        let load_device = if !loading_isq || self.config.calibration_file.is_some() {
+        let load_device = if !loading_isq || calibrating {
             loading_isq = false;
             device.clone()
         } else {
@@ -572,6 +583,26 @@ impl Loader for NormalLoader {
             None
         };
 
+        let share_weights = match &self.shared_weights {
+            Some(shared_weights) => {
+                // Layers quantized with ISQ get new weights, so they are not shared
+                let exclude = if !loading_isq && in_situ_quant.is_none() {
+                    None
+                } else if matches!(self.config.organization, IsqOrganization::MoeExpertsOnly) {
+                    Some(self.inner.isq_layer_regexes_moqe(&config)?)
+                } else {
+                    Some(self.inner.isq_layer_regexes(&config)?)
+                };
+                let key = SharedWeightsKey {
+                    model_id: self.model_id.clone(),
+                    revision: self.revision.read().unwrap().clone(),
+                    dtype,
+                };
+                Some(move |vb| shared_weights.wrap(key, vb, exclude))
+            }
+            None => None,
+        };
+
         let mut model = if use_nccl || cfg!(feature = "ring") {
             let (mapper, sharded_vb) = distributed::prepare_distributed_mapper(
                 dtype,
@@ -628,11 +659,13 @@ impl Loader for NormalLoader {
                     mapper,
                     loading_isq,
                     self.config.from_uqff.is_some(),
+                    unmerged_lora,
                     device.clone(),
                     attention_mechanism,
                     matches!(self.config.organization, IsqOrganization::MoeExpertsOnly),
                     multi_progress.clone(),
                     matformer_slicing_config.clone(),
+                    share_weights,
                 ),
                 _ => unreachable!(),
             }
@@ -654,6 +687,7 @@ impl Loader for NormalLoader {
                     matches!(self.config.organization, IsqOrganization::MoeExpertsOnly),
                     multi_progress.clone(),
                     matformer_slicing_config.clone(),
+                    share_weights,
                 ),
                 ModelKind::Adapter {
                     adapter: AdapterKind::XLora,
@@ -684,11 +718,13 @@ impl Loader for NormalLoader {
                     mapper,
                     loading_isq,
                     self.config.from_uqff.is_some(),
+                    unmerged_lora,
                     device.clone(),
                     attention_mechanism,
                     matches!(self.config.organization, IsqOrganization::MoeExpertsOnly),
                     multi_progress.clone(),
                     matformer_slicing_config.clone(),
+                    share_weights,
                 ),
                 _ => unreachable!(),
             }
@@ -696,7 +732,7 @@ impl Loader for NormalLoader {
 
         let tokenizer = get_tokenizer(paths.get_tokenizer_filename(), None)?;
         let gen_conf: Option<GenerationConfig> = paths.get_gen_conf_filename().and_then(|f| {
-            match serde_json::from_str::<GenerationConfig>(&fs::read_to_string(f).unwrap()) {
+            match serde_json::from_str::<GenerationConfig>(&read_model_file_to_string(f).unwrap()) {
                 Ok(conf) => Some(conf),
                 Err(e) => {
                     warn!("Failed to parse generation_config.json: {}", e);
@@ -717,17 +753,13 @@ impl Loader for NormalLoader {
             None,
         );
 
-        if let Some(calibration_file) = &self.config.calibration_file {
-            let calibration_data = std::fs::read_to_string(calibration_file)?;
-            // Tokenize, don't add bos yet
-            let tokens = tokenizer
-                .encode_fast(calibration_data, false)
-                .map_err(anyhow::Error::msg)?
-                .get_ids()
-                .to_vec();
+        if let Some((tokens, source)) = calibration_tokens(
+            self.config.calibration_file.as_ref(),
+            self.config.calibration_data.as_ref(),
+            &tokenizer,
+        )? {
             info!(
-                "Collecting imatrix from calibration file `{}` of {} tokens.",
-                calibration_file.display(),
+                "Collecting imatrix from {source} of {} tokens.",
                 tokens.len()
             );
             let bos_toks = chat_template.bos_tok().map(|b| vec![b]).unwrap_or_default();
@@ -739,69 +771,18 @@ impl Loader for NormalLoader {
                 IsqOrganization::Default => model.begin_track_stats()?,
                 IsqOrganization::MoeExpertsOnly => model.begin_track_stats_moe_experts_only()?,
             }
-
-            const CHUNK_SIZE: usize = 1024;
-            let n_chunks = tokens.len().div_ceil(CHUNK_SIZE);
-            let start = Instant::now();
-            for (i, chunk) in tokens.chunks(CHUNK_SIZE).enumerate() {
-                let chunk = [vec![bos_tok_id], chunk.to_vec()].concat();
-                let chunk_len = chunk.len();
-
-                let start = Instant::now();
-                let inputs = make_prompt_chunk(
-                    0,
-                    vec![&chunk],
-                    &[0],
-                    &load_device,
-                    None,
-                    false,
-                    None,
-                    Some(pipeline_mapper.as_ref()),
-                )?;
-
-                model.forward(
-                    &inputs.input.to_device(model.device())?,
-                    &inputs.positions,
-                    inputs.context_lens.clone(),
-                    inputs.position_ids.clone(),
-                    None,
-                    &inputs.flash_meta.clone(),
-                )?;
-
-                match model.cache_mut() {
-                    EitherCache::Full(full) => {
-                        for layer in &mut *full.lock() {
-                            *layer = None
-                        }
-                    }
-                    EitherCache::Normal(normal) => {
-                        for layer in &mut *normal.lock().unwrap().0 {
-                            layer.reset();
-                        }
-                    }
-                }
-
-                let end = Instant::now();
-                info!(
-                    "Processed chunk {}/{n_chunks} ({chunk_len} tokens), {:.2}s",
-                    i + 1,
-                    end.duration_since(start).as_secs_f32()
-                );
-            }
-            load_device.synchronize()?;
-            let end = Instant::now();
-            info!(
-                "Finished collecting imatrix in {:.2}s",
-                end.duration_since(start).as_secs_f32()
-            );
+            run_calibration(
+                &mut *model,
+                &tokens,
+                bos_tok_id,
+                &load_device,
+                pipeline_mapper.as_ref(),
+            )?;
         }
 
         // Only if loading from UQFF
         if (loading_isq || self.config.topology.is_some()) && self.config.from_uqff.is_none() {
-            let imatrix_source = match (
-                self.config.imatrix.as_ref(),
-                self.config.calibration_file.is_some(),
-            ) {
+            let imatrix_source = match (self.config.imatrix.as_ref(), calibrating) {
                 (None, false) => None,
                 (Some(file), false) => Some(ImatrixDataSource::File(file)),
                 (None, true) => Some(ImatrixDataSource::Collected),
@@ -818,6 +799,7 @@ impl Loader for NormalLoader {
                 self.config.topology.as_ref(),
                 silent,
                 imatrix_source,
+                self.config.mixed_precision_isq,
                 self.config.organization,
                 self.config.write_uqff.as_ref(),
                 UqffFullSer {
@@ -856,6 +838,9 @@ impl Loader for NormalLoader {
         let (cache_config, cache_engine) = if let Some(paged_attn_config) = paged_attn_config {
             let cache_config = calculate_cache_config(
                 paged_attn_config.mem_gpu,
+                paged_attn_config.swap_space,
+                paged_attn_config.host_offload_bytes,
+                paged_attn_config.watermark,
                 paged_attn_config.block_size,
                 dtype,
                 paged_attn_config.cache_type,
@@ -897,7 +882,23 @@ impl Loader for NormalLoader {
         let sliding_window = model.config().sliding_window;
         let model_metadata = Arc::new(model.config().clone());
 
-        Ok(Arc::new(Mutex::new(NormalPipeline {
+        let lora_adapters = match (&self.lora_adapter_ids, paths.get_adapter_paths()) {
+            (Some(adapter_ids), AdapterPaths::Lora(lora_adapter_paths)) => adapter_ids
+                .iter()
+                .cloned()
+                .zip(lora_adapter_paths.iter().cloned())
+                .collect(),
+            _ => IndexMap::new(),
+        };
+        // Adapters given when loading the model are merged into the weights, unless they are applied
+        // to quantized weights.
+        let merged_lora_adapters = lora_adapters
+            .iter()
+            .filter(|(_, paths)| !unmerged_lora || paths.lora_config.needs_merge())
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        let mut pipeline = NormalPipeline {
             model,
             tokenizer: tokenizer.into(),
             no_kv_cache: self.no_kv_cache,
@@ -936,7 +937,86 @@ impl Loader for NormalLoader {
             config,
             imatrix: self.config.imatrix.clone(),
             mapper: pipeline_mapper,
-        })))
+            lora_adapters,
+            merged_lora_adapters,
+            token_source: self
+                .token_source
+                .read()
+                .expect("Failed to read token source")
+                .clone()
+                .unwrap_or(TokenSource::CacheToken),
+            revision: self
+                .revision
+                .read()
+                .expect("Failed to read revision")
+                .clone(),
+            original_layers: None,
+        };
+        for (adapter_id, paths) in pipeline.lora_adapters.clone() {
+            if !pipeline.merged_lora_adapters.contains(&adapter_id) {
+                let adapter = pipeline.load_lora_weights(&paths)?;
+                pipeline.add_batched_lora_adapter(&adapter_id, &adapter)?;
+            }
+        }
+
+        Ok(Arc::new(Mutex::new(pipeline)))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn estimate_memory_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<MemoryEstimate> {
+        let cache = self
+            .hf_cache_path
+            .clone()
+            .map(Cache::new)
+            .unwrap_or_default();
+        GLOBAL_HF_CACHE.get_or_init(|| cache);
+
+        let config_filename = get_config_path!(&token_source, revision.clone(), self, silent);
+        *self
+            .token_source
+            .write()
+            .expect("Failed to write to token source") = Some(token_source);
+        *self.revision.write().expect("Failed to write to revision") = revision;
+        let from_uqff = match &self.config.from_uqff {
+            Some(from_uqff) => Some(get_uqff_paths!(from_uqff, self, silent)),
+            None => None,
+        };
+
+        let config = normalize_quantization_config(std::fs::read_to_string(config_filename)?)?;
+        let paged_attn_config = if self.inner.supports_paged_attention(&config)? {
+            paged_attn_config
+        } else {
+            None
+        };
+
+        // Activations and the KV cache of a manual device map are sized by the default params.
+        let params = match &mapper {
+            DeviceMapSetting::Auto(params) => params.clone(),
+            _ => AutoDeviceMapParams::default_text(),
+        };
+        auto_device_map::estimate_memory(
+            &*self.inner,
+            &config,
+            dtype,
+            device,
+            mapper,
+            in_situ_quant,
+            paged_attn_config.as_ref(),
+            from_uqff.as_deref(),
+            None,
+            self.config.topology.as_ref(),
+            params,
+        )
     }
 
     fn get_id(&self) -> String {
@@ -967,6 +1047,7 @@ impl IsqPipelineMixin for NormalPipeline {
             self.topology.as_ref(),
             self.silent,
             self.imatrix.as_ref().map(ImatrixDataSource::File),
+            None,
             self.organization,
             None,
             UqffFullSer {
@@ -981,6 +1062,338 @@ impl IsqPipelineMixin for NormalPipeline {
         )?;
         Ok(())
     }
+
+    fn requantize_model(&mut self, dtype: IsqType) -> Result<()> {
+        if self.original_layers.is_none() {
+            self.original_layers = self.model.offload_unquantized_layers(self.organization)?;
+        }
+        let Some(originals) = &self.original_layers else {
+            warn!("The original weights of the model are not available because it was quantized when loaded, requantizing the quantized weights instead.");
+            return self.re_isq_model(dtype);
+        };
+        self.model.restore_layers(self.organization, originals)?;
+        self.re_isq_model(dtype)
+    }
+
+    fn re_isq_model_calibrated(&mut self, dtype: IsqType, data: &CalibrationData) -> Result<()> {
+        if mistralrs_quant::distributed::use_nccl() {
+            anyhow::bail!("Collecting an imatrix is not supported with tensor parallelism.");
+        }
+        let Some((tokens, source)) = calibration_tokens(None, Some(data), &self.tokenizer)? else {
+            unreachable!("calibration data was given");
+        };
+        info!(
+            "Collecting imatrix from {source} of {} tokens.",
+            tokens.len()
+        );
+        let bos_tok_id = self
+            .chat_template
+            .bos_tok()
+            .and_then(|bos| self.tokenizer.token_to_id(&bos))
+            .context("Collecting an imatrix requires a BOS token.")?;
+
+        match self.organization {
+            IsqOrganization::Default => self.model.begin_track_stats()?,
+            IsqOrganization::MoeExpertsOnly => self.model.begin_track_stats_moe_experts_only()?,
+        }
+        let device = self.device().clone();
+        run_calibration(
+            &mut *self.model,
+            &tokens,
+            bos_tok_id,
+            &device,
+            self.mapper.as_ref(),
+        )?;
+
+        let multi_progress = Arc::new(MultiProgress::new());
+        self.model.quantize(
+            Some(dtype),
+            device,
+            self.topology.as_ref(),
+            self.silent,
+            Some(ImatrixDataSource::Collected),
+            None,
+            self.organization,
+            None,
+            UqffFullSer {
+                tokenizer: &self.tokenizer,
+                template_filename: &self.template_filename,
+                generation_config: self.generation_config.as_ref(),
+                config: self.config.clone(),
+                processor_filename: &None,
+                preprocessor_filename: &None,
+            },
+            multi_progress,
+        )?;
+        Ok(())
+    }
+
+    fn export_model(&mut self, path: &Path, format: ExportFormat) -> Result<()> {
+        if mistralrs_quant::distributed::use_nccl() {
+            anyhow::bail!("Exporting the model is not supported with tensor parallelism.");
+        }
+        let (layers, _) = self.model.get_layers();
+        if layers
+            .iter()
+            .any(|(layer, _)| layer.as_batched_lora().is_some())
+        {
+            anyhow::bail!(
+                "The model has unmerged LoRA adapters, merge them with `Model::merge_lora` before exporting."
+            );
+        }
+        let full_ser = UqffFullSer {
+            tokenizer: &self.tokenizer,
+            template_filename: &self.template_filename,
+            generation_config: self.generation_config.as_ref(),
+            config: self.config.clone(),
+            processor_filename: &None,
+            preprocessor_filename: &None,
+        };
+        match format {
+            ExportFormat::Uqff => self.model.serialize_uqff(
+                &path.to_path_buf(),
+                self.silent,
+                self.organization,
+                full_ser,
+            )?,
+            ExportFormat::Safetensors => self.model.serialize_safetensors(path, full_ser)?,
+            ExportFormat::Gguf(quant) => self.model.serialize_gguf(path, quant, full_ser)?,
+        }
+        Ok(())
+    }
+}
+
+/// Run `model` over the calibration `tokens` in chunks which each start with BOS, so the layers
+/// which are tracking stats collect an imatrix.
+fn run_calibration(
+    model: &mut (dyn NormalModel + Send + Sync),
+    tokens: &[u32],
+    bos_tok_id: u32,
+    device: &Device,
+    mapper: &dyn DeviceMapper,
+) -> Result<()> {
+    const CHUNK_SIZE: usize = 1024;
+    let n_chunks = tokens.len().div_ceil(CHUNK_SIZE);
+    let start = Instant::now();
+    for (i, chunk) in tokens.chunks(CHUNK_SIZE).enumerate() {
+        let chunk = [vec![bos_tok_id], chunk.to_vec()].concat();
+        let chunk_len = chunk.len();
+
+        let start = Instant::now();
+        let inputs = make_prompt_chunk(
+            0,
+            vec![&chunk],
+            &[0],
+            device,
+            None,
+            false,
+            None,
+            Some(mapper),
+        )?;
+
+        model.forward(
+            &inputs.input.to_device(model.device())?,
+            &inputs.positions,
+            inputs.context_lens.clone(),
+            inputs.position_ids.clone(),
+            None,
+            &inputs.flash_meta.clone(),
+        )?;
+
+        match model.cache_mut() {
+            EitherCache::Full(full) => {
+                for layer in &mut *full.lock() {
+                    *layer = None
+                }
+            }
+            EitherCache::Normal(normal) => {
+                for layer in &mut *normal.lock().unwrap().0 {
+                    layer.reset();
+                }
+            }
+        }
+
+        let end = Instant::now();
+        info!(
+            "Processed chunk {}/{n_chunks} ({chunk_len} tokens), {:.2}s",
+            i + 1,
+            end.duration_since(start).as_secs_f32()
+        );
+    }
+    device.synchronize()?;
+    let end = Instant::now();
+    info!(
+        "Finished collecting imatrix in {:.2}s",
+        end.duration_since(start).as_secs_f32()
+    );
+    Ok(())
+}
+
+/// The dtype and device to apply LoRA weights to `layer` in. Quantized layers use the activation dtype.
+fn lora_dtype_and_device(layer: &Arc<dyn QuantMethod>, activation_dtype: DType) -> (DType, Device) {
+    let (dtype, device) = layer.dtype_and_device();
+    if matches!(dtype, DType::F16 | DType::BF16 | DType::F32) {
+        (dtype, device)
+    } else {
+        (activation_dtype, device)
+    }
+}
+
+impl NormalPipeline {
+    fn load_lora_weights(&self, adapter: &LoraAdapterPaths) -> Result<LoraAdapter> {
+        if mistralrs_quant::distributed::use_nccl() {
+            anyhow::bail!("Runtime LoRA adapters are not supported with tensor parallelism.");
+        }
+        if self.metadata.is_xlora {
+            anyhow::bail!("Runtime LoRA adapters are not supported for X-LoRA models.");
+        }
+        if adapter.lora_config.saves_modules() {
+            anyhow::bail!(
+                "LoRA adapters with `modules_to_save` can only be applied when loading the model."
+            );
+        }
+
+        let weights = from_mmaped_safetensors(
+            vec![adapter.adapter_path.clone()],
+            Vec::new(),
+            Some(DType::F32),
+            &Device::Cpu,
+            vec![None],
+            self.silent,
+            None,
+            |_| true,
+            Arc::new(|_| DeviceForLoadTensor::Base),
+        )?;
+        Ok(LoraAdapter {
+            config: adapter.lora_config.clone(),
+            weights,
+        })
+    }
+
+    /// Add the delta weights of a LoRA adapter, multiplied by `sign`, to each layer it targets.
+    fn merge_lora_adapter(&mut self, adapter: &LoraAdapter, sign: f64) -> Result<()> {
+        if adapter.config.use_dora && sign < 0.0 {
+            anyhow::bail!("DoRA adapters cannot be removed from the model weights once applied.");
+        }
+
+        let activation_dtype = self.metadata.activation_dtype;
+        let mut n_applied = 0;
+        for (layer, name) in self.model.get_layers_with_names()? {
+            let Some(name) = name else {
+                continue;
+            };
+            let Some(delta) = mistralrs_quant::lora_delta_weight(adapter, &name, &**layer)? else {
+                continue;
+            };
+            // Quantized layers are dequantized before the delta is added.
+            let (dtype, device) = lora_dtype_and_device(layer, activation_dtype);
+            let delta = (delta * sign)?.to_device(&device)?.to_dtype(dtype)?;
+            *layer = layer.add_delta_w(&delta)?;
+            n_applied += 1;
+        }
+
+        if n_applied == 0 {
+            anyhow::bail!("The LoRA adapter does not target any layers of this model.");
+        }
+        if !self.silent {
+            info!("Merged LoRA adapter into {n_applied} layers.");
+        }
+        Ok(())
+    }
+
+    /// Add a LoRA adapter, unmerged, to each layer it targets.
+    fn add_batched_lora_adapter(&mut self, adapter_id: &str, adapter: &LoraAdapter) -> Result<()> {
+        let activation_dtype = self.metadata.activation_dtype;
+        let mut n_applied = 0;
+        for (layer, name) in self.model.get_layers_with_names()? {
+            let Some(name) = name else {
+                continue;
+            };
+            let Some((a, b, scale)) = mistralrs_quant::lora_weights(adapter, &name)? else {
+                continue;
+            };
+            let (dtype, device) = lora_dtype_and_device(layer, activation_dtype);
+            let a = a.to_device(&device)?.to_dtype(dtype)?;
+            let b = b.to_device(&device)?.to_dtype(dtype)?;
+            *layer = BatchedLoraLinear::add_adapter(layer, adapter_id.to_string(), a, b, scale);
+            n_applied += 1;
+        }
+
+        if n_applied == 0 {
+            anyhow::bail!("The LoRA adapter does not target any layers of this model.");
+        }
+        if !self.silent {
+            info!("Added LoRA adapter to {n_applied} layers.");
+        }
+        Ok(())
+    }
+}
+
+impl LoraPipelineMixin for NormalPipeline {
+    fn load_lora_adapter(&mut self, adapter_id: String, scale: Option<f64>) -> Result<()> {
+        if self.lora_adapters.contains_key(&adapter_id) {
+            anyhow::bail!("LoRA adapter `{adapter_id}` is already loaded.");
+        }
+        let mut paths = get_lora_adapter_paths(
+            &adapter_id,
+            &self.token_source,
+            self.revision.clone().unwrap_or("main".to_string()),
+        )?;
+        paths.lora_config.scale = scale;
+        let adapter = self.load_lora_weights(&paths)?;
+        // DoRA rescales the merged weights, so it cannot be applied per sequence.
+        if adapter.config.use_dora {
+            self.merge_lora_adapter(&adapter, 1.0)?;
+            self.merged_lora_adapters.push(adapter_id.clone());
+        } else {
+            self.add_batched_lora_adapter(&adapter_id, &adapter)?;
+        }
+        self.lora_adapters.insert(adapter_id, paths);
+        // The kept original layers do not include the adapter.
+        self.original_layers = None;
+        Ok(())
+    }
+
+    fn unload_lora_adapter(&mut self, adapter_id: &str) -> Result<()> {
+        let Some(paths) = self.lora_adapters.get(adapter_id).cloned() else {
+            anyhow::bail!("LoRA adapter `{adapter_id}` is not loaded.");
+        };
+        if self.merged_lora_adapters.iter().any(|id| id == adapter_id) {
+            let adapter = self.load_lora_weights(&paths)?;
+            self.merge_lora_adapter(&adapter, -1.0)?;
+            self.merged_lora_adapters.retain(|id| id != adapter_id);
+        } else {
+            let (layers, _) = self.model.get_layers();
+            for (layer, _) in layers {
+                *layer = BatchedLoraLinear::remove_adapter(layer, adapter_id);
+            }
+        }
+        self.lora_adapters.shift_remove(adapter_id);
+        self.original_layers = None;
+        Ok(())
+    }
+
+    fn merge_lora_adapters(&mut self) -> Result<()> {
+        let (layers, _) = self.model.get_layers();
+        for (layer, _) in layers {
+            *layer = BatchedLoraLinear::merge_adapters(layer)?;
+        }
+        self.lora_adapters.clear();
+        self.merged_lora_adapters.clear();
+        self.original_layers = None;
+        Ok(())
+    }
+
+    fn lora_adapter_ids(&self) -> Vec<String> {
+        self.lora_adapters.keys().cloned().collect()
+    }
+
+    fn lora_adapter_infos(&self) -> Vec<LoraAdapterInfo> {
+        self.lora_adapters
+            .iter()
+            .map(|(id, paths)| paths.info(id.clone(), self.merged_lora_adapters.contains(id)))
+            .collect()
+    }
 }
 
 impl CacheManagerMixin for NormalPipeline {
@@ -1113,6 +1526,25 @@ impl Pipeline for NormalPipeline {
             Ok(ForwardInputsResult::CausalGeneration { logits })
         }
     }
+    fn forward_inputs_hidden_states(
+        &mut self,
+        inputs: Box<dyn Any>,
+    ) -> Result<Tensor, candle_core::Error> {
+        let ModelInputs {
+            input_ids,
+            seqlen_offsets,
+            paged_attn_meta,
+            flash_meta,
+            ..
+        } = *inputs.downcast().expect("Downcast failed.");
+        if paged_attn_meta.is_some() || self.model.is_xlora() {
+            candle_core::bail!(
+                "The hidden states are not returned with PagedAttention or X-LoRA models."
+            );
+        }
+        self.model
+            .forward_hidden_states(&input_ids, &seqlen_offsets, &flash_meta)
+    }
     async fn sample_causal_gen(
         &self,
         seqs: &mut [&mut Sequence],
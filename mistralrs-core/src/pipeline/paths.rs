@@ -8,7 +8,7 @@ use anyhow::Result;
 use either::Either;
 use hf_hub::{
     api::sync::{ApiBuilder, ApiRepo},
-    Repo, RepoType,
+    Cache, Repo, RepoType,
 };
 use regex_automata::meta::Regex;
 use serde_json::Value;
@@ -19,11 +19,15 @@ use crate::{
     lora::LoraConfig,
     pipeline::{
         chat_template::{ChatTemplate, ChatTemplateValue},
+        in_memory::read_model_file_to_string,
         isq::UQFF_RESIDUAL_SAFETENSORS,
     },
-    utils::tokens::get_token,
+    utils::{
+        progress::{check_load_cancelled, has_load_progress_callback, DownloadProgress},
+        tokens::get_token,
+    },
     xlora_models::XLoraConfig,
-    ModelPaths, Ordering, TokenSource, GLOBAL_HF_CACHE,
+    LoraAdapterInfo, ModelPaths, Ordering, TokenSource, GLOBAL_HF_CACHE,
 };
 
 // Match files against these, avoids situations like `consolidated.safetensors`
@@ -37,6 +41,25 @@ pub struct LoraAdapterPaths {
     pub adapter_path: PathBuf,
 }
 
+impl LoraAdapterPaths {
+    pub(crate) fn info(&self, name: String, merged: bool) -> LoraAdapterInfo {
+        let mut target_modules = self
+            .lora_config
+            .target_modules
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        target_modules.sort();
+        LoraAdapterInfo {
+            name,
+            rank: self.lora_config.rank,
+            target_modules,
+            merged,
+            active: merged,
+        }
+    }
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug)]
 pub enum AdapterPaths {
@@ -52,13 +75,16 @@ pub enum AdapterPaths {
     None,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn get_xlora_paths(
     base_model_id: String,
     xlora_model_id: Option<&String>,
     lora_adapter_ids: Option<&Vec<String>>,
+    lora_adapter_scales: &HashMap<String, f64>,
     token_source: &TokenSource,
     revision: String,
     xlora_order: Option<&Ordering>,
+    xlora_classifier_path: Option<&PathBuf>,
 ) -> Result<AdapterPaths> {
     match (lora_adapter_ids, xlora_model_id, xlora_order) {
         (None, Some(xlora_id), Some(xlora_order)) => {
@@ -91,8 +117,11 @@ pub fn get_xlora_paths(
             }
             let xlora_classifier = xlora_classifier.first();
 
-            let classifier_path = xlora_classifier
-                .map(|xlora_classifier| api_get_file!(api, xlora_classifier, model_id));
+            let classifier_path = match xlora_classifier_path {
+                Some(path) => Some(path.clone()),
+                None => xlora_classifier
+                    .map(|xlora_classifier| api_get_file!(api, xlora_classifier, model_id)),
+            };
 
             // Get the path for the xlora config by checking all for valid versions.
             // NOTE(EricLBuehler): Remove this functionality because all configs should be deserializable
@@ -270,33 +299,9 @@ pub fn get_xlora_paths(
         (Some(adapter_ids), None, None) => {
             let mut lora_adapter_paths = Vec::new();
             for adapter_id in adapter_ids {
-                info!("Loading adapter at `{adapter_id}`");
-
-                let api = {
-                    let cache = GLOBAL_HF_CACHE.get().cloned().unwrap_or_default();
-                    let mut api = ApiBuilder::from_cache(cache)
-                        .with_progress(true)
-                        .with_token(get_token(token_source)?);
-                    if let Ok(x) = std::env::var("HF_HUB_CACHE") {
-                        api = api.with_cache_dir(x.into());
-                    }
-                    api.build().map_err(candle_core::Error::msg)?
-                };
-                let api = api.repo(Repo::with_revision(
-                    adapter_id.clone(),
-                    RepoType::Model,
-                    revision.clone(),
-                ));
-
-                let config_path = api.get("adapter_config.json")?;
-                let adapter_path = api.get("adapter_model.safetensors")?;
-                let lora_config: mistralrs_quant::LoraConfig =
-                    serde_json::from_str(&fs::read_to_string(config_path)?)?;
-
-                lora_adapter_paths.push(LoraAdapterPaths {
-                    lora_config,
-                    adapter_path,
-                });
+                let mut paths = get_lora_adapter_paths(adapter_id, token_source, revision.clone())?;
+                paths.lora_config.scale = lora_adapter_scales.get(adapter_id).copied();
+                lora_adapter_paths.push(paths);
             }
 
             Ok(AdapterPaths::Lora(lora_adapter_paths))
@@ -308,6 +313,65 @@ pub fn get_xlora_paths(
     }
 }
 
+/// Download (or fetch from the cache) the config and weights of a single LoRA adapter.
+pub fn get_lora_adapter_paths(
+    adapter_id: &str,
+    token_source: &TokenSource,
+    revision: String,
+) -> Result<LoraAdapterPaths> {
+    info!("Loading adapter at `{adapter_id}`");
+
+    let api = {
+        let cache = GLOBAL_HF_CACHE.get().cloned().unwrap_or_default();
+        let mut api = ApiBuilder::from_cache(cache)
+            .with_progress(true)
+            .with_token(get_token(token_source)?);
+        if let Ok(x) = std::env::var("HF_HUB_CACHE") {
+            api = api.with_cache_dir(x.into());
+        }
+        api.build().map_err(candle_core::Error::msg)?
+    };
+    let api = api.repo(Repo::with_revision(
+        adapter_id.to_string(),
+        RepoType::Model,
+        revision,
+    ));
+
+    // Adapters may also be loaded from a local directory
+    let model_id = Path::new(adapter_id);
+    let config_path = api_get_file!(api, "adapter_config.json", model_id);
+    let adapter_path = api_get_file!(api, "adapter_model.safetensors", model_id);
+    let lora_config: mistralrs_quant::LoraConfig =
+        serde_json::from_str(&fs::read_to_string(config_path)?)?;
+
+    Ok(LoraAdapterPaths {
+        lora_config,
+        adapter_path,
+    })
+}
+
+/// Get a weight file like `api_get_file!`, but report the download to the load progress callback
+/// if the file is not cached yet.
+fn get_weight_file(api: &ApiRepo, file: &str, model_id: &Path, revision: &str) -> Result<PathBuf> {
+    check_load_cancelled()?;
+    if model_id.exists() || !has_load_progress_callback() {
+        return Ok(api_get_file!(api, file, model_id));
+    }
+    let cache = match std::env::var("HF_HUB_CACHE") {
+        Ok(x) => Cache::new(x.into()),
+        Err(_) => GLOBAL_HF_CACHE.get().cloned().unwrap_or_default(),
+    };
+    let repo = Repo::with_revision(
+        model_id.display().to_string(),
+        RepoType::Model,
+        revision.to_string(),
+    );
+    match cache.repo(repo).get(file) {
+        Some(path) => Ok(path),
+        None => Ok(api.download_with_progress(file, DownloadProgress::default())?),
+    }
+}
+
 pub fn get_model_paths(
     revision: String,
     token_source: &TokenSource,
@@ -339,7 +403,7 @@ pub fn get_model_paths(
                     revision.clone(),
                 ));
                 let model_id = Path::new(&id);
-                files.push(api_get_file!(qapi, name, model_id));
+                files.push(get_weight_file(&qapi, name, model_id, &revision)?);
             }
             Ok(files)
         }
@@ -387,7 +451,7 @@ pub fn get_model_paths(
                     .collect::<Vec<_>>()
             );
             for rfilename in files {
-                filenames.push(api_get_file!(api, &rfilename, model_id));
+                filenames.push(get_weight_file(api, &rfilename, model_id, &revision)?);
             }
             Ok(filenames)
         }
@@ -426,7 +490,7 @@ pub(crate) fn get_chat_template(
         ) {
             panic!("Template filename {template_filename:?} must end with `.json` or `.jinja`.");
         }
-        Some(fs::read_to_string(template_filename).expect("Loading chat template failed."))
+        Some(read_model_file_to_string(template_filename).expect("Loading chat template failed."))
     } else if chat_template_fallback.is_some_and(|f| f.ends_with(".json")) {
         // User specified a file
         let template_filename = chat_template_fallback
@@ -466,8 +530,8 @@ pub(crate) fn get_chat_template(
     // Overwrite to use any present `chat_template.json`, only if there is not one present already.
     if template.chat_template.is_none() {
         if let Some(chat_template_explicit) = chat_template_explicit {
-            let ct =
-                fs::read_to_string(chat_template_explicit).expect("Loading chat template failed.");
+            let ct = read_model_file_to_string(chat_template_explicit)
+                .expect("Loading chat template failed.");
 
             let new_chat_template = if chat_template_explicit.ends_with(".jinja") {
                 ct
@@ -498,7 +562,7 @@ pub(crate) fn get_chat_template(
     let processor_conf: Option<crate::vision_models::processor_config::ProcessorConfig> = paths
         .get_processor_config()
         .as_ref()
-        .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
+        .map(|f| serde_json::from_str(&read_model_file_to_string(f).unwrap()).unwrap());
     if let Some(processor_conf) = processor_conf {
         if processor_conf.chat_template.is_some() {
             template.chat_template = processor_conf
@@ -0,0 +1,250 @@
+use super::embedding::{
+    get_model_paths, load_tokenizer, stack_encodings, EmbeddingModelPaths, EmbeddingProcessor,
+    EMBEDDING_BATCH_SIZE,
+};
+use super::{
+    AnyMoePipelineMixin, Cache, CacheManagerMixin, EitherCache, ForwardInputsResult,
+    GeneralMetadata, IsqPipelineMixin, Loader, LoraPipelineMixin, MetadataMixin, ModelCategory,
+    ModelKind, ModelPaths, PreProcessingMixin, Processor, TokenSource,
+};
+use crate::device_map::DeviceMapper;
+use crate::embedding::RerankerModel;
+use crate::pipeline::{ChatTemplate, Modalities, SupportedModality};
+use crate::prefix_cacher::PrefixCacheManagerV2;
+use crate::sequence::Sequence;
+use crate::{DeviceMapSetting, PagedAttentionConfig, Pipeline, TryIntoDType};
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use mistralrs_quant::IsqType;
+use rand_isaac::Isaac64Rng;
+use std::any::Any;
+use std::sync::Arc;
+use tokenizers::{Encoding, Tokenizer};
+use tokio::sync::Mutex;
+
+/// A cross-encoder reranker (BERT, RoBERTa or XLM-RoBERTa sequence classification
+/// architecture), which scores the relevance of each document to a query.
+pub struct RerankerPipeline {
+    model_id: String,
+    model: RerankerModel,
+    tokenizer: Arc<Tokenizer>,
+    device: Device,
+    metadata: Arc<GeneralMetadata>,
+    dummy_cache: EitherCache,
+}
+
+pub struct RerankerLoader {
+    pub model_id: String,
+}
+
+impl Loader for RerankerLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        let paths: Box<dyn ModelPaths> = Box::new(get_model_paths(
+            &self.model_id,
+            revision,
+            &token_source,
+            silent,
+        )?);
+        self.load_model_from_path(
+            &paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            paged_attn_config,
+        )
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        _silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        _paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        let paths = &paths
+            .as_ref()
+            .as_any()
+            .downcast_ref::<EmbeddingModelPaths>()
+            .expect("Path downcast failed.");
+
+        if matches!(mapper, DeviceMapSetting::Map(_)) {
+            anyhow::bail!("Device mapping is not supported for reranker models.")
+        }
+        if in_situ_quant.is_some() {
+            anyhow::bail!("Reranker models do not support ISQ.")
+        }
+
+        #[cfg(feature = "cuda")]
+        if let Device::Cuda(dev) = &device {
+            unsafe { dev.disable_event_tracking() };
+        }
+
+        let mapper = DeviceMapSetting::dummy().into_mapper(usize::MAX, device, None)?;
+        let dtype = mapper.get_min_dtype(dtype)?;
+
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&paths.weights, dtype, device)? };
+        let (model, max_seq_len) =
+            RerankerModel::load(vb, &std::fs::read_to_string(&paths.config)?)?;
+        let tokenizer = load_tokenizer(&paths.tokenizer, max_seq_len)?;
+
+        Ok(Arc::new(Mutex::new(RerankerPipeline {
+            model_id: self.model_id.clone(),
+            model,
+            tokenizer: Arc::new(tokenizer),
+            device: device.clone(),
+            metadata: Arc::new(GeneralMetadata {
+                max_seq_len,
+                llg_factory: None,
+                is_xlora: false,
+                no_prefix_cache: true,
+                num_hidden_layers: 1, // FIXME(EricLBuehler): we know this is only for caching, so its OK.
+                eos_tok: vec![],
+                kind: ModelKind::Normal,
+                no_kv_cache: true, // NOTE(EricLBuehler): no cache for these.
+                activation_dtype: dtype,
+                sliding_window: None,
+                cache_config: None,
+                cache_engine: None,
+                model_metadata: None,
+                modalities: Modalities {
+                    input: vec![SupportedModality::Text],
+                    output: vec![SupportedModality::Text],
+                },
+            }),
+            dummy_cache: EitherCache::Full(Cache::new(0, false)),
+        })))
+    }
+
+    fn get_id(&self) -> String {
+        self.model_id.clone()
+    }
+
+    fn get_kind(&self) -> ModelKind {
+        ModelKind::Normal
+    }
+}
+
+impl PreProcessingMixin for RerankerPipeline {
+    fn get_processor(&self) -> Arc<dyn Processor> {
+        Arc::new(EmbeddingProcessor)
+    }
+    fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+        None
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        None
+    }
+}
+
+impl IsqPipelineMixin for RerankerPipeline {
+    fn re_isq_model(&mut self, _dtype: IsqType) -> Result<()> {
+        anyhow::bail!("Reranker models do not support ISQ.")
+    }
+}
+
+impl CacheManagerMixin for RerankerPipeline {
+    fn clone_in_cache(&self, _seqs: &mut [&mut Sequence]) {}
+    fn clone_out_cache(&self, _seqs: &mut [&mut Sequence]) {}
+    fn set_none_cache(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _reset_non_granular: bool,
+        _modify_draft_cache: bool,
+        _load_preallocated_cache: bool,
+    ) {
+    }
+    fn cache(&self) -> &EitherCache {
+        &self.dummy_cache
+    }
+}
+
+impl MetadataMixin for RerankerPipeline {
+    fn device(&self) -> Device {
+        self.device.clone()
+    }
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+    fn name(&self) -> String {
+        self.model_id.clone()
+    }
+    fn reset_non_granular_state(&self) {}
+    fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        Some(self.tokenizer.clone())
+    }
+    fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for RerankerPipeline {
+    fn forward_inputs(
+        &mut self,
+        _inputs: Box<dyn Any>,
+        _return_raw_logits: bool,
+    ) -> candle_core::Result<ForwardInputsResult> {
+        candle_core::bail!("`forward_inputs` is incompatible with `RerankerPipeline`");
+    }
+
+    async fn sample_causal_gen(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _logits: Vec<Tensor>,
+        _prefix_cacher: &mut PrefixCacheManagerV2,
+        _disable_eos_stop: bool,
+        _srng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<(), candle_core::Error> {
+        candle_core::bail!("`sample_causal_gen` is incompatible with `RerankerPipeline`");
+    }
+
+    fn category(&self) -> ModelCategory {
+        ModelCategory::Reranker
+    }
+
+    fn rerank(&mut self, query: &str, documents: &[String]) -> Result<Vec<f32>> {
+        let mut scores = Vec::with_capacity(documents.len());
+        for chunk in documents.chunks(EMBEDDING_BATCH_SIZE) {
+            let pairs = chunk
+                .iter()
+                .map(|document| (query.to_string(), document.clone()))
+                .collect::<Vec<_>>();
+            let encodings = self
+                .tokenizer
+                .encode_batch(pairs, true)
+                .map_err(anyhow::Error::msg)?;
+            let input_ids = stack_encodings(&encodings, Encoding::get_ids, &self.device)?;
+            let token_type_ids = stack_encodings(&encodings, Encoding::get_type_ids, &self.device)?;
+            let attention_mask =
+                stack_encodings(&encodings, Encoding::get_attention_mask, &self.device)?;
+
+            let logits = self
+                .model
+                .score(&input_ids, &token_type_ids, &attention_mask)?;
+            scores.extend(logits.to_vec1::<f32>()?);
+        }
+        Ok(scores)
+    }
+}
+
+impl LoraPipelineMixin for RerankerPipeline {}
+
+impl AnyMoePipelineMixin for RerankerPipeline {}
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use candle_core::{DType, Result, Tensor};
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
 use rand_isaac::Isaac64Rng;
 
 use crate::{
@@ -10,7 +10,7 @@ use crate::{
     tools::parse_text_tools,
 };
 
-use super::Pipeline;
+use super::{ForwardInputsResult, InputProcessorOutput, Pipeline};
 
 macro_rules! fixup_sentencepiece {
     ($txt:expr) => {
@@ -24,6 +24,77 @@ macro_rules! fixup_sentencepiece {
     };
 }
 
+/// Append the logprobs of `tokens`, the text of the first starting at character `text_offset`, in
+/// the format of the completions API.
+fn extend_completion_logprobs<'a>(
+    completion_logprobs: &mut crate::CompletionLogprobs,
+    mut text_offset: usize,
+    tokens: impl IntoIterator<Item = (String, Option<&'a Logprobs>)>,
+) {
+    for (token, logprob) in tokens {
+        completion_logprobs.text_offset.push(text_offset);
+        text_offset += token.chars().count();
+        completion_logprobs.tokens.push(token);
+        completion_logprobs
+            .token_logprobs
+            .push(logprob.map(|logprob| logprob.logprob));
+        completion_logprobs.top_logprobs.push(
+            logprob
+                .and_then(|logprob| logprob.top_logprobs.as_ref())
+                .map(|top_logprobs| {
+                    top_logprobs
+                        .iter()
+                        .map(|top| {
+                            (
+                                top.bytes.clone().unwrap_or_else(|| top.token.to_string()),
+                                top.logprob,
+                            )
+                        })
+                        .collect()
+                }),
+        );
+    }
+}
+
+/// The logprobs of each token of the prompt of `seq` after the first, from one run of the whole
+/// prompt. This uses the model cache, so the caches of the sequences of the batch must have been
+/// cloned out of it.
+pub(crate) fn prompt_logprobs<P: Pipeline + ?Sized>(
+    pipeline: &mut P,
+    seq: &mut Sequence,
+) -> Result<Vec<Logprobs>> {
+    let sampler = seq.sampler();
+    let tokens = seq.get_toks().to_vec();
+    let seqs = &mut [seq];
+    mistralrs_quant::set_lora_batch(vec![seqs[0].adapters().map(|a| a.to_vec())]);
+    let inputs = pipeline.get_processor().inputs_processor().process_inputs(
+        pipeline.tokenizer(),
+        seqs,
+        true,
+        pipeline.get_metadata().is_xlora,
+        &pipeline.device(),
+        pipeline.get_metadata().no_kv_cache,
+        None,
+        true,
+        pipeline.get_input_processor_config(),
+        None,
+        pipeline.device_mapper(),
+    );
+    let InputProcessorOutput { inputs, .. } = inputs.map_err(candle_core::Error::msg)?;
+
+    pipeline.set_none_cache(seqs, false, false, false);
+    let ForwardInputsResult::RawLogits { logits } = pipeline.forward_inputs(inputs, true)? else {
+        candle_core::bail!("The prompt logprobs require the logits of every prompt token.")
+    };
+    let logits = logits.i(0)?.to_device(&Device::Cpu)?;
+    tokens
+        .iter()
+        .skip(1)
+        .enumerate()
+        .map(|(i, &token)| sampler.logprobs_of(&logits.i(i)?, token))
+        .collect()
+}
+
 pub(crate) async fn finish_or_add_toks_to_seq(
     this: &dyn Pipeline,
     prefix_cacher: &mut PrefixCacheManagerV2,
@@ -80,6 +151,11 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         let send = true;
         if !tool_use_still_possible || tool_use_is_done {
             if send {
+                let text_offset = if seq.return_logprobs() {
+                    seq.streamed_chars()
+                } else {
+                    0
+                };
                 if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
                     if seq.get_mut_group().is_chat {
                         let (text_new, tool_calls) =
@@ -117,12 +193,13 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                                 index: seq.get_response_index(),
                                 finish_reason: is_done.map(|x| x.to_string()),
                                 logprobs: if seq.return_logprobs() {
-                                    Some(crate::ResponseLogprob {
-                                        token: delta,
-                                        bytes: logprobs.bytes.clone().map(|b| b.into_bytes()),
-                                        logprob: logprobs.logprob,
-                                        top_logprobs: logprobs.top_logprobs.unwrap().clone(),
-                                    })
+                                    let mut chunk_logprobs = crate::CompletionLogprobs::default();
+                                    extend_completion_logprobs(
+                                        &mut chunk_logprobs,
+                                        text_offset,
+                                        [(delta, Some(&logprobs))],
+                                    );
+                                    Some(chunk_logprobs)
                                 } else {
                                     None
                                 },
@@ -208,6 +285,8 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 | crate::sequence::StopReason::Eos
                 | crate::sequence::StopReason::StopTok(_)
                 | crate::sequence::StopReason::Canceled
+                | crate::sequence::StopReason::Timeout
+                | crate::sequence::StopReason::KvQuota(_)
                 | crate::sequence::StopReason::ToolCalls => {
                     String::from_utf8_lossy(seq.completion_bytes())
                         .trim_start()
@@ -216,6 +295,10 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 crate::sequence::StopReason::StopString {
                     completion_bytes_pos,
                     ..
+                }
+                | crate::sequence::StopReason::StopRegex {
+                    completion_bytes_pos,
+                    ..
                 } => {
                     let txt = String::from_utf8_lossy(seq.completion_bytes());
                     txt[..completion_bytes_pos].trim_start().to_string()
@@ -246,11 +329,43 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 };
                 seq.add_choice_to_group(choice);
             } else {
+                let logprobs = logprobs.map(|logprobs| {
+                    let mut completion_logprobs = crate::CompletionLogprobs::default();
+                    let mut text_offset = 0;
+                    if let Some(prompt) = seq.echoed_prompt() {
+                        // The first token of the prompt has no logprobs
+                        let first = tokenizer
+                            .as_ref()
+                            .and_then(|tokenizer| {
+                                tokenizer.decode(&seq.get_toks()[..1], false).ok()
+                            })
+                            .unwrap_or_default();
+                        extend_completion_logprobs(
+                            &mut completion_logprobs,
+                            0,
+                            std::iter::once((first, None)).chain(seq.prompt_logprobs().iter().map(
+                                |logprob| {
+                                    (logprob.bytes.clone().unwrap_or_default(), Some(logprob))
+                                },
+                            )),
+                        );
+                        text_offset = prompt.chars().count();
+                    }
+                    extend_completion_logprobs(
+                        &mut completion_logprobs,
+                        text_offset,
+                        logprobs
+                            .into_iter()
+                            .zip(seq.logprobs())
+                            .map(|(resp_logprob, logprob)| (resp_logprob.token, Some(logprob))),
+                    );
+                    completion_logprobs
+                });
                 let choice = crate::CompletionChoice {
                     finish_reason: fixup_sentencepiece!(reason),
                     index: seq.get_response_index(),
                     text,
-                    logprobs: None,
+                    logprobs,
                 };
                 seq.add_completion_choice_to_group(choice);
             }
@@ -298,6 +413,10 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         this.reset_non_granular_state();
     }
 
+    if !matches!(seq.getstate(), crate::sequence::SequenceState::Done(_)) {
+        seq.maybe_shift_context(this.get_metadata().max_seq_len);
+    }
+
     Ok(())
 }
 
@@ -358,6 +477,12 @@ pub async fn sample_sequence(
     multiple_sequences: bool,
 ) -> Result<Logprobs> {
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    // Seeded sequences sample on the CPU with their own generator, so the other sequences of the
+    // batch do not change their output
+    let (logits, rng) = match seq.rng() {
+        Some(seq_rng) => (logits.to_device(&Device::Cpu)?, seq_rng),
+        None => (logits, rng),
+    };
 
     let sampler = seq.sampler();
     let ctx_clone = seq.get_toks().to_vec();
@@ -507,3 +632,48 @@ pub async fn sample_target_sequence_speculative(
     }
     Ok(sampled)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::sampler::{Logprobs, TopLogprob};
+
+    use super::extend_completion_logprobs;
+
+    #[test]
+    fn completion_logprobs_have_the_offsets_of_their_tokens() {
+        let logprob = Logprobs {
+            token: 1,
+            logprob: -0.5,
+            bytes: Some(" world".to_string()),
+            top_logprobs: Some(vec![
+                TopLogprob {
+                    token: 1,
+                    logprob: -0.5,
+                    bytes: Some(" world".to_string()),
+                },
+                TopLogprob {
+                    token: 2,
+                    logprob: -1.,
+                    bytes: None,
+                },
+            ]),
+        };
+        let mut logprobs = crate::CompletionLogprobs::default();
+        extend_completion_logprobs(
+            &mut logprobs,
+            0,
+            [
+                ("Hello".to_string(), None),
+                (" world".to_string(), Some(&logprob)),
+            ],
+        );
+        extend_completion_logprobs(&mut logprobs, 12, [("!".to_string(), Some(&logprob))]);
+
+        assert_eq!(logprobs.tokens, ["Hello", " world", "!"]);
+        assert_eq!(logprobs.token_logprobs, [None, Some(-0.5), Some(-0.5)]);
+        assert_eq!(logprobs.text_offset, [0, 5, 12]);
+        assert!(logprobs.top_logprobs[0].is_none());
+        let top = logprobs.top_logprobs[1].as_ref().unwrap();
+        assert_eq!(top.keys().collect::<Vec<_>>(), [" world", "2"]);
+    }
+}
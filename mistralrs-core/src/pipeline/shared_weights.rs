@@ -0,0 +1,128 @@
+//! Weights shared between pipelines of the same base model, for example to load it with two sets
+//! of adapters without holding two copies of the base weights.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use candle_core::{DType, Device, DeviceLocation, Result, Shape, Tensor};
+use candle_nn::{var_builder::SimpleBackend, Init};
+use mistralrs_quant::{ShardedSafeTensors, ShardedVarBuilder};
+use regex::Regex;
+
+/// Identifies the weights of a base model.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SharedWeightsKey {
+    pub model_id: String,
+    /// `None` for the default revision.
+    pub revision: Option<String>,
+    pub dtype: DType,
+}
+
+type TensorKey = (String, DType, DeviceLocation);
+
+/// A cache of the weights loaded by pipelines, keyed by the model ID, revision and dtype. Loaders
+/// given the same cache reuse the tensors of earlier pipelines of the same model instead of loading
+/// them again, so both pipelines reference the same device memory.
+///
+/// The tensors are held until [`SharedWeights::clear`] is called or the cache is dropped. Layers
+/// which are quantized with ISQ are not shared.
+#[derive(Clone, Default)]
+pub struct SharedWeights {
+    models: Arc<Mutex<HashMap<SharedWeightsKey, Arc<Mutex<HashMap<TensorKey, Tensor>>>>>>,
+}
+
+impl SharedWeights {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of tensors held for each model.
+    pub fn num_tensors(&self) -> HashMap<SharedWeightsKey, usize> {
+        self.models
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, tensors)| (key.clone(), tensors.lock().unwrap().len()))
+            .collect()
+    }
+
+    /// Release the tensors held by the cache. Pipelines which use them keep their own references.
+    pub fn clear(&self) {
+        self.models.lock().unwrap().clear();
+    }
+
+    /// Load the tensors of `vb` through the cache of the model `key`. Tensors matching `exclude`,
+    /// such as the layers to quantize, are loaded from `vb` without being shared.
+    pub(crate) fn wrap(
+        &self,
+        key: SharedWeightsKey,
+        vb: ShardedVarBuilder,
+        exclude: Option<Vec<Regex>>,
+    ) -> ShardedVarBuilder {
+        let tensors = self.models.lock().unwrap().entry(key).or_default().clone();
+        let dtype = vb.dtype();
+        let device = vb.device().clone();
+        ShardedSafeTensors::wrap(
+            Box::new(SharedWeightsBackend {
+                vb,
+                tensors,
+                exclude: exclude.unwrap_or_default(),
+            }),
+            dtype,
+            device,
+        )
+    }
+}
+
+struct SharedWeightsBackend {
+    vb: ShardedVarBuilder,
+    tensors: Arc<Mutex<HashMap<TensorKey, Tensor>>>,
+    exclude: Vec<Regex>,
+}
+
+impl SharedWeightsBackend {
+    fn get_or_load(
+        &self,
+        name: &str,
+        dtype: DType,
+        dev: &Device,
+        load: impl FnOnce(ShardedVarBuilder) -> Result<Tensor>,
+    ) -> Result<Tensor> {
+        let vb = self.vb.clone().set_device(dev.clone());
+        if self.exclude.iter().any(|r| r.is_match(name)) {
+            return load(vb);
+        }
+        let key = (name.to_string(), dtype, dev.location());
+        if let Some(tensor) = self.tensors.lock().unwrap().get(&key) {
+            return Ok(tensor.clone());
+        }
+        let tensor = load(vb)?;
+        self.tensors.lock().unwrap().insert(key, tensor.clone());
+        Ok(tensor)
+    }
+}
+
+impl SimpleBackend for SharedWeightsBackend {
+    fn get(&self, s: Shape, name: &str, _: Init, dtype: DType, dev: &Device) -> Result<Tensor> {
+        let tensor = self.get_or_load(name, dtype, dev, |vb| {
+            vb.get_with_hints_dtype(s.clone(), name, Default::default(), dtype)
+        })?;
+        if tensor.shape() != &s {
+            candle_core::bail!(
+                "shape mismatch for {name}, expected {s:?}, got {:?}",
+                tensor.shape()
+            );
+        }
+        Ok(tensor)
+    }
+
+    fn get_unchecked(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor> {
+        self.get_or_load(name, dtype, dev, |vb| vb.get_unchecked_dtype(name, dtype))
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.vb.contains_tensor(name)
+    }
+}
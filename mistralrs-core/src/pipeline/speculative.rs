@@ -1,5 +1,6 @@
 use std::{
     any::Any,
+    path::Path,
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
@@ -20,7 +21,8 @@ use crate::{
     },
     prefix_cacher::PrefixCacheManagerV2,
     sequence::Sequence,
-    DeviceMapSetting, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource, TryIntoDType,
+    CalibrationData, DeviceMapSetting, ExportFormat, Loader, LoraAdapterInfo, ModelKind,
+    PagedAttentionConfig, Pipeline, TokenSource, TryIntoDType,
 };
 
 use crate::kv_cache::CacheManager;
@@ -28,7 +30,7 @@ use crate::kv_cache::CacheManager;
 use super::{
     chat_template::ChatTemplate, sampling::SpeculativeSample, AnyMoePipelineMixin,
     CacheBackendMetadata, CacheInstruction, CacheManagerMixin, EitherCache, ForwardInputsResult,
-    GeneralMetadata, IsqPipelineMixin, MetadataMixin, ModelCategory, ModelPaths,
+    GeneralMetadata, IsqPipelineMixin, LoraPipelineMixin, MetadataMixin, ModelCategory, ModelPaths,
     PreProcessingMixin,
 };
 
@@ -240,6 +242,21 @@ impl IsqPipelineMixin for SpeculativePipeline {
         get_mut_arcmutex!(self.target).re_isq_model(dtype)?;
         get_mut_arcmutex!(self.draft).re_isq_model(dtype)
     }
+    fn requantize_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).requantize_model(dtype)?;
+        get_mut_arcmutex!(self.draft).requantize_model(dtype)
+    }
+    fn re_isq_model_calibrated(
+        &mut self,
+        dtype: IsqType,
+        data: &CalibrationData,
+    ) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).re_isq_model_calibrated(dtype, data)?;
+        get_mut_arcmutex!(self.draft).re_isq_model_calibrated(dtype, data)
+    }
+    fn export_model(&mut self, path: &Path, format: ExportFormat) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).export_model(path, format)
+    }
 }
 
 impl CacheManagerMixin for SpeculativePipeline {
@@ -339,6 +356,13 @@ impl Pipeline for SpeculativePipeline {
         rng: Arc<Mutex<Isaac64Rng>>,
         backend_metadata: CacheBackendMetadata,
     ) -> Result<Duration> {
+        mistralrs_quant::set_lora_batch(
+            input_seqs
+                .iter()
+                .map(|seq| seq.adapters().map(|a| a.to_vec()))
+                .collect(),
+        );
+
         match backend_metadata {
             CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
                 match pre_op {
@@ -622,4 +646,22 @@ impl Pipeline for SpeculativePipeline {
     }
 }
 
+impl LoraPipelineMixin for SpeculativePipeline {
+    fn load_lora_adapter(&mut self, adapter_id: String, scale: Option<f64>) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).load_lora_adapter(adapter_id, scale)
+    }
+    fn unload_lora_adapter(&mut self, adapter_id: &str) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).unload_lora_adapter(adapter_id)
+    }
+    fn merge_lora_adapters(&mut self) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).merge_lora_adapters()
+    }
+    fn lora_adapter_ids(&self) -> Vec<String> {
+        get_mut_arcmutex!(self.target).lora_adapter_ids()
+    }
+    fn lora_adapter_infos(&self) -> Vec<LoraAdapterInfo> {
+        get_mut_arcmutex!(self.target).lora_adapter_infos()
+    }
+}
+
 impl AnyMoePipelineMixin for SpeculativePipeline {}
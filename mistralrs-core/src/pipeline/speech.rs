@@ -1,15 +1,19 @@
 use super::text_models_inputs_processor::PagedAttentionMeta;
+use super::transcription::TranscriptionPipeline;
 use super::{
     AdapterPaths, AnyMoePipelineMixin, Cache, CacheManagerMixin, EitherCache, ForwardInputsResult,
     GeneralMetadata, InputProcessorOutput, InputsProcessor, InputsProcessorType, IsqPipelineMixin,
-    Loader, MessagesAction, MetadataMixin, ModelCategory, ModelKind, ModelPaths,
+    Loader, LoraPipelineMixin, MessagesAction, MetadataMixin, ModelCategory, ModelKind, ModelPaths,
     PreProcessingMixin, Processor, TokenSource,
 };
 use crate::device_map::DeviceMapper;
 use crate::pipeline::{ChatTemplate, Modalities, SupportedModality};
 use crate::prefix_cacher::PrefixCacheManagerV2;
 use crate::sequence::Sequence;
-use crate::speech_models::{DiaConfig, DiaPipeline, SpeechGenerationOutput, SpeechLoaderType};
+use crate::speech_models::{
+    utils::split_sentences, AudioChunk, DiaConfig, DiaPipeline, ParlerConfig, ParlerPipeline,
+    SpeechGenerationOutput, SpeechLoaderType, SpeechModel, WhisperConfig, WhisperPipeline,
+};
 use crate::utils::varbuilder_utils::DeviceForLoadTensor;
 use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
 use crate::{
@@ -31,10 +35,15 @@ use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
 use tracing::info;
 
+/// Sentences shorter than this are spoken together with the next one.
+const MIN_SENTENCE_LEN: usize = 32;
+
 #[derive(Clone, Debug)]
 pub struct SpeechModelPaths {
     weights: Vec<PathBuf>,
     config: PathBuf,
+    /// Dia does not use a tokenizer.
+    tokenizer: Option<PathBuf>,
 }
 
 impl ModelPaths for SpeechModelPaths {
@@ -136,7 +145,7 @@ impl InputsProcessor for SpeechInputsProcessor {
 
 pub struct SpeechPipeline {
     model_id: String,
-    model: DiaPipeline,
+    model: SpeechModel,
     metadata: Arc<GeneralMetadata>,
     dummy_cache: EitherCache,
     cfg: SpeechGenerationConfig,
@@ -163,8 +172,9 @@ impl Loader for SpeechLoader {
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
         let paths: anyhow::Result<Box<dyn ModelPaths>> = {
-            // Main weights first, DAC is the final one.
+            // Main weights first, DAC is the final one if the model uses a separate one.
             let mut weights = Vec::new();
+            let mut tokenizer = None;
 
             // Main model
             let config = {
@@ -182,26 +192,32 @@ impl Loader for SpeechLoader {
 
                 let weight = api_get_file!(api, "model.safetensors", &model_id);
                 let config = api_get_file!(api, "config.json", &model_id);
+                if self.arch != SpeechLoaderType::Dia {
+                    tokenizer = Some(api_get_file!(api, "tokenizer.json", &model_id));
+                }
                 weights.push(weight);
                 config
             };
 
+            // Apply default here
+            let dac_model = match self.arch {
+                SpeechLoaderType::Dia => Some(
+                    self.dac_model_id
+                        .clone()
+                        .unwrap_or_else(|| "EricB/dac_44khz".to_string()),
+                ),
+                // The Parler-TTS weights include the DAC.
+                SpeechLoaderType::Parler | SpeechLoaderType::Whisper => None,
+            };
+
             // DAC model
-            {
+            if let Some(dac_model) = dac_model {
                 let api = ApiBuilder::new()
                     .with_progress(!silent)
                     .with_token(get_token(&token_source)?)
                     .build()?;
                 let revision = revision.unwrap_or("main".to_string());
 
-                // Apply default here
-                let dac_model = self
-                    .dac_model_id
-                    .clone()
-                    .unwrap_or_else(|| match self.arch {
-                        SpeechLoaderType::Dia => "EricB/dac_44khz".to_string(),
-                    });
-
                 let api = api.repo(Repo::with_revision(
                     dac_model.clone(),
                     RepoType::Model,
@@ -213,7 +229,11 @@ impl Loader for SpeechLoader {
                 weights.push(weight);
             }
 
-            Ok(Box::new(SpeechModelPaths { weights, config }))
+            Ok(Box::new(SpeechModelPaths {
+                weights,
+                config,
+                tokenizer,
+            }))
         };
         self.load_model_from_path(
             &paths?,
@@ -247,9 +267,7 @@ impl Loader for SpeechLoader {
             anyhow::bail!("Device mapping is not supported for speech models.")
         }
 
-        mistralrs_quant::set_immediate_isq(in_situ_quant, vec![Regex::new(".*")?]);
-
-        let cfg: DiaConfig = serde_json::from_str(&std::fs::read_to_string(&paths.config)?)?;
+        mistralrs_quant::set_immediate_isq(in_situ_quant, vec![Regex::new(".*")?], Vec::new());
 
         #[cfg(feature = "cuda")]
         if let Device::Cuda(dev) = &device {
@@ -259,29 +277,97 @@ impl Loader for SpeechLoader {
         let mapper = DeviceMapSetting::dummy().into_mapper(usize::MAX, device, None)?;
         let dtype = mapper.get_min_dtype(dtype)?;
 
-        // Last weight is the dac.
-        let model_weights = paths.weights[..paths.weights.len() - 1].to_vec();
-        let vb = from_mmaped_safetensors(
-            model_weights,
-            Vec::new(),
-            Some(dtype),
-            device,
-            vec![None],
-            silent,
-            None,
-            |_| true,
-            Arc::new(|_| DeviceForLoadTensor::Base),
-        )?;
+        if self.arch.is_transcription() {
+            let cfg: WhisperConfig =
+                serde_json::from_str(&std::fs::read_to_string(&paths.config)?)?;
+            let vb = from_mmaped_safetensors(
+                paths.weights.clone(),
+                Vec::new(),
+                Some(dtype),
+                device,
+                vec![None],
+                silent,
+                None,
+                |_| true,
+                Arc::new(|_| DeviceForLoadTensor::Base),
+            )?;
+            let tokenizer = Tokenizer::from_file(
+                paths
+                    .tokenizer
+                    .as_ref()
+                    .expect("Transcription models have a tokenizer."),
+            )
+            .map_err(anyhow::Error::msg)?;
+
+            let model = WhisperPipeline::new(&cfg, vb, tokenizer)?;
+            return Ok(Arc::new(Mutex::new(TranscriptionPipeline::new(
+                self.model_id.clone(),
+                model,
+                device.clone(),
+                dtype,
+            ))));
+        }
 
-        let dac_vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(&[paths.weights.last().unwrap()], dtype, device)?
+        let model = match self.arch {
+            SpeechLoaderType::Dia => {
+                let cfg: DiaConfig =
+                    serde_json::from_str(&std::fs::read_to_string(&paths.config)?)?;
+
+                // Last weight is the dac.
+                let model_weights = paths.weights[..paths.weights.len() - 1].to_vec();
+                let vb = from_mmaped_safetensors(
+                    model_weights,
+                    Vec::new(),
+                    Some(dtype),
+                    device,
+                    vec![None],
+                    silent,
+                    None,
+                    |_| true,
+                    Arc::new(|_| DeviceForLoadTensor::Base),
+                )?;
+
+                let dac_vb = unsafe {
+                    VarBuilder::from_mmaped_safetensors(
+                        &[paths.weights.last().unwrap()],
+                        dtype,
+                        device,
+                    )?
+                };
+
+                SpeechModel::Dia(DiaPipeline::new(&cfg, vb, dac_vb)?)
+            }
+            SpeechLoaderType::Parler => {
+                let cfg: ParlerConfig =
+                    serde_json::from_str(&std::fs::read_to_string(&paths.config)?)?;
+                let vb = from_mmaped_safetensors(
+                    paths.weights.clone(),
+                    Vec::new(),
+                    Some(dtype),
+                    device,
+                    vec![None],
+                    silent,
+                    None,
+                    |_| true,
+                    Arc::new(|_| DeviceForLoadTensor::Base),
+                )?;
+                let dac_vb = unsafe {
+                    VarBuilder::from_mmaped_safetensors(&paths.weights, dtype, device)?
+                        .pp("audio_encoder.model")
+                };
+                let tokenizer = Tokenizer::from_file(
+                    paths
+                        .tokenizer
+                        .as_ref()
+                        .expect("Parler-TTS models have a tokenizer."),
+                )
+                .map_err(anyhow::Error::msg)?;
+
+                SpeechModel::Parler(ParlerPipeline::new(&cfg, vb, dac_vb, tokenizer)?)
+            }
+            SpeechLoaderType::Whisper => unreachable!("Transcription models are loaded above."),
         };
 
-        // Only Dia is supported for now.
-        assert_eq!(self.arch, SpeechLoaderType::Dia);
-
-        let model = DiaPipeline::new(&cfg, vb, dac_vb)?;
-
         Ok(Arc::new(Mutex::new(SpeechPipeline {
             model_id: self.model_id.clone(),
             model,
@@ -307,6 +393,7 @@ impl Loader for SpeechLoader {
             dummy_cache: EitherCache::Full(Cache::new(0, false)),
             cfg: self
                 .cfg
+                .clone()
                 .unwrap_or_else(|| SpeechGenerationConfig::default(self.arch)),
         })))
     }
@@ -418,6 +505,35 @@ impl Pipeline for SpeechPipeline {
     fn category(&self) -> ModelCategory {
         ModelCategory::Speech
     }
+
+    fn synthesize(
+        &mut self,
+        text: &str,
+        on_chunk: &mut dyn FnMut(AudioChunk) -> Result<()>,
+    ) -> Result<()> {
+        let chunks = match self.model {
+            // Dia generates a whole dialogue at once, with the speakers given by the tags in the
+            // text, so it is not split.
+            SpeechModel::Dia(_) => vec![text.trim()],
+            SpeechModel::Parler(_) => split_sentences(text, MIN_SENTENCE_LEN),
+        };
+        for chunk in chunks {
+            let SpeechGenerationOutput {
+                pcm,
+                rate,
+                channels,
+            } = self.model.generate(chunk, &self.cfg)?;
+            on_chunk(AudioChunk {
+                text: chunk.to_string(),
+                pcm,
+                rate,
+                channels,
+            })?;
+        }
+        Ok(())
+    }
 }
 
+impl LoraPipelineMixin for SpeechPipeline {}
+
 impl AnyMoePipelineMixin for SpeechPipeline {}
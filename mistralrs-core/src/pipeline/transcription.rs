@@ -0,0 +1,157 @@
+use super::embedding::EmbeddingProcessor;
+use super::{
+    AnyMoePipelineMixin, Cache, CacheManagerMixin, EitherCache, ForwardInputsResult,
+    GeneralMetadata, IsqPipelineMixin, LoraPipelineMixin, MetadataMixin, ModelCategory, ModelKind,
+    PreProcessingMixin, Processor,
+};
+use crate::device_map::DeviceMapper;
+use crate::pipeline::{ChatTemplate, Modalities, SupportedModality};
+use crate::prefix_cacher::PrefixCacheManagerV2;
+use crate::sequence::Sequence;
+use crate::speech_models::{TranscriptionSegment, WhisperPipeline};
+use crate::Pipeline;
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use mistralrs_quant::IsqType;
+use rand_isaac::Isaac64Rng;
+use std::any::Any;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+/// A speech-to-text model (Whisper), loaded by the [`super::SpeechLoader`].
+pub struct TranscriptionPipeline {
+    model_id: String,
+    model: WhisperPipeline,
+    device: Device,
+    metadata: Arc<GeneralMetadata>,
+    dummy_cache: EitherCache,
+}
+
+impl TranscriptionPipeline {
+    pub(super) fn new(
+        model_id: String,
+        model: WhisperPipeline,
+        device: Device,
+        dtype: DType,
+    ) -> Self {
+        Self {
+            model_id,
+            model,
+            device,
+            metadata: Arc::new(GeneralMetadata {
+                max_seq_len: 1024,
+                llg_factory: None,
+                is_xlora: false,
+                no_prefix_cache: true,
+                num_hidden_layers: 1, // FIXME(EricLBuehler): we know this is only for caching, so its OK.
+                eos_tok: vec![],
+                kind: ModelKind::Normal,
+                no_kv_cache: true, // NOTE(EricLBuehler): no cache for these.
+                activation_dtype: dtype,
+                sliding_window: None,
+                cache_config: None,
+                cache_engine: None,
+                model_metadata: None,
+                modalities: Modalities {
+                    input: vec![SupportedModality::Audio],
+                    output: vec![SupportedModality::Text],
+                },
+            }),
+            dummy_cache: EitherCache::Full(Cache::new(0, false)),
+        }
+    }
+}
+
+impl PreProcessingMixin for TranscriptionPipeline {
+    fn get_processor(&self) -> Arc<dyn Processor> {
+        Arc::new(EmbeddingProcessor)
+    }
+    fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+        None
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        None
+    }
+}
+
+impl IsqPipelineMixin for TranscriptionPipeline {
+    fn re_isq_model(&mut self, _dtype: IsqType) -> Result<()> {
+        anyhow::bail!("Transcription models do not support re-ISQ, set the ISQ type when loading.")
+    }
+}
+
+impl CacheManagerMixin for TranscriptionPipeline {
+    fn clone_in_cache(&self, _seqs: &mut [&mut Sequence]) {}
+    fn clone_out_cache(&self, _seqs: &mut [&mut Sequence]) {}
+    fn set_none_cache(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _reset_non_granular: bool,
+        _modify_draft_cache: bool,
+        _load_preallocated_cache: bool,
+    ) {
+    }
+    fn cache(&self) -> &EitherCache {
+        &self.dummy_cache
+    }
+}
+
+impl MetadataMixin for TranscriptionPipeline {
+    fn device(&self) -> Device {
+        self.device.clone()
+    }
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+    fn name(&self) -> String {
+        self.model_id.clone()
+    }
+    fn reset_non_granular_state(&self) {}
+    fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        None
+    }
+    fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for TranscriptionPipeline {
+    fn forward_inputs(
+        &mut self,
+        _inputs: Box<dyn Any>,
+        _return_raw_logits: bool,
+    ) -> candle_core::Result<ForwardInputsResult> {
+        candle_core::bail!("`forward_inputs` is incompatible with `TranscriptionPipeline`");
+    }
+
+    async fn sample_causal_gen(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _logits: Vec<Tensor>,
+        _prefix_cacher: &mut PrefixCacheManagerV2,
+        _disable_eos_stop: bool,
+        _srng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<(), candle_core::Error> {
+        candle_core::bail!("`sample_causal_gen` is incompatible with `TranscriptionPipeline`");
+    }
+
+    fn category(&self) -> ModelCategory {
+        ModelCategory::Transcription
+    }
+
+    fn transcribe(
+        &mut self,
+        samples: &[f32],
+        sample_rate: u32,
+        language: Option<&str>,
+        on_segment: &mut dyn FnMut(TranscriptionSegment) -> Result<()>,
+    ) -> Result<()> {
+        self.model
+            .transcribe(samples, sample_rate, language, on_segment)
+    }
+}
+
+impl LoraPipelineMixin for TranscriptionPipeline {}
+
+impl AnyMoePipelineMixin for TranscriptionPipeline {}
@@ -1,12 +1,12 @@
-use super::isq::ImatrixDataSource;
-use super::isq::UqffFullSer;
+use super::isq::{calibration_tokens, CalibrationData, ImatrixDataSource, MixedPrecisionIsq};
+use super::isq::{read_uqff_embedded_files, UqffFullSer};
 use super::{
-    get_model_paths, get_xlora_paths, AdapterKind, AnyMoePipelineMixin, AutoVisionLoader,
-    CacheManager, CacheManagerMixin, EitherCache, ForwardInputsResult, Gemma3Loader,
-    GeneralMetadata, IsqPipelineMixin, Loader, MetadataMixin, MiniCpmOLoader, ModelCategory,
-    ModelKind, ModelPaths, MultimodalPromptPrefixer, Phi4MMLoader, PreProcessingMixin, Processor,
-    Qwen2VLLoader, Qwen3VLLoader, TokenSource, VLlama4Loader, VLlamaLoader, VisionModel,
-    VisionModelLoader,
+    get_model_paths, get_xlora_paths, AdapterKind, AnyMoePipelineMixin, AutoDeviceMapParams,
+    AutoVisionLoader, CacheManager, CacheManagerMixin, EitherCache, ForwardInputsResult,
+    Gemma3Loader, GeneralMetadata, IsqPipelineMixin, Loader, LoraPipelineMixin, MemoryEstimate,
+    MetadataMixin, MiniCpmOLoader, ModelCategory, ModelKind, ModelPaths, MultimodalPromptPrefixer,
+    Phi4MMLoader, PreProcessingMixin, Processor, Qwen2VLLoader, Qwen3VLLoader, TokenSource,
+    VLlama4Loader, VLlamaLoader, VisionModel, VisionModelLoader,
 };
 use super::{
     Gemma3nLoader, Idefics2Loader, Idefics3Loader, LLaVALoader, LLaVANextLoader, Mistral3Loader,
@@ -16,12 +16,18 @@ use crate::attention::ATTENTION_CHUNK_SIZE;
 use crate::device_map::{self, DeviceMapper};
 use crate::distributed::{self, WorkerTransferData};
 use crate::kv_cache::{FullCacheManager, NormalCacheManager};
+use crate::matformer::{MatformerConfig, MatformerSliceConfig};
 use crate::paged_attention::{calculate_cache_config, AttentionImplementation, CacheEngine};
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
+use crate::pipeline::in_memory::{read_model_file_to_string, InMemoryFiles};
 use crate::pipeline::llg::build_llg_factory;
 use crate::pipeline::loaders::auto_device_map;
-use crate::pipeline::loaders::QuantizationConfigShim;
+use crate::pipeline::loaders::{
+    apply_sliding_window_override, normalize_quantization_config, QuantizationConfigShim,
+    SlidingWindowOverride,
+};
 use crate::pipeline::sampling::sample_and_add_toks;
+use crate::pipeline::shared_weights::{SharedWeights, SharedWeightsKey};
 use crate::pipeline::text_models_inputs_processor::make_prompt_chunk;
 use crate::pipeline::{get_chat_template, ChatTemplate, IsqOrganization, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManagerV2;
@@ -33,9 +39,10 @@ use crate::vision_models::preprocessor_config::PreProcessorConfig;
 use crate::vision_models::processor_config::ProcessorConfig;
 use crate::vision_models::ModelInputs;
 use crate::{
-    api_dir_list, api_get_file, get_paths, get_uqff_paths, vision_normal_model_loader,
-    vision_normal_model_loader_sharded, AnyMoeExpertType, DeviceMapSetting, Ordering,
-    PagedAttentionConfig, Pipeline, Topology, TryIntoDType, GLOBAL_HF_CACHE,
+    api_dir_list, api_get_file, get_config_path, get_paths, get_uqff_paths,
+    vision_lora_model_loader, vision_normal_model_loader, vision_normal_model_loader_sharded,
+    AnyMoeExpertType, DeviceMapSetting, Ordering, PagedAttentionConfig, Pipeline, Topology,
+    TryIntoDType, GLOBAL_HF_CACHE,
 };
 use anyhow::Result;
 use candle_core::{Device, Tensor, Var};
@@ -43,16 +50,16 @@ use hf_hub::Cache;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use indicatif::MultiProgress;
 use mistralrs_quant::log::once_log_info;
-use mistralrs_quant::{AfqLayer, GgufMatMul, HqqLayer, IsqType, QuantizedSerdeType};
+use mistralrs_quant::{IsqType, QuantMethod};
 use rand_isaac::Isaac64Rng;
 use regex_automata::meta::Regex;
 use std::any::Any;
-use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use std::time::Instant;
-use std::{env, fs};
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
 use tracing::{info, warn};
@@ -77,6 +84,8 @@ pub struct VisionPipeline {
     processor_filename: Option<PathBuf>,
     preprocessor_filename: Option<PathBuf>,
     imatrix: Option<PathBuf>,
+    // The unquantized layers, kept on the CPU for requantizing
+    original_layers: Option<Vec<Arc<dyn QuantMethod>>>,
 }
 
 /// A loader for a vision (non-quantized) model.
@@ -89,12 +98,15 @@ pub struct VisionLoader {
     tokenizer_json: Option<String>,
     xlora_model_id: Option<String>,
     xlora_order: Option<Ordering>,
+    xlora_classifier_path: Option<PathBuf>,
     token_source: RwLock<Option<TokenSource>>,
     revision: RwLock<Option<String>>,
     from_uqff: RwLock<Option<Vec<PathBuf>>>,
     jinja_explicit: Option<String>,
     hf_cache_path: Option<PathBuf>,
     lora_adapter_ids: Option<Vec<String>>,
+    lora_adapter_scales: HashMap<String, f64>,
+    shared_weights: Option<SharedWeights>,
 }
 
 #[derive(Default)]
@@ -108,6 +120,8 @@ pub struct VisionLoaderBuilder {
     jinja_explicit: Option<String>,
     hf_cache_path: Option<PathBuf>,
     lora_adapter_ids: Option<Vec<String>>,
+    lora_adapter_scales: HashMap<String, f64>,
+    shared_weights: Option<SharedWeights>,
 }
 
 #[derive(Clone, Default)]
@@ -119,9 +133,18 @@ pub struct VisionSpecificConfig {
     pub max_edge: Option<u32>,
     pub imatrix: Option<PathBuf>,
     pub calibration_file: Option<PathBuf>,
+    /// Calibration data given in memory, used instead of a calibration file.
+    pub calibration_data: Option<CalibrationData>,
+    /// Quantize the most sensitive layers to a higher precision type within a memory budget.
+    pub mixed_precision_isq: Option<MixedPrecisionIsq>,
     pub hf_cache_path: Option<PathBuf>,
     pub matformer_config_path: Option<PathBuf>,
     pub matformer_slice_name: Option<String>,
+    /// Regexes of the tensor names (such as `lm_head.weight`) which are kept at full precision
+    /// when applying ISQ.
+    pub isq_skip: Vec<String>,
+    /// Override the sliding window attention of the model config.
+    pub sliding_window: Option<SlidingWindowOverride>,
 }
 
 impl VisionLoaderBuilder {
@@ -149,6 +172,13 @@ impl VisionLoaderBuilder {
         self
     }
 
+    /// Reuse the weights of other pipelines of the same model, revision and dtype loaded with
+    /// this cache instead of loading them again.
+    pub fn with_shared_weights(mut self, shared_weights: SharedWeights) -> Self {
+        self.shared_weights = Some(shared_weights);
+        self
+    }
+
     pub fn with_lora(mut self, lora_adapter_ids: Vec<String>) -> Self {
         self.kind = ModelKind::Adapter {
             adapter: AdapterKind::Lora,
@@ -157,6 +187,12 @@ impl VisionLoaderBuilder {
         self
     }
 
+    /// Override the `lora_alpha / r` scale of some of the LoRA adapters, keyed by adapter ID.
+    pub fn with_lora_scales(mut self, lora_adapter_scales: HashMap<String, f64>) -> Self {
+        self.lora_adapter_scales = lora_adapter_scales;
+        self
+    }
+
     pub fn build(self, loader: Option<VisionLoaderType>) -> Box<dyn Loader> {
         let loader: Box<dyn VisionModelLoader> = match loader {
             Some(VisionLoaderType::Phi3V) => Box::new(Phi3VLoader),
@@ -185,16 +221,40 @@ impl VisionLoaderBuilder {
             tokenizer_json: self.tokenizer_json,
             xlora_model_id: None,
             xlora_order: None,
+            xlora_classifier_path: None,
             jinja_explicit: self.jinja_explicit,
             token_source: RwLock::new(None),
             revision: RwLock::new(None),
             from_uqff: RwLock::new(None),
             hf_cache_path: self.hf_cache_path,
             lora_adapter_ids: self.lora_adapter_ids,
+            lora_adapter_scales: self.lora_adapter_scales,
+            shared_weights: self.shared_weights,
         })
     }
 }
 
+impl VisionLoader {
+    /// Load the Matformer slicing config, if one was provided.
+    fn matformer_slicing_config(&self) -> Result<Option<MatformerSliceConfig>> {
+        let Some(matformer_path) = &self.config.matformer_config_path else {
+            return Ok(None);
+        };
+        info!("Loading Matformer config from {:?}", matformer_path);
+        let config = Arc::new(MatformerConfig::from_file(matformer_path)?);
+
+        if let Some(slice_name) = &self.config.matformer_slice_name {
+            info!("Using Matformer slice: {}", slice_name);
+            Ok(Some(MatformerSliceConfig::new(slice_name.clone(), config)))
+        } else {
+            // If no slice name is provided but config exists, we'll need to handle this
+            // For now, return None and let the model handle the default slice selection
+            warn!("Matformer config loaded but no slice name specified. Models will use their default slice.");
+            Ok(None)
+        }
+    }
+}
+
 impl Loader for VisionLoader {
     #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     fn load_model_from_hf(
@@ -215,6 +275,14 @@ impl Loader for VisionLoader {
             .unwrap_or_default();
         GLOBAL_HF_CACHE.get_or_init(|| cache);
 
+        let uqff_files = if let Some(from_uqff) = self.config.from_uqff.clone() {
+            let from_uqff = get_uqff_paths!(&from_uqff, self, silent);
+            let embedded = InMemoryFiles::register(read_uqff_embedded_files(&from_uqff)?);
+            *self.from_uqff.write().unwrap() = Some(from_uqff);
+            Some(embedded)
+        } else {
+            None
+        };
         let paths: anyhow::Result<Box<dyn ModelPaths>> = get_paths!(
             LocalModelPaths,
             &token_source,
@@ -223,11 +291,9 @@ impl Loader for VisionLoader {
             None,
             None,
             silent,
-            self.config.from_uqff.is_some()
+            self.config.from_uqff.is_some(),
+            uqff_files.as_ref()
         );
-        if let Some(from_uqff) = self.config.from_uqff.clone() {
-            *self.from_uqff.write().unwrap() = Some(get_uqff_paths!(&from_uqff, self, silent));
-        }
         *self
             .token_source
             .write()
@@ -255,7 +321,9 @@ impl Loader for VisionLoader {
         mut in_situ_quant: Option<IsqType>,
         mut paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
-        let config = std::fs::read_to_string(paths.get_config_filename())?;
+        let config =
+            normalize_quantization_config(read_model_file_to_string(paths.get_config_filename())?)?;
+        let config = apply_sliding_window_override(config, self.config.sliding_window)?;
 
         if !self.inner.supports_paged_attention(&config) {
             paged_attn_config = None;
@@ -286,26 +354,7 @@ impl Loader for VisionLoader {
             device.clone()
         };
 
-        // Load matformer slicing config if provided
-        let matformer_slicing_config = if let Some(matformer_path) =
-            &self.config.matformer_config_path
-        {
-            use crate::matformer::{MatformerConfig, MatformerSliceConfig};
-            info!("Loading Matformer config from {:?}", matformer_path);
-            let config = Arc::new(MatformerConfig::from_file(matformer_path)?);
-
-            if let Some(slice_name) = &self.config.matformer_slice_name {
-                info!("Using Matformer slice: {}", slice_name);
-                Some(MatformerSliceConfig::new(slice_name.clone(), config))
-            } else {
-                // If no slice name is provided but config exists, we'll need to handle this
-                // For now, return None and let the model handle the default slice selection
-                warn!("Matformer config loaded but no slice name specified. Models will use their default slice.");
-                None
-            }
-        } else {
-            None
-        };
+        let matformer_slicing_config = self.matformer_slicing_config()?;
 
         // If auto, convert to Map if not using nccl
         if use_nccl {
@@ -326,102 +375,20 @@ impl Loader for VisionLoader {
 
             // ISQ or UQFF: quantized path
             // Match logic below where UQFF has priority
+            let weight_pack_factor = auto_device_map::weight_pack_factor(
+                &config,
+                dtype,
+                in_situ_quant,
+                self.from_uqff.read().unwrap().as_deref(),
+            )?;
             let (layer_sizes_in_bytes, non_mapped_size_in_bytes, total_model_size_in_bytes) =
-                if let Some(serialized) = &*self.from_uqff.read().unwrap() {
-                    let weight_pack_factor = {
-                        let ser_artifacts = unsafe {
-                            candle_core::safetensors::MmapedSafetensors::multi(serialized)?
-                        };
-                        let mut total_pack_factors = 0;
-                        let total_tensors = ser_artifacts.tensors().len();
-                        for (_, artifact) in ser_artifacts.tensors() {
-                            let artifact = artifact.data();
-                            // NOTE(EricLBuehler): isq type is ALWAYS byte 4 (5th) of the tensor.
-                            let isq_type = artifact[mistralrs_quant::UQFF_QUANT_TYPE_OFFSET];
-                            let pack_factor = match QuantizedSerdeType::try_from(isq_type as usize)?
-                            {
-                                QuantizedSerdeType::Hqq => {
-                                    HqqLayer::get_isq_type_from_uqff(Cow::Borrowed(artifact))?
-                                        .pack_factor(dtype)
-                                }
-                                QuantizedSerdeType::Gguf => {
-                                    GgufMatMul::get_isq_type_from_uqff(Cow::Borrowed(artifact))?
-                                        .pack_factor(dtype)
-                                }
-                                QuantizedSerdeType::Fp8 => IsqType::F8E4M3.pack_factor(dtype),
-                                QuantizedSerdeType::Unquant => 1,
-                                QuantizedSerdeType::Afq => {
-                                    AfqLayer::get_isq_type_from_uqff(Cow::Borrowed(artifact))?
-                                        .pack_factor(dtype)
-                                }
-                            };
-                            total_pack_factors += pack_factor;
-                        }
-
-                        total_pack_factors / total_tensors
-                    };
-
-                    let layer_sizes_in_bytes = self.inner.layer_sizes_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        matformer_slicing_config.as_ref(),
-                    )?;
-                    let non_mapped_size_in_bytes = self.inner.non_mapped_size_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        matformer_slicing_config.as_ref(),
-                    )?;
-                    let layer_sizes_sum = layer_sizes_in_bytes.iter().sum::<usize>();
-                    (
-                        layer_sizes_in_bytes,
-                        non_mapped_size_in_bytes,
-                        layer_sizes_sum + non_mapped_size_in_bytes,
-                    )
-                } else if let Some(isq) = in_situ_quant {
-                    let weight_pack_factor = isq.pack_factor(dtype);
-                    let layer_sizes_in_bytes = self.inner.layer_sizes_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        matformer_slicing_config.as_ref(),
-                    )?;
-                    let non_mapped_size_in_bytes = self.inner.non_mapped_size_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        matformer_slicing_config.as_ref(),
-                    )?;
-                    let layer_sizes_sum = layer_sizes_in_bytes.iter().sum::<usize>();
-                    (
-                        layer_sizes_in_bytes,
-                        non_mapped_size_in_bytes,
-                        layer_sizes_sum + non_mapped_size_in_bytes,
-                    )
-                } else {
-                    // Be sure to get the weight pack factor here; we might be loading a prequantized model.
-                    let weight_pack_factor =
-                        QuantizationConfigShim::get_quant_config_pack_factor(&config, dtype)?;
-                    let layer_sizes_in_bytes = self.inner.layer_sizes_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        matformer_slicing_config.as_ref(),
-                    )?;
-                    let non_mapped_size_in_bytes = self.inner.non_mapped_size_in_bytes(
-                        &config,
-                        dtype,
-                        weight_pack_factor,
-                        matformer_slicing_config.as_ref(),
-                    )?;
-                    let layer_sizes_sum = layer_sizes_in_bytes.iter().sum::<usize>();
-                    (
-                        layer_sizes_in_bytes,
-                        non_mapped_size_in_bytes,
-                        layer_sizes_sum + non_mapped_size_in_bytes,
-                    )
-                };
+                auto_device_map::model_sizes_in_bytes(
+                    &*self.inner,
+                    &config,
+                    dtype,
+                    weight_pack_factor,
+                    matformer_slicing_config.as_ref(),
+                )?;
 
             let new = auto_device_map::get_device_layers(
                 &*self.inner,
@@ -468,10 +435,25 @@ impl Loader for VisionLoader {
             once_log_info("FlashAttention is enabled.");
         }
 
+        // Layer names are only known while loading, so skipping layers requires immediate ISQ.
+        let calibrating =
+            self.config.calibration_file.is_some() || self.config.calibration_data.is_some();
+        let isq_skip = self
+            .config
+            .isq_skip
+            .iter()
+            .map(|skip| regex::Regex::new(skip))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if !isq_skip.is_empty()
+            && (self.config.imatrix.is_some() || calibrating || self.config.write_uqff.is_some())
+        {
+            anyhow::bail!("ISQ skip patterns cannot be used with an imatrix, a calibration file or when writing UQFF.");
+        }
+
         // Logic for ISQ here: if no calibration (i.e imatrix), then allow immediate ISQ. Otherwise, back to normal.
         let mut loading_isq = if self.config.imatrix.is_none()
-            && self.config.calibration_file.is_none()
-            && !device.is_cuda()
+            && !calibrating
+            && (!device.is_cuda() || !isq_skip.is_empty())
             && self.config.write_uqff.is_none()
             && in_situ_quant.is_some()
         {
@@ -480,7 +462,7 @@ impl Loader for VisionLoader {
             if predicates.is_empty() {
                 warn!("No predicates for this model and ISQ setting detected. ISQ will not be applied to any weights!");
             }
-            mistralrs_quant::set_immediate_isq(in_situ_quant, predicates);
+            mistralrs_quant::set_immediate_isq(in_situ_quant, predicates, isq_skip);
             false
         } else {
             in_situ_quant.is_some()
@@ -493,14 +475,31 @@ impl Loader for VisionLoader {
                 .any(|layer| layer.as_ref().is_some_and(|layer| layer.isq.is_some()));
         }
 
-        if self.config.imatrix.is_some() && self.config.calibration_file.is_some() {
+        if self.config.imatrix.is_some() && calibrating {
             anyhow::bail!(
-                "`imatrix` and `calibration_file` were both specified, this is not allowed."
+                "`imatrix` and a calibration file or calibration data were both specified, this is not allowed."
+            );
+        }
+        if self.config.calibration_file.is_some() && self.config.calibration_data.is_some() {
+            anyhow::bail!(
+                "`calibration_file` and `calibration_data` were both specified, this is not allowed."
+            );
+        }
+        if self.config.mixed_precision_isq.is_some()
+            && !calibrating
+            && self.config.imatrix.is_none()
+        {
+            anyhow::bail!(
+                "Mixed precision ISQ requires a calibration file, calibration data or an imatrix."
             );
         }
 
+        if self.kind.is_adapted() && self.config.from_uqff.is_some() {
+            anyhow::bail!("LoRA adapters cannot be combined with UQFF for vision models.");
+        }
+
         // Load onto the regular device if not using isq or if the calibration file is specified
-        let load_device = if !loading_isq || self.config.calibration_file.is_some() {
+        let load_device = if !loading_isq || calibrating {
             loading_isq = false;
             device.clone()
         } else {
@@ -515,6 +514,24 @@ impl Loader for VisionLoader {
 
         let multi_progress = Arc::new(MultiProgress::new());
 
+        let share_weights = match &self.shared_weights {
+            Some(shared_weights) => {
+                // Layers quantized with ISQ get new weights, so they are not shared
+                let exclude = if loading_isq || in_situ_quant.is_some() {
+                    Some(self.inner.isq_layer_regexes(&config)?)
+                } else {
+                    None
+                };
+                let key = SharedWeightsKey {
+                    model_id: self.model_id.clone(),
+                    revision: self.revision.read().unwrap().clone(),
+                    dtype,
+                };
+                Some(move |vb| shared_weights.wrap(key, vb, exclude))
+            }
+            None => None,
+        };
+
         let mut model = if use_nccl {
             let (mapper, sharded_vb) = distributed::prepare_distributed_mapper(
                 dtype,
@@ -542,6 +559,24 @@ impl Loader for VisionLoader {
                     multi_progress.clone(),
                     matformer_slicing_config.clone(),
                 ),
+                ModelKind::Adapter {
+                    adapter: AdapterKind::Lora,
+                } => vision_lora_model_loader!(
+                    paths,
+                    Some(dtype),
+                    &load_device,
+                    layer_devices.clone(),
+                    config,
+                    self.inner,
+                    silent,
+                    mapper,
+                    loading_isq,
+                    device.clone(),
+                    attention_mechanism,
+                    multi_progress.clone(),
+                    matformer_slicing_config.clone(),
+                    share_weights,
+                ),
                 _ => unreachable!(),
             }
         } else {
@@ -561,6 +596,25 @@ impl Loader for VisionLoader {
                     attention_mechanism,
                     multi_progress,
                     matformer_slicing_config.clone(),
+                    share_weights,
+                ),
+                ModelKind::Adapter {
+                    adapter: AdapterKind::Lora,
+                } => vision_lora_model_loader!(
+                    paths,
+                    Some(dtype),
+                    &load_device,
+                    layer_devices.clone(),
+                    config,
+                    self.inner,
+                    silent,
+                    mapper,
+                    loading_isq,
+                    device.clone(),
+                    attention_mechanism,
+                    multi_progress,
+                    matformer_slicing_config.clone(),
+                    share_weights,
                 ),
                 _ => unreachable!(),
             }
@@ -570,14 +624,15 @@ impl Loader for VisionLoader {
         let preprocessor_config: PreProcessorConfig = match paths.get_preprocessor_config().as_ref()
         {
             Some(preprocessor_config) => {
-                serde_json::from_str(&fs::read_to_string(preprocessor_config).unwrap()).unwrap()
+                serde_json::from_str(&read_model_file_to_string(preprocessor_config).unwrap())
+                    .unwrap()
             }
             None => PreProcessorConfig::default(),
         };
         let processor_config: Option<ProcessorConfig> = paths
             .get_processor_config()
             .as_ref()
-            .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
+            .map(|f| serde_json::from_str(&read_model_file_to_string(f).unwrap()).unwrap());
 
         let processor = self.inner.get_processor(
             &config,
@@ -593,7 +648,7 @@ impl Loader for VisionLoader {
 
         let gen_conf: Option<GenerationConfig> = paths
             .get_gen_conf_filename()
-            .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
+            .map(|f| serde_json::from_str(&read_model_file_to_string(f).unwrap()).unwrap());
         let chat_template_explicit = paths
             .get_chat_template_explicit()
             .as_ref()
@@ -606,17 +661,13 @@ impl Loader for VisionLoader {
             None,
         );
 
-        if let Some(calibration_file) = &self.config.calibration_file {
-            let calibration_data = std::fs::read_to_string(calibration_file)?;
-            // Tokenize, don't add bos yet
-            let tokens = tokenizer
-                .encode_fast(calibration_data, false)
-                .map_err(anyhow::Error::msg)?
-                .get_ids()
-                .to_vec();
+        if let Some((tokens, source)) = calibration_tokens(
+            self.config.calibration_file.as_ref(),
+            self.config.calibration_data.as_ref(),
+            &tokenizer,
+        )? {
             info!(
-                "Collecting imatrix from calibration file `{}` of {} tokens.",
-                calibration_file.display(),
+                "Collecting imatrix from {source} of {} tokens.",
                 tokens.len()
             );
             let bos_toks = chat_template.bos_tok().map(|b| vec![b]).unwrap_or_default();
@@ -685,10 +736,7 @@ impl Loader for VisionLoader {
 
         // Only if loading from UQFF
         if (loading_isq || self.config.topology.is_some()) && self.config.from_uqff.is_none() {
-            let imatrix_source = match (
-                self.config.imatrix.as_ref(),
-                self.config.calibration_file.is_some(),
-            ) {
+            let imatrix_source = match (self.config.imatrix.as_ref(), calibrating) {
                 (None, false) => None,
                 (Some(file), false) => Some(ImatrixDataSource::File(file)),
                 (None, true) => Some(ImatrixDataSource::Collected),
@@ -700,6 +748,7 @@ impl Loader for VisionLoader {
                 self.config.topology.as_ref(),
                 silent,
                 imatrix_source,
+                self.config.mixed_precision_isq,
                 IsqOrganization::Default,
                 self.config.write_uqff.as_ref(),
                 UqffFullSer {
@@ -728,6 +777,9 @@ impl Loader for VisionLoader {
             );
             let cache_config = calculate_cache_config(
                 paged_attn_config.mem_gpu,
+                paged_attn_config.swap_space,
+                paged_attn_config.host_offload_bytes,
+                paged_attn_config.watermark,
                 paged_attn_config.block_size,
                 dtype,
                 paged_attn_config.cache_type,
@@ -785,9 +837,68 @@ impl Loader for VisionLoader {
             preprocessor_filename: paths.get_preprocessor_config().clone(),
             mapper: pipeline_mapper,
             imatrix: self.config.imatrix.clone(),
+            original_layers: None,
         })))
     }
 
+    #[allow(clippy::too_many_arguments)]
+    fn estimate_memory_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<MemoryEstimate> {
+        let cache = self
+            .hf_cache_path
+            .clone()
+            .map(Cache::new)
+            .unwrap_or_default();
+        GLOBAL_HF_CACHE.get_or_init(|| cache);
+
+        let config_filename = get_config_path!(&token_source, revision.clone(), self, silent);
+        *self
+            .token_source
+            .write()
+            .expect("Failed to write to token source") = Some(token_source);
+        *self.revision.write().expect("Failed to write to revision") = revision;
+        let from_uqff = match &self.config.from_uqff {
+            Some(from_uqff) => Some(get_uqff_paths!(from_uqff, self, silent)),
+            None => None,
+        };
+
+        let config = normalize_quantization_config(std::fs::read_to_string(config_filename)?)?;
+        let paged_attn_config = if self.inner.supports_paged_attention(&config) {
+            paged_attn_config
+        } else {
+            None
+        };
+        let matformer_slicing_config = self.matformer_slicing_config()?;
+
+        // Activations and the KV cache of a manual device map are sized by the default params.
+        let params = match &mapper {
+            DeviceMapSetting::Auto(params) => params.maybe_promote_to_vision(),
+            _ => AutoDeviceMapParams::default_vision(),
+        };
+        auto_device_map::estimate_memory(
+            &*self.inner,
+            &config,
+            dtype,
+            device,
+            mapper,
+            in_situ_quant,
+            paged_attn_config.as_ref(),
+            from_uqff.as_deref(),
+            matformer_slicing_config.as_ref(),
+            self.config.topology.as_ref(),
+            params,
+        )
+    }
+
     fn get_id(&self) -> String {
         self.model_id.to_string()
     }
@@ -819,6 +930,7 @@ impl IsqPipelineMixin for VisionPipeline {
                 self.topology.as_ref(),
                 self.silent,
                 self.imatrix.as_ref().map(ImatrixDataSource::File),
+                None,
                 IsqOrganization::Default,
                 None,
                 UqffFullSer {
@@ -833,6 +945,21 @@ impl IsqPipelineMixin for VisionPipeline {
             )
             .map_err(anyhow::Error::msg)
     }
+
+    fn requantize_model(&mut self, dtype: IsqType) -> Result<()> {
+        if self.original_layers.is_none() {
+            self.original_layers = self
+                .model
+                .offload_unquantized_layers(IsqOrganization::Default)?;
+        }
+        let Some(originals) = &self.original_layers else {
+            warn!("The original weights of the model are not available because it was quantized when loaded, requantizing the quantized weights instead.");
+            return self.re_isq_model(dtype);
+        };
+        self.model
+            .restore_layers(IsqOrganization::Default, originals)?;
+        self.re_isq_model(dtype)
+    }
 }
 
 impl CacheManagerMixin for VisionPipeline {
@@ -959,6 +1086,8 @@ impl Pipeline for VisionPipeline {
     }
 }
 
+impl LoraPipelineMixin for VisionPipeline {}
+
 impl AnyMoePipelineMixin for VisionPipeline {
     fn amoe_finish_training(&mut self, gate_model_id: Option<String>) -> candle_core::Result<()> {
         self.model.finish_training(gate_model_id)
@@ -1,39 +1,25 @@
 use std::{
+    collections::HashMap,
+    fs,
     hash::{DefaultHasher, Hash, Hasher},
+    path::PathBuf,
     sync::Arc,
 };
 
-use candle_core::{Device, Result};
+use candle_core::{safetensors, Device, Result, Tensor};
 use indexmap::IndexMap;
 use itertools::Itertools;
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 
 use crate::{
     get_mut_arcmutex,
-    paged_attention::{BlockEngine, LogicalTokenBlock, PhysicalTokenBlock},
-    pipeline::KvCache,
+    kv_cache::SingleCache,
+    paged_attention::{BlockEngine, BlockEngineSequence, LogicalTokenBlock, PhysicalTokenBlock},
+    pipeline::{KvCache, Pipeline},
     sequence::{self, Sequence},
 };
 
-type BlockBestMatch<'a> = (
-    usize,                         // matched_len
-    &'a [LogicalTokenBlock],       // logical blocks
-    &'a [Arc<PhysicalTokenBlock>], // physical blocks
-    usize,                         // audios_match_until
-    usize,                         // images_match_until
-);
-
-fn hash_logical_blocks(logical_blocks: &[LogicalTokenBlock]) -> Vec<u64> {
-    logical_blocks
-        .iter()
-        .map(|block| {
-            let mut hasher = DefaultHasher::new();
-            block.hash(&mut hasher);
-            hasher.finish()
-        })
-        .collect::<Vec<_>>()
-}
-
 #[derive(PartialEq, Eq, Debug, Hash)]
 struct Tokens(Vec<u32>);
 
@@ -59,22 +45,173 @@ struct CacheElement {
     cache: Vec<Option<KvCache>>,
     audio_hashes: Option<Vec<u64>>,
     image_hashes: Option<Vec<u64>>,
+    /// The request of the sequence, for [`PrefixCacheManagerV2::snapshot_session`].
+    request_id: Option<usize>,
 }
 
-#[derive(Clone)]
-struct BlockCacheElement {
-    logical_blocks: Vec<LogicalTokenBlock>,
-    physical_blocks: Vec<Arc<PhysicalTokenBlock>>,
-    image_hashes: Option<Vec<u64>>,
-    audio_hashes: Option<Vec<u64>>,
+/// The KV cache of a conversation, taken with `Model::snapshot_session` and restored with
+/// `Model::resume_session`, possibly by another instance of the same model. It is stored in the
+/// safetensors format.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionBlob {
+    data: Vec<u8>,
+}
+
+impl SessionBlob {
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// The directory the prefix caches are persisted in across restarts.
+struct PersistDir {
+    dir: PathBuf,
+    /// Identifies the model, see [`model_fingerprint`]. The caches of other models in `dir` are
+    /// ignored.
+    fingerprint: u64,
+}
+
+impl PersistDir {
+    fn path(&self, toks: &Tokens) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        toks.hash(&mut hasher);
+        self.dir.join(format!(
+            "{:016x}-{:016x}.safetensors",
+            self.fingerprint,
+            hasher.finish()
+        ))
+    }
+
+    /// The persisted caches of the model.
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let prefix = format!("{:016x}-", self.fingerprint);
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir).map_err(candle_core::Error::wrap)? {
+            let path = entry.map_err(candle_core::Error::wrap)?.path();
+            if path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".safetensors"))
+            {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Identifies the model and the settings which determine its KV cache, so that persisted prefix
+/// caches are only loaded by the model which wrote them.
+pub(crate) fn model_fingerprint(pipeline: &dyn Pipeline) -> u64 {
+    let metadata = pipeline.get_metadata();
+    let mut hasher = DefaultHasher::new();
+    pipeline.name().hash(&mut hasher);
+    metadata.kind.to_string().hash(&mut hasher);
+    format!("{:?}", metadata.activation_dtype).hash(&mut hasher);
+    metadata.num_hidden_layers.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The tensors which persist the cache of `toks`, or `None` if a layer is not a normal KV cache.
+/// Only the tokens whose keys and values are in the cache are kept, as the last sampled token of
+/// a sequence is not.
+fn cache_tensors(
+    toks: &Tokens,
+    cache: &[Option<KvCache>],
+) -> Result<Option<HashMap<String, Tensor>>> {
+    let mut tensors = HashMap::new();
+    let mut cached_len = toks.0.len();
+    for (i, layer) in cache.iter().enumerate() {
+        match layer {
+            Some(KvCache::Normal { k, v }) => {
+                if let (Some(k_data), Some(v_data)) = (k.current_data()?, v.current_data()?) {
+                    cached_len = cached_len.min(k_data.dim(k.dim)?);
+                    tensors.insert(format!("{i}.k"), k_data.contiguous()?);
+                    tensors.insert(format!("{i}.v"), v_data.contiguous()?);
+                }
+            }
+            Some(KvCache::Rotating { .. } | KvCache::H2o(_)) => return Ok(None),
+            None => (),
+        }
+    }
+    tensors.insert(
+        "tokens".to_string(),
+        Tensor::new(&toks.0[..cached_len], &Device::Cpu)?,
+    );
+    Ok(Some(tensors))
+}
+
+/// The tokens and KV cache stored in `tensors` by [`cache_tensors`], with the settings of the
+/// layers of `template`. Returns `None` if the layers do not match.
+fn cache_from_tensors(
+    tensors: &HashMap<String, Tensor>,
+    template: &[KvCache],
+) -> Result<Option<(Vec<u32>, Vec<Option<KvCache>>)>> {
+    let Some(toks) = tensors.get("tokens") else {
+        return Ok(None);
+    };
+    let toks = toks.to_vec1::<u32>()?;
+
+    let mut cache = Vec::with_capacity(template.len());
+    for (i, layer) in template.iter().enumerate() {
+        let data = (
+            tensors.get(&format!("{i}.k")),
+            tensors.get(&format!("{i}.v")),
+        );
+        match (layer, data) {
+            (KvCache::Normal { k, v }, (Some(k_data), Some(v_data))) => {
+                let k = restore_cache(k, k_data, toks.len())?;
+                let v = restore_cache(v, v_data, toks.len())?;
+                cache.push(Some(KvCache::Normal { k, v }));
+            }
+            (_, (None, None)) => {
+                let mut layer = layer.clone();
+                layer.reset();
+                cache.push(Some(layer));
+            }
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some((toks, cache)))
+}
+
+/// A cache with the settings of `template` holding the first `len` tokens of `data`.
+fn restore_cache(template: &SingleCache, data: &Tensor, len: usize) -> Result<SingleCache> {
+    let data_len = data.dim(template.dim)?;
+    if data_len < len {
+        candle_core::bail!("The KV cache holds {data_len} tokens, expected {len}.");
+    }
+    let data = if data_len > len {
+        data.narrow(template.dim, 0, len)?.contiguous()?
+    } else {
+        data.clone()
+    };
+    Ok(SingleCache {
+        all_data: Some(data),
+        dim: template.dim,
+        current_seq_len: len,
+        capacity_seq_len: len,
+        max_seq_len: template.max_seq_len,
+    })
 }
 
 pub struct PrefixCacheManagerV2 {
     caches: IndexMap<Tokens, CacheElement>,
-    block_caches: IndexMap<Vec<u64>, BlockCacheElement>, // (hashed logical blocks) => BlockCacheElement
     n_on_device: usize,
     no_prefix_cache: bool,
     block_engine: Option<Arc<tokio::sync::Mutex<BlockEngine>>>,
+    persist_dir: Option<PersistDir>,
 }
 
 #[derive(Clone)]
@@ -107,13 +244,163 @@ impl PrefixCacheManagerV2 {
         }
         PrefixCacheManagerV2 {
             caches: IndexMap::new(),
-            block_caches: IndexMap::new(),
             n_on_device,
             no_prefix_cache,
             block_engine,
+            persist_dir: None,
         }
     }
 
+    /// Persist the caches in `dir` with [`PrefixCacheManagerV2::save`], and load them with
+    /// [`PrefixCacheManagerV2::load`]. `fingerprint` identifies the model, see
+    /// [`model_fingerprint`].
+    pub(crate) fn persist_in(&mut self, dir: PathBuf, fingerprint: u64) {
+        self.persist_dir = Some(PersistDir { dir, fingerprint });
+    }
+
+    /// Load the caches persisted by an earlier run of the model onto `device`, keeping the most
+    /// recent ones up to the number of caches kept on the device. `template` is the KV cache of the
+    /// model, whose layers give the settings of the loaded caches. Returns the number of caches
+    /// which were loaded.
+    pub(crate) fn load(&mut self, template: &[KvCache], device: &Device) -> Result<usize> {
+        let Some(persist_dir) = &self.persist_dir else {
+            return Ok(0);
+        };
+        if self.no_prefix_cache || self.block_engine.is_some() {
+            return Ok(0);
+        }
+
+        let mut files = persist_dir
+            .files()?
+            .into_iter()
+            .map(|path| (fs::metadata(&path).and_then(|m| m.modified()).ok(), path))
+            .collect::<Vec<_>>();
+        files.sort();
+        let start = files.len().saturating_sub(self.n_on_device);
+
+        let mut n_loaded = 0;
+        for (_, path) in &files[start..] {
+            let tensors = safetensors::load(path, device)?;
+            let Some((toks, cache)) = cache_from_tensors(&tensors, template)? else {
+                warn!(
+                    "Ignoring the prefix cache `{}`, which does not match the model.",
+                    path.display()
+                );
+                continue;
+            };
+            self.caches.insert(
+                toks.into(),
+                CacheElement {
+                    cache,
+                    image_hashes: None,
+                    audio_hashes: None,
+                    request_id: None,
+                },
+            );
+            n_loaded += 1;
+        }
+        Ok(n_loaded)
+    }
+
+    /// Write the caches of text-only sequences to the persist directory, replacing the caches
+    /// written by earlier runs of the model. Returns the number of caches which were written.
+    pub(crate) fn save(&self) -> Result<usize> {
+        let Some(persist_dir) = &self.persist_dir else {
+            return Ok(0);
+        };
+        if self.no_prefix_cache || self.block_engine.is_some() {
+            return Ok(0);
+        }
+
+        fs::create_dir_all(&persist_dir.dir).map_err(candle_core::Error::wrap)?;
+        for path in persist_dir.files()? {
+            fs::remove_file(path).map_err(candle_core::Error::wrap)?;
+        }
+
+        let mut n_saved = 0;
+        for (toks, element) in &self.caches {
+            if element.image_hashes.is_some() || element.audio_hashes.is_some() {
+                continue;
+            }
+            let Some(tensors) = cache_tensors(toks, &element.cache)? else {
+                continue;
+            };
+            safetensors::save(&tensors, persist_dir.path(toks))?;
+            n_saved += 1;
+        }
+        Ok(n_saved)
+    }
+
+    /// The KV cache of the last sequence of the request `request_id` which finished, if it is
+    /// still in the prefix cache. `fingerprint` identifies the model, see [`model_fingerprint`].
+    pub(crate) fn snapshot_session(
+        &self,
+        request_id: usize,
+        fingerprint: u64,
+    ) -> anyhow::Result<SessionBlob> {
+        if self.no_prefix_cache || self.block_engine.is_some() {
+            anyhow::bail!("Sessions require the prefix cache, without PagedAttention.");
+        }
+        let Some((toks, element)) = self
+            .caches
+            .iter()
+            .rev()
+            .find(|(_, element)| element.request_id == Some(request_id))
+        else {
+            anyhow::bail!("The KV cache of request {request_id} is not in the prefix cache.");
+        };
+        if element.image_hashes.is_some() || element.audio_hashes.is_some() {
+            anyhow::bail!("Sessions with images or audio are not supported.");
+        }
+        let Some(mut tensors) = cache_tensors(toks, &element.cache)? else {
+            anyhow::bail!("Sessions are not supported with sliding window or H2O KV caches.");
+        };
+        tensors.insert(
+            "fingerprint".to_string(),
+            Tensor::new(&fingerprint.to_le_bytes(), &Device::Cpu)?,
+        );
+        Ok(SessionBlob {
+            data: ::safetensors::serialize(tensors, None)?,
+        })
+    }
+
+    /// Add the KV cache of `session` to the prefix cache on `device`, so that it is reused by the
+    /// next request which continues the conversation. `template` is the KV cache of the model, and
+    /// `fingerprint` identifies it.
+    pub(crate) fn resume_session(
+        &mut self,
+        session: &SessionBlob,
+        template: &[KvCache],
+        device: &Device,
+        fingerprint: u64,
+    ) -> anyhow::Result<()> {
+        if self.no_prefix_cache || self.block_engine.is_some() {
+            anyhow::bail!("Sessions require the prefix cache, without PagedAttention.");
+        }
+        let tensors = safetensors::load_buffer(&session.data, device)?;
+        let session_fingerprint = tensors
+            .get("fingerprint")
+            .map(|x| x.to_vec1::<u8>())
+            .transpose()?;
+        if session_fingerprint.as_deref() != Some(&fingerprint.to_le_bytes()[..]) {
+            anyhow::bail!("The session was taken with another model.");
+        }
+        let Some((toks, cache)) = cache_from_tensors(&tensors, template)? else {
+            anyhow::bail!("The session does not match the KV cache of the model.");
+        };
+        self.caches.insert(
+            toks.into(),
+            CacheElement {
+                cache,
+                image_hashes: None,
+                audio_hashes: None,
+                request_id: None,
+            },
+        );
+        self.evict_caches()?;
+        Ok(())
+    }
+
     /// This always keeps the cache on the device.
     pub fn add_sequence(&mut self, seq: &mut Sequence) {
         // Do not cache if prefix caching disabled
@@ -121,27 +408,14 @@ impl PrefixCacheManagerV2 {
             return;
         }
 
-        if let Some(_block_engine) = &self.block_engine {
-            // let logical_token_blocks = seq.logical_token_blocks();
-            // let block_engine = get_mut_arcmutex!(block_engine);
-            // let block_table = &block_engine.block_tables[seq.id()];
-            // let hashed_logical_blocks = hash_logical_blocks(logical_token_blocks);
-
-            // if !self.block_caches.contains_key(&hashed_logical_blocks) {
-            //     for block in block_table {
-            //         block.deref_mut().increment_refcount();
-            //     }
-
-            //     self.block_caches.insert(
-            //         hashed_logical_blocks,
-            //         BlockCacheElement {
-            //             logical_blocks: logical_token_blocks.to_vec(),
-            //             physical_blocks: block_table.clone(),
-            //             image_hashes: seq.image_hashes().map(|x| x.to_vec()),
-            //             audio_hashes: seq.audio_hashes().map(|x| x.to_vec()),
-            //         },
-            //     );
-            // }
+        // The cache would be specific to the selected LoRA adapters
+        if seq.adapters().is_some() {
+            return;
+        }
+
+        if self.block_engine.is_some() {
+            // Also share the blocks of the generated tokens, for the next turn of the conversation
+            self.share_blocks(seq);
         } else {
             let cache = seq.normal_cache().to_vec();
 
@@ -151,11 +425,33 @@ impl PrefixCacheManagerV2 {
                     cache,
                     image_hashes: seq.image_hashes().map(|x| x.to_vec()),
                     audio_hashes: seq.audio_hashes().map(|x| x.to_vec()),
+                    request_id: Some(seq.request_id()),
                 },
             );
         }
     }
 
+    /// Whether sequences can start from the PagedAttention blocks which other sequences computed,
+    /// which is always the case without PagedAttention, as normal KV caches are copied.
+    pub(crate) fn can_share_prompts(&self) -> bool {
+        self.block_engine.is_none() || !self.no_prefix_cache
+    }
+
+    /// Share the computed full PagedAttention blocks of `seq` with later sequences which start
+    /// with the same tokens, including while `seq` is still running.
+    pub fn share_blocks(&mut self, seq: &Sequence) {
+        let Some(block_engine) = &self.block_engine else {
+            return;
+        };
+        if self.no_prefix_cache || !seq.can_share_blocks() {
+            return;
+        }
+        let mut block_engine = get_mut_arcmutex!(block_engine);
+        // The last token is not in the KV cache until the next step
+        let n_blocks = seq.get_toks().len().saturating_sub(1) / block_engine.block_size();
+        block_engine.share_prefix(*seq.id(), seq.logical_token_blocks(), n_blocks);
+    }
+
     /// Evict the caches. This will evict the first k seqs such that the number of sequences on device after the copy is
     /// the maximum allowed. Returns the number of evicted sequences.
     pub fn evict_caches(&mut self) -> Result<usize> {
@@ -176,18 +472,18 @@ impl PrefixCacheManagerV2 {
                 KvCache::Rotating { k, .. } => {
                     k.all_data().as_ref().expect("No KV cache data").device()
                 }
+                KvCache::H2o(cache) => cache
+                    .k
+                    .all_data()
+                    .as_ref()
+                    .expect("No KV cache data")
+                    .device(),
             };
 
             if !matches!(cache_device, Device::Cpu) {
                 n_on_device += 1;
             }
         }
-        // Count block‑caches that still reside on‑device.
-        for cache in self.block_caches.values() {
-            if !cache.physical_blocks.is_empty() {
-                n_on_device += 1;
-            }
-        }
         let mut n_evicted = 0;
         // Intentionally evict the first ones first, as they are the oldest
         for cache in self.caches.values_mut() {
@@ -206,6 +502,12 @@ impl PrefixCacheManagerV2 {
                 KvCache::Rotating { k, .. } => {
                     k.all_data().as_ref().expect("No KV cache data").device()
                 }
+                KvCache::H2o(cache) => cache
+                    .k
+                    .all_data()
+                    .as_ref()
+                    .expect("No KV cache data")
+                    .device(),
             };
 
             if !matches!(cache_device, Device::Cpu) {
@@ -214,25 +516,7 @@ impl PrefixCacheManagerV2 {
             }
         }
 
-        // Now evict block‑caches if we still exceed the on‑device limit.
-        for cache in self.block_caches.values_mut() {
-            if n_on_device - n_evicted <= self.n_on_device {
-                break;
-            }
-            if !cache.physical_blocks.is_empty() {
-                // Drop our strong references and decrement ref‑counts so the
-                // BlockEngine can reclaim the KV blocks.
-                for block in &cache.physical_blocks {
-                    block.deref_mut().decrement_refcount();
-                }
-                cache.physical_blocks.clear();
-                n_evicted += 1;
-            }
-        }
-
         self.caches.retain(|_tokens, cache| !cache.cache.is_empty());
-        self.block_caches
-            .retain(|_key, cache| !cache.physical_blocks.is_empty());
 
         Ok(n_evicted)
     }
@@ -243,12 +527,9 @@ impl PrefixCacheManagerV2 {
 
         self.caches.clear();
 
-        for cache in self.block_caches.values_mut() {
-            for block in &cache.physical_blocks {
-                block.deref_mut().decrement_refcount();
-            }
+        if let Some(block_engine) = &self.block_engine {
+            get_mut_arcmutex!(block_engine).clear_shared_prefixes();
         }
-        self.block_caches.clear();
         Ok(len)
     }
 
@@ -265,124 +546,37 @@ impl PrefixCacheManagerV2 {
         }
 
         if let Some(block_engine) = &self.block_engine {
-            let block_engine = get_mut_arcmutex!(block_engine);
+            // The tokens of images and audio do not identify them, so their blocks are not shared
+            if image_hashes.is_some_and(|x| !x.is_empty())
+                || audio_hashes.is_some_and(|x| !x.is_empty())
+            {
+                return Ok(None);
+            }
+            let mut block_engine = get_mut_arcmutex!(block_engine);
             let block_size = block_engine.block_size();
-            let mut test_logical_blocks = Vec::new();
+            let mut logical_blocks = Vec::new();
             for tok in toks {
                 sequence::util_append_token_to_blocks(
                     *tok as usize,
-                    &mut test_logical_blocks,
+                    &mut logical_blocks,
                     block_size,
                 );
             }
-            let hashed_logical_blocks = hash_logical_blocks(&test_logical_blocks);
-
-            let mut best_match: Option<BlockBestMatch> = None;
-            for (logical, cache_elem) in &self.block_caches {
-                let logical_matches_until = logical
-                    .iter()
-                    .zip(&hashed_logical_blocks)
-                    .take_while(|(a, b)| **a == **b)
-                    .count();
-                let matched_len: usize = cache_elem.logical_blocks[..logical_matches_until]
-                    .iter()
-                    .map(|block| block.num_tokens())
-                    .sum();
-
-                let images_match_until = if let (Some(input_hashes), Some(cached_hashes)) =
-                    (image_hashes, cache_elem.image_hashes.as_ref())
-                {
-                    input_hashes
-                        .iter()
-                        .zip(cached_hashes)
-                        .take_while(|(a, b)| a == b)
-                        .count()
-                } else {
-                    0
-                };
-
-                let audios_match_until = if let (Some(input_hashes), Some(cached_hashes)) =
-                    (audio_hashes, cache_elem.audio_hashes.as_ref())
-                {
-                    input_hashes
-                        .iter()
-                        .zip(cached_hashes)
-                        .take_while(|(a, b)| a == b)
-                        .count()
-                } else {
-                    0
-                };
-
-                if best_match
-                    .is_some_and(|(best_match_len, _, _, _, _)| best_match_len < matched_len)
-                    || best_match.is_none()
-                {
-                    best_match = Some((
-                        matched_len,
-                        &cache_elem.logical_blocks,
-                        &cache_elem.physical_blocks,
-                        images_match_until,
-                        audios_match_until,
-                    ))
-                }
-            }
 
-            let Some((
-                match_len,
-                logical_blocks,
-                physical_blocks,
-                images_match_until,
-                audios_match_until,
-            )) = best_match
-            else {
-                return Ok(None);
-            };
-
-            // Determine how many blocks cover the matched prefix
-            let mut n_blocks = match_len.div_ceil(block_size);
-            n_blocks = n_blocks.min(logical_blocks.len());
-
-            if n_blocks == 0 {
+            // The last token is always computed, for its logits
+            let max_blocks = (toks.len() - 1) / block_size;
+            let physical_blocks = block_engine.match_prefix(&logical_blocks, max_blocks);
+            if physical_blocks.is_empty() {
                 return Ok(None);
             }
-
-            // Take the first n_blocks of both logical and physical blocks
-            let mut logical_prefix = logical_blocks[..n_blocks].to_vec();
-            let physical_prefix = physical_blocks[..n_blocks].to_vec();
-            for block in &physical_prefix {
-                block.deref_mut().increment_refcount();
-            }
-
-            // If the last reused block is full, reserve an extra empty block for new tokens
-            let new_toks = toks[match_len..].to_vec();
-            logical_prefix.push(LogicalTokenBlock::new(block_size));
-            for tok in &new_toks {
-                sequence::util_append_token_to_blocks(
-                    *tok as usize,
-                    &mut logical_prefix,
-                    block_size,
-                );
-            }
-            if logical_prefix.last().is_some_and(|last| last.is_full()) {
-                logical_prefix.push(LogicalTokenBlock::new(block_size));
-            }
-            let images_to_keep = if let Some(input_hashes) = image_hashes {
-                input_hashes.len().saturating_sub(images_match_until)
-            } else {
-                0
-            };
-            let audios_to_keep = if let Some(input_hashes) = audio_hashes {
-                input_hashes.len().saturating_sub(audios_match_until)
-            } else {
-                0
-            };
+            let offset = physical_blocks.len() * block_size;
             return Ok(Some(MatchingCache::Paged {
-                logical_blocks: logical_prefix,
-                physical_blocks: physical_prefix,
-                toks: new_toks,
-                offset: match_len,
-                images_to_keep,
-                audios_to_keep,
+                logical_blocks,
+                physical_blocks,
+                toks: toks[offset..].to_vec(),
+                offset,
+                images_to_keep: 0,
+                audios_to_keep: 0,
             }));
         }
 
@@ -6,10 +6,11 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::{
-    response::Response, sampler::SamplingParams, tools::ToolChoice, CustomLogitsProcessor,
-    DiffusionGenerationParams, Tool,
+    prefix_cacher::SessionBlob, response::Response, sampler::SamplingParams, tools::ToolChoice,
+    AudioChunk, CalibrationData, CustomLogitsProcessor, DiffusionGenerationParams, Tool,
+    TranscriptionSegment,
 };
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, path::PathBuf, sync::Arc, time::Instant};
 use tokio::sync::mpsc::Sender;
 
 pub type LlguidanceGrammar = llguidance::api::TopLevelGrammar;
@@ -19,8 +20,12 @@ pub type LlguidanceGrammar = llguidance::api::TopLevelGrammar;
 pub enum Constraint {
     Regex(String),
     Lark(String),
+    /// A GBNF grammar, in the format of llama.cpp.
+    Gbnf(String),
     JsonSchema(serde_json::Value),
     Llguidance(LlguidanceGrammar),
+    /// Exactly one of the given strings.
+    Choice(Vec<String>),
     None,
 }
 
@@ -131,7 +136,9 @@ pub struct WebSearchOptions {
 /// - `tool_choice`: Choice of tools
 /// - `logits_processors`: Custom logits processors. Order of application:
 ///     1) Apply penalties from `sampling_params`
-///     2) Apply these custom logits processors sequentially
+///     2) Apply the logits processors of the model, then these custom logits processors
+///        sequentially, then ban the tokens which would
+///        complete one of the `banned_strings` of `sampling_params`
 ///     3) Apply temperature and softmax
 ///     4) Sample the next token (topk, topp, minp, etc)
 /// - `return_raw_logits`: Return raw logits.
@@ -152,7 +159,19 @@ pub struct NormalRequest {
     pub logits_processors: Option<Vec<Arc<dyn CustomLogitsProcessor>>>,
     pub return_raw_logits: bool,
     pub web_search_options: Option<WebSearchOptions>,
+    /// LoRA adapters to run this request with, each with the weight its deltas are scaled by.
+    /// `None` means all loaded adapters with a weight of 1.
+    pub adapters: Option<Vec<(String, f64)>>,
     pub model_id: Option<String>,
+    /// The client this request is made for, such as a user or API key. With
+    /// [`SchedulingPolicy::FairShare`](crate::SchedulingPolicy::FairShare), the tokens of each
+    /// tenant are balanced. Requests without a tenant share one.
+    pub tenant: Option<String>,
+    /// When this request must be done by. If it has not started by then, it is dropped from the
+    /// queue with a [`DeadlineExceeded`](crate::DeadlineExceeded) error. Otherwise, generation
+    /// stops and the partial result is returned with the finish reason `timeout`.
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
 }
 
 impl NormalRequest {
@@ -178,7 +197,10 @@ impl NormalRequest {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            adapters: None,
             model_id: None,
+            tenant: None,
+            deadline: None,
         }
     }
 }
@@ -207,14 +229,267 @@ pub struct DetokenizationRequest {
     pub response: Sender<anyhow::Result<String>>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+/// How to change the LoRA adapters of the loaded model.
+pub enum LoraAdapterAction {
+    /// Add the adapter with this ID to the model, optionally scaling its delta weights by the
+    /// given scale instead of `lora_alpha / r`.
+    Load(String, Option<f64>),
+    /// Remove the adapter with this ID from the model.
+    Unload(String),
+    /// Make the active adapters part of the base weights and drop all adapters.
+    Merge,
+    /// Only list the loaded adapters.
+    List,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+/// A LoRA adapter loaded into the model.
+pub struct LoraAdapterInfo {
+    /// The Hugging Face model ID or local directory the adapter was loaded from.
+    pub name: String,
+    pub rank: usize,
+    pub target_modules: Vec<String>,
+    /// Whether the adapter is merged into the model weights.
+    pub merged: bool,
+    /// Whether the adapter is merged into the model weights or used by a running sequence.
+    pub active: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to change the LoRA adapters of the loaded model. The loaded adapters are sent back
+/// after the change.
+pub struct LoraAdapterRequest {
+    pub action: LoraAdapterAction,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<Vec<LoraAdapterInfo>>>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+/// The format to export the weights of the model in.
+pub enum ExportFormat {
+    /// A UQFF file, keeping quantized layers quantized.
+    Uqff,
+    /// A directory in the Hugging Face layout, with unquantized weights in `model.safetensors`.
+    Safetensors,
+    /// A GGUF file in the layout llama.cpp expects, with the tokenizer and chat template. Layers
+    /// are quantized to the given GGML type, or keep their GGML type if it is not given.
+    Gguf(Option<IsqType>),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to write the weights of the loaded model, with any merged LoRA adapters, to disk.
+pub struct ExportRequest {
+    pub path: PathBuf,
+    pub format: ExportFormat,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<()>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to quantize the model to `isq_type` from its original weights.
+pub struct RequantizeRequest {
+    pub isq_type: IsqType,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<()>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to collect an imatrix from the calibration `data` and reapply ISQ to the model with it.
+pub struct CalibratedIsqRequest {
+    pub isq_type: IsqType,
+    pub data: CalibrationData,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<()>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to compute the embeddings of `texts` with a loaded embedding model.
+pub struct EmbeddingRequest {
+    pub texts: Vec<String>,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<Vec<Vec<f32>>>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to score the relevance of each of `documents` to `query` with a loaded reranker model.
+/// The scores are sent back in the order of `documents`.
+pub struct RerankRequest {
+    pub query: String,
+    pub documents: Vec<String>,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<Vec<f32>>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to transcribe mono `samples` at `sample_rate` with a loaded transcription model.
+/// `language` is a language code such as `en`, and is detected if not given. Each segment is
+/// sent back as soon as it is decoded, and the sender is dropped once the transcription is done.
+pub struct TranscriptionRequest {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub language: Option<String>,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<TranscriptionSegment>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to speak `text` with a loaded text-to-speech model. Each chunk of audio is sent back
+/// as soon as it is generated, and the sender is dropped once the text has been spoken.
+pub struct SynthesisRequest {
+    pub text: String,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<AudioChunk>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+/// A snapshot of the state of an engine.
+pub struct EngineStats {
+    /// The sequences waiting to run, including those swapped out.
+    pub num_waiting: usize,
+    pub num_running: usize,
+    /// The PagedAttention KV cache blocks which are in use, if PagedAttention is enabled.
+    pub kv_blocks_used: Option<usize>,
+    /// The number of PagedAttention KV cache blocks, if PagedAttention is enabled.
+    pub kv_blocks_total: Option<usize>,
+    /// The fraction of the sequences since the engine started which reused a cached prefix.
+    pub prefix_cache_hit_rate: f64,
+    /// The tokens processed per second over the last 5 seconds.
+    pub tokens_per_sec: f64,
+    /// The sequences since the engine started which reused a cached prefix.
+    pub prefix_cache_hits: usize,
+    /// The sequences since the engine started which did not reuse a cached prefix.
+    pub prefix_cache_misses: usize,
+    /// The use of the PagedAttention KV cache, if PagedAttention is enabled.
+    pub kv_cache: Option<KvCacheStats>,
+    /// The prompt tokens of the sequences since the engine started.
+    pub prompt_tokens: usize,
+    /// The tokens generated since the engine started.
+    pub generation_tokens: usize,
+    /// The time from the arrival of each sequence to its first token.
+    pub time_to_first_token: LatencyHistogram,
+    /// The time to generate each token after the first, which is the time of the step which
+    /// generated it.
+    pub time_per_output_token: LatencyHistogram,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+/// A histogram of latencies in seconds since the engine started, in the format of Prometheus.
+pub struct LatencyHistogram {
+    /// The buckets in ascending order. Each counts the latencies up to its bound, including
+    /// those of the previous buckets.
+    pub buckets: Vec<HistogramBucket>,
+    /// The sum of the latencies.
+    pub sum: f64,
+    /// The number of latencies, including those above the bound of the last bucket.
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct HistogramBucket {
+    /// The upper bound of the bucket, in seconds.
+    pub le: f64,
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+/// A snapshot of the PagedAttention KV cache of an engine. The counters are totals since the
+/// engine started.
+pub struct KvCacheStats {
+    /// The tokens in each block.
+    pub block_size: usize,
+    pub gpu_blocks_total: usize,
+    /// The GPU blocks which are used by sequences.
+    pub gpu_blocks_used: usize,
+    /// The GPU blocks which hold shared prefixes no sequence uses, which are reused by new
+    /// sequences with the same prefix or evicted when blocks are needed.
+    pub gpu_blocks_cached: usize,
+    pub gpu_blocks_free: usize,
+    /// The host blocks, for swapped out sequences and offloaded prefix blocks.
+    pub cpu_blocks_total: usize,
+    pub cpu_blocks_used: usize,
+    /// The host blocks which hold offloaded shared prefix blocks, included in `cpu_blocks_used`.
+    pub cpu_blocks_cached: usize,
+    /// The blocks moved to the host when sequences were preempted.
+    pub blocks_swapped_out: usize,
+    /// The blocks moved back to the GPU when preempted sequences resumed.
+    pub blocks_swapped_in: usize,
+    /// The shared prefix blocks offloaded to the host instead of being evicted.
+    pub blocks_offloaded: usize,
+    /// The offloaded shared prefix blocks moved back to the GPU when they were matched.
+    pub blocks_restored: usize,
+    /// The full blocks of new sequences which were found in the shared prefixes.
+    pub prefix_block_hits: usize,
+    /// The full blocks of new sequences which were not found in the shared prefixes.
+    pub prefix_block_misses: usize,
+    /// The fraction of the token slots of the blocks of the running sequences which hold no token,
+    /// as only the last block of each sequence is partially filled.
+    pub fragmentation: f64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to take a snapshot of the state of the engine.
+pub struct StatsRequest {
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<EngineStats>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to take a snapshot of the KV cache of a conversation, see [`SessionBlob`].
+pub struct SnapshotSessionRequest {
+    /// The `NormalRequest::id` of the last turn of the conversation, which must have finished.
+    pub request_id: usize,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<SessionBlob>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to restore the KV cache of a conversation, so the next turn does not prefill it again.
+pub struct ResumeSessionRequest {
+    pub session: SessionBlob,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<()>>,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 /// A request to the Engine, encapsulating the various parameters as well as
 /// the `mpsc` response `Sender` used to return the [`Response`].
 pub enum Request {
     Normal(Box<NormalRequest>),
     ReIsq(IsqType),
+    Requantize(RequantizeRequest),
+    CalibratedIsq(CalibratedIsqRequest),
     Tokenize(TokenizationRequest),
     Detokenize(DetokenizationRequest),
+    LoraAdapter(LoraAdapterRequest),
+    Export(ExportRequest),
+    Embedding(EmbeddingRequest),
+    Rerank(RerankRequest),
+    Transcription(TranscriptionRequest),
+    Synthesis(SynthesisRequest),
+    Stats(StatsRequest),
+    SnapshotSession(SnapshotSessionRequest),
+    ResumeSession(ResumeSessionRequest),
+    /// Cancel the sequences of the request with this `NormalRequest::id`. Queued sequences are
+    /// removed, and running ones finish after their next step with the finish reason `canceled`.
+    Cancel(usize),
+    /// Reject new requests and stop the engine once the running ones have finished.
+    Drain,
     // Sending a terminate request causes the `run` function to return to the thread created in `MistralRs::new`,
     // and then Engine will be dropped.
     Terminate,
@@ -240,12 +515,58 @@ impl Debug for Request {
             Request::ReIsq(tp) => {
                 write!(f, "Re ISQ Request {tp:?}",)
             }
+            Request::Requantize(req) => {
+                write!(f, "Requantize Request {:?}", req.isq_type)
+            }
+            Request::CalibratedIsq(req) => {
+                write!(f, "Calibrated ISQ Request {:?}", req.isq_type)
+            }
             Request::Tokenize(req) => {
                 write!(f, "Tokenization Request {:?}", req.text)
             }
             Request::Detokenize(req) => {
                 write!(f, "Tokenization Request {:?}", req.tokens)
             }
+            Request::LoraAdapter(req) => {
+                write!(f, "LoRA Adapter Request {:?}", req.action)
+            }
+            Request::Export(req) => {
+                write!(
+                    f,
+                    "Export Request {:?} to {}",
+                    req.format,
+                    req.path.display()
+                )
+            }
+            Request::Embedding(req) => {
+                write!(f, "Embedding Request for {} texts", req.texts.len())
+            }
+            Request::Rerank(req) => {
+                write!(
+                    f,
+                    "Rerank Request {:?} for {} documents",
+                    req.query,
+                    req.documents.len()
+                )
+            }
+            Request::Transcription(req) => {
+                write!(
+                    f,
+                    "Transcription Request for {} samples at {} Hz",
+                    req.samples.len(),
+                    req.sample_rate
+                )
+            }
+            Request::Synthesis(req) => {
+                write!(f, "Synthesis Request {:?}", req.text)
+            }
+            Request::Stats(_) => write!(f, "Stats Request"),
+            Request::SnapshotSession(req) => {
+                write!(f, "Snapshot Session Request {}", req.request_id)
+            }
+            Request::ResumeSession(_) => write!(f, "Resume Session Request"),
+            Request::Cancel(id) => write!(f, "Cancel Request {id}"),
+            Request::Drain => write!(f, "Drain Request"),
             Request::Terminate => write!(f, "Termination Request"),
             Request::TerminateAllSeqsNextStep => write!(f, "Terminate All Seqs Next Step"),
         }
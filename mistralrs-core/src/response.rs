@@ -5,6 +5,7 @@ use std::{
 };
 
 use candle_core::Tensor;
+use indexmap::IndexMap;
 #[cfg(feature = "pyo3_macros")]
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
@@ -72,6 +73,22 @@ pub struct Logprobs {
 
 generate_repr!(Logprobs);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Default, Serialize)]
+/// Logprobs per token of a completion, in the format of the OpenAI completions API. The first
+/// token of an echoed prompt has no logprobs.
+pub struct CompletionLogprobs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<Option<f32>>,
+    /// The most likely alternatives of each token, by their text.
+    pub top_logprobs: Vec<Option<IndexMap<String, f32>>>,
+    /// The character offset of each token in the text of the choice.
+    pub text_offset: Vec<usize>,
+}
+
+generate_repr!(CompletionLogprobs);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -105,7 +122,7 @@ generate_repr!(ChunkChoice);
 pub struct CompletionChunkChoice {
     pub text: String,
     pub index: usize,
-    pub logprobs: Option<ResponseLogprob>,
+    pub logprobs: Option<CompletionLogprobs>,
     pub finish_reason: Option<String>,
 }
 
@@ -169,7 +186,7 @@ pub struct CompletionChoice {
     pub finish_reason: String,
     pub index: usize,
     pub text: String,
-    pub logprobs: Option<()>,
+    pub logprobs: Option<CompletionLogprobs>,
 }
 
 generate_repr!(CompletionChoice);
@@ -201,6 +218,7 @@ pub struct CompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    pub usage: Option<Usage>,
 }
 
 generate_repr!(CompletionChunkResponse);
@@ -225,6 +243,66 @@ pub struct ImageGenerationResponse {
 
 generate_repr!(ImageGenerationResponse);
 
+/// The error sent as a [`Response::InternalError`] when a request is rejected because
+/// `max_queue_len` sequences are already waiting to run. The request may be retried later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull {
+    pub max_queue_len: usize,
+}
+
+impl Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The request queue is full ({} sequences waiting), try again later.",
+            self.max_queue_len
+        )
+    }
+}
+
+impl Error for QueueFull {}
+
+/// The error sent as a [`Response::InternalError`] when a request is dropped from the queue
+/// because its deadline passed before it started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineExceeded;
+
+impl Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The request deadline passed before it started.")
+    }
+}
+
+impl Error for DeadlineExceeded {}
+
+/// The error sent as a [`Response::InternalError`] when a request is canceled before it started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestCanceled;
+
+impl Display for RequestCanceled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The request was canceled before it started.")
+    }
+}
+
+impl Error for RequestCanceled {}
+
+/// The error sent as a [`Response::InternalError`] when a request is rejected because the engine
+/// is draining, see `MistralRs::drain`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Draining;
+
+impl Display for Draining {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The model is shutting down and does not accept new requests."
+        )
+    }
+}
+
+impl Error for Draining {}
+
 /// The response enum contains 3 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no prefix)
@@ -11,7 +11,10 @@ use mistralrs_quant::{CumSumOp, SortOp};
 use pyo3::pyclass;
 
 use once_cell::sync::Lazy;
-use rand::distr::{weighted::WeightedIndex, Distribution};
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    Rng,
+};
 use rand_isaac::Isaac64Rng;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
@@ -34,15 +37,41 @@ pub struct SamplingParams {
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
     pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
     pub repetition_penalty: Option<f32>,
     pub stop_toks: Option<StopTokens>,
+    /// Regexes which stop the sequence once the text generated so far matches one of them.
+    pub stop_regexes: Option<Vec<String>>,
+    /// Keep the text which matched a stop string or regex at the end of the completion.
+    pub include_stop_str_in_output: bool,
     pub max_len: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
     pub dry_params: Option<DrySamplingParams>,
+    pub xtc_params: Option<XtcSamplingParams>,
+    /// Strings which may not appear in the output, however they would be tokenized.
+    pub banned_strings: Option<Vec<String>>,
+    /// Never repeat an n-gram of this many tokens of the context, including the prompt.
+    pub no_repeat_ngram_size: Option<usize>,
+    /// Generate the last token of the prompt again, so a prompt which ends in the middle of a
+    /// token does not force an unusual tokenization at the start of the completion.
+    pub token_healing: bool,
+    /// The scale of classifier-free guidance, which moves the logits away from the logits after
+    /// `negative_prompt`. A scale of 1 leaves them unchanged.
+    pub guidance_scale: Option<f32>,
+    /// The negative prompt of classifier-free guidance. Without one, the logits are moved away
+    /// from those after an empty prompt.
+    pub negative_prompt: Option<String>,
+    /// The degeneration penalty of contrastive search, which chooses among the `top_k` most likely
+    /// tokens by weighing their probability against the similarity of their hidden states to
+    /// those of the previous tokens.
+    pub penalty_alpha: Option<f32>,
+    /// Sample with a random number generator seeded with this, so the output only depends on the
+    /// request. The choices of a request with several use consecutive seeds.
+    pub seed: Option<u64>,
 }
 
 impl SamplingParams {
@@ -56,15 +85,26 @@ impl SamplingParams {
             top_k: Some(1),
             top_p: None,
             min_p: None,
+            typical_p: None,
             top_n_logprobs: 0,
             frequency_penalty: None,
             presence_penalty: None,
             repetition_penalty: None,
             stop_toks: None,
+            stop_regexes: None,
+            include_stop_str_in_output: false,
             max_len: None,
             logits_bias: None,
             n_choices: 1,
             dry_params: None,
+            xtc_params: None,
+            banned_strings: None,
+            no_repeat_ngram_size: None,
+            token_healing: false,
+            guidance_scale: None,
+            negative_prompt: None,
+            penalty_alpha: None,
+            seed: None,
         }
     }
 }
@@ -104,6 +144,25 @@ impl Default for DrySamplingParams {
     }
 }
 
+/// Exclude Top Choices (XTC) sampling. At each step, with probability `probability`, all the tokens
+/// which are at least `threshold` likely are removed except the least likely of them. This steers
+/// the output away from the most predictable continuations while keeping a viable one.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct XtcSamplingParams {
+    /// Values above 0.5 disable XTC, as at most one token can be that likely.
+    pub threshold: f32,
+    pub probability: f32,
+}
+
+impl XtcSamplingParams {
+    pub fn new_with_defaults(probability: f32, threshold: Option<f32>) -> Self {
+        Self {
+            threshold: threshold.unwrap_or(0.1),
+            probability,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct DrySamplingParamsInner {
     pub sequence_breakers: HashSet<u32>,
@@ -151,6 +210,13 @@ impl DrySamplingParamsInner {
 
 /// Customizable logits processor.
 ///
+/// Processors are registered per request in [`NormalRequest::logits_processors`], or for every
+/// request of a model with [`MistralRsBuilder::with_logits_processor`]. They run after the
+/// penalties and logit bias of the request, and before its sampling parameters.
+///
+/// [`NormalRequest::logits_processors`]: crate::NormalRequest::logits_processors
+/// [`MistralRsBuilder::with_logits_processor`]: crate::MistralRsBuilder::with_logits_processor
+///
 /// # Example
 /// ```rust
 /// use std::{sync::Arc, ops::Mul};
@@ -189,9 +255,12 @@ pub struct Sampler {
     presence_penalty: Option<f32>,
     repetition_penalty: Option<f32>,
     dry_params: Option<DrySamplingParamsInner>,
+    xtc_params: Option<XtcSamplingParams>,
+    logits_bias: Option<HashMap<u32, f32>>,
     top_k: i64,
     top_p: f64,
     min_p: f64,
+    typical_p: f64,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     /// Cached Gumbel noise tensor to avoid reallocating it.
     gumbel_cache: Arc<Mutex<Option<Tensor>>>,
@@ -229,9 +298,12 @@ impl Sampler {
         presence_penalty: Option<f32>,
         repetition_penalty: Option<f32>,
         dry_params: Option<DrySamplingParams>,
+        xtc_params: Option<XtcSamplingParams>,
+        logits_bias: Option<HashMap<u32, f32>>,
         top_k: i64,
         top_p: f64,
         min_p: f64,
+        typical_p: f64,
         logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     ) -> anyhow::Result<Self> {
         let temperature = if temperature.is_none_or(|v| v < 1e-7) {
@@ -256,9 +328,12 @@ impl Sampler {
             presence_penalty,
             repetition_penalty,
             dry_params,
+            xtc_params,
+            logits_bias,
             top_k,
             top_p,
             min_p,
+            typical_p,
             logits_processors,
             gumbel_cache: Arc::new(Mutex::new(None)),
         })
@@ -306,6 +381,28 @@ impl Sampler {
         Ok(result)
     }
 
+    /// The logprobs of `token` after `logits`, such as those of a prompt token after the previous
+    /// ones, with the most likely alternatives.
+    pub(crate) fn logprobs_of(&self, logits: &Tensor, token: u32) -> Result<Logprobs> {
+        let probs: Vec<f32> =
+            candle_nn::ops::softmax_last_dim(&logits.to_dtype(DType::F32)?)?.to_vec1()?;
+        let bytes = match &self.tokenizer {
+            Some(tokenizer) => Some(
+                tokenizer
+                    .decode(&[token], false)
+                    .map_err(|x| Error::Msg(x.to_string()))?,
+            ),
+            None => None,
+        };
+
+        Ok(Logprobs {
+            token,
+            logprob: probs[token as usize].log(10.0),
+            top_logprobs: Some(self.get_top_logprobs(&probs, &[])?),
+            bytes,
+        })
+    }
+
     fn sample_argmax(&self, logits: Tensor, return_logprobs: bool) -> Result<Logprobs> {
         let next_token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
 
@@ -531,6 +628,7 @@ impl Sampler {
         top_k: i64,
         top_p: f32,
         min_p: f32,
+        typical_p: f32,
     ) -> Result<Logprobs> {
         let mut probs: Vec<f32> = logits.to_vec1()?;
         let argsort_indices: Vec<u32> = logits.arg_sort_last_dim(false)?.to_vec1()?;
@@ -544,6 +642,10 @@ impl Sampler {
             }
         }
 
+        if typical_p > 0.0 && typical_p < 1.0 {
+            Self::apply_typical_p(&mut probs, typical_p);
+        }
+
         // TOP P
 
         // top-p sampling (or "nucleus sampling") samples from the smallest set of
@@ -560,7 +662,8 @@ impl Sampler {
             }
         }
 
-        let max_p = probs[argsort_indices[0] as usize];
+        // Typical sampling may have removed the most likely token
+        let max_p = probs.iter().copied().fold(0.0, f32::max);
 
         // MIN P
 
@@ -649,6 +752,7 @@ impl Sampler {
         top_k: i64,
         top_p: f32,
         min_p: f32,
+        typical_p: f32,
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
     ) -> Result<Logprobs> {
@@ -663,48 +767,108 @@ impl Sampler {
             }
         }
 
-        if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
+        if typical_p > 0.0 && typical_p < 1.0 {
+            Self::apply_typical_p(probs, typical_p);
         }
 
-        // TOP P
+        if top_p > 0.0 && top_p < 1.0 {
+            // TOP P
 
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
+            // top-p sampling (or "nucleus sampling") samples from the smallest set of
+            // tokens that exceed probability top_p. This way we never sample tokens that
+            // have very low probabilities and are less likely to go "off the rails".
 
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                probs[*index as usize] = 0.0;
-            } else {
-                cumsum += probs[*index as usize];
+            // Clamp smaller probabilities to zero.
+            let mut cumsum = 0.;
+            for index in &argsort_indices {
+                if cumsum >= top_p {
+                    probs[*index as usize] = 0.0;
+                } else {
+                    cumsum += probs[*index as usize];
+                }
             }
         }
 
-        if min_p <= 0.0 || min_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
-        }
-
-        let max_p = probs[argsort_indices[0] as usize];
+        if min_p > 0.0 && min_p < 1.0 {
+            // Typical sampling may have removed the most likely token
+            let max_p = probs.iter().copied().fold(0.0, f32::max);
 
-        // MIN P
+            // MIN P
 
-        // min-p sampling samples from the tokens whose prob are greater than
-        // (max prob of token in dist) * min_p
+            // min-p sampling samples from the tokens whose prob are greater than
+            // (max prob of token in dist) * min_p
 
-        // Clamp smaller probabilities to zero.
-        for index in &argsort_indices {
-            if max_p * min_p >= probs[*index as usize] {
-                probs[*index as usize] = 0.0;
+            // Clamp smaller probabilities to zero.
+            for index in &argsort_indices {
+                if max_p * min_p >= probs[*index as usize] {
+                    probs[*index as usize] = 0.0;
+                }
             }
         }
 
+        self.apply_xtc(probs, &argsort_indices, &rng);
+
         // Sample with clamped probabilities.
         self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
     }
 
+    /// Locally typical sampling: keep the tokens whose information content is closest to the
+    /// entropy of the distribution, up to a total probability of `typical_p`.
+    fn apply_typical_p(probs: &mut [f32], typical_p: f32) {
+        // The earlier samplers clamp probabilities without normalizing them
+        let total = probs.iter().sum::<f32>();
+        let entropy = -probs
+            .iter()
+            .filter(|p| **p > 0.0)
+            .map(|p| (p / total) * (p / total).ln())
+            .sum::<f32>();
+
+        let mut order = (0..probs.len())
+            .filter(|i| probs[*i] > 0.0)
+            .collect::<Vec<_>>();
+        let shift = |i: usize| (-(probs[i] / total).ln() - entropy).abs();
+        order.sort_by(|a, b| shift(*a).total_cmp(&shift(*b)));
+
+        let mut cumsum = 0.;
+        for i in order {
+            if cumsum >= typical_p {
+                probs[i] = 0.0;
+            } else {
+                cumsum += probs[i] / total;
+            }
+        }
+    }
+
+    /// Remove the tokens which are at least `threshold` likely, except the least likely of them,
+    /// with probability `probability`. `argsort_indices` must sort `probs` in descending order.
+    fn apply_xtc(&self, probs: &mut [f32], argsort_indices: &[u32], rng: &Mutex<Isaac64Rng>) {
+        let Some(xtc) = self.xtc_params else {
+            return;
+        };
+        if xtc.probability <= 0.0 || xtc.threshold > 0.5 {
+            return;
+        }
+        if rng
+            .lock()
+            .expect("could not lock rng mutex")
+            .random::<f32>()
+            >= xtc.probability
+        {
+            return;
+        }
+
+        // The earlier samplers clamp probabilities without normalizing them
+        let total = probs.iter().sum::<f32>();
+        let n_top = argsort_indices
+            .iter()
+            .map(|index| probs[*index as usize])
+            .take_while(|p| *p > 0.0 && *p >= xtc.threshold * total)
+            .count();
+        for index in argsort_indices.iter().take(n_top.saturating_sub(1)) {
+            probs[*index as usize] = 0.0;
+        }
+    }
+
     fn apply_penalties(&self, mut logits: Vec<f32>, context: &[u32]) -> Result<Tensor> {
         if context.is_empty() {
             candle_core::bail!("Penalty context is empty, this should not happen.");
@@ -716,10 +880,42 @@ impl Sampler {
         // Frequency, presence, repetition penalty
         self.apply_freq_pres_rep_penalty(&mut logits, context)?;
 
+        self.apply_logits_bias(&mut logits);
+
         let vocab_size = logits.len();
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
     }
 
+    /// Add the bias of each token to its logit. As with OpenAI, a bias of -100 bans the token and a
+    /// bias of 100 forces it (or one of the forced tokens, if there are several).
+    fn apply_logits_bias(&self, logits: &mut [f32]) {
+        let Some(logits_bias) = &self.logits_bias else {
+            return;
+        };
+        let in_vocab = |tok: &u32| (*tok as usize) < logits.len();
+
+        let forced = logits_bias
+            .iter()
+            .filter(|(tok, bias)| in_vocab(tok) && **bias >= 100.)
+            .map(|(tok, _)| *tok as usize)
+            .collect::<HashSet<_>>();
+        if !forced.is_empty() {
+            for (tok, logit) in logits.iter_mut().enumerate() {
+                if !forced.contains(&tok) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+
+        for (tok, bias) in logits_bias.iter().filter(|(tok, _)| in_vocab(tok)) {
+            if *bias <= -100. {
+                logits[*tok as usize] = f32::NEG_INFINITY;
+            } else if *bias < 100. {
+                logits[*tok as usize] += bias;
+            }
+        }
+    }
+
     fn apply_freq_pres_rep_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
         if self.frequency_penalty.is_some()
             || self.presence_penalty.is_some()
@@ -868,6 +1064,7 @@ impl Sampler {
                     self.top_k,
                     self.top_p as f32,
                     self.min_p as f32,
+                    self.typical_p as f32,
                 )?,
                 Some(temperature) => {
                     let logits = (&logits / temperature)?;
@@ -879,6 +1076,7 @@ impl Sampler {
                         self.top_k,
                         self.top_p as f32,
                         self.min_p as f32,
+                        self.typical_p as f32,
                     )?
                 }
             }
@@ -896,6 +1094,7 @@ impl Sampler {
                         self.top_k,
                         self.top_p as f32,
                         self.min_p as f32,
+                        self.typical_p as f32,
                         return_logprobs,
                         rng,
                     )?
@@ -924,9 +1123,12 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
             32,
             0.1,
             0.05,
+            1.0,
             vec![],
         )
         .unwrap();
@@ -964,9 +1166,12 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
             32,
             0.1,
             0.05,
+            1.0,
             vec![],
         )
         .unwrap();
@@ -986,4 +1191,177 @@ mod tests {
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn test_min_p_without_top_p() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.5,
+            1.0,
+            vec![],
+        )
+        .unwrap();
+        // Without min-p, one of the many unlikely tokens would almost always be sampled
+        let mut logits = vec![0f32; 1024];
+        logits[0] = 1.0;
+        logits[1] = 0.9;
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        for _ in 0..16 {
+            let logits = Tensor::new(logits.as_slice(), &Device::Cpu).unwrap();
+            let res = sampler
+                .sample(logits, &[0], false, rng.clone(), false, false)
+                .unwrap();
+            assert!(res.token < 2);
+        }
+    }
+
+    #[test]
+    fn test_dry_penalty() {
+        use super::{DrySamplingParamsInner, Sampler};
+        use std::collections::HashSet;
+
+        let mut sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            vec![],
+        )
+        .unwrap();
+        sampler.dry_params = Some(DrySamplingParamsInner {
+            sequence_breakers: HashSet::from([5]),
+            multiplier: 1.0,
+            base: 2.0,
+            allowed_length: 2,
+        });
+
+        // `1 2 3` repeats, so continuing it with `4` is penalized by 1.0 * 2.0^(3 - 2)
+        let mut logits = vec![0f32; 8];
+        sampler
+            .apply_dry_penalty(&mut logits, &[1, 2, 3, 4, 1, 2, 3])
+            .unwrap();
+        assert_eq!(logits, [0., 0., 0., 0., -2., 0., 0., 0.]);
+
+        // A sequence breaker ends the match
+        let mut logits = vec![0f32; 8];
+        sampler
+            .apply_dry_penalty(&mut logits, &[1, 5, 3, 4, 1, 5, 3])
+            .unwrap();
+        assert_eq!(logits, [0.; 8]);
+    }
+
+    #[test]
+    fn test_xtc() {
+        use super::{Sampler, XtcSamplingParams};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Mutex;
+
+        let mut sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            vec![],
+        )
+        .unwrap();
+        sampler.xtc_params = Some(XtcSamplingParams::new_with_defaults(1.0, Some(0.1)));
+        let rng = Mutex::new(Isaac64Rng::seed_from_u64(42));
+
+        // All the tokens above the threshold are removed except the least likely of them
+        let mut probs = vec![0.05, 0.5, 0.15, 0.3];
+        sampler.apply_xtc(&mut probs, &[1, 3, 2, 0], &rng);
+        assert_eq!(probs, [0.05, 0., 0.15, 0.]);
+
+        // Nothing is removed with a single token above the threshold
+        let mut probs = vec![0.05, 0.9, 0.05];
+        sampler.apply_xtc(&mut probs, &[1, 0, 2], &rng);
+        assert_eq!(probs, [0.05, 0.9, 0.05]);
+    }
+
+    #[test]
+    fn test_typical_p() {
+        use super::Sampler;
+
+        // The most likely token carries less information than the entropy of the distribution
+        // (1.09 nats), and is further from it than the others
+        let mut probs = vec![0.4, 0.3, 0.3];
+        Sampler::apply_typical_p(&mut probs, 0.5);
+        assert_eq!(probs, [0., 0.3, 0.3]);
+
+        // Clamped tokens stay clamped, and the others are normalized
+        let mut probs = vec![0.2, 0.15, 0.15, 0.];
+        Sampler::apply_typical_p(&mut probs, 0.5);
+        assert_eq!(probs, [0., 0.15, 0.15, 0.]);
+    }
+
+    #[test]
+    fn test_logits_bias() {
+        use super::Sampler;
+        use std::collections::HashMap;
+
+        let mut sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            vec![],
+        )
+        .unwrap();
+
+        sampler.logits_bias = Some(HashMap::from([(0, 1.5), (1, -100.), (9, 2.)]));
+        let mut logits = vec![1f32; 3];
+        sampler.apply_logits_bias(&mut logits);
+        assert_eq!(logits, [2.5, f32::NEG_INFINITY, 1.]);
+
+        // Forcing a token bans all the others
+        sampler.logits_bias = Some(HashMap::from([(0, 1.5), (2, 100.)]));
+        let mut logits = vec![1f32; 3];
+        sampler.apply_logits_bias(&mut logits);
+        assert_eq!(logits, [f32::NEG_INFINITY, f32::NEG_INFINITY, 1.]);
+    }
 }
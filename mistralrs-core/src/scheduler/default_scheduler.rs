@@ -4,19 +4,25 @@ use std::{
     sync::{atomic::Ordering, Arc},
 };
 
+use mistralrs_quant::LoraAdapterSelection;
+
 use crate::{
     engine::{IntervalLogger, TERMINATE_ALL_NEXT_STEP},
     paged_attention::{BlockEngine, BlockTables},
+    request::KvCacheStats,
     sequence::{Sequence, SequenceState, StopReason},
+    Response,
 };
+use tokio::sync::mpsc::Sender;
 
-use super::{Scheduler, SchedulerOutput};
+use super::{Scheduler, SchedulerOutput, SchedulingPolicy, TenantUsage};
 
 pub trait FcfsBacker: Default {
     fn new() -> Self;
     fn add(&mut self, item: Sequence);
     fn into_iter(self) -> impl Iterator<Item = Sequence>;
     fn len(&self) -> usize;
+    fn iter(&self) -> impl Iterator<Item = &Sequence>;
     fn sort_ascending_ids(&mut self);
 }
 
@@ -37,6 +43,9 @@ impl FcfsBacker for VecDeque<Sequence> {
     fn len(&self) -> usize {
         VecDeque::len(self)
     }
+    fn iter(&self) -> impl Iterator<Item = &Sequence> {
+        VecDeque::iter(self)
+    }
 }
 
 pub struct DefaultSchedulerOutput<'a> {
@@ -175,10 +184,17 @@ pub struct DefaultScheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: DefaultSchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    policy: SchedulingPolicy,
+    usage: TenantUsage,
+    prefill_chunk_size: Option<usize>,
 }
 
 impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
-    pub fn new(method: DefaultSchedulerMethod) -> Self {
+    pub fn new(
+        method: DefaultSchedulerMethod,
+        policy: SchedulingPolicy,
+        prefill_chunk_size: Option<usize>,
+    ) -> Self {
         let bucketing_manager: Box<dyn BucketingManager<_>> = match method {
             DefaultSchedulerMethod::Fixed(_) => Box::new(FixedBucketingManager),
         };
@@ -187,6 +203,26 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            policy,
+            usage: TenantUsage::default(),
+            prefill_chunk_size,
+        }
+    }
+
+    /// Count the tokens of this step for the tenants of the running sequences.
+    fn record_usage(&mut self) {
+        if self.policy != SchedulingPolicy::FairShare {
+            return;
+        }
+        self.usage.retain(
+            self.running
+                .iter()
+                .chain(self.waiting.iter())
+                .map(|seq| seq.tenant()),
+        );
+        for seq in &self.running {
+            let tokens = if seq.is_prompt() { seq.len() } else { 1 };
+            self.usage.record(seq.tenant(), tokens);
         }
     }
 
@@ -195,13 +231,64 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
     /// without a state modification.
     fn bucket_and_waitlist_seqs(&mut self, running: Vec<Sequence>) -> Vec<Sequence> {
         let waiting = std::mem::take(&mut self.waiting);
-        let BucketedSeqs { running, waiting } = self
-            .bucketing_manager
-            .bucket_and_waitlist_seqs_waiting(running, waiting, true);
+        let BucketedSeqs { running, waiting } = self.bucket(running, waiting, true);
         self.waiting = waiting;
         running
     }
 
+    /// With chunked prefill, the next chunk of each prompt is set and the prompts are bucketed
+    /// separately from the completions, so a chunk of prompts runs in each step alongside the
+    /// completions.
+    fn bucket(
+        &mut self,
+        mut running: Vec<Sequence>,
+        waiting: Backer,
+        discrete: bool,
+    ) -> BucketedSeqs<Backer> {
+        let Some(chunk_size) = self.prefill_chunk_size else {
+            return self
+                .bucketing_manager
+                .bucket_and_waitlist_seqs_waiting(running, waiting, discrete);
+        };
+        for seq in running.iter_mut().filter(|seq| seq.is_prompt()) {
+            seq.set_prefill_chunk(chunk_size);
+        }
+        let (prompt, completion) = running
+            .into_iter()
+            .partition::<Vec<_>, _>(|seq| seq.is_prompt());
+        let BucketedSeqs {
+            mut running,
+            waiting,
+        } = self
+            .bucketing_manager
+            .bucket_and_waitlist_seqs_waiting(completion, waiting, discrete);
+        let BucketedSeqs {
+            running: prompt,
+            waiting,
+        } = self
+            .bucketing_manager
+            .bucket_and_waitlist_seqs_waiting(prompt, waiting, discrete);
+        running.extend(prompt);
+        BucketedSeqs { running, waiting }
+    }
+
+    fn split_prompt_completion(&mut self) -> DefaultSchedulerOutput<'_> {
+        let mut completion = Vec::new();
+        let mut prompt = Vec::new();
+        for seq in &mut self.running {
+            if seq.is_prompt() {
+                prompt.push(seq);
+            } else {
+                completion.push(seq);
+            }
+        }
+
+        DefaultSchedulerOutput {
+            completion: completion.into(),
+            prompt: prompt.into(),
+        }
+    }
+
     /// Schedule all sequences based on their state and the available space.
     pub fn schedule(&mut self, logger: &IntervalLogger) -> DefaultSchedulerOutput<'_> {
         // Filter out all done sequences
@@ -230,6 +317,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                 self.waiting = Backer::new();
                 let running = std::mem::take(&mut self.running);
                 self.running = self.bucket_and_waitlist_seqs(running);
+                self.record_usage();
                 logger.set_num_running(self.running.len());
                 logger.set_num_waiting(self.waiting.len());
                 return DefaultSchedulerOutput {
@@ -245,22 +333,27 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                         .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
                     TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
                 }
+                self.record_usage();
                 logger.set_num_running(self.running.len());
                 logger.set_num_waiting(self.waiting.len());
-                return DefaultSchedulerOutput {
-                    prompt: vec![].into(),
-                    completion: self.running.iter_mut().collect::<Vec<_>>().into(),
-                };
+                // Prompts may still be running with chunked prefill
+                return self.split_prompt_completion();
             }
             _ => {}
         }
 
         // Sort the waiting seqs
         waiting.sort_ascending_ids();
+        let waiting = match self.policy {
+            SchedulingPolicy::Fcfs => waiting.into_iter().collect::<Vec<_>>(),
+            SchedulingPolicy::FairShare => self.usage.order(waiting.into_iter(), |seq| {
+                (seq.tenant().map(ToString::to_string), seq.len())
+            }),
+        };
 
         // If the waiting sequence will fit, add it. Otherwise remove it
         let mut new_waiting = Backer::new();
-        for seq in waiting.into_iter() {
+        for seq in waiting {
             if self.sequence_fits(&running, &seq) {
                 if seq.is_waiting() {
                     seq.set_state(SequenceState::RunningPrompt);
@@ -274,30 +367,16 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         let BucketedSeqs {
             running,
             waiting: new_waiting,
-        } = self
-            .bucketing_manager
-            .bucket_and_waitlist_seqs_waiting(running, new_waiting, false);
+        } = self.bucket(running, new_waiting, false);
 
         self.running = running;
         self.waiting = new_waiting;
+        self.record_usage();
 
         logger.set_num_running(self.running.len());
         logger.set_num_waiting(self.waiting.len());
 
-        let mut completion = Vec::new();
-        let mut prompt = Vec::new();
-        for seq in &mut self.running {
-            if seq.is_completion() {
-                completion.push(seq);
-            } else {
-                prompt.push(seq);
-            }
-        }
-
-        DefaultSchedulerOutput {
-            completion: completion.into(),
-            prompt: prompt.into(),
-        }
+        self.split_prompt_completion()
     }
 
     fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
@@ -319,6 +398,12 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn running_len(&self) -> usize {
         self.running.len()
     }
+    fn running_lora_adapters(&self) -> Vec<LoraAdapterSelection> {
+        self.running
+            .iter()
+            .map(|seq| seq.adapters().map(|a| a.to_vec()))
+            .collect()
+    }
     fn add_seq(&mut self, seq: Sequence) {
         if seq.is_running() {
             // prefill case
@@ -327,6 +412,27 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
             self.waiting.add(seq);
         }
     }
+    fn remove_timed_out(&mut self) -> Vec<Sender<Response>> {
+        let (timed_out, waiting): (Vec<_>, VecDeque<_>) = self
+            .waiting
+            .drain(..)
+            .partition(|seq| seq.timed_out_before_start());
+        self.waiting = waiting;
+        timed_out.iter().map(|seq| seq.responder()).collect()
+    }
+    fn cancel(&mut self, request_id: usize) -> Vec<Sender<Response>> {
+        let (canceled, waiting): (Vec<_>, VecDeque<_>) = self
+            .waiting
+            .drain(..)
+            .partition(|seq| seq.request_id() == request_id && seq.is_queued());
+        self.waiting = waiting;
+        self.running
+            .iter_mut()
+            .chain(self.waiting.iter_mut())
+            .filter(|seq| seq.request_id() == request_id)
+            .for_each(|seq| seq.request_cancel());
+        canceled.iter().map(|seq| seq.responder()).collect()
+    }
     fn block_tables(&self) -> Option<BlockTables> {
         None
     }
@@ -337,4 +443,79 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn block_engine(&self) -> Option<Arc<tokio::sync::Mutex<BlockEngine>>> {
         None
     }
+    fn kv_cache_stats(&self) -> Option<KvCacheStats> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, num::NonZeroUsize};
+
+    use super::{BucketedSeqs, DefaultScheduler, DefaultSchedulerMethod};
+    use crate::{
+        scheduler::SchedulingPolicy,
+        sequence::{tests::new_text_seq, Sequence, SequenceState},
+    };
+
+    fn scheduler(prefill_chunk_size: Option<usize>) -> DefaultScheduler<VecDeque<Sequence>> {
+        DefaultScheduler::new(
+            DefaultSchedulerMethod::Fixed(NonZeroUsize::new(8).unwrap()),
+            SchedulingPolicy::Fcfs,
+            prefill_chunk_size,
+        )
+    }
+
+    fn running_seqs() -> Vec<Sequence> {
+        let prompts = [0..10, 10..20].into_iter().enumerate().map(|(id, tokens)| {
+            let seq = new_text_seq(tokens.collect(), id);
+            seq.set_state(SequenceState::RunningPrompt);
+            seq
+        });
+        let completion = new_text_seq((0..3).collect(), 2);
+        completion.set_state(SequenceState::RunningCompletion);
+        prompts.chain([completion]).collect()
+    }
+
+    #[test]
+    fn prompt_chunks_run_with_the_completions() {
+        // The prompts and the completion are in different buckets
+        let BucketedSeqs { running, waiting } =
+            scheduler(None).bucket(running_seqs(), VecDeque::new(), true);
+        assert_eq!(running.len(), 1);
+        assert_eq!(waiting.len(), 2);
+
+        let mut scheduler = scheduler(Some(4));
+        let mut running = running_seqs();
+        let prompts = running
+            .iter()
+            .filter(|seq| seq.is_prompt())
+            .map(|seq| seq.get_toks().to_vec())
+            .collect::<Vec<_>>();
+        let mut prefilled = vec![Vec::new(); prompts.len()];
+        let mut steps = 0;
+        while running.iter().any(Sequence::is_prompt) {
+            let BucketedSeqs {
+                running: mut scheduled,
+                waiting,
+            } = scheduler.bucket(running, VecDeque::new(), true);
+            // The chunks of the prompts have the same length and offset, so the prompts run
+            // together and alongside the completion in every step
+            assert_eq!(scheduled.len(), 3);
+            assert_eq!(waiting.len(), 0);
+            for seq in scheduled.iter_mut().filter(|seq| seq.is_prompt()) {
+                assert_eq!(seq.token_offset(), prefilled[*seq.id()].len());
+                prefilled[*seq.id()].extend(seq.get_toks());
+                if seq.is_partial_prefill() {
+                    seq.advance_prefill_chunk();
+                } else {
+                    seq.set_state(SequenceState::RunningCompletion);
+                }
+            }
+            running = scheduled;
+            steps += 1;
+        }
+        assert_eq!(steps, 3);
+        assert_eq!(prefilled, prompts);
+    }
 }
@@ -0,0 +1,122 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// How the scheduler orders the sequences which wait to run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
+pub enum SchedulingPolicy {
+    /// First come, first served.
+    #[default]
+    Fcfs,
+    /// Interleave the sequences of each tenant (see `NormalRequest::tenant`), running those of the
+    /// tenants which have consumed the fewest tokens first. This keeps a single heavy tenant from
+    /// starving the others.
+    FairShare,
+}
+
+/// The tokens consumed by the tenants which have sequences in the scheduler.
+#[derive(Default)]
+pub(crate) struct TenantUsage {
+    tokens: HashMap<Option<String>, usize>,
+}
+
+impl TenantUsage {
+    pub(crate) fn record(&mut self, tenant: Option<&str>, tokens: usize) {
+        *self
+            .tokens
+            .entry(tenant.map(ToString::to_string))
+            .or_default() += tokens;
+    }
+
+    pub(crate) fn get(&self, tenant: Option<&str>) -> usize {
+        self.tokens
+            .get(&tenant.map(ToString::to_string))
+            .copied()
+            .unwrap_or_else(|| self.min())
+    }
+
+    fn min(&self) -> usize {
+        self.tokens.values().copied().min().unwrap_or(0)
+    }
+
+    /// Forget the tenants without sequences in the scheduler, so a tenant is not penalized for
+    /// the tokens it consumed while the others were idle.
+    pub(crate) fn retain<'a>(&mut self, active: impl IntoIterator<Item = Option<&'a str>>) {
+        let active = active.into_iter().collect::<HashSet<_>>();
+        self.tokens
+            .retain(|tenant, _| active.contains(&tenant.as_deref()));
+    }
+
+    /// Order `items` by repeatedly taking the oldest item of the tenant with the fewest tokens,
+    /// counting the tokens of the items taken before it. `key` gives the tenant and tokens of an
+    /// item. New tenants start with the fewest tokens of the known tenants.
+    pub(crate) fn order<T>(
+        &self,
+        items: impl IntoIterator<Item = T>,
+        key: impl Fn(&T) -> (Option<String>, usize),
+    ) -> Vec<T> {
+        // The tenants in order of their oldest item, which breaks ties
+        let mut tenants = Vec::new();
+        let mut queues: HashMap<Option<String>, VecDeque<(T, usize)>> = HashMap::new();
+        for item in items {
+            let (tenant, tokens) = key(&item);
+            if !queues.contains_key(&tenant) {
+                tenants.push(tenant.clone());
+            }
+            queues.entry(tenant).or_default().push_back((item, tokens));
+        }
+
+        let mut usage = tenants
+            .iter()
+            .map(|tenant| self.get(tenant.as_deref()))
+            .collect::<Vec<_>>();
+        let mut ordered = Vec::new();
+        loop {
+            let Some(i) = (0..tenants.len())
+                .filter(|i| !queues[&tenants[*i]].is_empty())
+                .min_by_key(|i| usage[*i])
+            else {
+                break;
+            };
+            let (item, tokens) = queues.get_mut(&tenants[i]).unwrap().pop_front().unwrap();
+            usage[i] += tokens;
+            ordered.push(item);
+        }
+        ordered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TenantUsage;
+
+    #[test]
+    fn interleaves_by_usage() {
+        let mut usage = TenantUsage::default();
+        usage.record(Some("heavy"), 100);
+        usage.record(Some("light"), 10);
+
+        let items = [
+            ("heavy", 10),
+            ("heavy", 10),
+            ("light", 50),
+            ("light", 50),
+            ("light", 50),
+            ("new", 5),
+        ];
+        let ordered = usage.order(items, |(tenant, tokens)| {
+            (Some(tenant.to_string()), *tokens)
+        });
+        // `new` starts at the lowest usage, 10, and ties go to the tenant which came first
+        assert_eq!(
+            ordered,
+            vec![
+                ("light", 50),
+                ("new", 5),
+                ("light", 50),
+                ("heavy", 10),
+                ("heavy", 10),
+                ("light", 50),
+            ]
+        );
+    }
+}
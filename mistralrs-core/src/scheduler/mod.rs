@@ -1,9 +1,13 @@
 mod default_scheduler;
+mod fair_share;
 
 use std::sync::Arc;
 
 pub use default_scheduler::{DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput};
-use tokio::sync::Mutex;
+pub use fair_share::SchedulingPolicy;
+pub(crate) use fair_share::TenantUsage;
+use mistralrs_quant::LoraAdapterSelection;
+use tokio::sync::{mpsc::Sender, Mutex};
 
 use crate::{
     engine::IntervalLogger,
@@ -11,7 +15,9 @@ use crate::{
         BlockEngine, BlockTables, CacheConfig, PagedAttentionScheduler,
         PagedAttentionSchedulerConfig, PagedAttentionSchedulerOutput,
     },
+    request::KvCacheStats,
     sequence::Sequence,
+    Response,
 };
 
 #[derive(Clone)]
@@ -26,16 +32,27 @@ pub enum SchedulerConfig {
 }
 
 impl SchedulerConfig {
-    pub fn into_scheduler(self) -> Arc<Mutex<dyn Scheduler>> {
+    /// `prefill_chunk_size` is only used by the default scheduler, the engine rejects it with
+    /// PagedAttention.
+    pub fn into_scheduler(
+        self,
+        policy: SchedulingPolicy,
+        prefill_chunk_size: Option<usize>,
+    ) -> Arc<Mutex<dyn Scheduler>> {
         match self {
-            Self::DefaultScheduler { method } => {
-                Arc::new(Mutex::new(DefaultScheduler::new(method)))
-            }
+            Self::DefaultScheduler { method } => Arc::new(Mutex::new(DefaultScheduler::new(
+                method,
+                policy,
+                prefill_chunk_size,
+            ))),
             Self::PagedAttentionMeta {
                 max_num_seqs,
                 config,
             } => Arc::new(Mutex::new(PagedAttentionScheduler::new(
-                PagedAttentionSchedulerConfig { max_num_seqs },
+                PagedAttentionSchedulerConfig {
+                    max_num_seqs,
+                    policy,
+                },
                 config,
             ))),
         }
@@ -55,7 +72,15 @@ pub trait Scheduler: Send + Sync {
     fn schedule(&mut self, logger: &IntervalLogger) -> SchedulerOutput<'_>;
     fn waiting_len(&self) -> usize;
     fn running_len(&self) -> usize;
+    /// The LoRA adapters selected by each running sequence, `None` selecting all adapters.
+    fn running_lora_adapters(&self) -> Vec<LoraAdapterSelection>;
     fn add_seq(&mut self, seq: Sequence);
+    /// Remove the waiting sequences whose deadline passed before they started, returning their
+    /// responders.
+    fn remove_timed_out(&mut self) -> Vec<Sender<Response>>;
+    /// Cancel the sequences of the request `request_id`. Queued sequences are removed and their
+    /// responders returned, the others finish after their next step.
+    fn cancel(&mut self, request_id: usize) -> Vec<Sender<Response>>;
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
 
@@ -63,4 +88,6 @@ pub trait Scheduler: Send + Sync {
     fn block_tables(&self) -> Option<BlockTables>;
     fn block_size(&self) -> Option<usize>;
     fn block_engine(&self) -> Option<Arc<Mutex<BlockEngine>>>;
+    /// A snapshot of the use of the PagedAttention KV cache.
+    fn kv_cache_stats(&self) -> Option<KvCacheStats>;
 }
@@ -1,5 +1,7 @@
 use crate::{
+    contrastive::ContrastiveSearch,
     get_mut_arcmutex, get_mut_group,
+    guidance::Guidance,
     paged_attention::PhysicalTokenBlock,
     pipeline::{text_models_inputs_processor::PagedAttentionMeta, LayerCaches},
     response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
@@ -8,18 +10,20 @@ use crate::{
 };
 use crate::{
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
-    pipeline::{DiffusionGenerationParams, KvCache},
+    pipeline::{DiffusionGenerationParams, GeneralMetadata, KvCache},
     response::CompletionChoice,
     tools::ToolCallingMatcher,
     CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, ImageChoice,
     ImageGenerationResponse, ImageGenerationResponseFormat,
 };
 use candle_core::Tensor;
+use rand::SeedableRng;
+use rand_isaac::Isaac64Rng;
 use std::{
     fmt::Display,
     hash::{DefaultHasher, Hash, Hasher},
     sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::{
     mpsc::{error::SendError, Sender},
@@ -36,7 +40,13 @@ pub enum StopReason {
         stop_string_idx: usize,
         completion_bytes_pos: usize,
     },
+    StopRegex {
+        stop_regex_idx: usize,
+        completion_bytes_pos: usize,
+    },
     Canceled,
+    Timeout,
+    KvQuota(usize),
     GeneratedImage,
     GeneratedSpeech,
     ToolCalls,
@@ -47,8 +57,12 @@ impl Display for StopReason {
         match self {
             StopReason::Eos => write!(f, "stop"),
             StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
-            StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
+            StopReason::StopTok(_)
+            | StopReason::StopString { .. }
+            | StopReason::StopRegex { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
+            StopReason::Timeout => write!(f, "timeout"),
+            StopReason::KvQuota(_) => write!(f, "kv_quota"),
             StopReason::GeneratedImage => write!(f, "generated_image"),
             StopReason::GeneratedSpeech => write!(f, "generated_speech"),
             StopReason::ToolCalls => write!(f, "tool_calls"),
@@ -160,6 +174,98 @@ impl SequenceCustomMetadata {
     }
 }
 
+/// Attention sinks in the style of StreamingLLM (<https://arxiv.org/abs/2309.17453>). Once a
+/// sequence reaches the maximum length of the model, only its first `sinks` tokens and its
+/// `window` most recent tokens are kept in its context. The kept tokens are prefilled again, so
+/// their positions restart from the start of the context and the generation can continue
+/// indefinitely, forgetting the tokens in between.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttentionSinkConfig {
+    /// The number of tokens at the start of the sequence which are always kept.
+    pub sinks: usize,
+    /// The number of most recent tokens which are kept.
+    pub window: usize,
+}
+
+impl AttentionSinkConfig {
+    /// Keep 4 attention sinks and the `window` most recent tokens.
+    pub fn new(window: usize) -> Self {
+        Self { sinks: 4, window }
+    }
+
+    pub fn with_sinks(mut self, sinks: usize) -> Self {
+        self.sinks = sinks;
+        self
+    }
+}
+
+/// The most KV cache a single sequence may hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvQuota {
+    Tokens(usize),
+    /// Bytes of KV cache over all layers. With PagedAttention, this is rounded down to whole blocks.
+    Bytes(usize),
+}
+
+/// What happens to a sequence once it holds its [`KvQuota`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KvQuotaAction {
+    /// Finish it with the finish reason `kv_quota`.
+    #[default]
+    Finish,
+    /// Keep only its attention sinks and most recent tokens in its context, as with
+    /// `MistralRsBuilder::with_attention_sinks`, and keep generating.
+    Evict(AttentionSinkConfig),
+}
+
+/// A bound on the KV cache each sequence may hold, so a single long request cannot take the whole
+/// KV cache from the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KvQuotaConfig {
+    pub quota: KvQuota,
+    pub action: KvQuotaAction,
+}
+
+impl KvQuotaConfig {
+    /// Finish the sequences which reach `quota`.
+    pub fn new(quota: KvQuota) -> Self {
+        Self {
+            quota,
+            action: KvQuotaAction::default(),
+        }
+    }
+
+    pub fn with_action(mut self, action: KvQuotaAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// The most tokens a sequence may hold, or `None` if the size of the KV cache of the model is
+    /// unknown.
+    pub(crate) fn max_tokens(&self, metadata: &GeneralMetadata) -> Option<usize> {
+        let bytes = match self.quota {
+            KvQuota::Tokens(tokens) => return Some(tokens),
+            KvQuota::Bytes(bytes) => bytes,
+        };
+        let model = metadata.model_metadata.as_ref()?;
+        let dtype = match &metadata.cache_config {
+            Some(cache_config) => cache_config.cache_type.to_dtype(metadata.activation_dtype),
+            None => metadata.activation_dtype,
+        };
+        // The same size as PagedAttention allocates for each token
+        let bytes_per_token = dtype.size_in_bytes()
+            * model.num_kv_heads()
+            * model.k_head_dim().max(model.v_head_dim())
+            * model.num_layers()
+            * 2;
+        let tokens = bytes / bytes_per_token;
+        Some(match &metadata.cache_config {
+            Some(cache_config) => tokens / cache_config.block_size * cache_config.block_size,
+            None => tokens,
+        })
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum SeqStepType {
     PromptAndDecode,
@@ -383,6 +489,9 @@ pub struct Sequence {
     sampler: Arc<Sampler>,
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
+    stop_regexes: Vec<regex::bytes::Regex>,
+    // The completion ends after the matched stop string or regex, instead of before it
+    include_stop_str: bool,
     return_logprobs: bool,
     responder: Sender<Response>,
     response_index: usize,
@@ -392,6 +501,17 @@ pub struct Sequence {
     pub(crate) return_raw_logits: bool,
     token_offset: usize,
     eos_tokens: Vec<u32>,
+    adapters: Option<Vec<(String, f64)>>,
+    tenant: Option<String>,
+    deadline: Option<Instant>,
+    request_id: usize,
+    cancel_requested: bool,
+
+    // Attention sinks
+    attention_sinks: Option<AttentionSinkConfig>,
+    num_shifted_out: usize, // The tokens dropped from the context, which still count as generated
+    context_shifted: bool,
+    kv_quota: Option<(usize, KvQuotaAction)>,
 
     // Multimodal data (images, diffusion settings, pixel caches)
     pub multimodal: MultimodalData,
@@ -403,8 +523,25 @@ pub struct Sequence {
     // Speculative
     is_tmp: bool,
 
+    // The text of the token which token healing removed from the prompt, repeated at the start
+    // of the first generated token
+    healed_prefix: Vec<u8>,
+
     // Prefix caching
     prefill_prompt_toks: Option<Vec<u32>>,
+    // The other choices of the request, started from the KV cache of this sequence once it
+    // computed their shared prompt
+    prompt_forks: Vec<Sequence>,
+    // The negative prompt of classifier-free guidance
+    guidance: Option<Guidance>,
+    // The copy of this sequence which computes the hidden states of contrastive search
+    contrastive: Option<ContrastiveSearch>,
+    // The copy of the prompt which is run once to compute the logprobs of its tokens
+    prompt_logprobs_seq: Option<Box<Sequence>>,
+    // The logprobs of the tokens of the prompt after the first
+    prompt_logprobs: Vec<Logprobs>,
+    // The random number generator of a seeded sequence, instead of the one of the engine
+    rng: Option<Arc<std::sync::Mutex<Isaac64Rng>>>,
 
     // Cache
     normal_cache: Vec<Option<KvCache>>,
@@ -526,6 +663,12 @@ impl Sequence {
         //
         return_raw_logits: bool,
         eos_tokens: Vec<u32>,
+        adapters: Option<Vec<(String, f64)>>,
+        tenant: Option<String>,
+        deadline: Option<Instant>,
+        request_id: usize,
+        attention_sinks: Option<AttentionSinkConfig>,
+        kv_quota: Option<(usize, KvQuotaAction)>,
     ) -> Self {
         let prompt_len = tokens.len();
         let mut custom_metadata = if let Some(block_size) = block_size {
@@ -561,6 +704,8 @@ impl Sequence {
             sampler: sampler.into(),
             stop_tokens,
             stop_strings,
+            stop_regexes: Vec::new(),
+            include_stop_str: false,
             max_len,
             return_logprobs,
             prompt_tok_per_sec: 0.,
@@ -570,7 +715,14 @@ impl Sequence {
             response_index,
             creation_time,
             recognizer,
+            healed_prefix: Vec::new(),
             prefill_prompt_toks: None,
+            prompt_forks: Vec::new(),
+            guidance: None,
+            contrastive: None,
+            prompt_logprobs_seq: None,
+            prompt_logprobs: Vec::new(),
+            rng: None,
             suffix,
             prefix,
             cumulative_logprob: 0.,
@@ -594,6 +746,15 @@ impl Sequence {
             return_raw_logits,
             token_offset: 0,
             eos_tokens,
+            adapters,
+            tenant,
+            deadline,
+            request_id,
+            cancel_requested: false,
+            attention_sinks,
+            num_shifted_out: 0,
+            context_shifted: false,
+            kv_quota,
             total_prompt_time: None,
             waitlisted_count: 0,
         }
@@ -654,6 +815,119 @@ impl Sequence {
         self
     }
 
+    /// Prefill all tokens again once the PagedAttention blocks of the sequence were freed, starting
+    /// after the shared blocks `physical_blocks` of its prefix.
+    pub(crate) fn reset_paged_prefill(&mut self, physical_blocks: Vec<Arc<PhysicalTokenBlock>>) {
+        let SequenceCustomMetadata::PagedAttention {
+            physical_blocks_prefill,
+            block_size,
+            ..
+        } = &mut self.custom_metadata
+        else {
+            return;
+        };
+        self.token_offset = physical_blocks.len() * *block_size;
+        self.prefill_prompt_toks =
+            (self.token_offset > 0).then(|| self.tokens[self.token_offset..].to_vec());
+        *physical_blocks_prefill = (!physical_blocks.is_empty()).then_some(physical_blocks);
+    }
+
+    /// Stop the sequence once the text generated so far matches one of `stop_regexes`. Each
+    /// match, like each match of a stop string, is kept at the end of the completion if
+    /// `include_stop_str` is set.
+    pub(crate) fn set_stop_regexes(
+        &mut self,
+        stop_regexes: Vec<regex::bytes::Regex>,
+        include_stop_str: bool,
+    ) {
+        self.stop_regexes = stop_regexes;
+        self.include_stop_str = include_stop_str;
+    }
+
+    /// The first generated token starts with `healed_prefix`, the text of the token which token
+    /// healing removed from the prompt, and which is not part of the completion.
+    pub(crate) fn set_healed_prefix(&mut self, healed_prefix: Vec<u8>) {
+        self.healed_prefix = healed_prefix;
+    }
+
+    /// Start `forks`, the other choices of the request, once this sequence computed the prompt they
+    /// share, instead of each of them computing it.
+    pub(crate) fn set_prompt_forks(&mut self, forks: Vec<Sequence>) {
+        self.prompt_forks = forks;
+    }
+
+    pub(crate) fn take_prompt_forks(&mut self) -> Vec<Sequence> {
+        std::mem::take(&mut self.prompt_forks)
+    }
+
+    pub(crate) fn set_guidance(&mut self, guidance: Guidance) {
+        self.guidance = Some(guidance);
+    }
+
+    pub(crate) fn take_guidance(&mut self) -> Option<Guidance> {
+        self.guidance.take()
+    }
+
+    pub(crate) fn has_guidance(&self) -> bool {
+        self.guidance.is_some()
+    }
+
+    pub(crate) fn set_contrastive(&mut self, contrastive: ContrastiveSearch) {
+        self.contrastive = Some(contrastive);
+    }
+
+    pub(crate) fn take_contrastive(&mut self) -> Option<ContrastiveSearch> {
+        self.contrastive.take()
+    }
+
+    pub(crate) fn has_contrastive(&self) -> bool {
+        self.contrastive.is_some()
+    }
+
+    /// Compute the logprobs of the prompt tokens by running `seq`, a copy of the prompt, once the
+    /// prompt was computed.
+    pub(crate) fn set_prompt_logprobs_seq(&mut self, seq: Sequence) {
+        self.prompt_logprobs_seq = Some(Box::new(seq));
+    }
+
+    pub(crate) fn take_prompt_logprobs_seq(&mut self) -> Option<Box<Sequence>> {
+        self.prompt_logprobs_seq.take()
+    }
+
+    pub(crate) fn set_prompt_logprobs(&mut self, logprobs: Vec<Logprobs>) {
+        self.prompt_logprobs = logprobs;
+    }
+
+    /// The prompt which starts the text of the choice, for a completion request with `echo`.
+    pub(crate) fn echoed_prompt(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    /// The logprobs of the tokens of the prompt after the first, if they were requested.
+    pub fn prompt_logprobs(&self) -> &[Logprobs] {
+        &self.prompt_logprobs
+    }
+
+    /// Sample with a random number generator seeded with `seed` and used by no other sequence.
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        self.rng = Some(Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(
+            seed,
+        ))));
+    }
+
+    pub(crate) fn rng(&self) -> Option<Arc<std::sync::Mutex<Isaac64Rng>>> {
+        self.rng.clone()
+    }
+
+    /// Whether the PagedAttention blocks of the sequence may be shared with other sequences which
+    /// start with the same tokens. The tokens of images and audio do not identify them, and the KV
+    /// cache depends on the LoRA adapters.
+    pub(crate) fn can_share_blocks(&self) -> bool {
+        self.adapters.is_none()
+            && self.image_hashes().is_none_or(|x| x.is_empty())
+            && self.audio_hashes().is_none_or(|x| x.is_empty())
+    }
+
     /// This is the number of tokens. If the KV cache is Some, then it will use that.
     pub fn len(&self) -> usize {
         if let Some(toks) = &self.prefill_prompt_toks {
@@ -734,6 +1008,37 @@ impl Sequence {
         self.token_offset
     }
 
+    /// Process at most the next `chunk_size` tokens of the prompt which are not in the KV cache in
+    /// the next step. Prompts with images or audio, X-LoRA sequences and sequences which return
+    /// the raw logits are processed at once.
+    pub(crate) fn set_prefill_chunk(&mut self, chunk_size: usize) {
+        if self.has_images()
+            || self.has_audios()
+            || self.is_xlora()
+            || self.return_raw_logits
+            || !matches!(self.sequence_stepping_type, SeqStepType::PromptAndDecode)
+        {
+            return;
+        }
+        let end = self.tokens.len().min(self.token_offset + chunk_size.max(1));
+        self.prefill_prompt_toks = Some(self.tokens[self.token_offset..end].to_vec());
+    }
+
+    /// If the prompt tokens of the next step are a chunk which does not end the prompt, so no
+    /// token is sampled after them.
+    pub fn is_partial_prefill(&self) -> bool {
+        self.prefill_prompt_toks
+            .as_ref()
+            .is_some_and(|toks| self.token_offset + toks.len() < self.tokens.len())
+    }
+
+    /// Mark the current prompt chunk as processed.
+    pub(crate) fn advance_prefill_chunk(&mut self) {
+        if let Some(toks) = self.prefill_prompt_toks.take() {
+            self.token_offset += toks.len();
+        }
+    }
+
     /// This will also set prompt_len
     pub(crate) fn set_toks_and_reallocate(
         &mut self,
@@ -839,11 +1144,15 @@ impl Sequence {
             is_done,
             Some(StopReason::Eos) | Some(StopReason::StopTok(_))
         );
+        let healed_prefix = std::mem::take(&mut self.healed_prefix);
+        let completion_bytes = completion_bytes
+            .strip_prefix(healed_prefix.as_slice())
+            .unwrap_or(&completion_bytes);
         if !stopped_by_token {
             // Completion bytes is used to check for stop strings, and as the response buffer.
             // We don't need to add stop tokens to the completion bytes to check for stop strings.
             // And by not adding it here, we can avoid having to delete these tokens from the output.
-            self.completion_bytes.extend_from_slice(&completion_bytes);
+            self.completion_bytes.extend_from_slice(completion_bytes);
             self.last_completion_bytes_len = completion_bytes.len();
         }
         self.last_logprob = tok.logprob;
@@ -853,6 +1162,12 @@ impl Sequence {
             .append_token_to_blocks(tok.token as usize);
 
         self.cumulative_logprob += tok.logprob;
+        if let Some(guidance) = &mut self.guidance {
+            guidance.seq.tokens.push(tok.token);
+        }
+        if let Some(contrastive) = &mut self.contrastive {
+            contrastive.seq.tokens.push(tok.token);
+        }
         self.tokens.push(tok.token);
         self.logprobs.push(tok);
         self.reset_prefill_toks();
@@ -895,14 +1210,19 @@ impl Sequence {
             SequenceState::Done(StopReason::Canceled)
         ) {
             Some(StopReason::Canceled)
+        } else if self.cancel_requested {
+            Some(StopReason::Canceled)
+        } else if self.is_past_deadline() {
+            Some(StopReason::Timeout)
         } else if self.stop_tokens.contains(&tok) {
             Some(StopReason::StopTok(tok))
-        } else if self.max_len.is_some()
-            && self.tokens.len().saturating_sub(self.prompt_len) + 1 >= self.max_len.unwrap()
+        } else if self.max_len.is_some() && self.num_completion_toks() + 1 >= self.max_len.unwrap()
         {
             // add_token will be called after this check
             Some(StopReason::Length(self.max_len.unwrap()))
-        } else if self.tokens.len().saturating_sub(self.prompt_len) >= max_model_len {
+        } else if let Some(max_tokens) = self.kv_quota_to_finish() {
+            Some(StopReason::KvQuota(max_tokens))
+        } else if !self.can_shift_context() && self.num_completion_toks() >= max_model_len {
             Some(StopReason::ModelLength(max_model_len))
         } else {
             if !self.stop_strings.is_empty() {
@@ -911,11 +1231,27 @@ impl Sequence {
                     {
                         return Some(StopReason::StopString {
                             stop_string_idx: idx,
-                            completion_bytes_pos: pos,
+                            completion_bytes_pos: if self.include_stop_str {
+                                pos + s.len()
+                            } else {
+                                pos
+                            },
                         });
                     }
                 }
             }
+            for (idx, re) in self.stop_regexes.iter().enumerate() {
+                if let Some(m) = re.find(&self.completion_bytes) {
+                    return Some(StopReason::StopRegex {
+                        stop_regex_idx: idx,
+                        completion_bytes_pos: if self.include_stop_str {
+                            m.end()
+                        } else {
+                            m.start()
+                        },
+                    });
+                }
+            }
             None
         }
     }
@@ -937,6 +1273,14 @@ impl Sequence {
     }
 
     /// Returns the delta between the last two decoded sequences
+    /// The number of characters of the completion which were streamed so far.
+    pub(crate) fn streamed_chars(&self) -> usize {
+        String::from_utf8_lossy(&self.completion_bytes[..self.stream_idx])
+            .trim_start()
+            .chars()
+            .count()
+    }
+
     pub fn get_delta(
         &mut self,
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
@@ -987,7 +1331,7 @@ impl Sequence {
         get_mut_group!(self).total_time = now - self.timestamp;
 
         get_mut_group!(self).total_prompt_toks = self.prompt_len;
-        get_mut_group!(self).total_toks = self.len();
+        get_mut_group!(self).total_toks = self.len() + self.num_shifted_out;
     }
 
     pub fn add_image_choice_to_group(&self, choice: ImageChoice) {
@@ -1041,6 +1385,120 @@ impl Sequence {
         self.update_time_info();
     }
 
+    /// The LoRA adapters this sequence runs with, and their weights. `None` means all loaded
+    /// adapters with a weight of 1.
+    pub fn adapters(&self) -> Option<&[(String, f64)]> {
+        self.adapters.as_deref()
+    }
+
+    /// The client this sequence is run for, if the request set one.
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// Whether the deadline of the request has passed.
+    pub fn is_past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Whether this sequence is waiting to run and has not generated any tokens.
+    pub(crate) fn is_queued(&self) -> bool {
+        self.is_waiting() && self.num_completion_toks() == 0
+    }
+
+    /// Whether the deadline of the request passed before this sequence generated any tokens.
+    pub(crate) fn timed_out_before_start(&self) -> bool {
+        self.is_queued() && self.is_past_deadline()
+    }
+
+    /// The ID of the request this sequence was created for, see `NormalRequest::id`.
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+
+    /// The number of tokens generated so far, including those dropped from the context by the
+    /// attention sinks.
+    fn num_completion_toks(&self) -> usize {
+        (self.tokens.len() + self.num_shifted_out).saturating_sub(self.prompt_len)
+    }
+
+    /// Whether the context of the sequence is shifted by its attention sinks once it reaches the
+    /// maximum length. Sequences with images or audio are not shifted, as the kept tokens could
+    /// split them.
+    fn can_shift_context(&self) -> bool {
+        (self.attention_sinks.is_some()
+            || matches!(self.kv_quota, Some((_, KvQuotaAction::Evict(_)))))
+            && !self.is_tmp
+            && matches!(self.sequence_stepping_type, SeqStepType::PromptAndDecode)
+            && self.image_hashes().is_none_or(|x| x.is_empty())
+            && self.audio_hashes().is_none_or(|x| x.is_empty())
+    }
+
+    /// The KV quota of the sequence if the next token reaches it and the sequence is finished then.
+    /// Sequences which evict are finished too when their context cannot be shifted.
+    fn kv_quota_to_finish(&self) -> Option<usize> {
+        let (max_tokens, action) = self.kv_quota?;
+        let finish = matches!(action, KvQuotaAction::Finish) || !self.can_shift_context();
+        (finish && self.tokens.len() + 1 >= max_tokens).then_some(max_tokens)
+    }
+
+    /// Once the sequence reaches `max_seq_len` tokens, or its KV quota if it evicts, keep only its
+    /// attention sinks and its most recent tokens, and drop its KV cache so the kept tokens are
+    /// prefilled again in its next step. Returns whether the context was shifted.
+    pub(crate) fn maybe_shift_context(&mut self, max_seq_len: usize) -> bool {
+        let (AttentionSinkConfig { sinks, window }, max_len) = match self.kv_quota {
+            Some((max_tokens, KvQuotaAction::Evict(sinks))) => (sinks, max_tokens.min(max_seq_len)),
+            _ => match self.attention_sinks {
+                Some(sinks) => (sinks, max_seq_len),
+                None => return false,
+            },
+        };
+        if self.tokens.len() < max_len || !self.can_shift_context() {
+            return false;
+        }
+        let n_dropped = self.tokens.len() - sinks - window;
+        self.tokens.drain(sinks..sinks + n_dropped);
+        self.num_shifted_out += n_dropped;
+
+        if let SequenceCustomMetadata::PagedAttention {
+            logical_token_blocks,
+            physical_blocks_prefill,
+            block_size: _,
+        } = &mut self.custom_metadata
+        {
+            logical_token_blocks.clear();
+            *physical_blocks_prefill = None;
+        }
+        self.custom_metadata
+            .append_tokens_to_blocks(self.tokens.iter().map(|x| *x as usize).collect());
+
+        self.normal_cache.iter_mut().for_each(|c| *c = None);
+        self.normal_draft_cache.iter_mut().for_each(|c| *c = None);
+        self.cache.iter_mut().for_each(|c| *c = None);
+        self.draft_cache.iter_mut().for_each(|c| *c = None);
+        if let Some(xlora_cache) = &mut self.xlora_cache {
+            xlora_cache.iter_mut().for_each(|c| *c = None);
+        }
+        self.scaling_cache = None;
+        self.prefill_prompt_toks = None;
+        self.token_offset = 0;
+        self.context_shifted = true;
+        self.set_state(SequenceState::RunningPrompt);
+        true
+    }
+
+    /// Whether the context was shifted since the last call, which means the PagedAttention blocks
+    /// of the sequence must be freed and allocated again for its kept tokens.
+    pub(crate) fn take_context_shift(&mut self) -> bool {
+        std::mem::take(&mut self.context_shifted)
+    }
+
+    /// Finish this sequence after its next step, with the finish reason `canceled`.
+    pub(crate) fn request_cancel(&mut self) {
+        self.cancel_requested = true;
+    }
+
     pub fn take_images(&mut self) -> Option<Vec<image::DynamicImage>> {
         self.multimodal.take_images()
     }
@@ -1298,6 +1756,7 @@ impl SequenceGroup {
                     model: model.clone(),
                     system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                     object: "text_completion".to_string(),
+                    usage: usage_opt,
                 }))
                 .await?;
         }
@@ -1315,3 +1774,184 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+
+    use super::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer};
+    use crate::sampler::Sampler;
+
+    /// A text sequence with the prompt `tokens` and no KV cache.
+    pub(crate) fn new_text_seq(tokens: Vec<u32>, id: usize) -> Sequence {
+        let (responder, _) = tokio::sync::mpsc::channel(1);
+        let sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            vec![],
+        )
+        .unwrap();
+        Sequence::new_waiting(
+            tokens,
+            String::new(),
+            id,
+            0,
+            1,
+            responder,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            false,
+            false,
+            Arc::new(Mutex::new(SequenceGroup::new(1, false, false, None))),
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            SeqStepType::PromptAndDecode,
+            None,
+            None,
+            false,
+            vec![],
+            None,
+            None,
+            None,
+            id,
+            None,
+            None,
+        )
+    }
+
+    /// The tokens of the chunks `steps`, checking that each chunk starts where the previous one
+    /// ended.
+    fn concat_chunks(steps: &[(usize, Vec<u32>)]) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        for (offset, chunk) in steps {
+            assert_eq!(*offset, steps[0].0 + tokens.len());
+            tokens.extend(chunk);
+        }
+        tokens
+    }
+
+    /// The (offset, tokens) of each prompt step with chunks of `chunk_size` tokens, as the engine
+    /// runs them.
+    fn prefill_steps(seq: &mut Sequence, chunk_size: usize) -> Vec<(usize, Vec<u32>)> {
+        let mut steps = Vec::new();
+        loop {
+            seq.set_prefill_chunk(chunk_size);
+            steps.push((seq.token_offset(), seq.get_toks().to_vec()));
+            if !seq.is_partial_prefill() {
+                return steps;
+            }
+            seq.advance_prefill_chunk();
+        }
+    }
+
+    #[test]
+    fn chunked_prefill_matches_one_shot_prefill() {
+        let tokens = (0..10).collect::<Vec<u32>>();
+        let one_shot = new_text_seq(tokens.clone(), 0);
+        assert!(!one_shot.is_partial_prefill());
+        assert_eq!(
+            (one_shot.token_offset(), one_shot.get_toks()),
+            (0, &tokens[..])
+        );
+
+        let mut seq = new_text_seq(tokens.clone(), 0);
+        let steps = prefill_steps(&mut seq, 4);
+        assert_eq!(
+            steps,
+            vec![
+                (0, vec![0, 1, 2, 3]),
+                (4, vec![4, 5, 6, 7]),
+                (8, vec![8, 9]),
+            ]
+        );
+        assert_eq!(concat_chunks(&steps), tokens);
+        assert_eq!(seq.len(), 2);
+
+        // A prompt which fits in one chunk, or ends exactly at a chunk boundary
+        let mut seq = new_text_seq(tokens.clone(), 0);
+        assert_eq!(prefill_steps(&mut seq, 10), vec![(0, tokens.clone())]);
+        let mut seq = new_text_seq(tokens.clone(), 0);
+        assert_eq!(
+            prefill_steps(&mut seq, 5),
+            vec![(0, vec![0, 1, 2, 3, 4]), (5, vec![5, 6, 7, 8, 9])]
+        );
+        // A chunk has at least one token
+        let mut seq = new_text_seq(tokens.clone(), 0);
+        assert_eq!(prefill_steps(&mut seq, 0).len(), 10);
+    }
+
+    #[test]
+    fn chunked_prefill_starts_after_the_prefix_cache() {
+        let tokens = (0..10).collect::<Vec<u32>>();
+        let one_shot =
+            new_text_seq(tokens.clone(), 0).prefill_v2_normal(vec![None], tokens[6..].to_vec(), 6);
+        assert!(!one_shot.is_partial_prefill());
+        assert_eq!(
+            (one_shot.token_offset(), one_shot.get_toks()),
+            (6, &tokens[6..])
+        );
+
+        let mut seq =
+            new_text_seq(tokens.clone(), 0).prefill_v2_normal(vec![None], tokens[6..].to_vec(), 6);
+        let steps = prefill_steps(&mut seq, 3);
+        assert_eq!(steps, vec![(6, vec![6, 7, 8]), (9, vec![9])]);
+        assert_eq!(concat_chunks(&steps), &tokens[6..]);
+    }
+
+    #[test]
+    fn prompt_forks_wait_for_the_last_chunk() {
+        let tokens = (0..10).collect::<Vec<u32>>();
+        let mut seq = new_text_seq(tokens.clone(), 0);
+        seq.set_prompt_forks(vec![
+            new_text_seq(tokens.clone(), 1),
+            new_text_seq(tokens.clone(), 2),
+        ]);
+        // The engine starts the forks once the prompt step is not a partial prefill
+        for _ in 0..2 {
+            seq.set_prefill_chunk(4);
+            assert!(seq.is_partial_prefill());
+            seq.advance_prefill_chunk();
+        }
+        seq.set_prefill_chunk(4);
+        assert!(!seq.is_partial_prefill());
+        let forks = seq.take_prompt_forks();
+        assert_eq!(
+            forks.iter().map(|fork| *fork.id()).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert!(forks.iter().all(|fork| fork.get_toks() == tokens));
+    }
+
+    #[test]
+    fn raw_logits_prompts_are_not_chunked() {
+        let tokens = (0..10).collect::<Vec<u32>>();
+        let mut seq = new_text_seq(tokens.clone(), 0);
+        seq.return_raw_logits = true;
+        seq.set_prefill_chunk(4);
+        assert!(!seq.is_partial_prefill());
+        assert_eq!(seq.get_toks(), tokens);
+    }
+}
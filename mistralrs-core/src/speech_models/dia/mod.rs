@@ -19,7 +19,7 @@ use tracing::info;
 
 use crate::ops::apply_triangular;
 
-use super::{utils::normalize_loudness, SpeechGenerationConfig, SpeechGenerationOutput};
+use super::{dac, utils::normalize_loudness, SpeechGenerationConfig, SpeechGenerationOutput};
 
 /// Aggregated outputs for generation preparation.
 pub struct PrepareGenerationOutput {
@@ -34,7 +34,6 @@ pub struct PrepareGenerationOutput {
 mod audio;
 mod cache;
 mod config;
-mod dac;
 mod model;
 
 const RATE: usize = 44100;
@@ -388,7 +387,10 @@ impl DiaPipeline {
             temperature,
             top_p,
             top_k,
-        } = cfg;
+        } = cfg
+        else {
+            candle_core::bail!("Dia expects a Dia speech generation config.");
+        };
 
         let audio_pad_value = self.cfg.data.audio_pad_value as u32;
         let audio_eos_value = self.cfg.data.audio_eos_value as u32;
@@ -1,16 +1,33 @@
 mod bs1770;
+mod dac;
 mod dia;
+mod parler;
 pub mod utils;
+mod whisper;
 
 use std::{str::FromStr, sync::Arc};
 
+use candle_core::{Device, Result};
 pub use dia::{DiaConfig, DiaPipeline};
-use serde::Deserialize;
+pub use parler::{ParlerConfig, ParlerPipeline};
+use serde::{Deserialize, Serialize};
+pub use whisper::{WhisperConfig, WhisperPipeline};
 
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
 pub enum SpeechLoaderType {
     #[serde(rename = "dia")]
     Dia,
+    #[serde(rename = "parler")]
+    Parler,
+    #[serde(rename = "whisper")]
+    Whisper,
+}
+
+impl SpeechLoaderType {
+    /// Whether the model transcribes speech to text, rather than generating speech.
+    pub fn is_transcription(&self) -> bool {
+        matches!(self, Self::Whisper)
+    }
 }
 
 impl FromStr for SpeechLoaderType {
@@ -18,14 +35,16 @@ impl FromStr for SpeechLoaderType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "dia" => Ok(Self::Dia),
+            "parler" => Ok(Self::Parler),
+            "whisper" => Ok(Self::Whisper),
             a => Err(format!(
-                "Unknown architecture `{a}`. Possible architectures: `dia`."
+                "Unknown architecture `{a}`. Possible architectures: `dia`, `parler`, `whisper`."
             )),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum SpeechGenerationConfig {
     Dia {
         max_tokens: Option<usize>,
@@ -34,18 +53,31 @@ pub enum SpeechGenerationConfig {
         top_p: f32,
         top_k: Option<usize>,
     },
+    Parler {
+        max_tokens: Option<usize>,
+        temperature: f32,
+        /// A natural language description of the voice, such as its gender, pitch, speed and the
+        /// recording quality.
+        description: String,
+    },
 }
 
 impl SpeechGenerationConfig {
     pub fn default(ty: SpeechLoaderType) -> Self {
         match ty {
-            SpeechLoaderType::Dia => Self::Dia {
+            // Transcription models do not use the speech generation config.
+            SpeechLoaderType::Dia | SpeechLoaderType::Whisper => Self::Dia {
                 max_tokens: None,
                 cfg_scale: 3.,
                 temperature: 1.3,
                 top_p: 0.95,
                 top_k: Some(35),
             },
+            SpeechLoaderType::Parler => Self::Parler {
+                max_tokens: None,
+                temperature: 1.,
+                description: parler::DEFAULT_DESCRIPTION.to_string(),
+            },
         }
     }
 }
@@ -56,3 +88,47 @@ pub struct SpeechGenerationOutput {
     pub rate: usize,
     pub channels: usize,
 }
+
+/// A text-to-speech model.
+pub enum SpeechModel {
+    Dia(DiaPipeline),
+    Parler(ParlerPipeline),
+}
+
+impl SpeechModel {
+    pub fn generate(
+        &mut self,
+        text: &str,
+        cfg: &SpeechGenerationConfig,
+    ) -> Result<SpeechGenerationOutput> {
+        match self {
+            Self::Dia(model) => model.generate(text, cfg),
+            Self::Parler(model) => model.generate(text, cfg),
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        match self {
+            Self::Dia(model) => model.device(),
+            Self::Parler(model) => model.device(),
+        }
+    }
+}
+
+/// Synthesized speech for one chunk of the input text.
+#[derive(Clone, Debug)]
+pub struct AudioChunk {
+    /// The text spoken in this chunk.
+    pub text: String,
+    pub pcm: Arc<Vec<f32>>,
+    pub rate: usize,
+    pub channels: usize,
+}
+
+/// A transcribed window of audio. `start` and `end` are in seconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TranscriptionSegment {
+    pub start: f32,
+    pub end: f32,
+    pub text: String,
+}
@@ -0,0 +1,44 @@
+use serde::Deserialize;
+
+use crate::{diffusion_models::t5, speech_models::dac};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParlerDecoderConfig {
+    pub vocab_size: usize,
+    pub max_position_embeddings: usize,
+    pub num_hidden_layers: usize,
+    pub ffn_dim: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    pub num_cross_attention_key_value_heads: Option<usize>,
+    pub hidden_size: usize,
+    pub num_codebooks: usize,
+    pub pad_token_id: u32,
+    pub bos_token_id: u32,
+    pub eos_token_id: u32,
+    #[serde(default)]
+    pub rope_embeddings: bool,
+}
+
+impl ParlerDecoderConfig {
+    pub fn num_key_value_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    pub fn num_cross_attention_key_value_heads(&self) -> usize {
+        self.num_cross_attention_key_value_heads
+            .unwrap_or(self.num_key_value_heads())
+    }
+}
+
+// https://github.com/huggingface/parler-tts/blob/main/parler_tts/configuration_parler_tts.py
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParlerConfig {
+    pub decoder: ParlerDecoderConfig,
+    pub text_encoder: t5::Config,
+    pub audio_encoder: dac::Config,
+    /// Vocabulary size of the prompt embeddings.
+    pub vocab_size: usize,
+    #[serde(default)]
+    pub prompt_cross_attention: bool,
+}
@@ -0,0 +1,194 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::{sync::Arc, time::Instant};
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use mistralrs_quant::ShardedVarBuilder;
+use model::ParlerDecoder;
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    SeedableRng,
+};
+use rand_isaac::Isaac64Rng;
+use tokenizers::Tokenizer;
+use tracing::info;
+
+pub use config::ParlerConfig;
+
+use crate::diffusion_models::t5::T5EncoderModel;
+
+use super::{dac, SpeechGenerationConfig, SpeechGenerationOutput};
+
+mod config;
+mod model;
+
+const RATE: usize = 44100;
+const CHANNELS: usize = 1;
+const TOKENS_PER_SECOND: usize = 86;
+
+/// The voice used by the default speech generation config.
+pub(super) const DEFAULT_DESCRIPTION: &str = "A female speaker delivers a slightly expressive and animated speech with a moderate speed and pitch. The recording is of very high quality, with the speaker's voice sounding clear and very close up.";
+
+pub struct ParlerPipeline {
+    text_encoder: T5EncoderModel,
+    decoder: ParlerDecoder,
+    dac: dac::Model,
+    tokenizer: Tokenizer,
+    cfg: ParlerConfig,
+    device: Device,
+}
+
+impl ParlerPipeline {
+    pub fn new(
+        cfg: &ParlerConfig,
+        vb: ShardedVarBuilder,
+        dac_vb: VarBuilder,
+        tokenizer: Tokenizer,
+    ) -> Result<Self> {
+        let device = vb.device().clone();
+        let text_encoder =
+            T5EncoderModel::load(vb.pp("text_encoder"), &cfg.text_encoder, &device, false)?;
+        let dac = dac::Model::new(&cfg.audio_encoder, dac_vb.set_dtype(DType::F32))?;
+
+        Ok(Self {
+            text_encoder,
+            decoder: ParlerDecoder::new(cfg, vb)?,
+            dac,
+            tokenizer,
+            cfg: cfg.clone(),
+            device,
+        })
+    }
+
+    fn tokenize(&self, text: &str) -> Result<Tensor> {
+        let encoding = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(candle_core::Error::msg)?;
+        Tensor::new(encoding.get_ids(), &self.device)?.unsqueeze(0)
+    }
+
+    fn sample(logits: &Tensor, temperature: f32, rng: &mut Isaac64Rng) -> Result<u32> {
+        if temperature == 0. {
+            return logits.argmax(0)?.to_scalar::<u32>();
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&(logits / temperature as f64)?)?;
+        let distr = WeightedIndex::new(probs.to_vec1::<f32>()?).map_err(candle_core::Error::msg)?;
+        Ok(distr.sample(rng) as u32)
+    }
+
+    /// Speak `text` in the voice given by the `description` of the speech generation config.
+    pub fn generate(
+        &mut self,
+        text: &str,
+        cfg: &SpeechGenerationConfig,
+    ) -> Result<SpeechGenerationOutput> {
+        let SpeechGenerationConfig::Parler {
+            max_tokens,
+            temperature,
+            description,
+        } = cfg
+        else {
+            candle_core::bail!("Parler-TTS expects a Parler speech generation config.");
+        };
+
+        let num_codebooks = self.cfg.decoder.num_codebooks;
+        let bos = self.cfg.decoder.bos_token_id;
+        let eos = self.cfg.decoder.eos_token_id;
+
+        let description = self.tokenize(description)?;
+        let encoded = self.text_encoder.forward(&description)?;
+        let mut state = self.decoder.start(&encoded)?;
+        let prompt = self.tokenize(text)?;
+
+        // The prompt and the delay of the last codebook take up positions too.
+        let max_tokens = max_tokens
+            .unwrap_or(usize::MAX)
+            .min(self.cfg.decoder.max_position_embeddings - prompt.dim(1)? - num_codebooks);
+        // Only the codebook entries and EOS can be sampled, not BOS or the unused entries.
+        let valid_mask = Tensor::from_vec(
+            (0..self.cfg.decoder.vocab_size as u32)
+                .map(|i| if i > eos { f32::NEG_INFINITY } else { 0. })
+                .collect::<Vec<_>>(),
+            self.cfg.decoder.vocab_size,
+            &self.device,
+        )?;
+
+        // Codebook `i` is delayed by `i` frames: it stays at BOS for the first `i` steps, and
+        // ends `i` steps after the first codebook.
+        let mut frame = vec![bos; num_codebooks];
+        let mut codes = vec![Vec::new(); num_codebooks];
+        let mut eos_step = None;
+        let mut rng = Isaac64Rng::seed_from_u64(0);
+
+        let mut start = Instant::now();
+        for step in 0..max_tokens + num_codebooks - 1 {
+            let input = Tensor::new(frame.as_slice(), &self.device)?.unsqueeze(0)?;
+            let logits = self
+                .decoder
+                .decode(&input, (step == 0).then_some(&prompt), &mut state)?;
+
+            if step == max_tokens {
+                eos_step.get_or_insert(step);
+            }
+            for (i, logits) in logits.iter().enumerate().take(step + 1) {
+                frame[i] = match eos_step {
+                    Some(eos_step) if step >= eos_step + i => eos,
+                    _ => Self::sample(
+                        &logits.squeeze(0)?.broadcast_add(&valid_mask)?,
+                        *temperature,
+                        &mut rng,
+                    )?,
+                };
+                if i == 0 && frame[0] == eos {
+                    eos_step.get_or_insert(step);
+                }
+            }
+            if frame.iter().all(|&token| token == eos) {
+                break;
+            }
+            for (codebook, &token) in codes.iter_mut().zip(&frame) {
+                if token != bos && token != eos {
+                    codebook.push(token);
+                }
+            }
+
+            if (step + 1) % TOKENS_PER_SECOND == 0 {
+                let end = Instant::now();
+                info!(
+                    "Generated {}s of audio, {} tokens at {:.2} tokens/second.",
+                    (step + 1) / TOKENS_PER_SECOND,
+                    step + 1,
+                    TOKENS_PER_SECOND as f32 / (end - start).as_secs_f32()
+                );
+                start = end;
+            }
+        }
+
+        let len = codes.iter().map(Vec::len).min().unwrap_or(0);
+        if len == 0 {
+            return Ok(SpeechGenerationOutput {
+                pcm: Arc::new(Vec::new()),
+                rate: RATE,
+                channels: CHANNELS,
+            });
+        }
+        let codes = codes
+            .into_iter()
+            .flat_map(|codebook| codebook.into_iter().take(len))
+            .collect::<Vec<_>>();
+        let codes = Tensor::from_vec(codes, (1, num_codebooks, len), &self.device)?;
+        let pcm = self.dac.decode_codes(&codes)?.squeeze(0)?.squeeze(0)?;
+
+        Ok(SpeechGenerationOutput {
+            pcm: Arc::new(pcm.to_vec1::<f32>()?),
+            rate: RATE,
+            channels: CHANNELS,
+        })
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+}
@@ -0,0 +1,381 @@
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Embedding, LayerNorm, Module};
+use mistralrs_quant::{QuantMethod, ShardedVarBuilder};
+
+use crate::{layers, layers_utils::repeat_kv};
+
+use super::config::{ParlerConfig, ParlerDecoderConfig};
+
+const LAYER_NORM_EPS: f64 = 1e-5;
+
+/// Keys and values of shape (b, h_kv, t, d).
+type KvCache = (Tensor, Tensor);
+
+struct ParlerAttention {
+    q_proj: Arc<dyn QuantMethod>,
+    k_proj: Arc<dyn QuantMethod>,
+    v_proj: Arc<dyn QuantMethod>,
+    out_proj: Arc<dyn QuantMethod>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+}
+
+impl ParlerAttention {
+    fn new(
+        hidden_size: usize,
+        num_heads: usize,
+        num_kv_heads: usize,
+        vb: ShardedVarBuilder,
+    ) -> Result<Self> {
+        let head_dim = hidden_size / num_heads;
+        Ok(Self {
+            q_proj: mistralrs_quant::linear_no_bias(
+                hidden_size,
+                num_heads * head_dim,
+                &None,
+                vb.pp("q_proj"),
+            )?,
+            k_proj: mistralrs_quant::linear_no_bias(
+                hidden_size,
+                num_kv_heads * head_dim,
+                &None,
+                vb.pp("k_proj"),
+            )?,
+            v_proj: mistralrs_quant::linear_no_bias(
+                hidden_size,
+                num_kv_heads * head_dim,
+                &None,
+                vb.pp("v_proj"),
+            )?,
+            out_proj: mistralrs_quant::linear_no_bias(
+                num_heads * head_dim,
+                hidden_size,
+                &None,
+                vb.pp("out_proj"),
+            )?,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+        })
+    }
+
+    /// (b, t, h * d) -> (b, h, t, d)
+    fn split_heads(&self, xs: &Tensor, num_heads: usize) -> Result<Tensor> {
+        let (b, t, _) = xs.dims3()?;
+        xs.reshape((b, t, num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()
+    }
+
+    fn project_kv(&self, xs: &Tensor) -> Result<KvCache> {
+        Ok((
+            self.split_heads(&self.k_proj.forward_autocast(xs)?, self.num_kv_heads)?,
+            self.split_heads(&self.v_proj.forward_autocast(xs)?, self.num_kv_heads)?,
+        ))
+    }
+
+    fn attend(&self, xs: &Tensor, (k, v): &KvCache, mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b, t, _) = xs.dims3()?;
+        let q = self.split_heads(&self.q_proj.forward_autocast(xs)?, self.num_heads)?;
+        let n_rep = self.num_heads / self.num_kv_heads;
+        let k = repeat_kv(k.clone(), n_rep)?;
+        let v = repeat_kv(v.clone(), n_rep)?;
+
+        let mut scores = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        if let Some(mask) = mask {
+            scores = scores.broadcast_add(mask)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let out =
+            probs
+                .matmul(&v)?
+                .transpose(1, 2)?
+                .reshape((b, t, self.num_heads * self.head_dim))?;
+        self.out_proj.forward_autocast(&out)
+    }
+}
+
+struct DecoderLayer {
+    self_attn: ParlerAttention,
+    self_attn_layer_norm: LayerNorm,
+    encoder_attn: ParlerAttention,
+    encoder_attn_layer_norm: LayerNorm,
+    fc1: Arc<dyn QuantMethod>,
+    fc2: Arc<dyn QuantMethod>,
+    final_layer_norm: LayerNorm,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &ParlerDecoderConfig, vb: ShardedVarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: ParlerAttention::new(
+                cfg.hidden_size,
+                cfg.num_attention_heads,
+                cfg.num_key_value_heads(),
+                vb.pp("self_attn"),
+            )?,
+            self_attn_layer_norm: layers::layer_norm(
+                cfg.hidden_size,
+                LAYER_NORM_EPS,
+                vb.pp("self_attn_layer_norm"),
+            )?,
+            encoder_attn: ParlerAttention::new(
+                cfg.hidden_size,
+                cfg.num_attention_heads,
+                cfg.num_cross_attention_key_value_heads(),
+                vb.pp("encoder_attn"),
+            )?,
+            encoder_attn_layer_norm: layers::layer_norm(
+                cfg.hidden_size,
+                LAYER_NORM_EPS,
+                vb.pp("encoder_attn_layer_norm"),
+            )?,
+            fc1: mistralrs_quant::linear_no_bias(
+                cfg.hidden_size,
+                cfg.ffn_dim,
+                &None,
+                vb.pp("fc1"),
+            )?,
+            fc2: mistralrs_quant::linear_no_bias(
+                cfg.ffn_dim,
+                cfg.hidden_size,
+                &None,
+                vb.pp("fc2"),
+            )?,
+            final_layer_norm: layers::layer_norm(
+                cfg.hidden_size,
+                LAYER_NORM_EPS,
+                vb.pp("final_layer_norm"),
+            )?,
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        self_cache: &mut Option<KvCache>,
+        cross_kv: &KvCache,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let normed = self.self_attn_layer_norm.forward(xs)?;
+        let (k, v) = self.self_attn.project_kv(&normed)?;
+        let kv = match self_cache.take() {
+            Some((prev_k, prev_v)) => {
+                (Tensor::cat(&[prev_k, k], 2)?, Tensor::cat(&[prev_v, v], 2)?)
+            }
+            None => (k, v),
+        };
+        let xs = (xs + self.self_attn.attend(&normed, &kv, mask)?)?;
+        *self_cache = Some(kv);
+
+        let normed = self.encoder_attn_layer_norm.forward(&xs)?;
+        let xs = (&xs + self.encoder_attn.attend(&normed, cross_kv, None)?)?;
+
+        let normed = self.final_layer_norm.forward(&xs)?;
+        let mlp = self
+            .fc2
+            .forward_autocast(&self.fc1.forward_autocast(&normed)?.gelu_erf()?)?;
+        &xs + mlp
+    }
+}
+
+/// The decoder state for one generation: the cross-attention keys and values of the encoded
+/// description, and the self-attention cache of the frames decoded so far.
+pub struct DecoderState {
+    cross_kv: Vec<KvCache>,
+    self_cache: Vec<Option<KvCache>>,
+    seqlen_offset: usize,
+}
+
+// https://github.com/huggingface/parler-tts/blob/main/parler_tts/modeling_parler_tts.py
+pub struct ParlerDecoder {
+    embed_tokens: Vec<Embedding>,
+    embed_prompts: Embedding,
+    enc_to_dec_proj: Option<Arc<dyn QuantMethod>>,
+    positions: Tensor,
+    layers: Vec<DecoderLayer>,
+    layer_norm: LayerNorm,
+    lm_heads: Vec<Arc<dyn QuantMethod>>,
+}
+
+impl ParlerDecoder {
+    pub fn new(cfg: &ParlerConfig, vb: ShardedVarBuilder) -> Result<Self> {
+        let dec_cfg = &cfg.decoder;
+        if dec_cfg.rope_embeddings {
+            candle_core::bail!("Parler-TTS checkpoints with rotary embeddings are not supported.");
+        }
+        if cfg.prompt_cross_attention {
+            candle_core::bail!(
+                "Parler-TTS checkpoints with prompt cross-attention are not supported."
+            );
+        }
+
+        let vb_dec = vb.pp("decoder");
+        let vb_model = vb_dec.pp("model.decoder");
+
+        let embed_tokens = (0..dec_cfg.num_codebooks)
+            .map(|i| {
+                // The embeddings have one more entry than the LM heads.
+                layers::embedding(
+                    dec_cfg.vocab_size + 1,
+                    dec_cfg.hidden_size,
+                    vb_model.pp(format!("embed_tokens.{i}")),
+                    &None,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let layers = (0..dec_cfg.num_hidden_layers)
+            .map(|i| DecoderLayer::new(dec_cfg, vb_model.pp(format!("layers.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+        let lm_heads = (0..dec_cfg.num_codebooks)
+            .map(|i| {
+                mistralrs_quant::linear_no_bias(
+                    dec_cfg.hidden_size,
+                    dec_cfg.vocab_size,
+                    &None,
+                    vb_dec.pp(format!("lm_heads.{i}")),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let enc_to_dec_proj = if cfg.text_encoder.d_model != dec_cfg.hidden_size {
+            Some(mistralrs_quant::linear(
+                cfg.text_encoder.d_model,
+                dec_cfg.hidden_size,
+                &None,
+                vb.pp("enc_to_dec_proj"),
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            embed_tokens,
+            embed_prompts: layers::embedding(
+                cfg.vocab_size,
+                dec_cfg.hidden_size,
+                vb.pp("embed_prompts"),
+                &None,
+            )?,
+            enc_to_dec_proj,
+            positions: sinusoidal_positions(
+                dec_cfg.max_position_embeddings,
+                dec_cfg.hidden_size,
+                vb.device(),
+            )?
+            .to_dtype(vb.dtype())?,
+            layers,
+            layer_norm: layers::layer_norm(
+                dec_cfg.hidden_size,
+                LAYER_NORM_EPS,
+                vb_model.pp("layer_norm"),
+            )?,
+            lm_heads,
+        })
+    }
+
+    /// Start decoding, given the encoded description of shape (b, t, d).
+    pub fn start(&self, encoder_hidden_states: &Tensor) -> Result<DecoderState> {
+        let encoder_hidden_states = match &self.enc_to_dec_proj {
+            Some(proj) => proj.forward_autocast(encoder_hidden_states)?,
+            None => encoder_hidden_states.clone(),
+        };
+        Ok(DecoderState {
+            cross_kv: self
+                .layers
+                .iter()
+                .map(|layer| layer.encoder_attn.project_kv(&encoder_hidden_states))
+                .collect::<Result<Vec<_>>>()?,
+            self_cache: vec![None; self.layers.len()],
+            seqlen_offset: 0,
+        })
+    }
+
+    /// Run the decoder on the next frame of `codes` of shape (b, num_codebooks). The prompt token
+    /// ids of shape (b, t) are prepended on the first step. Returns the F32 logits of each
+    /// codebook, of shape (b, vocab).
+    pub fn decode(
+        &self,
+        codes: &Tensor,
+        prompt: Option<&Tensor>,
+        state: &mut DecoderState,
+    ) -> Result<Vec<Tensor>> {
+        let mut xs = self.embed_tokens[0].forward(&codes.narrow(1, 0, 1)?)?;
+        for (i, embed) in self.embed_tokens.iter().enumerate().skip(1) {
+            xs = (xs + embed.forward(&codes.narrow(1, i, 1)?)?)?;
+        }
+        if let Some(prompt) = prompt {
+            xs = Tensor::cat(&[self.embed_prompts.forward(prompt)?, xs], 1)?;
+        }
+
+        let seq_len = xs.dim(1)?;
+        let positions = self.positions.narrow(0, state.seqlen_offset, seq_len)?;
+        let mut xs = xs.broadcast_add(&positions)?;
+
+        let mask = if seq_len > 1 {
+            Some(causal_mask(
+                seq_len,
+                state.seqlen_offset,
+                xs.dtype(),
+                xs.device(),
+            )?)
+        } else {
+            None
+        };
+        for ((layer, self_cache), cross_kv) in self
+            .layers
+            .iter()
+            .zip(&mut state.self_cache)
+            .zip(&state.cross_kv)
+        {
+            xs = layer.forward(&xs, self_cache, cross_kv, mask.as_ref())?;
+        }
+        state.seqlen_offset += seq_len;
+
+        let xs = self
+            .layer_norm
+            .forward(&xs.narrow(1, seq_len - 1, 1)?)?
+            .squeeze(1)?;
+        self.lm_heads
+            .iter()
+            .map(|head| head.forward_autocast(&xs)?.to_dtype(DType::F32))
+            .collect()
+    }
+}
+
+/// Sinusoidal position embeddings of shape (num_positions, dim), with the cosines first.
+fn sinusoidal_positions(num_positions: usize, dim: usize, device: &Device) -> Result<Tensor> {
+    let half_dim = dim / 2;
+    let scale = -(10000f64.ln()) / (half_dim - 1) as f64;
+    let freqs = (0..half_dim)
+        .map(|i| (i as f64 * scale).exp() as f32)
+        .collect::<Vec<_>>();
+    let freqs = Tensor::from_vec(freqs, (1, half_dim), device)?;
+    let positions = Tensor::arange(0u32, num_positions as u32, device)?
+        .to_dtype(DType::F32)?
+        .unsqueeze(1)?;
+    let angles = positions.broadcast_mul(&freqs)?;
+    Tensor::cat(&[angles.cos()?, angles.sin()?], 1)
+}
+
+/// Mask of shape (t, offset + t) hiding the future positions.
+fn causal_mask(seq_len: usize, offset: usize, dtype: DType, device: &Device) -> Result<Tensor> {
+    let mask = (0..seq_len)
+        .flat_map(|i| {
+            (0..offset + seq_len).map(move |j| {
+                if j > i + offset {
+                    f32::NEG_INFINITY
+                } else {
+                    0.
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    Tensor::from_vec(mask, (seq_len, offset + seq_len), device)?
+        .to_dtype(dtype)?
+        .unsqueeze(0)?
+        .unsqueeze(0)
+}
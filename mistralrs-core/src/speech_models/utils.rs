@@ -33,6 +33,35 @@ pub(crate) fn normalize_loudness(
     }
 }
 
+/// Split `text` after each sentence-ending punctuation mark and line break, so that a long text
+/// can be synthesized one sentence at a time. Sentences shorter than `min_len` characters are
+/// merged with the next one, or with the previous one at the end of the text.
+pub(crate) fn split_sentences(text: &str, min_len: usize) -> Vec<&str> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let at_word_end = !matches!(chars.peek(), Some((_, next)) if !next.is_whitespace());
+        let end = i + c.len_utf8();
+        if (c == '\n' || (matches!(c, '.' | '!' | '?') && at_word_end))
+            && text[start..end].trim().chars().count() >= min_len
+        {
+            ranges.push((start, end));
+            start = end;
+        }
+    }
+    if !text[start..].trim().is_empty() {
+        match ranges.last_mut() {
+            Some((_, end)) if text[start..].trim().chars().count() < min_len => *end = text.len(),
+            _ => ranges.push((start, text.len())),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(|(start, end)| text[start..end].trim())
+        .collect()
+}
+
 pub trait Sample {
     fn to_i16(&self) -> i16;
 }
@@ -88,3 +117,22 @@ pub fn write_pcm_as_wav<W: Write, S: Sample>(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::split_sentences;
+
+    #[test]
+    fn sentences_are_split_and_short_ones_merged() {
+        let text = "Hello there. This is mistral.rs, version 0.6! Ok.\nIs it fast? Yes.";
+        assert_eq!(
+            split_sentences(text, 10),
+            vec![
+                "Hello there.",
+                "This is mistral.rs, version 0.6!",
+                "Ok.\nIs it fast? Yes."
+            ]
+        );
+        assert!(split_sentences("  ", 10).is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use anyhow::Result;
+use rubato::Resampler;
+use rustfft::{num_complex::Complex32, FftPlanner};
+
+pub const SAMPLE_RATE: u32 = 16000;
+const N_FFT: usize = 400;
+const HOP_LENGTH: usize = 160;
+/// Whisper always encodes 30 s windows of audio.
+pub const N_SAMPLES: usize = 30 * SAMPLE_RATE as usize;
+pub const N_FRAMES: usize = N_SAMPLES / HOP_LENGTH;
+
+/// Resample mono `samples` to the 16 kHz expected by Whisper.
+pub fn resample(samples: &[f32], from_rate: u32) -> Result<Vec<f32>> {
+    if from_rate == SAMPLE_RATE {
+        return Ok(samples.to_vec());
+    }
+
+    let sinc = rubato::SincInterpolationParameters {
+        sinc_len: 256,
+        f_cutoff: 0.95,
+        interpolation: rubato::SincInterpolationType::Linear,
+        oversampling_factor: 256,
+        window: rubato::WindowFunction::BlackmanHarris2,
+    };
+    let mut resampler = rubato::SincFixedIn::<f32>::new(
+        SAMPLE_RATE as f64 / from_rate as f64,
+        2.0,
+        sinc,
+        samples.len(),
+        1,
+    )?;
+    let result = resampler.process(&[samples.to_vec()], None)?;
+    Ok(result[0].clone())
+}
+
+/// Slaney-style mel filterbank of shape (n_mels, N_FFT / 2 + 1), as computed by
+/// `librosa.filters.mel(sr=16000, n_fft=400, n_mels=n_mels)`.
+pub fn mel_filters(n_mels: usize) -> Vec<f32> {
+    const F_SP: f64 = 200.0 / 3.0;
+    const MIN_LOG_HZ: f64 = 1000.0;
+    const MIN_LOG_MEL: f64 = MIN_LOG_HZ / F_SP;
+    let logstep = 6.4f64.ln() / 27.0;
+
+    let hz_to_mel = |hz: f64| {
+        if hz >= MIN_LOG_HZ {
+            MIN_LOG_MEL + (hz / MIN_LOG_HZ).ln() / logstep
+        } else {
+            hz / F_SP
+        }
+    };
+    let mel_to_hz = |mel: f64| {
+        if mel >= MIN_LOG_MEL {
+            MIN_LOG_HZ * (logstep * (mel - MIN_LOG_MEL)).exp()
+        } else {
+            F_SP * mel
+        }
+    };
+
+    let n_freqs = N_FFT / 2 + 1;
+    let fft_freqs = (0..n_freqs)
+        .map(|i| i as f64 * SAMPLE_RATE as f64 / N_FFT as f64)
+        .collect::<Vec<_>>();
+    let max_mel = hz_to_mel(SAMPLE_RATE as f64 / 2.0);
+    let mel_freqs = (0..n_mels + 2)
+        .map(|i| mel_to_hz(max_mel * i as f64 / (n_mels + 1) as f64))
+        .collect::<Vec<_>>();
+
+    let mut filters = vec![0f32; n_mels * n_freqs];
+    for m in 0..n_mels {
+        let (left, center, right) = (mel_freqs[m], mel_freqs[m + 1], mel_freqs[m + 2]);
+        let enorm = 2.0 / (right - left);
+        for (k, &freq) in fft_freqs.iter().enumerate() {
+            let lower = (freq - left) / (center - left);
+            let upper = (right - freq) / (right - center);
+            filters[m * n_freqs + k] = (lower.min(upper).max(0.0) * enorm) as f32;
+        }
+    }
+    filters
+}
+
+/// Log-mel spectrogram of shape (n_mels, N_FRAMES) of one window of at most `N_SAMPLES` 16 kHz
+/// samples, which is zero-padded to 30 s.
+pub fn log_mel_spectrogram(samples: &[f32], filters: &[f32], n_mels: usize) -> Vec<f32> {
+    let n_freqs = N_FFT / 2 + 1;
+    let mut padded = samples.to_vec();
+    padded.resize(N_SAMPLES, 0.0);
+
+    // Reflection padding, as `torch.stft(center=True)` does.
+    let pad = N_FFT / 2;
+    let mut signal = Vec::with_capacity(N_SAMPLES + 2 * pad);
+    signal.extend(padded[1..=pad].iter().rev());
+    signal.extend(&padded);
+    signal.extend(padded[N_SAMPLES - pad - 1..N_SAMPLES - 1].iter().rev());
+
+    // Periodic Hann window
+    let window = (0..N_FFT)
+        .map(|n| 0.5 * (1.0 - (2.0 * std::f32::consts::PI * n as f32 / N_FFT as f32).cos()))
+        .collect::<Vec<_>>();
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(N_FFT);
+
+    let mut mel = vec![0f32; n_mels * N_FRAMES];
+    let mut buffer = vec![Complex32::new(0.0, 0.0); N_FFT];
+    // The last frame of the STFT is dropped, as in the reference implementation.
+    for frame in 0..N_FRAMES {
+        let start = frame * HOP_LENGTH;
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = Complex32::new(signal[start + i] * window[i], 0.0);
+        }
+        fft.process(&mut buffer);
+        let power = buffer[..n_freqs]
+            .iter()
+            .map(|c| c.norm_sqr())
+            .collect::<Vec<_>>();
+        for m in 0..n_mels {
+            let filter = &filters[m * n_freqs..(m + 1) * n_freqs];
+            let energy = filter.iter().zip(&power).map(|(f, p)| f * p).sum::<f32>();
+            mel[m * N_FRAMES + frame] = energy.max(1e-10).log10();
+        }
+    }
+
+    let max = mel.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    for value in &mut mel {
+        *value = (value.max(max - 8.0) + 4.0) / 4.0;
+    }
+    mel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mel_filters, N_FFT};
+
+    #[test]
+    fn mel_filters_are_normalized_triangles() {
+        let n_freqs = N_FFT / 2 + 1;
+        let filters = mel_filters(80);
+        assert_eq!(filters.len(), 80 * n_freqs);
+        assert!(filters.iter().all(|w| *w >= 0.0));
+        // Every filter covers at least one FFT bin.
+        for m in 0..80 {
+            assert!(filters[m * n_freqs..(m + 1) * n_freqs]
+                .iter()
+                .any(|w| *w > 0.0));
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use serde::Deserialize;
+
+// https://github.com/huggingface/transformers/blob/main/src/transformers/models/whisper/configuration_whisper.py
+#[derive(Debug, Clone, Deserialize)]
+pub struct WhisperConfig {
+    pub vocab_size: usize,
+    pub num_mel_bins: usize,
+    pub d_model: usize,
+    pub encoder_layers: usize,
+    pub encoder_attention_heads: usize,
+    pub encoder_ffn_dim: usize,
+    pub decoder_layers: usize,
+    pub decoder_attention_heads: usize,
+    pub decoder_ffn_dim: usize,
+    pub max_source_positions: usize,
+    pub max_target_positions: usize,
+    #[serde(default)]
+    pub suppress_tokens: Vec<u32>,
+    #[serde(default)]
+    pub begin_suppress_tokens: Vec<u32>,
+}
+
+impl WhisperConfig {
+    /// English-only checkpoints have a smaller vocabulary, without the language and task tokens
+    /// in the prompt.
+    pub fn is_multilingual(&self) -> bool {
+        self.vocab_size >= 51865
+    }
+}
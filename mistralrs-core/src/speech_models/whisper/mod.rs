@@ -0,0 +1,199 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use mistralrs_quant::ShardedVarBuilder;
+use model::{DecoderState, WhisperModel};
+use tokenizers::Tokenizer;
+use tracing::info;
+
+pub use config::WhisperConfig;
+
+use super::TranscriptionSegment;
+
+mod audio;
+mod config;
+mod model;
+
+const SOT_TOKEN: &str = "<|startoftranscript|>";
+const EOT_TOKEN: &str = "<|endoftext|>";
+const TRANSCRIBE_TOKEN: &str = "<|transcribe|>";
+const TRANSLATE_TOKEN: &str = "<|translate|>";
+const NO_TIMESTAMPS_TOKEN: &str = "<|notimestamps|>";
+
+pub struct WhisperPipeline {
+    model: WhisperModel,
+    tokenizer: Tokenizer,
+    cfg: WhisperConfig,
+    mel_filters: Vec<f32>,
+    device: Device,
+    dtype: DType,
+    sot_token: u32,
+    eot_token: u32,
+    transcribe_token: u32,
+    translate_token: u32,
+    no_timestamps_token: u32,
+    /// Added to the logits at every step.
+    suppress_mask: Tensor,
+    /// Added to the logits of the first sampled token.
+    begin_suppress_mask: Tensor,
+}
+
+fn token_id(tokenizer: &Tokenizer, token: &str) -> Result<u32> {
+    tokenizer
+        .token_to_id(token)
+        .ok_or_else(|| anyhow::anyhow!("Whisper tokenizer is missing the `{token}` token."))
+}
+
+fn suppress_mask(
+    suppressed: impl Fn(u32) -> bool,
+    vocab_size: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let mask = (0..vocab_size as u32)
+        .map(|i| if suppressed(i) { f32::NEG_INFINITY } else { 0. })
+        .collect::<Vec<_>>();
+    Ok(Tensor::from_vec(mask, vocab_size, device)?)
+}
+
+impl WhisperPipeline {
+    pub fn new(cfg: &WhisperConfig, vb: ShardedVarBuilder, tokenizer: Tokenizer) -> Result<Self> {
+        let device = vb.device().clone();
+        let dtype = vb.dtype();
+        let model = WhisperModel::new(cfg, vb)?;
+
+        let no_timestamps_token = token_id(&tokenizer, NO_TIMESTAMPS_TOKEN)?;
+        // Timestamps are not predicted, so every timestamp token (after `<|notimestamps|>`) is
+        // suppressed along with the tokens the config suppresses.
+        let suppress_mask = suppress_mask(
+            |i| i > no_timestamps_token || cfg.suppress_tokens.contains(&i),
+            cfg.vocab_size,
+            &device,
+        )?;
+        let begin_suppress_mask = suppress_mask(
+            |i| cfg.begin_suppress_tokens.contains(&i),
+            cfg.vocab_size,
+            &device,
+        )?;
+
+        Ok(Self {
+            model,
+            mel_filters: audio::mel_filters(cfg.num_mel_bins),
+            sot_token: token_id(&tokenizer, SOT_TOKEN)?,
+            eot_token: token_id(&tokenizer, EOT_TOKEN)?,
+            transcribe_token: token_id(&tokenizer, TRANSCRIBE_TOKEN)?,
+            translate_token: token_id(&tokenizer, TRANSLATE_TOKEN)?,
+            no_timestamps_token,
+            suppress_mask,
+            begin_suppress_mask,
+            tokenizer,
+            cfg: cfg.clone(),
+            device,
+            dtype,
+        })
+    }
+
+    /// The language tokens are the ones between `<|startoftranscript|>` and `<|translate|>`.
+    fn language_tokens(&self) -> std::ops::Range<u32> {
+        self.sot_token + 1..self.translate_token
+    }
+
+    /// Pick the most likely language token for the window encoded in `state`.
+    fn detect_language(&self, mut state: DecoderState) -> Result<u32> {
+        let tokens = Tensor::new(&[[self.sot_token]], &self.device)?;
+        let logits = self.model.decode(&tokens, &mut state)?.squeeze(0)?;
+        let languages = self.language_tokens();
+        let language_logits = logits
+            .narrow(0, languages.start as usize, languages.len())?
+            .to_vec1::<f32>()?;
+        let (best, _) = language_logits
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .ok_or_else(|| anyhow::anyhow!("Whisper tokenizer has no language tokens."))?;
+        Ok(languages.start + best as u32)
+    }
+
+    /// Greedily decode the transcription of one 30 s window.
+    fn decode_window(&self, mut state: DecoderState, prompt: &[u32]) -> Result<String> {
+        let mut tokens = Tensor::new(prompt, &self.device)?.unsqueeze(0)?;
+        let mut generated = Vec::new();
+        // As in the reference implementation, at most half of the context is sampled.
+        let sample_len = self.cfg.max_target_positions / 2;
+        while generated.len() < sample_len {
+            let mut logits = self
+                .model
+                .decode(&tokens, &mut state)?
+                .squeeze(0)?
+                .broadcast_add(&self.suppress_mask)?;
+            if generated.is_empty() {
+                logits = logits.broadcast_add(&self.begin_suppress_mask)?;
+            }
+            let next = logits.argmax(0)?.to_scalar::<u32>()?;
+            if next == self.eot_token {
+                break;
+            }
+            generated.push(next);
+            tokens = Tensor::new(&[[next]], &self.device)?;
+        }
+        self.tokenizer
+            .decode(&generated, true)
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Transcribe mono `samples` at `sample_rate` in 30 s windows, calling `on_segment` with each
+    /// window as soon as it is decoded. `language` is a language code such as `en`; if it is not
+    /// given, it is detected from the first window.
+    pub fn transcribe(
+        &self,
+        samples: &[f32],
+        sample_rate: u32,
+        language: Option<&str>,
+        mut on_segment: impl FnMut(TranscriptionSegment) -> Result<()>,
+    ) -> Result<()> {
+        let samples = audio::resample(samples, sample_rate)?;
+        let duration = samples.len() as f32 / audio::SAMPLE_RATE as f32;
+        let mut language_token = None;
+
+        for (i, window) in samples.chunks(audio::N_SAMPLES).enumerate() {
+            let mel = audio::log_mel_spectrogram(window, &self.mel_filters, self.cfg.num_mel_bins);
+            let mel = Tensor::from_vec(
+                mel,
+                (1, self.cfg.num_mel_bins, audio::N_FRAMES),
+                &self.device,
+            )?
+            .to_dtype(self.dtype)?;
+            let state = self.model.encode(&mel)?;
+
+            let mut prompt = vec![self.sot_token];
+            if self.cfg.is_multilingual() {
+                let token = match (language_token, language) {
+                    (Some(token), _) => token,
+                    (None, Some(language)) => {
+                        token_id(&self.tokenizer, &format!("<|{language}|>"))?
+                    }
+                    (None, None) => {
+                        let token = self.detect_language(state.clone())?;
+                        info!(
+                            "Detected language `{}`.",
+                            self.tokenizer.id_to_token(token).unwrap_or_default()
+                        );
+                        token
+                    }
+                };
+                language_token = Some(token);
+                prompt.extend([token, self.transcribe_token]);
+            }
+            prompt.push(self.no_timestamps_token);
+
+            let text = self.decode_window(state, &prompt)?;
+            let start = (i * audio::N_SAMPLES) as f32 / audio::SAMPLE_RATE as f32;
+            on_segment(TranscriptionSegment {
+                start,
+                end: (start + 30.).min(duration),
+                text: text.trim().to_string(),
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Conv1d, Conv1dConfig, Embedding, LayerNorm, Module};
+use mistralrs_quant::{QuantMethod, ShardedVarBuilder};
+
+use crate::layers;
+
+use super::config::WhisperConfig;
+
+const LAYER_NORM_EPS: f64 = 1e-5;
+
+/// Keys and values of shape (b, h, t, d).
+type KvCache = (Tensor, Tensor);
+
+struct WhisperAttention {
+    q_proj: Arc<dyn QuantMethod>,
+    k_proj: Arc<dyn QuantMethod>,
+    v_proj: Arc<dyn QuantMethod>,
+    out_proj: Arc<dyn QuantMethod>,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl WhisperAttention {
+    fn new(d_model: usize, num_heads: usize, vb: ShardedVarBuilder) -> Result<Self> {
+        Ok(Self {
+            q_proj: mistralrs_quant::linear(d_model, d_model, &None, vb.pp("q_proj"))?,
+            k_proj: mistralrs_quant::linear_no_bias(d_model, d_model, &None, vb.pp("k_proj"))?,
+            v_proj: mistralrs_quant::linear(d_model, d_model, &None, vb.pp("v_proj"))?,
+            out_proj: mistralrs_quant::linear(d_model, d_model, &None, vb.pp("out_proj"))?,
+            num_heads,
+            head_dim: d_model / num_heads,
+        })
+    }
+
+    /// (b, t, h * d) -> (b, h, t, d)
+    fn split_heads(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b, t, _) = xs.dims3()?;
+        xs.reshape((b, t, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()
+    }
+
+    fn project_kv(&self, xs: &Tensor) -> Result<KvCache> {
+        Ok((
+            self.split_heads(&self.k_proj.forward_autocast(xs)?)?,
+            self.split_heads(&self.v_proj.forward_autocast(xs)?)?,
+        ))
+    }
+
+    fn attend(&self, xs: &Tensor, (k, v): &KvCache, mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b, t, _) = xs.dims3()?;
+        let q = self.split_heads(&self.q_proj.forward_autocast(xs)?)?;
+        let mut scores = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        if let Some(mask) = mask {
+            scores = scores.broadcast_add(mask)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let out =
+            probs
+                .matmul(v)?
+                .transpose(1, 2)?
+                .reshape((b, t, self.num_heads * self.head_dim))?;
+        self.out_proj.forward_autocast(&out)
+    }
+}
+
+struct WhisperMlp {
+    fc1: Arc<dyn QuantMethod>,
+    fc2: Arc<dyn QuantMethod>,
+}
+
+impl WhisperMlp {
+    fn new(d_model: usize, ffn_dim: usize, vb: ShardedVarBuilder) -> Result<Self> {
+        Ok(Self {
+            fc1: mistralrs_quant::linear(d_model, ffn_dim, &None, vb.pp("fc1"))?,
+            fc2: mistralrs_quant::linear(ffn_dim, d_model, &None, vb.pp("fc2"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.fc2
+            .forward_autocast(&self.fc1.forward_autocast(xs)?.gelu_erf()?)
+    }
+}
+
+struct EncoderLayer {
+    self_attn: WhisperAttention,
+    self_attn_layer_norm: LayerNorm,
+    mlp: WhisperMlp,
+    final_layer_norm: LayerNorm,
+}
+
+impl EncoderLayer {
+    fn new(cfg: &WhisperConfig, vb: ShardedVarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: WhisperAttention::new(
+                cfg.d_model,
+                cfg.encoder_attention_heads,
+                vb.pp("self_attn"),
+            )?,
+            self_attn_layer_norm: layers::layer_norm(
+                cfg.d_model,
+                LAYER_NORM_EPS,
+                vb.pp("self_attn_layer_norm"),
+            )?,
+            mlp: WhisperMlp::new(cfg.d_model, cfg.encoder_ffn_dim, vb.clone())?,
+            final_layer_norm: layers::layer_norm(
+                cfg.d_model,
+                LAYER_NORM_EPS,
+                vb.pp("final_layer_norm"),
+            )?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let normed = self.self_attn_layer_norm.forward(xs)?;
+        let kv = self.self_attn.project_kv(&normed)?;
+        let xs = (xs + self.self_attn.attend(&normed, &kv, None)?)?;
+        &xs + self.mlp.forward(&self.final_layer_norm.forward(&xs)?)?
+    }
+}
+
+struct DecoderLayer {
+    self_attn: WhisperAttention,
+    self_attn_layer_norm: LayerNorm,
+    encoder_attn: WhisperAttention,
+    encoder_attn_layer_norm: LayerNorm,
+    mlp: WhisperMlp,
+    final_layer_norm: LayerNorm,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &WhisperConfig, vb: ShardedVarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: WhisperAttention::new(
+                cfg.d_model,
+                cfg.decoder_attention_heads,
+                vb.pp("self_attn"),
+            )?,
+            self_attn_layer_norm: layers::layer_norm(
+                cfg.d_model,
+                LAYER_NORM_EPS,
+                vb.pp("self_attn_layer_norm"),
+            )?,
+            encoder_attn: WhisperAttention::new(
+                cfg.d_model,
+                cfg.decoder_attention_heads,
+                vb.pp("encoder_attn"),
+            )?,
+            encoder_attn_layer_norm: layers::layer_norm(
+                cfg.d_model,
+                LAYER_NORM_EPS,
+                vb.pp("encoder_attn_layer_norm"),
+            )?,
+            mlp: WhisperMlp::new(cfg.d_model, cfg.decoder_ffn_dim, vb.clone())?,
+            final_layer_norm: layers::layer_norm(
+                cfg.d_model,
+                LAYER_NORM_EPS,
+                vb.pp("final_layer_norm"),
+            )?,
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        self_cache: &mut Option<KvCache>,
+        cross_kv: &KvCache,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let normed = self.self_attn_layer_norm.forward(xs)?;
+        let (k, v) = self.self_attn.project_kv(&normed)?;
+        let kv = match self_cache.take() {
+            Some((prev_k, prev_v)) => {
+                (Tensor::cat(&[prev_k, k], 2)?, Tensor::cat(&[prev_v, v], 2)?)
+            }
+            None => (k, v),
+        };
+        let xs = (xs + self.self_attn.attend(&normed, &kv, mask)?)?;
+        *self_cache = Some(kv);
+
+        let normed = self.encoder_attn_layer_norm.forward(&xs)?;
+        let xs = (&xs + self.encoder_attn.attend(&normed, cross_kv, None)?)?;
+
+        &xs + self.mlp.forward(&self.final_layer_norm.forward(&xs)?)?
+    }
+}
+
+/// The decoder state for one 30 s window: the cross-attention keys and values of the encoded
+/// audio, and the self-attention cache of the tokens decoded so far.
+#[derive(Clone)]
+pub struct DecoderState {
+    cross_kv: Vec<KvCache>,
+    self_cache: Vec<Option<KvCache>>,
+    seqlen_offset: usize,
+}
+
+// https://github.com/huggingface/transformers/blob/main/src/transformers/models/whisper/modeling_whisper.py
+pub struct WhisperModel {
+    conv1: Conv1d,
+    conv2: Conv1d,
+    encoder_positions: Tensor,
+    encoder_layers: Vec<EncoderLayer>,
+    encoder_layer_norm: LayerNorm,
+    embed_tokens: Embedding,
+    decoder_positions: Tensor,
+    decoder_layers: Vec<DecoderLayer>,
+    decoder_layer_norm: LayerNorm,
+}
+
+impl WhisperModel {
+    pub fn new(cfg: &WhisperConfig, vb: ShardedVarBuilder) -> Result<Self> {
+        let vb_enc = vb.pp("model.encoder");
+        let vb_dec = vb.pp("model.decoder");
+
+        let encoder_layers = (0..cfg.encoder_layers)
+            .map(|i| EncoderLayer::new(cfg, vb_enc.pp(format!("layers.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+        let decoder_layers = (0..cfg.decoder_layers)
+            .map(|i| DecoderLayer::new(cfg, vb_dec.pp(format!("layers.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            conv1: layers::conv1d(
+                cfg.num_mel_bins,
+                cfg.d_model,
+                3,
+                Conv1dConfig {
+                    padding: 1,
+                    ..Default::default()
+                },
+                vb_enc.pp("conv1"),
+            )?,
+            conv2: layers::conv1d(
+                cfg.d_model,
+                cfg.d_model,
+                3,
+                Conv1dConfig {
+                    padding: 1,
+                    stride: 2,
+                    ..Default::default()
+                },
+                vb_enc.pp("conv2"),
+            )?,
+            encoder_positions: vb_enc
+                .pp("embed_positions")
+                .get((cfg.max_source_positions, cfg.d_model), "weight")?,
+            encoder_layers,
+            encoder_layer_norm: layers::layer_norm(
+                cfg.d_model,
+                LAYER_NORM_EPS,
+                vb_enc.pp("layer_norm"),
+            )?,
+            embed_tokens: layers::embedding(
+                cfg.vocab_size,
+                cfg.d_model,
+                vb_dec.pp("embed_tokens"),
+                &None,
+            )?,
+            decoder_positions: vb_dec
+                .pp("embed_positions")
+                .get((cfg.max_target_positions, cfg.d_model), "weight")?,
+            decoder_layers,
+            decoder_layer_norm: layers::layer_norm(
+                cfg.d_model,
+                LAYER_NORM_EPS,
+                vb_dec.pp("layer_norm"),
+            )?,
+        })
+    }
+
+    /// Encode the log-mel spectrogram of shape (b, n_mels, 3000), returning the initial decoder
+    /// state.
+    pub fn encode(&self, mel: &Tensor) -> Result<DecoderState> {
+        let xs = self.conv1.forward(mel)?.gelu_erf()?;
+        let xs = self.conv2.forward(&xs)?.gelu_erf()?.transpose(1, 2)?;
+        let positions = self.encoder_positions.narrow(0, 0, xs.dim(1)?)?;
+        let mut xs = xs.broadcast_add(&positions)?;
+        for layer in &self.encoder_layers {
+            xs = layer.forward(&xs)?;
+        }
+        let xs = self.encoder_layer_norm.forward(&xs)?;
+
+        Ok(DecoderState {
+            cross_kv: self
+                .decoder_layers
+                .iter()
+                .map(|layer| layer.encoder_attn.project_kv(&xs))
+                .collect::<Result<Vec<_>>>()?,
+            self_cache: vec![None; self.decoder_layers.len()],
+            seqlen_offset: 0,
+        })
+    }
+
+    /// Run the decoder on the next `tokens` of shape (b, t), returning the logits of the last
+    /// token as F32 of shape (b, vocab).
+    pub fn decode(&self, tokens: &Tensor, state: &mut DecoderState) -> Result<Tensor> {
+        let seq_len = tokens.dim(1)?;
+        let positions = self
+            .decoder_positions
+            .narrow(0, state.seqlen_offset, seq_len)?;
+        let mut xs = self
+            .embed_tokens
+            .forward(tokens)?
+            .broadcast_add(&positions)?;
+
+        let mask = if seq_len > 1 {
+            Some(causal_mask(
+                seq_len,
+                state.seqlen_offset,
+                xs.dtype(),
+                xs.device(),
+            )?)
+        } else {
+            None
+        };
+        for ((layer, self_cache), cross_kv) in self
+            .decoder_layers
+            .iter()
+            .zip(&mut state.self_cache)
+            .zip(&state.cross_kv)
+        {
+            xs = layer.forward(&xs, self_cache, cross_kv, mask.as_ref())?;
+        }
+        state.seqlen_offset += seq_len;
+
+        let xs = self
+            .decoder_layer_norm
+            .forward(&xs.narrow(1, seq_len - 1, 1)?)?;
+        // The output projection is tied to the token embeddings.
+        xs.squeeze(1)?
+            .broadcast_matmul(&self.embed_tokens.embeddings().t()?)?
+            .to_dtype(DType::F32)
+    }
+}
+
+/// Mask of shape (t, offset + t) hiding the future tokens.
+fn causal_mask(seq_len: usize, offset: usize, dtype: DType, device: &Device) -> Result<Tensor> {
+    let mask = (0..seq_len)
+        .flat_map(|i| {
+            (0..offset + seq_len).map(move |j| {
+                if j > i + offset {
+                    f32::NEG_INFINITY
+                } else {
+                    0.
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    Tensor::from_vec(mask, (seq_len, offset + seq_len), device)?
+        .to_dtype(dtype)?
+        .unsqueeze(0)?
+        .unsqueeze(0)
+}
@@ -0,0 +1,79 @@
+//! Token healing: the last token of the prompt is removed and generated again, so a prompt which
+//! ends in the middle of what would be a longer token does not force an unusual tokenization at
+//! the start of the completion.
+
+use candle_core::{Device, Result, Tensor};
+use llguidance::toktrie::TokEnv;
+
+use crate::sampler::CustomLogitsProcessor;
+
+/// Restricts the first token of the completion to the tokens which start with the text of the
+/// token removed from the prompt.
+pub(crate) struct TokenHealing {
+    /// The length of the prompt after the token was removed.
+    prompt_len: usize,
+    allowed: Vec<u32>,
+}
+
+impl TokenHealing {
+    /// Remove the last token of `prompt`, returning the processor which generates it again and the
+    /// bytes of the removed token. Returns `None` if the prompt is too short, or ends with a special
+    /// token.
+    pub(crate) fn heal(prompt: &mut Vec<u32>, tok_env: &TokEnv) -> Option<(Self, Vec<u8>)> {
+        let tok_trie = tok_env.tok_trie();
+        let last = *prompt.last()?;
+        if prompt.len() < 2 || tok_trie.is_special_token(last) {
+            return None;
+        }
+        let removed = tok_trie.token(last).to_vec();
+        let tokens = (0..tok_trie.vocab_size() as u32)
+            .filter(|tok| !tok_trie.is_special_token(*tok))
+            .map(|tok| tok_trie.token(tok))
+            .collect::<Vec<_>>();
+        let allowed = Self::allowed_tokens(&removed, &tokens);
+        prompt.pop();
+        Some((
+            Self {
+                prompt_len: prompt.len(),
+                allowed,
+            },
+            removed,
+        ))
+    }
+
+    fn allowed_tokens(removed: &[u8], tokens: &[&[u8]]) -> Vec<u32> {
+        tokens
+            .iter()
+            .enumerate()
+            .filter(|(_, token)| token.starts_with(removed))
+            .map(|(tok, _)| tok as u32)
+            .collect()
+    }
+}
+
+impl CustomLogitsProcessor for TokenHealing {
+    fn apply(&self, logits: &Tensor, context: &[u32]) -> Result<Tensor> {
+        if context.len() != self.prompt_len {
+            return Ok(logits.clone());
+        }
+        let mut logits_vec = vec![f32::NEG_INFINITY; logits.dim(0)?];
+        let original = logits.to_vec1::<f32>()?;
+        for tok in &self.allowed {
+            if let Some(logit) = logits_vec.get_mut(*tok as usize) {
+                *logit = original[*tok as usize];
+            }
+        }
+        Tensor::from_vec(logits_vec, logits.shape(), &Device::Cpu)?.to_device(logits.device())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenHealing;
+
+    #[test]
+    fn allows_tokens_extending_the_removed_token() {
+        let tokens: [&[u8]; 5] = [b"\"", b"\":", b"\"\n", b"a\"", b":"];
+        assert_eq!(TokenHealing::allowed_tokens(b"\"", &tokens), [0, 1, 2]);
+    }
+}
@@ -614,9 +614,13 @@ fn loader_from_selected(
                 }),
                 imatrix,
                 calibration_file,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 hf_cache_path,
                 matformer_config_path: None,
                 matformer_slice_name: None,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -651,9 +655,13 @@ fn loader_from_selected(
                 }),
                 imatrix: None,
                 calibration_file: None,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 hf_cache_path,
                 matformer_config_path: None,
                 matformer_slice_name: None,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -695,9 +703,13 @@ fn loader_from_selected(
                 }),
                 imatrix: None,
                 calibration_file: None,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 hf_cache_path,
                 matformer_config_path: None,
                 matformer_slice_name: None,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -917,10 +929,14 @@ fn loader_from_selected(
                 }),
                 max_edge,
                 calibration_file,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 imatrix,
                 hf_cache_path,
                 matformer_config_path: None,
                 matformer_slice_name: None,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -19,12 +19,31 @@ pub struct DeserLayerTopology {
 #[derive(Deserialize)]
 pub struct DeserTopology(HashMap<String, DeserLayerTopology>);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct LayerTopology {
     pub isq: Option<IsqType>,
     pub device: Option<Device>,
 }
 
+impl LayerTopology {
+    /// A layer which uses the model's ISQ type and device mapping.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Quantize the layer with ISQ of this type.
+    pub fn isq(mut self, isq: IsqType) -> Self {
+        self.isq = Some(isq);
+        self
+    }
+
+    /// Load the layer on this device.
+    pub fn device(mut self, device: Device) -> Self {
+        self.device = Some(device);
+        self
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 struct CustomRange {
     start: usize,
@@ -57,6 +76,25 @@ impl PartialOrd for CustomRange {
 pub struct Topology(pub Vec<Option<LayerTopology>>);
 
 impl Topology {
+    /// Build a topology in code instead of from a YAML file.
+    ///
+    /// ```no_run
+    /// # use candle_core::Device;
+    /// # use mistralrs_core::{IsqType, LayerTopology, Topology};
+    /// # fn run() -> candle_core::Result<()> {
+    /// let gpu0 = Device::new_cuda(0)?;
+    /// let topology = Topology::builder()
+    ///     .range(0..8, LayerTopology::new().isq(IsqType::Q4K).device(gpu0.clone()))
+    ///     .range(8..32, LayerTopology::new().isq(IsqType::Q8_0).device(gpu0))
+    ///     .layer(32, LayerTopology::new().device(Device::Cpu))
+    ///     .build();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> TopologyBuilder {
+        TopologyBuilder(Self::empty())
+    }
+
     /// Create an empty topology.
     pub fn empty() -> Self {
         Topology(Vec::new())
@@ -82,6 +120,10 @@ impl Topology {
         self
     }
 
+    pub fn with_layer(self, layer: usize, topology: LayerTopology) -> Self {
+        self.with_range(layer..layer + 1, topology)
+    }
+
     #[allow(clippy::should_implement_trait)]
     pub fn from_str(topology: &str) -> anyhow::Result<Self> {
         let deser: DeserTopology = serde_yaml::from_str(topology)?;
@@ -169,3 +211,59 @@ impl Topology {
         }
     }
 }
+
+/// Builder for a [`Topology`], created with [`Topology::builder`]. Layers which are not given use
+/// the model's ISQ type and device mapping. If ranges overlap, the last one is used.
+#[derive(Clone, Debug)]
+pub struct TopologyBuilder(Topology);
+
+impl TopologyBuilder {
+    /// Use `topology` for the layers in `range`.
+    pub fn range(self, range: Range<usize>, topology: LayerTopology) -> Self {
+        Self(self.0.with_range(range, topology))
+    }
+
+    /// Use `topology` for the layer `layer`.
+    pub fn layer(self, layer: usize, topology: LayerTopology) -> Self {
+        Self(self.0.with_layer(layer, topology))
+    }
+
+    pub fn build(self) -> Topology {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_quant::IsqType;
+
+    use super::{LayerTopology, Topology};
+
+    #[test]
+    fn builder_later_ranges_take_priority() {
+        let topology = Topology::builder()
+            .range(0..4, LayerTopology::new().isq(IsqType::Q4K))
+            .layer(2, LayerTopology::new().isq(IsqType::Q8_0))
+            .range(6..8, LayerTopology::new())
+            .build();
+
+        let isq = topology
+            .0
+            .iter()
+            .map(|layer| layer.as_ref().map(|layer| layer.isq))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            isq,
+            vec![
+                Some(Some(IsqType::Q4K)),
+                Some(Some(IsqType::Q4K)),
+                Some(Some(IsqType::Q8_0)),
+                Some(Some(IsqType::Q4K)),
+                None,
+                None,
+                Some(None),
+                Some(None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Inspect UQFF files without loading them, for example to check that a UQFF file can be loaded by
+//! this build before downloading the rest of the model.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use candle_core::safetensors::MmapedSafetensors;
+use mistralrs_quant::{read_uqff_layer_header, IsqType, QuantizedSerdeType};
+
+use crate::pipeline::read_uqff_embedded_files;
+
+/// A layer stored in a UQFF file.
+#[derive(Debug, Clone)]
+pub struct UqffLayerInfo {
+    /// The index of the layer, in the order the model produces its ISQ layers.
+    pub index: usize,
+    /// `None` if the weight is not quantized.
+    pub isq_type: Option<IsqType>,
+    /// The shape of the dequantized weight.
+    pub shape: Vec<usize>,
+    pub has_bias: bool,
+    /// The size of the serialized layer.
+    pub size_in_bytes: usize,
+}
+
+/// The contents of a UQFF file and whether this build can load it.
+#[derive(Debug, Clone)]
+pub struct UqffReport {
+    pub layers: Vec<UqffLayerInfo>,
+    /// The size of all layers.
+    pub total_size_in_bytes: usize,
+    /// The model files embedded in the metadata, such as `tokenizer.json`.
+    pub embedded_files: Vec<String>,
+    /// The device features this build was compiled with.
+    pub build_features: Vec<&'static str>,
+    /// Why this build cannot load the file. Empty if it can.
+    pub incompatibilities: Vec<String>,
+    /// Layers which can be loaded, but are slow or unsupported on some devices of this build.
+    pub warnings: Vec<String>,
+}
+
+impl UqffReport {
+    /// If this build can load the file.
+    pub fn is_compatible(&self) -> bool {
+        self.incompatibilities.is_empty()
+    }
+}
+
+fn build_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "cuda") {
+        features.push("cuda");
+    }
+    if cfg!(feature = "metal") {
+        features.push("metal");
+    }
+    if cfg!(feature = "accelerate") {
+        features.push("accelerate");
+    }
+    if cfg!(feature = "mkl") {
+        features.push("mkl");
+    }
+    features
+}
+
+/// Read the layers of the UQFF file at `path` and check them against this build.
+pub fn inspect(path: impl AsRef<Path>) -> Result<UqffReport> {
+    let path = path.as_ref();
+    let artifacts = unsafe { MmapedSafetensors::new(path)? };
+
+    let mut layers = Vec::new();
+    let mut incompatibilities = Vec::new();
+    let mut warnings = Vec::new();
+    let mut tensors = artifacts.tensors();
+    tensors.sort_by_key(|(name, _)| name.parse::<usize>().unwrap_or(usize::MAX));
+    for (name, tensor) in tensors {
+        let Ok(index) = name.parse::<usize>() else {
+            anyhow::bail!(
+                "`{}` is not a UQFF layer file, found tensor `{name}`.",
+                path.display()
+            );
+        };
+        let data = tensor.data();
+        let header = match read_uqff_layer_header(data) {
+            Ok(header) => header,
+            Err(e) => {
+                incompatibilities.push(format!("Layer {index}: {e}"));
+                continue;
+            }
+        };
+
+        if matches!(header.serde_type, QuantizedSerdeType::Afq) && !cfg!(feature = "metal") {
+            warnings.push(format!(
+                "Layer {index} is AFQ, which is dequantized in each forward pass without Metal."
+            ));
+        }
+        if let Some(isq_type) = header.isq_type {
+            let cuda_supported = !matches!(
+                isq_type,
+                IsqType::Q8_1
+                    | IsqType::Q8K
+                    | IsqType::AFQ2
+                    | IsqType::AFQ3
+                    | IsqType::AFQ4
+                    | IsqType::AFQ6
+                    | IsqType::AFQ8
+            );
+            if cfg!(feature = "cuda") && !cuda_supported {
+                warnings.push(format!(
+                    "Layer {index} is {isq_type:?}, which is not supported on CUDA devices."
+                ));
+            }
+        }
+
+        layers.push(UqffLayerInfo {
+            index,
+            isq_type: header.isq_type,
+            shape: header.weight_shape,
+            has_bias: header.has_bias,
+            size_in_bytes: data.len(),
+        });
+    }
+
+    let mut embedded_files = read_uqff_embedded_files(&[PathBuf::from(path)])?
+        .into_keys()
+        .collect::<Vec<_>>();
+    embedded_files.sort();
+
+    Ok(UqffReport {
+        total_size_in_bytes: layers.iter().map(|layer| layer.size_in_bytes).sum(),
+        layers,
+        embedded_files,
+        build_features: build_features(),
+        incompatibilities,
+        warnings,
+    })
+}
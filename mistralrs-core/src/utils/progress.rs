@@ -3,8 +3,136 @@ use mistralrs_quant::get_immediate_isq;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rayon::prelude::*;
 use std::iter::Iterator;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
 use tqdm::Iter;
 
+/// An event reported to the load progress callback while a model is downloaded and loaded.
+#[derive(Clone, Debug)]
+pub enum LoadEvent {
+    /// `downloaded` out of `total` bytes of the weight file `file` have been downloaded.
+    Download {
+        file: String,
+        downloaded: usize,
+        total: usize,
+    },
+    /// `loaded` out of `total` weight files have been loaded.
+    LoadShard { loaded: usize, total: usize },
+    /// `quantized` out of `total` tensors have been quantized with ISQ. This is only reported when
+    /// ISQ is applied after loading; otherwise, the tensors are quantized as the weights are loaded.
+    Isq { quantized: usize, total: usize },
+    /// Automatic device mapping placed `layers` repeating layers on `device`.
+    DeviceMap { device: String, layers: usize },
+}
+
+pub type LoadProgressCallback = Arc<dyn Fn(LoadEvent) + Send + Sync>;
+
+static LOAD_PROGRESS_CALLBACK: RwLock<Option<LoadProgressCallback>> = RwLock::new(None);
+
+/// Set the callback which receives a [`LoadEvent`] at each step of loading a model, or clear it
+/// with `None`. The callback is global, so models loaded at the same time all report to it.
+pub fn set_load_progress_callback(callback: Option<LoadProgressCallback>) {
+    *LOAD_PROGRESS_CALLBACK.write().unwrap() = callback;
+}
+
+pub(crate) fn has_load_progress_callback() -> bool {
+    LOAD_PROGRESS_CALLBACK.read().unwrap().is_some()
+}
+
+/// Report the event built by `event` if a load progress callback is set.
+pub(crate) fn report_load_event(event: impl FnOnce() -> LoadEvent) {
+    if let Some(callback) = &*LOAD_PROGRESS_CALLBACK.read().unwrap() {
+        callback(event());
+    }
+}
+
+/// A token which cancels loading a model: once [`CancellationToken::cancel`] is called, loading
+/// stops at the next file download, weight tensor or ISQ tensor with an error, and everything
+/// allocated so far is freed. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+static LOAD_CANCELLATION_TOKEN: RwLock<Option<CancellationToken>> = RwLock::new(None);
+
+/// Set the token which cancels loading a model, or clear it with `None`. Like the load progress
+/// callback, the token is global, so cancelling it cancels all models being loaded.
+pub fn set_load_cancellation_token(token: Option<CancellationToken>) {
+    *LOAD_CANCELLATION_TOKEN.write().unwrap() = token;
+}
+
+pub(crate) fn load_cancelled() -> bool {
+    LOAD_CANCELLATION_TOKEN
+        .read()
+        .unwrap()
+        .as_ref()
+        .is_some_and(CancellationToken::is_cancelled)
+}
+
+/// Error out if loading the model has been cancelled.
+pub(crate) fn check_load_cancelled() -> candle_core::Result<()> {
+    if load_cancelled() {
+        candle_core::bail!("Model loading was cancelled.");
+    }
+    Ok(())
+}
+
+/// Reports the download of one file to the load progress callback, at most once per MB.
+#[derive(Default)]
+pub(crate) struct DownloadProgress {
+    file: String,
+    downloaded: usize,
+    reported: usize,
+    total: usize,
+}
+
+impl DownloadProgress {
+    const REPORT_INTERVAL: usize = 1024 * 1024;
+
+    fn report(&mut self) {
+        self.reported = self.downloaded;
+        report_load_event(|| LoadEvent::Download {
+            file: self.file.clone(),
+            downloaded: self.downloaded,
+            total: self.total,
+        });
+    }
+}
+
+impl hf_hub::api::Progress for DownloadProgress {
+    fn init(&mut self, size: usize, filename: &str) {
+        self.file = filename.to_string();
+        self.total = size;
+        self.report();
+    }
+
+    fn update(&mut self, size: usize) {
+        self.downloaded += size;
+        if self.downloaded - self.reported >= Self::REPORT_INTERVAL {
+            self.report();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.report();
+    }
+}
+
 // Optionally display a progress bar via the `tqdm` crate:
 // Usage: `iter.with_progress(true)`
 // Similar to the `iter.tqdm()` feature except this supports opt-in via parameter.
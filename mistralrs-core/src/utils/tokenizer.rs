@@ -5,6 +5,8 @@ use serde::Deserialize;
 use serde_json::Value;
 use tokenizers::{tokenizer, Tokenizer};
 
+use crate::pipeline::in_memory::read_model_file;
+
 #[derive(Deserialize)]
 struct AddedToken {
     id: usize,
@@ -17,7 +19,7 @@ pub(crate) fn get_tokenizer<P: AsRef<Path> + Clone>(
     processor_added_tokens: Option<&[&str]>,
 ) -> Result<Tokenizer> {
     let mut tokenizer = {
-        let raw = std::fs::read(p.clone()).map_err(anyhow::Error::msg)?;
+        let raw = read_model_file(p.clone()).map_err(anyhow::Error::msg)?;
         let mut tokenizer: Value = serde_json::from_slice(&raw).unwrap();
         let added_tokens: Vec<AddedToken> =
             serde_json::from_value(tokenizer["added_tokens"].clone()).unwrap();
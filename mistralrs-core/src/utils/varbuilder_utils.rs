@@ -8,11 +8,18 @@ use std::{
 };
 
 use candle_core::{pickle::PthTensors, DType, Device, Result, Tensor};
-use mistralrs_quant::{safetensors::MmapedSafetensors, ShardedSafeTensors, ShardedVarBuilder};
+use mistralrs_quant::{
+    safetensors::{Load, MmapedSafetensors},
+    ShardedSafeTensors, ShardedVarBuilder,
+};
 use regex::Regex;
+use safetensors::tensor::{Metadata, SafeTensors, TensorView};
 
 use crate::lora::LoraConfig;
-use crate::utils::progress::IterWithProgress;
+use crate::pipeline::in_memory::in_memory_file;
+use crate::utils::progress::{
+    check_load_cancelled, report_load_event, IterWithProgress, LoadEvent,
+};
 use derive_new::new;
 
 trait TensorLoaderBackend {
@@ -35,6 +42,47 @@ impl TensorLoaderBackend for SafetensorBackend {
     }
 }
 
+/// A safetensors file held in memory by an `InMemoryModelPaths`.
+struct InMemorySafetensorBackend {
+    data: Arc<[u8]>,
+    header_size: usize,
+    metadata: Metadata,
+}
+
+impl InMemorySafetensorBackend {
+    fn new(data: Arc<[u8]>) -> Result<Self> {
+        let (header_size, metadata) = SafeTensors::read_metadata(&data)?;
+        Ok(Self {
+            data,
+            header_size,
+            metadata,
+        })
+    }
+}
+
+impl TensorLoaderBackend for InMemorySafetensorBackend {
+    fn get_names(&self) -> Vec<String> {
+        self.metadata.tensors().into_keys().collect::<Vec<_>>()
+    }
+    fn load_name(&self, name: &str, device: &Device, dtype: Option<DType>) -> Result<Tensor> {
+        let info =
+            self.metadata
+                .info(name)
+                .ok_or_else(|| candle_core::Error::CannotFindTensor {
+                    path: name.to_string(),
+                })?;
+        // The tensor data follows the 8 byte header length and the header.
+        let start = 8 + self.header_size;
+        let (begin, end) = info.data_offsets;
+        let view = TensorView::new(
+            info.dtype,
+            info.shape.clone(),
+            &self.data[start + begin..start + end],
+        )?;
+        view.load(device, dtype)
+    }
+}
+
 struct PickleBackend(PthTensors);
 
 impl TensorLoaderBackend for PickleBackend {
@@ -74,12 +122,13 @@ pub(crate) fn from_mmaped_safetensors(
     predicate: impl Fn(String) -> bool + Send + Sync + Clone + 'static,
     get_device_for_tensor: Arc<dyn Fn(String) -> DeviceForLoadTensor + Send + Sync + 'static>,
 ) -> Result<ShardedVarBuilder> {
-    // No mmap for cuda.
-    if xlora_paths.is_empty() && !base_device.is_cuda() || cfg!(feature = "ring") {
+    // No mmap for cuda, or for weights which are held in memory.
+    let in_memory = paths.iter().any(|p| in_memory_file(p).is_some());
+    if !in_memory && (xlora_paths.is_empty() && !base_device.is_cuda() || cfg!(feature = "ring")) {
         if !silent {
             tracing::info!("Loading model using mmap strategy.");
         }
-        return Ok(unsafe {
+        let vb = unsafe {
             ShardedSafeTensors::sharded(
                 &paths,
                 dtype.unwrap_or(DType::F16),
@@ -87,7 +136,13 @@ pub(crate) fn from_mmaped_safetensors(
                 make_dummy_regexes,
                 Arc::new(predicate),
             )?
+        };
+        check_load_cancelled()?;
+        report_load_event(|| LoadEvent::LoadShard {
+            loaded: paths.len(),
+            total: paths.len(),
         });
+        return Ok(vb);
     }
 
     #[allow(clippy::type_complexity)]
@@ -168,7 +223,17 @@ pub(crate) fn from_mmaped_safetensors(
 
     let mut ws = HashMap::new();
     // Wait until all spawned threads have finished loading tensors:
-    while !handles.iter().all(|h| h.is_finished()) {}
+    let mut loaded = 0;
+    while loaded < handles.len() {
+        let finished = handles.iter().filter(|h| h.is_finished()).count();
+        if finished > loaded {
+            loaded = finished;
+            report_load_event(|| LoadEvent::LoadShard {
+                loaded,
+                total: handles.len(),
+            });
+        }
+    }
     for h in handles {
         ws.extend(h.join().unwrap()?);
     }
@@ -239,9 +304,10 @@ trait LoadTensors {
             .to_str()
             .expect("Expected to convert")
         {
-            "safetensors" => Box::new(SafetensorBackend(unsafe {
-                MmapedSafetensors::new(path)?
-            })),
+            "safetensors" => match in_memory_file(path) {
+                Some(data) => Box::new(InMemorySafetensorBackend::new(data)?),
+                None => Box::new(SafetensorBackend(unsafe { MmapedSafetensors::new(path)? })),
+            },
             "pth" | "pt" | "bin" => Box::new(PickleBackend(
                 candle_core::pickle::PthTensors::new(path, None)?
             )),
@@ -259,6 +325,7 @@ trait LoadTensors {
         let mut loaded_tensors = HashMap::new();
         if !iter.is_empty() {
             for (load_name, key_name) in iter.into_iter().with_progress(is_silent) {
+                check_load_cancelled()?;
                 if !make_dummy_predicate(&load_name) {
                     let dev = match get_device_for_tensor(load_name.clone()) {
                         DeviceForLoadTensor::Base => base_device,
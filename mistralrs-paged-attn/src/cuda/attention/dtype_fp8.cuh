@@ -15,8 +15,13 @@ enum class Fp8KVCacheDataType {
   kAuto = 0,
   kFp8E4M3 = 1,
   kFp8E5M2 = 2,
+  kInt8 = 3,
 };
 
+// An int8 value cache block has this many extra rows per head after the
+// values: the float key scales and then the float value scales of each token.
+constexpr int kInt8ScaleRows = 2 * sizeof(float);
+
 // fp8 vector types for quantization of kv cache
 template <>
 struct Vec<uint8_t, 1> {
@@ -40,6 +40,7 @@ impl PagedAttention {
             DType::BF16 => 1,
             DType::F32 => 2,
             DType::F8E4M3 => 3,
+            DType::U8 => 4,
             dtype => candle::bail!("cache dtype {dtype:?} is not supported"),
         };
 
@@ -97,15 +98,15 @@ impl PagedAttention {
 
         // Get cuda slices for all tensors
         let q = q.as_cuda_slice::<T>()?;
-        let (kc_ptr, _kc_guard) = if cache_dtype == 3 {
-            slice_ptr(kc.as_cuda_slice::<F8E4M3>()?, kc_l.start_offset())
-        } else {
-            slice_ptr(kc.as_cuda_slice::<T>()?, kc_l.start_offset())
+        let (kc_ptr, _kc_guard) = match cache_dtype {
+            3 => slice_ptr(kc.as_cuda_slice::<F8E4M3>()?, kc_l.start_offset()),
+            4 => slice_ptr(kc.as_cuda_slice::<u8>()?, kc_l.start_offset()),
+            _ => slice_ptr(kc.as_cuda_slice::<T>()?, kc_l.start_offset()),
         };
-        let (vc_ptr, _vc_guard) = if cache_dtype == 3 {
-            slice_ptr(vc.as_cuda_slice::<F8E4M3>()?, vc_l.start_offset())
-        } else {
-            slice_ptr(vc.as_cuda_slice::<T>()?, vc_l.start_offset())
+        let (vc_ptr, _vc_guard) = match cache_dtype {
+            3 => slice_ptr(vc.as_cuda_slice::<F8E4M3>()?, vc_l.start_offset()),
+            4 => slice_ptr(vc.as_cuda_slice::<u8>()?, vc_l.start_offset()),
+            _ => slice_ptr(vc.as_cuda_slice::<T>()?, vc_l.start_offset()),
         };
         let cl = cl.as_cuda_slice::<u32>()?; // Should be i32!
         let bt = bt.as_cuda_slice::<u32>()?; // Should be i32!
@@ -185,13 +186,23 @@ impl PagedAttention {
             )
         }
 
-        if (num_blocks, num_kv_heads, head_size, block_size) != vc_l.shape().dims4()? {
+        // An int8 value cache has the scales of its tokens after the values of each head.
+        let is_int8 = cache_dtype == 4;
+        let v_head_size = if is_int8 {
+            head_size + crate::INT8_SCALE_ROWS
+        } else {
+            head_size
+        };
+        if (num_blocks, num_kv_heads, v_head_size, block_size) != vc_l.shape().dims4()? {
             candle::bail!(
                 "shape mismatch key_cache {:?} and value_cache {:?}",
                 kc_l.shape(),
                 vc_l.shape()
             )
         }
+        if is_int8 && (!vc_l.is_contiguous() || self.k_v_scale.is_some()) {
+            candle::bail!("an int8 value_cache must be contiguous and has no k_scale or v_scale")
+        }
 
         if (num_seqs) != cl_l.shape().dims1()? {
             candle::bail!(
@@ -338,7 +349,8 @@ impl candle::CustomOp1 for PagedAttention {
 /// * `q` - Query tensor with shape `(num_sequences, num_heads_q, head_size)`.
 /// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads_kv, head_size / x, block_size, x)`
 ///   with `x` being the size of an element in bytes.
-/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads_kv, head_size, block_size)`.
+/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads_kv, head_size, block_size)`,
+///   or `(num_blocks, num_heads_kv, head_size + INT8_SCALE_ROWS, block_size)` for a `u8` int8 cache.
 /// * `block_tables` - Padded table associating blocks to each sequence of shape `(num_sequences, max_context_len // block_size)`
 /// * `context_lens` - Tensor associating lengths to each sequence of shape `(num_sequences)`
 /// * `max_context_len` - Max of `context_len`
@@ -398,6 +410,7 @@ fn update_cache<
         DType::BF16 => 1,
         DType::F32 => 2,
         DType::F8E4M3 => 3,
+        DType::U8 => 4,
         dtype => candle::bail!("cache dtype {dtype:?} is not supported"),
     };
 
@@ -473,6 +486,13 @@ fn update_cache<
             slice_ptr(kc, kc_l.start_offset()),
             slice_ptr(vc, vc_l.start_offset()),
         )
+    } else if cache_dtype == 4 {
+        let kc = kc.as_cuda_slice::<u8>()?;
+        let vc = vc.as_cuda_slice::<u8>()?;
+        (
+            slice_ptr(kc, kc_l.start_offset()),
+            slice_ptr(vc, vc_l.start_offset()),
+        )
     } else {
         let kc = kc.as_cuda_slice::<T>()?;
         let vc = vc.as_cuda_slice::<T>()?;
@@ -527,13 +547,23 @@ fn update_cache<
         )
     }
 
-    if (num_blocks, num_heads, head_size, block_size) != vc_l.shape().dims4()? {
+    // An int8 value cache has the scales of its tokens after the values of each head.
+    let is_int8 = cache_dtype == 4;
+    let v_head_size = if is_int8 {
+        head_size + crate::INT8_SCALE_ROWS
+    } else {
+        head_size
+    };
+    if (num_blocks, num_heads, v_head_size, block_size) != vc_l.shape().dims4()? {
         candle::bail!(
             "shape mismatch key_cache {:?} and value_cache {:?}",
             kc_l.shape(),
             vc_l.shape()
         )
     }
+    if is_int8 && (!vc_l.is_contiguous() || !kc_l.is_contiguous() || k_v_scale.is_some()) {
+        candle::bail!("int8 caches must be contiguous and have no k_scale or v_scale")
+    }
 
     if (num_tokens) != s_l.shape().dims1()? {
         candle::bail!(
@@ -582,7 +612,9 @@ fn update_cache<
 /// * `value` - Value tensor of shape `(num_tokens, num_heads, head_size)`.
 /// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads, head_size / x, block_size, x)`
 ///   with `x` being the size of an element in bytes.
-/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`.
+/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`,
+///   or `(num_blocks, num_heads, head_size + INT8_SCALE_ROWS, block_size)` for a `u8` int8 cache,
+///   whose per-token scales are computed here.
 /// * `slot_mapping` - Mapping associating a slot to each token of shape `(num_tokens)`.
 pub fn reshape_and_cache(
     key: &Tensor,
@@ -175,6 +175,15 @@ __device__ void paged_attention_kernel(
   const float alibi_slope =
       alibi_slopes == nullptr ? 0.f : alibi_slopes[head_idx];
 
+  // An int8 value cache has the per-token scales after the values of each
+  // head, so its strides differ from those of the key cache.
+  constexpr bool IS_INT8 = kv_dt == vllm::Fp8KVCacheDataType::kInt8;
+  const int v_head_stride =
+      IS_INT8 ? (HEAD_SIZE + vllm::kInt8ScaleRows) * BLOCK_SIZE
+              : kv_head_stride;
+  const int v_block_stride =
+      IS_INT8 ? num_kv_heads * v_head_stride : kv_block_stride;
+
   // A vector type to store a part of a key or a query.
   // The vector size is configured in such a way that the threads in a thread
   // group fetch or compute 16 bytes at a time. For example, if the size of a
@@ -243,6 +252,13 @@ __device__ void paged_attention_kernel(
           (thread_group_idx + i * WARP_SIZE) % BLOCK_SIZE;
       const int token_idx = block_idx * BLOCK_SIZE + physical_block_offset;
       K_vec k_vecs[NUM_VECS_PER_THREAD];
+      float k_token_scale = 1.f;
+      if constexpr (IS_INT8) {
+        const float *k_scales = reinterpret_cast<const float *>(
+            v_cache + physical_block_number * v_block_stride +
+            kv_head_idx * v_head_stride + HEAD_SIZE * BLOCK_SIZE);
+        k_token_scale = k_scales[physical_block_offset];
+      }
 
 #pragma unroll
       for (int j = 0; j < NUM_VECS_PER_THREAD; j++) {
@@ -256,6 +272,15 @@ __device__ void paged_attention_kernel(
         if constexpr (kv_dt == vllm::Fp8KVCacheDataType::kAuto) {
           k_vecs[j] = *reinterpret_cast<const K_vec *>(
               k_ptr + offset1 * BLOCK_SIZE * x + offset2);
+        } else if constexpr (IS_INT8) {
+          const int8_t *k_int8 = reinterpret_cast<const int8_t *>(
+              k_ptr + offset1 * BLOCK_SIZE * x + offset2);
+          scalar_t *k_vec_ptr = reinterpret_cast<scalar_t *>(&k_vecs[j]);
+#pragma unroll
+          for (int l = 0; l < VEC_SIZE; l++) {
+            from_float(k_vec_ptr[l],
+                       static_cast<float>(k_int8[l]) * k_token_scale);
+          }
         } else {
           using Cache_K_vec = typename vllm::Vec<cache_t, VEC_SIZE>::Type;
           Cache_K_vec fp8_k_vec = *reinterpret_cast<const Cache_K_vec *>(
@@ -373,8 +398,8 @@ __device__ void paged_attention_kernel(
     from_float(logits_vec, *reinterpret_cast<Float_L_vec *>(logits + token_idx -
                                                             start_token_idx));
 
-    const cache_t *v_ptr = v_cache + physical_block_number * kv_block_stride +
-                           kv_head_idx * kv_head_stride;
+    const cache_t *v_ptr = v_cache + physical_block_number * v_block_stride +
+                           kv_head_idx * v_head_stride;
 #pragma unroll
     for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
       const int row_idx = lane / NUM_V_VECS_PER_ROW + i * NUM_ROWS_PER_ITER;
@@ -384,6 +409,18 @@ __device__ void paged_attention_kernel(
 
         if constexpr (kv_dt == vllm::Fp8KVCacheDataType::kAuto) {
           v_vec = *reinterpret_cast<const V_vec *>(v_ptr + offset);
+        } else if constexpr (IS_INT8) {
+          const int8_t *v_int8 =
+              reinterpret_cast<const int8_t *>(v_ptr + offset);
+          const float *v_scales = reinterpret_cast<const float *>(
+              v_ptr + (HEAD_SIZE + vllm::kInt8ScaleRows / 2) * BLOCK_SIZE);
+          scalar_t *v_vec_ptr = reinterpret_cast<scalar_t *>(&v_vec);
+#pragma unroll
+          for (int j = 0; j < V_VEC_SIZE; j++) {
+            from_float(v_vec_ptr[j],
+                       static_cast<float>(v_int8[j]) *
+                           v_scales[physical_block_offset + j]);
+          }
         } else {
           using Cache_V_vec = typename vllm::Vec<cache_t, V_VEC_SIZE>::Type;
           Cache_V_vec fp8_v_vec =
@@ -731,6 +768,9 @@ inline void paged_attention_v1_launcher(
   case 32:                                                                     \
     CALL_V1_LAUNCHER(T, CACHE_T, KV_DT, 32);                                   \
     break;                                                                     \
+  case 64:                                                                     \
+    CALL_V1_LAUNCHER(T, CACHE_T, KV_DT, 64);                                   \
+    break;                                                                     \
   default:                                                                     \
     break;                                                                     \
   }
@@ -835,6 +875,9 @@ inline void paged_attention_v2_launcher(
   case 32:                                                                     \
     CALL_V2_LAUNCHER(T, CACHE_T, KV_DT, 32);                                   \
     break;                                                                     \
+  case 64:                                                                     \
+    CALL_V2_LAUNCHER(T, CACHE_T, KV_DT, 64);                                   \
+    break;                                                                     \
   default:                                                                     \
     break;                                                                     \
   }
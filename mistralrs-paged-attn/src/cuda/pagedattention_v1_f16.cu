@@ -15,13 +15,18 @@ extern "C" void paged_attention_v1_f16(
     int32_t max_num_blocks_per_seq, int32_t q_stride, int32_t kv_block_stride,
     int32_t kv_head_stride, cudaStream_t stream,
 
-    uint32_t cache_dtype, // 0 => f16; 1 => bf16; 2 => f32; 3 => fp8_e4m3
+    uint32_t cache_dtype, // 0 => f16; 1 => bf16; 2 => f32; 3 => fp8_e4m3;
+                          // 4 => int8
     float *k_scale, float *v_scale) {
 
   if (cache_dtype == 3) {
     // FP8 cache
     CALL_V1_LAUNCHER_BLOCK_SIZE(uint16_t, uint8_t,
                                 vllm::Fp8KVCacheDataType::kFp8E4M3);
+  } else if (cache_dtype == 4) {
+    // Int8 cache
+    CALL_V1_LAUNCHER_BLOCK_SIZE(uint16_t, int8_t,
+                                vllm::Fp8KVCacheDataType::kInt8);
   } else {
     // Non-FP8 cache
     CALL_V1_LAUNCHER_BLOCK_SIZE(uint16_t, uint16_t,
@@ -84,6 +84,104 @@ __global__ void reshape_and_cache_kernel(
   }
 }
 
+inline __device__ float int8_cache_to_float(uint16_t v) {
+  return half_to_float(v);
+}
+inline __device__ float int8_cache_to_float(__nv_bfloat16 v) {
+  return to_float(v);
+}
+inline __device__ float int8_cache_to_float(float v) { return v; }
+
+inline __device__ int8_t quantize_int8(float v, float scale) {
+  return static_cast<int8_t>(fmaxf(-127.f, fminf(127.f, rintf(v / scale))));
+}
+
+#define INT8_RESHAPE_THREADS 128
+
+// Grid: (num_tokens, num_heads). Each head of a token is quantized with its own
+// absmax scale, which is stored after the values of the head in the value
+// cache block.
+template <typename scalar_t>
+__global__ void reshape_and_cache_int8_kernel(
+    const scalar_t *__restrict__ key,   // [num_tokens, num_heads, head_size]
+    const scalar_t *__restrict__ value, // [num_tokens, num_heads, head_size]
+    int8_t *__restrict__ key_cache,     // [num_blocks, num_heads, head_size/x,
+                                        // block_size, x]
+    int8_t *__restrict__ value_cache,   // [num_blocks, num_heads,
+                                        // head_size + 8, block_size]
+    const int64_t *__restrict__ slot_mapping, // [num_tokens]
+    const int key_stride, const int value_stride, const int num_heads,
+    const int head_size, const int block_size, const int x) {
+  const int64_t token_idx = blockIdx.x;
+  const int head_idx = blockIdx.y;
+  const int64_t slot_idx = slot_mapping[token_idx];
+  if (slot_idx < 0) {
+    // Padding token that should be ignored.
+    return;
+  }
+
+  const int64_t block_idx = slot_idx / block_size;
+  const int64_t block_offset = slot_idx % block_size;
+
+  const scalar_t *src_key = key + token_idx * key_stride + head_idx * head_size;
+  const scalar_t *src_value =
+      value + token_idx * value_stride + head_idx * head_size;
+
+  __shared__ float k_red[INT8_RESHAPE_THREADS];
+  __shared__ float v_red[INT8_RESHAPE_THREADS];
+  float k_max = 0.f;
+  float v_max = 0.f;
+  for (int i = threadIdx.x; i < head_size; i += blockDim.x) {
+    k_max = fmaxf(k_max, fabsf(int8_cache_to_float(src_key[i])));
+    v_max = fmaxf(v_max, fabsf(int8_cache_to_float(src_value[i])));
+  }
+  k_red[threadIdx.x] = k_max;
+  v_red[threadIdx.x] = v_max;
+  __syncthreads();
+  for (int s = blockDim.x / 2; s > 0; s >>= 1) {
+    if (threadIdx.x < s) {
+      k_red[threadIdx.x] = fmaxf(k_red[threadIdx.x], k_red[threadIdx.x + s]);
+      v_red[threadIdx.x] = fmaxf(v_red[threadIdx.x], v_red[threadIdx.x + s]);
+    }
+    __syncthreads();
+  }
+  const float k_scale = k_red[0] > 0.f ? k_red[0] / 127.f : 1.f;
+  const float v_scale = v_red[0] > 0.f ? v_red[0] / 127.f : 1.f;
+
+  int8_t *tgt_value_head =
+      value_cache + (block_idx * num_heads + head_idx) *
+                        (head_size + vllm::kInt8ScaleRows) * block_size;
+  for (int head_offset = threadIdx.x; head_offset < head_size;
+       head_offset += blockDim.x) {
+    const int x_idx = head_offset / x;
+    const int x_offset = head_offset % x;
+    const int64_t tgt_key_idx =
+        block_idx * num_heads * (head_size / x) * block_size * x +
+        head_idx * (head_size / x) * block_size * x + x_idx * block_size * x +
+        block_offset * x + x_offset;
+    key_cache[tgt_key_idx] =
+        quantize_int8(int8_cache_to_float(src_key[head_offset]), k_scale);
+    tgt_value_head[head_offset * block_size + block_offset] =
+        quantize_int8(int8_cache_to_float(src_value[head_offset]), v_scale);
+  }
+  if (threadIdx.x == 0) {
+    float *scales =
+        reinterpret_cast<float *>(tgt_value_head + head_size * block_size);
+    scales[block_offset] = k_scale;
+    scales[block_size + block_offset] = v_scale;
+  }
+}
+
+#define CALL_RESHAPE_AND_CACHE_INT8(KV_T)                                      \
+  vllm::reshape_and_cache_int8_kernel<KV_T>                                    \
+      <<<dim3(num_tokens, num_heads), dim3(INT8_RESHAPE_THREADS), 0,           \
+         stream>>>(reinterpret_cast<KV_T *>(key),                              \
+                   reinterpret_cast<KV_T *>(value),                            \
+                   reinterpret_cast<int8_t *>(key_cache),                      \
+                   reinterpret_cast<int8_t *>(value_cache), slot_mapping,      \
+                   key_stride, value_stride, num_heads, head_size, block_size, \
+                   x);
+
 #define CALL_RESHAPE_AND_CACHE(KV_T, CACHE_T, KV_DTYPE)                        \
   vllm::reshape_and_cache_kernel<KV_T, CACHE_T, KV_DTYPE>                      \
       <<<grid, block, 0, stream>>>(                                            \
@@ -100,7 +198,9 @@ extern "C" void reshape_and_cache(
     void *key,         // [num_tokens, num_heads, head_size]
     void *value,       // [num_tokens, num_heads, head_size]
     void *key_cache,   // [num_blocks, num_heads, head_size/x, block_size, x]
-    void *value_cache, // [num_blocks, num_heads, head_size, block_size]
+    void *value_cache, // [num_blocks, num_heads, head_size, block_size], or
+                       // [num_blocks, num_heads, head_size + 8, block_size]
+                       // for int8
     int64_t *slot_mapping, // [num_tokens]
 
     int32_t num_tokens, int32_t num_heads, int32_t head_size,
@@ -108,7 +208,8 @@ extern "C" void reshape_and_cache(
     cudaStream_t stream,
 
     uint32_t dtype,       // 0 => f16; 1 => bf16; 2 => f32
-    uint32_t cache_dtype, // 0 => f16; 1 => bf16; 2 => f32; 3 => fp8_e4m3
+    uint32_t cache_dtype, // 0 => f16; 1 => bf16; 2 => f32; 3 => fp8_e4m3;
+                          // 4 => int8
     float *k_scale, float *v_scale) {
   dim3 grid(num_tokens);
   dim3 block(std::min(num_heads * head_size, 512));
@@ -125,6 +226,15 @@ extern "C" void reshape_and_cache(
       CALL_RESHAPE_AND_CACHE(float, uint8_t,
                              vllm::Fp8KVCacheDataType::kFp8E4M3);
     }
+  } else if (cache_dtype == 4) {
+    // Int8 cache
+    if (dtype == 0) {
+      CALL_RESHAPE_AND_CACHE_INT8(uint16_t);
+    } else if (dtype == 1) {
+      CALL_RESHAPE_AND_CACHE_INT8(__nv_bfloat16);
+    } else if (dtype == 2) {
+      CALL_RESHAPE_AND_CACHE_INT8(float);
+    }
   } else {
     // Non-FP8 cache
     if (dtype == 0) {
@@ -1,3 +1,7 @@
+/// Extra rows per head of an int8 value cache block, after the `head_size` rows of values. They
+/// hold the `f32` key scales and then the `f32` value scales of the `block_size` tokens.
+pub const INT8_SCALE_ROWS: usize = 2 * std::mem::size_of::<f32>();
+
 #[cfg(all(feature = "cuda", target_family = "unix"))]
 mod cuda;
 #[cfg(all(feature = "cuda", target_family = "unix"))]
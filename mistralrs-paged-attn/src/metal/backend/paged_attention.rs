@@ -40,6 +40,7 @@ impl candle_core::CustomOp1 for PagedAttention {
             DType::BF16 => PagedAttentionDType::BF16,
             DType::F32 => PagedAttentionDType::F32,
             DType::F8E4M3 => PagedAttentionDType::F8E4M3,
+            DType::U8 => PagedAttentionDType::I8,
             dtype => candle_core::bail!("dtype {dtype:?} is not supported"),
         };
 
@@ -140,13 +141,25 @@ impl candle_core::CustomOp1 for PagedAttention {
             )
         }
 
-        if (num_blocks, num_kv_heads, head_size, block_size) != vc_l.shape().dims4()? {
+        // An int8 value cache has the scales of its tokens after the values of each head.
+        let is_int8 = matches!(cache_ty, PagedAttentionDType::I8);
+        let v_head_size = if is_int8 {
+            head_size + crate::INT8_SCALE_ROWS
+        } else {
+            head_size
+        };
+        if (num_blocks, num_kv_heads, v_head_size, block_size) != vc_l.shape().dims4()? {
             candle_core::bail!(
                 "shape mismatch key_cache {:?} and value_cache {:?}",
                 kc_l.shape(),
                 vc_l.shape()
             )
         }
+        if is_int8 && (!vc_l.is_contiguous() || self.k_v_scale.is_some()) {
+            candle_core::bail!(
+                "an int8 value_cache must be contiguous and has no k_scale or v_scale"
+            )
+        }
 
         if (num_seqs) != cl_l.shape().dims1()? {
             candle_core::bail!(
@@ -305,7 +318,8 @@ impl candle_core::CustomOp1 for PagedAttention {
 /// * `q` - Query tensor with shape `(num_sequences, num_heads_q, head_size)`.
 /// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads_kv, head_size / x, block_size, x)`
 ///   with `x` being the size of an element in bytes.
-/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads_kv, head_size, block_size)`.
+/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads_kv, head_size, block_size)`,
+///   or `(num_blocks, num_heads_kv, head_size + INT8_SCALE_ROWS, block_size)` for a `u8` int8 cache.
 /// * `block_tables` - Padded table associating blocks to each sequence of shape `(num_sequences, max_context_len // block_size)`
 /// * `context_lens` - Tensor associating lengths to each sequence of shape `(num_sequences)`
 /// * `max_context_len` - Max of `context_len`
@@ -349,7 +363,9 @@ pub fn paged_attention(
 /// * `value` - Value tensor of shape `(num_tokens, num_heads, head_size)`.
 /// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads, head_size / x, block_size, x)`
 ///   with `x` being the size of an element in bytes.
-/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`.
+/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`,
+///   or `(num_blocks, num_heads, head_size + INT8_SCALE_ROWS, block_size)` for a `u8` int8 cache,
+///   whose per-token scales are computed here.
 /// * `slot_mapping` - Mapping associating a slot to each token of shape `(num_tokens)`.
 pub fn reshape_and_cache(
     key: &Tensor,
@@ -370,6 +386,7 @@ pub fn reshape_and_cache(
         DType::BF16 => PagedAttentionDType::BF16,
         DType::F32 => PagedAttentionDType::F32,
         DType::F8E4M3 => PagedAttentionDType::F8E4M3,
+        DType::U8 => PagedAttentionDType::I8,
         dtype => candle_core::bail!("dtype {dtype:?} is not supported"),
     };
 
@@ -467,13 +484,23 @@ pub fn reshape_and_cache(
         )
     }
 
-    if (num_blocks, num_heads, head_size, block_size) != vc_l.shape().dims4()? {
+    // An int8 value cache has the scales of its tokens after the values of each head.
+    let is_int8 = matches!(cache_ty, PagedAttentionDType::I8);
+    let v_head_size = if is_int8 {
+        head_size + crate::INT8_SCALE_ROWS
+    } else {
+        head_size
+    };
+    if (num_blocks, num_heads, v_head_size, block_size) != vc_l.shape().dims4()? {
         candle_core::bail!(
             "shape mismatch key_cache {:?} and value_cache {:?}",
             kc_l.shape(),
             vc_l.shape()
         )
     }
+    if is_int8 && (!vc_l.is_contiguous() || !kc_l.is_contiguous() || k_v_scale.is_some()) {
+        candle_core::bail!("int8 caches must be contiguous and have no k_scale or v_scale")
+    }
 
     if (num_tokens) != s_l.shape().dims1()? {
         candle_core::bail!(
@@ -366,6 +366,7 @@ pub enum PagedAttentionDType {
     BF16 = 1,
     F32 = 2,
     F8E4M3 = 3,
+    I8 = 4,
 }
 
 impl PagedAttentionDType {
@@ -375,10 +376,15 @@ impl PagedAttentionDType {
             PagedAttentionDType::BF16 => "bfloat16_t",
             PagedAttentionDType::F16 => "half",
             PagedAttentionDType::F8E4M3 => "uchar",
+            PagedAttentionDType::I8 => "char",
         }
     }
 }
 
+/// The threads of the int8 reshape and cache kernel, which is `INT8_RESHAPE_THREADS` in
+/// `reshape_and_cache.metal`.
+const INT8_RESHAPE_THREADS: u64 = 128;
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_reshape_and_cache(
     device: &Device,
@@ -405,18 +411,25 @@ pub fn call_reshape_and_cache(
     key_stride: i32,
     value_stride: i32,
 ) -> Result<(), MetalKernelError> {
-    let name = format!(
-        "reshape_and_cache_kv_{}_cache_{}",
-        kv_ty.to_repr(),
-        cache_ty.to_repr()
-    );
+    // The int8 cache computes its own scales, one per token and head.
+    let is_int8 = matches!(cache_ty, PagedAttentionDType::I8);
+    let pipeline = if is_int8 {
+        let name = format!("reshape_and_cache_int8_kv_{}", kv_ty.to_repr());
+        kernels.load_pipeline(device, name)?
+    } else {
+        let name = format!(
+            "reshape_and_cache_kv_{}_cache_{}",
+            kv_ty.to_repr(),
+            cache_ty.to_repr()
+        );
 
-    let constants = Some(ConstantValues::new(vec![(
-        10,
-        Value::Bool(/* use_fp8_scales */ k_v_scale.is_some()),
-    )]));
+        let constants = Some(ConstantValues::new(vec![(
+            10,
+            Value::Bool(/* use_fp8_scales */ k_v_scale.is_some()),
+        )]));
 
-    let pipeline = kernels.load_pipeline_with_constants(device, name, constants)?;
+        kernels.load_pipeline_with_constants(device, name, constants)?
+    };
     let encoder = ep.encoder();
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
@@ -461,15 +474,32 @@ pub fn call_reshape_and_cache(
         &x as *const _ as *const c_void,
     );
 
-    let thread_groups_count = MTLSize {
-        width: num_tokens as u64,
-        height: 1,
-        depth: 1,
-    };
-    let threads_per_threadgroup = MTLSize {
-        width: (num_heads * head_size).min(512) as u64,
-        height: 1,
-        depth: 1,
+    let (thread_groups_count, threads_per_threadgroup) = if is_int8 {
+        (
+            MTLSize {
+                width: num_tokens as u64,
+                height: num_heads as u64,
+                depth: 1,
+            },
+            MTLSize {
+                width: INT8_RESHAPE_THREADS,
+                height: 1,
+                depth: 1,
+            },
+        )
+    } else {
+        (
+            MTLSize {
+                width: num_tokens as u64,
+                height: 1,
+                depth: 1,
+            },
+            MTLSize {
+                width: (num_heads * head_size).min(512) as u64,
+                height: 1,
+                depth: 1,
+            },
+        )
     };
     encoder.dispatch_thread_groups(thread_groups_count, threads_per_threadgroup);
     Ok(())
@@ -804,6 +834,12 @@ pub fn call_paged_attention_v2(
                     got: DType::F8E4M3,
                 })
             }
+            PagedAttentionDType::I8 => {
+                return Err(MetalKernelError::DTypeMismatch {
+                    expected: vec![DType::F32, DType::F16, DType::BF16],
+                    got: DType::U8,
+                })
+            }
         };
         let mut name = name.to_string();
         name.push_str(&format!("_hs{head_size}"));
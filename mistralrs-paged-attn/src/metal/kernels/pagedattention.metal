@@ -436,6 +436,26 @@ template <> struct Vec<uchar, 8> {
   using Type = Uchar8_;
 };
 
+// ========================================== Int8 (char) vector data types.
+
+struct Char8_ {
+  char4 x;
+  char4 y;
+};
+
+template <> struct Vec<char, 1> {
+  using Type = char;
+};
+template <> struct Vec<char, 2> {
+  using Type = char2;
+};
+template <> struct Vec<char, 4> {
+  using Type = char4;
+};
+template <> struct Vec<char, 8> {
+  using Type = Char8_;
+};
+
 // FP16 vector data types.
 struct Half8_ {
   half4 x;
@@ -557,6 +577,16 @@ template <typename T> inline constexpr bool is_uchar() { return false; }
 // Specialization: T is uchar
 template <> inline constexpr bool is_uchar<uchar>() { return true; }
 
+// General case: not char
+template <typename T> inline constexpr bool is_char() { return false; }
+
+// Specialization: T is char, the int8 cache
+template <> inline constexpr bool is_char<char>() { return true; }
+
+// An int8 value cache block has this many extra rows per head after the
+// values: the float key scales and then the float value scales of each token.
+constant int kInt8ScaleRows = 2 * sizeof(float);
+
 // Generic fallback – will fail to compile if a required specialisation is
 // missing.
 template <typename Vec, typename Quant_vec>
@@ -835,6 +865,14 @@ template <typename T, typename CACHE_T, int HEAD_SIZE, int BLOCK_SIZE,
   const int kv_head_idx = head_idx / num_queries_per_kv;
   const float alibi_slope = !use_alibi ? 0.f : alibi_slopes[head_idx];
 
+  // An int8 value cache has the per-token scales after the values of each
+  // head, so its strides differ from those of the key cache.
+  const int v_head_stride = is_char<CACHE_T>()
+                                ? (HEAD_SIZE + kInt8ScaleRows) * BLOCK_SIZE
+                                : kv_head_stride;
+  const int v_block_stride =
+      is_char<CACHE_T>() ? num_kv_heads * v_head_stride : kv_block_stride;
+
   // A vector type to store a part of a key or a query.
   // The vector size is configured in such a way that the threads in a thread
   // group fetch or compute 16 bytes at a time. For example, if the size of a
@@ -901,6 +939,14 @@ template <typename T, typename CACHE_T, int HEAD_SIZE, int BLOCK_SIZE,
           (thread_group_idx + i * NUM_SIMD_LANES) % BLOCK_SIZE;
       const int token_idx = block_idx * BLOCK_SIZE + physical_block_offset;
       K_vec k_vecs[NUM_VECS_PER_THREAD];
+      float k_token_scale = 1.f;
+      if constexpr (is_char<CACHE_T>()) {
+        const device float *k_scales =
+            reinterpret_cast<const device float *>(
+                v_cache + physical_block_number * v_block_stride +
+                kv_head_idx * v_head_stride + HEAD_SIZE * BLOCK_SIZE);
+        k_token_scale = k_scales[physical_block_offset];
+      }
 
 #pragma unroll
       for (int j = 0; j < NUM_VECS_PER_THREAD; j++) {
@@ -916,6 +962,15 @@ template <typename T, typename CACHE_T, int HEAD_SIZE, int BLOCK_SIZE,
           Quant_vec k_vec_quant = *reinterpret_cast<const device Quant_vec *>(
               k_ptr + offset1 * BLOCK_SIZE * x + offset2);
           k_vecs[j] = fp8_convert<K_vec, Quant_vec>(k_vec_quant, *k_scale);
+        } else if constexpr (is_char<CACHE_T>()) {
+          // Int8 support
+          const device CACHE_T *k_int8 =
+              k_ptr + offset1 * BLOCK_SIZE * x + offset2;
+          thread T *k_vec_ptr = reinterpret_cast<thread T *>(&k_vecs[j]);
+#pragma unroll
+          for (int l = 0; l < VEC_SIZE; l++) {
+            k_vec_ptr[l] = T(float(k_int8[l]) * k_token_scale);
+          }
         } else {
           // Non-FP8 default
           k_vecs[j] = *reinterpret_cast<const device K_vec *>(
@@ -1033,8 +1088,8 @@ template <typename T, typename CACHE_T, int HEAD_SIZE, int BLOCK_SIZE,
     from_float(logits_vec, logits_float_vec);
 
     const device CACHE_T *v_ptr = v_cache +
-                                  physical_block_number * kv_block_stride +
-                                  kv_head_idx * kv_head_stride;
+                                  physical_block_number * v_block_stride +
+                                  kv_head_idx * v_head_stride;
 #pragma unroll
     for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
       const int row_idx = lane / NUM_V_VECS_PER_ROW + i * NUM_ROWS_PER_ITER;
@@ -1051,6 +1106,17 @@ template <typename T, typename CACHE_T, int HEAD_SIZE, int BLOCK_SIZE,
           V_quant_vec v_quant_vec =
               *reinterpret_cast<const device V_quant_vec *>(v_ptr + offset);
           v_vec = fp8_convert<V_vec, V_quant_vec>(v_quant_vec, *v_scale);
+        } else if constexpr (is_char<CACHE_T>()) {
+          // Int8 support
+          const device float *v_scales =
+              reinterpret_cast<const device float *>(
+                  v_ptr + (HEAD_SIZE + kInt8ScaleRows / 2) * BLOCK_SIZE);
+          thread T *v_vec_ptr = reinterpret_cast<thread T *>(&v_vec);
+#pragma unroll
+          for (int j = 0; j < V_VEC_SIZE; j++) {
+            v_vec_ptr[j] = T(float(v_ptr[offset + j]) *
+                             v_scales[physical_block_offset + j]);
+          }
         } else {
           // Non-FP8 default
           v_vec = *reinterpret_cast<const device V_vec *>(v_ptr + offset);
@@ -1352,6 +1418,8 @@ template <typename T, int HEAD_SIZE, int NUM_THREADS, int NUM_SIMD_LANES,
   instantiate_paged_attention_heads(type, cache_type, 16, num_threads,         \
                                     num_simd_lanes, partition_size);           \
   instantiate_paged_attention_heads(type, cache_type, 32, num_threads,         \
+                                    num_simd_lanes, partition_size);           \
+  instantiate_paged_attention_heads(type, cache_type, 64, num_threads,         \
                                     num_simd_lanes, partition_size);
 
 // TODO: tune num_threads = 256
@@ -1379,6 +1447,10 @@ instantiate_paged_attention_v1(float, uchar, 32);
 instantiate_paged_attention_v1(bfloat16_t, uchar, 32);
 instantiate_paged_attention_v1(half, uchar, 32);
 
+instantiate_paged_attention_v1(float, char, 32);
+instantiate_paged_attention_v1(bfloat16_t, char, 32);
+instantiate_paged_attention_v1(half, char, 32);
+
 instantiate_paged_attention_v2_reduce(float, 32);
 instantiate_paged_attention_v2_reduce(bfloat16_t, 32);
 instantiate_paged_attention_v2_reduce(half, 32);
@@ -1390,3 +1462,7 @@ instantiate_paged_attention_v2(half, half, 32);
 instantiate_paged_attention_v2(float, uchar, 32);
 instantiate_paged_attention_v2(bfloat16_t, uchar, 32);
 instantiate_paged_attention_v2(half, uchar, 32);
+
+instantiate_paged_attention_v2(float, char, 32);
+instantiate_paged_attention_v2(bfloat16_t, char, 32);
+instantiate_paged_attention_v2(half, char, 32);
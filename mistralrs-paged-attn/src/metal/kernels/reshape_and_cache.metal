@@ -95,6 +95,119 @@ template <typename KV_T, typename CACHE_T>
   }
 }
 
+// An int8 value cache block has this many extra rows per head after the
+// values: the float key scales and then the float value scales of each token.
+constant int kInt8ScaleRows = 2 * sizeof(float);
+
+#define INT8_RESHAPE_THREADS 128
+
+inline char quantize_int8(float v, float scale) {
+  return static_cast<char>(clamp(rint(v / scale), -127.f, 127.f));
+}
+
+// Threadgroups: (num_tokens, num_heads). Each head of a token is quantized with
+// its own absmax scale, which is stored after the values of the head in the
+// value cache block.
+template <typename KV_T>
+[[kernel]] void reshape_and_cache_int8(
+    const device KV_T *__restrict__ key
+    [[buffer(0)]], // [num_tokens, num_heads, head_size]
+    const device KV_T *__restrict__ value
+    [[buffer(1)]], // [num_tokens, num_heads, head_size]
+    device char *__restrict__ key_cache
+    [[buffer(2)]], // [num_blocks, num_heads, head_size/x, block_size, x]
+    device char *__restrict__ value_cache
+    [[buffer(3)]], // [num_blocks, num_heads, head_size + 8, block_size]
+    const device int64_t *__restrict__ slot_mapping
+    [[buffer(4)]], // [num_tokens]
+    device const int &key_stride [[buffer(7)]],
+    device const int &value_stride [[buffer(8)]],
+    device const int &num_heads [[buffer(9)]],
+    device const int &head_size [[buffer(10)]],
+    device const int &block_size [[buffer(11)]],
+    device const int &x [[buffer(12)]],
+    uint2 gid [[threadgroup_position_in_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint threads_per_threadgroup [[threads_per_threadgroup]]) {
+  const int64_t token_idx = gid.x;
+  const int head_idx = gid.y;
+  const int64_t slot_idx = slot_mapping[token_idx];
+  if (slot_idx < 0) {
+    // Padding token that should be ignored.
+    return;
+  }
+
+  const int64_t block_idx = slot_idx / block_size;
+  const int64_t block_offset = slot_idx % block_size;
+
+  const device KV_T *src_key =
+      key + token_idx * key_stride + head_idx * head_size;
+  const device KV_T *src_value =
+      value + token_idx * value_stride + head_idx * head_size;
+
+  threadgroup float k_red[INT8_RESHAPE_THREADS];
+  threadgroup float v_red[INT8_RESHAPE_THREADS];
+  float k_max = 0.f;
+  float v_max = 0.f;
+  for (int i = tid; i < head_size; i += threads_per_threadgroup) {
+    k_max = max(k_max, abs((float)src_key[i]));
+    v_max = max(v_max, abs((float)src_value[i]));
+  }
+  k_red[tid] = k_max;
+  v_red[tid] = v_max;
+  threadgroup_barrier(mem_flags::mem_threadgroup);
+  for (uint s = threads_per_threadgroup / 2; s > 0; s >>= 1) {
+    if (tid < s) {
+      k_red[tid] = max(k_red[tid], k_red[tid + s]);
+      v_red[tid] = max(v_red[tid], v_red[tid + s]);
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+  }
+  const float k_scale = k_red[0] > 0.f ? k_red[0] / 127.f : 1.f;
+  const float v_scale = v_red[0] > 0.f ? v_red[0] / 127.f : 1.f;
+
+  device char *tgt_value_head =
+      value_cache + (block_idx * num_heads + head_idx) *
+                        (head_size + kInt8ScaleRows) * block_size;
+  for (int head_offset = tid; head_offset < head_size;
+       head_offset += threads_per_threadgroup) {
+    const int x_idx = head_offset / x;
+    const int x_offset = head_offset % x;
+    const int64_t tgt_key_idx =
+        block_idx * num_heads * (head_size / x) * block_size * x +
+        head_idx * (head_size / x) * block_size * x + x_idx * block_size * x +
+        block_offset * x + x_offset;
+    key_cache[tgt_key_idx] =
+        quantize_int8((float)src_key[head_offset], k_scale);
+    tgt_value_head[head_offset * block_size + block_offset] =
+        quantize_int8((float)src_value[head_offset], v_scale);
+  }
+  if (tid == 0) {
+    device float *scales = reinterpret_cast<device float *>(
+        tgt_value_head + head_size * block_size);
+    scales[block_offset] = k_scale;
+    scales[block_size + block_offset] = v_scale;
+  }
+}
+
+#define instantiate_reshape_and_cache_int8(kv_type)                            \
+  template [[host_name("reshape_and_cache_int8_kv_" #kv_type)]] [[kernel]]     \
+  void reshape_and_cache_int8<kv_type>(                                        \
+      const device kv_type *__restrict__ key [[buffer(0)]],                    \
+      const device kv_type *__restrict__ value [[buffer(1)]],                  \
+      device char *__restrict__ key_cache [[buffer(2)]],                       \
+      device char *__restrict__ value_cache [[buffer(3)]],                     \
+      const device int64_t *__restrict__ slot_mapping [[buffer(4)]],           \
+      device const int &key_stride [[buffer(7)]],                              \
+      device const int &value_stride [[buffer(8)]],                            \
+      device const int &num_heads [[buffer(9)]],                               \
+      device const int &head_size [[buffer(10)]],                              \
+      device const int &block_size [[buffer(11)]],                             \
+      device const int &x [[buffer(12)]],                                      \
+      uint2 gid [[threadgroup_position_in_grid]],                              \
+      uint tid [[thread_position_in_threadgroup]],                             \
+      uint threads_per_threadgroup [[threads_per_threadgroup]]);
+
 #define instantiate_reshape_and_cache(kv_type, cache_type)                     \
   template [[host_name("reshape_and_cache_kv_" #kv_type                        \
                        "_cache_" #cache_type)]] [[kernel]] void                \
@@ -125,3 +238,7 @@ instantiate_reshape_and_cache(half, half);
 instantiate_reshape_and_cache(float, uchar);
 instantiate_reshape_and_cache(bfloat16_t, uchar);
 instantiate_reshape_and_cache(half, uchar);
+
+instantiate_reshape_and_cache_int8(float);
+instantiate_reshape_and_cache_int8(bfloat16_t);
+instantiate_reshape_and_cache_int8(half);
@@ -333,6 +333,7 @@ class Which(Enum):
 class PagedCacheType(Enum):
     Auto: int = 0
     F8E4M3: int = 1
+    Int8: int = 2
 
 class Runner:
     def __init__(
@@ -384,21 +385,21 @@ class Runner:
         - `in_situ_quant` sets the optional in-situ quantization for a model.
         - `anymoe_config` specifies the AnyMoE config. If this is set, then the model will be loaded as an AnyMoE model.
         - `pa_gpu_mem`: GPU memory to allocate for KV cache with PagedAttention in MBs.
-            PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+            PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
             The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
         - `pa_gpu_mem_usage`: Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
             If this is not set and the device is CUDA, it will default to `0.9`.
-            PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+            PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
             The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
         - `pa_ctxt_len`: Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold).
-            PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+            PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
             The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
             This is the default setting, and it defaults to the `max-seq-len` specified in after the model type.
         - `pa_blk_size` sets the block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA,
-            it will default to 32. PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
-        - `pa_cache_type` sets the PagedAttention KV cache type (auto or f8e4m3). Defaults to `auto`.
-        - `no_paged_attn` disables PagedAttention on CUDA. Because PagedAttention is already disabled on Metal, this is only applicable on CUDA.
-        - `paged_attn` enables PagedAttention on Metal. Because PagedAttention is already enabled on CUDA, this is only applicable on Metal.
+            it will default to 32. PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
+        - `pa_cache_type` sets the PagedAttention KV cache type (auto, f8e4m3 or int8). Defaults to `auto`.
+        - `no_paged_attn` disables PagedAttention on CUDA and Metal.
+        - `paged_attn` enables PagedAttention. Because PagedAttention is already enabled on CUDA and Metal, this only overrides `no_paged_attn`.
         - `seed`, used to ensure reproducible random number generation.
         - `enable_search`: Enable searching compatible with the OpenAI `web_search_options` setting. This uses the BERT model specified below or the default.
         - `search_bert_model`: specify a Hugging Face model ID for a BERT model to assist web searching. Defaults to Snowflake Arctic Embed L.
@@ -594,12 +595,19 @@ class ChatCompletionChunkResponse:
     system_fingerprint: str
     object: str
 
+@dataclass
+class CompletionLogprobs:
+    tokens: list[str]
+    token_logprobs: list[float | None]
+    top_logprobs: list[dict[str, float] | None]
+    text_offset: list[int]
+
 @dataclass
 class CompletionChoice:
     finish_reason: str
     index: int
     text: str
-    # NOTE(EricLBuehler): `logprobs` in undocumented
+    logprobs: CompletionLogprobs | None
 
 @dataclass
 class CompletionResponse:
@@ -186,9 +186,13 @@ fn parse_which(
                 }),
                 imatrix,
                 calibration_file,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 hf_cache_path,
                 matformer_config_path,
                 matformer_slice_name,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             chat_template,
             tokenizer_json,
@@ -223,9 +227,13 @@ fn parse_which(
                 }),
                 imatrix: None,
                 calibration_file: None,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 hf_cache_path,
                 matformer_config_path: None,
                 matformer_slice_name: None,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             chat_template,
             tokenizer_json,
@@ -267,9 +275,13 @@ fn parse_which(
                 }),
                 imatrix: None,
                 calibration_file: None,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 hf_cache_path,
                 matformer_config_path: None,
                 matformer_slice_name: None,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             chat_template,
             tokenizer_json,
@@ -474,10 +486,14 @@ fn parse_which(
                 }),
                 max_edge,
                 calibration_file,
+                calibration_data: None,
+                mixed_precision_isq: None,
                 imatrix,
                 hf_cache_path,
                 matformer_config_path,
                 matformer_slice_name,
+                isq_skip: Vec::new(),
+                sliding_window: None,
             },
             chat_template,
             tokenizer_json,
@@ -520,6 +536,7 @@ fn build_constraint(grammar: Option<&str>, grammar_type: Option<&str>) -> PyApiR
     let constraint = match grammar_type.unwrap() {
         "regex" => Constraint::Regex(grammar.to_string()),
         "lark" => Constraint::Lark(grammar.to_string()),
+        "gbnf" => Constraint::Gbnf(grammar.to_string()),
         "json_schema" => {
             let value = serde_json::from_str::<serde_json::Value>(grammar)
                 .map_err(|e| PyApiErr::from(format!("Failed to parse JSON schema: {e}")))?;
@@ -531,8 +548,14 @@ fn build_constraint(grammar: Option<&str>, grammar_type: Option<&str>) -> PyApiR
             })?;
             Constraint::Llguidance(value)
         }
+        "choice" => {
+            let choices = serde_json::from_str::<Vec<String>>(grammar).map_err(|e| {
+                PyApiErr::from(format!("Failed to parse JSON list of choices: {e}"))
+            })?;
+            Constraint::Choice(choices)
+        }
         _ => return Err(PyApiErr::from(
-            "Grammar type is specified but is not `regex`, `lark`, `json_schema`, nor `llguidance`",
+            "Grammar type is specified but is not `regex`, `lark`, `gbnf`, `json_schema`, `llguidance`, nor `choice`",
         )),
     };
 
@@ -774,13 +797,12 @@ impl Runner {
             None => DeviceMapSetting::Auto(auto_map_params),
         };
 
-        let no_paged_attn = if device.is_cuda() || mistralrs_core::distributed::use_nccl() {
-            no_paged_attn
-        } else if device.is_metal() {
-            !paged_attn
-        } else {
-            true
-        };
+        let no_paged_attn =
+            if device.is_cuda() || device.is_metal() || mistralrs_core::distributed::use_nccl() {
+                no_paged_attn && !paged_attn
+            } else {
+                true
+            };
 
         let cache_config = match (
             pa_blk_size,
@@ -1180,7 +1202,18 @@ impl Runner {
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    typical_p: None,
                     dry_params,
+                    xtc_params: None,
+                    banned_strings: None,
+                    no_repeat_ngram_size: None,
+                    token_healing: false,
+                    guidance_scale: None,
+                    negative_prompt: None,
+                    penalty_alpha: None,
+                    seed: None,
+                    stop_regexes: None,
+                    include_stop_str_in_output: false,
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -1192,7 +1225,10 @@ impl Runner {
                 logits_processors: None,
                 return_raw_logits: false,
                 web_search_options: request.web_search_options.clone(),
+                adapters: None,
                 model_id: model_id.clone(),
+                tenant: None,
+                deadline: None,
             }));
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1291,7 +1327,18 @@ impl Runner {
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    typical_p: None,
                     dry_params,
+                    xtc_params: None,
+                    banned_strings: None,
+                    no_repeat_ngram_size: None,
+                    token_healing: false,
+                    guidance_scale: None,
+                    negative_prompt: None,
+                    penalty_alpha: None,
+                    seed: None,
+                    stop_regexes: None,
+                    include_stop_str_in_output: false,
                 },
                 response: tx,
                 return_logprobs: false,
@@ -1303,7 +1350,10 @@ impl Runner {
                 logits_processors: None,
                 return_raw_logits: false,
                 web_search_options: None,
+                adapters: None,
                 model_id: model_id.clone(),
+                tenant: None,
+                deadline: None,
             }));
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1364,7 +1414,10 @@ impl Runner {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            adapters: None,
             model_id: model_id.clone(),
+            tenant: None,
+            deadline: None,
         }));
 
         let sender = self.runner.get_sender(model_id.as_deref())?;
@@ -1407,7 +1460,10 @@ impl Runner {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            adapters: None,
             model_id: model_id.clone(),
+            tenant: None,
+            deadline: None,
         }));
 
         let sender = self.runner.get_sender(model_id.as_deref())?;
@@ -1791,7 +1847,18 @@ impl Runner {
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    typical_p: None,
                     dry_params,
+                    xtc_params: None,
+                    banned_strings: None,
+                    no_repeat_ngram_size: None,
+                    token_healing: false,
+                    guidance_scale: None,
+                    negative_prompt: None,
+                    penalty_alpha: None,
+                    seed: None,
+                    stop_regexes: None,
+                    include_stop_str_in_output: false,
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -1803,7 +1870,10 @@ impl Runner {
                 logits_processors: None,
                 return_raw_logits: false,
                 web_search_options: request.web_search_options.clone(),
+                adapters: None,
                 model_id: Some(model_id.clone()),
+                tenant: None,
+                deadline: None,
             }));
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1901,7 +1971,18 @@ impl Runner {
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    typical_p: None,
                     dry_params,
+                    xtc_params: None,
+                    banned_strings: None,
+                    no_repeat_ngram_size: None,
+                    token_healing: false,
+                    guidance_scale: None,
+                    negative_prompt: None,
+                    penalty_alpha: None,
+                    seed: None,
+                    stop_regexes: None,
+                    include_stop_str_in_output: false,
                 },
                 response: tx,
                 return_logprobs: false,
@@ -1913,7 +1994,10 @@ impl Runner {
                 logits_processors: None,
                 return_raw_logits: false,
                 web_search_options: None,
+                adapters: None,
                 model_id: Some(model_id.clone()),
+                tenant: None,
+                deadline: None,
             }));
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -2277,6 +2361,7 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::Usage>()?;
     m.add_class::<mistralrs_core::ChatCompletionResponse>()?;
     m.add_class::<mistralrs_core::ChatCompletionChunkResponse>()?;
+    m.add_class::<mistralrs_core::CompletionLogprobs>()?;
     m.add_class::<mistralrs_core::CompletionChoice>()?;
     m.add_class::<mistralrs_core::CompletionResponse>()?;
     m.add_class::<mistralrs_core::TopLogprob>()?;
@@ -6,14 +6,15 @@ use std::{
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::Linear;
 
 use crate::{
     utils::{
         deserialize_tensor, fake_deserialize_tensor, serialize_tensor, version_is_compatible,
         UQFF_VERSION,
     },
-    Comm, IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedConfig,
-    QuantizedSerde, QuantizedSerdeType, ShardedVarBuilder,
+    AfqModuleConfig, Comm, IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard,
+    QuantizedConfig, QuantizedSerde, QuantizedSerdeType, ShardedVarBuilder, UnquantLinear,
 };
 
 pub(crate) mod ops;
@@ -241,9 +242,29 @@ impl AfqLayer {
         bias: bool,
         vb: ShardedVarBuilder,
     ) -> Result<Arc<dyn QuantMethod>> {
-        let QuantizedConfig::Afq { bits, group_size } = config else {
+        let QuantizedConfig::Afq {
+            bits,
+            group_size,
+            module_overrides,
+        } = config
+        else {
             candle_core::bail!("Unexpected quantization config.")
         };
+        let (bits, group_size) = match module_overrides.get(&vb.prefix()) {
+            Some(Some(AfqModuleConfig { bits, group_size })) => (bits, group_size),
+            Some(None) => {
+                let weight = vb.get_with_hints((out_dim, in_dim), "weight", Default::default())?;
+                let bias = if bias {
+                    Some(vb.get((out_dim,), "bias")?)
+                } else {
+                    None
+                };
+                return Ok(Arc::new(UnquantLinear::new(
+                    QuantMethodConfig::Unquantized(Linear::new(weight, bias)),
+                )?));
+            }
+            None => (bits, group_size),
+        };
 
         let w_q = vb.get_with_hints_dtype(
             (out_dim, in_dim * bits / 32),
@@ -280,9 +301,21 @@ impl AfqLayer {
         bias: bool,
         vb: ShardedVarBuilder,
     ) -> Result<Arc<dyn QuantMethod>> {
-        let QuantizedConfig::Afq { bits, group_size } = config else {
+        let QuantizedConfig::Afq {
+            bits,
+            group_size,
+            module_overrides,
+        } = config
+        else {
             candle_core::bail!("Unexpected quantization config.")
         };
+        let (bits, group_size) = match module_overrides.get(&vb.prefix()) {
+            Some(Some(AfqModuleConfig { bits, group_size })) => (bits, group_size),
+            Some(None) => {
+                candle_core::bail!("Packed experts `{}` must be quantized.", vb.prefix())
+            }
+            None => (bits, group_size),
+        };
 
         let w_q = vb.get_with_hints_dtype(
             (num_local_experts, out_dim, in_dim * bits / 32),
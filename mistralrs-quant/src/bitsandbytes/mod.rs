@@ -4,10 +4,12 @@ use std::{
 };
 
 use candle_core::{Context, DType, Device, Result, Shape, Tensor};
+use candle_nn::Linear;
 use serde::Deserialize;
 
 use crate::{
-    IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde, ShardedVarBuilder,
+    DummyLayer, IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde,
+    ShardedVarBuilder, UnquantLinear,
 };
 
 #[cfg(feature = "cuda")]
@@ -75,21 +77,28 @@ pub struct BnbLinear {
 
 impl BnbLinear {
     pub fn linear_b(
-        _in_dim: usize,
+        in_dim: usize,
         out_dim: usize,
         bias: bool,
         vb: ShardedVarBuilder,
-    ) -> Result<Self> {
-        let weight = vb.get_unchecked_dtype("weight", DType::U8)?;
+    ) -> Result<Arc<dyn QuantMethod>> {
+        // Handle the case where the layer is dummy (no tensors)
+        if !vb.contains_tensor("weight") {
+            let layer = <DummyLayer as QuantMethod>::new(QuantMethodConfig::Dummy)?;
+            return Ok(Arc::new(layer) as Arc<dyn QuantMethod>);
+        }
 
         let vb_w = vb.pp("weight");
 
+        // Layers in `llm_int8_skip_modules`, such as the LM head, are saved unquantized
         if !vb_w.contains_tensor("quant_state.bitsandbytes__nf4")
             && !vb_w.contains_tensor("quant_state.bitsandbytes__fp4")
         {
-            candle_core::bail!("`BnbLinear` expects either `...__nf4` or `...__fp4` tensors, this means the layer is not 4bit.");
+            return crate::linear_b(in_dim, out_dim, bias, &None, vb);
         }
 
+        let weight = vb.get_unchecked_dtype("weight", DType::U8)?;
+
         let bias = if bias {
             Some(vb.get((out_dim,), "bias")?)
         } else {
@@ -114,7 +123,7 @@ impl BnbLinear {
             BnbQuantType::Int8 => None,
         };
         let Some(state) = state else {
-            candle_core::bail!("Only fp8/nf4 quantization is supported for now.")
+            candle_core::bail!("Only fp4/nf4 quantization is supported for now.")
         };
 
         let state_str = String::from_utf8(state.to_vec1::<u8>()?)?;
@@ -156,12 +165,22 @@ impl BnbLinear {
             dtype: state.dtype,
         };
 
-        Ok(Self {
+        Ok(Arc::new(Self {
             weight,
             bias,
             params,
             quant_ty,
-        })
+        }))
+    }
+
+    fn dequantize_linear(&self) -> Result<Linear> {
+        let weight = Self::dequantize(&self.weight, &self.params, self.quant_ty)?;
+        let bias = self
+            .bias
+            .as_ref()
+            .map(|b| b.to_dtype(weight.dtype()))
+            .transpose()?;
+        Ok(Linear::new(weight, bias))
     }
 
     /// Dequantize input (u8). Handles nested absmax dequantization.
@@ -248,8 +267,14 @@ impl QuantMethod for BnbLinear {
         None
     }
 
-    fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
-        candle_core::bail!("HQQ quantization does not support adding weight delta.")
+    fn add_delta_w(&self, delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        let lin = self.dequantize_linear()?;
+        Ok(Arc::new(<UnquantLinear as QuantMethod>::new(
+            QuantMethodConfig::Unquantized(Linear::new(
+                (lin.weight() + delta.to_dtype(lin.weight().dtype())?)?,
+                lin.bias().cloned(),
+            )),
+        )?))
     }
 
     fn dtype_and_device(&self) -> (DType, Device) {
@@ -258,13 +283,17 @@ impl QuantMethod for BnbLinear {
 
     fn apply_isq(
         self: Arc<Self>,
-        _dtype: Option<IsqType>,
-        _device: Device,
-        _n_quantized: &AtomicUsize,
-        _imatrix_weight: Option<Vec<f32>>,
-        _guard: QuantizeOntoGuard,
+        dtype: Option<IsqType>,
+        device: Device,
+        n_quantized: &AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
+        guard: QuantizeOntoGuard,
     ) -> Result<Arc<dyn QuantMethod>> {
-        todo!()
+        // Requantize from the dequantized weight, as with any unquantized layer
+        let unquant = <UnquantLinear as QuantMethod>::new(QuantMethodConfig::Unquantized(
+            self.dequantize_linear()?,
+        ))?;
+        Arc::new(unquant).apply_isq(dtype, device, n_quantized, imatrix_weight, guard)
     }
 }
 
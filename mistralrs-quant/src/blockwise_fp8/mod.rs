@@ -104,8 +104,7 @@ impl QuantMethod for BlockwiseFP8Linear {
             self.dequant_dtype,
         )?;
         match dtype {
-            /*Some(IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | */
-            Some(IsqType::HQQ4 | IsqType::HQQ8) => {
+            Some(IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | IsqType::HQQ4 | IsqType::HQQ8) => {
                 let _acquired_quantize_guard = guard.acquire(&device);
                 if imatrix_weight.is_some() {
                     // TODO just warn?
@@ -116,9 +115,9 @@ impl QuantMethod for BlockwiseFP8Linear {
                 let bits = match dtype.unwrap() {
                     IsqType::HQQ8 => HqqBits::Eight,
                     IsqType::HQQ4 => HqqBits::Four,
-                    // IsqType::HQQ3 => HqqBits::Three,
-                    // IsqType::HQQ2 => HqqBits::Two,
-                    // IsqType::HQQ1 => HqqBits::One,
+                    IsqType::HQQ3 => HqqBits::Three,
+                    IsqType::HQQ2 => HqqBits::Two,
+                    IsqType::HQQ1 => HqqBits::One,
                     _ => unreachable!(),
                 };
                 let cfg = HqqConfig {
@@ -191,6 +190,14 @@ impl QuantMethod for BlockwiseFP8Linear {
                         .map(|b| b.to_dtype(DType::F32).unwrap().to_device(&device).unwrap()),
                 })?))
             }
+            Some(IsqType::MARLIN4) => {
+                // Quantize from the dequantized weight, as with any unquantized layer
+                let unquant = UnquantLinear::new(QuantMethodConfig::Unquantized(Linear::new(
+                    weight,
+                    self.bias.clone(),
+                )))?;
+                Arc::new(unquant).apply_isq(dtype, device, n_quantized, imatrix_weight, guard)
+            }
             Some(IsqType::F8E4M3) => {
                 let _acquired_quantize_guard = guard.acquire(&device);
                 if imatrix_weight.is_some() {
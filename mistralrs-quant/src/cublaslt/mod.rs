@@ -79,6 +79,22 @@ pub fn maybe_init_cublas_lt_wrapper(device: Device) {
     });
 }
 
+/// Whether `device` has FP8 tensor cores, which the F8 cuBLASLt matmul requires. These are
+/// available from compute capability 8.9 (Ada and Hopper).
+pub fn supports_f8_matmul(device: &Device) -> bool {
+    #[cfg(feature = "cuda")]
+    if let Device::Cuda(dev) = device {
+        use candle_core::cuda::cudarc::driver::sys::CUdevice_attribute;
+
+        let stream = dev.cuda_stream();
+        let attribute = |attr| stream.context().attribute(attr).unwrap_or(0);
+        let major = attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR);
+        let minor = attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR);
+        return (major, minor) >= (8, 9);
+    }
+    false
+}
+
 #[derive(Debug, Clone)]
 pub struct CublasLtWrapper {
     #[cfg(feature = "cuda")]
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use candle_core::{Context, Device, IndexOp, Result, Tensor, D};
+use candle_core::{quantized::QTensor, Context, Device, IndexOp, Result, Tensor, D};
 use candle_nn::Linear;
 
 use crate::{
@@ -9,6 +9,7 @@ use crate::{
     gptq::gptq_linear,
     lora::merge_lora_weights,
     should_apply_immediate_isq,
+    torchao::torchao_linear_b,
     utils::isq::{apply_immediate_isq, apply_immediate_isq_always},
     AfqLayer, BnbLinear, DistributedKind, DummyLayer, FP8Linear, GgufMatMul, HqqLayer, MXFP4Layer,
     QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedConfig, QuantizedSerde,
@@ -62,6 +63,7 @@ impl RowParallelLayer {
                 QuantizedConfig::GptqAwq { .. }
                     | QuantizedConfig::Bitsandbytes { .. }
                     | QuantizedConfig::Afq { .. }
+                    | QuantizedConfig::Torchao { .. }
             ) && comm.world_size() != 1
             {
                 candle_core::bail!(
@@ -79,7 +81,7 @@ impl RowParallelLayer {
                     blockwise_fp8_linear_b(in_dim, out_dim, quant_conf, false, shard, vb.clone())?
                 }
                 QuantizedConfig::Bitsandbytes { .. } => {
-                    Arc::new(BnbLinear::linear_b(in_dim, out_dim, bias, vb.clone())?) as Arc<_>
+                    BnbLinear::linear_b(in_dim, out_dim, bias, vb.clone())?
                 }
                 QuantizedConfig::Afq { .. } => {
                     AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
@@ -87,6 +89,9 @@ impl RowParallelLayer {
                 QuantizedConfig::MXFP4 {} => {
                     MXFP4Layer::linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
                 }
+                QuantizedConfig::Torchao { .. } => {
+                    torchao_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
+                }
             }
         } else {
             // Handle the case where the layer is dummy (no tensors)
@@ -237,6 +242,10 @@ impl QuantMethod for RowParallelLayer {
         self.weight.unquant_weight_bias()
     }
 
+    fn ggml_weight(&self) -> Option<Arc<QTensor>> {
+        self.weight.ggml_weight()
+    }
+
     fn apply_isq(
         self: Arc<Self>,
         dtype: Option<crate::IsqType>,
@@ -339,6 +348,7 @@ impl ColumnParallelLayer {
                 QuantizedConfig::GptqAwq { .. }
                     | QuantizedConfig::Bitsandbytes { .. }
                     | QuantizedConfig::Afq { .. }
+                    | QuantizedConfig::Torchao { .. }
             ) && comm.world_size() != 1
             {
                 candle_core::bail!(
@@ -356,7 +366,7 @@ impl ColumnParallelLayer {
                     blockwise_fp8_linear_b(in_dim, out_dim, quant_conf, false, shard, vb.clone())?
                 }
                 QuantizedConfig::Bitsandbytes { .. } => {
-                    Arc::new(BnbLinear::linear_b(in_dim, out_dim, bias, vb.clone())?) as Arc<_>
+                    BnbLinear::linear_b(in_dim, out_dim, bias, vb.clone())?
                 }
                 QuantizedConfig::Afq { .. } => {
                     AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
@@ -364,6 +374,9 @@ impl ColumnParallelLayer {
                 QuantizedConfig::MXFP4 {} => {
                     MXFP4Layer::linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
                 }
+                QuantizedConfig::Torchao { .. } => {
+                    torchao_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
+                }
             }
         } else {
             // Handle the case where the layer is dummy (no tensors)
@@ -549,6 +562,10 @@ impl QuantMethod for ColumnParallelLayer {
         self.weight.unquant_weight_bias()
     }
 
+    fn ggml_weight(&self) -> Option<Arc<QTensor>> {
+        self.weight.ggml_weight()
+    }
+
     fn apply_isq(
         self: Arc<Self>,
         dtype: Option<crate::IsqType>,
@@ -651,7 +668,7 @@ impl ReplicatedLayer {
                     vb.clone(),
                 )?,
                 QuantizedConfig::Bitsandbytes { .. } => {
-                    Arc::new(BnbLinear::linear_b(in_dim, out_dim, bias, vb.clone())?) as Arc<_>
+                    BnbLinear::linear_b(in_dim, out_dim, bias, vb.clone())?
                 }
                 QuantizedConfig::Afq { .. } => {
                     AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
@@ -659,6 +676,9 @@ impl ReplicatedLayer {
                 QuantizedConfig::MXFP4 {} => {
                     MXFP4Layer::linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
                 }
+                QuantizedConfig::Torchao { .. } => {
+                    torchao_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
+                }
             }
         } else {
             // Handle the case where the layer is dummy (no tensors)
@@ -721,7 +741,7 @@ impl ReplicatedLayer {
                     vb.clone(),
                 )?,
                 QuantizedConfig::Bitsandbytes { .. } => {
-                    Arc::new(BnbLinear::linear_b(in_dim, out_dim, bias, vb.clone())?) as Arc<_>
+                    BnbLinear::linear_b(in_dim, out_dim, bias, vb.clone())?
                 }
                 QuantizedConfig::Afq { .. } => {
                     AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
@@ -729,6 +749,9 @@ impl ReplicatedLayer {
                 QuantizedConfig::MXFP4 {} => {
                     MXFP4Layer::linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
                 }
+                QuantizedConfig::Torchao { .. } => {
+                    torchao_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
+                }
             }
         } else {
             // Handle the case where the layer is dummy (no tensors)
@@ -814,6 +837,10 @@ impl QuantMethod for ReplicatedLayer {
         self.0.unquant_weight_bias()
     }
 
+    fn ggml_weight(&self) -> Option<Arc<QTensor>> {
+        self.0.ggml_weight()
+    }
+
     fn apply_isq(
         self: Arc<Self>,
         dtype: Option<crate::IsqType>,
@@ -12,12 +12,13 @@ use quantize::QuantizationResult;
 mod quantize;
 
 use crate::{
-    cublaslt::{maybe_init_cublas_lt_wrapper, CUBLASLT_CONTROLLER},
+    cublaslt::{maybe_init_cublas_lt_wrapper, supports_f8_matmul, CUBLASLT_CONTROLLER},
     utils::{
         deserialize_tensor, read_dtype, serialize_tensor, version_is_compatible, write_dtype,
         UQFF_VERSION,
     },
     IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde, QuantizedSerdeType,
+    UnquantLinear,
 };
 
 #[derive(Debug)]
@@ -28,6 +29,8 @@ pub struct FP8Linear {
     quant_scale: Tensor,
     /// Quantized type
     dtype: DType,
+    /// Whether the device can multiply in FP8 natively, otherwise the weight is dequantized.
+    f8_matmul: bool,
 }
 
 impl QuantMethod for FP8Linear {
@@ -57,6 +60,7 @@ impl QuantMethod for FP8Linear {
                     dequant_w_scale: dequantize_scale,
                     quant_scale: quantize_scale,
                     dtype,
+                    f8_matmul: supports_f8_matmul(lin.weight().device()),
                 })
             }
         }
@@ -70,19 +74,24 @@ impl QuantMethod for FP8Linear {
         maybe_init_cublas_lt_wrapper(x.device().clone());
 
         match CUBLASLT_CONTROLLER.get() {
-            Some(handle) => {
+            Some(handle) if self.f8_matmul && self.dtype == DType::F8E4M3 => {
                 let n_dims = x.dims().len();
-                if n_dims < 3 {
+                if n_dims < 2 {
                     candle_core::bail!(
-                        "FP8Linear `matmul` via cuBLASlt expects `x` to have at least 3 dimensions"
+                        "FP8Linear `matmul` via cuBLASlt expects `x` to have at least 2 dimensions"
                     );
                 }
                 // Set up target shape
                 let mut tgt_shape = x.dims().to_vec();
                 *tgt_shape.last_mut().unwrap() = self.lin.weight().dim(0)?;
+                let out_dtype = x.dtype();
 
                 // Flatten for correct dims
-                let mut x = x.flatten_to(D::Minus(3))?;
+                let mut x = if n_dims == 2 {
+                    x.unsqueeze(0)?
+                } else {
+                    x.flatten_to(D::Minus(3))?
+                };
 
                 // Prepare the b tensor. If it is not quantized, quantize it
                 let mut dequant_x_scale = self.dequant_x_scale.clone();
@@ -96,11 +105,12 @@ impl QuantMethod for FP8Linear {
                     dequant_x_scale = dequantize_scale;
                 }
 
-                // Handle bias
-                let beta = match self.lin.bias().is_some() {
-                    true => Some(1.0),
-                    false => None,
-                };
+                // The bias is fused into the matmul, which outputs bf16
+                let bias = self
+                    .lin
+                    .bias()
+                    .map(|b| b.to_dtype(DType::BF16))
+                    .transpose()?;
 
                 // Naming
                 let a = self.lin.weight().unsqueeze(0)?;
@@ -113,15 +123,16 @@ impl QuantMethod for FP8Linear {
                         &self.dequant_w_scale,
                         &dequant_x_scale,
                         &self.quant_scale,
-                        self.lin.bias(),
                         None,
-                        beta,
                         None,
                         None,
+                        bias.as_ref(),
+                        None,
                     )?
-                    .reshape(tgt_shape)
+                    .reshape(tgt_shape)?
+                    .to_dtype(out_dtype)
             }
-            None => {
+            _ => {
                 // Dequantize matmul
                 let dequant_x = x.clone();
                 let lin = self.dequantize(x.dtype())?;
@@ -149,13 +160,17 @@ impl QuantMethod for FP8Linear {
 
     fn apply_isq(
         self: Arc<Self>,
-        _dtype: Option<IsqType>,
-        _device: Device,
-        _n_quantized: &AtomicUsize,
-        _imatrix_weight: Option<Vec<f32>>,
-        _guard: QuantizeOntoGuard,
+        dtype: Option<IsqType>,
+        device: Device,
+        n_quantized: &AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
+        guard: QuantizeOntoGuard,
     ) -> Result<Arc<dyn QuantMethod>> {
-        todo!()
+        // Requantize from the dequantized weight, as with any unquantized layer
+        let unquant = <UnquantLinear as QuantMethod>::new(QuantMethodConfig::Unquantized(
+            self.dequantize(DType::BF16)?,
+        ))?;
+        Arc::new(unquant).apply_isq(dtype, device, n_quantized, imatrix_weight, guard)
     }
 }
 
@@ -267,6 +282,7 @@ impl QuantizedSerde for FP8Linear {
         };
 
         Ok(Arc::new(Self {
+            f8_matmul: supports_f8_matmul(w.device()),
             lin: Linear::new(w, b),
             dequant_w_scale,
             dequant_x_scale,
@@ -317,6 +333,7 @@ impl QuantizedSerde for FP8Linear {
 
         Ok((
             Arc::new(Self {
+                f8_matmul: supports_f8_matmul(w.device()),
                 lin: Linear::new(w, None),
                 dequant_w_scale,
                 dequant_x_scale,
@@ -115,6 +115,13 @@ impl QuantMethod for GgufMatMul {
         }
     }
 
+    fn ggml_weight(&self) -> Option<Arc<QTensor>> {
+        match &self.w {
+            QMatMul::QTensor(q) => Some(q.clone()),
+            QMatMul::Tensor(_) | QMatMul::TensorF16(_) => None,
+        }
+    }
+
     fn apply_isq(
         self: Arc<Self>,
         dtype: Option<IsqType>,
@@ -1,12 +1,71 @@
 use crate::{
     DummyLayer, IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedConfig,
-    QuantizedSerde, ShardedVarBuilder,
+    QuantizedSerde, ShardedVarBuilder, UnquantLinear,
 };
 use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Linear, Module};
 use std::sync::{atomic::AtomicUsize, Arc};
 
+/// AutoAWQ packs the 8 values of each `i32` in this order of the output columns.
+const AWQ_PACK_ORDER: [usize; 8] = [0, 2, 4, 6, 1, 3, 5, 7];
+
+/// Unpack 4 bit AWQ values, packed along the last dimension, into one `u8` per value.
+fn unpack_awq(packed: &Tensor) -> Result<Tensor> {
+    let (rows, cols) = packed.dims2()?;
+    let packed = packed
+        .to_device(&Device::Cpu)?
+        .flatten_all()?
+        .to_vec1::<i32>()?;
+    let mut values = vec![0u8; packed.len() * AWQ_PACK_ORDER.len()];
+    for (i, word) in packed.into_iter().enumerate() {
+        for (j, col) in AWQ_PACK_ORDER.into_iter().enumerate() {
+            values[i * AWQ_PACK_ORDER.len() + col] = ((word as u32 >> (4 * j)) & 0xF) as u8;
+        }
+    }
+    Tensor::from_vec(values, (rows, cols * AWQ_PACK_ORDER.len()), &Device::Cpu)
+}
+
+/// AWQ layer for devices without the GPTQ/Marlin kernels: the weight is kept at 4 bits, one
+/// value per byte, and is dequantized for each forward pass.
 #[derive(Debug)]
-pub struct GptqLayer;
+pub struct GptqLayer {
+    /// (in_dim, out_dim), u8
+    q_weight: Tensor,
+    /// (in_dim / group_size, out_dim)
+    scales: Tensor,
+    /// Zero points multiplied by the scales, (in_dim / group_size, out_dim)
+    scaled_zeros: Tensor,
+    bias: Option<Tensor>,
+}
+
+impl GptqLayer {
+    fn dequantize(&self, dtype: DType) -> Result<Tensor> {
+        let (groups, out_dim) = self.scales.dims2()?;
+        self.q_weight
+            .to_dtype(dtype)?
+            .reshape((groups, (), out_dim))?
+            .broadcast_mul(&self.scales.to_dtype(dtype)?.unsqueeze(1)?)?
+            .broadcast_sub(&self.scaled_zeros.to_dtype(dtype)?.unsqueeze(1)?)?
+            .reshape(((), out_dim))?
+            .t()
+    }
+
+    fn dequantize_linear(&self, dtype: DType) -> Result<Linear> {
+        let bias = self.bias.as_ref().map(|b| b.to_dtype(dtype)).transpose()?;
+        Ok(Linear::new(self.dequantize(dtype)?, bias))
+    }
+}
+
+impl GptqLayer {
+    /// The Marlin kernels require CUDA.
+    pub(crate) fn marlin4_supports_shape(_out_dim: usize, _in_dim: usize) -> bool {
+        false
+    }
+
+    pub(crate) fn quantize_marlin4(_weight: &Tensor, _bias: Option<Tensor>) -> Result<Self> {
+        candle_core::bail!("Marlin quantization requires CUDA.")
+    }
+}
 
 impl QuantMethod for GptqLayer {
     fn new(method: QuantMethodConfig) -> Result<Self>
@@ -14,8 +73,37 @@ impl QuantMethod for GptqLayer {
         Self: Sized,
     {
         match method {
-            QuantMethodConfig::GptqAwq { .. } => {
-                candle_core::bail!("GPTQ is only supported on CUDA.")
+            QuantMethodConfig::GptqAwq {
+                bits,
+                q_weight,
+                qzeros,
+                scales,
+                bias,
+                is_awq,
+                ..
+            } => {
+                if !is_awq {
+                    candle_core::bail!("GPTQ is only supported on CUDA.")
+                }
+                if bits != 4 {
+                    candle_core::bail!(
+                        "AWQ is only supported in 4 bits on this device, got {bits} bits."
+                    )
+                }
+                let Some(qzeros) = qzeros else {
+                    candle_core::bail!("AWQ requires `qzeros`.")
+                };
+                let device = q_weight.device().clone();
+                let scaled_zeros = unpack_awq(&qzeros)?
+                    .to_device(&device)?
+                    .to_dtype(scales.dtype())?
+                    .mul(&scales)?;
+                Ok(Self {
+                    q_weight: unpack_awq(&q_weight)?.to_device(&device)?,
+                    scales,
+                    scaled_zeros,
+                    bias,
+                })
             }
             QuantMethodConfig::Gguf { .. }
             | QuantMethodConfig::Unquantized(_)
@@ -32,34 +120,44 @@ impl QuantMethod for GptqLayer {
     }
 
     fn dequantize_w(&self) -> Result<Tensor> {
-        todo!()
+        self.dequantize(self.scales.dtype())
     }
 
-    fn forward(&self, _a: &Tensor) -> Result<Tensor> {
-        todo!()
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        self.dequantize_linear(a.dtype())?.forward(a)
     }
 
     fn quantized_act_type(&self) -> Option<DType> {
-        todo!()
+        None
     }
 
-    fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
-        todo!()
+    fn add_delta_w(&self, delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        let lin = self.dequantize_linear(delta.dtype())?;
+        Ok(Arc::new(<UnquantLinear as QuantMethod>::new(
+            QuantMethodConfig::Unquantized(Linear::new(
+                (lin.weight() + delta)?,
+                lin.bias().cloned(),
+            )),
+        )?))
     }
 
     fn dtype_and_device(&self) -> (DType, candle_core::Device) {
-        todo!()
+        (self.scales.dtype(), self.scales.device().clone())
     }
 
     fn apply_isq(
         self: Arc<Self>,
-        _dtype: Option<IsqType>,
-        _device: Device,
-        _n_quantized: &AtomicUsize,
-        _imatrix_weight: Option<Vec<f32>>,
-        _guard: QuantizeOntoGuard,
+        dtype: Option<IsqType>,
+        device: Device,
+        n_quantized: &AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
+        guard: QuantizeOntoGuard,
     ) -> Result<Arc<dyn QuantMethod>> {
-        todo!()
+        // Requantize from the dequantized weight, as with any unquantized layer
+        let unquant = <UnquantLinear as QuantMethod>::new(QuantMethodConfig::Unquantized(
+            self.dequantize_linear(self.scales.dtype())?,
+        ))?;
+        Arc::new(unquant).apply_isq(dtype, device, n_quantized, imatrix_weight, guard)
     }
 }
 
@@ -116,7 +214,7 @@ pub fn gptq_linear(
     };
 
     let qweight = vb.get_with_hints_dtype(qw_shape, "qweight", Default::default(), DType::I32)?;
-    let scale_and_zero_size = in_dim / group_size;
+    let scale_and_zero_size = group_size.map_or(1, |group_size| in_dim.div_ceil(group_size));
     let qzeros = vb.get_with_hints_dtype(
         (scale_and_zero_size, out_dim / pack_factor!(bits)),
         "qzeros",
@@ -132,10 +230,10 @@ pub fn gptq_linear(
         (scale_and_zero_size, out_dim),
         "scales",
         Default::default(),
-        DType::F16,
+        vb.dtype(),
     )?;
     let bias = if vb.contains_tensor("bias") {
-        Some(vb.get_with_hints_dtype((out_dim,), "bias", Default::default(), DType::F16)?)
+        Some(vb.get_with_hints_dtype((out_dim,), "bias", Default::default(), vb.dtype())?)
     } else {
         None
     };
@@ -147,6 +245,7 @@ pub fn gptq_linear(
         qzeros: Some(qzeros),
         scales,
         g_idx,
+        perm: None,
         bias,
         workspace: None,
         is_marlin: false,
@@ -154,3 +253,23 @@ pub fn gptq_linear(
     };
     Ok(Arc::new(GptqLayer::new(config)?))
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Result, Tensor};
+
+    use super::unpack_awq;
+
+    #[test]
+    fn test_unpack_awq() -> Result<()> {
+        // Columns 0..8 hold the values 0..8, packed in AutoAWQ's order.
+        let word = [0u32, 2, 4, 6, 1, 3, 5, 7]
+            .iter()
+            .enumerate()
+            .fold(0u32, |word, (i, v)| word | (v << (4 * i)));
+        let packed = Tensor::new(&[[word as i32]], &Device::Cpu)?;
+        let values = unpack_awq(&packed)?.to_vec2::<u8>()?;
+        assert_eq!(values, vec![(0..8).collect::<Vec<u8>>()]);
+        Ok(())
+    }
+}
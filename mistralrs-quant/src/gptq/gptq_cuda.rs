@@ -63,6 +63,7 @@ pub struct GptqLayer {
     scales: Tensor,         // f16
     bias: Option<Tensor>,   // f16
     g_idx: Option<Tensor>,  // i32
+    perm: Option<Tensor>,   // u32
     bits: i32,
     use_exllama: bool,
     workspace: Option<Tensor>,
@@ -301,6 +302,7 @@ impl QuantMethod for GptqLayer {
                 qzeros,
                 scales,
                 g_idx,
+                perm,
                 bias,
                 workspace,
                 is_marlin,
@@ -320,6 +322,7 @@ impl QuantMethod for GptqLayer {
                     qzeros,
                     scales,
                     g_idx,
+                    perm,
                     bits,
                     use_exllama,
                     bias,
@@ -372,7 +375,11 @@ impl QuantMethod for GptqLayer {
                 )?
                 .reshape(out_shape)?,
             (_, _, true) => marlin_matmul(
-                a,
+                // The weight rows are sorted by group for act-order checkpoints.
+                &match &self.perm {
+                    Some(perm) => a.index_select(perm, D::Minus1)?,
+                    None => a.clone(),
+                },
                 &self.q_weight,
                 &self.scales,
                 &self.qzeros,
@@ -426,6 +433,111 @@ macro_rules! pack_factor {
     };
 }
 
+fn get_scale_perms() -> (Vec<u32>, Vec<u32>) {
+    let mut scale_perm: Vec<u32> = Vec::new();
+    for i in 0..8 {
+        scale_perm.extend((0..8).map(|j| i + 8 * j));
+    }
+    let mut scale_perm_single: Vec<u32> = Vec::new();
+    for i in 0..4 {
+        scale_perm_single.extend([0, 1, 8, 9, 16, 17, 24, 25].iter().map(|&j| 2 * i + j));
+    }
+    (scale_perm, scale_perm_single)
+}
+
+fn marlin_permute_scales(
+    s: &Tensor,
+    size_k: usize,
+    size_n: usize,
+    group_size: i32,
+    _num_bits: u32,
+) -> Result<Tensor> {
+    let (scale_perm, scale_perm_single) = get_scale_perms();
+    let s = if (group_size as usize) < size_k && group_size != -1 {
+        let s = s.reshape(((), scale_perm.len()))?;
+        let scale_perm_tensor = Tensor::from_slice(&scale_perm, scale_perm.len(), s.device())?;
+        s.index_select(&scale_perm_tensor, 1)?
+    } else {
+        let s = s.reshape(((), scale_perm_single.len()))?;
+        let scale_perm_single_tensor =
+            Tensor::from_slice(&scale_perm_single, scale_perm_single.len(), s.device())?;
+        s.index_select(&scale_perm_single_tensor, 1)?
+    };
+
+    let s = s.reshape(((), size_n))?.contiguous()?;
+    Ok(s)
+}
+
+/// The group size of weights quantized with [`IsqType::MARLIN4`].
+const MARLIN4_GROUP_SIZE: usize = 128;
+
+impl GptqLayer {
+    /// If the Marlin kernels can run a weight of this shape, quantized with [`IsqType::MARLIN4`].
+    pub(crate) fn marlin4_supports_shape(out_dim: usize, in_dim: usize) -> bool {
+        HAVE_MARLIN_KERNELS && in_dim % MARLIN4_GROUP_SIZE == 0 && out_dim % 64 == 0
+    }
+
+    /// Quantize `weight` to 4 bits with symmetric groups of 128 input channels, in the GPTQ format
+    /// of the Marlin kernels.
+    pub(crate) fn quantize_marlin4(weight: &Tensor, bias: Option<Tensor>) -> Result<Self> {
+        let device = weight.device();
+        let (out_dim, in_dim) = weight.dims2()?;
+        if !Self::marlin4_supports_shape(out_dim, in_dim) {
+            candle_core::bail!("Marlin cannot quantize a weight of shape ({out_dim}, {in_dim}).");
+        }
+
+        // (groups, group size, out_dim)
+        let w = weight.to_dtype(DType::F32)?.t()?.reshape((
+            in_dim / MARLIN4_GROUP_SIZE,
+            MARLIN4_GROUP_SIZE,
+            out_dim,
+        ))?;
+        let scales = (w.abs()?.max_keepdim(1)? / 7.)?.maximum(1e-8)?;
+        // The zero point is 8
+        let q = (w.broadcast_div(&scales)?.round()? + 8.)?
+            .clamp(0f32, 15f32)?
+            .to_dtype(DType::U8)?
+            .flatten_all()?
+            .to_vec1::<u8>()?;
+
+        // GPTQ packs 8 consecutive input channels into each i32
+        let mut q_weight = vec![0i32; in_dim / pack_factor!(4) * out_dim];
+        for (i, value) in q.into_iter().enumerate() {
+            let (row, col) = (i / out_dim, i % out_dim);
+            q_weight[row / pack_factor!(4) * out_dim + col] |=
+                (value as i32) << (4 * (row % pack_factor!(4)));
+        }
+        let q_weight = Tensor::from_vec(q_weight, (in_dim / pack_factor!(4), out_dim), device)?;
+        let perm = Tensor::arange(0u32, in_dim as u32, device)?;
+        let q_weight = marlin_weight_repack(&q_weight, &Some(perm), in_dim, 4, false)?;
+        let scales = marlin_permute_scales(
+            &scales.squeeze(1)?.to_dtype(DType::F16)?,
+            in_dim,
+            out_dim,
+            MARLIN4_GROUP_SIZE as i32,
+            4,
+        )?;
+
+        Ok(Self {
+            q_weight,
+            qzeros: None,
+            scales,
+            bias: bias.map(|b| b.to_dtype(DType::F16)).transpose()?,
+            g_idx: None,
+            perm: None,
+            bits: 4,
+            use_exllama: false,
+            workspace: Some(Tensor::zeros(
+                out_dim / pack_factor!(4),
+                DType::U32,
+                device,
+            )?),
+            is_marlin: true,
+            is_awq: false,
+        })
+    }
+}
+
 pub fn gptq_linear(
     in_dim: usize,
     out_dim: usize,
@@ -453,7 +565,12 @@ pub fn gptq_linear(
         return Ok(Arc::new(layer) as Arc<dyn QuantMethod>);
     }
 
-    let marlin_compatible = *bits == 4 || *bits == 8;
+    // Marlin has kernels for groups of 64 and 128, and for one group per output channel.
+    let marlin_compatible = (*bits == 4 || *bits == 8)
+        && match group_size {
+            Some(group_size) => matches!(group_size, 64 | 128) && in_dim % group_size == 0,
+            None => true,
+        };
     let marlin_format = checkpoint_format
         .as_ref()
         .is_some_and(|fmt| fmt == "marlin")
@@ -479,7 +596,7 @@ pub fn gptq_linear(
         Default::default(),
         DType::I32,
     )?;
-    let scale_and_zero_size = in_dim / group_size;
+    let scale_and_zero_size = group_size.map_or(1, |group_size| in_dim.div_ceil(group_size));
     let scales = vb.get_with_hints_dtype(
         (scale_and_zero_size, out_dim),
         if marlin_format { "s" } else { "scales" },
@@ -501,48 +618,13 @@ pub fn gptq_linear(
             qzeros: None,
             scales,
             g_idx: None,
+            perm: None,
             bias,
             workspace: Some(workspace),
             is_marlin: true,
             is_awq,
         }
     } else {
-        fn get_scale_perms() -> (Vec<u32>, Vec<u32>) {
-            let mut scale_perm: Vec<u32> = Vec::new();
-            for i in 0..8 {
-                scale_perm.extend((0..8).map(|j| i + 8 * j));
-            }
-            let mut scale_perm_single: Vec<u32> = Vec::new();
-            for i in 0..4 {
-                scale_perm_single.extend([0, 1, 8, 9, 16, 17, 24, 25].iter().map(|&j| 2 * i + j));
-            }
-            (scale_perm, scale_perm_single)
-        }
-
-        fn marlin_permute_scales(
-            s: &Tensor,
-            size_k: usize,
-            size_n: usize,
-            group_size: i32,
-            _num_bits: u32,
-        ) -> Result<Tensor> {
-            let (scale_perm, scale_perm_single) = get_scale_perms();
-            let s = if (group_size as usize) < size_k && group_size != -1 {
-                let s = s.reshape(((), scale_perm.len()))?;
-                let scale_perm_tensor =
-                    Tensor::from_slice(&scale_perm, scale_perm.len(), s.device())?;
-                s.index_select(&scale_perm_tensor, 1)?
-            } else {
-                let s = s.reshape(((), scale_perm_single.len()))?;
-                let scale_perm_single_tensor =
-                    Tensor::from_slice(&scale_perm_single, scale_perm_single.len(), s.device())?;
-                s.index_select(&scale_perm_single_tensor, 1)?
-            };
-
-            let s = s.reshape(((), size_n))?.contiguous()?;
-            Ok(s)
-        }
-
         let qzeros = vb.get_with_hints_dtype(
             (scale_and_zero_size, out_dim / pack_factor!(bits)),
             "qzeros",
@@ -550,31 +632,41 @@ pub fn gptq_linear(
             DType::I32,
         )?;
 
-        let (g_idx, perm) = if is_awq {
+        let (g_idx, act_order_perm) = if is_awq {
             (None, None)
         } else {
             let g_idx =
                 vb.get_with_hints_dtype((in_dim,), "g_idx", Default::default(), DType::I32)?;
-            let perm = g_idx
-                .to_device(&Device::Cpu)?
-                .arg_sort_last_dim(true)?
-                .to_device(g_idx.device())?;
-            (Some(g_idx), Some(perm))
+            let act_order_perm =
+                super::act_order_perm(&g_idx.to_vec1::<i32>()?, group_size.unwrap_or(in_dim));
+            (Some(g_idx), act_order_perm)
         };
 
-        // Repack to marlin format
+        // Repack to marlin format, with the rows sorted by group for act-order checkpoints
         let qweight = if marlin_compatible {
+            let perm = if is_awq {
+                None
+            } else {
+                let perm = act_order_perm
+                    .clone()
+                    .unwrap_or_else(|| (0..in_dim as u32).collect());
+                Some(Tensor::from_vec(perm, in_dim, vb.device())?)
+            };
             marlin_weight_repack(&qweight, &perm, in_dim, *bits as i32, is_awq)?
         } else {
             qweight
         };
+        let perm = match act_order_perm {
+            Some(perm) if marlin_compatible => Some(Tensor::from_vec(perm, in_dim, vb.device())?),
+            _ => None,
+        };
 
         let scales = if marlin_compatible {
             marlin_permute_scales(
                 &scales,
-                in_dim / pack_factor!(bits),
+                in_dim,
                 out_dim,
-                *group_size as i32,
+                group_size.map_or(-1, |group_size| group_size as i32),
                 *bits as u32,
             )?
         } else {
@@ -593,6 +685,7 @@ pub fn gptq_linear(
             qzeros: Some(qzeros),
             scales,
             g_idx,
+            perm,
             bias,
             workspace,
             is_marlin: marlin_compatible,
@@ -13,3 +13,33 @@ mod marlin_ffi;
 pub use gptq_cpu::{gptq_linear, GptqLayer};
 #[cfg(feature = "cuda")]
 pub use gptq_cuda::{gptq_linear, GptqLayer};
+
+/// The order which sorts the input channels by group for a checkpoint quantized with act-order
+/// (`desc_act`), where `g_idx` does not list the groups in order. `None` if it does.
+#[cfg_attr(not(feature = "cuda"), allow(dead_code))]
+pub(crate) fn act_order_perm(g_idx: &[i32], group_size: usize) -> Option<Vec<u32>> {
+    let in_order = g_idx
+        .iter()
+        .enumerate()
+        .all(|(i, &group)| group as usize == i / group_size);
+    if in_order {
+        return None;
+    }
+    let mut perm = (0..g_idx.len() as u32).collect::<Vec<_>>();
+    perm.sort_by_key(|&i| g_idx[i as usize]);
+    Some(perm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::act_order_perm;
+
+    #[test]
+    fn test_act_order_perm() {
+        assert_eq!(act_order_perm(&[0, 0, 1, 1], 2), None);
+        // One group per output channel
+        assert_eq!(act_order_perm(&[0, 0, 0, 0], 4), None);
+        // The channels of each group stay in their original order.
+        assert_eq!(act_order_perm(&[1, 0, 1, 0], 2), Some(vec![1, 3, 0, 2]));
+    }
+}
@@ -618,9 +618,11 @@ impl HqqLayer {
                 .w_q
                 .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant4Bit { h, w })?
                 .reshape(&self.w_shape),
+            // The packed rows are padded to a multiple of 10.
             3 => self
                 .w_q
                 .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant3Bit { h, w })?
+                .narrow(0, 0, self.cfg.group_size.into())?
                 .reshape(&self.w_shape),
             2 => self
                 .w_q
@@ -960,9 +962,9 @@ impl QuantMethod for HqqLayer {
         let bits = match dtype {
             Some(IsqType::HQQ8) => HqqBits::Eight,
             Some(IsqType::HQQ4) => HqqBits::Four,
-            // Some(IsqType::HQQ3) => HqqBits::Three,
-            // Some(IsqType::HQQ2) => HqqBits::Two,
-            // Some(IsqType::HQQ1) => HqqBits::One,
+            Some(IsqType::HQQ3) => HqqBits::Three,
+            Some(IsqType::HQQ2) => HqqBits::Two,
+            Some(IsqType::HQQ1) => HqqBits::One,
             _ => candle_core::bail!("Expected a HQQ ISQ type."),
         };
         let cfg = HqqConfig {
@@ -1288,9 +1290,9 @@ impl HqqLayer {
         match bits {
             HqqBits::Eight => Ok(IsqType::HQQ8),
             HqqBits::Four => Ok(IsqType::HQQ4),
-            HqqBits::One | HqqBits::Two | HqqBits::Three => {
-                candle_core::bail!("cannot convert hqq bits to isq type")
-            }
+            HqqBits::Three => Ok(IsqType::HQQ3),
+            HqqBits::Two => Ok(IsqType::HQQ2),
+            HqqBits::One => Ok(IsqType::HQQ1),
         }
     }
 }
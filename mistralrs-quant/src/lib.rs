@@ -1,5 +1,6 @@
 use std::{
     borrow::Cow,
+    collections::HashMap,
     fmt::Debug,
     num::NonZeroUsize,
     sync::{atomic::AtomicUsize, Arc, Mutex, MutexGuard},
@@ -30,14 +31,15 @@ mod mxfp4;
 pub mod rotary;
 pub mod safetensors;
 mod scalar_fp8;
+mod torchao;
 mod unquantized;
 mod utils;
 mod vector_fp8;
 
 use gptq::gptq_linear;
-use lora::merge_lora_weights;
 use regex::Regex;
 pub use safetensors::{Shard, ShardedSafeTensors, ShardedVarBuilder};
+use torchao::torchao_linear_b;
 
 pub use afq::{AfqBits, AfqGroupSize, AfqLayer};
 pub use bitsandbytes::{BnbLinear, BnbQuantParams, BnbQuantType};
@@ -57,13 +59,18 @@ pub use gptq::GptqLayer;
 pub use hqq::{HqqAxis, HqqBits, HqqConfig, HqqLayer};
 pub use imatrix::{CollectedImatrixData, ImatrixLayerStats};
 pub use lora::{
-    clear_applied_loras, get_applied_loras, linear_no_bias_static_lora, push_applied_lora,
-    LoraAdapter, LoraConfig, StaticLoraConfig, MULTI_LORA_DELIMITER,
+    apply_modules_to_save, clear_applied_loras, get_applied_loras, linear_no_bias_static_lora,
+    lora_delta_weight, lora_weights, merge_lora_weights, push_applied_lora, set_lora_batch,
+    AppliedLorasGuard, BatchedLoraLinear, LoraAdapter, LoraAdapterSelection, LoraConfig,
+    StaticLoraConfig, MULTI_LORA_DELIMITER,
 };
 pub use mxfp4::MXFP4Layer;
 pub use unquantized::UnquantLinear;
 pub use utils::isq::apply_immediate_isq;
-pub use utils::{log, BitWiseOp, CumSumOp, LeftshiftOp, NonZeroOp, SortOp, UQFF_QUANT_TYPE_OFFSET};
+pub use utils::{
+    log, read_uqff_layer_header, BitWiseOp, CumSumOp, LeftshiftOp, NonZeroOp, SortOp,
+    UqffLayerHeader, UQFF_QUANT_TYPE_OFFSET,
+};
 pub use vector_fp8::{fp8_vector_dequantize, fp8_vector_quantize};
 
 use candle_nn::{Conv1d, Conv2d, Linear, Module};
@@ -74,18 +81,21 @@ pub struct ImmediateIsqParams {
     pub guard: QuantizeOntoGuard,
     pub ty: Option<IsqType>,
     pub predicates: Vec<Regex>,
+    /// Layers matching any of these are not quantized, even if they match `predicates`.
+    pub skip: Vec<Regex>,
 }
 
 thread_local! {
     static ENGINE_IMMEDIATE_ISQ: std::cell::RefCell<Option<ImmediateIsqParams>> = const { std::cell::RefCell::new(None) } ;
 }
 
-pub fn set_immediate_isq(isq: Option<IsqType>, predicates: Vec<Regex>) {
+pub fn set_immediate_isq(isq: Option<IsqType>, predicates: Vec<Regex>, skip: Vec<Regex>) {
     ENGINE_IMMEDIATE_ISQ.with(|cell| {
         *cell.borrow_mut() = Some(ImmediateIsqParams {
             guard: QuantizeOntoGuard::new(),
             ty: isq,
             predicates,
+            skip,
         });
     });
 }
@@ -111,6 +121,7 @@ pub fn should_apply_immediate_isq(vb: &ShardedVarBuilder) -> bool {
             .predicates
             .iter()
             .any(|predicate| predicate.is_match(&prefix))
+        && !immediate_isq.skip.iter().any(|skip| skip.is_match(&prefix))
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -118,7 +129,8 @@ pub fn should_apply_immediate_isq(vb: &ShardedVarBuilder) -> bool {
 pub enum QuantizedConfig {
     GptqAwq {
         bits: usize,
-        group_size: usize,
+        /// `None` if each output channel is a single group, which is `group_size: -1`.
+        group_size: Option<usize>,
         checkpoint_format: Option<String>,
         is_awq: bool,
     },
@@ -131,8 +143,23 @@ pub enum QuantizedConfig {
     Afq {
         bits: usize,
         group_size: usize,
+        /// Per module settings of an MLX checkpoint, keyed by module name. `None` means that the
+        /// module is not quantized.
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        module_overrides: HashMap<String, Option<AfqModuleConfig>>,
     },
     MXFP4 {},
+    Torchao {
+        /// 4 for int4 checkpoints, otherwise 8.
+        bits: usize,
+    },
+}
+
+/// The quantization of a single module in an MLX checkpoint.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct AfqModuleConfig {
+    pub bits: usize,
+    pub group_size: usize,
 }
 
 // Common fields for all variants
@@ -140,10 +167,39 @@ pub enum QuantizedConfig {
 struct RawConfig {
     quant_method: Option<String>,
     bits: Option<usize>,
-    group_size: Option<usize>,
+    // GPTQ uses `-1` for one group per output channel.
+    group_size: Option<isize>,
     checkpoint_format: Option<String>,
     weight_block_size: Option<Vec<usize>>,
     bnb_4bit_quant_type: Option<String>,
+    // MLX lists modules with a different quantization, or `false` if not quantized, by name.
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+impl RawConfig {
+    fn group_size<E: serde::de::Error>(&self) -> std::result::Result<usize, E> {
+        let group_size = self
+            .group_size
+            .ok_or_else(|| E::missing_field("group_size"))?;
+        usize::try_from(group_size)
+            .map_err(|_| E::custom(format!("Unexpected group size {group_size}")))
+    }
+
+    fn afq_module_overrides(&self) -> HashMap<String, Option<AfqModuleConfig>> {
+        self.other
+            .iter()
+            .filter_map(|(name, value)| match value {
+                serde_json::Value::Bool(false) => Some((name.clone(), None)),
+                serde_json::Value::Object(_) => {
+                    serde_json::from_value::<AfqModuleConfig>(value.clone())
+                        .ok()
+                        .map(|config| (name.clone(), Some(config)))
+                }
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 // Custom deserializer implementation
@@ -159,9 +215,10 @@ impl<'de> Deserialize<'de> for QuantizedConfig {
                 let bits = raw
                     .bits
                     .ok_or_else(|| serde::de::Error::missing_field("bits"))?;
-                let group_size = raw
-                    .group_size
-                    .ok_or_else(|| serde::de::Error::missing_field("group_size"))?;
+                let group_size = match raw.group_size {
+                    Some(-1) => None,
+                    _ => Some(raw.group_size()?),
+                };
                 Ok(QuantizedConfig::GptqAwq {
                     bits,
                     group_size,
@@ -182,10 +239,22 @@ impl<'de> Deserialize<'de> for QuantizedConfig {
                 let bits = raw
                     .bits
                     .ok_or_else(|| serde::de::Error::missing_field("bits"))?;
-                let group_size = raw
-                    .group_size
-                    .ok_or_else(|| serde::de::Error::missing_field("group_size"))?;
-                Ok(QuantizedConfig::Afq { bits, group_size })
+                let group_size = raw.group_size()?;
+                Ok(QuantizedConfig::Afq {
+                    bits,
+                    group_size,
+                    module_overrides: raw.afq_module_overrides(),
+                })
+            }
+            Some(m) if m == "torchao" => {
+                // For example `{"default": {"_type": "IntxWeightOnlyConfig", "_data": {"weight_dtype": ...}}}`
+                let quant_type = raw
+                    .other
+                    .get("quant_type")
+                    .map(|quant_type| quant_type.to_string().to_lowercase())
+                    .unwrap_or_default();
+                let bits = if quant_type.contains("int4") { 4 } else { 8 };
+                Ok(QuantizedConfig::Torchao { bits })
             }
             Some(m) if m == "mxfp4" => {
                 Ok(QuantizedConfig::MXFP4 {  })
@@ -194,14 +263,16 @@ impl<'de> Deserialize<'de> for QuantizedConfig {
                 let bits = raw
                     .bits
                     .ok_or_else(|| serde::de::Error::missing_field("bits"))?;
-                let group_size = raw
-                    .group_size
-                    .ok_or_else(|| serde::de::Error::missing_field("group_size"))?;
-                Ok(QuantizedConfig::Afq { bits, group_size })
+                let group_size = raw.group_size()?;
+                Ok(QuantizedConfig::Afq {
+                    bits,
+                    group_size,
+                    module_overrides: raw.afq_module_overrides(),
+                })
             }
             Some(unknown_method) => {
                 Err(serde::de::Error::custom(format!(
-                    "Unknown quantization method: {unknown_method}. Expected one of: gptq, fp8, bitsandbytes, afq, or not specified"
+                    "Unknown quantization method: {unknown_method}. Expected one of: gptq, fp8, bitsandbytes, afq, torchao, or not specified"
                 )))
             },
         }
@@ -216,6 +287,7 @@ impl QuantizedConfig {
             Self::Bitsandbytes { .. } => "bitsandbytes",
             Self::Afq { .. } => "afq",
             Self::MXFP4 { .. } => "mxfp4",
+            Self::Torchao { .. } => "torchao",
         }
     }
 
@@ -229,23 +301,25 @@ impl QuantizedConfig {
             Self::Bitsandbytes {
                 bnb_4bit_quant_type: None,
             } => "8 bits".to_string(),
-            Self::Afq { bits, .. } => format!("{bits} bits"),
+            Self::Afq { bits, .. } | Self::Torchao { bits } => format!("{bits} bits"),
             Self::MXFP4 {} => format!("{} bits", mxfp4::N_BITS),
         }
     }
 
     pub fn pack_factor(&self, dtype: DType) -> usize {
         match self {
-            Self::GptqAwq { bits, .. } | Self::Afq { bits, .. } => match bits {
-                2 => IsqType::Q2K.pack_factor(dtype),
-                3 => IsqType::Q3K.pack_factor(dtype),
-                4 => IsqType::Q4K.pack_factor(dtype),
-                5 => IsqType::Q5K.pack_factor(dtype),
-                6 => IsqType::Q6K.pack_factor(dtype),
-                8 => IsqType::Q8_0.pack_factor(dtype),
-                40 => 4, // mxfp4: 2 FP4 values per byte = factor of 4
-                other => panic!("Unexpected bits in `pack_factor` {other}"),
-            },
+            Self::GptqAwq { bits, .. } | Self::Afq { bits, .. } | Self::Torchao { bits } => {
+                match bits {
+                    2 => IsqType::Q2K.pack_factor(dtype),
+                    3 => IsqType::Q3K.pack_factor(dtype),
+                    4 => IsqType::Q4K.pack_factor(dtype),
+                    5 => IsqType::Q5K.pack_factor(dtype),
+                    6 => IsqType::Q6K.pack_factor(dtype),
+                    8 => IsqType::Q8_0.pack_factor(dtype),
+                    40 => 4, // mxfp4: 2 FP4 values per byte = factor of 4
+                    other => panic!("Unexpected bits in `pack_factor` {other}"),
+                }
+            }
             Self::Fp8 { .. } => IsqType::Q8_0.pack_factor(dtype),
             Self::Bitsandbytes {
                 bnb_4bit_quant_type: Some(_),
@@ -267,6 +341,8 @@ pub enum QuantMethodConfig {
         qzeros: Option<Tensor>,
         scales: Tensor,
         g_idx: Option<Tensor>,
+        /// The order of the input channels in the Marlin weight, for act-order checkpoints.
+        perm: Option<Tensor>,
         bias: Option<Tensor>,
         workspace: Option<Tensor>,
         is_marlin: bool,
@@ -422,10 +498,12 @@ pub enum IsqType {
     Q8K,
     HQQ8,
     HQQ4,
-    // HQQ3,
-    // HQQ2,
-    // HQQ1,
+    HQQ3,
+    HQQ2,
+    HQQ1,
     F8E4M3,
+    /// 4 bits in groups of 128, for the Marlin kernels on CUDA.
+    MARLIN4,
     AFQ8,
     AFQ6,
     AFQ4,
@@ -465,20 +543,27 @@ impl IsqType {
             // Estimates
             Self::HQQ4 => 4,
             Self::HQQ8 => 2,
+            Self::HQQ3 => 5,
+            Self::HQQ2 => 8,
+            Self::HQQ1 => 16,
             Self::F8E4M3 => 2,
+            Self::MARLIN4 => 4,
         }
     }
 
     pub fn get_max_isq_cpu_threads(&self) -> Option<NonZeroUsize> {
         match self {
-            /*IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | */
-            IsqType::HQQ4
+            IsqType::HQQ1
+            | IsqType::HQQ2
+            | IsqType::HQQ3
+            | IsqType::HQQ4
             | IsqType::HQQ8
             | IsqType::AFQ2
             | IsqType::AFQ3
             | IsqType::AFQ4
             | IsqType::AFQ6
-            | IsqType::AFQ8 => {
+            | IsqType::AFQ8
+            | IsqType::MARLIN4 => {
                 // Use 1 because our HQQ quantizes on the GPU
                 Some(1.try_into().unwrap())
             }
@@ -533,7 +618,7 @@ impl TryFrom<IsqType> for GgmlDType {
                     | GgmlDType::Q5K
                     | GgmlDType::Q6K
             ) {
-                candle_core::bail!("GGML ISQ type on CUDA must be one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `HQQ8`, `HQQ4`")
+                candle_core::bail!("GGML ISQ type on CUDA must be one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `HQQ8`, `HQQ4`, `HQQ3`, `HQQ2`, `HQQ1`")
             }
         }
         Ok(tp)
@@ -752,6 +837,11 @@ pub trait QuantMethod: Send + Sync + Debug + QuantizedSerde {
         None
     }
 
+    /// If the weight is a GGML quantized tensor, return it, for example to write it to a GGUF file.
+    fn ggml_weight(&self) -> Option<Arc<QTensor>> {
+        None
+    }
+
     /// Begin tracking stats into an ImatrixLayerStats
     fn begin_track_stats(&mut self) -> Result<()> {
         candle_core::bail!("`{}` does not support tracking stats.", self.name())
@@ -765,6 +855,11 @@ pub trait QuantMethod: Send + Sync + Debug + QuantizedSerde {
     fn is_distributed(&self) -> Option<DistributedKind> {
         None
     }
+
+    /// If this layer has unmerged LoRA adapters, return it.
+    fn as_batched_lora(&self) -> Option<&BatchedLoraLinear> {
+        None
+    }
 }
 
 impl Module for dyn QuantMethod {
@@ -793,7 +888,7 @@ pub fn linear_no_bias(
                 blockwise_fp8_linear_b(in_dim, out_dim, quant_conf, false, Default::default(), vb)?
             }
             QuantizedConfig::Bitsandbytes { .. } => {
-                Arc::new(BnbLinear::linear_b(in_dim, out_dim, false, vb)?) as Arc<_>
+                BnbLinear::linear_b(in_dim, out_dim, false, vb)?
             }
             QuantizedConfig::Afq { .. } => {
                 AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, false, vb)?
@@ -801,6 +896,9 @@ pub fn linear_no_bias(
             QuantizedConfig::MXFP4 {} => {
                 MXFP4Layer::linear_b(in_dim, out_dim, quant_conf, false, vb)?
             }
+            QuantizedConfig::Torchao { .. } => {
+                torchao_linear_b(in_dim, out_dim, quant_conf, false, vb)?
+            }
         }
     } else {
         // Handle the case where the layer is dummy (no tensors)
@@ -839,15 +937,16 @@ pub fn linear(
             QuantizedConfig::Fp8 { .. } => {
                 blockwise_fp8_linear_b(in_dim, out_dim, quant_conf, true, Default::default(), vb)?
             }
-            QuantizedConfig::Bitsandbytes { .. } => {
-                Arc::new(BnbLinear::linear_b(in_dim, out_dim, true, vb)?) as Arc<_>
-            }
+            QuantizedConfig::Bitsandbytes { .. } => BnbLinear::linear_b(in_dim, out_dim, true, vb)?,
             QuantizedConfig::Afq { .. } => {
                 AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, true, vb)?
             }
             QuantizedConfig::MXFP4 {} => {
                 MXFP4Layer::linear_b(in_dim, out_dim, quant_conf, true, vb)?
             }
+            QuantizedConfig::Torchao { .. } => {
+                torchao_linear_b(in_dim, out_dim, quant_conf, true, vb)?
+            }
         }
     } else {
         // Handle the case where the layer is dummy (no tensors)
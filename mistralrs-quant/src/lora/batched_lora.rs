@@ -0,0 +1,318 @@
+use std::{cell::RefCell, sync::Arc};
+
+use candle_core::{DType, Device, Result, Tensor};
+
+use crate::{
+    DistributedKind, IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde,
+};
+
+/// The LoRA adapters selected by a sequence, with the weight each adapter's output is scaled by.
+/// `None` selects all adapters with a weight of 1.
+pub type LoraAdapterSelection = Option<Vec<(String, f64)>>;
+
+thread_local! {
+    static ENGINE_LORA_BATCH: RefCell<Vec<LoraAdapterSelection>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Set the LoRA adapters selected by each sequence of the batch for the current engine thread.
+/// The entries must be in the same order as the rows of the batch.
+pub fn set_lora_batch(adapters: Vec<LoraAdapterSelection>) {
+    ENGINE_LORA_BATCH.with(|batch| *batch.borrow_mut() = adapters);
+}
+
+#[derive(Debug, Clone)]
+struct BatchedLoraAdapter {
+    name: String,
+    /// (r, in_dim)
+    a: Tensor,
+    /// (out_dim, r)
+    b: Tensor,
+    scale: f64,
+}
+
+impl BatchedLoraAdapter {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = xs.to_dtype(self.a.dtype())?;
+        xs.broadcast_matmul(&self.a.t()?)?
+            .broadcast_matmul(&self.b.t()?)?
+            * self.scale
+    }
+}
+
+/// A layer with unmerged LoRA adapters on top of its base weights. Each adapter is only applied to
+/// the rows of the batch which select it, so sequences using different adapters can be batched
+/// together (as in S-LoRA).
+#[derive(Debug)]
+pub struct BatchedLoraLinear {
+    base: Arc<dyn QuantMethod>,
+    adapters: Vec<BatchedLoraAdapter>,
+}
+
+impl BatchedLoraLinear {
+    /// Add the adapter `name` with weights `lora_A` of shape (r, in_dim) and `lora_B` of shape
+    /// (out_dim, r) to `layer`.
+    pub fn add_adapter(
+        layer: &Arc<dyn QuantMethod>,
+        name: String,
+        a: Tensor,
+        b: Tensor,
+        scale: f64,
+    ) -> Arc<dyn QuantMethod> {
+        let adapter = BatchedLoraAdapter { name, a, b, scale };
+        match layer.as_batched_lora() {
+            Some(this) => {
+                let mut adapters = this.adapters.clone();
+                adapters.push(adapter);
+                Arc::new(Self {
+                    base: this.base.clone(),
+                    adapters,
+                })
+            }
+            None => Arc::new(Self {
+                base: layer.clone(),
+                adapters: vec![adapter],
+            }),
+        }
+    }
+
+    /// Remove the adapter `name` from `layer`, if it has it.
+    pub fn remove_adapter(layer: &Arc<dyn QuantMethod>, name: &str) -> Arc<dyn QuantMethod> {
+        let Some(this) = layer.as_batched_lora() else {
+            return layer.clone();
+        };
+        let adapters = this
+            .adapters
+            .iter()
+            .filter(|adapter| adapter.name != name)
+            .cloned()
+            .collect::<Vec<_>>();
+        if adapters.is_empty() {
+            this.base.clone()
+        } else {
+            Arc::new(Self {
+                base: this.base.clone(),
+                adapters,
+            })
+        }
+    }
+
+    /// Merge all adapters of `layer` into its base weights.
+    pub fn merge_adapters(layer: &Arc<dyn QuantMethod>) -> Result<Arc<dyn QuantMethod>> {
+        let Some(this) = layer.as_batched_lora() else {
+            return Ok(layer.clone());
+        };
+        let (dtype, device) = this.base.dtype_and_device();
+        let mut base = this.base.clone();
+        for BatchedLoraAdapter { a, b, scale, .. } in &this.adapters {
+            let delta = (b.to_dtype(DType::F32)?.matmul(&a.to_dtype(DType::F32)?)? * *scale)?;
+            base = base.add_delta_w(&delta.to_device(&device)?.to_dtype(dtype)?)?;
+        }
+        Ok(base)
+    }
+}
+
+/// The weight of the adapter `name` in `selection`, if it is selected.
+fn adapter_weight(selection: &LoraAdapterSelection, name: &str) -> Option<f64> {
+    match selection {
+        None => Some(1.0),
+        Some(adapters) => adapters
+            .iter()
+            .find(|(adapter, _)| adapter == name)
+            .map(|(_, weight)| *weight),
+    }
+}
+
+impl QuantMethod for BatchedLoraLinear {
+    fn new(_method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        candle_core::bail!("BatchedLoraLinear should not be constructed with `QuantMethod::new`")
+    }
+
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        let mut xs = self.base.forward(a)?;
+        let batch = ENGINE_LORA_BATCH.with(|batch| batch.borrow().clone());
+
+        // Without a batch, or if every row selects the same adapters, apply them to the whole input.
+        let uniform = batch
+            .first()
+            .filter(|first| batch.iter().all(|sel| sel == *first));
+        if batch.is_empty() || uniform.is_some() {
+            let selection = uniform.cloned().flatten();
+            for adapter in &self.adapters {
+                if let Some(weight) = adapter_weight(&selection, &adapter.name) {
+                    xs = (&xs + (adapter.forward(a)? * weight)?.to_dtype(xs.dtype())?)?;
+                }
+            }
+            return Ok(xs);
+        }
+
+        // The rows of the input cannot be mapped to the sequences, e.g. for the patches of a
+        // vision tower or packed inputs, so no adapter is applied to them.
+        if a.dim(0)? != batch.len() {
+            crate::log::once_log_warn(format!(
+                "Not applying LoRA adapters to an input of batch size {}, which does not match the {} sequences selecting them.",
+                a.dim(0)?,
+                batch.len()
+            ));
+            return Ok(xs);
+        }
+        for adapter in &self.adapters {
+            let (rows, weights): (Vec<u32>, Vec<f32>) = batch
+                .iter()
+                .enumerate()
+                .filter_map(|(i, selection)| {
+                    adapter_weight(selection, &adapter.name).map(|weight| (i as u32, weight as f32))
+                })
+                .unzip();
+            if rows.is_empty() {
+                continue;
+            }
+            let mut weights_shape = vec![1; a.rank()];
+            weights_shape[0] = rows.len();
+            let weights = Tensor::new(weights.as_slice(), a.device())?.reshape(weights_shape)?;
+            let rows = Tensor::new(rows.as_slice(), a.device())?;
+            let delta = adapter.forward(&a.index_select(&rows, 0)?)?;
+            let delta = delta
+                .broadcast_mul(&weights.to_dtype(delta.dtype())?)?
+                .to_dtype(xs.dtype())?;
+            xs = xs.index_add(&rows, &delta, 0)?;
+        }
+        Ok(xs)
+    }
+
+    fn gather_forward(&self, a: &Tensor, indices: &Tensor) -> Result<Tensor> {
+        self.base.gather_forward(a, indices)
+    }
+
+    fn add_delta_w(&self, delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        Ok(Arc::new(Self {
+            base: self.base.add_delta_w(delta)?,
+            adapters: self.adapters.clone(),
+        }))
+    }
+
+    fn dequantize_w(&self) -> Result<Tensor> {
+        self.base.dequantize_w()
+    }
+
+    fn dtype_and_device(&self) -> (DType, Device) {
+        self.base.dtype_and_device()
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        self.base.quantized_act_type()
+    }
+
+    fn unquant_weight_bias(&self) -> Option<(Tensor, Option<Tensor>)> {
+        self.base.unquant_weight_bias()
+    }
+
+    fn apply_isq(
+        self: Arc<Self>,
+        dtype: Option<IsqType>,
+        device: Device,
+        n_quantized: &std::sync::atomic::AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
+        guard: QuantizeOntoGuard,
+    ) -> Result<Arc<dyn QuantMethod>> {
+        let base = self.base.clone().apply_isq(
+            dtype,
+            device.clone(),
+            n_quantized,
+            imatrix_weight,
+            guard,
+        )?;
+        let adapters = self
+            .adapters
+            .iter()
+            .map(|adapter| {
+                Ok(BatchedLoraAdapter {
+                    a: adapter.a.to_device(&device)?,
+                    b: adapter.b.to_device(&device)?,
+                    ..adapter.clone()
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Arc::new(Self { base, adapters }))
+    }
+
+    fn is_distributed(&self) -> Option<DistributedKind> {
+        self.base.is_distributed()
+    }
+
+    fn as_batched_lora(&self) -> Option<&BatchedLoraLinear> {
+        Some(self)
+    }
+}
+
+impl QuantizedSerde for BatchedLoraLinear {
+    fn name(&self) -> &'static str {
+        "batched-lora"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use candle_nn::Linear;
+
+    use super::{set_lora_batch, BatchedLoraLinear};
+    use crate::{QuantMethod, QuantMethodConfig, UnquantLinear};
+
+    /// A layer with an identity base weight and the adapters `a`, adding 1 to the first output,
+    /// and `b`, adding 1 to the second output, for an input of ones.
+    fn layer() -> Result<Arc<dyn QuantMethod>> {
+        let dev = Device::Cpu;
+        let base: Arc<dyn QuantMethod> = Arc::new(UnquantLinear::new(
+            QuantMethodConfig::Unquantized(Linear::new(Tensor::eye(2, DType::F32, &dev)?, None)),
+        )?);
+        let lora_a = Tensor::new(&[[0.5f32, 0.5]], &dev)?;
+        let layer = BatchedLoraLinear::add_adapter(
+            &base,
+            "a".to_string(),
+            lora_a.clone(),
+            Tensor::new(&[[1f32], [0.]], &dev)?,
+            1.0,
+        );
+        Ok(BatchedLoraLinear::add_adapter(
+            &layer,
+            "b".to_string(),
+            lora_a,
+            Tensor::new(&[[0f32], [1.]], &dev)?,
+            1.0,
+        ))
+    }
+
+    #[test]
+    fn test_rows_get_their_selected_adapters() -> Result<()> {
+        let layer = layer()?;
+        let xs = Tensor::ones((3, 1, 2), DType::F32, &Device::Cpu)?;
+        set_lora_batch(vec![
+            Some(vec![("a".to_string(), 1.0)]),
+            Some(vec![("b".to_string(), 2.0)]),
+            Some(vec![]),
+        ]);
+        let out = layer.forward(&xs)?.squeeze(1)?.to_vec2::<f32>()?;
+        set_lora_batch(Vec::new());
+        assert_eq!(out, vec![vec![2., 1.], vec![1., 3.], vec![1., 1.]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mismatched_batch_applies_no_adapter() -> Result<()> {
+        let layer = layer()?;
+        let xs = Tensor::ones((4, 2), DType::F32, &Device::Cpu)?;
+        set_lora_batch(vec![
+            Some(vec![("a".to_string(), 1.0)]),
+            Some(vec![("b".to_string(), 1.0)]),
+        ]);
+        let out = layer.forward(&xs)?.to_vec2::<f32>()?;
+        set_lora_batch(Vec::new());
+        assert_eq!(out, vec![vec![1., 1.]; 4]);
+        Ok(())
+    }
+}
@@ -1,13 +1,18 @@
+mod batched_lora;
 mod static_lora;
 
-use std::{cell::RefCell, collections::HashSet};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
 
-use candle_core::{DType, Result, Tensor};
+pub use batched_lora::{set_lora_batch, BatchedLoraLinear, LoraAdapterSelection};
+use candle_core::{DType, Result, Shape, Tensor};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 pub use static_lora::linear_no_bias_static_lora;
 
-use crate::{Shard, ShardedVarBuilder};
+use crate::{QuantMethod, Shard, ShardedVarBuilder};
 
 thread_local! {
     static ENGINE_APPLIED_LORAS: RefCell<Vec<LoraAdapter>> = const { RefCell::new(Vec::new()) };
@@ -28,6 +33,25 @@ pub fn clear_applied_loras() {
     ENGINE_APPLIED_LORAS.with(|loras| loras.borrow_mut().clear());
 }
 
+/// Clears the LoRA adapters of the current engine thread when created and when dropped, so the
+/// adapters pushed while loading a model are not merged into the next model loaded on the thread.
+#[must_use]
+pub struct AppliedLorasGuard(());
+
+impl AppliedLorasGuard {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        clear_applied_loras();
+        Self(())
+    }
+}
+
+impl Drop for AppliedLorasGuard {
+    fn drop(&mut self) {
+        clear_applied_loras();
+    }
+}
+
 pub const MULTI_LORA_DELIMITER: &str = ";";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -44,6 +68,46 @@ pub struct LoraConfig {
     #[serde(rename = "lora_alpha")]
     pub alpha: f64,
     pub target_modules: HashSet<String>,
+    /// Weight-decomposed LoRA (DoRA), which also learns the magnitude of each output row.
+    #[serde(default)]
+    pub use_dora: bool,
+    /// Overrides `lora_alpha / r` as the scale of the adapter.
+    #[serde(skip)]
+    pub scale: Option<f64>,
+    /// Per-module ranks, keyed by a pattern matching the end of the module name.
+    #[serde(default)]
+    pub rank_pattern: HashMap<String, usize>,
+    /// Per-module alphas, keyed by a pattern matching the end of the module name.
+    #[serde(default)]
+    pub alpha_pattern: HashMap<String, f64>,
+    /// Modules which are fully retrained and saved in the adapter, such as `lm_head`, replacing
+    /// the base weights.
+    #[serde(default)]
+    pub modules_to_save: Option<Vec<String>>,
+}
+
+impl LoraConfig {
+    /// Whether the adapter can only be applied by merging it into the weights: DoRA adapters and
+    /// adapters which replace whole modules.
+    pub fn needs_merge(&self) -> bool {
+        self.use_dora || self.saves_modules()
+    }
+
+    /// Whether the adapter replaces any whole modules with `modules_to_save`.
+    pub fn saves_modules(&self) -> bool {
+        self.modules_to_save
+            .as_ref()
+            .is_some_and(|modules| !modules.is_empty())
+    }
+
+    /// Whether the layer at `prefix` is one of the `modules_to_save`.
+    fn saves_module(&self, prefix: &str) -> bool {
+        self.modules_to_save.as_ref().is_some_and(|modules| {
+            modules
+                .iter()
+                .any(|module| prefix == module || prefix.ends_with(&format!(".{module}")))
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -52,54 +116,277 @@ pub struct LoraAdapter {
     pub weights: ShardedVarBuilder,
 }
 
-pub(crate) fn merge_lora_weights(
+fn target_modules_regex(config: &LoraConfig) -> Result<Regex> {
+    let target_modules = config
+        .target_modules
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("|");
+    Regex::new(&target_modules).map_err(candle_core::Error::msg)
+}
+
+/// Handle base_model.model things from peft
+fn adapter_weights_for(weights: &ShardedVarBuilder, prefix: &str) -> ShardedVarBuilder {
+    if weights
+        .pp("base_model.model")
+        .pp(prefix)
+        .contains_tensor("lora_A.weight")
+    {
+        weights.pp("base_model.model").pp(prefix)
+    } else {
+        weights.pp(prefix)
+    }
+}
+
+/// The value of `patterns` for the layer at `prefix`. As in PEFT, a key matches if it matches
+/// the whole module name or a suffix of it starting after a `.`; the longest matching key wins.
+fn pattern_value<V: Copy>(patterns: &HashMap<String, V>, prefix: &str) -> Result<Option<V>> {
+    let mut best: Option<(&str, V)> = None;
+    for (key, value) in patterns {
+        let regex = Regex::new(&format!(r"^(.*\.)?(?:{key})$")).map_err(candle_core::Error::msg)?;
+        if regex.is_match(prefix) && best.is_none_or(|(best, _)| key.len() > best.len()) {
+            best = Some((key, *value));
+        }
+    }
+    Ok(best.map(|(_, value)| value))
+}
+
+/// The rank of the adapter for the layer at `prefix`, from `rank_pattern` or `r`.
+fn lora_rank(config: &LoraConfig, prefix: &str) -> Result<usize> {
+    Ok(pattern_value(&config.rank_pattern, prefix)?.unwrap_or(config.rank))
+}
+
+/// `alpha / r` for the layer at `prefix`, using `rank_pattern` and `alpha_pattern`, unless the
+/// scale is overridden.
+fn lora_scale(config: &LoraConfig, prefix: &str) -> Result<f64> {
+    if let Some(scale) = config.scale {
+        return Ok(scale);
+    }
+    let rank = lora_rank(config, prefix)?;
+    let alpha = pattern_value(&config.alpha_pattern, prefix)?.unwrap_or(config.alpha);
+    Ok(if rank > 0 { alpha / rank as f64 } else { 1.0 })
+}
+
+/// Compute `B @ A * scale` for the layer at `prefix`, in the dtype of `a`.
+fn scaled_delta(config: &LoraConfig, prefix: &str, a: &Tensor, b: &Tensor) -> Result<Tensor> {
+    let scale = lora_scale(config, prefix)?;
+
+    let ab = if a.device().is_cpu() {
+        b.to_dtype(DType::F32)?.matmul(&a.to_dtype(DType::F32)?)?
+    } else {
+        b.matmul(a)?
+    };
+
+    (ab * scale)?.to_dtype(a.dtype())
+}
+
+/// PEFT has saved the DoRA magnitude both as a parameter and as a module.
+fn dora_magnitude_name(weights: &ShardedVarBuilder) -> &'static str {
+    if weights.contains_tensor("lora_magnitude_vector") {
+        "lora_magnitude_vector"
+    } else {
+        "lora_magnitude_vector.weight"
+    }
+}
+
+/// Compute the DoRA weight `m * (W + delta) / ||W + delta||`, where the norm is taken over each
+/// output row, in the dtype of `weight`.
+fn dora_weight(weight: &Tensor, delta: &Tensor, magnitude: &Tensor) -> Result<Tensor> {
+    let merged = (weight.to_dtype(DType::F32)? + delta.to_dtype(DType::F32)?)?;
+    let norm = merged.sqr()?.sum_keepdim(1)?.sqrt()?;
+    let magnitude = magnitude
+        .to_dtype(DType::F32)?
+        .reshape((merged.dim(0)?, 1))?;
+    merged
+        .broadcast_mul(&magnitude.broadcast_div(&norm)?)?
+        .to_dtype(weight.dtype())
+}
+
+/// Get `lora_A`, `lora_B` and the scale of `adapter` for the layer at `prefix` (for example
+/// `model.layers.0.self_attn.q_proj`), or `None` if the adapter does not target that layer.
+///
+/// The adapter weights are loaded unsharded; this is used to apply adapters to an already
+/// loaded model via [`BatchedLoraLinear`].
+pub fn lora_weights(adapter: &LoraAdapter, prefix: &str) -> Result<Option<(Tensor, Tensor, f64)>> {
+    let LoraAdapter { config, weights } = adapter;
+    if !target_modules_regex(config)?.is_match(prefix) {
+        return Ok(None);
+    }
+
+    let weights = adapter_weights_for(weights, prefix);
+    if !weights.contains_tensor("lora_A.weight") || !weights.contains_tensor("lora_B.weight") {
+        return Ok(None);
+    }
+
+    let a = weights.get_unchecked("lora_A.weight")?;
+    let b = weights.get_unchecked("lora_B.weight")?;
+    Ok(Some((a, b, lora_scale(config, prefix)?)))
+}
+
+/// Compute the delta weight of `adapter` for `layer` at `prefix` (for example
+/// `model.layers.0.self_attn.q_proj`), or `None` if the adapter does not target that layer.
+///
+/// The adapter weights are loaded unsharded; this is used to apply adapters to an already
+/// loaded model via [`QuantMethod::add_delta_w`]. For DoRA adapters the delta depends on the
+/// current weight of `layer`, so it cannot be subtracted again to remove the adapter.
+pub fn lora_delta_weight(
+    adapter: &LoraAdapter,
+    prefix: &str,
+    layer: &dyn QuantMethod,
+) -> Result<Option<Tensor>> {
+    let LoraAdapter { config, weights } = adapter;
+    if !target_modules_regex(config)?.is_match(prefix) {
+        return Ok(None);
+    }
+
+    let weights = adapter_weights_for(weights, prefix);
+    if !weights.contains_tensor("lora_A.weight") || !weights.contains_tensor("lora_B.weight") {
+        return Ok(None);
+    }
+
+    let a = weights.get_unchecked("lora_A.weight")?;
+    let b = weights.get_unchecked("lora_B.weight")?;
+    let delta = scaled_delta(config, prefix, &a, &b)?;
+    if !config.use_dora {
+        return Ok(Some(delta));
+    }
+
+    let magnitude = weights.get_unchecked(dora_magnitude_name(&weights))?;
+    let weight = layer
+        .dequantize_w()?
+        .to_device(delta.device())?
+        .to_dtype(DType::F32)?;
+    let delta = (dora_weight(&weight, &delta, &magnitude)? - &weight)?;
+    Ok(Some(delta))
+}
+
+/// Replace `weight`, of the full shape `shape`, with the weight saved by an applied adapter which
+/// lists the layer at the prefix of `vb` in its `modules_to_save`. Layers which are not linear
+/// layers, such as embeddings, use this directly.
+pub fn apply_modules_to_save<S: Into<Shape>>(
     vb: &ShardedVarBuilder,
-    mut weight: Tensor,
+    weight: Tensor,
+    shape: S,
+    shard: Shard,
+) -> Result<Tensor> {
+    let prefix = vb.prefix();
+    let applied_loras = get_applied_loras();
+    let Some(LoraAdapter { weights, .. }) = applied_loras
+        .iter()
+        .rev()
+        .find(|adapter| adapter.config.saves_module(&prefix))
+    else {
+        return Ok(weight);
+    };
+
+    // PEFT saves the module at its full name, under `base_model.model`.
+    let weights = if weights
+        .pp("base_model.model")
+        .pp(&prefix)
+        .contains_tensor("weight")
+    {
+        weights.pp("base_model.model").pp(&prefix)
+    } else {
+        weights.pp(&prefix)
+    };
+    weights
+        .get_with_hints(shape, "weight", shard)?
+        .to_device(weight.device())?
+        .to_dtype(weight.dtype())
+}
+
+/// Merge the applied LoRA adapters which target the layer at the prefix of `vb` into `weight`.
+/// Layers which do not go through the quantized linear constructors, such as those of vision
+/// towers, use this directly.
+pub fn merge_lora_weights(
+    vb: &ShardedVarBuilder,
+    weight: Tensor,
     in_dim: usize,
     out_dim: usize,
     shard: Shard,
 ) -> Result<Tensor> {
+    let mut weight = apply_modules_to_save(vb, weight, (out_dim, in_dim), shard)?;
+    let prefix = vb.prefix();
     let applied_loras = get_applied_loras();
     for LoraAdapter { config, weights } in &applied_loras {
-        let target_modules = config
-            .target_modules
-            .iter()
-            .map(ToString::to_string)
-            .collect::<Vec<_>>()
-            .join("|");
-        let regex = Regex::new(&target_modules).map_err(candle_core::Error::msg)?;
-        if !regex.is_match(&vb.prefix()) {
+        if config.saves_module(&prefix) || !target_modules_regex(config)?.is_match(&prefix) {
             continue;
         }
 
-        // Handle base_model.model things from peft
-        let weights = if weights
-            .pp("base_model.model")
-            .pp(vb.prefix())
-            .contains_tensor("lora_A.weight")
-        {
-            weights.pp("base_model.model").pp(vb.prefix())
-        } else {
-            weights.pp(vb.prefix())
-        };
+        let weights = adapter_weights_for(weights, &prefix);
 
-        let a = weights.get_with_hints((config.rank, in_dim), "lora_A.weight", shard)?;
-        let b = weights.get_with_hints((out_dim, config.rank), "lora_B.weight", shard)?;
-        let scale = if config.rank > 0 {
-            config.alpha / config.rank as f64
-        } else {
-            1.0
-        };
+        let rank = lora_rank(config, &prefix)?;
+        let a = weights.get_with_hints((rank, in_dim), "lora_A.weight", shard)?;
+        let b = weights.get_with_hints((out_dim, rank), "lora_B.weight", shard)?;
 
-        let ab = if a.device().is_cpu() {
-            b.to_dtype(DType::F32)?.matmul(&a.to_dtype(DType::F32)?)?
+        // With immediate ISQ, the base weight is loaded on the CPU.
+        let delta_weight = scaled_delta(config, &prefix, &a, &b)?.to_device(weight.device())?;
+        weight = if config.use_dora {
+            // The norm of each output row needs the full input dimension.
+            if matches!(
+                shard,
+                Shard::Simple { dim: 1, world_size, .. } if world_size > 1
+            ) || matches!(shard, Shard::Offset { dim: 1, .. })
+            {
+                candle_core::bail!(
+                    "DoRA adapters are not supported for layers sharded along the input dimension."
+                );
+            }
+            let magnitude =
+                weights.get_with_hints(out_dim, dora_magnitude_name(&weights), shard)?;
+            let magnitude = magnitude.to_device(weight.device())?;
+            dora_weight(&weight, &delta_weight, &magnitude)?
         } else {
-            b.matmul(&a)?
+            (weight + delta_weight)?
         };
-
-        let delta_weight = (ab * scale)?;
-        weight = (weight + delta_weight.to_dtype(a.dtype())?)?;
     }
 
     Ok(weight)
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use candle_core::{Device, Result, Tensor};
+
+    use super::{dora_weight, pattern_value};
+
+    #[test]
+    fn test_pattern_value_matches_module_suffix() -> Result<()> {
+        let patterns = HashMap::from([
+            ("q_proj".to_string(), 8),
+            ("layers.0.self_attn.q_proj".to_string(), 16),
+            ("mlp\\..*_proj".to_string(), 4),
+        ]);
+        let value = |prefix| pattern_value(&patterns, prefix);
+
+        assert_eq!(value("model.layers.0.self_attn.q_proj")?, Some(16));
+        assert_eq!(value("model.layers.1.self_attn.q_proj")?, Some(8));
+        assert_eq!(value("model.layers.1.mlp.up_proj")?, Some(4));
+        // Keys only match whole module names.
+        assert_eq!(value("model.layers.1.self_attn.xq_proj")?, None);
+        assert_eq!(value("model.layers.1.self_attn.k_proj")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dora_weight_row_norms() -> Result<()> {
+        let dev = Device::Cpu;
+        let weight = Tensor::new(&[[3f32, 0.], [0., 1.]], &dev)?;
+        let delta = Tensor::new(&[[0f32, 4.], [0., 1.]], &dev)?;
+        let magnitude = Tensor::new(&[10f32, 2.], &dev)?;
+
+        let merged = dora_weight(&weight, &delta, &magnitude)?;
+        // Each row of `W + delta` is rescaled to the norm given by the magnitude.
+        let expected = [[6f32, 8.], [0., 2.]];
+        let merged = merged.to_vec2::<f32>()?;
+        for (row, expected) in merged.iter().zip(expected) {
+            for (x, y) in row.iter().zip(expected) {
+                assert!((x - y).abs() < 1e-5);
+            }
+        }
+        Ok(())
+    }
+}
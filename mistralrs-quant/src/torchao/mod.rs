@@ -0,0 +1,246 @@
+//! Loading of checkpoints quantized with [torchao](https://github.com/pytorch/ao), such as the int4
+//! and int8 weight-only models exported after QAT.
+//!
+//! torchao stores a quantized weight as its integer values in `weight_qdata` (int8, one value per
+//! element), with `weight_scale` and an optional `weight_zero_point` for each group of input
+//! channels: `w = (q - zero_point) * scale`. These are repacked without loss into the GGUF `Q4_1`
+//! (if the values fit in 4 bits) or `Q8_0` formats, so the existing kernels run the layer.
+
+use std::sync::Arc;
+
+use candle_core::{quantized::ggml_file::qtensor_from_ggml, quantized::GgmlDType, DType, Device};
+use candle_core::{Result, Tensor};
+use candle_nn::Linear;
+use half::f16;
+
+use crate::{
+    DummyLayer, GgufMatMul, QuantMethod, QuantMethodConfig, QuantizedConfig, ShardedVarBuilder,
+    UnquantLinear,
+};
+
+/// The GGUF block size of `Q4_1` and `Q8_0`.
+const BLOCK_SIZE: usize = 32;
+
+/// The integer weight of a torchao layer, with its quantization parameters.
+struct TorchaoWeight {
+    /// (out_dim, in_dim)
+    qdata: Vec<i16>,
+    /// (out_dim, groups)
+    scale: Vec<f32>,
+    /// (out_dim, groups)
+    zero_point: Option<Vec<f32>>,
+    out_dim: usize,
+    in_dim: usize,
+    group_size: usize,
+}
+
+impl TorchaoWeight {
+    fn params(&self, row: usize, col: usize) -> (f32, f32) {
+        let group = row * (self.in_dim / self.group_size) + col / self.group_size;
+        let zero_point = self.zero_point.as_ref().map_or(0., |z| z[group]);
+        (self.scale[group], zero_point)
+    }
+
+    fn fits_in_4_bits(&self) -> bool {
+        self.qdata.iter().all(|q| (-8..=7).contains(q))
+    }
+
+    fn has_zero_point(&self) -> bool {
+        self.zero_point
+            .as_ref()
+            .is_some_and(|z| z.iter().any(|z| *z != 0.))
+    }
+
+    /// Repack into `Q4_1`, storing `q + 8` with the offset `-(8 + zero_point) * scale`.
+    fn to_q4_1(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.qdata.len() / BLOCK_SIZE * 20);
+        for (i, block) in self.qdata.chunks_exact(BLOCK_SIZE).enumerate() {
+            let (row, col) = (i * BLOCK_SIZE / self.in_dim, i * BLOCK_SIZE % self.in_dim);
+            let (scale, zero_point) = self.params(row, col);
+            data.extend(f16::from_f32(scale).to_le_bytes());
+            data.extend(f16::from_f32(-(8. + zero_point) * scale).to_le_bytes());
+            // Each byte holds value `j` in the low nibble and value `j + 16` in the high nibble
+            let (lo, hi) = block.split_at(BLOCK_SIZE / 2);
+            data.extend(
+                lo.iter()
+                    .zip(hi)
+                    .map(|(lo, hi)| (lo + 8) as u8 | (((hi + 8) as u8) << 4)),
+            );
+        }
+        data
+    }
+
+    /// Repack into `Q8_0`, which requires a zero point of 0.
+    fn to_q8_0(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.qdata.len() / BLOCK_SIZE * 34);
+        for (i, block) in self.qdata.chunks_exact(BLOCK_SIZE).enumerate() {
+            let (row, col) = (i * BLOCK_SIZE / self.in_dim, i * BLOCK_SIZE % self.in_dim);
+            let (scale, _) = self.params(row, col);
+            data.extend(f16::from_f32(scale).to_le_bytes());
+            data.extend(block.iter().map(|q| *q as i8 as u8));
+        }
+        data
+    }
+
+    fn dequantize(&self, device: &Device, dtype: DType) -> Result<Tensor> {
+        let w = self
+            .qdata
+            .iter()
+            .enumerate()
+            .map(|(i, q)| {
+                let (scale, zero_point) = self.params(i / self.in_dim, i % self.in_dim);
+                (*q as f32 - zero_point) * scale
+            })
+            .collect::<Vec<_>>();
+        Tensor::from_vec(w, (self.out_dim, self.in_dim), device)?.to_dtype(dtype)
+    }
+}
+
+pub fn torchao_linear_b(
+    in_dim: usize,
+    out_dim: usize,
+    config: &QuantizedConfig,
+    bias: bool,
+    vb: ShardedVarBuilder,
+) -> Result<Arc<dyn QuantMethod>> {
+    let QuantizedConfig::Torchao { .. } = config else {
+        candle_core::bail!("Unexpected quantization config.")
+    };
+
+    // Handle the case where the layer is not quantized
+    if vb.contains_tensor("weight") {
+        return crate::linear_b(in_dim, out_dim, bias, &None, vb);
+    }
+
+    // Handle the case where the layer is dummy (no tensors)
+    if !(vb.contains_tensor("weight_qdata") && vb.contains_tensor("weight_scale")) {
+        let layer = <DummyLayer as QuantMethod>::new(QuantMethodConfig::Dummy)?;
+        return Ok(Arc::new(layer) as Arc<dyn QuantMethod>);
+    }
+
+    let qdata = vb
+        .get_with_hints_dtype(
+            (out_dim, in_dim),
+            "weight_qdata",
+            Default::default(),
+            DType::I16,
+        )?
+        .to_dtype(DType::I16)?;
+    let scale = vb
+        .get_unchecked_dtype("weight_scale", DType::F32)?
+        .to_dtype(DType::F32)?;
+    let groups = scale.elem_count() / out_dim;
+    if groups == 0 || scale.elem_count() % out_dim != 0 || in_dim % groups != 0 {
+        candle_core::bail!(
+            "Expected torchao scales for groups of input channels, got shape {:?} for a weight of shape ({out_dim}, {in_dim}).",
+            scale.dims()
+        );
+    }
+    let zero_point = if vb.contains_tensor("weight_zero_point") {
+        let zero_point = vb
+            .get_unchecked_dtype("weight_zero_point", DType::F32)?
+            .to_dtype(DType::F32)?;
+        if zero_point.elem_count() != scale.elem_count() {
+            candle_core::bail!(
+                "Expected one torchao zero point per scale, got shapes {:?} and {:?}.",
+                zero_point.dims(),
+                scale.dims()
+            );
+        }
+        Some(zero_point.flatten_all()?.to_vec1::<f32>()?)
+    } else {
+        None
+    };
+    let weight = TorchaoWeight {
+        qdata: qdata.flatten_all()?.to_vec1::<i16>()?,
+        scale: scale.flatten_all()?.to_vec1::<f32>()?,
+        zero_point,
+        out_dim,
+        in_dim,
+        group_size: in_dim / groups,
+    };
+
+    let bias = if bias {
+        Some(vb.get_with_hints_dtype((out_dim,), "bias", Default::default(), vb.dtype())?)
+    } else {
+        None
+    };
+
+    let ggml_dtype = if weight.group_size % BLOCK_SIZE != 0 {
+        None
+    } else if weight.fits_in_4_bits() {
+        Some(GgmlDType::Q4_1)
+    } else if !weight.has_zero_point() {
+        Some(GgmlDType::Q8_0)
+    } else {
+        None
+    };
+    let layer: Arc<dyn QuantMethod> = match ggml_dtype {
+        Some(ggml_dtype) => {
+            let data = match ggml_dtype {
+                GgmlDType::Q4_1 => weight.to_q4_1(),
+                _ => weight.to_q8_0(),
+            };
+            let q_weight =
+                qtensor_from_ggml(ggml_dtype, &data, vec![out_dim, in_dim], vb.device())?;
+            Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                q_weight: Arc::new(q_weight),
+                b: bias.map(|b| b.to_dtype(DType::F32)).transpose()?,
+            })?)
+        }
+        // No GGUF type matches the weight, so it is kept unquantized
+        None => Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+            Linear::new(weight.dequantize(vb.device(), vb.dtype())?, bias),
+        ))?),
+    };
+    Ok(layer)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{quantized::ggml_file::qtensor_from_ggml, quantized::GgmlDType, Device};
+
+    use super::TorchaoWeight;
+
+    fn weight(qdata: Vec<i16>, zero_point: Option<Vec<f32>>) -> TorchaoWeight {
+        TorchaoWeight {
+            qdata,
+            scale: vec![0.5, 0.25],
+            zero_point,
+            out_dim: 1,
+            in_dim: 64,
+            group_size: 32,
+        }
+    }
+
+    fn assert_lossless(weight: &TorchaoWeight, ggml_dtype: GgmlDType, data: &[u8]) {
+        let expected = weight
+            .dequantize(&Device::Cpu, candle_core::DType::F32)
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        let q_weight = qtensor_from_ggml(ggml_dtype, data, vec![1, 64], &Device::Cpu).unwrap();
+        let actual = q_weight
+            .dequantize(&Device::Cpu)
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_repack_int4() {
+        let qdata = (0..64).map(|i| i % 16 - 8).collect();
+        let weight = weight(qdata, Some(vec![1., -2.]));
+        assert!(weight.fits_in_4_bits());
+        assert_lossless(&weight, GgmlDType::Q4_1, &weight.to_q4_1());
+    }
+
+    #[test]
+    fn test_repack_int8() {
+        let qdata = (0..64).map(|i| i * 4 - 128).collect();
+        let weight = weight(qdata, None);
+        assert!(!weight.fits_in_4_bits());
+        assert_lossless(&weight, GgmlDType::Q8_0, &weight.to_q8_0());
+    }
+}
@@ -13,8 +13,8 @@ use crate::{
     generate_isq, generate_isq_imatrix,
     hqq::{HqqAxis, HqqBits, HqqConfig, HqqLayer, ISQ_HQQ_DEFAULT_OPT_STEPS, ISQ_HQQ_GROUP_SIZE},
     utils::{deserialize_tensor, serialize_tensor, version_is_compatible, UQFF_VERSION},
-    AfqBits, AfqGroupSize, AfqLayer, FP8Linear, GgufMatMul, ImatrixLayerStats, IsqType, MatMul,
-    QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde, QuantizedSerdeType,
+    AfqBits, AfqGroupSize, AfqLayer, FP8Linear, GgufMatMul, GptqLayer, ImatrixLayerStats, IsqType,
+    MatMul, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedSerde, QuantizedSerdeType,
 };
 
 #[derive(Debug)]
@@ -156,8 +156,7 @@ impl QuantMethod for UnquantLinear {
         guard: QuantizeOntoGuard,
     ) -> Result<Arc<dyn QuantMethod>> {
         match dtype {
-            /*Some(IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | */
-            Some(IsqType::HQQ4 | IsqType::HQQ8) => {
+            Some(IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | IsqType::HQQ4 | IsqType::HQQ8) => {
                 let _acquired_quantize_guard = guard.acquire(&device);
                 if imatrix_weight.is_some() {
                     // TODO just warn?
@@ -168,9 +167,9 @@ impl QuantMethod for UnquantLinear {
                 let bits = match dtype.unwrap() {
                     IsqType::HQQ8 => HqqBits::Eight,
                     IsqType::HQQ4 => HqqBits::Four,
-                    // IsqType::HQQ3 => HqqBits::Three,
-                    // IsqType::HQQ2 => HqqBits::Two,
-                    // IsqType::HQQ1 => HqqBits::One,
+                    IsqType::HQQ3 => HqqBits::Three,
+                    IsqType::HQQ2 => HqqBits::Two,
+                    IsqType::HQQ1 => HqqBits::One,
                     _ => unreachable!(),
                 };
                 let cfg = HqqConfig {
@@ -243,6 +242,35 @@ impl QuantMethod for UnquantLinear {
                         .map(|b| b.to_dtype(DType::F32).unwrap().to_device(&device).unwrap()),
                 })?))
             }
+            Some(IsqType::MARLIN4) => {
+                let (out_dim, in_dim) = self.w.dims2()?;
+                if !device.is_cuda() || !GptqLayer::marlin4_supports_shape(out_dim, in_dim) {
+                    // The Marlin kernels cannot run this layer
+                    return self.apply_isq(
+                        Some(IsqType::Q4K),
+                        device,
+                        n_quantized,
+                        imatrix_weight,
+                        guard,
+                    );
+                }
+                let _acquired_quantize_guard = guard.acquire(&device);
+                if imatrix_weight.is_some() {
+                    // TODO just warn?
+                    candle_core::bail!("MARLIN4 does not support imatrix.");
+                }
+
+                n_quantized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let b = if let Some(b) = &self.b {
+                    Some(b.to_device(&device)?)
+                } else {
+                    None
+                };
+                Ok(Arc::new(GptqLayer::quantize_marlin4(
+                    &self.w.to_device(&device)?,
+                    b,
+                )?))
+            }
             Some(IsqType::F8E4M3) => {
                 let _acquired_quantize_guard = guard.acquire(&device);
                 if imatrix_weight.is_some() {
@@ -30,7 +30,7 @@ pub(crate) fn apply_immediate_isq_always(
     if let Some(ImmediateIsqParams {
         guard,
         ty: Some(immediate_isq),
-        predicates: _,
+        ..
     }) = get_immediate_isq()
     {
         layer.clone().apply_isq(
@@ -6,11 +6,11 @@ mod ops;
 mod uqff;
 
 pub use ops::{BitWiseOp, CumSumOp, LeftshiftOp, NonZeroOp, SortOp};
-pub use uqff::UQFF_QUANT_TYPE_OFFSET;
 pub(crate) use uqff::{
     deserialize_tensor, fake_deserialize_tensor, read_dtype, serialize_tensor,
     version_is_compatible, write_dtype, UQFF_VERSION,
 };
+pub use uqff::{read_uqff_layer_header, UqffLayerHeader, UQFF_QUANT_TYPE_OFFSET};
 
 #[cfg(feature = "cuda")]
 use candle_core::{
@@ -1,9 +1,13 @@
+use std::{borrow::Cow, io::Cursor};
+
 use byteorder::{LittleEndian, ReadBytesExt};
 
 use candle_core::{DType, Device, Result, Tensor, WithDType};
 use float8::F8E4M3;
 use half::{bf16, f16};
 
+use crate::{AfqLayer, GgufMatMul, HqqLayer, IsqType, QuantizedSerdeType};
+
 // v0.1.0: initial release
 // v0.1.1: add i16 dtype
 // v0.1.2: add F8E4M3
@@ -192,6 +196,94 @@ pub(crate) fn deserialize_tensor<R: std::io::Read>(
     }
 }
 
+/// Seek the reader past a tensor, returning its shape.
+fn skip_tensor<R: std::io::Read + std::io::Seek>(buffer: &mut R) -> Result<Vec<usize>> {
+    let data_len = buffer.read_u32::<LittleEndian>()? as usize;
+    let _dtype = read_dtype(buffer)?;
+    let dims = read_dims(buffer)?;
+    buffer.seek_relative(data_len as i64)?;
+    Ok(dims)
+}
+
+fn read_dims<R: std::io::Read>(buffer: &mut R) -> Result<Vec<usize>> {
+    let n_dims = buffer.read_u32::<LittleEndian>()? as usize;
+    let mut dims = Vec::with_capacity(n_dims);
+    for _ in 0..n_dims {
+        dims.push(buffer.read_u32::<LittleEndian>()? as usize)
+    }
+    Ok(dims)
+}
+
+/// The header of a layer serialized to UQFF, read without loading its tensors.
+#[derive(Debug, Clone)]
+pub struct UqffLayerHeader {
+    pub serde_type: QuantizedSerdeType,
+    /// `None` if the weight is not quantized.
+    pub isq_type: Option<IsqType>,
+    /// The shape of the dequantized weight.
+    pub weight_shape: Vec<usize>,
+    pub has_bias: bool,
+}
+
+/// Read the header of a layer serialized to UQFF. This fails if the layer was written by an
+/// incompatible version.
+pub fn read_uqff_layer_header(data: &[u8]) -> Result<UqffLayerHeader> {
+    let mut buffer = Cursor::new(data);
+
+    let version = buffer.read_u32::<LittleEndian>()?;
+    version_is_compatible(version)?;
+    let serde_type = QuantizedSerdeType::try_from(buffer.read_u8()? as usize)?;
+
+    let (isq_type, weight_shape, has_bias) = match serde_type {
+        QuantizedSerdeType::Gguf => {
+            let _len = buffer.read_u32::<LittleEndian>()?;
+            let has_bias = buffer.read_u8()? != 0;
+            let _dtype = buffer.read_u32::<LittleEndian>()?;
+            let weight_shape = read_dims(&mut buffer)?;
+            // F32, F16 and BF16 weights are not an ISQ type.
+            let isq_type = GgufMatMul::get_isq_type_from_uqff(Cow::Borrowed(data)).ok();
+            (isq_type, weight_shape, has_bias)
+        }
+        QuantizedSerdeType::Unquant => {
+            let has_bias = buffer.read_u8()? != 0;
+            (None, skip_tensor(&mut buffer)?, has_bias)
+        }
+        QuantizedSerdeType::Hqq => {
+            let has_bias = buffer.read_u8()? != 0;
+            for _ in 0..3 {
+                skip_tensor(&mut buffer)?;
+            }
+            let weight_shape = read_dims(&mut buffer)?;
+            let isq_type = HqqLayer::get_isq_type_from_uqff(Cow::Borrowed(data))?;
+            (Some(isq_type), weight_shape, has_bias)
+        }
+        QuantizedSerdeType::Fp8 => {
+            let has_bias = buffer.read_u8()? != 0;
+            (Some(IsqType::F8E4M3), skip_tensor(&mut buffer)?, has_bias)
+        }
+        QuantizedSerdeType::Afq => {
+            let has_bias = buffer.read_u8()? != 0;
+            let mut weight_shape = skip_tensor(&mut buffer)?;
+            skip_tensor(&mut buffer)?;
+            skip_tensor(&mut buffer)?;
+            let bits = buffer.read_u8()? as usize;
+            // The weight is packed into u32s.
+            if let Some(cols) = weight_shape.last_mut() {
+                *cols = *cols * 32 / bits;
+            }
+            let isq_type = AfqLayer::get_isq_type_from_uqff(Cow::Borrowed(data))?;
+            (Some(isq_type), weight_shape, has_bias)
+        }
+    };
+
+    Ok(UqffLayerHeader {
+        serde_type,
+        isq_type,
+        weight_shape,
+        has_bias,
+    })
+}
+
 /// Just seek the reader ahead.
 pub(crate) fn fake_deserialize_tensor<R: std::io::Read + std::io::Seek>(
     buffer: &mut R,
@@ -256,3 +348,27 @@ fn bytes_to_data<T: WithDType>(
         Tensor::from_slice(&c, shape, device)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Result, Tensor};
+    use candle_nn::Linear;
+
+    use crate::{QuantMethod, QuantMethodConfig, QuantizedSerde, UnquantLinear};
+
+    use super::read_uqff_layer_header;
+
+    #[test]
+    fn reads_unquantized_layer_header() -> Result<()> {
+        let w = Tensor::zeros((8, 4), DType::F32, &Device::Cpu)?;
+        let b = Tensor::zeros(8, DType::F32, &Device::Cpu)?;
+        let layer = UnquantLinear::new(QuantMethodConfig::Unquantized(Linear::new(w, Some(b))))?;
+        let data = layer.serialize()?;
+
+        let header = read_uqff_layer_header(&data)?;
+        assert!(header.isq_type.is_none());
+        assert_eq!(header.weight_shape, vec![8, 4]);
+        assert!(header.has_bias);
+        Ok(())
+    }
+}
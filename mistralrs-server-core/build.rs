@@ -0,0 +1,7 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/mistralrs.proto");
+        tonic_build::compile_protos("proto/mistralrs.proto").expect("Failed to compile protos");
+    }
+}
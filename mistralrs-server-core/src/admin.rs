@@ -0,0 +1,263 @@
+//! ## Admin route handlers, to load and unload models while the server runs.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Extension, Json, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::ModelSelected;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tracing::info;
+
+use crate::{
+    handler_core::{ErrorToResponse, JsonError},
+    health,
+    mistralrs_for_server_builder::{MistralRsForServerBuilder, ModelConfig},
+    types::ExtractedMistralRsState,
+};
+
+/// The configuration of the admin endpoints.
+#[derive(Clone)]
+pub struct AdminConfig {
+    /// The API key which the admin requests must be authorized with.
+    key: String,
+    /// The settings which models are loaded with, besides those of the load request.
+    builder: MistralRsForServerBuilder,
+}
+
+impl AdminConfig {
+    pub fn new(key: String, builder: MistralRsForServerBuilder) -> Self {
+        Self { key, builder }
+    }
+
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), axum::response::Response> {
+        let authorized = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            // The comparison takes the same time wherever the key differs
+            .is_some_and(|key| bool::from(key.as_bytes().ct_eq(self.key.as_bytes())));
+        if authorized {
+            Ok(())
+        } else {
+            Err(JsonError::new("Invalid admin API key.".to_string())
+                .to_response(StatusCode::UNAUTHORIZED))
+        }
+    }
+
+    /// Check that a request to load `model_id`, when `loaded` are the loaded models, is authorized
+    /// and that the model is not loaded yet.
+    fn check_load(
+        &self,
+        headers: &HeaderMap,
+        loaded: &[String],
+        model_id: &str,
+    ) -> Result<(), axum::response::Response> {
+        self.authorize(headers)?;
+        if loaded.iter().any(|loaded| loaded == model_id) {
+            return Err(
+                JsonError::new(format!("Model {model_id} is already loaded"))
+                    .to_response(StatusCode::CONFLICT),
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that a request to unload `model_id`, when `loaded` are the loaded models, is
+    /// authorized and that the model is loaded.
+    fn check_unload(
+        &self,
+        headers: &HeaderMap,
+        loaded: &[String],
+        model_id: &str,
+    ) -> Result<(), axum::response::Response> {
+        self.authorize(headers)?;
+        if !loaded.iter().any(|loaded| loaded == model_id) {
+            return Err(JsonError::new(format!("Model {model_id} not found"))
+                .to_response(StatusCode::NOT_FOUND));
+        }
+        Ok(())
+    }
+}
+
+/// A request to load a model.
+#[derive(Deserialize)]
+pub struct LoadModelRequest {
+    /// The ID which requests use for the model.
+    pub model_id: String,
+    /// The model selector, as in the multi-model configuration file, such as
+    /// `"Plain": { "model_id": "..." }`.
+    #[serde(flatten)]
+    pub model: ModelSelected,
+    pub chat_template: Option<String>,
+    pub jinja_explicit: Option<String>,
+    pub num_device_layers: Option<Vec<String>>,
+    pub in_situ_quant: Option<String>,
+    /// Enable or disable PagedAttention, instead of the setting of the server.
+    pub paged_attn: Option<bool>,
+    pub paged_attn_gpu_mem: Option<usize>,
+    pub paged_attn_gpu_mem_usage: Option<f32>,
+    pub paged_ctxt_len: Option<usize>,
+    pub paged_attn_block_size: Option<usize>,
+}
+
+/// A request to unload a model.
+#[derive(Deserialize)]
+pub struct UnloadModelRequest {
+    pub model_id: String,
+}
+
+#[derive(Serialize)]
+pub struct AdminModelResponse {
+    pub model_id: String,
+    /// `loaded` or `unloaded`.
+    pub status: &'static str,
+}
+
+/// Load a model and serve it alongside the loaded models.
+pub async fn load_model(
+    State(state): ExtractedMistralRsState,
+    Extension(admin): Extension<Arc<AdminConfig>>,
+    headers: HeaderMap,
+    Json(request): Json<LoadModelRequest>,
+) -> axum::response::Response {
+    let model_id = request.model_id;
+    if let Err(response) = admin.check_load(
+        &headers,
+        &state.list_models().unwrap_or_default(),
+        &model_id,
+    ) {
+        return response;
+    }
+
+    let mut builder = admin
+        .builder
+        .clone()
+        .with_paged_attn_gpu_mem_optional(request.paged_attn_gpu_mem)
+        .with_paged_attn_gpu_mem_usage_optional(request.paged_attn_gpu_mem_usage)
+        .with_paged_ctxt_len_optional(request.paged_ctxt_len)
+        .with_paged_attn_block_size_optional(request.paged_attn_block_size);
+    if request.paged_attn.is_some() {
+        builder = builder.set_paged_attn(request.paged_attn);
+    }
+    let config = ModelConfig {
+        model_id: model_id.clone(),
+        model: request.model,
+        chat_template: request.chat_template,
+        jinja_explicit: request.jinja_explicit,
+        num_device_layers: request.num_device_layers,
+        in_situ_quant: request.in_situ_quant,
+    };
+
+    // Loading blocks for a long time, so it runs on its own thread
+    info!("Loading model {model_id}.");
+    health::set_loading(&model_id, true);
+    let handle = tokio::runtime::Handle::current();
+    let loaded = tokio::task::spawn_blocking(move || {
+        handle.block_on(builder.build_from_model_config(config))
+    })
+    .await;
+    health::set_loading(&model_id, false);
+    let error = match loaded {
+        Ok(Ok(loaded)) => {
+            if let Err(e) = state.add_model_from(model_id.clone(), &loaded, None) {
+                return JsonError::new(e).to_response(StatusCode::CONFLICT);
+            }
+            None
+        }
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(e) => Some(e.to_string()),
+    };
+    if let Some(e) = error {
+        health::record_error(&model_id, e.clone());
+        return JsonError::new(format!("Failed to load model {model_id}: {e}"))
+            .to_response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    info!("Model {model_id} loaded.");
+
+    Json(AdminModelResponse {
+        model_id,
+        status: "loaded",
+    })
+    .into_response()
+}
+
+/// Unload a model, freeing its memory. Its running requests are dropped.
+pub async fn unload_model(
+    State(state): ExtractedMistralRsState,
+    Extension(admin): Extension<Arc<AdminConfig>>,
+    headers: HeaderMap,
+    Json(request): Json<UnloadModelRequest>,
+) -> axum::response::Response {
+    let model_id = request.model_id;
+    if let Err(response) = admin.check_unload(
+        &headers,
+        &state.list_models().unwrap_or_default(),
+        &model_id,
+    ) {
+        return response;
+    }
+    if let Err(e) = state.unload_model(Some(&model_id)).await {
+        return JsonError::new(format!("Failed to unload model {model_id}: {e}"))
+            .to_response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    health::forget(&model_id);
+    info!("Model {model_id} unloaded.");
+
+    Json(AdminModelResponse {
+        model_id,
+        status: "unloaded",
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+
+    use super::AdminConfig;
+    use crate::mistralrs_for_server_builder::MistralRsForServerBuilder;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn rejects_requests_without_the_admin_key() {
+        let admin = AdminConfig::new("sk-admin".to_string(), MistralRsForServerBuilder::new());
+        let loaded = ["llama".to_string()];
+
+        for headers in [
+            HeaderMap::new(),
+            headers("Bearer sk-other"),
+            headers("Bearer sk-admin2"),
+            headers("sk-admin"),
+        ] {
+            let response = admin.check_load(&headers, &loaded, "qwen").unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let response = admin.check_unload(&headers, &loaded, "llama").unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        assert!(admin
+            .check_load(&headers("Bearer sk-admin"), &loaded, "qwen")
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_loading_a_loaded_model() {
+        let admin = AdminConfig::new("sk-admin".to_string(), MistralRsForServerBuilder::new());
+        let loaded = ["llama".to_string()];
+        let headers = headers("Bearer sk-admin");
+
+        let response = admin.check_load(&headers, &loaded, "llama").unwrap_err();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = admin.check_unload(&headers, &loaded, "qwen").unwrap_err();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(admin.check_unload(&headers, &loaded, "llama").is_ok());
+    }
+}
@@ -0,0 +1,312 @@
+//! ## API key authentication, with the policy of each key.
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Request as HttpRequest, State},
+    http::{header::AUTHORIZATION, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use either::Either;
+use mistralrs_core::{Request, RequestMessage, TokenizationRequest};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::mpsc::channel;
+
+use crate::{
+    handler_core::{send_request_with_model, ErrorToResponse, JsonError},
+    tenants::Tenants,
+    types::SharedMistralRsState,
+};
+
+/// The routes which are served without an API key. The admin endpoints have their own key.
+const PUBLIC_ROUTES: [&str; 4] = ["/", "/health", "/healthz", "/readyz"];
+
+/// Whether requests to `path` are served without an API key and without rate limits.
+pub(crate) fn is_public_route(path: &str) -> bool {
+    PUBLIC_ROUTES.contains(&path) || path.starts_with("/admin/")
+}
+
+/// What the requests authorized with an API key may do.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyPolicy {
+    /// The models the key may use. All models may be used if this is not set.
+    pub models: Option<Vec<String>>,
+    /// The maximum number of prompt and generated tokens of a request. Requests without
+    /// `max_tokens` generate up to this limit.
+    pub max_context: Option<usize>,
+    /// The requests per minute, instead of the rate limit of the server.
+    pub requests_per_minute: Option<usize>,
+    /// The prompt and generated tokens per minute, instead of the rate limit of the server.
+    pub tokens_per_minute: Option<usize>,
+    /// The tenant of the requests of the key, which their `x-tenant-id` header may only repeat.
+    pub tenant: Option<String>,
+}
+
+impl ApiKeyPolicy {
+    /// Whether the key may use `model`, where `default` is the default model.
+    pub(crate) fn allows_model(&self, state: &SharedMistralRsState, model: &str) -> bool {
+        if self.models.is_none() {
+            return true;
+        }
+        let default_model = state.get_default_model_id().ok().flatten();
+        self.allows(model, default_model.as_deref())
+    }
+
+    /// Whether the key may use `model`, where `default` is `default_model`.
+    fn allows(&self, model: &str, default_model: Option<&str>) -> bool {
+        let Some(models) = &self.models else {
+            return true;
+        };
+        let model = if model == "default" {
+            match default_model {
+                Some(default_model) => default_model,
+                None => return false,
+            }
+        } else {
+            model
+        };
+        models.iter().any(|allowed| allowed == model)
+    }
+}
+
+/// The API keys which requests must be authorized with, and the policy of each.
+#[derive(Clone)]
+pub struct ApiKeys {
+    policies: HashMap<String, ApiKeyPolicy>,
+}
+
+impl ApiKeys {
+    pub fn new(policies: HashMap<String, ApiKeyPolicy>) -> Self {
+        Self { policies }
+    }
+
+    /// Read the keys from a JSON file which maps each key to its policy, such as
+    /// `{"sk-abc": {"models": ["llama"], "max_context": 8192, "requests_per_minute": 60}}`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the API keys file {}", path.display()))?;
+        let policies = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse the API keys file {}", path.display()))?;
+        Ok(Self::new(policies))
+    }
+
+    /// The policy of `key`, if it is one of the keys.
+    pub(crate) fn policy(&self, key: &str) -> Option<&ApiKeyPolicy> {
+        self.policies.get(key)
+    }
+}
+
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Whether the POST requests to `path` run the model named by their body. Files hold no model,
+/// and the requests of a batch are checked when they run.
+fn selects_model(path: &str) -> bool {
+    !path.starts_with("/v1/files") && !path.starts_with("/v1/batches")
+}
+
+/// The model a request body runs. Bodies without a model, or which are not JSON, run the
+/// `default` model, which is `tenant_default` for the requests of a tenant with a default model.
+fn requested_model(body: &[u8], tenant_default: Option<&str>) -> String {
+    let model = serde_json::from_slice::<Value>(body)
+        .ok()
+        .and_then(|body| body.get("model")?.as_str().map(ToString::to_string))
+        .unwrap_or_else(|| "default".to_string());
+    match tenant_default {
+        Some(tenant_default) if model == "default" => tenant_default.to_string(),
+        _ => model,
+    }
+}
+
+/// Authenticate a request by its API key, rejecting it with 401 if the key is missing or unknown,
+/// and 403 if the key may not use the model of the request. The policy of the key is added to the
+/// extensions of the request, for its maximum context and rate limits.
+pub(crate) async fn authenticate(
+    State((keys, tenants, state)): State<(
+        Arc<ApiKeys>,
+        Option<Arc<Tenants>>,
+        SharedMistralRsState,
+    )>,
+    request: HttpRequest,
+    next: Next,
+) -> axum::response::Response {
+    if is_public_route(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let Some(key) = bearer_token(request.headers()).map(ToString::to_string) else {
+        return JsonError::new("Missing API key.".to_string())
+            .to_response(StatusCode::UNAUTHORIZED);
+    };
+    let Some(policy) = keys.policy(&key).cloned() else {
+        return JsonError::new("Invalid API key.".to_string())
+            .to_response(StatusCode::UNAUTHORIZED);
+    };
+
+    // The model of a request is in its body, which is read and put back
+    let mut request = if policy.models.is_some()
+        && request.method() == Method::POST
+        && selects_model(request.uri().path())
+    {
+        let (parts, body) = request.into_parts();
+        let bytes =
+            match Bytes::from_request(HttpRequest::from_parts(parts.clone(), body), &()).await {
+                Ok(bytes) => bytes,
+                Err(rejection) => return rejection.into_response(),
+            };
+        let tenant_default = policy
+            .tenant
+            .as_deref()
+            .zip(tenants.as_deref())
+            .and_then(|(tenant, tenants)| tenants.default_model(tenant));
+        let model = requested_model(&bytes, tenant_default);
+        if !policy.allows_model(&state, &model) {
+            return forbidden_model(&model);
+        }
+        HttpRequest::from_parts(parts, Body::from(bytes))
+    } else {
+        request
+    };
+    request.extensions_mut().insert(policy);
+    next.run(request).await
+}
+
+pub(crate) fn forbidden_model(model: &str) -> axum::response::Response {
+    JsonError::new(format!("This API key may not use the model `{model}`."))
+        .to_response(StatusCode::FORBIDDEN)
+}
+
+/// Check that `request`, to be sent to `model_id`, fits in the maximum context of `policy`,
+/// counting its prompt tokens. Requests without a maximum number of tokens to generate are
+/// limited to the rest of the context.
+pub(crate) async fn apply_max_context(
+    state: &SharedMistralRsState,
+    request: &mut Request,
+    policy: Option<&ApiKeyPolicy>,
+    model_id: Option<&str>,
+) -> Result<(), axum::response::Response> {
+    let Some(max_context) = policy.and_then(|policy| policy.max_context) else {
+        return Ok(());
+    };
+    let Request::Normal(request) = request else {
+        return Ok(());
+    };
+    let (text, enable_thinking) = match &request.messages {
+        RequestMessage::Chat {
+            messages,
+            enable_thinking,
+        }
+        | RequestMessage::VisionChat {
+            messages,
+            enable_thinking,
+            ..
+        } => (Either::Left(messages.clone()), *enable_thinking),
+        RequestMessage::Completion { text, .. } => (Either::Right(text.clone()), None),
+        RequestMessage::CompletionTokens(tokens) => {
+            return fit_context(request, tokens.len(), max_context)
+        }
+        RequestMessage::ImageGeneration { .. } | RequestMessage::SpeechGeneration { .. } => {
+            return Ok(())
+        }
+    };
+
+    let (tx, mut rx) = channel(1);
+    let tokenize = Request::Tokenize(TokenizationRequest {
+        text,
+        tools: request.tools.clone(),
+        add_generation_prompt: true,
+        add_special_tokens: true,
+        enable_thinking,
+        response: tx,
+    });
+    let prompt_tokens = match send_request_with_model(state, tokenize, model_id).await {
+        Ok(()) => rx.recv().await,
+        Err(e) => {
+            return Err(JsonError::new(e.to_string()).to_response(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    };
+    match prompt_tokens {
+        Some(Ok(tokens)) => fit_context(request, tokens.len(), max_context),
+        Some(Err(e)) => Err(JsonError::new(e.to_string()).to_response(StatusCode::BAD_REQUEST)),
+        None => Err(
+            JsonError::new("No response received from the model.".to_string())
+                .to_response(StatusCode::INTERNAL_SERVER_ERROR),
+        ),
+    }
+}
+
+fn fit_context(
+    request: &mut mistralrs_core::NormalRequest,
+    prompt_tokens: usize,
+    max_context: usize,
+) -> Result<(), axum::response::Response> {
+    let max_len = request.sampling_params.max_len;
+    if prompt_tokens + max_len.unwrap_or(1) > max_context {
+        let requested = match max_len {
+            Some(max_len) => {
+                format!("{prompt_tokens} prompt tokens and {max_len} tokens to generate")
+            }
+            None => format!("{prompt_tokens} prompt tokens"),
+        };
+        return Err(JsonError::new(format!(
+            "The request has {requested}, over the maximum context of {max_context} tokens of this API key."
+        ))
+        .to_response(StatusCode::FORBIDDEN));
+    }
+    request.sampling_params.max_len = Some(max_len.unwrap_or(max_context - prompt_tokens));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{requested_model, ApiKeyPolicy};
+
+    #[test]
+    fn parses_policies() {
+        let policies: HashMap<String, ApiKeyPolicy> = serde_json::from_str(
+            r#"{"sk-a": {"models": ["llama"], "max_context": 4096}, "sk-b": {}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            policies["sk-a"].models.as_deref(),
+            Some(&["llama".to_string()][..])
+        );
+        assert_eq!(policies["sk-a"].max_context, Some(4096));
+        assert!(policies["sk-b"].models.is_none());
+        assert!(serde_json::from_str::<ApiKeyPolicy>(r#"{"model": []}"#).is_err());
+    }
+
+    #[test]
+    fn restricts_requests_without_a_model() {
+        let policy = ApiKeyPolicy {
+            models: Some(vec!["llama".to_string()]),
+            ..Default::default()
+        };
+        let model = requested_model(br#"{"messages": []}"#, None);
+        assert_eq!(model, "default");
+        assert!(!policy.allows(&model, Some("mistral")));
+        assert!(!policy.allows(&model, None));
+        assert!(policy.allows(&model, Some("llama")));
+        assert_eq!(requested_model(br#"{"model": 1}"#, None), "default");
+        assert_eq!(requested_model(b"prompt", None), "default");
+
+        // The requests of a tenant use its default model
+        assert_eq!(requested_model(b"{}", Some("llama")), "llama");
+        assert_eq!(
+            requested_model(br#"{"model": "mistral"}"#, Some("llama")),
+            "mistral"
+        );
+    }
+}
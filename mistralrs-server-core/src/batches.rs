@@ -0,0 +1,670 @@
+//! ## Batch API functionality and route handlers.
+//!
+//! A batch runs the requests of an uploaded JSONL file in the background, and stores their
+//! responses in an output file. Its requests are only sent to a model while no other sequences
+//! wait to run on it, so interactive requests go ahead of them.
+
+use std::{
+    collections::HashSet,
+    sync::{LazyLock, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{Extension, Json, Path, Query, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use indexmap::IndexMap;
+use mistralrs_core::{Request, StatsRequest};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+use crate::{
+    auth::{forbidden_model, ApiKeyPolicy},
+    chat_completion::chatcompletions,
+    completions::completions,
+    embeddings::embeddings,
+    files::{file_content, store_file, unix_timestamp, RETENTION_SECS},
+    handler_core::{resource_owner, ErrorToResponse, JsonError, REQUEST_ID_HEADER},
+    openai::{
+        BatchCreateRequest, BatchError, BatchErrors, BatchListResponse, BatchObject,
+        BatchRequestCounts, ChatCompletionRequest, CompletionRequest,
+    },
+    rate_limit::{usage_tokens, TokenCharger},
+    types::{ExtractedMistralRsState, SharedMistralRsState},
+};
+
+/// The endpoints which the requests of a batch can use.
+const BATCH_ENDPOINTS: [&str; 3] = ["/v1/chat/completions", "/v1/completions", "/v1/embeddings"];
+
+/// The requests of a batch which are sent to the model at once.
+const MAX_IN_FLIGHT: usize = 8;
+
+/// How often to check whether the model is free to run another request of a batch.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct StoredBatch {
+    batch: BatchObject,
+    /// The tenant or API key which created the batch, the only one which may access it.
+    owner: Option<String>,
+}
+
+/// The batches, in the order they were created, kept until they finish and expire.
+static BATCHES: LazyLock<RwLock<IndexMap<String, StoredBatch>>> = LazyLock::new(Default::default);
+
+/// A line of the input file of a batch.
+#[derive(Debug, Deserialize)]
+struct BatchInputLine {
+    custom_id: String,
+    method: String,
+    url: String,
+    body: Value,
+}
+
+/// Parse the input file of a batch, checking that every request uses `endpoint` and has a unique
+/// `custom_id`.
+fn parse_input(content: &[u8], endpoint: &str) -> Result<Vec<BatchInputLine>, Vec<BatchError>> {
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    let mut custom_ids = HashSet::new();
+    let text = String::from_utf8_lossy(content);
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut error = |code: &str, message: String| {
+            errors.push(BatchError {
+                code: code.to_string(),
+                message,
+                line: Some(i + 1),
+            })
+        };
+        let line: BatchInputLine = match serde_json::from_str(line) {
+            Ok(line) => line,
+            Err(e) => {
+                error("invalid_json_line", format!("Invalid request: {e}"));
+                continue;
+            }
+        };
+        if line.method != "POST" {
+            error(
+                "invalid_method",
+                format!("The method must be `POST`, not `{}`.", line.method),
+            );
+        } else if line.url != endpoint {
+            error(
+                "mismatched_endpoint",
+                format!(
+                    "The URL `{}` does not match the endpoint `{endpoint}` of the batch.",
+                    line.url
+                ),
+            );
+        } else if !custom_ids.insert(line.custom_id.clone()) {
+            error(
+                "duplicate_custom_id",
+                format!("The custom_id `{}` is used more than once.", line.custom_id),
+            );
+        } else {
+            lines.push(line);
+        }
+    }
+    if lines.is_empty() && errors.is_empty() {
+        errors.push(BatchError {
+            code: "empty_file".to_string(),
+            message: "The input file contains no requests.".to_string(),
+            line: None,
+        });
+    }
+    if errors.is_empty() {
+        Ok(lines)
+    } else {
+        Err(errors)
+    }
+}
+
+fn update_batch(batch_id: &str, f: impl FnOnce(&mut BatchObject)) {
+    if let Some(stored) = BATCHES.write().unwrap().get_mut(batch_id) {
+        f(&mut stored.batch);
+    }
+}
+
+fn is_cancelling(batch_id: &str) -> bool {
+    BATCHES
+        .read()
+        .unwrap()
+        .get(batch_id)
+        .is_some_and(|stored| stored.batch.status == "cancelling")
+}
+
+/// Delete the batches which have finished and expired.
+fn evict_expired_batches() {
+    let now = unix_timestamp();
+    BATCHES.write().unwrap().retain(|_, stored| {
+        let finished = matches!(
+            stored.batch.status.as_str(),
+            "failed" | "completed" | "cancelled"
+        );
+        !finished || stored.batch.expires_at > now
+    });
+}
+
+/// Wait until no sequences wait to run on the model, so the request of a batch goes behind them.
+async fn wait_for_idle(state: &SharedMistralRsState, model_id: Option<&str>) {
+    loop {
+        let Ok(sender) = state.get_sender(model_id) else {
+            return;
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        if sender
+            .send(Request::Stats(StatsRequest { response: tx }))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        match rx.recv().await {
+            Some(stats) if stats.num_waiting > 0 => tokio::time::sleep(IDLE_POLL_INTERVAL).await,
+            _ => return,
+        }
+    }
+}
+
+/// Run one request of a batch through the handler of its endpoint, returning the status code,
+/// request ID and body of the response.
+async fn run_request(
+    state: SharedMistralRsState,
+    endpoint: &str,
+    body: Value,
+    headers: HeaderMap,
+    policy: Option<ApiKeyPolicy>,
+) -> Result<(StatusCode, Option<String>, Value)> {
+    let model = body
+        .get("model")
+        .and_then(Value::as_str)
+        .unwrap_or("default")
+        .to_string();
+    if policy
+        .as_ref()
+        .is_some_and(|policy| !policy.allows_model(&state, &model))
+    {
+        return response_parts(forbidden_model(&model)).await;
+    }
+    let response = match endpoint {
+        "/v1/chat/completions" => {
+            let mut request: ChatCompletionRequest = serde_json::from_value(body)?;
+            request.stream = Some(false);
+            chatcompletions(State(state), headers, policy.map(Extension), Json(request)).await
+        }
+        "/v1/completions" => {
+            let mut request: CompletionRequest = serde_json::from_value(body)?;
+            request.stream = Some(false);
+            completions(State(state), headers, policy.map(Extension), Json(request)).await
+        }
+        "/v1/embeddings" => embeddings(State(state), Json(serde_json::from_value(body)?))
+            .await
+            .into_response(),
+        _ => anyhow::bail!("Unsupported batch endpoint `{endpoint}`"),
+    };
+    response_parts(response).await
+}
+
+/// The status code, request ID and body of a response.
+async fn response_parts(
+    response: axum::response::Response,
+) -> Result<(StatusCode, Option<String>, Value)> {
+    let status = response.status();
+    let request_id = response
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(ToString::to_string);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+    let body = serde_json::from_slice(&bytes)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+    Ok((status, request_id, body))
+}
+
+/// Run the requests of a batch, then store their responses and finish the batch. With `charger`,
+/// each request waits until the client has tokens left, and their tokens are charged to it.
+async fn run_batch(
+    state: SharedMistralRsState,
+    batch_id: String,
+    endpoint: String,
+    lines: Vec<BatchInputLine>,
+    headers: HeaderMap,
+    policy: Option<ApiKeyPolicy>,
+    charger: Option<TokenCharger>,
+    owner: Option<String>,
+) {
+    let mut in_flight = JoinSet::new();
+    let mut outputs = Vec::new();
+    let mut errors = Vec::new();
+    let mut record = |custom_id: String, result: Result<(StatusCode, Option<String>, Value)>| {
+        let (line, succeeded) = match result {
+            Ok((status, request_id, body)) => (
+                json!({
+                    "id": format!("batch_req_{}", Uuid::new_v4().simple()),
+                    "custom_id": custom_id,
+                    "response": {
+                        "status_code": status.as_u16(),
+                        "request_id": request_id,
+                        "body": body,
+                    },
+                    "error": null,
+                }),
+                status.is_success(),
+            ),
+            Err(e) => (
+                json!({
+                    "id": format!("batch_req_{}", Uuid::new_v4().simple()),
+                    "custom_id": custom_id,
+                    "response": null,
+                    "error": { "code": "invalid_request", "message": e.to_string() },
+                }),
+                false,
+            ),
+        };
+        if succeeded {
+            outputs.push(line);
+        } else {
+            errors.push(line);
+        }
+        update_batch(&batch_id, |batch| {
+            if succeeded {
+                batch.request_counts.completed += 1;
+            } else {
+                batch.request_counts.failed += 1;
+            }
+        });
+    };
+
+    for line in lines {
+        if is_cancelling(&batch_id) {
+            break;
+        }
+        while in_flight.len() >= MAX_IN_FLIGHT {
+            if let Some(Ok((custom_id, result))) = in_flight.join_next().await {
+                record(custom_id, result);
+            }
+        }
+        let model_id = line
+            .body
+            .get("model")
+            .and_then(Value::as_str)
+            .filter(|model| *model != "default")
+            .map(ToString::to_string);
+        wait_for_idle(&state, model_id.as_deref()).await;
+        if let Some(wait) = charger.as_ref().and_then(TokenCharger::wait) {
+            tokio::time::sleep(wait).await;
+        }
+
+        let state = state.clone();
+        let endpoint = endpoint.clone();
+        let headers = headers.clone();
+        let policy = policy.clone();
+        let charger = charger.clone();
+        in_flight.spawn(async move {
+            let result = run_request(state, &endpoint, line.body, headers, policy).await;
+            // The handlers are called directly, so the rate limiter does not see their responses
+            if let (Some(charger), Ok((_, _, body))) = (&charger, &result) {
+                if let Some(usage) = usage_tokens(body) {
+                    charger.charge(usage.total_tokens);
+                }
+            }
+            (line.custom_id, result)
+        });
+    }
+    while let Some(joined) = in_flight.join_next().await {
+        if let Ok((custom_id, result)) = joined {
+            record(custom_id, result);
+        }
+    }
+
+    update_batch(&batch_id, |batch| {
+        batch.finalizing_at = Some(unix_timestamp());
+        if batch.status != "cancelling" {
+            batch.status = "finalizing".to_string();
+        }
+    });
+    let store = |lines: Vec<Value>, name: &str| {
+        (!lines.is_empty()).then(|| {
+            let content = lines
+                .iter()
+                .map(|line| format!("{line}\n"))
+                .collect::<String>();
+            let filename = format!("{batch_id}_{name}.jsonl");
+            store_file(
+                filename,
+                "batch_output",
+                Bytes::from(content),
+                owner.clone(),
+            )
+            .id
+        })
+    };
+    let output_file_id = store(outputs, "output");
+    let error_file_id = store(errors, "error");
+    update_batch(&batch_id, |batch| {
+        batch.output_file_id = output_file_id;
+        batch.error_file_id = error_file_id;
+        if batch.status == "cancelling" {
+            batch.status = "cancelled".to_string();
+            batch.cancelled_at = Some(unix_timestamp());
+        } else {
+            batch.status = "completed".to_string();
+            batch.completed_at = Some(unix_timestamp());
+        }
+    });
+}
+
+fn store_batch(batch: BatchObject, owner: Option<String>) {
+    evict_expired_batches();
+    BATCHES
+        .write()
+        .unwrap()
+        .insert(batch.id.clone(), StoredBatch { batch, owner });
+}
+
+fn not_found(batch_id: &str) -> axum::response::Response {
+    JsonError::new(format!("Batch with ID '{batch_id}' not found"))
+        .to_response(StatusCode::NOT_FOUND)
+}
+
+/// Create batch endpoint
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/batches",
+    request_body = BatchCreateRequest,
+    responses((status = 200, description = "Batch object", body = BatchObject))
+)]
+pub async fn create_batch(
+    State(state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    charger: Option<Extension<TokenCharger>>,
+    Json(request): Json<BatchCreateRequest>,
+) -> axum::response::Response {
+    let policy = policy.map(|Extension(policy)| policy);
+    let owner = resource_owner(&headers, policy.as_ref());
+    let Some(content) = file_content(&request.input_file_id, &owner) else {
+        return JsonError::new(format!(
+            "File with ID '{}' not found",
+            request.input_file_id
+        ))
+        .to_response(StatusCode::NOT_FOUND);
+    };
+    if !BATCH_ENDPOINTS.contains(&request.endpoint.as_str()) {
+        return JsonError::new(format!(
+            "Unsupported endpoint `{}`, expected one of {}.",
+            request.endpoint,
+            BATCH_ENDPOINTS.join(", ")
+        ))
+        .to_response(StatusCode::BAD_REQUEST);
+    }
+
+    let now = unix_timestamp();
+    let mut batch = BatchObject {
+        id: format!("batch_{}", Uuid::new_v4().simple()),
+        object: "batch".to_string(),
+        endpoint: request.endpoint.clone(),
+        errors: None,
+        input_file_id: request.input_file_id,
+        completion_window: request.completion_window,
+        status: "validating".to_string(),
+        output_file_id: None,
+        error_file_id: None,
+        created_at: now,
+        expires_at: now + RETENTION_SECS,
+        in_progress_at: None,
+        finalizing_at: None,
+        completed_at: None,
+        failed_at: None,
+        cancelling_at: None,
+        cancelled_at: None,
+        request_counts: BatchRequestCounts::default(),
+        metadata: request.metadata,
+    };
+    match parse_input(&content, &request.endpoint) {
+        Ok(lines) => {
+            batch.status = "in_progress".to_string();
+            batch.in_progress_at = Some(now);
+            batch.request_counts.total = lines.len();
+            store_batch(batch.clone(), owner.clone());
+
+            // The requests of the batch count towards the tenant of the API key which created it
+            let mut request_headers = HeaderMap::new();
+            if let Some(key) = headers.get(AUTHORIZATION) {
+                request_headers.insert(AUTHORIZATION, key.clone());
+            }
+            tokio::spawn(run_batch(
+                state,
+                batch.id.clone(),
+                request.endpoint,
+                lines,
+                request_headers,
+                policy,
+                charger.map(|Extension(charger)| charger),
+                owner,
+            ));
+        }
+        Err(errors) => {
+            batch.status = "failed".to_string();
+            batch.failed_at = Some(now);
+            batch.errors = Some(BatchErrors {
+                object: "list".to_string(),
+                data: errors,
+            });
+            store_batch(batch.clone(), owner);
+        }
+    }
+    Json(batch).into_response()
+}
+
+/// Get batch by ID endpoint
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/v1/batches/{batch_id}",
+    params(("batch_id" = String, Path, description = "The ID of the batch to retrieve")),
+    responses(
+        (status = 200, description = "Batch object", body = BatchObject),
+        (status = 404, description = "The client has no batch with this ID")
+    )
+)]
+pub async fn retrieve_batch(
+    State(_state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    Path(batch_id): Path<String>,
+) -> axum::response::Response {
+    evict_expired_batches();
+    let owner = resource_owner(&headers, policy.as_deref());
+    match BATCHES.read().unwrap().get(&batch_id) {
+        Some(stored) if stored.owner == owner => Json(stored.batch.clone()).into_response(),
+        _ => not_found(&batch_id),
+    }
+}
+
+/// Cancel batch endpoint
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/batches/{batch_id}/cancel",
+    params(("batch_id" = String, Path, description = "The ID of the batch to cancel")),
+    responses(
+        (status = 200, description = "Batch object", body = BatchObject),
+        (status = 404, description = "The client has no batch with this ID")
+    )
+)]
+pub async fn cancel_batch(
+    State(_state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    Path(batch_id): Path<String>,
+) -> axum::response::Response {
+    evict_expired_batches();
+    let owner = resource_owner(&headers, policy.as_deref());
+    let mut batches = BATCHES.write().unwrap();
+    let Some(batch) = batches
+        .get_mut(&batch_id)
+        .filter(|stored| stored.owner == owner)
+        .map(|stored| &mut stored.batch)
+    else {
+        return not_found(&batch_id);
+    };
+    if batch.status != "in_progress" && batch.status != "cancelling" {
+        return JsonError::new(format!(
+            "Batch with ID '{batch_id}' cannot be cancelled, as it is {}",
+            batch.status
+        ))
+        .to_response(StatusCode::CONFLICT);
+    }
+    // The requests which were sent finish, and the others are not run
+    if batch.status == "in_progress" {
+        batch.status = "cancelling".to_string();
+        batch.cancelling_at = Some(unix_timestamp());
+    }
+    Json(batch.clone()).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct BatchListQuery {
+    /// List the batches created before the batch with this ID.
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+/// List batches endpoint
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/v1/batches",
+    params(
+        ("after" = Option<String>, Query, description = "List the batches created before the batch with this ID"),
+        ("limit" = Option<usize>, Query, description = "The number of batches to list, 20 by default")
+    ),
+    responses((status = 200, description = "The batches of the client, newest first", body = BatchListResponse))
+)]
+pub async fn list_batches(
+    State(_state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    Query(query): Query<BatchListQuery>,
+) -> impl IntoResponse {
+    evict_expired_batches();
+    let owner = resource_owner(&headers, policy.as_deref());
+    let limit = query.limit.unwrap_or(20);
+    let batches = BATCHES.read().unwrap();
+    let newest_first = batches
+        .values()
+        .rev()
+        .filter(|stored| stored.owner == owner)
+        .map(|stored| &stored.batch);
+    let mut listed = match &query.after {
+        Some(after) => newest_first
+            .skip_while(|batch| batch.id != *after)
+            .skip(1)
+            .cloned()
+            .collect::<Vec<_>>(),
+        None => newest_first.cloned().collect(),
+    };
+    let has_more = listed.len() > limit;
+    listed.truncate(limit);
+    Json(BatchListResponse {
+        object: "list".to_string(),
+        first_id: listed.first().map(|batch| batch.id.clone()),
+        last_id: listed.last().map(|batch| batch.id.clone()),
+        data: listed,
+        has_more,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{evict_expired_batches, parse_input, store_batch, BATCHES};
+    use crate::openai::BatchObject;
+
+    #[test]
+    fn only_finished_batches_expire() {
+        let batch = |id: &str, status: &str| -> BatchObject {
+            serde_json::from_value(json!({
+                "id": id,
+                "object": "batch",
+                "endpoint": "/v1/completions",
+                "errors": null,
+                "input_file_id": "file-a",
+                "completion_window": "24h",
+                "status": status,
+                "output_file_id": null,
+                "error_file_id": null,
+                "created_at": 0,
+                "expires_at": 0,
+                "in_progress_at": null,
+                "finalizing_at": null,
+                "completed_at": null,
+                "failed_at": null,
+                "cancelling_at": null,
+                "cancelled_at": null,
+                "request_counts": {"total": 1, "completed": 0, "failed": 0},
+                "metadata": null,
+            }))
+            .unwrap()
+        };
+        store_batch(batch("batch_expired_running", "in_progress"), None);
+        store_batch(batch("batch_expired_done", "completed"), None);
+        evict_expired_batches();
+        let batches = BATCHES.read().unwrap();
+        assert!(batches.contains_key("batch_expired_running"));
+        assert!(!batches.contains_key("batch_expired_done"));
+    }
+
+    #[test]
+    fn parses_batch_input() {
+        let input = concat!(
+            r#"{"custom_id": "a", "method": "POST", "url": "/v1/completions", "body": {"prompt": "Hi"}}"#,
+            "\n\n",
+            r#"{"custom_id": "b", "method": "POST", "url": "/v1/completions", "body": {"prompt": "Bye"}}"#,
+            "\n",
+        );
+        let lines = parse_input(input.as_bytes(), "/v1/completions").unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].custom_id, "b");
+        assert_eq!(lines[1].body["prompt"], "Bye");
+    }
+
+    #[test]
+    fn rejects_invalid_batch_input() {
+        let input = [
+            r#"{"custom_id": "a", "method": "POST", "url": "/v1/completions", "body": {}}"#,
+            r#"{"custom_id": "a", "method": "POST", "url": "/v1/completions", "body": {}}"#,
+            r#"{"custom_id": "b", "method": "POST", "url": "/v1/embeddings", "body": {}}"#,
+            "not json",
+        ]
+        .join("\n");
+        let errors = parse_input(input.as_bytes(), "/v1/completions").unwrap_err();
+        let codes = errors
+            .iter()
+            .map(|e| (e.code.as_str(), e.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            [
+                ("duplicate_custom_id", Some(2)),
+                ("mismatched_endpoint", Some(3)),
+                ("invalid_json_line", Some(4)),
+            ]
+        );
+        assert_eq!(
+            parse_input(b"\n", "/v1/completions").unwrap_err()[0].code,
+            "empty_file"
+        );
+    }
+}
@@ -4,8 +4,8 @@ use std::{ops::Deref, pin::Pin, task::Poll, time::Duration};
 
 use anyhow::{Context, Result};
 use axum::{
-    extract::{Json, State},
-    http::{self},
+    extract::{Extension, Json, State},
+    http::{self, HeaderMap},
     response::{
         sse::{Event, KeepAlive},
         IntoResponse, Sse,
@@ -22,13 +22,15 @@ use serde_json::Value;
 use tokio::sync::mpsc::{Receiver, Sender};
 
 use crate::{
+    auth::{apply_max_context, ApiKeyPolicy},
     completion_core::{
-        convert_stop_tokens, get_dry_sampling_params, handle_completion_error,
-        BaseCompletionResponder,
+        convert_adapters, convert_stop_tokens, get_dry_sampling_params, get_xtc_sampling_params,
+        handle_completion_error, BaseCompletionResponder,
     },
     handler_core::{
-        base_process_non_streaming_response, create_response_channel, send_request_with_model,
-        BaseJsonModelError, ErrorToResponse, JsonError, ModelErrorMessage,
+        base_process_non_streaming_response, create_response_channel, internal_error_status,
+        request_id_headers, send_request_with_model, set_tenant_from_api_key, BaseJsonModelError,
+        ErrorToResponse, JsonError, ModelErrorMessage,
     },
     openai::{
         ChatCompletionRequest, Grammar, JsonSchemaResponseFormat, MessageInnerContent,
@@ -180,7 +182,7 @@ impl IntoResponse for ChatCompletionResponder {
             ChatCompletionResponder::Json(s) => Json(s).into_response(),
             ChatCompletionResponder::InternalError(e) => {
                 JsonError::new(sanitize_error_message(e.as_ref()))
-                    .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .to_response(internal_error_status(e.as_ref()))
             }
             ChatCompletionResponder::ValidationError(e) => {
                 JsonError::new(sanitize_error_message(e.as_ref()))
@@ -194,6 +196,36 @@ impl IntoResponse for ChatCompletionResponder {
     }
 }
 
+/// The constraint which makes the output follow a `response_format`: any JSON object for
+/// `json_object`, and the schema for `json_schema`. The constraint is enforced while decoding, so
+/// the output parses against the schema unless it is cut short by `max_tokens`.
+fn response_format_constraint(format: ResponseFormat) -> Result<Constraint> {
+    match format {
+        ResponseFormat::Text => Ok(Constraint::None),
+        ResponseFormat::JsonObject => Ok(Constraint::JsonSchema(
+            serde_json::json!({ "type": "object" }),
+        )),
+        ResponseFormat::JsonSchema {
+            json_schema: JsonSchemaResponseFormat { name, schema, .. },
+        } => {
+            if name.is_empty()
+                || name.len() > 64
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                anyhow::bail!(
+                    "The `response_format` schema name `{name}` must have 1 to 64 letters, digits, underscores, or dashes."
+                );
+            }
+            if !schema.is_object() && !schema.is_boolean() {
+                anyhow::bail!("The `response_format` schema must be a JSON object.");
+            }
+            Ok(Constraint::JsonSchema(schema))
+        }
+    }
+}
+
 /// Parses and validates a chat completion request.
 ///
 /// This function transforms an OpenAI-compatible chat completion request into the
@@ -432,6 +464,7 @@ pub async fn parse_request(
         oairequest.dry_base,
         oairequest.dry_allowed_length,
     )?;
+    let xtc_params = get_xtc_sampling_params(oairequest.xtc_probability, oairequest.xtc_threshold);
 
     let is_streaming = oairequest.stream.unwrap_or(false);
 
@@ -439,16 +472,25 @@ pub async fn parse_request(
         anyhow::bail!("Request `grammar` and `response_format` were both provided but are mutually exclusive.")
     }
 
+    // A stop sequence could end the output in the middle of the JSON
+    if stop_toks.is_some()
+        && matches!(
+            oairequest.response_format,
+            Some(ResponseFormat::JsonObject | ResponseFormat::JsonSchema { .. })
+        )
+    {
+        anyhow::bail!("Request `stop` can not be used with a JSON `response_format`.")
+    }
+
     let constraint = match oairequest.grammar {
         Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
         Some(Grammar::Lark(lark)) => Constraint::Lark(lark),
+        Some(Grammar::Gbnf(gbnf)) => Constraint::Gbnf(gbnf),
+        Some(Grammar::Choice(choices)) => Constraint::Choice(choices),
         Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema),
         Some(Grammar::Llguidance(llguidance)) => Constraint::Llguidance(llguidance),
         None => match oairequest.response_format {
-            Some(ResponseFormat::JsonSchema {
-                json_schema: JsonSchemaResponseFormat { name: _, schema },
-            }) => Constraint::JsonSchema(schema),
-            Some(ResponseFormat::Text) => Constraint::None,
+            Some(format) => response_format_constraint(format)?,
             None => Constraint::None,
         },
     };
@@ -462,6 +504,7 @@ pub async fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
+                typical_p: oairequest.typical_p,
                 top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
@@ -471,6 +514,16 @@ pub async fn parse_request(
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                xtc_params,
+                banned_strings: oairequest.banned_strings,
+                no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
+                token_healing: oairequest.token_healing.unwrap_or(false),
+                guidance_scale: oairequest.guidance_scale,
+                negative_prompt: oairequest.negative_prompt,
+                penalty_alpha: oairequest.penalty_alpha,
+                seed: oairequest.seed,
+                stop_regexes: oairequest.stop_regexes,
+                include_stop_str_in_output: oairequest.include_stop_str_in_output.unwrap_or(false),
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
@@ -482,11 +535,14 @@ pub async fn parse_request(
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: oairequest.web_search_options,
+            adapters: convert_adapters(oairequest.adapters),
             model_id: if oairequest.model == "default" {
                 None
             } else {
                 Some(oairequest.model.clone())
             },
+            tenant: oairequest.user,
+            deadline: None,
         })),
         is_streaming,
     ))
@@ -502,8 +558,10 @@ pub async fn parse_request(
 )]
 pub async fn chatcompletions(
     State(state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
     Json(oairequest): Json<ChatCompletionRequest>,
-) -> ChatCompletionResponder {
+) -> axum::response::Response {
     let (tx, mut rx) = create_response_channel(None);
 
     // Extract model_id for routing before parsing
@@ -513,20 +571,28 @@ pub async fn chatcompletions(
         Some(oairequest.model.clone())
     };
 
-    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
+    let (mut request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
         Ok(x) => x,
-        Err(e) => return handle_error(state, e.into()),
+        Err(e) => return handle_error(state, e.into()).into_response(),
     };
+    set_tenant_from_api_key(&mut request, &headers);
+    if let Err(response) =
+        apply_max_context(&state, &mut request, policy.as_deref(), model_id.as_deref()).await
+    {
+        return response;
+    }
+    let request_id = request_id_headers(&request, &headers);
 
     if let Err(e) = send_request_with_model(&state, request, model_id.as_deref()).await {
-        return handle_error(state, e.into());
+        return handle_error(state, e.into()).into_response();
     }
 
-    if is_streaming {
+    let responder = if is_streaming {
         ChatCompletionResponder::Sse(create_streamer(rx, state, None, None))
     } else {
         process_non_streaming_response(&mut rx, state).await
-    }
+    };
+    (request_id, responder).into_response()
 }
 
 /// Handle route / generation errors and logging them.
@@ -585,3 +651,48 @@ pub fn match_responses(state: SharedMistralRsState, response: Response) -> ChatC
         Response::Raw { .. } => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::Constraint;
+    use serde_json::json;
+
+    use super::response_format_constraint;
+    use crate::openai::{ResponseFormat, ResponsesTextConfig};
+
+    #[test]
+    fn response_formats_become_constraints() {
+        let format = |format| serde_json::from_value::<ResponseFormat>(format).unwrap();
+
+        let schema = json!({"type": "object", "properties": {"a": {"type": "integer"}}});
+        let constraint = response_format_constraint(format(json!({
+            "type": "json_schema",
+            "json_schema": {"name": "answer", "schema": schema, "strict": true},
+        })))
+        .unwrap();
+        assert!(matches!(constraint, Constraint::JsonSchema(value) if value == schema));
+        assert!(matches!(
+            response_format_constraint(format(json!({"type": "json_object"}))).unwrap(),
+            Constraint::JsonSchema(value) if value == json!({"type": "object"})
+        ));
+        assert!(matches!(
+            response_format_constraint(format(json!({"type": "text"}))).unwrap(),
+            Constraint::None
+        ));
+        assert!(response_format_constraint(format(json!({
+            "type": "json_schema",
+            "json_schema": {"name": "an answer", "schema": {}},
+        })))
+        .is_err());
+
+        // The `text.format` of the Responses API has the fields of the schema at its top level
+        let text = serde_json::from_value::<ResponsesTextConfig>(json!({
+            "format": {"type": "json_schema", "name": "answer", "schema": schema},
+        }))
+        .unwrap();
+        assert!(matches!(
+            response_format_constraint(text.format.unwrap().into()).unwrap(),
+            Constraint::JsonSchema(value) if value == schema
+        ));
+    }
+}
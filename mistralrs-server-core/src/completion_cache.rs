@@ -0,0 +1,311 @@
+//! ## Cache of the responses of identical chat completion and completion requests.
+//!
+//! A request is identified by its endpoint and its canonical body, which has the model, messages
+//! or prompt, and sampling parameters including the seed. Only deterministic requests which are
+//! not streamed are cached, those with a seed or greedy sampling, and only their successful
+//! responses.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{FromRequest, Request as HttpRequest, State},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::IntoResponse,
+};
+use serde_json::Value;
+
+use crate::auth::ApiKeyPolicy;
+
+/// The response header which is `hit` for the responses from the cache, and `miss` for those
+/// which were generated.
+pub const CACHE_HEADER: &str = "x-cache";
+
+/// The endpoints whose responses are cached.
+const CACHED_ROUTES: [&str; 2] = ["/v1/chat/completions", "/v1/completions"];
+
+/// The keys of a request which do not change its response.
+const IGNORED_KEYS: [&str; 2] = ["stream", "user"];
+
+/// The limits of the cache.
+#[derive(Clone, Copy, Debug)]
+pub struct CompletionCacheConfig {
+    /// How long a response is served from the cache.
+    pub ttl: Duration,
+    /// The most responses which are cached. The least recently used are evicted.
+    pub max_entries: usize,
+}
+
+struct CacheEntry {
+    body: Bytes,
+    content_type: Option<HeaderValue>,
+    inserted: Instant,
+    last_used: Instant,
+}
+
+/// The cached responses, by the key of their request.
+pub(crate) struct CompletionCache {
+    config: CompletionCacheConfig,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl CompletionCache {
+    pub(crate) fn new(config: CompletionCacheConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &str, now: Instant) -> Option<(Bytes, Option<HeaderValue>)> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        if now.saturating_duration_since(entry.inserted) > self.config.ttl {
+            entries.remove(key);
+            return None;
+        }
+        entry.last_used = now;
+        Some((entry.body.clone(), entry.content_type.clone()))
+    }
+
+    fn insert(&self, key: String, body: Bytes, content_type: Option<HeaderValue>, now: Instant) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.config.ttl;
+        entries.retain(|_, entry| now.saturating_duration_since(entry.inserted) <= ttl);
+        if entries.len() >= self.config.max_entries && !entries.contains_key(&key) {
+            let least_recent = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recent) = least_recent {
+                entries.remove(&least_recent);
+            }
+        }
+        entries.insert(
+            key,
+            CacheEntry {
+                body,
+                content_type,
+                inserted: now,
+                last_used: now,
+            },
+        );
+    }
+}
+
+/// Write `value` as JSON with the keys of its objects sorted.
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(object) => {
+            let mut keys = object.keys().collect::<Vec<_>>();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write!(out, "{}:", Value::from(key.as_str())).unwrap();
+                write_canonical(&object[key], out);
+            }
+            out.push('}');
+        }
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(value, out);
+            }
+            out.push(']');
+        }
+        value => write!(out, "{value}").unwrap(),
+    }
+}
+
+/// Whether the response to the request `body` only depends on it: it has a `seed`, or samples
+/// greedily with a `temperature` of 0 or a `top_k` of 1. Other requests sample a new response each
+/// time.
+fn is_deterministic(body: &serde_json::Map<String, Value>) -> bool {
+    let seeded = body.get("seed").is_some_and(|seed| !seed.is_null());
+    let greedy = body.get("temperature").and_then(Value::as_f64) == Some(0.0)
+        || body.get("top_k").and_then(Value::as_u64) == Some(1);
+    seeded || greedy
+}
+
+/// The key of a request to `path` with `body`, or `None` if it is streamed or not deterministic.
+/// The maximum context of the API key is part of the key, as it limits the tokens to generate.
+fn cache_key(path: &str, body: &Value, max_context: Option<usize>) -> Option<String> {
+    let object = body.as_object()?;
+    if object.get("stream").and_then(Value::as_bool) == Some(true) || !is_deterministic(object) {
+        return None;
+    }
+    let mut object = object.clone();
+    for key in IGNORED_KEYS {
+        object.remove(key);
+    }
+    let mut key = format!("{path} {max_context:?} ");
+    write_canonical(&Value::Object(object), &mut key);
+    Some(key)
+}
+
+/// Serve the responses of identical requests from the cache, unless the request has a
+/// `Cache-Control: no-cache` or `no-store` header.
+pub(crate) async fn cache_completions(
+    State(cache): State<Arc<CompletionCache>>,
+    request: HttpRequest,
+    next: Next,
+) -> axum::response::Response {
+    let path = request.uri().path().to_string();
+    let bypass = request
+        .headers()
+        .get(CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("no-cache") || value.contains("no-store"));
+    if request.method() != Method::POST || !CACHED_ROUTES.contains(&path.as_str()) || bypass {
+        return next.run(request).await;
+    }
+
+    let max_context = request
+        .extensions()
+        .get::<ApiKeyPolicy>()
+        .and_then(|policy| policy.max_context);
+    let (parts, body) = request.into_parts();
+    let bytes = match Bytes::from_request(HttpRequest::from_parts(parts.clone(), body), &()).await {
+        Ok(bytes) => bytes,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let key = serde_json::from_slice::<Value>(&bytes)
+        .ok()
+        .and_then(|body| cache_key(&path, &body, max_context));
+    let request = HttpRequest::from_parts(parts, Body::from(bytes));
+    let Some(key) = key else {
+        return next.run(request).await;
+    };
+
+    if let Some((body, content_type)) = cache.get(&key, Instant::now()) {
+        let mut response = Body::from(body).into_response();
+        if let Some(content_type) = content_type {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        response
+            .headers_mut()
+            .insert(CACHE_HEADER, HeaderValue::from_static("hit"));
+        return response;
+    }
+
+    let response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    cache.insert(
+        key,
+        body.clone(),
+        parts.headers.get(CONTENT_TYPE).cloned(),
+        Instant::now(),
+    );
+    parts
+        .headers
+        .insert(CACHE_HEADER, HeaderValue::from_static("miss"));
+    axum::response::Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use axum::body::Bytes;
+    use serde_json::json;
+
+    use super::{cache_key, CompletionCache, CompletionCacheConfig};
+
+    #[test]
+    fn identical_requests_have_one_key() {
+        let a = json!({"model": "llama", "messages": [{"role": "user", "content": "Hi"}], "seed": 1, "user": "a"});
+        let b =
+            json!({"seed": 1, "messages": [{"content": "Hi", "role": "user"}], "model": "llama"});
+        assert_eq!(
+            cache_key("/v1/chat/completions", &a, None),
+            cache_key("/v1/chat/completions", &b, None)
+        );
+        assert_ne!(
+            cache_key("/v1/chat/completions", &a, None),
+            cache_key(
+                "/v1/chat/completions",
+                &json!({"model": "llama", "seed": 2}),
+                None
+            )
+        );
+        assert_ne!(
+            cache_key("/v1/chat/completions", &a, None),
+            cache_key("/v1/chat/completions", &a, Some(4096))
+        );
+        assert_eq!(
+            cache_key(
+                "/v1/completions",
+                &json!({"prompt": "Hi", "stream": true}),
+                None
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn only_deterministic_requests_are_cached() {
+        let path = "/v1/chat/completions";
+        let messages = json!([{"role": "user", "content": "Hi"}]);
+        assert_eq!(cache_key(path, &json!({"messages": messages}), None), None);
+        assert_eq!(
+            cache_key(
+                path,
+                &json!({"messages": messages, "temperature": 0.7, "seed": null}),
+                None
+            ),
+            None
+        );
+        assert!(cache_key(path, &json!({"messages": messages, "seed": 7}), None).is_some());
+        assert!(cache_key(path, &json!({"messages": messages, "temperature": 0}), None).is_some());
+        assert!(cache_key(path, &json!({"messages": messages, "top_k": 1}), None).is_some());
+    }
+
+    #[test]
+    fn entries_expire_and_are_evicted() {
+        let cache = CompletionCache::new(CompletionCacheConfig {
+            ttl: Duration::from_secs(60),
+            max_entries: 2,
+        });
+        let start = Instant::now();
+        cache.insert("a".to_string(), Bytes::from("1"), None, start);
+        cache.insert("b".to_string(), Bytes::from("2"), None, start);
+        assert!(cache.get("a", start + Duration::from_secs(1)).is_some());
+        // `b` is the least recently used
+        cache.insert(
+            "c".to_string(),
+            Bytes::from("3"),
+            None,
+            start + Duration::from_secs(2),
+        );
+        assert!(cache.get("b", start + Duration::from_secs(2)).is_none());
+        assert!(cache.get("a", start + Duration::from_secs(2)).is_some());
+        assert!(cache.get("c", start + Duration::from_secs(63)).is_none());
+    }
+}
@@ -4,9 +4,15 @@ use std::error::Error;
 
 use anyhow::Result;
 use axum::response::Sse;
-use mistralrs_core::{DrySamplingParams, MistralRs, StopTokens as InternalStopTokens};
+use mistralrs_core::{
+    DrySamplingParams, MistralRs, StopTokens as InternalStopTokens, XtcSamplingParams,
+};
 
-use crate::{openai::StopTokens, types::SharedMistralRsState, util::sanitize_error_message};
+use crate::{
+    openai::{AdapterSelection, StopTokens},
+    types::SharedMistralRsState,
+    util::sanitize_error_message,
+};
 
 /// Generic responder enum for different completion types.
 #[derive(Debug)]
@@ -48,6 +54,22 @@ pub(crate) fn convert_stop_tokens(stop_seqs: Option<StopTokens>) -> Option<Inter
     }
 }
 
+/// Helper function to convert from the OpenAI adapter selection to the adapters and weights of a
+/// request.
+pub(crate) fn convert_adapters(
+    adapters: Option<Vec<AdapterSelection>>,
+) -> Option<Vec<(String, f64)>> {
+    adapters.map(|adapters| {
+        adapters
+            .into_iter()
+            .map(|adapter| match adapter {
+                AdapterSelection::Name(name) => (name, 1.0),
+                AdapterSelection::Weighted { name, weight } => (name, weight),
+            })
+            .collect()
+    })
+}
+
 /// Helper function to get the dry sampling params.
 pub(crate) fn get_dry_sampling_params(
     dry_multiplier: Option<f32>,
@@ -68,3 +90,12 @@ pub(crate) fn get_dry_sampling_params(
         None => Ok(None),
     }
 }
+
+/// Helper function to get the XTC sampling params.
+pub(crate) fn get_xtc_sampling_params(
+    xtc_probability: Option<f32>,
+    xtc_threshold: Option<f32>,
+) -> Option<XtcSamplingParams> {
+    xtc_probability
+        .map(|probability| XtcSamplingParams::new_with_defaults(probability, xtc_threshold))
+}
@@ -9,8 +9,8 @@ use std::{
 
 use anyhow::Result;
 use axum::{
-    extract::{Json, State},
-    http::{self},
+    extract::{Extension, Json, State},
+    http::{self, HeaderMap},
     response::{
         sse::{Event, KeepAlive},
         IntoResponse, Sse,
@@ -21,16 +21,17 @@ use mistralrs_core::{
     RequestMessage, Response, SamplingParams,
 };
 use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::warn;
 
 use crate::{
+    auth::{apply_max_context, ApiKeyPolicy},
     completion_core::{
-        convert_stop_tokens, get_dry_sampling_params, handle_completion_error,
-        BaseCompletionResponder,
+        convert_adapters, convert_stop_tokens, get_dry_sampling_params, get_xtc_sampling_params,
+        handle_completion_error, BaseCompletionResponder,
     },
     handler_core::{
-        base_process_non_streaming_response, create_response_channel, send_request,
-        BaseJsonModelError, ErrorToResponse, JsonError, ModelErrorMessage,
+        base_process_non_streaming_response, create_response_channel, internal_error_status,
+        request_id_headers, send_request, set_tenant_from_api_key, BaseJsonModelError,
+        ErrorToResponse, JsonError, ModelErrorMessage,
     },
     openai::{CompletionRequest, Grammar},
     streaming::{base_create_streamer, get_keep_alive_interval, BaseStreamer, DoneState},
@@ -173,7 +174,7 @@ impl IntoResponse for CompletionResponder {
             CompletionResponder::Json(s) => Json(s).into_response(),
             CompletionResponder::InternalError(e) => {
                 JsonError::new(sanitize_error_message(e.as_ref()))
-                    .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .to_response(internal_error_status(e.as_ref()))
             }
             CompletionResponder::ValidationError(e) => {
                 JsonError::new(sanitize_error_message(e.as_ref()))
@@ -202,10 +203,6 @@ pub fn parse_request(
 
     let stop_toks = convert_stop_tokens(oairequest.stop_seqs);
 
-    if oairequest.logprobs.is_some() {
-        warn!("Completion requests do not support logprobs.");
-    }
-
     let is_streaming = oairequest.stream.unwrap_or(false);
 
     let dry_params = get_dry_sampling_params(
@@ -214,6 +211,7 @@ pub fn parse_request(
         oairequest.dry_base,
         oairequest.dry_allowed_length,
     )?;
+    let xtc_params = get_xtc_sampling_params(oairequest.xtc_probability, oairequest.xtc_threshold);
 
     Ok((
         Request::Normal(Box::new(NormalRequest {
@@ -228,7 +226,8 @@ pub fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
-                top_n_logprobs: 1,
+                typical_p: oairequest.typical_p,
+                top_n_logprobs: oairequest.logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
                 repetition_penalty: oairequest.repetition_penalty,
@@ -237,14 +236,26 @@ pub fn parse_request(
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                xtc_params,
+                banned_strings: oairequest.banned_strings,
+                no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
+                token_healing: oairequest.token_healing.unwrap_or(false),
+                guidance_scale: oairequest.guidance_scale,
+                negative_prompt: oairequest.negative_prompt,
+                penalty_alpha: oairequest.penalty_alpha,
+                seed: oairequest.seed,
+                stop_regexes: oairequest.stop_regexes,
+                include_stop_str_in_output: oairequest.include_stop_str_in_output.unwrap_or(false),
             },
             response: tx,
-            return_logprobs: false,
+            return_logprobs: oairequest.logprobs.is_some(),
             is_streaming,
             suffix: oairequest.suffix,
             constraint: match oairequest.grammar {
                 Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
                 Some(Grammar::Lark(lark)) => Constraint::Lark(lark),
+                Some(Grammar::Gbnf(gbnf)) => Constraint::Gbnf(gbnf),
+                Some(Grammar::Choice(choices)) => Constraint::Choice(choices),
                 Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema),
                 Some(Grammar::Llguidance(llguidance)) => Constraint::Llguidance(llguidance),
                 None => Constraint::None,
@@ -254,11 +265,14 @@ pub fn parse_request(
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            adapters: convert_adapters(oairequest.adapters),
             model_id: if oairequest.model == "default" {
                 None
             } else {
                 Some(oairequest.model.clone())
             },
+            tenant: oairequest.user,
+            deadline: None,
         })),
         is_streaming,
     ))
@@ -274,24 +288,32 @@ pub fn parse_request(
 )]
 pub async fn completions(
     State(state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
     Json(oairequest): Json<CompletionRequest>,
-) -> CompletionResponder {
+) -> axum::response::Response {
     let (tx, mut rx) = create_response_channel(None);
 
-    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx) {
+    let (mut request, is_streaming) = match parse_request(oairequest, state.clone(), tx) {
         Ok(x) => x,
-        Err(e) => return handle_error(state, e.into()),
+        Err(e) => return handle_error(state, e.into()).into_response(),
     };
+    set_tenant_from_api_key(&mut request, &headers);
+    if let Err(response) = apply_max_context(&state, &mut request, policy.as_deref(), None).await {
+        return response;
+    }
+    let request_id = request_id_headers(&request, &headers);
 
     if let Err(e) = send_request(&state, request).await {
-        return handle_error(state, e.into());
+        return handle_error(state, e.into()).into_response();
     }
 
-    if is_streaming {
+    let responder = if is_streaming {
         CompletionResponder::Sse(create_streamer(rx, state, None, None))
     } else {
         process_non_streaming_response(&mut rx, state).await
-    }
+    };
+    (request_id, responder).into_response()
 }
 
 /// Handle route / generation errors and logging them.
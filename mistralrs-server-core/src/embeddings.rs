@@ -0,0 +1,188 @@
+//! ## Embeddings functionality and route handler.
+
+use std::error::Error;
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Json, State},
+    http,
+    response::IntoResponse,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use either::Either;
+use mistralrs_core::{MistralRs, Request, TokenizationRequest};
+use tokio::sync::mpsc::channel;
+
+use crate::{
+    handler_core::{internal_error_status, send_request_with_model, ErrorToResponse, JsonError},
+    openai::{
+        EmbeddingData, EmbeddingEncodingFormat, EmbeddingInput, EmbeddingRequest,
+        EmbeddingResponse, EmbeddingUsage, EmbeddingVector,
+    },
+    types::{ExtractedMistralRsState, SharedMistralRsState},
+    util::{sanitize_error_message, validate_model_name},
+};
+
+const NO_RESPONSE: &str = "No response received from the model.";
+
+/// Represents different types of embeddings responses.
+pub enum EmbeddingResponder {
+    Json(EmbeddingResponse),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+impl IntoResponse for EmbeddingResponder {
+    /// Converts the embeddings responder into an HTTP response.
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            EmbeddingResponder::Json(response) => Json(response).into_response(),
+            EmbeddingResponder::InternalError(e) => {
+                JsonError::new(sanitize_error_message(e.as_ref()))
+                    .to_response(internal_error_status(e.as_ref()))
+            }
+            EmbeddingResponder::ValidationError(e) => {
+                JsonError::new(sanitize_error_message(e.as_ref()))
+                    .to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+/// Embeddings endpoint handler.
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/embeddings",
+    request_body = EmbeddingRequest,
+    responses((status = 200, description = "Embeddings", body = EmbeddingResponse))
+)]
+pub async fn embeddings(
+    State(state): ExtractedMistralRsState,
+    Json(oairequest): Json<EmbeddingRequest>,
+) -> EmbeddingResponder {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    if let Err(e) = validate_model_name(&oairequest.model, state.clone()) {
+        return handle_error(state, e.into());
+    }
+    let model_id = (oairequest.model != "default").then_some(oairequest.model.as_str());
+
+    let texts = match oairequest.input {
+        EmbeddingInput::Single(text) => vec![text],
+        EmbeddingInput::Multi(texts) => texts,
+    };
+    if texts.is_empty() {
+        return EmbeddingResponder::ValidationError(Box::new(JsonError::new(
+            "`input` must contain at least one text.".to_string(),
+        )));
+    }
+
+    let prompt_tokens = match count_tokens(&state, &texts, model_id).await {
+        Ok(tokens) => tokens,
+        Err(e) => return handle_error(state, e.into()),
+    };
+    let embeddings = match embed(&state, texts, model_id).await {
+        Ok(embeddings) => embeddings,
+        Err(e) => return handle_error(state, e.into()),
+    };
+
+    let data = embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| EmbeddingData {
+            object: "embedding".to_string(),
+            index,
+            embedding: encode_embedding(embedding, oairequest.encoding_format),
+        })
+        .collect();
+    EmbeddingResponder::Json(EmbeddingResponse {
+        object: "list".to_string(),
+        data,
+        model: oairequest.model,
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    })
+}
+
+fn encode_embedding(embedding: Vec<f32>, format: EmbeddingEncodingFormat) -> EmbeddingVector {
+    match format {
+        EmbeddingEncodingFormat::Float => EmbeddingVector::Float(embedding),
+        EmbeddingEncodingFormat::Base64 => {
+            let bytes = embedding
+                .iter()
+                .flat_map(|x| x.to_le_bytes())
+                .collect::<Vec<_>>();
+            EmbeddingVector::Base64(STANDARD.encode(bytes))
+        }
+    }
+}
+
+/// Helper function to handle embeddings errors and logging them.
+/// Count the tokens which the model splits `texts` into, the usage of embedding them.
+pub(crate) async fn count_tokens(
+    state: &SharedMistralRsState,
+    texts: &[String],
+    model_id: Option<&str>,
+) -> Result<usize> {
+    let mut tokens = 0;
+    for text in texts {
+        let (tx, mut rx) = channel(1);
+        let request = Request::Tokenize(TokenizationRequest {
+            text: Either::Right(text.clone()),
+            tools: None,
+            add_generation_prompt: false,
+            add_special_tokens: true,
+            enable_thinking: None,
+            response: tx,
+        });
+        send_request_with_model(state, request, model_id).await?;
+        tokens += rx.recv().await.context(NO_RESPONSE)??.len();
+    }
+    Ok(tokens)
+}
+
+/// Embed `texts` with the model.
+pub(crate) async fn embed(
+    state: &SharedMistralRsState,
+    texts: Vec<String>,
+    model_id: Option<&str>,
+) -> Result<Vec<Vec<f32>>> {
+    let (tx, mut rx) = channel(1);
+    let request = Request::Embedding(mistralrs_core::EmbeddingRequest {
+        texts,
+        response: tx,
+    });
+    send_request_with_model(state, request, model_id).await?;
+    rx.recv().await.context(NO_RESPONSE)?
+}
+
+pub fn handle_error(
+    state: SharedMistralRsState,
+    e: Box<dyn std::error::Error + Send + Sync + 'static>,
+) -> EmbeddingResponder {
+    let sanitized_msg = sanitize_error_message(&*e);
+    let e = anyhow::Error::msg(sanitized_msg);
+    MistralRs::maybe_log_error(state, &*e);
+    EmbeddingResponder::InternalError(e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode_embedding;
+    use crate::openai::{EmbeddingEncodingFormat, EmbeddingVector};
+
+    #[test]
+    fn encodes_base64_as_little_endian_floats() {
+        let EmbeddingVector::Base64(encoded) =
+            encode_embedding(vec![1.0, -2.0], EmbeddingEncodingFormat::Base64)
+        else {
+            panic!("Expected a base64 embedding");
+        };
+        // 1.0 is 0x3F800000 and -2.0 is 0xC0000000
+        assert_eq!(encoded, "AACAPwAAAMA=");
+    }
+}
@@ -0,0 +1,283 @@
+//! ## File storage and route handlers, used for the input and output of batches.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Extension, Json, Multipart, Path, State},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use uuid::Uuid;
+
+use crate::{
+    auth::ApiKeyPolicy,
+    handler_core::{resource_owner, ErrorToResponse, JsonError},
+    openai::{FileDeleteResponse, FileListResponse, FileObject},
+    types::ExtractedMistralRsState,
+};
+
+/// How long files and finished batches are kept, 30 days.
+pub(crate) const RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+
+struct StoredFile {
+    object: FileObject,
+    content: Bytes,
+    /// The tenant or API key which created the file, the only one which may access it.
+    owner: Option<String>,
+}
+
+/// The uploaded files and the outputs of batches, kept in memory until they expire.
+static FILES: LazyLock<RwLock<HashMap<String, StoredFile>>> = LazyLock::new(Default::default);
+
+pub(crate) fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time travel has occurred!")
+        .as_secs()
+}
+
+/// Delete the files which have expired.
+fn evict_expired_files() {
+    let now = unix_timestamp();
+    FILES
+        .write()
+        .unwrap()
+        .retain(|_, file| file.object.expires_at > now);
+}
+
+/// Store a file of `owner`, returning its file object.
+pub(crate) fn store_file(
+    filename: String,
+    purpose: &str,
+    content: Bytes,
+    owner: Option<String>,
+) -> FileObject {
+    evict_expired_files();
+    let created_at = unix_timestamp();
+    let object = FileObject {
+        id: format!("file-{}", Uuid::new_v4().simple()),
+        object: "file".to_string(),
+        bytes: content.len(),
+        created_at,
+        filename,
+        purpose: purpose.to_string(),
+        expires_at: created_at + RETENTION_SECS,
+    };
+    FILES.write().unwrap().insert(
+        object.id.clone(),
+        StoredFile {
+            object: object.clone(),
+            content,
+            owner,
+        },
+    );
+    object
+}
+
+/// The content of the file `file_id`, if it has not expired and belongs to `owner`.
+pub(crate) fn file_content(file_id: &str, owner: &Option<String>) -> Option<Bytes> {
+    evict_expired_files();
+    FILES
+        .read()
+        .unwrap()
+        .get(file_id)
+        .filter(|file| file.owner == *owner)
+        .map(|file| file.content.clone())
+}
+
+fn not_found(file_id: &str) -> axum::response::Response {
+    JsonError::new(format!("File with ID '{file_id}' not found")).to_response(StatusCode::NOT_FOUND)
+}
+
+/// Upload file endpoint
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/files",
+    request_body(content_type = "multipart/form-data", description = "The `file` to upload and its `purpose`, such as `batch`"),
+    responses((status = 200, description = "File object", body = FileObject))
+)]
+pub async fn upload_file(
+    State(_state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    mut multipart: Multipart,
+) -> axum::response::Response {
+    let mut file = None;
+    let mut purpose = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return JsonError::new(e.body_text()).to_response(e.status()),
+        };
+        match field.name() {
+            Some("file") => {
+                let filename = field.file_name().unwrap_or("file").to_string();
+                match field.bytes().await {
+                    Ok(content) => file = Some((filename, content)),
+                    Err(e) => return JsonError::new(e.body_text()).to_response(e.status()),
+                }
+            }
+            Some("purpose") => match field.text().await {
+                Ok(text) => purpose = Some(text),
+                Err(e) => return JsonError::new(e.body_text()).to_response(e.status()),
+            },
+            _ => {}
+        }
+    }
+
+    let (Some((filename, content)), Some(purpose)) = (file, purpose) else {
+        return JsonError::new("The `file` and `purpose` fields are required.".to_string())
+            .to_response(StatusCode::BAD_REQUEST);
+    };
+    let owner = resource_owner(&headers, policy.as_deref());
+    Json(store_file(filename, &purpose, content, owner)).into_response()
+}
+
+/// List files endpoint
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/v1/files",
+    responses((status = 200, description = "The files of the client", body = FileListResponse))
+)]
+pub async fn list_files(
+    State(_state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+) -> impl IntoResponse {
+    evict_expired_files();
+    let owner = resource_owner(&headers, policy.as_deref());
+    let mut data = FILES
+        .read()
+        .unwrap()
+        .values()
+        .filter(|file| file.owner == owner)
+        .map(|file| file.object.clone())
+        .collect::<Vec<_>>();
+    data.sort_by_key(|file| std::cmp::Reverse(file.created_at));
+    Json(FileListResponse {
+        object: "list".to_string(),
+        data,
+    })
+}
+
+/// Get file by ID endpoint
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/v1/files/{file_id}",
+    params(("file_id" = String, Path, description = "The ID of the file to retrieve")),
+    responses(
+        (status = 200, description = "File object", body = FileObject),
+        (status = 404, description = "The client has no file with this ID")
+    )
+)]
+pub async fn retrieve_file(
+    State(_state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    Path(file_id): Path<String>,
+) -> axum::response::Response {
+    evict_expired_files();
+    let owner = resource_owner(&headers, policy.as_deref());
+    match FILES.read().unwrap().get(&file_id) {
+        Some(file) if file.owner == owner => Json(file.object.clone()).into_response(),
+        _ => not_found(&file_id),
+    }
+}
+
+/// Get file content endpoint
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/v1/files/{file_id}/content",
+    params(("file_id" = String, Path, description = "The ID of the file to download")),
+    responses(
+        (status = 200, description = "The content of the file"),
+        (status = 404, description = "The client has no file with this ID")
+    )
+)]
+pub async fn retrieve_file_content(
+    State(_state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    Path(file_id): Path<String>,
+) -> axum::response::Response {
+    let owner = resource_owner(&headers, policy.as_deref());
+    match file_content(&file_id, &owner) {
+        Some(content) => ([(CONTENT_TYPE, "application/octet-stream")], content).into_response(),
+        None => not_found(&file_id),
+    }
+}
+
+/// Delete file endpoint
+#[utoipa::path(
+    delete,
+    tag = "Mistral.rs",
+    path = "/v1/files/{file_id}",
+    params(("file_id" = String, Path, description = "The ID of the file to delete")),
+    responses(
+        (status = 200, description = "File deleted", body = FileDeleteResponse),
+        (status = 404, description = "The client has no file with this ID")
+    )
+)]
+pub async fn delete_file(
+    State(_state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    Path(file_id): Path<String>,
+) -> axum::response::Response {
+    evict_expired_files();
+    let owner = resource_owner(&headers, policy.as_deref());
+    let mut files = FILES.write().unwrap();
+    if !files.get(&file_id).is_some_and(|file| file.owner == owner) {
+        return not_found(&file_id);
+    }
+    match files.remove(&file_id) {
+        Some(_) => Json(FileDeleteResponse {
+            id: file_id,
+            object: "file".to_string(),
+            deleted: true,
+        })
+        .into_response(),
+        None => not_found(&file_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+
+    use super::{evict_expired_files, file_content, store_file, FILES};
+
+    #[test]
+    fn files_belong_to_their_owner_until_they_expire() {
+        let owner = Some("tenant-a".to_string());
+        let file = store_file(
+            "input.jsonl".to_string(),
+            "batch",
+            Bytes::from("{}"),
+            owner.clone(),
+        );
+        assert_eq!(file_content(&file.id, &owner), Some(Bytes::from("{}")));
+        assert_eq!(file_content(&file.id, &Some("tenant-b".to_string())), None);
+        assert_eq!(file_content(&file.id, &None), None);
+
+        FILES
+            .write()
+            .unwrap()
+            .get_mut(&file.id)
+            .unwrap()
+            .object
+            .expires_at = 0;
+        evict_expired_files();
+        assert!(!FILES.read().unwrap().contains_key(&file.id));
+    }
+}
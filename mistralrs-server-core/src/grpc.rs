@@ -0,0 +1,570 @@
+//! ## gRPC inference service, served alongside the HTTP API.
+//!
+//! The service is defined in `proto/mistralrs.proto`. It offers unary and server-streaming
+//! generation, and embeddings, to callers which prefer typed contracts over HTTP and SSE. The
+//! requests are authenticated and rate limited as those of the HTTP API.
+
+use std::{net::SocketAddr, pin::Pin, sync::Arc, time::Instant};
+
+use axum::http::StatusCode;
+use either::Either;
+use futures::{stream, Stream};
+use indexmap::IndexMap;
+use mistralrs_core::{
+    NormalRequest, Request, RequestMessage, Response, SamplingParams, StopTokens,
+};
+use tokio::sync::mpsc::Receiver;
+use tonic::{transport::Server, Status};
+
+use crate::{
+    auth::{apply_max_context, bearer_token, ApiKeyPolicy, ApiKeys},
+    embeddings,
+    handler_core::{
+        create_response_channel, error_message, send_request_with_model, set_tenant_from_api_key,
+    },
+    rate_limit::{client_id, RateLimitConfig, RateLimiter},
+    types::SharedMistralRsState,
+};
+
+/// The types and service traits generated from the protobuf definitions.
+pub mod proto {
+    tonic::include_proto!("mistralrs.v1");
+}
+
+use proto::{
+    generate_request::Input,
+    inference_server::{Inference, InferenceServer},
+    ChatMessages, EmbedRequest, EmbedResponse, Embedding, GenerateRequest, GenerateResponse,
+    GenerateStreamResponse,
+};
+
+const NO_RESPONSE: &str = "No response received from the model.";
+
+/// The client of a request whose tokens are rate limited, added to its extensions.
+#[derive(Clone)]
+struct LimitedClient {
+    client: String,
+    tokens_per_minute: usize,
+}
+
+/// The gRPC inference service, which sends its requests to the models of `state`.
+pub struct InferenceService {
+    state: SharedMistralRsState,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl InferenceService {
+    pub fn new(state: SharedMistralRsState) -> Self {
+        Self {
+            state,
+            limiter: None,
+        }
+    }
+
+    /// Charge the tokens of a response to the client of its request.
+    fn charge(&self, client: Option<&LimitedClient>, tokens: usize) {
+        charge(self.limiter.as_deref(), client, tokens);
+    }
+
+    /// Send a generation request, returning the receiver of its responses.
+    async fn send_generate(
+        &self,
+        request: tonic::Request<GenerateRequest>,
+        is_streaming: bool,
+    ) -> Result<Receiver<Response>, Status> {
+        let policy = request.extensions().get::<ApiKeyPolicy>().cloned();
+        let headers = request.metadata().clone().into_headers();
+        let request = request.into_inner();
+        check_model(&self.state, policy.as_ref(), &request.model)?;
+        let messages = request_message(request.input)?;
+        let sampling = request.sampling.unwrap_or_default();
+        let sampling_params = SamplingParams {
+            temperature: sampling.temperature,
+            top_k: sampling.top_k.map(|top_k| top_k as usize),
+            top_p: sampling.top_p,
+            min_p: sampling.min_p,
+            frequency_penalty: sampling.frequency_penalty,
+            presence_penalty: sampling.presence_penalty,
+            repetition_penalty: sampling.repetition_penalty,
+            max_len: sampling.max_tokens.map(|max_tokens| max_tokens as usize),
+            stop_toks: (!sampling.stop.is_empty()).then_some(StopTokens::Seqs(sampling.stop)),
+            seed: sampling.seed,
+            ..SamplingParams::deterministic()
+        };
+
+        let (tx, rx) = create_response_channel(None);
+        let model_id = model_id(&request.model);
+        let mut normal = NormalRequest::new_simple(
+            messages,
+            sampling_params,
+            tx,
+            self.state.next_request_id(),
+            None,
+            None,
+        );
+        normal.is_streaming = is_streaming;
+        normal.model_id = model_id.clone();
+        normal.tenant = policy.as_ref().and_then(|policy| policy.tenant.clone());
+        let mut request = Request::Normal(Box::new(normal));
+        set_tenant_from_api_key(&mut request, &headers);
+        if let Err(response) = apply_max_context(
+            &self.state,
+            &mut request,
+            policy.as_ref(),
+            model_id.as_deref(),
+        )
+        .await
+        {
+            return Err(response_status(response).await);
+        }
+        send_request_with_model(&self.state, request, model_id.as_deref())
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        Ok(rx)
+    }
+}
+
+fn charge(limiter: Option<&RateLimiter>, client: Option<&LimitedClient>, tokens: usize) {
+    if let (Some(limiter), Some(client)) = (limiter, client) {
+        limiter.charge_tokens(
+            &client.client,
+            tokens,
+            client.tokens_per_minute,
+            Instant::now(),
+        );
+    }
+}
+
+/// Check that the API key of a request may use `model`.
+fn check_model(
+    state: &SharedMistralRsState,
+    policy: Option<&ApiKeyPolicy>,
+    model: &str,
+) -> Result<(), Status> {
+    let model = if model.is_empty() { "default" } else { model };
+    if policy.is_some_and(|policy| !policy.allows_model(state, model)) {
+        return Err(Status::permission_denied(format!(
+            "This API key may not use the model `{model}`."
+        )));
+    }
+    Ok(())
+}
+
+/// The status of an error response of the HTTP API.
+async fn response_status(response: axum::response::Response) -> Status {
+    let code = response.status();
+    let message = error_message(response).await;
+    match code {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::FORBIDDEN => Status::permission_denied(message),
+        _ => Status::internal(message),
+    }
+}
+
+/// Authenticate and rate limit a request as the HTTP API does. The policy of its API key, and its
+/// client if its tokens are limited, are added to its extensions.
+fn check_access(
+    api_keys: Option<&ApiKeys>,
+    limiter: Option<&RateLimiter>,
+    mut request: tonic::Request<()>,
+) -> Result<tonic::Request<()>, Status> {
+    let headers = request.metadata().clone().into_headers();
+    let policy = match api_keys {
+        Some(api_keys) => {
+            let Some(key) = bearer_token(&headers) else {
+                return Err(Status::unauthenticated("Missing API key."));
+            };
+            let Some(policy) = api_keys.policy(key) else {
+                return Err(Status::unauthenticated("Invalid API key."));
+            };
+            Some(policy.clone())
+        }
+        None => None,
+    };
+
+    if let Some(limiter) = limiter {
+        let client = client_id(&headers, policy.is_some(), request.remote_addr());
+        let limits = limiter.limits(policy.as_ref());
+        if let Some(wait) = limiter.admit(&client, limits, Instant::now()) {
+            let seconds = (wait.as_secs_f64().ceil() as u64).max(1);
+            return Err(Status::resource_exhausted(format!(
+                "Rate limit exceeded, retry in {seconds} seconds."
+            )));
+        }
+        if let Some(tokens_per_minute) = limits.tokens_per_minute {
+            request.extensions_mut().insert(LimitedClient {
+                client,
+                tokens_per_minute,
+            });
+        }
+    }
+    if let Some(policy) = policy {
+        request.extensions_mut().insert(policy);
+    }
+    Ok(request)
+}
+
+/// The model ID of a request, where an empty ID or `default` is the default model.
+fn model_id(model: &str) -> Option<String> {
+    (!model.is_empty() && model != "default").then(|| model.to_string())
+}
+
+fn request_message(input: Option<Input>) -> Result<RequestMessage, Status> {
+    match input {
+        Some(Input::Prompt(text)) => Ok(RequestMessage::Completion {
+            text,
+            echo_prompt: false,
+            best_of: None,
+        }),
+        Some(Input::Messages(ChatMessages { messages })) => Ok(RequestMessage::Chat {
+            messages: messages
+                .into_iter()
+                .map(|message| {
+                    IndexMap::from([
+                        ("role".to_string(), Either::Left(message.role)),
+                        ("content".to_string(), Either::Left(message.content)),
+                    ])
+                })
+                .collect(),
+            enable_thinking: None,
+        }),
+        None => Err(Status::invalid_argument(
+            "`prompt` or `messages` is required.",
+        )),
+    }
+}
+
+fn usage(usage: &mistralrs_core::Usage) -> proto::Usage {
+    proto::Usage {
+        prompt_tokens: usage.prompt_tokens as u32,
+        completion_tokens: usage.completion_tokens as u32,
+        total_tokens: usage.total_tokens as u32,
+    }
+}
+
+/// The status of a response which is an error.
+fn error_status(response: Response) -> Status {
+    match response {
+        Response::ValidationError(e) => Status::invalid_argument(e.to_string()),
+        Response::InternalError(e) => Status::internal(e.to_string()),
+        Response::ModelError(msg, _) | Response::CompletionModelError(msg, _) => {
+            Status::internal(msg)
+        }
+        _ => Status::internal("Unexpected response type"),
+    }
+}
+
+type GenerateStream = Pin<Box<dyn Stream<Item = Result<GenerateStreamResponse, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Inference for InferenceService {
+    async fn generate(
+        &self,
+        request: tonic::Request<GenerateRequest>,
+    ) -> Result<tonic::Response<GenerateResponse>, Status> {
+        let client = request.extensions().get::<LimitedClient>().cloned();
+        let mut rx = self.send_generate(request, false).await?;
+        let response = match rx.recv().await {
+            Some(Response::Done(response)) => {
+                self.charge(client.as_ref(), response.usage.total_tokens);
+                let choice = response.choices.into_iter().next();
+                GenerateResponse {
+                    id: response.id,
+                    model: response.model,
+                    text: choice
+                        .as_ref()
+                        .and_then(|choice| choice.message.content.clone())
+                        .unwrap_or_default(),
+                    finish_reason: choice
+                        .map(|choice| choice.finish_reason)
+                        .unwrap_or_default(),
+                    usage: Some(usage(&response.usage)),
+                }
+            }
+            Some(Response::CompletionDone(response)) => {
+                self.charge(client.as_ref(), response.usage.total_tokens);
+                let choice = response.choices.into_iter().next();
+                GenerateResponse {
+                    id: response.id,
+                    model: response.model,
+                    text: choice
+                        .as_ref()
+                        .map(|choice| choice.text.clone())
+                        .unwrap_or_default(),
+                    finish_reason: choice
+                        .map(|choice| choice.finish_reason)
+                        .unwrap_or_default(),
+                    usage: Some(usage(&response.usage)),
+                }
+            }
+            Some(response) => return Err(error_status(response)),
+            None => return Err(Status::internal(NO_RESPONSE)),
+        };
+        Ok(tonic::Response::new(response))
+    }
+
+    type GenerateStreamStream = GenerateStream;
+
+    async fn generate_stream(
+        &self,
+        request: tonic::Request<GenerateRequest>,
+    ) -> Result<tonic::Response<Self::GenerateStreamStream>, Status> {
+        let client = request.extensions().get::<LimitedClient>().cloned();
+        let limiter = self.limiter.clone();
+        let rx = self.send_generate(request, true).await?;
+        // The stream ends after the response with a finish reason, or after an error
+        let responses = stream::unfold(Some(rx), move |rx| {
+            let client = client.clone();
+            let limiter = limiter.clone();
+            async move {
+                let mut rx = rx?;
+                let response = match rx.recv().await? {
+                    Response::Chunk(chunk) => {
+                        if let Some(chunk_usage) = &chunk.usage {
+                            charge(
+                                limiter.as_deref(),
+                                client.as_ref(),
+                                chunk_usage.total_tokens,
+                            );
+                        }
+                        let choice = chunk.choices.into_iter().next();
+                        GenerateStreamResponse {
+                            id: chunk.id,
+                            text: choice
+                                .as_ref()
+                                .and_then(|choice| choice.delta.content.clone())
+                                .unwrap_or_default(),
+                            finish_reason: choice.and_then(|choice| choice.finish_reason),
+                            usage: chunk.usage.as_ref().map(usage),
+                        }
+                    }
+                    Response::CompletionChunk(chunk) => {
+                        if let Some(chunk_usage) = &chunk.usage {
+                            charge(
+                                limiter.as_deref(),
+                                client.as_ref(),
+                                chunk_usage.total_tokens,
+                            );
+                        }
+                        let choice = chunk.choices.into_iter().next();
+                        GenerateStreamResponse {
+                            id: chunk.id,
+                            text: choice
+                                .as_ref()
+                                .map(|choice| choice.text.clone())
+                                .unwrap_or_default(),
+                            finish_reason: choice.and_then(|choice| choice.finish_reason),
+                            usage: chunk.usage.as_ref().map(usage),
+                        }
+                    }
+                    response => return Some((Err(error_status(response)), None)),
+                };
+                let rx = response.finish_reason.is_none().then_some(rx);
+                Some((Ok(response), rx))
+            }
+        });
+        Ok(tonic::Response::new(Box::pin(responses)))
+    }
+
+    async fn embed(
+        &self,
+        request: tonic::Request<EmbedRequest>,
+    ) -> Result<tonic::Response<EmbedResponse>, Status> {
+        let client = request.extensions().get::<LimitedClient>().cloned();
+        let policy = request.extensions().get::<ApiKeyPolicy>().cloned();
+        let request = request.into_inner();
+        check_model(&self.state, policy.as_ref(), &request.model)?;
+        if request.inputs.is_empty() {
+            return Err(Status::invalid_argument(
+                "`inputs` must contain at least one text.",
+            ));
+        }
+        let model_id = model_id(&request.model);
+        let prompt_tokens =
+            embeddings::count_tokens(&self.state, &request.inputs, model_id.as_deref())
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+        let embeddings = embeddings::embed(&self.state, request.inputs, model_id.as_deref())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        self.charge(client.as_ref(), prompt_tokens);
+
+        Ok(tonic::Response::new(EmbedResponse {
+            model: model_id.unwrap_or_else(|| "default".to_string()),
+            embeddings: embeddings
+                .into_iter()
+                .map(|values| Embedding { values })
+                .collect(),
+            usage: Some(proto::Usage {
+                prompt_tokens: prompt_tokens as u32,
+                completion_tokens: 0,
+                total_tokens: prompt_tokens as u32,
+            }),
+        }))
+    }
+}
+
+/// The rate limiter of the requests, as the HTTP API builds it: with `rate_limit` or API keys,
+/// whose policies may set the limits of their own.
+fn build_limiter(
+    api_keys: Option<&ApiKeys>,
+    rate_limit: Option<RateLimitConfig>,
+) -> Option<RateLimiter> {
+    (rate_limit.is_some() || api_keys.is_some())
+        .then(|| RateLimiter::new(rate_limit.unwrap_or_default()))
+}
+
+/// Serve the gRPC inference service for the models of `state` on `address`. Requests must be
+/// authorized with one of `api_keys`, as a `Bearer` key in their `authorization` metadata, and
+/// are rate limited by `rate_limit` and the policies of the keys. The buckets of the clients are
+/// not shared with the HTTP API.
+pub async fn serve_grpc(
+    state: SharedMistralRsState,
+    address: SocketAddr,
+    api_keys: Option<ApiKeys>,
+    rate_limit: Option<RateLimitConfig>,
+) -> anyhow::Result<()> {
+    let limiter = build_limiter(api_keys.as_ref(), rate_limit).map(Arc::new);
+    let api_keys = api_keys.map(Arc::new);
+    let service = InferenceService {
+        state,
+        limiter: limiter.clone(),
+    };
+    let interceptor = move |request| check_access(api_keys.as_deref(), limiter.as_deref(), request);
+    Server::builder()
+        .add_service(InferenceServer::with_interceptor(service, interceptor))
+        .serve(address)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use either::Either;
+    use mistralrs_core::RequestMessage;
+    use tonic::Code;
+
+    use super::{
+        build_limiter, check_access, model_id,
+        proto::{generate_request::Input, ChatMessage, ChatMessages},
+        request_message, LimitedClient,
+    };
+    use crate::{
+        auth::{ApiKeyPolicy, ApiKeys},
+        rate_limit::{RateLimitConfig, RateLimiter},
+    };
+
+    fn request(authorization: Option<&str>) -> tonic::Request<()> {
+        let mut request = tonic::Request::new(());
+        if let Some(authorization) = authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn requests_need_an_api_key() {
+        let api_keys = ApiKeys::new(HashMap::from([(
+            "sk-a".to_string(),
+            ApiKeyPolicy {
+                max_context: Some(4096),
+                ..Default::default()
+            },
+        )]));
+
+        for authorization in [None, Some("Bearer sk-b"), Some("sk-a")] {
+            let status = check_access(Some(&api_keys), None, request(authorization)).unwrap_err();
+            assert_eq!(status.code(), Code::Unauthenticated);
+        }
+        let request = check_access(Some(&api_keys), None, request(Some("Bearer sk-a"))).unwrap();
+        let policy = request.extensions().get::<ApiKeyPolicy>().unwrap();
+        assert_eq!(policy.max_context, Some(4096));
+    }
+
+    #[test]
+    fn requests_are_rate_limited() {
+        let api_keys = ApiKeys::new(HashMap::from([(
+            "sk-a".to_string(),
+            ApiKeyPolicy {
+                tokens_per_minute: Some(100),
+                ..Default::default()
+            },
+        )]));
+        let limiter = RateLimiter::new(RateLimitConfig {
+            requests_per_minute: Some(1),
+            tokens_per_minute: None,
+        });
+
+        let admitted = check_access(
+            Some(&api_keys),
+            Some(&limiter),
+            request(Some("Bearer sk-a")),
+        )
+        .unwrap();
+        let client = admitted.extensions().get::<LimitedClient>().unwrap();
+        assert_eq!(client.tokens_per_minute, 100);
+        let status = check_access(
+            Some(&api_keys),
+            Some(&limiter),
+            request(Some("Bearer sk-a")),
+        )
+        .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn api_keys_are_limited_without_server_limits() {
+        let api_keys = ApiKeys::new(HashMap::from([(
+            "sk-a".to_string(),
+            ApiKeyPolicy {
+                requests_per_minute: Some(1),
+                ..Default::default()
+            },
+        )]));
+        assert!(build_limiter(None, None).is_none());
+        let limiter = build_limiter(Some(&api_keys), None).unwrap();
+
+        check_access(
+            Some(&api_keys),
+            Some(&limiter),
+            request(Some("Bearer sk-a")),
+        )
+        .unwrap();
+        let status = check_access(
+            Some(&api_keys),
+            Some(&limiter),
+            request(Some("Bearer sk-a")),
+        )
+        .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn converts_chat_messages() {
+        let input = Input::Messages(ChatMessages {
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content: "Hello!".to_string(),
+            }],
+        });
+        let Ok(RequestMessage::Chat { messages, .. }) = request_message(Some(input)) else {
+            panic!("Expected chat messages");
+        };
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(&messages[0]["role"], Either::Left(role) if role == "user"));
+        assert!(matches!(&messages[0]["content"], Either::Left(content) if content == "Hello!"));
+
+        assert!(request_message(None).is_err());
+    }
+
+    #[test]
+    fn empty_model_is_the_default() {
+        assert_eq!(model_id(""), None);
+        assert_eq!(model_id("default"), None);
+        assert_eq!(model_id("llama").as_deref(), Some("llama"));
+    }
+}
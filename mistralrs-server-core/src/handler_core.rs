@@ -1,12 +1,22 @@
 //! Core functionality for handlers.
 
+use std::{
+    collections::BTreeMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{LazyLock, Mutex},
+};
+
 use anyhow::{Context, Result};
-use axum::{extract::Json, http::StatusCode, response::IntoResponse};
-use mistralrs_core::{Request, Response};
+use axum::{
+    extract::Json,
+    http::{header::AUTHORIZATION, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{Draining, QueueFull, Request, Response};
 use serde::Serialize;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::types::SharedMistralRsState;
+use crate::{auth::ApiKeyPolicy, types::SharedMistralRsState};
 
 /// Default buffer size for the response channel used in streaming operations.
 ///
@@ -25,6 +35,18 @@ pub(crate) trait ErrorToResponse: Serialize {
     }
 }
 
+/// The status code of an internal error: 429 if the request was rejected because the request
+/// queue of the model is full, otherwise 500.
+pub(crate) fn internal_error_status(e: &(dyn std::error::Error + 'static)) -> StatusCode {
+    if e.downcast_ref::<QueueFull>().is_some() {
+        StatusCode::TOO_MANY_REQUESTS
+    } else if e.downcast_ref::<Draining>().is_some() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
 /// Standard JSON error response structure.
 #[derive(Serialize, Debug)]
 pub(crate) struct JsonError {
@@ -86,6 +108,86 @@ pub fn create_response_channel(
     channel(channel_buffer_size)
 }
 
+/// Identifies the client of a request without a tenant by its API key, for fair-share scheduling.
+/// The key is hashed so it is not held by the scheduler.
+pub(crate) fn set_tenant_from_api_key(request: &mut Request, headers: &HeaderMap) {
+    let Request::Normal(request) = request else {
+        return;
+    };
+    if request.tenant.is_some() {
+        return;
+    }
+    request.tenant = api_key_id(headers);
+}
+
+/// The hash of the API key of a request, which identifies its client.
+fn api_key_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|key| key.to_str().ok())
+        .map(|key| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            format!("key-{:016x}", hasher.finish())
+        })
+}
+
+/// The owner of the files and batches created by a request: the tenant of its API key, or else
+/// the key itself.
+pub(crate) fn resource_owner(headers: &HeaderMap, policy: Option<&ApiKeyPolicy>) -> Option<String> {
+    policy
+        .and_then(|policy| policy.tenant.clone())
+        .or_else(|| api_key_id(headers))
+}
+
+/// The message of an error response, for those sent outside of HTTP.
+pub(crate) async fn error_message(response: axum::response::Response) -> String {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap_or_default();
+    serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| body.get("message")?.as_str().map(ToString::to_string))
+        .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned())
+}
+
+/// The response header holding the ID of a request, which can be canceled with
+/// `DELETE /v1/requests/{request_id}`.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The number of the most recent requests whose API keys are kept to authorize their cancellation.
+const MAX_REQUEST_OWNERS: usize = 10_000;
+
+/// The API key of each recent request, by request ID. Request IDs increase, so the first
+/// entries are the oldest.
+static REQUEST_OWNERS: LazyLock<Mutex<BTreeMap<usize, Option<String>>>> =
+    LazyLock::new(Default::default);
+
+/// The headers which identify `request` in its response. The API key in `headers` is recorded
+/// as the owner of the request, the only client which may cancel it.
+pub(crate) fn request_id_headers(request: &Request, headers: &HeaderMap) -> HeaderMap {
+    let mut response_headers = HeaderMap::new();
+    if let Request::Normal(request) = request {
+        response_headers.insert(REQUEST_ID_HEADER, HeaderValue::from(request.id));
+        let mut owners = REQUEST_OWNERS.lock().unwrap();
+        owners.insert(request.id, api_key_id(headers));
+        while owners.len() > MAX_REQUEST_OWNERS {
+            owners.pop_first();
+        }
+    }
+    response_headers
+}
+
+/// Whether the request `request_id` is one of the recent requests made with the API key in
+/// `headers`.
+pub(crate) fn owns_request(request_id: usize, headers: &HeaderMap) -> bool {
+    REQUEST_OWNERS
+        .lock()
+        .unwrap()
+        .get(&request_id)
+        .is_some_and(|owner| *owner == api_key_id(headers))
+}
+
 /// Sends a request to the model processing pipeline.
 pub async fn send_request(state: &SharedMistralRsState, request: Request) -> Result<()> {
     send_request_with_model(state, request, None).await
@@ -124,3 +226,41 @@ pub(crate) async fn base_process_non_streaming_response<R>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::AUTHORIZATION, HeaderMap, HeaderValue};
+    use mistralrs_core::{NormalRequest, Request, RequestMessage, SamplingParams};
+    use tokio::sync::mpsc::channel;
+
+    use super::{owns_request, request_id_headers};
+
+    #[test]
+    fn only_the_key_of_a_request_owns_it() {
+        let key = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(key).unwrap());
+            headers
+        };
+        let (tx, _rx) = channel(1);
+        let request = Request::Normal(Box::new(NormalRequest::new_simple(
+            RequestMessage::Completion {
+                text: "Hi".to_string(),
+                echo_prompt: false,
+                best_of: None,
+            },
+            SamplingParams::deterministic(),
+            tx,
+            usize::MAX - 1,
+            None,
+            None,
+        )));
+
+        let headers = request_id_headers(&request, &key("Bearer a"));
+        assert_eq!(headers["x-request-id"], (usize::MAX - 1).to_string());
+        assert!(owns_request(usize::MAX - 1, &key("Bearer a")));
+        assert!(!owns_request(usize::MAX - 1, &key("Bearer b")));
+        assert!(!owns_request(usize::MAX - 1, &HeaderMap::new()));
+        assert!(!owns_request(usize::MAX - 2, &key("Bearer a")));
+    }
+}
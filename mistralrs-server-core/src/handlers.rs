@@ -1,12 +1,19 @@
 //! ## General mistral.rs server route handlers.
 
+use std::collections::HashMap;
+
 use anyhow::Result;
-use axum::extract::{Json, State};
-use mistralrs_core::{parse_isq_value, MistralRs, Request};
+use axum::{
+    extract::{Json, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{parse_isq_value, EngineStats, MistralRs, Request, StatsRequest};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
 use crate::{
+    handler_core::{owns_request, ErrorToResponse, JsonError},
     openai::{ModelObject, ModelObjects},
     types::ExtractedMistralRsState,
 };
@@ -72,6 +79,32 @@ pub async fn health() -> &'static str {
     "OK"
 }
 
+#[utoipa::path(
+  get,
+  tag = "Mistral.rs",
+  path = "/v1/stats",
+  responses((status = 200, description = "The scheduler, KV cache and throughput stats of each model", body = HashMap<String, EngineStats>))
+)]
+pub async fn stats(
+    State(state): ExtractedMistralRsState,
+) -> Result<Json<HashMap<String, EngineStats>>, String> {
+    let mut stats = HashMap::new();
+    for model_id in state.list_models()? {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        state
+            .get_sender(Some(&model_id))
+            .map_err(|e| e.to_string())?
+            .send(Request::Stats(StatsRequest { response: tx }))
+            .await
+            .map_err(|e| e.to_string())?;
+        // An engine which stopped does not answer
+        if let Some(model_stats) = rx.recv().await {
+            stats.insert(model_id, model_stats);
+        }
+    }
+    Ok(Json(stats))
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ReIsqRequest {
     #[schema(example = "Q4K")]
@@ -95,3 +128,44 @@ pub async fn re_isq(
     state.get_sender(None).unwrap().send(request).await.unwrap();
     Ok(repr)
 }
+
+#[utoipa::path(
+  delete,
+  tag = "Mistral.rs",
+  path = "/v1/requests/{request_id}",
+  params(("request_id" = usize, Path, description = "The ID of the request to cancel, from the `x-request-id` response header")),
+  responses(
+    (status = 200, description = "Cancel a request. A queued request is removed, and a running one stops after its next step."),
+    (status = 404, description = "No recent request with this ID was made with the API key of the caller")
+  )
+)]
+pub async fn cancel_request(
+    State(state): ExtractedMistralRsState,
+    Path(request_id): Path<usize>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    if !owns_request(request_id, &headers) {
+        return JsonError::new(format!("Request {request_id} not found."))
+            .to_response(StatusCode::NOT_FOUND);
+    }
+    let repr = format!("Cancel request {request_id}");
+    MistralRs::maybe_log_request(state.clone(), repr.clone());
+    let models = match state.list_models() {
+        Ok(models) => models,
+        Err(e) => return JsonError::new(e).to_response(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    // Request IDs are unique across the models, so only the model running it is affected
+    for model_id in models {
+        let sent = match state.get_sender(Some(&model_id)) {
+            Ok(sender) => sender
+                .send(Request::Cancel(request_id))
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = sent {
+            return JsonError::new(e).to_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    repr.into_response()
+}
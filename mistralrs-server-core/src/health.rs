@@ -0,0 +1,385 @@
+//! ## Liveness and readiness endpoints, with the load state of each model.
+//!
+//! `/healthz` succeeds while the process serves requests, and `/readyz` only once the models are
+//! loaded and their engines are running, optionally after a generation of one token by each.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    sync::{Arc, LazyLock, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Json, Query, Request as HttpRequest, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Router,
+};
+use either::Either;
+use indexmap::IndexMap;
+use mistralrs_core::{
+    ModelCategory, NormalRequest, Request, RequestMessage, Response, SamplingParams,
+};
+use serde::{Deserialize, Serialize};
+use tower::{service_fn, ServiceExt};
+use utoipa::ToSchema;
+
+use crate::{
+    handler_core::{create_response_channel, send_request_with_model, ErrorToResponse, JsonError},
+    types::{ExtractedMistralRsState, SharedMistralRsState},
+};
+
+/// How long the generation probe of a model may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct ModelRecord {
+    loading: bool,
+    last_error: Option<String>,
+}
+
+/// The models which are loading or failed, and the last error of each model.
+static MODEL_RECORDS: LazyLock<Mutex<HashMap<String, ModelRecord>>> =
+    LazyLock::new(Default::default);
+
+/// Record that a model started or finished loading.
+pub(crate) fn set_loading(model_id: &str, loading: bool) {
+    MODEL_RECORDS
+        .lock()
+        .unwrap()
+        .entry(model_id.to_string())
+        .or_default()
+        .loading = loading;
+}
+
+/// Record the last error of a model, such as a failure to load it.
+pub(crate) fn record_error(model_id: &str, error: String) {
+    MODEL_RECORDS
+        .lock()
+        .unwrap()
+        .entry(model_id.to_string())
+        .or_default()
+        .last_error = Some(error);
+}
+
+/// Forget a model which was unloaded.
+pub(crate) fn forget(model_id: &str) {
+    MODEL_RECORDS.lock().unwrap().remove(model_id);
+}
+
+/// The load state of a model.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelState {
+    Loading,
+    Loaded,
+    /// The engine thread stopped. It is rebooted by the next request to the model.
+    Stopped,
+    /// The model failed to load.
+    Failed,
+}
+
+/// The result of a generation of one token.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct ProbeResult {
+    pub ok: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct ModelHealth {
+    pub state: ModelState,
+    /// Whether this is the default model.
+    pub default: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// The generation probe, if one was requested and the model generates text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<ProbeResult>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadinessStatus {
+    /// The server started and the models are loading.
+    Loading,
+    Ready,
+    NotReady,
+    /// The server is shutting down.
+    Draining,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct ReadinessResponse {
+    pub status: ReadinessStatus,
+    pub models: BTreeMap<String, ModelHealth>,
+}
+
+impl IntoResponse for ReadinessResponse {
+    fn into_response(self) -> axum::response::Response {
+        let status = if self.status == ReadinessStatus::Ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReadinessQuery {
+    #[serde(default)]
+    pub probe: bool,
+}
+
+/// Whether the server is ready: the default model is loaded, and no loaded model has an engine
+/// which stopped or a probe which failed. Models which are loading or failed to load alongside
+/// the loaded models do not affect the readiness.
+fn readiness(draining: bool, models: &BTreeMap<String, ModelHealth>) -> ReadinessStatus {
+    if draining {
+        return ReadinessStatus::Draining;
+    }
+    let has_default = models
+        .values()
+        .any(|model| model.default && model.state == ModelState::Loaded);
+    let broken = models.values().any(|model| {
+        model.state == ModelState::Stopped || model.probe.as_ref().is_some_and(|probe| !probe.ok)
+    });
+    if has_default && !broken {
+        ReadinessStatus::Ready
+    } else {
+        ReadinessStatus::NotReady
+    }
+}
+
+/// Generate one token with a model.
+async fn probe(state: &SharedMistralRsState, model_id: &str) -> ProbeResult {
+    let start = Instant::now();
+    let (tx, mut rx) = create_response_channel(None);
+    let request = NormalRequest::new_simple(
+        RequestMessage::Chat {
+            messages: vec![IndexMap::from([
+                ("role".to_string(), Either::Left("user".to_string())),
+                ("content".to_string(), Either::Left("ping".to_string())),
+            ])],
+            enable_thinking: None,
+        },
+        SamplingParams {
+            max_len: Some(1),
+            ..SamplingParams::deterministic()
+        },
+        tx,
+        state.next_request_id(),
+        None,
+        None,
+    );
+    let result =
+        match send_request_with_model(state, Request::Normal(Box::new(request)), Some(model_id))
+            .await
+        {
+            Ok(()) => match tokio::time::timeout(PROBE_TIMEOUT, rx.recv()).await {
+                Ok(Some(Response::Done(_))) => Ok(()),
+                Ok(Some(Response::ModelError(msg, _))) => Err(msg),
+                Ok(Some(Response::InternalError(e) | Response::ValidationError(e))) => {
+                    Err(e.to_string())
+                }
+                Ok(Some(_)) => Err("Unexpected response type".to_string()),
+                Ok(None) => Err("No response received from the model.".to_string()),
+                Err(_) => Err(format!(
+                    "No response within {} seconds.",
+                    PROBE_TIMEOUT.as_secs()
+                )),
+            },
+            Err(e) => Err(e.to_string()),
+        };
+    let error = result.err();
+    if let Some(error) = &error {
+        record_error(model_id, format!("Probe failed: {error}"));
+    }
+    ProbeResult {
+        ok: error.is_none(),
+        latency_ms: start.elapsed().as_millis() as u64,
+        error,
+    }
+}
+
+#[utoipa::path(
+  get,
+  tag = "Mistral.rs",
+  path = "/healthz",
+  responses((status = 200, description = "The process is up and serving requests, although the models may still be loading"))
+)]
+pub async fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+#[utoipa::path(
+  get,
+  tag = "Mistral.rs",
+  path = "/readyz",
+  params(("probe" = Option<bool>, Query, description = "Also generate one token with each loaded text or vision model")),
+  responses(
+    (status = 200, description = "The models are loaded and ready", body = ReadinessResponse),
+    (status = 503, description = "The models are loading, or a model is not ready", body = ReadinessResponse)
+  )
+)]
+pub async fn readyz(
+    State(state): ExtractedMistralRsState,
+    Query(query): Query<ReadinessQuery>,
+) -> ReadinessResponse {
+    let default_model = state.get_default_model_id().ok().flatten();
+    let mut models = BTreeMap::new();
+    for model_id in state.list_models().unwrap_or_default() {
+        let running = state.is_engine_running(&model_id).unwrap_or(false);
+        let probe = if query.probe && running {
+            match state.get_model_category(Some(&model_id)) {
+                Ok(ModelCategory::Text | ModelCategory::Vision { .. }) => {
+                    Some(probe(&state, &model_id).await)
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+        if !running {
+            record_error(&model_id, "The engine stopped.".to_string());
+        }
+        models.insert(
+            model_id.clone(),
+            ModelHealth {
+                state: if running {
+                    ModelState::Loaded
+                } else {
+                    ModelState::Stopped
+                },
+                default: default_model.as_ref() == Some(&model_id),
+                last_error: None,
+                probe,
+            },
+        );
+    }
+    for (model_id, record) in MODEL_RECORDS.lock().unwrap().iter() {
+        match models.get_mut(model_id) {
+            Some(model) => model.last_error.clone_from(&record.last_error),
+            None if record.loading || record.last_error.is_some() => {
+                models.insert(
+                    model_id.clone(),
+                    ModelHealth {
+                        state: if record.loading {
+                            ModelState::Loading
+                        } else {
+                            ModelState::Failed
+                        },
+                        default: false,
+                        last_error: record.last_error.clone(),
+                        probe: None,
+                    },
+                );
+            }
+            None => (),
+        }
+    }
+
+    ReadinessResponse {
+        status: readiness(state.is_draining(), &models),
+        models,
+    }
+}
+
+/// A router to serve while the models load at startup: `/healthz` succeeds and `/readyz` reports
+/// that the server is loading, until the router of the loaded models is set, which then serves
+/// all requests. This lets the port be bound before the models load.
+///
+/// ### Example
+/// ```ignore
+/// let listener = tokio::net::TcpListener::bind("0.0.0.0:1234").await?;
+/// let loading = LoadingRouter::new();
+/// tokio::spawn(axum::serve(listener, loading.router()).into_future());
+///
+/// let app = MistralRsServerRouterBuilder::new().with_mistralrs(load_models().await?).build().await?;
+/// loading.set_app(app);
+/// ```
+#[derive(Clone, Default)]
+pub struct LoadingRouter {
+    app: Arc<OnceLock<Router>>,
+}
+
+impl LoadingRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve all requests with `app` from now on.
+    pub fn set_app(&self, app: Router) {
+        let _ = self.app.set(app);
+    }
+
+    pub fn router(&self) -> Router {
+        let app = self.app.clone();
+        Router::new().fallback_service(service_fn(move |request: HttpRequest| {
+            let app = app.get().cloned();
+            async move {
+                match app {
+                    Some(app) => app.oneshot(request).await,
+                    None => Ok::<_, Infallible>(loading_response(request.uri().path())),
+                }
+            }
+        }))
+    }
+}
+
+fn loading_response(path: &str) -> axum::response::Response {
+    match path {
+        "/healthz" | "/health" | "/" => Json(serde_json::json!({ "status": "ok" })).into_response(),
+        "/readyz" => ReadinessResponse {
+            status: ReadinessStatus::Loading,
+            models: BTreeMap::new(),
+        }
+        .into_response(),
+        _ => JsonError::new("The models are loading.".to_string())
+            .to_response(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{readiness, ModelHealth, ModelState, ProbeResult, ReadinessStatus};
+
+    fn model(state: ModelState, default: bool) -> ModelHealth {
+        ModelHealth {
+            state,
+            default,
+            last_error: None,
+            probe: None,
+        }
+    }
+
+    #[test]
+    fn ready_with_the_default_model() {
+        let mut models = BTreeMap::from([
+            ("a".to_string(), model(ModelState::Loaded, true)),
+            ("b".to_string(), model(ModelState::Loading, false)),
+        ]);
+        assert_eq!(readiness(false, &models), ReadinessStatus::Ready);
+        assert_eq!(readiness(true, &models), ReadinessStatus::Draining);
+
+        models.get_mut("a").unwrap().probe = Some(ProbeResult {
+            ok: false,
+            latency_ms: 10,
+            error: Some("failed".to_string()),
+        });
+        assert_eq!(readiness(false, &models), ReadinessStatus::NotReady);
+
+        let models = BTreeMap::from([("a".to_string(), model(ModelState::Stopped, true))]);
+        assert_eq!(readiness(false, &models), ReadinessStatus::NotReady);
+        assert_eq!(
+            readiness(false, &BTreeMap::new()),
+            ReadinessStatus::NotReady
+        );
+    }
+}
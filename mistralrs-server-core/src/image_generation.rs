@@ -16,8 +16,8 @@ use tokio::sync::mpsc::{Receiver, Sender};
 
 use crate::{
     handler_core::{
-        base_process_non_streaming_response, create_response_channel, send_request,
-        ErrorToResponse, JsonError,
+        base_process_non_streaming_response, create_response_channel, internal_error_status,
+        send_request, ErrorToResponse, JsonError,
     },
     openai::ImageGenerationRequest,
     types::{ExtractedMistralRsState, SharedMistralRsState},
@@ -38,7 +38,7 @@ impl IntoResponse for ImageGenerationResponder {
             ImageGenerationResponder::Json(s) => Json(s).into_response(),
             ImageGenerationResponder::InternalError(e) => {
                 JsonError::new(sanitize_error_message(e.as_ref()))
-                    .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .to_response(internal_error_status(e.as_ref()))
             }
             ImageGenerationResponder::ValidationError(e) => {
                 JsonError::new(sanitize_error_message(e.as_ref()))
@@ -84,11 +84,14 @@ pub fn parse_request(
         logits_processors: None,
         return_raw_logits: false,
         web_search_options: None,
+        adapters: None,
         model_id: if oairequest.model == "default" {
             None
         } else {
             Some(oairequest.model.clone())
         },
+        tenant: None,
+        deadline: None,
     })))
 }
 
@@ -219,19 +219,33 @@
 //! }
 //! ```
 
+pub mod admin;
+pub mod auth;
+pub mod batches;
 pub mod cached_responses;
 pub mod chat_completion;
+pub mod completion_cache;
 mod completion_core;
 pub mod completions;
+pub mod embeddings;
+pub mod files;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod handler_core;
 mod handlers;
+pub mod health;
 pub mod image_generation;
+pub mod metrics;
 pub mod mistralrs_for_server_builder;
 pub mod mistralrs_server_router_builder;
+pub mod ollama;
 pub mod openai;
 pub mod openapi_doc;
+pub mod rate_limit;
 pub mod responses;
 pub mod speech_generation;
 pub mod streaming;
+pub mod tenants;
 pub mod types;
 pub mod util;
+pub mod ws;
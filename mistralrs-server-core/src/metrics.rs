@@ -0,0 +1,269 @@
+//! ## Prometheus metrics functionality and route handler.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{LazyLock, Mutex},
+};
+
+use axum::{
+    extract::{MatchedPath, Request as HttpRequest, State},
+    http::header::CONTENT_TYPE,
+    middleware::Next,
+    response::IntoResponse,
+};
+use mistralrs_core::{EngineStats, LatencyHistogram, Request, StatsRequest};
+
+use crate::types::ExtractedMistralRsState;
+
+/// The HTTP requests since the server started, by route and status code.
+static HTTP_REQUESTS: LazyLock<Mutex<BTreeMap<(String, u16), u64>>> =
+    LazyLock::new(Default::default);
+
+/// Count the requests to each route, for the metrics.
+pub(crate) async fn count_requests(request: HttpRequest, next: Next) -> axum::response::Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let response = next.run(request).await;
+    if let Some(route) = route {
+        *HTTP_REQUESTS
+            .lock()
+            .unwrap()
+            .entry((route, response.status().as_u16()))
+            .or_default() += 1;
+    }
+    response
+}
+
+/// Prometheus metrics endpoint handler.
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/metrics",
+    responses((status = 200, description = "The metrics of the server and of each model, in the Prometheus text format"))
+)]
+pub async fn metrics(State(state): ExtractedMistralRsState) -> impl IntoResponse {
+    let mut stats = BTreeMap::new();
+    for model_id in state.list_models().unwrap_or_default() {
+        let Ok(sender) = state.get_sender(Some(&model_id)) else {
+            continue;
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        if sender
+            .send(Request::Stats(StatsRequest { response: tx }))
+            .await
+            .is_err()
+        {
+            continue;
+        }
+        // An engine which stopped does not answer
+        if let Some(model_stats) = rx.recv().await {
+            stats.insert(model_id, model_stats);
+        }
+    }
+    let requests = HTTP_REQUESTS.lock().unwrap().clone();
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_metrics(&requests, &stats),
+    )
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Write the metrics in the Prometheus text format.
+fn render_metrics(
+    requests: &BTreeMap<(String, u16), u64>,
+    stats: &BTreeMap<String, EngineStats>,
+) -> String {
+    fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} {kind}").unwrap();
+    }
+
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "mistralrs_http_requests_total",
+        "counter",
+        "HTTP requests by route and status code.",
+    );
+    for ((route, status), count) in requests {
+        writeln!(
+            out,
+            "mistralrs_http_requests_total{{route=\"{}\",status=\"{status}\"}} {count}",
+            escape_label(route)
+        )
+        .unwrap();
+    }
+
+    let models = stats
+        .iter()
+        .map(|(model, stats)| (format!("model=\"{}\"", escape_label(model)), stats))
+        .collect::<Vec<_>>();
+    let gauges: [(&str, &str, &str, fn(&EngineStats) -> Option<f64>); 9] = [
+        (
+            "mistralrs_num_requests_waiting",
+            "gauge",
+            "Sequences waiting to run, including those swapped out.",
+            |s| Some(s.num_waiting as f64),
+        ),
+        (
+            "mistralrs_num_requests_running",
+            "gauge",
+            "Sequences running.",
+            |s| Some(s.num_running as f64),
+        ),
+        (
+            "mistralrs_kv_cache_usage_ratio",
+            "gauge",
+            "Fraction of the PagedAttention KV cache blocks in use.",
+            |s| match (s.kv_blocks_used, s.kv_blocks_total) {
+                (Some(used), Some(total)) if total > 0 => Some(used as f64 / total as f64),
+                _ => None,
+            },
+        ),
+        (
+            "mistralrs_kv_cache_blocks_used",
+            "gauge",
+            "PagedAttention KV cache blocks in use.",
+            |s| s.kv_blocks_used.map(|used| used as f64),
+        ),
+        (
+            "mistralrs_tokens_per_second",
+            "gauge",
+            "Tokens processed per second over the last 5 seconds.",
+            |s| Some(s.tokens_per_sec),
+        ),
+        (
+            "mistralrs_prompt_tokens_total",
+            "counter",
+            "Prompt tokens of the sequences.",
+            |s| Some(s.prompt_tokens as f64),
+        ),
+        (
+            "mistralrs_generation_tokens_total",
+            "counter",
+            "Generated tokens.",
+            |s| Some(s.generation_tokens as f64),
+        ),
+        (
+            "mistralrs_prefix_cache_hits_total",
+            "counter",
+            "Sequences which reused a cached prefix.",
+            |s| Some(s.prefix_cache_hits as f64),
+        ),
+        (
+            "mistralrs_prefix_cache_misses_total",
+            "counter",
+            "Sequences which did not reuse a cached prefix.",
+            |s| Some(s.prefix_cache_misses as f64),
+        ),
+    ];
+    for (name, kind, help, value) in gauges {
+        header(&mut out, name, kind, help);
+        for (labels, stats) in &models {
+            if let Some(value) = value(stats) {
+                writeln!(out, "{name}{{{labels}}} {value}").unwrap();
+            }
+        }
+    }
+
+    let histograms: [(&str, &str, fn(&EngineStats) -> &LatencyHistogram); 2] = [
+        (
+            "mistralrs_time_to_first_token_seconds",
+            "Time from the arrival of each sequence to its first token.",
+            |s| &s.time_to_first_token,
+        ),
+        (
+            "mistralrs_time_per_output_token_seconds",
+            "Time to generate each token after the first.",
+            |s| &s.time_per_output_token,
+        ),
+    ];
+    for (name, help, histogram) in histograms {
+        header(&mut out, name, "histogram", help);
+        for (labels, stats) in &models {
+            let histogram = histogram(stats);
+            for bucket in &histogram.buckets {
+                writeln!(
+                    out,
+                    "{name}_bucket{{{labels},le=\"{}\"}} {}",
+                    bucket.le, bucket.count
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            )
+            .unwrap();
+            writeln!(out, "{name}_sum{{{labels}}} {}", histogram.sum).unwrap();
+            writeln!(out, "{name}_count{{{labels}}} {}", histogram.count).unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use mistralrs_core::{EngineStats, HistogramBucket, LatencyHistogram};
+
+    use super::render_metrics;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let histogram = LatencyHistogram {
+            buckets: vec![
+                HistogramBucket { le: 0.1, count: 1 },
+                HistogramBucket { le: 1., count: 2 },
+            ],
+            sum: 1.5,
+            count: 3,
+        };
+        let stats = EngineStats {
+            num_waiting: 2,
+            num_running: 1,
+            kv_blocks_used: None,
+            kv_blocks_total: None,
+            prefix_cache_hit_rate: 0.,
+            tokens_per_sec: 12.5,
+            prefix_cache_hits: 0,
+            prefix_cache_misses: 3,
+            kv_cache: None,
+            prompt_tokens: 30,
+            generation_tokens: 40,
+            time_to_first_token: histogram.clone(),
+            time_per_output_token: histogram,
+        };
+        let requests = BTreeMap::from([(("/v1/chat/completions".to_string(), 200), 3)]);
+        let text = render_metrics(&requests, &BTreeMap::from([("a\"b".to_string(), stats)]));
+
+        let lines = text.lines().collect::<Vec<_>>();
+        for expected in [
+            "# TYPE mistralrs_http_requests_total counter",
+            r#"mistralrs_http_requests_total{route="/v1/chat/completions",status="200"} 3"#,
+            r#"mistralrs_num_requests_waiting{model="a\"b"} 2"#,
+            r#"mistralrs_tokens_per_second{model="a\"b"} 12.5"#,
+            r#"mistralrs_generation_tokens_total{model="a\"b"} 40"#,
+            "# TYPE mistralrs_time_to_first_token_seconds histogram",
+            r#"mistralrs_time_to_first_token_seconds_bucket{model="a\"b",le="0.1"} 1"#,
+            r#"mistralrs_time_to_first_token_seconds_bucket{model="a\"b",le="+Inf"} 3"#,
+            r#"mistralrs_time_to_first_token_seconds_sum{model="a\"b"} 1.5"#,
+        ] {
+            assert!(lines.contains(&expected), "missing `{expected}` in\n{text}");
+        }
+        // Without PagedAttention there is no KV cache usage
+        assert!(!text.contains("mistralrs_kv_cache_usage_ratio{"));
+    }
+}
@@ -7,9 +7,10 @@ use candle_core::Device;
 use mistralrs_core::{
     get_auto_device_map_params, get_model_dtype, get_tgt_non_granular_index, paged_attn_supported,
     parse_isq_value, AutoDeviceMapParams, BertEmbeddingModel, DefaultSchedulerMethod,
-    DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, Loader, LoaderBuilder,
-    McpClientConfig, MemoryGpuConfig, MistralRsBuilder, ModelSelected, PagedAttentionConfig,
-    PagedCacheType, SchedulerConfig, SearchCallback, TokenSource,
+    DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, KvQuotaConfig, Loader,
+    LoaderBuilder, McpClientConfig, MemoryGpuConfig, MistralRsBuilder, ModelSelected,
+    PagedAttentionConfig, PagedCacheType, SchedulerConfig, SchedulingPolicy, SearchCallback,
+    TokenSource,
 };
 use tracing::{info, warn};
 
@@ -91,10 +92,12 @@ pub mod defaults {
     pub const PAGED_ATTN_GPU_MEM_USAGE: Option<f32> = None;
     pub const PAGED_CTXT_LEN: Option<usize> = None;
     pub const PAGED_ATTN_BLOCK_SIZE: Option<usize> = None;
+    pub const PAGED_ATTN_SWAP_SPACE: usize = 0;
+    pub const PAGED_ATTN_WATERMARK: f32 = 0.;
     pub const PAGED_ATTN: Option<bool> = None;
     pub const PAGED_ATTN_CPU: bool = false;
     pub const PAGED_ATTN_CUDA: bool = true;
-    pub const PAGED_ATTN_METAL: bool = false;
+    pub const PAGED_ATTN_METAL: bool = true;
     pub const CPU: bool = false;
     pub const ENABLE_SEARCH: bool = false;
     pub const SEARCH_BERT_MODEL: Option<String> = None;
@@ -137,6 +140,7 @@ pub mod defaults {
 ///        .build()
 ///        .await?;
 /// ```
+#[derive(Clone)]
 pub struct MistralRsForServerBuilder {
     /// The Candle device to use for model execution (CPU, CUDA, Metal, etc.).
     device: Option<Device>,
@@ -185,6 +189,21 @@ pub struct MistralRsForServerBuilder {
     /// Number of prefix caches to hold on the device. Other caches are evicted to the CPU based on a LRU strategy.
     prefix_cache_n: usize,
 
+    /// How waiting sequences are ordered. Fair share balances the tokens of each `user`.
+    scheduling_policy: SchedulingPolicy,
+
+    /// Process prompts in chunks of at most this many tokens, interleaved with the completions.
+    prefill_chunk_size: Option<usize>,
+
+    /// Reject new requests with 429 once this many sequences are waiting to run.
+    max_queue_len: Option<usize>,
+
+    /// Bound the KV cache held by each sequence.
+    kv_quota: Option<KvQuotaConfig>,
+
+    /// Make the output of each request depend only on the request.
+    deterministic: bool,
+
     /// NOTE: This can be omitted to use automatic device mapping!
     /// Number of device layers to load and run on GPU(s). All others will be on the CPU.
     /// If one GPU is used, then this value should be an integer. Otherwise, it follows the following pattern:
@@ -195,27 +214,35 @@ pub struct MistralRsForServerBuilder {
     in_situ_quant: Option<String>,
 
     /// GPU memory to allocate for KV cache with PagedAttention in MBs.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     paged_attn_gpu_mem: Option<usize>,
 
     /// Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
     /// If this is not set and the device is CUDA, it will default to `0.9`.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     paged_attn_gpu_mem_usage: Option<f32>,
 
     /// Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold).
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     /// This is the default setting, and it defaults to the `max-seq-len` specified in after the model type.
     paged_ctxt_len: Option<usize>,
 
     /// Block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA, it will default to 32.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     paged_attn_block_size: Option<usize>,
 
-    /// Enables or disables PagedAttention. By default, PagedAttention will be enabled for CUDA and disabled for Metal (and is not supported for CPU). Use this to override the default behavior.
+    /// Host memory in MB to swap the KV cache of preempted sequences to with PagedAttention. If 0,
+    /// preempted sequences are recomputed when resumed.
+    paged_attn_swap_space: usize,
+
+    /// Fraction of the PagedAttention GPU blocks to keep free when admitting new sequences, so
+    /// running sequences can grow without being preempted.
+    paged_attn_watermark: f32,
+
+    /// Enables or disables PagedAttention. By default, PagedAttention will be enabled for CUDA and Metal (and is not supported for CPU). Use this to override the default behavior.
     paged_attn: Option<bool>,
 
     /// Use CPU only
@@ -255,12 +282,19 @@ impl Default for MistralRsForServerBuilder {
             token_source: defaults::TOKEN_SOURCE,
             interactive_mode: defaults::INTERACTIVE_MODE,
             prefix_cache_n: defaults::PREFIX_CACHE_N,
+            scheduling_policy: SchedulingPolicy::Fcfs,
+            prefill_chunk_size: None,
+            max_queue_len: None,
+            kv_quota: None,
+            deterministic: false,
             num_device_layers: defaults::NUM_DEVICE_LAYERS,
             in_situ_quant: defaults::IN_SITU_QUANT,
             paged_attn_gpu_mem: defaults::PAGED_ATTN_GPU_MEM,
             paged_attn_gpu_mem_usage: defaults::PAGED_ATTN_GPU_MEM_USAGE,
             paged_ctxt_len: defaults::PAGED_CTXT_LEN,
             paged_attn_block_size: defaults::PAGED_ATTN_BLOCK_SIZE,
+            paged_attn_swap_space: defaults::PAGED_ATTN_SWAP_SPACE,
+            paged_attn_watermark: defaults::PAGED_ATTN_WATERMARK,
             paged_attn: defaults::PAGED_ATTN,
             cpu: defaults::CPU,
             enable_search: defaults::ENABLE_SEARCH,
@@ -422,6 +456,62 @@ impl MistralRsForServerBuilder {
         self
     }
 
+    /// Sets how waiting sequences are ordered.
+    pub fn with_scheduling_policy(mut self, scheduling_policy: SchedulingPolicy) -> Self {
+        self.scheduling_policy = scheduling_policy;
+        self
+    }
+
+    /// Sets the most prompt tokens processed in one step, so the completions of other sequences
+    /// run between the chunks of long prompts.
+    pub fn with_prefill_chunk_size(mut self, prefill_chunk_size: usize) -> Self {
+        self.prefill_chunk_size = Some(prefill_chunk_size);
+        self
+    }
+
+    /// Sets the optional most prompt tokens processed in one step.
+    pub fn with_prefill_chunk_size_optional(mut self, prefill_chunk_size: Option<usize>) -> Self {
+        if let Some(prefill_chunk_size) = prefill_chunk_size {
+            self = self.with_prefill_chunk_size(prefill_chunk_size);
+        }
+        self
+    }
+
+    /// Sets the most sequences waiting to run before new requests are rejected.
+    pub fn with_max_queue_len(mut self, max_queue_len: usize) -> Self {
+        self.max_queue_len = Some(max_queue_len);
+        self
+    }
+
+    /// Sets the optional most sequences waiting to run before new requests are rejected.
+    pub fn with_max_queue_len_optional(mut self, max_queue_len: Option<usize>) -> Self {
+        if let Some(max_queue_len) = max_queue_len {
+            self = self.with_max_queue_len(max_queue_len);
+        }
+        self
+    }
+
+    /// Sets the most KV cache each sequence may hold.
+    pub fn with_kv_quota(mut self, kv_quota: KvQuotaConfig) -> Self {
+        self.kv_quota = Some(kv_quota);
+        self
+    }
+
+    /// Sets the optional most KV cache each sequence may hold.
+    pub fn with_kv_quota_optional(mut self, kv_quota: Option<KvQuotaConfig>) -> Self {
+        if let Some(kv_quota) = kv_quota {
+            self = self.with_kv_quota(kv_quota);
+        }
+        self
+    }
+
+    /// Sets whether the output of each request depends only on the request, for reproducible
+    /// evaluations.
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
     /// Sets the device layer mapping
     pub fn with_num_device_layers(mut self, num_device_layers: Vec<String>) -> Self {
         self.num_device_layers = Some(num_device_layers);
@@ -459,7 +549,7 @@ impl MistralRsForServerBuilder {
     /// sets the value to whatever `Option<bool>` is passed in as `None`, `Some(true)`
     /// and `Some(false)` have different implications.
     ///
-    /// `None`: default behavior for target device (e.g. enable for CUDA, disable for Metal)
+    /// `None`: default behavior for target device (e.g. enable for CUDA and Metal, disable for CPU)
     /// `Some(true)`: enable (if supported by target device)
     /// `Some(false)`: disable
     pub fn set_paged_attn(mut self, paged_attn: Option<bool>) -> Self {
@@ -518,6 +608,19 @@ impl MistralRsForServerBuilder {
         self
     }
 
+    /// Sets the host memory in MB to swap out preempted sequences to with PagedAttention.
+    pub fn with_paged_attn_swap_space(mut self, paged_attn_swap_space: usize) -> Self {
+        self.paged_attn_swap_space = paged_attn_swap_space;
+        self
+    }
+
+    /// Sets the fraction of the PagedAttention GPU blocks to keep free when admitting new
+    /// sequences.
+    pub fn with_paged_attn_watermark(mut self, paged_attn_watermark: f32) -> Self {
+        self.paged_attn_watermark = paged_attn_watermark;
+        self
+    }
+
     /// Sets the block size for PagedAttention.
     pub fn with_paged_attn_cache_type(mut self, cache_type: PagedCacheType) -> Self {
         self.paged_cache_type = cache_type;
@@ -596,6 +699,26 @@ impl MistralRsForServerBuilder {
         }
     }
 
+    /// Build an instance with only the model of `config`, using the other settings of this
+    /// builder. The models of a multi-model builder are ignored.
+    ///
+    /// This is used to load a model into a running server, by moving it into the instance of the
+    /// server with [`mistralrs_core::MistralRs::add_model_from`].
+    pub async fn build_from_model_config(
+        &self,
+        config: ModelConfig,
+    ) -> Result<SharedMistralRsState> {
+        let mut builder = self.clone();
+        builder.models.clear();
+        builder.default_model_id = None;
+        builder.model = Some(config.model);
+        builder.chat_template = config.chat_template;
+        builder.jinja_explicit = config.jinja_explicit;
+        builder.num_device_layers = config.num_device_layers;
+        builder.in_situ_quant = config.in_situ_quant;
+        builder.build_single_model().await
+    }
+
     /// Build a single-model instance (legacy mode)
     async fn build_single_model(mut self) -> Result<SharedMistralRsState> {
         let model = self.model.context("Model was None")?;
@@ -625,6 +748,8 @@ impl MistralRsForServerBuilder {
             self.paged_attn_gpu_mem_usage,
             self.paged_ctxt_len,
             self.paged_cache_type,
+            self.paged_attn_swap_space,
+            self.paged_attn_watermark,
             !paged_attn,
             max_seq_len,
         )?;
@@ -668,7 +793,12 @@ impl MistralRsForServerBuilder {
         .with_opt_log(self.log)
         .with_truncate_sequence(self.truncate_sequence)
         .with_no_kv_cache(self.no_kv_cache)
-        .with_prefix_cache_n(self.prefix_cache_n);
+        .with_prefix_cache_n(self.prefix_cache_n)
+        .with_scheduling_policy(self.scheduling_policy)
+        .with_opt_prefill_chunk_size(self.prefill_chunk_size)
+        .with_opt_max_queue_len(self.max_queue_len)
+        .with_opt_kv_quota(self.kv_quota)
+        .with_deterministic(self.deterministic);
 
         // Add MCP client configuration if provided
         if let Some(mcp_config) = self.mcp_client_config {
@@ -740,6 +870,8 @@ impl MistralRsForServerBuilder {
             self.paged_attn_gpu_mem_usage,
             self.paged_ctxt_len,
             self.paged_cache_type,
+            self.paged_attn_swap_space,
+            self.paged_attn_watermark,
             !paged_attn,
             max_seq_len,
         )?;
@@ -782,7 +914,12 @@ impl MistralRsForServerBuilder {
         .with_opt_log(self.log.clone())
         .with_truncate_sequence(self.truncate_sequence)
         .with_no_kv_cache(self.no_kv_cache)
-        .with_prefix_cache_n(self.prefix_cache_n);
+        .with_prefix_cache_n(self.prefix_cache_n)
+        .with_scheduling_policy(self.scheduling_policy)
+        .with_opt_prefill_chunk_size(self.prefill_chunk_size)
+        .with_opt_max_queue_len(self.max_queue_len)
+        .with_opt_kv_quota(self.kv_quota)
+        .with_deterministic(self.deterministic);
 
         // Add MCP client configuration if provided
         if let Some(mcp_config) = self.mcp_client_config.clone() {
@@ -861,7 +998,16 @@ impl MistralRsForServerBuilder {
                 no_kv_cache: self.no_kv_cache,
                 no_prefix_cache: false,
                 prefix_cache_n: self.prefix_cache_n,
+                prefix_cache_dir: None,
                 disable_eos_stop: false,
+                scheduling_policy: self.scheduling_policy,
+                prefill_chunk_size: self.prefill_chunk_size,
+                prefill_pipeline: None,
+                max_queue_len: self.max_queue_len,
+                attention_sinks: None,
+                kv_quota: self.kv_quota,
+                logits_processors: Vec::new(),
+                deterministic: self.deterministic,
                 throughput_logging_enabled: !self.interactive_mode,
                 search_embedding_model: bert_model.clone(),
                 search_callback: self.search_callback.clone(),
@@ -1014,16 +1160,19 @@ fn configure_paged_attn(device: &Device, paged_attn: Option<bool>) -> bool {
 }
 
 /// Initializes the cache configuration for paged attention based on provided parameters.
+#[allow(clippy::too_many_arguments)]
 fn init_cache_config(
     paged_attn_block_size: Option<usize>,
     paged_attn_gpu_mem: Option<usize>,
     paged_attn_gpu_mem_usage: Option<f32>,
     paged_ctxt_len: Option<usize>,
     cache_type: PagedCacheType,
+    swap_space: usize,
+    watermark: f32,
     no_paged_attn: bool,
     max_seq_len: usize,
 ) -> Result<Option<PagedAttentionConfig>> {
-    match (
+    let cache_config = match (
         paged_attn_block_size,
         paged_attn_gpu_mem,
         paged_attn_gpu_mem_usage,
@@ -1076,7 +1225,8 @@ fn init_cache_config(
             )?))
         }
         (_, _, _, _, _, _) => Ok(None),
-    }
+    };
+    Ok(cache_config?.map(|cfg| cfg.with_swap_space(swap_space).with_watermark(watermark)))
 }
 
 /// Initializes the scheduler configuration based on cache settings and pipeline metadata.
@@ -1137,3 +1287,24 @@ pub fn get_bert_model(
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::configure_paged_attn;
+
+    #[test]
+    fn paged_attn_is_not_used_on_cpu() {
+        assert!(!configure_paged_attn(&Device::Cpu, None));
+        assert!(!configure_paged_attn(&Device::Cpu, Some(true)));
+    }
+
+    #[cfg(feature = "metal")]
+    #[test]
+    fn paged_attn_is_enabled_by_default_on_metal() {
+        let device = Device::new_metal(0).unwrap();
+        assert!(configure_paged_attn(&device, None));
+        assert!(!configure_paged_attn(&device, Some(false)));
+    }
+}
@@ -1,24 +1,39 @@
 //! ## mistral.rs server router builder.
 
+use std::sync::Arc;
+
 use anyhow::Result;
 use axum::{
-    extract::DefaultBodyLimit,
+    extract::{DefaultBodyLimit, Extension},
     http::{self, Method},
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post},
     Router,
 };
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use utoipa_swagger_ui::SwaggerUi;
 
 use crate::{
+    admin::{load_model, unload_model, AdminConfig},
+    auth::{authenticate, ApiKeys},
+    batches::{cancel_batch, create_batch, list_batches, retrieve_batch},
     chat_completion::chatcompletions,
+    completion_cache::{cache_completions, CompletionCache, CompletionCacheConfig},
     completions::completions,
-    handlers::{health, models, re_isq},
+    embeddings::embeddings,
+    files::{delete_file, list_files, retrieve_file, retrieve_file_content, upload_file},
+    handlers::{cancel_request, health, models, re_isq, stats},
+    health::{healthz, readyz},
     image_generation::image_generation,
+    metrics::{count_requests, metrics},
+    ollama::{chat, generate, tags},
     openapi_doc::get_openapi_doc,
+    rate_limit::{rate_limit, RateLimitConfig, RateLimiter},
     responses::{create_response, delete_response, get_response},
     speech_generation::speech_generation,
+    tenants::{route_tenant, tenant_usage, Tenants, TENANT_HEADER},
     types::SharedMistralRsState,
+    ws::websocket,
 };
 
 // NOTE(EricLBuehler): Accept up to 50mb input
@@ -64,6 +79,16 @@ pub struct MistralRsServerRouterBuilder {
     allowed_origins: Option<Vec<String>>,
     /// Optional axum default request body limit
     max_body_limit: Option<usize>,
+    /// Optional configuration of the admin endpoints, which are not served without it
+    admin: Option<AdminConfig>,
+    /// Optional API keys which requests must be authorized with
+    api_keys: Option<ApiKeys>,
+    /// Optional default rate limits of each client
+    rate_limit: Option<RateLimitConfig>,
+    /// Optional tenants which requests are routed by
+    tenants: Option<Tenants>,
+    /// Optional cache of the responses of identical completion requests
+    completion_cache: Option<CompletionCacheConfig>,
 }
 
 impl Default for MistralRsServerRouterBuilder {
@@ -75,6 +100,11 @@ impl Default for MistralRsServerRouterBuilder {
             base_path: None,
             allowed_origins: None,
             max_body_limit: None,
+            admin: None,
+            api_keys: None,
+            rate_limit: None,
+            tenants: None,
+            completion_cache: None,
         }
     }
 }
@@ -132,6 +162,46 @@ impl MistralRsServerRouterBuilder {
         self
     }
 
+    /// Enables the admin endpoints, `/admin/models/load` and `/admin/models/unload`, which load
+    /// and unload models while the server runs. Their requests must be authorized with the key of
+    /// `admin` as a bearer token.
+    pub fn with_admin(mut self, admin: AdminConfig) -> Self {
+        self.admin = Some(admin);
+        self
+    }
+
+    /// Requires the requests to be authorized with one of `api_keys` as a bearer token, and
+    /// enforces the policy of the key. `/` and the health endpoints are served without a key.
+    pub fn with_api_keys(mut self, api_keys: ApiKeys) -> Self {
+        self.api_keys = Some(api_keys);
+        self
+    }
+
+    /// Limits the requests and tokens per minute of each client, identified by its API key or IP
+    /// address. Requests over a limit are rejected with `429 Too Many Requests`. The policies of
+    /// the API keys override these limits.
+    ///
+    /// The IP addresses are only known if the router is served with
+    /// `into_make_service_with_connect_info::<SocketAddr>()`.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Routes the requests by their tenant, from their API key policy or `x-tenant-id` header,
+    /// completing them with the defaults of the tenant and counting its usage.
+    pub fn with_tenants(mut self, tenants: Tenants) -> Self {
+        self.tenants = Some(tenants);
+        self
+    }
+
+    /// Serves the responses of identical chat completion and completion requests which are not
+    /// streamed from a cache.
+    pub fn with_completion_cache(mut self, config: CompletionCacheConfig) -> Self {
+        self.completion_cache = Some(config);
+        self
+    }
+
     /// Builds the configured axum router.
     ///
     /// ### Examples
@@ -155,6 +225,11 @@ impl MistralRsServerRouterBuilder {
             self.base_path.as_deref(),
             self.allowed_origins,
             self.max_body_limit,
+            self.admin,
+            self.api_keys,
+            self.rate_limit,
+            self.tenants,
+            self.completion_cache,
         );
 
         mistralrs_server_router
@@ -165,12 +240,18 @@ impl MistralRsServerRouterBuilder {
 ///
 /// This function creates a router with all the necessary API endpoints,
 /// CORS configuration, body size limits, and optional Swagger documentation.
+#[allow(clippy::too_many_arguments)]
 fn init_router(
     state: SharedMistralRsState,
     include_swagger_routes: bool,
     base_path: Option<&str>,
     allowed_origins: Option<Vec<String>>,
     max_body_limit: Option<usize>,
+    admin: Option<AdminConfig>,
+    api_keys: Option<ApiKeys>,
+    rate_limit_config: Option<RateLimitConfig>,
+    tenants: Option<Tenants>,
+    completion_cache: Option<CompletionCacheConfig>,
 ) -> Result<Router> {
     let allow_origin = if let Some(origins) = allowed_origins {
         let parsed_origins: Result<Vec<_>, _> = origins.into_iter().map(|o| o.parse()).collect();
@@ -187,7 +268,11 @@ fn init_router(
 
     let cors_layer = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST])
-        .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
+        .allow_headers([
+            http::header::CONTENT_TYPE,
+            http::header::AUTHORIZATION,
+            http::HeaderName::from_static(TENANT_HEADER),
+        ])
         .allow_origin(allow_origin);
 
     // Use the provided base path or default to ""
@@ -196,10 +281,25 @@ fn init_router(
     let mut router = Router::new()
         .route("/v1/chat/completions", post(chatcompletions))
         .route("/v1/completions", post(completions))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/files", post(upload_file).get(list_files))
+        .route(
+            "/v1/files/{file_id}",
+            get(retrieve_file).delete(delete_file),
+        )
+        .route("/v1/files/{file_id}/content", get(retrieve_file_content))
+        .route("/v1/batches", post(create_batch).get(list_batches))
+        .route("/v1/batches/{batch_id}", get(retrieve_batch))
+        .route("/v1/batches/{batch_id}/cancel", post(cancel_batch))
         .route("/v1/models", get(models))
         .route("/health", get(health))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/v1/stats", get(stats))
+        .route("/metrics", get(metrics))
         .route("/", get(health))
         .route("/re_isq", post(re_isq))
+        .route("/v1/requests/{request_id}", delete(cancel_request))
         .route("/v1/images/generations", post(image_generation))
         .route("/v1/audio/speech", post(speech_generation))
         .route("/v1/responses", post(create_response))
@@ -207,6 +307,52 @@ fn init_router(
             "/v1/responses/{response_id}",
             get(get_response).delete(delete_response),
         )
+        .route("/v1/usage", get(tenant_usage))
+        .route("/v1/ws", get(websocket))
+        .route("/api/chat", post(chat))
+        .route("/api/generate", post(generate))
+        .route("/api/tags", get(tags));
+
+    if let Some(admin) = admin {
+        router = router
+            .route("/admin/models/load", post(load_model))
+            .route("/admin/models/unload", post(unload_model))
+            .layer(Extension(Arc::new(admin)));
+    }
+
+    // The cache runs after the tenant routing, so that the key has the defaults of the tenant
+    if let Some(config) = completion_cache {
+        router = router.route_layer(middleware::from_fn_with_state(
+            Arc::new(CompletionCache::new(config)),
+            cache_completions,
+        ));
+    }
+
+    // The tenant routing and rate limits run after the authentication, which sets the tenant and
+    // limits of the API key
+    let tenants = tenants.map(Arc::new);
+    if let Some(tenants) = &tenants {
+        router = router.route_layer(middleware::from_fn_with_state(
+            (tenants.clone(), state.clone()),
+            route_tenant,
+        ));
+    }
+    if rate_limit_config.is_some() || api_keys.is_some() {
+        let limiter = RateLimiter::new(rate_limit_config.unwrap_or_default());
+        router = router.route_layer(middleware::from_fn_with_state(
+            Arc::new(limiter),
+            rate_limit,
+        ));
+    }
+    if let Some(api_keys) = api_keys {
+        router = router.route_layer(middleware::from_fn_with_state(
+            (Arc::new(api_keys), tenants, state.clone()),
+            authenticate,
+        ));
+    }
+
+    let mut router = router
+        .route_layer(middleware::from_fn(count_requests))
         .layer(cors_layer)
         .layer(DefaultBodyLimit::max(router_max_body_limit))
         .with_state(state);
@@ -0,0 +1,676 @@
+//! ## Ollama-compatible API: `/api/chat`, `/api/generate`, and `/api/tags`.
+//!
+//! The requests are converted to chat completion and completion requests, and their responses
+//! to the Ollama format. Streamed responses are newline-delimited JSON objects, the last of
+//! which has `done` set, with the token counts and durations of the request.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, Json, State},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use futures::stream;
+use mistralrs_core::{Request, Response, ToolCallResponse, Usage};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::sync::mpsc::Receiver;
+
+use crate::{
+    auth::{apply_max_context, ApiKeyPolicy},
+    chat_completion, completions,
+    handler_core::{create_response_channel, send_request_with_model, set_tenant_from_api_key},
+    openai::{ChatCompletionRequest, CompletionRequest},
+    rate_limit::TokenCharger,
+    types::{ExtractedMistralRsState, SharedMistralRsState},
+    util::sanitize_error_message,
+};
+
+/// The sampling options of a request. Those without an equivalent, such as `num_ctx`, are
+/// ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct OllamaOptions {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    /// The maximum number of tokens to generate, where a negative number is no limit.
+    pub num_predict: Option<i64>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<u64>,
+    pub repeat_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OllamaFunctionCall {
+    pub name: String,
+    pub arguments: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OllamaToolCall {
+    pub function: OllamaFunctionCall,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OllamaMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+    /// Base64-encoded images, for vision models.
+    pub images: Option<Vec<String>>,
+    pub tool_calls: Option<Vec<OllamaToolCall>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OllamaChatRequest {
+    pub model: String,
+    #[serde(default)]
+    pub messages: Vec<OllamaMessage>,
+    pub tools: Option<Value>,
+    /// `json`, or a JSON schema which the response follows.
+    pub format: Option<Value>,
+    #[serde(default)]
+    pub options: OllamaOptions,
+    /// Whether to stream the response, which is the default.
+    pub stream: Option<bool>,
+    pub think: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OllamaGenerateRequest {
+    pub model: String,
+    #[serde(default)]
+    pub prompt: String,
+    pub system: Option<String>,
+    pub images: Option<Vec<String>>,
+    /// `json`, or a JSON schema which the response follows.
+    pub format: Option<Value>,
+    #[serde(default)]
+    pub options: OllamaOptions,
+    /// Whether to stream the response, which is the default.
+    pub stream: Option<bool>,
+    /// Whether to send the prompt without the chat template.
+    #[serde(default)]
+    pub raw: bool,
+    pub think: Option<bool>,
+}
+
+/// The endpoint of a request, which decides how the text of its responses is sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Api {
+    Chat,
+    Generate,
+}
+
+fn ollama_error(status: StatusCode, message: impl Into<String>) -> axum::response::Response {
+    (status, Json(json!({ "error": message.into() }))).into_response()
+}
+
+/// Format seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+fn rfc3339(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+    // The civil date of a day count, from Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+fn now() -> String {
+    rfc3339(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default(),
+    )
+}
+
+/// The ID of a loaded model. Ollama tools may add the `latest` tag to the names of the models.
+fn resolve_model(
+    state: &SharedMistralRsState,
+    model: &str,
+) -> Result<String, axum::response::Response> {
+    if model == "default" {
+        return Ok(model.to_string());
+    }
+    let models = state.list_models().unwrap_or_default();
+    [Some(model), model.strip_suffix(":latest")]
+        .into_iter()
+        .flatten()
+        .find(|name| models.iter().any(|id| id == name))
+        .map(ToString::to_string)
+        .ok_or_else(|| {
+            ollama_error(
+                StatusCode::NOT_FOUND,
+                format!("model \"{model}\" not found"),
+            )
+        })
+}
+
+/// The sampling fields of a chat completion or completion request.
+fn sampling_fields(options: OllamaOptions) -> Map<String, Value> {
+    let mut fields = Map::new();
+    let mut insert = |key: &str, value: Option<Value>| {
+        if let Some(value) = value {
+            fields.insert(key.to_string(), value);
+        }
+    };
+    insert("temperature", options.temperature.map(Value::from));
+    insert("top_p", options.top_p.map(Value::from));
+    insert("top_k", options.top_k.map(Value::from));
+    insert("min_p", options.min_p.map(Value::from));
+    insert("typical_p", options.typical_p.map(Value::from));
+    insert(
+        "max_tokens",
+        options
+            .num_predict
+            .filter(|num_predict| *num_predict >= 0)
+            .map(Value::from),
+    );
+    insert("stop", options.stop.map(Value::from));
+    insert("seed", options.seed.map(Value::from));
+    insert(
+        "repetition_penalty",
+        options.repeat_penalty.map(Value::from),
+    );
+    insert(
+        "presence_penalty",
+        options.presence_penalty.map(Value::from),
+    );
+    insert(
+        "frequency_penalty",
+        options.frequency_penalty.map(Value::from),
+    );
+    fields
+}
+
+/// The `response_format` of a `format`, which is `json` or a JSON schema.
+fn response_format(format: Option<Value>) -> Option<Value> {
+    match format? {
+        Value::String(format) if format == "json" => Some(json!({ "type": "json_object" })),
+        schema @ Value::Object(_) => Some(json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": schema },
+        })),
+        _ => None,
+    }
+}
+
+fn openai_message(message: OllamaMessage) -> Value {
+    let content = match message.images {
+        Some(images) if !images.is_empty() => {
+            let mut parts = vec![json!({ "type": "text", "text": message.content })];
+            parts.extend(images.into_iter().map(|image| {
+                json!({
+                    "type": "image_url",
+                    "image_url": { "url": format!("data:image/png;base64,{image}") },
+                })
+            }));
+            Value::Array(parts)
+        }
+        _ => Value::String(message.content),
+    };
+    let mut openai = json!({ "role": message.role, "content": content });
+    if let Some(tool_calls) = message.tool_calls {
+        openai["tool_calls"] = tool_calls
+            .into_iter()
+            .map(|call| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": call.function.name,
+                        "arguments": call.function.arguments.to_string(),
+                    },
+                })
+            })
+            .collect();
+    }
+    openai
+}
+
+fn chat_completion_request(
+    model: String,
+    messages: Vec<Value>,
+    tools: Option<Value>,
+    format: Option<Value>,
+    options: OllamaOptions,
+    think: Option<bool>,
+    stream: bool,
+) -> serde_json::Result<ChatCompletionRequest> {
+    let mut request = sampling_fields(options);
+    request.insert("model".to_string(), model.into());
+    request.insert("messages".to_string(), messages.into());
+    request.insert("stream".to_string(), stream.into());
+    if let Some(tools) = tools {
+        request.insert("tools".to_string(), tools);
+    }
+    if let Some(response_format) = response_format(format) {
+        request.insert("response_format".to_string(), response_format);
+    }
+    if let Some(think) = think {
+        request.insert("enable_thinking".to_string(), think.into());
+    }
+    serde_json::from_value(Value::Object(request))
+}
+
+/// The message of a chat response, whose tool calls have arguments as objects.
+fn ollama_message(content: Option<String>, tool_calls: Option<Vec<ToolCallResponse>>) -> Value {
+    let mut message = json!({ "role": "assistant", "content": content.unwrap_or_default() });
+    if let Some(tool_calls) = tool_calls.filter(|tool_calls| !tool_calls.is_empty()) {
+        message["tool_calls"] = tool_calls
+            .into_iter()
+            .map(|call| {
+                let arguments = serde_json::from_str(&call.function.arguments)
+                    .unwrap_or(Value::String(call.function.arguments));
+                json!({ "function": { "name": call.function.name, "arguments": arguments } })
+            })
+            .collect();
+    }
+    message
+}
+
+fn nanoseconds(secs: f32) -> u64 {
+    (f64::from(secs) * 1e9) as u64
+}
+
+/// A response object, which is the last of the request if it has a finish reason.
+fn ollama_object(
+    api: Api,
+    model: &str,
+    content: Option<String>,
+    tool_calls: Option<Vec<ToolCallResponse>>,
+    finish_reason: Option<&str>,
+    usage: Option<&Usage>,
+) -> Value {
+    let mut object = json!({ "model": model, "created_at": now() });
+    match api {
+        Api::Chat => object["message"] = ollama_message(content, tool_calls),
+        Api::Generate => object["response"] = content.unwrap_or_default().into(),
+    }
+    object["done"] = finish_reason.is_some().into();
+    if let Some(finish_reason) = finish_reason {
+        object["done_reason"] = if finish_reason == "length" {
+            "length"
+        } else {
+            "stop"
+        }
+        .into();
+    }
+    if let Some(usage) = usage {
+        object["total_duration"] = nanoseconds(usage.total_time_sec).into();
+        object["load_duration"] = 0.into();
+        object["prompt_eval_count"] = usage.prompt_tokens.into();
+        object["prompt_eval_duration"] = nanoseconds(usage.total_prompt_time_sec).into();
+        object["eval_count"] = usage.completion_tokens.into();
+        object["eval_duration"] = nanoseconds(usage.total_completion_time_sec).into();
+    }
+    object
+}
+
+/// The response object of a model response, or the status and message of an error.
+fn convert_response(
+    api: Api,
+    model: &str,
+    response: Response,
+) -> Result<Value, (StatusCode, String)> {
+    match response {
+        Response::Chunk(chunk) => {
+            let choice = chunk.choices.into_iter().next();
+            let finish_reason = choice.as_ref().and_then(|c| c.finish_reason.clone());
+            let (content, tool_calls) = choice
+                .map(|choice| (choice.delta.content, choice.delta.tool_calls))
+                .unwrap_or_default();
+            Ok(ollama_object(
+                api,
+                model,
+                content,
+                tool_calls,
+                finish_reason.as_deref(),
+                chunk.usage.as_ref(),
+            ))
+        }
+        Response::CompletionChunk(chunk) => {
+            let choice = chunk.choices.into_iter().next();
+            let finish_reason = choice.as_ref().and_then(|c| c.finish_reason.clone());
+            Ok(ollama_object(
+                api,
+                model,
+                choice.map(|choice| choice.text),
+                None,
+                finish_reason.as_deref(),
+                chunk.usage.as_ref(),
+            ))
+        }
+        Response::Done(response) => {
+            let choice = response.choices.into_iter().next();
+            let finish_reason = choice.as_ref().map(|c| c.finish_reason.clone());
+            let (content, tool_calls) = choice
+                .map(|choice| (choice.message.content, choice.message.tool_calls))
+                .unwrap_or_default();
+            Ok(ollama_object(
+                api,
+                model,
+                content,
+                tool_calls,
+                Some(finish_reason.as_deref().unwrap_or("stop")),
+                Some(&response.usage),
+            ))
+        }
+        Response::CompletionDone(response) => {
+            let choice = response.choices.into_iter().next();
+            let finish_reason = choice.as_ref().map(|c| c.finish_reason.clone());
+            Ok(ollama_object(
+                api,
+                model,
+                choice.map(|choice| choice.text),
+                None,
+                Some(finish_reason.as_deref().unwrap_or("stop")),
+                Some(&response.usage),
+            ))
+        }
+        Response::ValidationError(e) => {
+            Err((StatusCode::BAD_REQUEST, sanitize_error_message(e.as_ref())))
+        }
+        Response::InternalError(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            sanitize_error_message(e.as_ref()),
+        )),
+        Response::ModelError(msg, _) | Response::CompletionModelError(msg, _) => {
+            Err((StatusCode::INTERNAL_SERVER_ERROR, msg))
+        }
+        _ => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unexpected response type".to_string(),
+        )),
+    }
+}
+
+/// Send a request, and respond with its stream of response objects or its single one. The
+/// tokens of the request are charged with `charger`, as the rate limiter does not read the usage
+/// of Ollama responses.
+#[allow(clippy::too_many_arguments)]
+async fn respond(
+    state: SharedMistralRsState,
+    headers: &HeaderMap,
+    policy: Option<&ApiKeyPolicy>,
+    charger: Option<TokenCharger>,
+    mut request: Request,
+    api: Api,
+    model: String,
+    mut rx: Receiver<Response>,
+    is_streaming: bool,
+) -> axum::response::Response {
+    set_tenant_from_api_key(&mut request, headers);
+    let model_id = (model != "default").then_some(model.as_str());
+    if let Err(response) = apply_max_context(&state, &mut request, policy, model_id).await {
+        return response;
+    }
+    if let Err(e) = send_request_with_model(&state, request, model_id).await {
+        return ollama_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+
+    if !is_streaming {
+        let Some(response) = rx.recv().await else {
+            return ollama_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "No response received from the model.",
+            );
+        };
+        if let Some(charger) = &charger {
+            charger.charge_response(&response);
+        }
+        return match convert_response(api, &model, response) {
+            Ok(object) => Json(object).into_response(),
+            Err((status, message)) => ollama_error(status, message),
+        };
+    }
+
+    // The stream ends after the object which is done, or after an error
+    let objects = stream::unfold(Some(rx), move |rx| {
+        let model = model.clone();
+        let charger = charger.clone();
+        async move {
+            let mut rx = rx?;
+            let response = rx.recv().await?;
+            if let Some(charger) = &charger {
+                charger.charge_response(&response);
+            }
+            let (object, rx) = match convert_response(api, &model, response) {
+                Ok(object) => {
+                    let done = object["done"].as_bool().unwrap_or_default();
+                    (object, (!done).then_some(rx))
+                }
+                Err((_, message)) => (json!({ "error": message }), None),
+            };
+            let mut line = serde_json::to_vec(&object).expect("Serialization of object failed.");
+            line.push(b'\n');
+            Some((Ok::<_, std::convert::Infallible>(Bytes::from(line)), rx))
+        }
+    });
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(objects),
+    )
+        .into_response()
+}
+
+/// The response to a request without messages or a prompt, which Ollama tools send to load a
+/// model. The models are already loaded.
+fn loaded(api: Api, model: &str) -> axum::response::Response {
+    let mut object = ollama_object(api, model, None, None, Some("stop"), None);
+    object["done_reason"] = "load".into();
+    Json(object).into_response()
+}
+
+/// Ollama chat endpoint handler.
+pub async fn chat(
+    State(state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    charger: Option<Extension<TokenCharger>>,
+    Json(request): Json<OllamaChatRequest>,
+) -> axum::response::Response {
+    let model = match resolve_model(&state, &request.model) {
+        Ok(model) => model,
+        Err(response) => return response,
+    };
+    if request.messages.is_empty() {
+        return loaded(Api::Chat, &request.model);
+    }
+    let is_streaming = request.stream.unwrap_or(true);
+    let oairequest = match chat_completion_request(
+        model.clone(),
+        request.messages.into_iter().map(openai_message).collect(),
+        request.tools,
+        request.format,
+        request.options,
+        request.think,
+        is_streaming,
+    ) {
+        Ok(oairequest) => oairequest,
+        Err(e) => return ollama_error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+
+    let (tx, rx) = create_response_channel(None);
+    let core_request = match chat_completion::parse_request(oairequest, state.clone(), tx).await {
+        Ok((core_request, _)) => core_request,
+        Err(e) => return ollama_error(StatusCode::BAD_REQUEST, sanitize_error_message(&*e)),
+    };
+    respond(
+        state,
+        &headers,
+        policy.as_deref(),
+        charger.map(|Extension(charger)| charger),
+        core_request,
+        Api::Chat,
+        model,
+        rx,
+        is_streaming,
+    )
+    .await
+}
+
+/// Ollama generate endpoint handler. The prompt is sent with the chat template of the model,
+/// after the `system` prompt, unless the request is `raw`.
+pub async fn generate(
+    State(state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    charger: Option<Extension<TokenCharger>>,
+    Json(request): Json<OllamaGenerateRequest>,
+) -> axum::response::Response {
+    let model = match resolve_model(&state, &request.model) {
+        Ok(model) => model,
+        Err(response) => return response,
+    };
+    if request.prompt.is_empty() {
+        return loaded(Api::Generate, &request.model);
+    }
+    let is_streaming = request.stream.unwrap_or(true);
+    let (tx, rx) = create_response_channel(None);
+
+    let parsed = if request.raw {
+        let mut oairequest = sampling_fields(request.options);
+        oairequest.insert("model".to_string(), model.clone().into());
+        oairequest.insert("prompt".to_string(), request.prompt.into());
+        oairequest.insert("stream".to_string(), is_streaming.into());
+        match serde_json::from_value::<CompletionRequest>(Value::Object(oairequest)) {
+            Ok(oairequest) => completions::parse_request(oairequest, state.clone(), tx),
+            Err(e) => return ollama_error(StatusCode::BAD_REQUEST, e.to_string()),
+        }
+    } else {
+        let mut messages = Vec::new();
+        if let Some(system) = request.system {
+            messages.push(json!({ "role": "system", "content": system }));
+        }
+        messages.push(openai_message(OllamaMessage {
+            role: "user".to_string(),
+            content: request.prompt,
+            images: request.images,
+            tool_calls: None,
+        }));
+        match chat_completion_request(
+            model.clone(),
+            messages,
+            None,
+            request.format,
+            request.options,
+            request.think,
+            is_streaming,
+        ) {
+            Ok(oairequest) => chat_completion::parse_request(oairequest, state.clone(), tx).await,
+            Err(e) => return ollama_error(StatusCode::BAD_REQUEST, e.to_string()),
+        }
+    };
+    let core_request = match parsed {
+        Ok((core_request, _)) => core_request,
+        Err(e) => return ollama_error(StatusCode::BAD_REQUEST, sanitize_error_message(&*e)),
+    };
+    respond(
+        state,
+        &headers,
+        policy.as_deref(),
+        charger.map(|Extension(charger)| charger),
+        core_request,
+        Api::Generate,
+        model,
+        rx,
+        is_streaming,
+    )
+    .await
+}
+
+/// Ollama endpoint handler which lists the loaded models.
+pub async fn tags(State(state): ExtractedMistralRsState) -> Json<Value> {
+    let modified_at = rfc3339(state.get_creation_time());
+    let models = state
+        .list_models()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|model_id| {
+            json!({
+                "name": model_id,
+                "model": model_id,
+                "modified_at": modified_at,
+                "size": 0,
+                "digest": "",
+                "details": {
+                    "format": "",
+                    "family": "",
+                    "families": [],
+                    "parameter_size": "",
+                    "quantization_level": "",
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    Json(json!({ "models": models }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{chat_completion_request, openai_message, rfc3339, OllamaChatRequest};
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn converts_chat_requests() {
+        let request: OllamaChatRequest = serde_json::from_value(json!({
+            "model": "llama",
+            "messages": [{"role": "user", "content": "Hi", "images": ["aGk="]}],
+            "format": "json",
+            "options": {"temperature": 0.5, "num_predict": -1, "num_ctx": 4096},
+        }))
+        .unwrap();
+        let messages = request
+            .messages
+            .into_iter()
+            .map(openai_message)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages[0]["content"][1]["image_url"]["url"],
+            "data:image/png;base64,aGk="
+        );
+
+        let oairequest = chat_completion_request(
+            request.model,
+            messages,
+            request.tools,
+            request.format,
+            request.options,
+            request.think,
+            true,
+        )
+        .unwrap();
+        assert_eq!(oairequest.temperature, Some(0.5));
+        // A negative `num_predict` is no limit
+        assert_eq!(oairequest.max_tokens, None);
+        assert_eq!(oairequest.stream, Some(true));
+        assert!(oairequest.response_format.is_some());
+    }
+}
@@ -236,6 +236,17 @@ pub enum StopTokens {
     Single(String),
 }
 
+/// A LoRA adapter to run a request with, either by name or with a weight which its output is
+/// scaled by.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum AdapterSelection {
+    /// Adapter name, applied with a weight of 1
+    Name(String),
+    /// Adapter name and weight
+    Weighted { name: String, weight: f64 },
+}
+
 /// Default value helper
 fn default_false() -> bool {
     false
@@ -313,6 +324,12 @@ pub enum Grammar {
     /// Lark parser grammar
     #[serde(rename = "lark")]
     Lark(String),
+    /// GBNF grammar, in the format of llama.cpp
+    #[serde(rename = "gbnf")]
+    Gbnf(String),
+    /// Exactly one of the given strings
+    #[serde(rename = "choice")]
+    Choice(Vec<String>),
 }
 
 // Implement ToSchema manually to handle `LlguidanceGrammar`
@@ -348,6 +365,26 @@ impl PartialSchema for Grammar {
                             .build(),
                     ),
                 ))
+                .item(create_grammar_variant_schema(
+                    "gbnf",
+                    Schema::Object(
+                        ObjectBuilder::new()
+                            .schema_type(SchemaType::Type(Type::String))
+                            .build(),
+                    ),
+                ))
+                .item(create_grammar_variant_schema(
+                    "choice",
+                    Schema::Array(
+                        ArrayBuilder::new()
+                            .items(RefOr::T(Schema::Object(
+                                ObjectBuilder::new()
+                                    .schema_type(SchemaType::Type(Type::String))
+                                    .build(),
+                            )))
+                            .build(),
+                    ),
+                ))
                 .build(),
         ))
     }
@@ -455,8 +492,15 @@ fn llguidance_schema() -> Schema {
 /// JSON Schema for structured responses
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct JsonSchemaResponseFormat {
+    /// The name of the schema, of up to 64 letters, digits, underscores, and dashes
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
     pub schema: serde_json::Value,
+    /// Accepted for compatibility: the output always follows the schema, as it is enforced while
+    /// decoding
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
 }
 
 /// Response format for model output
@@ -466,6 +510,9 @@ pub enum ResponseFormat {
     /// Free-form text response
     #[serde(rename = "text")]
     Text,
+    /// Any JSON object
+    #[serde(rename = "json_object")]
+    JsonObject,
     /// Structured response following a JSON schema
     #[serde(rename = "json_schema")]
     JsonSchema {
@@ -473,6 +520,37 @@ pub enum ResponseFormat {
     },
 }
 
+/// Output format of a responses request, in the shape of the `text.format` of the Responses API
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type")]
+pub enum ResponsesTextFormat {
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "json_object")]
+    JsonObject,
+    #[serde(rename = "json_schema")]
+    JsonSchema(JsonSchemaResponseFormat),
+}
+
+impl From<ResponsesTextFormat> for ResponseFormat {
+    fn from(format: ResponsesTextFormat) -> Self {
+        match format {
+            ResponsesTextFormat::Text => ResponseFormat::Text,
+            ResponsesTextFormat::JsonObject => ResponseFormat::JsonObject,
+            ResponsesTextFormat::JsonSchema(json_schema) => {
+                ResponseFormat::JsonSchema { json_schema }
+            }
+        }
+    }
+}
+
+/// Text output configuration of a responses request
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ResponsesTextConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<ResponsesTextFormat>,
+}
+
 /// Chat completion request following OpenAI's specification
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ChatCompletionRequest {
@@ -522,6 +600,9 @@ pub struct ChatCompletionRequest {
     pub response_format: Option<ResponseFormat>,
     #[schema(example = json!(Option::None::<WebSearchOptions>))]
     pub web_search_options: Option<WebSearchOptions>,
+    /// Identifies the client for fair-share scheduling.
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
@@ -530,6 +611,8 @@ pub struct ChatCompletionRequest {
     pub grammar: Option<Grammar>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
     #[schema(example = json!(Option::None::<f32>))]
     pub dry_multiplier: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
@@ -538,8 +621,41 @@ pub struct ChatCompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_probability: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_threshold: Option<f32>,
+    /// Strings which may not appear in the output.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub banned_strings: Option<Vec<String>>,
+    /// Never repeat an n-gram of this many tokens.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+    /// Generate the last token of the prompt again.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub token_healing: Option<bool>,
+    /// The scale of classifier-free guidance, away from `negative_prompt`.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub guidance_scale: Option<f32>,
+    /// The negative prompt of classifier-free guidance.
+    #[schema(example = json!(Option::None::<String>))]
+    pub negative_prompt: Option<String>,
+    /// The degeneration penalty of contrastive search among the `top_k` most likely tokens.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub penalty_alpha: Option<f32>,
+    /// Seed of the random number generator, so the same request gives the same output.
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    /// Regexes which stop the generation once the output matches one of them.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub stop_regexes: Option<Vec<String>>,
+    /// Keep the text which matched a stop sequence or regex at the end of the output.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub include_stop_str_in_output: Option<bool>,
     #[schema(example = json!(Option::None::<bool>))]
     pub enable_thinking: Option<bool>,
+    #[schema(example = json!(Option::None::<Vec<AdapterSelection>>))]
+    pub adapters: Option<Vec<AdapterSelection>>,
 }
 
 /// Function for ChatCompletionRequest.messages Schema generation to handle `Either`
@@ -626,8 +742,9 @@ pub struct CompletionRequest {
     pub top_p: Option<f64>,
     #[schema(example = json!(Option::None::<String>))]
     pub suffix: Option<String>,
-    #[serde(rename = "user")]
-    pub _user: Option<String>,
+    /// Identifies the client for fair-share scheduling.
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
     #[schema(example = json!(Option::None::<Vec<Tool>>))]
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
@@ -640,6 +757,8 @@ pub struct CompletionRequest {
     pub grammar: Option<Grammar>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
     #[schema(example = json!(Option::None::<f32>))]
     pub repetition_penalty: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
@@ -650,6 +769,39 @@ pub struct CompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_probability: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_threshold: Option<f32>,
+    /// Strings which may not appear in the output.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub banned_strings: Option<Vec<String>>,
+    /// Never repeat an n-gram of this many tokens.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+    /// Generate the last token of the prompt again.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub token_healing: Option<bool>,
+    /// The scale of classifier-free guidance, away from `negative_prompt`.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub guidance_scale: Option<f32>,
+    /// The negative prompt of classifier-free guidance.
+    #[schema(example = json!(Option::None::<String>))]
+    pub negative_prompt: Option<String>,
+    /// The degeneration penalty of contrastive search among the `top_k` most likely tokens.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub penalty_alpha: Option<f32>,
+    /// Seed of the random number generator, so the same request gives the same output.
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    /// Regexes which stop the generation once the output matches one of them.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub stop_regexes: Option<Vec<String>>,
+    /// Keep the text which matched a stop sequence or regex at the end of the output.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub include_stop_str_in_output: Option<bool>,
+    #[schema(example = json!(Option::None::<Vec<AdapterSelection>>))]
+    pub adapters: Option<Vec<AdapterSelection>>,
 }
 
 /// Image generation request
@@ -732,6 +884,192 @@ pub struct SpeechGenerationRequest {
     pub response_format: AudioResponseFormat,
 }
 
+/// The texts to embed, either one text or several
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    /// Several texts, embedded in one batch
+    Multi(Vec<String>),
+    /// A single text
+    Single(String),
+}
+
+/// The encoding of the returned embeddings
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingEncodingFormat {
+    /// A list of floats
+    #[default]
+    Float,
+    /// The little-endian bytes of the 32-bit floats, encoded as base64
+    Base64,
+}
+
+/// Embeddings request
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingRequest {
+    /// The embedding model to use.
+    #[schema(example = "default")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    /// The texts to embed.
+    #[schema(example = json!(["The food was delicious.", "The waiter was friendly."]))]
+    pub input: EmbeddingInput,
+    /// The encoding of the returned embeddings.
+    #[serde(default)]
+    pub encoding_format: EmbeddingEncodingFormat,
+}
+
+/// An embedding, as floats or as base64
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+/// The embedding of one input text
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingData {
+    pub object: String,
+    /// The index of the text in the input.
+    pub index: usize,
+    pub embedding: EmbeddingVector,
+}
+
+/// Usage of an embeddings request
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// Embeddings response
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+/// An uploaded file
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct FileObject {
+    pub id: String,
+    pub object: String,
+    /// The size of the file in bytes.
+    pub bytes: usize,
+    /// The Unix timestamp (in seconds) of when the file was created.
+    pub created_at: u64,
+    pub filename: String,
+    /// `batch` for the input of a batch, and `batch_output` for its output and errors.
+    pub purpose: String,
+    /// The Unix timestamp (in seconds) of when the file is deleted.
+    pub expires_at: u64,
+}
+
+/// The list of uploaded files
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct FileListResponse {
+    pub object: String,
+    pub data: Vec<FileObject>,
+}
+
+/// The response to deleting a file
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct FileDeleteResponse {
+    pub id: String,
+    pub object: String,
+    pub deleted: bool,
+}
+
+/// Batch creation request
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct BatchCreateRequest {
+    /// The ID of an uploaded JSONL file with one request per line.
+    #[schema(example = "file-abc123")]
+    pub input_file_id: String,
+    /// The endpoint of every request of the batch: `/v1/chat/completions`, `/v1/completions` or
+    /// `/v1/embeddings`.
+    #[schema(example = "/v1/chat/completions")]
+    pub endpoint: String,
+    /// The time frame of the batch. It is accepted for compatibility, and batches do not expire.
+    #[schema(example = "24h")]
+    #[serde(default = "default_completion_window")]
+    pub completion_window: String,
+    #[schema(example = json!(Option::None::<HashMap<String, String>>))]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+fn default_completion_window() -> String {
+    "24h".to_string()
+}
+
+/// The progress of the requests of a batch
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct BatchRequestCounts {
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+}
+
+/// An error in the input file of a batch
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct BatchError {
+    pub code: String,
+    pub message: String,
+    /// The line of the input file, starting at 1.
+    pub line: Option<usize>,
+}
+
+/// The errors in the input file of a batch
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct BatchErrors {
+    pub object: String,
+    pub data: Vec<BatchError>,
+}
+
+/// A batch of requests, processed in the background
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct BatchObject {
+    pub id: String,
+    pub object: String,
+    pub endpoint: String,
+    /// The errors which failed the batch before any of its requests ran.
+    pub errors: Option<BatchErrors>,
+    pub input_file_id: String,
+    pub completion_window: String,
+    /// `validating`, `failed`, `in_progress`, `finalizing`, `completed`, `cancelling` or
+    /// `cancelled`.
+    pub status: String,
+    /// The file with the responses of the requests which succeeded.
+    pub output_file_id: Option<String>,
+    /// The file with the responses of the requests which failed.
+    pub error_file_id: Option<String>,
+    pub created_at: u64,
+    pub in_progress_at: Option<u64>,
+    pub finalizing_at: Option<u64>,
+    pub completed_at: Option<u64>,
+    pub failed_at: Option<u64>,
+    pub cancelling_at: Option<u64>,
+    pub cancelled_at: Option<u64>,
+    /// The Unix timestamp (in seconds) after which the batch is deleted once it has finished.
+    pub expires_at: u64,
+    pub request_counts: BatchRequestCounts,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+/// The list of batches
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct BatchListResponse {
+    pub object: String,
+    pub data: Vec<BatchObject>,
+    pub first_id: Option<String>,
+    pub last_id: Option<String>,
+    pub has_more: bool,
+}
+
 /// Helper type for messages field in ResponsesCreateRequest
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
@@ -815,8 +1153,14 @@ pub struct ResponsesCreateRequest {
     pub tool_choice: Option<ToolChoice>,
     #[schema(example = json!(Option::None::<ResponseFormat>))]
     pub response_format: Option<ResponseFormat>,
+    /// The output format, as in the Responses API. Exclusive with `response_format`.
+    #[schema(example = json!(Option::None::<ResponsesTextConfig>))]
+    pub text: Option<ResponsesTextConfig>,
     #[schema(example = json!(Option::None::<WebSearchOptions>))]
     pub web_search_options: Option<WebSearchOptions>,
+    /// Identifies the client for fair-share scheduling.
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
     #[schema(example = json!(Option::None::<Value>))]
     pub metadata: Option<Value>,
     #[schema(example = json!(Option::None::<bool>))]
@@ -843,6 +1187,8 @@ pub struct ResponsesCreateRequest {
     pub grammar: Option<Grammar>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
     #[schema(example = json!(Option::None::<f32>))]
     pub repetition_penalty: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
@@ -853,8 +1199,41 @@ pub struct ResponsesCreateRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_probability: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_threshold: Option<f32>,
+    /// Strings which may not appear in the output.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub banned_strings: Option<Vec<String>>,
+    /// Never repeat an n-gram of this many tokens.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+    /// Generate the last token of the prompt again.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub token_healing: Option<bool>,
+    /// The scale of classifier-free guidance, away from `negative_prompt`.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub guidance_scale: Option<f32>,
+    /// The negative prompt of classifier-free guidance.
+    #[schema(example = json!(Option::None::<String>))]
+    pub negative_prompt: Option<String>,
+    /// The degeneration penalty of contrastive search among the `top_k` most likely tokens.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub penalty_alpha: Option<f32>,
+    /// Seed of the random number generator, so the same request gives the same output.
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    /// Regexes which stop the generation once the output matches one of them.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub stop_regexes: Option<Vec<String>>,
+    /// Keep the text which matched a stop sequence or regex at the end of the output.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub include_stop_str_in_output: Option<bool>,
     #[schema(example = json!(Option::None::<bool>))]
     pub enable_thinking: Option<bool>,
+    #[schema(example = json!(Option::None::<Vec<AdapterSelection>>))]
+    pub adapters: Option<Vec<AdapterSelection>>,
 }
 
 /// Response object
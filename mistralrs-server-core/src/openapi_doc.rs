@@ -3,26 +3,46 @@
 use utoipa::OpenApi;
 
 use crate::{
+    batches::{
+        __path_cancel_batch, __path_create_batch, __path_list_batches, __path_retrieve_batch,
+    },
     chat_completion::__path_chatcompletions,
     completions::__path_completions,
-    handlers::{ReIsqRequest, __path_health, __path_models, __path_re_isq},
+    embeddings::__path_embeddings,
+    files::{
+        __path_delete_file, __path_list_files, __path_retrieve_file, __path_retrieve_file_content,
+        __path_upload_file,
+    },
+    handlers::{
+        ReIsqRequest, __path_cancel_request, __path_health, __path_models, __path_re_isq,
+        __path_stats,
+    },
+    health::{
+        ModelHealth, ModelState, ProbeResult, ReadinessResponse, ReadinessStatus, __path_healthz,
+        __path_readyz,
+    },
     image_generation::__path_image_generation,
+    metrics::__path_metrics,
     openai::{
-        AudioResponseFormat, ChatCompletionRequest, CompletionRequest, FunctionCalled, Grammar,
-        ImageGenerationRequest, JsonSchemaResponseFormat, Message, MessageContent,
-        MessageInnerContent, ModelObject, ModelObjects, ResponseFormat, ResponsesAnnotation,
-        ResponsesChunk, ResponsesContent, ResponsesCreateRequest, ResponsesDelta,
-        ResponsesDeltaContent, ResponsesDeltaOutput, ResponsesError, ResponsesIncompleteDetails,
-        ResponsesInputTokensDetails, ResponsesMessages, ResponsesObject, ResponsesOutput,
-        ResponsesOutputTokensDetails, ResponsesUsage, SpeechGenerationRequest, StopTokens,
-        ToolCall,
+        AdapterSelection, AudioResponseFormat, BatchCreateRequest, BatchError, BatchErrors,
+        BatchListResponse, BatchObject, BatchRequestCounts, ChatCompletionRequest, CompletionRequest,
+        EmbeddingData, EmbeddingEncodingFormat, EmbeddingInput, EmbeddingRequest,
+        EmbeddingResponse, EmbeddingUsage, EmbeddingVector, FileDeleteResponse, FileListResponse, FileObject, FunctionCalled, Grammar, ImageGenerationRequest, JsonSchemaResponseFormat, Message,
+        MessageContent, MessageInnerContent, ModelObject, ModelObjects, ResponseFormat,
+        ResponsesAnnotation, ResponsesChunk, ResponsesContent, ResponsesCreateRequest,
+        ResponsesDelta, ResponsesDeltaContent, ResponsesDeltaOutput, ResponsesError,
+        ResponsesIncompleteDetails, ResponsesInputTokensDetails, ResponsesMessages,
+        ResponsesObject, ResponsesOutput, ResponsesOutputTokensDetails, ResponsesTextConfig,
+        ResponsesTextFormat, ResponsesUsage,
+        SpeechGenerationRequest, StopTokens, ToolCall,
     },
     responses::{__path_create_response, __path_delete_response, __path_get_response},
     speech_generation::__path_speech_generation,
+    tenants::{TenantUsage, __path_tenant_usage},
 };
 use mistralrs_core::{
-    ApproximateUserLocation, Function, ImageGenerationResponseFormat, SearchContextSize, Tool,
-    ToolChoice, ToolType, WebSearchOptions, WebSearchUserLocation,
+    ApproximateUserLocation, EngineStats, Function, HistogramBucket, ImageGenerationResponseFormat,
+    KvCacheStats, LatencyHistogram, SearchContextSize, Tool, ToolChoice, ToolType, WebSearchOptions, WebSearchUserLocation,
 };
 
 /// This is used to generate the OpenAPI docs.
@@ -67,23 +87,49 @@ use mistralrs_core::{
 pub fn get_openapi_doc(base_path: Option<&str>) -> utoipa::openapi::OpenApi {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions, completions, re_isq, image_generation, speech_generation, create_response, get_response, delete_response),
+        paths(models, health, healthz, readyz, stats, metrics, chatcompletions, completions, embeddings, upload_file, list_files, retrieve_file, retrieve_file_content, delete_file, create_batch, list_batches, retrieve_batch, cancel_batch, re_isq, cancel_request, image_generation, speech_generation, create_response, get_response, delete_response, tenant_usage),
         components(schemas(
+            AdapterSelection,
             ApproximateUserLocation,
             AudioResponseFormat,
+            BatchCreateRequest,
+            BatchError,
+            BatchErrors,
+            BatchListResponse,
+            BatchObject,
+            BatchRequestCounts,
             ChatCompletionRequest,
             CompletionRequest,
+            EmbeddingData,
+            EmbeddingEncodingFormat,
+            EmbeddingInput,
+            EmbeddingRequest,
+            EmbeddingResponse,
+            EmbeddingUsage,
+            EmbeddingVector,
+            EngineStats,
+            FileDeleteResponse,
+            FileListResponse,
+            FileObject,
             Function,
             FunctionCalled,
             Grammar,
+            HistogramBucket,
             ImageGenerationRequest,
             ImageGenerationResponseFormat,
             JsonSchemaResponseFormat,
+            KvCacheStats,
+            LatencyHistogram,
             Message,
             MessageContent,
             MessageInnerContent,
+            ModelHealth,
             ModelObject,
             ModelObjects,
+            ModelState,
+            ProbeResult,
+            ReadinessResponse,
+            ReadinessStatus,
             ReIsqRequest,
             ResponseFormat,
             ResponsesAnnotation,
@@ -100,10 +146,13 @@ pub fn get_openapi_doc(base_path: Option<&str>) -> utoipa::openapi::OpenApi {
             ResponsesObject,
             ResponsesOutput,
             ResponsesOutputTokensDetails,
+            ResponsesTextConfig,
+            ResponsesTextFormat,
             ResponsesUsage,
             SearchContextSize,
             SpeechGenerationRequest,
             StopTokens,
+            TenantUsage,
             Tool,
             ToolCall,
             ToolChoice,
@@ -0,0 +1,586 @@
+//! ## Token-bucket rate limiting of the requests and tokens of each client.
+//!
+//! A client is identified by its API key once it was authenticated, or else by its IP address. The
+//! buckets of idle clients are dropped, as they are full. The tokens of
+//! a request are only known once its response is sent, so they are charged then: a client whose
+//! tokens bucket is empty waits until it has refilled, even if this was overdrawn. The tokens in
+//! the usage of the chat completion, completion, responses, and embedding responses are charged
+//! as they are sent, and the handlers whose responses have no such usage (the Ollama API,
+//! WebSockets, and batches) charge their tokens with the [`TokenCharger`] of the request.
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request as HttpRequest, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER},
+        HeaderMap, StatusCode,
+    },
+    middleware::Next,
+};
+use futures::StreamExt;
+use mistralrs_core::Response;
+use serde_json::Value;
+
+use crate::{
+    auth::{is_public_route, ApiKeyPolicy},
+    handler_core::{ErrorToResponse, JsonError},
+};
+
+/// How often the buckets of idle clients are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The routes whose responses hold the usage of their request, which is charged as they are sent.
+const USAGE_ROUTES: [&str; 4] = [
+    "/v1/chat/completions",
+    "/v1/completions",
+    "/v1/responses",
+    "/v1/embeddings",
+];
+
+/// The default limits of each client. The policy of an API key overrides them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimitConfig {
+    /// The requests per minute, which is also the largest burst of requests.
+    pub requests_per_minute: Option<usize>,
+    /// The prompt and generated tokens per minute.
+    pub tokens_per_minute: Option<usize>,
+}
+
+struct TokenBucket {
+    /// The capacity, which is refilled over a minute.
+    capacity: f64,
+    /// What is left, negative if overdrawn.
+    available: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: usize, now: Instant) -> Self {
+        Self {
+            capacity: per_minute as f64,
+            available: per_minute as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// How long to wait until `amount` is available, if it is not now.
+    fn wait_for(&mut self, amount: f64, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        (self.available < amount).then(|| {
+            Duration::from_secs_f64((amount - self.available) * 60. / self.capacity.max(1.))
+        })
+    }
+
+    fn take(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.available -= amount;
+    }
+}
+
+#[derive(Default)]
+struct ClientBuckets {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+impl ClientBuckets {
+    /// Whether the buckets have refilled, so that new ones would be the same.
+    fn is_full(&mut self, now: Instant) -> bool {
+        [&mut self.requests, &mut self.tokens]
+            .into_iter()
+            .flatten()
+            .all(|bucket| {
+                bucket.refill(now);
+                bucket.available >= bucket.capacity
+            })
+    }
+}
+
+struct Clients {
+    buckets: HashMap<String, ClientBuckets>,
+    swept: Instant,
+}
+
+/// The buckets of each client.
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    clients: Mutex<Clients>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            clients: Mutex::new(Clients {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// The limits of a client with the API key `policy`.
+    pub(crate) fn limits(&self, policy: Option<&ApiKeyPolicy>) -> RateLimitConfig {
+        RateLimitConfig {
+            requests_per_minute: policy
+                .and_then(|policy| policy.requests_per_minute)
+                .or(self.config.requests_per_minute),
+            tokens_per_minute: policy
+                .and_then(|policy| policy.tokens_per_minute)
+                .or(self.config.tokens_per_minute),
+        }
+    }
+
+    /// Admit a request of `client`, returning how long to wait if it is over one of its limits.
+    pub(crate) fn admit(
+        &self,
+        client: &str,
+        limits: RateLimitConfig,
+        now: Instant,
+    ) -> Option<Duration> {
+        let mut clients = self.clients.lock().unwrap();
+        if now.saturating_duration_since(clients.swept) >= SWEEP_INTERVAL {
+            clients.buckets.retain(|_, buckets| !buckets.is_full(now));
+            clients.swept = now;
+        }
+        let buckets = clients.buckets.entry(client.to_string()).or_default();
+        let mut wait = None;
+        if let Some(limit) = limits.requests_per_minute {
+            wait = buckets
+                .requests
+                .get_or_insert_with(|| TokenBucket::new(limit, now))
+                .wait_for(1., now);
+        }
+        if let Some(limit) = limits.tokens_per_minute {
+            // Any tokens left admit a request, whose tokens are charged once they are known
+            let bucket = buckets
+                .tokens
+                .get_or_insert_with(|| TokenBucket::new(limit, now));
+            wait = wait.max(bucket.wait_for(f64::MIN_POSITIVE, now));
+        }
+        if wait.is_none() {
+            if let Some(bucket) = &mut buckets.requests {
+                bucket.take(1., now);
+            }
+        }
+        wait
+    }
+
+    /// Charge `tokens` to `client`, whose limit is `per_minute`. The buckets of the client may have
+    /// been dropped while the request ran, so they are made again.
+    pub(crate) fn charge_tokens(
+        &self,
+        client: &str,
+        tokens: usize,
+        per_minute: usize,
+        now: Instant,
+    ) {
+        self.clients
+            .lock()
+            .unwrap()
+            .buckets
+            .entry(client.to_string())
+            .or_default()
+            .tokens
+            .get_or_insert_with(|| TokenBucket::new(per_minute, now))
+            .take(tokens as f64, now);
+    }
+
+    /// How long `client`, whose limit is `per_minute`, waits until it has tokens left, if it has
+    /// none now.
+    fn tokens_wait(&self, client: &str, per_minute: usize, now: Instant) -> Option<Duration> {
+        self.clients
+            .lock()
+            .unwrap()
+            .buckets
+            .entry(client.to_string())
+            .or_default()
+            .tokens
+            .get_or_insert_with(|| TokenBucket::new(per_minute, now))
+            .wait_for(f64::MIN_POSITIVE, now)
+    }
+}
+
+/// The tokens bucket of the client of a request, in its extensions if its tokens are limited. The
+/// handlers whose responses do not hold a usage charge the tokens of their requests with it.
+#[derive(Clone)]
+pub(crate) struct TokenCharger {
+    limiter: Arc<RateLimiter>,
+    client: String,
+    tokens_per_minute: usize,
+}
+
+impl TokenCharger {
+    /// Charge `tokens` to the client.
+    pub(crate) fn charge(&self, tokens: usize) {
+        self.limiter
+            .charge_tokens(&self.client, tokens, self.tokens_per_minute, Instant::now());
+    }
+
+    /// Charge the tokens of `response` if it is the last response of its request, which holds
+    /// its usage.
+    pub(crate) fn charge_response(&self, response: &Response) {
+        if let Some(tokens) = response_tokens(response) {
+            self.charge(tokens);
+        }
+    }
+
+    /// How long to wait until the client has tokens left, if it has none now.
+    pub(crate) fn wait(&self) -> Option<Duration> {
+        self.limiter
+            .tokens_wait(&self.client, self.tokens_per_minute, Instant::now())
+    }
+}
+
+/// The prompt and generated tokens of a model response, if it has the usage of its request.
+fn response_tokens(response: &Response) -> Option<usize> {
+    match response {
+        Response::Done(response) => Some(response.usage.total_tokens),
+        Response::CompletionDone(response) => Some(response.usage.total_tokens),
+        Response::Chunk(chunk) => chunk.usage.as_ref().map(|usage| usage.total_tokens),
+        Response::CompletionChunk(chunk) => chunk.usage.as_ref().map(|usage| usage.total_tokens),
+        _ => None,
+    }
+}
+
+/// The client of a request: a hash of its API key if it was authenticated, or its IP address.
+/// The keys of unauthenticated requests are not trusted, as any key would give a new client.
+pub(crate) fn client_id(
+    headers: &HeaderMap,
+    authenticated: bool,
+    address: Option<SocketAddr>,
+) -> String {
+    match headers.get(AUTHORIZATION).filter(|_| authenticated) {
+        Some(key) => {
+            let mut hasher = DefaultHasher::new();
+            key.as_bytes().hash(&mut hasher);
+            format!("key-{:016x}", hasher.finish())
+        }
+        None => match address {
+            Some(address) => format!("ip-{}", address.ip()),
+            None => "unknown".to_string(),
+        },
+    }
+}
+
+/// The tokens in the usage of a response.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct TokenUsage {
+    pub(crate) prompt_tokens: usize,
+    pub(crate) completion_tokens: usize,
+    pub(crate) total_tokens: usize,
+}
+
+/// The usage of a response body or stream event, of the chat completions, completions, or
+/// responses API.
+pub(crate) fn usage_tokens(body: &Value) -> Option<TokenUsage> {
+    let usage = body
+        .get("usage")
+        .or_else(|| body.get("response")?.get("usage"))?;
+    let tokens = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|key| usage.get(key)?.as_u64())
+            .map(|tokens| tokens as usize)
+    };
+    Some(TokenUsage {
+        prompt_tokens: tokens(&["prompt_tokens", "input_tokens"]).unwrap_or_default(),
+        completion_tokens: tokens(&["completion_tokens", "output_tokens"]).unwrap_or_default(),
+        total_tokens: tokens(&["total_tokens"])?,
+    })
+}
+
+/// The format of a response body which may hold a usage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BodyFormat {
+    Json,
+    EventStream,
+    /// Bodies of other content types, such as files, hold no usage and are not scanned.
+    Other,
+}
+
+/// Finds the usage in a response body as it is sent, a JSON object or server-sent events.
+pub(crate) struct UsageScanner {
+    format: BodyFormat,
+    buffer: Vec<u8>,
+}
+
+impl UsageScanner {
+    /// A scanner of the body of a response with `headers`.
+    pub(crate) fn new(headers: &HeaderMap) -> Self {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let format = if content_type.starts_with("text/event-stream") {
+            BodyFormat::EventStream
+        } else if content_type.starts_with("application/json") {
+            BodyFormat::Json
+        } else {
+            BodyFormat::Other
+        };
+        Self {
+            format,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Add a chunk of the body, returning the usage it completes.
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Option<TokenUsage> {
+        match self.format {
+            BodyFormat::Other => return None,
+            BodyFormat::Json => {
+                self.buffer.extend_from_slice(chunk);
+                // The body is only parsed once it may be complete, so it is not parsed again for
+                // each chunk
+                if self.buffer.trim_ascii_end().last() != Some(&b'}') {
+                    return None;
+                }
+                let tokens = serde_json::from_slice::<Value>(&self.buffer)
+                    .ok()
+                    .map(|body| usage_tokens(&body));
+                if tokens.is_some() {
+                    self.buffer.clear();
+                }
+                return tokens.flatten();
+            }
+            BodyFormat::EventStream => self.buffer.extend_from_slice(chunk),
+        }
+
+        let mut tokens = None;
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            let event = line
+                .strip_prefix(b"data:")
+                .and_then(|data| serde_json::from_slice::<Value>(data.trim_ascii()).ok());
+            if let Some(event_tokens) = event.as_ref().and_then(usage_tokens) {
+                tokens = Some(event_tokens);
+            }
+        }
+        tokens
+    }
+}
+
+/// Rate limit the requests of each client, rejecting those over a limit with 429 and a
+/// `Retry-After` header. The tokens of the responses are charged to the client as they are sent.
+pub(crate) async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    mut request: HttpRequest,
+    next: Next,
+) -> axum::response::Response {
+    if is_public_route(request.uri().path()) {
+        return next.run(request).await;
+    }
+    let address = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| *address);
+    let policy = request.extensions().get::<ApiKeyPolicy>();
+    let client = client_id(request.headers(), policy.is_some(), address);
+    let limits = limiter.limits(policy);
+
+    if let Some(wait) = limiter.admit(&client, limits, Instant::now()) {
+        let mut response = JsonError::new("Rate limit exceeded, retry later.".to_string())
+            .to_response(StatusCode::TOO_MANY_REQUESTS);
+        response.headers_mut().insert(
+            RETRY_AFTER,
+            (wait.as_secs_f64().ceil() as u64).max(1).into(),
+        );
+        return response;
+    }
+
+    let Some(tokens_per_minute) = limits.tokens_per_minute else {
+        return next.run(request).await;
+    };
+    let path = request.uri().path().to_string();
+    request.extensions_mut().insert(TokenCharger {
+        limiter: limiter.clone(),
+        client: client.clone(),
+        tokens_per_minute,
+    });
+    let response = next.run(request).await;
+    if !USAGE_ROUTES.contains(&path.as_str()) {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let mut scanner = UsageScanner::new(&parts.headers);
+    let body = body.into_data_stream().map(move |chunk| {
+        if let Ok(bytes) = &chunk {
+            if let Some(usage) = scanner.feed(bytes) {
+                limiter.charge_tokens(
+                    &client,
+                    usage.total_tokens,
+                    tokens_per_minute,
+                    Instant::now(),
+                );
+            }
+        }
+        chunk
+    });
+    axum::response::Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use axum::http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap,
+    };
+
+    use super::{
+        client_id, BodyFormat, RateLimitConfig, RateLimiter, TokenCharger, TokenUsage, UsageScanner,
+    };
+
+    #[test]
+    fn requests_refill_over_a_minute() {
+        let limiter = RateLimiter::new(RateLimitConfig::default());
+        let limits = RateLimitConfig {
+            requests_per_minute: Some(2),
+            tokens_per_minute: None,
+        };
+        let start = Instant::now();
+        assert_eq!(limiter.admit("a", limits, start), None);
+        assert_eq!(limiter.admit("a", limits, start), None);
+        assert_eq!(
+            limiter.admit("a", limits, start),
+            Some(Duration::from_secs(30))
+        );
+        // Other clients have their own buckets
+        assert_eq!(limiter.admit("b", limits, start), None);
+        assert_eq!(
+            limiter.admit("a", limits, start + Duration::from_secs(30)),
+            None
+        );
+    }
+
+    #[test]
+    fn tokens_are_charged_after_the_request() {
+        let limiter = RateLimiter::new(RateLimitConfig::default());
+        let limits = RateLimitConfig {
+            requests_per_minute: None,
+            tokens_per_minute: Some(600),
+        };
+        let start = Instant::now();
+        assert_eq!(limiter.admit("a", limits, start), None);
+        limiter.charge_tokens("a", 900, 600, start);
+        // The bucket is overdrawn by 300 tokens, which refill in 30 seconds
+        assert_eq!(
+            limiter.admit("a", limits, start),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            limiter.admit("a", limits, start + Duration::from_secs(31)),
+            None
+        );
+    }
+
+    #[test]
+    fn idle_clients_are_dropped() {
+        let limiter = RateLimiter::new(RateLimitConfig::default());
+        let limits = RateLimitConfig {
+            requests_per_minute: Some(2),
+            tokens_per_minute: None,
+        };
+        let start = Instant::now();
+        assert_eq!(limiter.admit("a", limits, start), None);
+        assert_eq!(limiter.admit("b", limits, start), None);
+        assert_eq!(limiter.admit("b", limits, start), None);
+
+        // After a minute both buckets are full, so only the client of the request is left
+        assert_eq!(
+            limiter.admit("b", limits, start + Duration::from_secs(61)),
+            None
+        );
+        let clients = limiter.clients.lock().unwrap();
+        assert_eq!(clients.buckets.keys().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[test]
+    fn only_authenticated_clients_are_keyed_by_their_key() {
+        let address = Some(SocketAddr::from(([10, 0, 0, 1], 1234)));
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer sk-a".parse().unwrap());
+
+        assert_eq!(client_id(&headers, false, address), "ip-10.0.0.1");
+        assert!(client_id(&headers, true, address).starts_with("key-"));
+        assert_eq!(client_id(&HeaderMap::new(), false, address), "ip-10.0.0.1");
+        assert_eq!(client_id(&headers, false, None), "unknown");
+    }
+
+    #[test]
+    fn scanner_finds_usage() {
+        let mut json = UsageScanner {
+            format: BodyFormat::Json,
+            buffer: Vec::new(),
+        };
+        assert_eq!(
+            json.feed(br#"{"usage": {"prompt_tokens": 5, "total_"#),
+            None
+        );
+        assert_eq!(
+            json.feed(br#"tokens": 12}}"#),
+            Some(TokenUsage {
+                prompt_tokens: 5,
+                completion_tokens: 0,
+                total_tokens: 12
+            })
+        );
+
+        let mut events = UsageScanner {
+            format: BodyFormat::EventStream,
+            buffer: Vec::new(),
+        };
+        assert_eq!(
+            events.feed(b"data: {\"choices\": []}\n\ndata: {\"usa"),
+            None
+        );
+        assert_eq!(
+            events
+                .feed(b"ge\": {\"total_tokens\": 7}}\n\ndata: [DONE]\n\n")
+                .map(|usage| usage.total_tokens),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn other_bodies_are_not_scanned() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "application/jsonl".parse().unwrap());
+        let mut file = UsageScanner::new(&headers);
+        assert_eq!(file.format, BodyFormat::Other);
+        assert_eq!(file.feed(br#"{"usage": {"total_tokens": 12}}"#), None);
+        assert!(file.buffer.is_empty());
+    }
+
+    #[test]
+    fn handlers_charge_and_wait_for_tokens() {
+        let charger = TokenCharger {
+            limiter: Arc::new(RateLimiter::new(RateLimitConfig::default())),
+            client: "a".to_string(),
+            tokens_per_minute: 600,
+        };
+        assert_eq!(charger.wait(), None);
+        charger.charge(900);
+        // The 300 overdrawn tokens and the next one refill in about 30 seconds
+        let wait = charger.wait().unwrap();
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(31));
+    }
+}
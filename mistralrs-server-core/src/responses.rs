@@ -4,8 +4,8 @@ use std::{pin::Pin, task::Poll, time::Duration};
 
 use anyhow::Result;
 use axum::{
-    extract::{Json, Path, State},
-    http::{self, StatusCode},
+    extract::{Extension, Json, Path, State},
+    http::{self, HeaderMap, StatusCode},
     response::{
         sse::{Event, KeepAlive},
         IntoResponse, Sse,
@@ -18,17 +18,18 @@ use tokio::sync::mpsc::Sender;
 use uuid::Uuid;
 
 use crate::{
+    auth::{apply_max_context, ApiKeyPolicy},
     cached_responses::get_response_cache,
     chat_completion::parse_request as parse_chat_request,
     completion_core::{handle_completion_error, BaseCompletionResponder},
     handler_core::{
-        create_response_channel, send_request_with_model, BaseJsonModelError, ErrorToResponse,
-        JsonError, ModelErrorMessage,
+        create_response_channel, internal_error_status, send_request_with_model,
+        set_tenant_from_api_key, BaseJsonModelError, ErrorToResponse, JsonError, ModelErrorMessage,
     },
     openai::{
-        ChatCompletionRequest, Message, MessageContent, ResponsesChunk, ResponsesContent,
-        ResponsesCreateRequest, ResponsesDelta, ResponsesDeltaContent, ResponsesDeltaOutput,
-        ResponsesError, ResponsesObject, ResponsesOutput, ResponsesUsage,
+        ChatCompletionRequest, Message, MessageContent, ResponseFormat, ResponsesChunk,
+        ResponsesContent, ResponsesCreateRequest, ResponsesDelta, ResponsesDeltaContent,
+        ResponsesDeltaOutput, ResponsesError, ResponsesObject, ResponsesOutput, ResponsesUsage,
     },
     streaming::{get_keep_alive_interval, BaseStreamer, DoneState},
     types::{ExtractedMistralRsState, OnChunkCallback, OnDoneCallback, SharedMistralRsState},
@@ -178,7 +179,7 @@ impl IntoResponse for ResponsesResponder {
             ResponsesResponder::Json(s) => Json(s).into_response(),
             ResponsesResponder::InternalError(e) => {
                 JsonError::new(sanitize_error_message(e.as_ref()))
-                    .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .to_response(internal_error_status(e.as_ref()))
             }
             ResponsesResponder::ValidationError(e) => {
                 JsonError::new(sanitize_error_message(e.as_ref()))
@@ -287,6 +288,16 @@ async fn parse_responses_request(
         None
     };
 
+    let text_format = oairequest.text.and_then(|text| text.format);
+    if text_format.is_some() && oairequest.response_format.is_some() {
+        anyhow::bail!(
+            "Request `text.format` and `response_format` were both provided but are mutually exclusive."
+        );
+    }
+    let response_format = oairequest
+        .response_format
+        .or(text_format.map(ResponseFormat::from));
+
     // Get messages from either messages or input field
     let messages = oairequest.input.into_either();
 
@@ -308,16 +319,30 @@ async fn parse_responses_request(
         stream: oairequest.stream,
         tools: oairequest.tools,
         tool_choice: oairequest.tool_choice,
-        response_format: oairequest.response_format,
+        response_format,
         web_search_options: oairequest.web_search_options,
+        user: oairequest.user,
         top_k: oairequest.top_k,
         grammar: oairequest.grammar,
         min_p: oairequest.min_p,
+        typical_p: oairequest.typical_p,
         dry_multiplier: oairequest.dry_multiplier,
         dry_base: oairequest.dry_base,
         dry_allowed_length: oairequest.dry_allowed_length,
         dry_sequence_breakers: oairequest.dry_sequence_breakers,
+        xtc_probability: oairequest.xtc_probability,
+        xtc_threshold: oairequest.xtc_threshold,
+        banned_strings: oairequest.banned_strings,
+        no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
+        token_healing: oairequest.token_healing.unwrap_or(false),
+        guidance_scale: oairequest.guidance_scale,
+        negative_prompt: oairequest.negative_prompt,
+        penalty_alpha: oairequest.penalty_alpha,
+        seed: oairequest.seed,
+        stop_regexes: oairequest.stop_regexes,
+        include_stop_str_in_output: oairequest.include_stop_str_in_output.unwrap_or(false),
         enable_thinking: oairequest.enable_thinking,
+        adapters: oairequest.adapters,
     };
 
     // Prepend previous messages if available
@@ -368,8 +393,10 @@ async fn parse_responses_request(
 )]
 pub async fn create_response(
     State(state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
     Json(oairequest): Json<ResponsesCreateRequest>,
-) -> ResponsesResponder {
+) -> axum::response::Response {
     let (tx, mut rx) = create_response_channel(None);
     let request_id = format!("resp_{}", Uuid::new_v4());
     let metadata = oairequest.metadata.clone();
@@ -382,17 +409,23 @@ pub async fn create_response(
         Some(oairequest.model.clone())
     };
 
-    let (request, is_streaming, conversation_history) =
+    let (mut request, is_streaming, conversation_history) =
         match parse_responses_request(oairequest, state.clone(), tx).await {
             Ok(x) => x,
-            Err(e) => return handle_error(state, e.into()),
+            Err(e) => return handle_error(state, e.into()).into_response(),
         };
+    set_tenant_from_api_key(&mut request, &headers);
+    if let Err(response) =
+        apply_max_context(&state, &mut request, policy.as_deref(), model_id.as_deref()).await
+    {
+        return response;
+    }
 
     if let Err(e) = send_request_with_model(&state, request, model_id.as_deref()).await {
-        return handle_error(state, e.into());
+        return handle_error(state, e.into()).into_response();
     }
 
-    if is_streaming {
+    let responder = if is_streaming {
         let streamer = ResponsesStreamer {
             rx,
             done_state: DoneState::Running,
@@ -522,7 +555,8 @@ pub async fn create_response(
                 anyhow::anyhow!("Unexpected response type").into(),
             ),
         }
-    }
+    };
+    responder.into_response()
 }
 
 /// Get response by ID endpoint
@@ -16,7 +16,9 @@ use mistralrs_core::{
 use tokio::sync::mpsc::{Receiver, Sender};
 
 use crate::{
-    handler_core::{create_response_channel, send_request, ErrorToResponse, JsonError},
+    handler_core::{
+        create_response_channel, internal_error_status, send_request, ErrorToResponse, JsonError,
+    },
     openai::{AudioResponseFormat, SpeechGenerationRequest},
     types::SharedMistralRsState,
     util::{sanitize_error_message, validate_model_name},
@@ -35,7 +37,7 @@ impl IntoResponse for SpeechGenerationResponder {
         match self {
             SpeechGenerationResponder::InternalError(e) => {
                 JsonError::new(sanitize_error_message(e.as_ref()))
-                    .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .to_response(internal_error_status(e.as_ref()))
             }
             SpeechGenerationResponder::ValidationError(e) => {
                 JsonError::new(sanitize_error_message(e.as_ref()))
@@ -77,11 +79,14 @@ pub fn parse_request(
         logits_processors: None,
         return_raw_logits: false,
         web_search_options: None,
+        adapters: None,
         model_id: if oairequest.model == "default" {
             None
         } else {
             Some(oairequest.model.clone())
         },
+        tenant: None,
+        deadline: None,
     }));
 
     Ok((request, oairequest.response_format))
@@ -0,0 +1,425 @@
+//! ## Multi-tenant routing, with the defaults and the usage of each tenant.
+//!
+//! A request belongs to the tenant of its API key, or without API keys to the tenant of its
+//! `x-tenant-id` header. The body of a chat completion, completion, responses, or embedding request is
+//! completed with the defaults of its tenant before it is handled: the default model, system
+//! prompt, and sampling parameters, which the request may override. The tokens of each tenant
+//! are counted apart, and each tenant only sees its own usage.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, FromRequest, Json, Request as HttpRequest, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::{
+    auth::{forbidden_model, is_public_route, ApiKeyPolicy},
+    handler_core::{ErrorToResponse, JsonError},
+    rate_limit::UsageScanner,
+    types::SharedMistralRsState,
+};
+
+/// The request header naming the tenant of a request, when the server does not check API keys.
+pub const TENANT_HEADER: &str = "x-tenant-id";
+
+/// The aliases of the maximum number of tokens to generate.
+const MAX_TOKENS_KEYS: [&str; 3] = ["max_tokens", "max_completion_tokens", "max_output_tokens"];
+
+/// The sampling parameters of the requests of a tenant which do not set them.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SamplingDefaults {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+}
+
+/// The configuration of a tenant.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    /// The model of the requests to the `default` model.
+    pub default_model: Option<String>,
+    /// A system prompt put before the messages of chat requests, and the instructions of
+    /// responses requests.
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub sampling: SamplingDefaults,
+}
+
+/// The tenants which requests are routed by.
+pub struct Tenants {
+    tenants: HashMap<String, TenantConfig>,
+}
+
+impl Tenants {
+    pub fn new(tenants: HashMap<String, TenantConfig>) -> Self {
+        Self { tenants }
+    }
+
+    /// Read the tenants from a JSON file which maps the name of each tenant to its configuration,
+    /// such as `{"acme": {"default_model": "llama", "sampling": {"temperature": 0.2}}}`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the tenants file {}", path.display()))?;
+        let tenants = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse the tenants file {}", path.display()))?;
+        Ok(Self::new(tenants))
+    }
+
+    /// The model of the requests of `tenant` to the `default` model, if it has one.
+    pub(crate) fn default_model(&self, tenant: &str) -> Option<&str> {
+        self.tenants.get(tenant)?.default_model.as_deref()
+    }
+}
+
+/// The tenant of a request, in its extensions.
+#[derive(Clone, Debug)]
+pub struct Tenant(pub String);
+
+/// The requests and tokens of a tenant since the server started.
+#[derive(Clone, Copy, Debug, Default, Serialize, ToSchema)]
+pub struct TenantUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+static TENANT_USAGE: LazyLock<Mutex<HashMap<String, TenantUsage>>> =
+    LazyLock::new(Default::default);
+
+/// The endpoints whose request bodies are completed with the defaults of the tenant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Endpoint {
+    ChatCompletions,
+    Completions,
+    Responses,
+    Embeddings,
+}
+
+impl Endpoint {
+    fn from_path(path: &str) -> Option<Self> {
+        match path {
+            "/v1/chat/completions" => Some(Self::ChatCompletions),
+            "/v1/completions" => Some(Self::Completions),
+            "/v1/responses" => Some(Self::Responses),
+            "/v1/embeddings" => Some(Self::Embeddings),
+            _ => None,
+        }
+    }
+}
+
+fn prepend_prompt(prompt: &str, text: &str) -> String {
+    format!("{prompt}\n\n{text}")
+}
+
+/// Complete the body of a request to `endpoint` with the defaults of `config`, returning its
+/// model.
+fn apply_defaults(endpoint: Endpoint, body: &mut Value, config: &TenantConfig) -> Option<String> {
+    let body = body.as_object_mut()?;
+    if let Some(default_model) = &config.default_model {
+        let model = body.get("model").and_then(Value::as_str);
+        if model.is_none_or(|model| model == "default") {
+            body.insert("model".to_string(), default_model.clone().into());
+        }
+    }
+
+    if let Some(prompt) = &config.system_prompt {
+        match endpoint {
+            Endpoint::ChatCompletions => {
+                if let Some(Value::Array(messages)) = body.get_mut("messages") {
+                    let first = messages
+                        .first_mut()
+                        .filter(|message| message["role"] == "system");
+                    match first.and_then(|message| message.get_mut("content")) {
+                        Some(Value::String(content)) => *content = prepend_prompt(prompt, content),
+                        _ => messages.insert(
+                            0,
+                            serde_json::json!({ "role": "system", "content": prompt }),
+                        ),
+                    }
+                }
+            }
+            Endpoint::Responses => {
+                let instructions = match body.get("instructions").and_then(Value::as_str) {
+                    Some(instructions) => prepend_prompt(prompt, instructions),
+                    None => prompt.clone(),
+                };
+                body.insert("instructions".to_string(), instructions.into());
+            }
+            Endpoint::Completions | Endpoint::Embeddings => (),
+        }
+    }
+
+    if endpoint != Endpoint::Embeddings {
+        let Ok(Value::Object(sampling)) = serde_json::to_value(&config.sampling) else {
+            unreachable!("Sampling defaults serialize to an object.");
+        };
+        // A default stop sequence could end a JSON output early
+        let is_json = [
+            body.get("response_format"),
+            body.get("text").and_then(|text| text.get("format")),
+        ]
+        .into_iter()
+        .flatten()
+        .any(|format| format.get("type").is_some_and(|kind| kind != "text"));
+        for (key, value) in sampling {
+            if key == "stop" && is_json {
+                continue;
+            }
+            let set = if key == "max_tokens" {
+                MAX_TOKENS_KEYS.iter().any(|key| body.contains_key(*key))
+            } else {
+                body.contains_key(&key)
+            };
+            if !set {
+                body.insert(key, value);
+            }
+        }
+    }
+
+    body.get("model")
+        .and_then(Value::as_str)
+        .map(ToString::to_string)
+}
+
+/// The tenant of a request with the API key `policy` and the tenant `header`. The header names the
+/// tenant only without API keys: a key may only name the tenant of its policy.
+fn request_tenant(
+    policy: Option<&ApiKeyPolicy>,
+    header: Option<&str>,
+) -> Result<Option<String>, String> {
+    let Some(policy) = policy else {
+        return Ok(header.map(ToString::to_string));
+    };
+    match header {
+        Some(header) if policy.tenant.as_deref() != Some(header) => {
+            Err(format!("This API key may not use the tenant `{header}`."))
+        }
+        _ => Ok(policy.tenant.clone()),
+    }
+}
+
+/// Route a request to its tenant: complete its body with the defaults of the tenant, add the
+/// tenant to its extensions, and count the tokens of its response. Requests naming an unknown
+/// tenant are rejected with 400, and those naming a tenant which their API key may not use with
+/// 403.
+pub(crate) async fn route_tenant(
+    State((tenants, state)): State<(Arc<Tenants>, SharedMistralRsState)>,
+    request: HttpRequest,
+    next: Next,
+) -> axum::response::Response {
+    if is_public_route(request.uri().path()) {
+        return next.run(request).await;
+    }
+    let policy = request.extensions().get::<ApiKeyPolicy>().cloned();
+    let header = request
+        .headers()
+        .get(TENANT_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let tenant = match request_tenant(policy.as_ref(), header) {
+        Ok(tenant) => tenant,
+        Err(e) => return JsonError::new(e).to_response(StatusCode::FORBIDDEN),
+    };
+    let Some(tenant) = tenant else {
+        return next.run(request).await;
+    };
+    let Some(config) = tenants.tenants.get(&tenant) else {
+        return JsonError::new(format!("Unknown tenant `{tenant}`."))
+            .to_response(StatusCode::BAD_REQUEST);
+    };
+
+    let endpoint =
+        Endpoint::from_path(request.uri().path()).filter(|_| request.method() == Method::POST);
+    let mut request = match endpoint {
+        Some(endpoint) => {
+            let (parts, body) = request.into_parts();
+            let bytes = match Bytes::from_request(HttpRequest::from_parts(parts.clone(), body), &())
+                .await
+            {
+                Ok(bytes) => bytes,
+                Err(rejection) => return rejection.into_response(),
+            };
+            // Bodies which are not JSON are left to the handler to reject
+            let bytes = match serde_json::from_slice::<Value>(&bytes) {
+                Ok(mut body) => {
+                    let model = apply_defaults(endpoint, &mut body, config);
+                    if let Some(policy) = &policy {
+                        let model = model.as_deref().unwrap_or("default");
+                        if !policy.allows_model(&state, model) {
+                            return forbidden_model(model);
+                        }
+                    }
+                    Bytes::from(serde_json::to_vec(&body).expect("Serialization of body failed."))
+                }
+                Err(_) => bytes,
+            };
+            HttpRequest::from_parts(parts, Body::from(bytes))
+        }
+        None => request,
+    };
+    request.extensions_mut().insert(Tenant(tenant.clone()));
+
+    let response = next.run(request).await;
+    if endpoint.is_none() {
+        return response;
+    }
+    TENANT_USAGE
+        .lock()
+        .unwrap()
+        .entry(tenant.clone())
+        .or_default()
+        .requests += 1;
+    let (parts, body) = response.into_parts();
+    let mut scanner = UsageScanner::new(&parts.headers);
+    let body = body.into_data_stream().map(move |chunk| {
+        if let Ok(bytes) = &chunk {
+            if let Some(tokens) = scanner.feed(bytes) {
+                let mut usage = TENANT_USAGE.lock().unwrap();
+                let usage = usage.entry(tenant.clone()).or_default();
+                usage.prompt_tokens += tokens.prompt_tokens as u64;
+                usage.completion_tokens += tokens.completion_tokens as u64;
+                usage.total_tokens += tokens.total_tokens as u64;
+            }
+        }
+        chunk
+    });
+    axum::response::Response::from_parts(parts, Body::from_stream(body))
+}
+
+/// Tenant usage endpoint handler.
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/v1/usage",
+    responses(
+        (status = 200, description = "The requests and tokens of the tenant of the request", body = TenantUsage),
+        (status = 404, description = "The request has no tenant")
+    )
+)]
+pub async fn tenant_usage(tenant: Option<Extension<Tenant>>) -> axum::response::Response {
+    let Some(Extension(Tenant(tenant))) = tenant else {
+        return JsonError::new("The request has no tenant.".to_string())
+            .to_response(StatusCode::NOT_FOUND);
+    };
+    let usage = TENANT_USAGE
+        .lock()
+        .unwrap()
+        .get(&tenant)
+        .copied()
+        .unwrap_or_default();
+    Json(usage).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{apply_defaults, request_tenant, Endpoint, TenantConfig};
+    use crate::auth::ApiKeyPolicy;
+
+    #[test]
+    fn applies_tenant_defaults() {
+        let config: TenantConfig = serde_json::from_value(json!({
+            "default_model": "llama",
+            "system_prompt": "Be brief.",
+            "sampling": {"temperature": 0.2, "max_tokens": 64},
+        }))
+        .unwrap();
+
+        let mut body = json!({
+            "messages": [{"role": "user", "content": "Hi"}],
+            "max_completion_tokens": 16,
+        });
+        let model = apply_defaults(Endpoint::ChatCompletions, &mut body, &config);
+        assert_eq!(model.as_deref(), Some("llama"));
+        assert_eq!(
+            body["messages"][0],
+            json!({"role": "system", "content": "Be brief."})
+        );
+        assert_eq!(body["temperature"], 0.2);
+        // The request overrides the defaults
+        assert!(body.get("max_tokens").is_none());
+
+        let mut body = json!({
+            "model": "mistral",
+            "messages": [{"role": "system", "content": "Use French."}],
+            "temperature": 1.0,
+        });
+        apply_defaults(Endpoint::ChatCompletions, &mut body, &config);
+        assert_eq!(body["model"], "mistral");
+        assert_eq!(body["messages"][0]["content"], "Be brief.\n\nUse French.");
+        assert_eq!(body["temperature"], 1.0);
+        assert_eq!(body["max_tokens"], 64);
+
+        let mut body = json!({"model": "default", "input": "Hi"});
+        apply_defaults(Endpoint::Embeddings, &mut body, &config);
+        assert_eq!(body, json!({"model": "llama", "input": "Hi"}));
+
+        let config: TenantConfig =
+            serde_json::from_value(json!({"sampling": {"stop": ["\n"]}})).unwrap();
+        let mut body = json!({"prompt": "Hi", "response_format": {"type": "json_object"}});
+        apply_defaults(Endpoint::Completions, &mut body, &config);
+        assert!(body.get("stop").is_none());
+
+        assert!(serde_json::from_value::<TenantConfig>(json!({"model": "llama"})).is_err());
+    }
+
+    #[test]
+    fn api_keys_may_only_name_their_tenant() {
+        assert_eq!(
+            request_tenant(None, Some("acme")),
+            Ok(Some("acme".to_string()))
+        );
+        assert_eq!(request_tenant(None, None), Ok(None));
+
+        let keyed = ApiKeyPolicy {
+            tenant: Some("acme".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            request_tenant(Some(&keyed), None),
+            Ok(Some("acme".to_string()))
+        );
+        assert_eq!(
+            request_tenant(Some(&keyed), Some("acme")),
+            Ok(Some("acme".to_string()))
+        );
+        assert!(request_tenant(Some(&keyed), Some("globex")).is_err());
+
+        // A key without a tenant may not pick one
+        let untenanted = ApiKeyPolicy::default();
+        assert_eq!(request_tenant(Some(&untenanted), None), Ok(None));
+        assert!(request_tenant(Some(&untenanted), Some("acme")).is_err());
+    }
+}
@@ -0,0 +1,365 @@
+//! ## WebSocket streaming of chat completions and completions.
+//!
+//! A client sends `submit` messages with the body of a chat completion or completion request,
+//! and receives the chunks of each as `delta` messages, followed by `done`. A `cancel` message
+//! stops a request, like `DELETE /v1/requests/{request_id}`. Each message is a JSON object with
+//! a `type`, and the `id` chosen by the client for the request.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension, State,
+    },
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use futures::{SinkExt, StreamExt};
+use mistralrs_core::{Request, RequestCanceled, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::{unbounded_channel, Receiver, UnboundedSender};
+
+use crate::{
+    auth::{apply_max_context, ApiKeyPolicy},
+    chat_completion, completions,
+    handler_core::{
+        create_response_channel, error_message, send_request_with_model, set_tenant_from_api_key,
+    },
+    openai::{ChatCompletionRequest, CompletionRequest},
+    rate_limit::TokenCharger,
+    types::{ExtractedMistralRsState, SharedMistralRsState},
+    util::sanitize_error_message,
+};
+
+/// The endpoint whose request a `submit` message carries.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+enum Endpoint {
+    #[default]
+    #[serde(rename = "/v1/chat/completions")]
+    ChatCompletions,
+    #[serde(rename = "/v1/completions")]
+    Completions,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Start a request, whose chunks are streamed whatever its `stream` field.
+    Submit {
+        id: String,
+        #[serde(default)]
+        endpoint: Endpoint,
+        request: Value,
+    },
+    /// Stop a request which was submitted.
+    Cancel { id: String },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    /// A chunk of a request, as sent in its server-sent events.
+    Delta { id: String, chunk: Value },
+    /// The request finished, or was canceled.
+    Done { id: String },
+    /// The request failed, or a message could not be handled.
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        message: String,
+    },
+}
+
+/// The requests of a connection which are running, by the ID chosen by the client.
+type Running = Arc<Mutex<HashMap<String, usize>>>;
+
+/// WebSocket endpoint which streams chat completions and completions, and cancels them.
+pub async fn websocket(
+    State(state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<Extension<ApiKeyPolicy>>,
+    charger: Option<Extension<TokenCharger>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let policy = policy.map(|Extension(policy)| policy);
+    let charger = charger.map(|Extension(charger)| charger);
+    ws.on_upgrade(move |socket| handle_socket(socket, state, headers, policy, charger))
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: SharedMistralRsState,
+    headers: HeaderMap,
+    policy: Option<ApiKeyPolicy>,
+    charger: Option<TokenCharger>,
+) {
+    let (mut sink, mut stream) = socket.split();
+    // The requests send their messages through a channel, as only one task can write a socket
+    let (out_tx, mut out_rx) = unbounded_channel::<ServerMessage>();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = out_rx.recv().await {
+            let text = serde_json::to_string(&message).expect("Serialization of message failed.");
+            if sink.send(Message::Text(text.into())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let running: Running = Arc::default();
+    while let Some(Ok(message)) = stream.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        match serde_json::from_str::<ClientMessage>(text.as_str()) {
+            Ok(ClientMessage::Submit {
+                id,
+                endpoint,
+                request,
+            }) => {
+                if running.lock().unwrap().contains_key(&id) {
+                    let _ = out_tx.send(ServerMessage::Error {
+                        message: format!("A request with the ID `{id}` is already running."),
+                        id: Some(id),
+                    });
+                    continue;
+                }
+                if let Err(message) = submit(
+                    &state,
+                    &headers,
+                    policy.as_ref(),
+                    charger.as_ref(),
+                    &running,
+                    &out_tx,
+                    id.clone(),
+                    endpoint,
+                    request,
+                )
+                .await
+                {
+                    let _ = out_tx.send(ServerMessage::Error {
+                        id: Some(id),
+                        message,
+                    });
+                }
+            }
+            Ok(ClientMessage::Cancel { id }) => {
+                let request_id = running.lock().unwrap().get(&id).copied();
+                match request_id {
+                    Some(request_id) => cancel(&state, request_id).await,
+                    None => {
+                        let _ = out_tx.send(ServerMessage::Error {
+                            message: format!("No request with the ID `{id}` is running."),
+                            id: Some(id),
+                        });
+                    }
+                }
+            }
+            Err(e) => {
+                let _ = out_tx.send(ServerMessage::Error {
+                    id: None,
+                    message: format!("Invalid message: {e}"),
+                });
+            }
+        }
+    }
+
+    // The requests of a closed connection have nobody to stream to
+    let request_ids = running
+        .lock()
+        .unwrap()
+        .drain()
+        .map(|(_, request_id)| request_id)
+        .collect::<Vec<_>>();
+    for request_id in request_ids {
+        cancel(&state, request_id).await;
+    }
+    drop(out_tx);
+    let _ = writer.await;
+}
+
+/// Send the request of a `submit` message, and stream its chunks from another task. Requests of
+/// a client without tokens left are rejected, and the tokens of the others are charged with
+/// `charger` once they finish.
+#[allow(clippy::too_many_arguments)]
+async fn submit(
+    state: &SharedMistralRsState,
+    headers: &HeaderMap,
+    policy: Option<&ApiKeyPolicy>,
+    charger: Option<&TokenCharger>,
+    running: &Running,
+    out_tx: &UnboundedSender<ServerMessage>,
+    id: String,
+    endpoint: Endpoint,
+    request: Value,
+) -> Result<(), String> {
+    if let Some(wait) = charger.and_then(TokenCharger::wait) {
+        let seconds = (wait.as_secs_f64().ceil() as u64).max(1);
+        return Err(format!("Rate limit exceeded, retry in {seconds} seconds."));
+    }
+    let (tx, rx) = create_response_channel(None);
+    let (model, mut request) = match endpoint {
+        Endpoint::ChatCompletions => {
+            let mut oairequest = serde_json::from_value::<ChatCompletionRequest>(request)
+                .map_err(|e| format!("Invalid chat completion request: {e}"))?;
+            oairequest.stream = Some(true);
+            let model = oairequest.model.clone();
+            let (request, _) = chat_completion::parse_request(oairequest, state.clone(), tx)
+                .await
+                .map_err(|e| sanitize_error_message(&*e))?;
+            (model, request)
+        }
+        Endpoint::Completions => {
+            let mut oairequest = serde_json::from_value::<CompletionRequest>(request)
+                .map_err(|e| format!("Invalid completion request: {e}"))?;
+            oairequest.stream = Some(true);
+            let model = oairequest.model.clone();
+            let (request, _) = completions::parse_request(oairequest, state.clone(), tx)
+                .map_err(|e| sanitize_error_message(&*e))?;
+            (model, request)
+        }
+    };
+    if policy.is_some_and(|policy| !policy.allows_model(state, &model)) {
+        return Err(format!("This API key may not use the model `{model}`."));
+    }
+    set_tenant_from_api_key(&mut request, headers);
+
+    let model_id = (model != "default").then_some(model);
+    if let Err(response) = apply_max_context(state, &mut request, policy, model_id.as_deref()).await
+    {
+        return Err(error_message(response).await);
+    }
+    let Request::Normal(normal) = &request else {
+        unreachable!("Completion requests are normal requests.");
+    };
+    let request_id = normal.id;
+
+    running.lock().unwrap().insert(id.clone(), request_id);
+    if let Err(e) = send_request_with_model(state, request, model_id.as_deref()).await {
+        running.lock().unwrap().remove(&id);
+        return Err(e.to_string());
+    }
+    tokio::spawn(forward(
+        rx,
+        id,
+        running.clone(),
+        out_tx.clone(),
+        charger.cloned(),
+    ));
+    Ok(())
+}
+
+/// Forward the responses of a request until it finishes.
+async fn forward(
+    mut rx: Receiver<Response>,
+    id: String,
+    running: Running,
+    out_tx: UnboundedSender<ServerMessage>,
+    charger: Option<TokenCharger>,
+) {
+    while let Some(response) = rx.recv().await {
+        if let Some(charger) = &charger {
+            charger.charge_response(&response);
+        }
+        let (chunk, finished) = match response {
+            Response::Chunk(chunk) => {
+                let finished = chunk.choices.iter().all(|x| x.finish_reason.is_some());
+                (serde_json::to_value(chunk), finished)
+            }
+            Response::CompletionChunk(chunk) => {
+                let finished = chunk.choices.iter().all(|x| x.finish_reason.is_some());
+                (serde_json::to_value(chunk), finished)
+            }
+            // A request canceled before it started running
+            Response::InternalError(e) if e.is::<RequestCanceled>() => break,
+            Response::InternalError(e) | Response::ValidationError(e) => {
+                let _ = out_tx.send(ServerMessage::Error {
+                    id: Some(id.clone()),
+                    message: sanitize_error_message(e.as_ref()),
+                });
+                running.lock().unwrap().remove(&id);
+                return;
+            }
+            Response::ModelError(message, _) | Response::CompletionModelError(message, _) => {
+                let _ = out_tx.send(ServerMessage::Error {
+                    id: Some(id.clone()),
+                    message,
+                });
+                running.lock().unwrap().remove(&id);
+                return;
+            }
+            _ => continue,
+        };
+        let chunk = chunk.expect("Serialization of chunk failed.");
+        let _ = out_tx.send(ServerMessage::Delta {
+            id: id.clone(),
+            chunk,
+        });
+        if finished {
+            break;
+        }
+    }
+    running.lock().unwrap().remove(&id);
+    let _ = out_tx.send(ServerMessage::Done { id });
+}
+
+/// Cancel a request, sending the cancellation to each model as only the one running it is
+/// affected.
+async fn cancel(state: &SharedMistralRsState, request_id: usize) {
+    for model_id in state.list_models().unwrap_or_default() {
+        if let Ok(sender) = state.get_sender(Some(&model_id)) {
+            let _ = sender.send(Request::Cancel(request_id)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientMessage, Endpoint, ServerMessage};
+
+    #[test]
+    fn parses_client_messages() {
+        let message = serde_json::from_str::<ClientMessage>(
+            r#"{"type": "submit", "id": "a", "request": {"model": "default", "messages": []}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            message,
+            ClientMessage::Submit {
+                endpoint: Endpoint::ChatCompletions,
+                ..
+            }
+        ));
+        let message = serde_json::from_str::<ClientMessage>(
+            r#"{"type": "submit", "id": "a", "endpoint": "/v1/completions", "request": {}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            message,
+            ClientMessage::Submit {
+                endpoint: Endpoint::Completions,
+                ..
+            }
+        ));
+        assert!(matches!(
+            serde_json::from_str::<ClientMessage>(r#"{"type": "cancel", "id": "a"}"#).unwrap(),
+            ClientMessage::Cancel { id } if id == "a"
+        ));
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"type": "delta", "id": "a"}"#).is_err());
+
+        assert_eq!(
+            serde_json::to_string(&ServerMessage::Error {
+                id: None,
+                message: "bad".to_string()
+            })
+            .unwrap(),
+            r#"{"type":"error","message":"bad"}"#
+        );
+    }
+}
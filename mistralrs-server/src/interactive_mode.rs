@@ -91,6 +91,15 @@ pub async fn interactive_mode(
             audio_interactive_mode(mistralrs, do_search, enable_thinking).await
         }
         Ok(ModelCategory::Speech) => speech_interactive_mode(mistralrs, do_search).await,
+        Ok(ModelCategory::Embedding) => {
+            eprintln!("Interactive mode is not supported for embedding models.")
+        }
+        Ok(ModelCategory::Reranker) => {
+            eprintln!("Interactive mode is not supported for reranker models.")
+        }
+        Ok(ModelCategory::Transcription) => {
+            eprintln!("Interactive mode is not supported for transcription models.")
+        }
         Err(e) => eprintln!("Error getting model category: {e}"),
     }
 }
@@ -106,6 +115,7 @@ Commands:
 - `\temperature <float>`: Set sampling temperature (0.0 to 2.0).
 - `\topk <int>`: Set top-k sampling value (>0).
 - `\topp <float>`: Set top-p sampling value in (0.0 to 1.0).
+- `\minp <float>`: Set min-p sampling value in [0.0 to 1.0), 0.0 disables it.
 "#;
 
 const TEXT_INTERACTIVE_HELP: &str = r#"
@@ -144,6 +154,7 @@ const CLEAR_CMD: &str = "\\clear";
 const TEMPERATURE_CMD: &str = "\\temperature";
 const TOPK_CMD: &str = "\\topk";
 const TOPP_CMD: &str = "\\topp";
+const MINP_CMD: &str = "\\minp";
 
 /// Regex string used to extract image URLs from prompts.
 const IMAGE_REGEX: &str = r#"((?:https?://|file://)?\S+?\.(?:png|jpe?g|bmp|gif|webp)(?:\?\S+?)?)"#;
@@ -155,6 +166,7 @@ fn interactive_sample_parameters() -> SamplingParams {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        typical_p: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
@@ -164,10 +176,20 @@ fn interactive_sample_parameters() -> SamplingParams {
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        xtc_params: None,
+        banned_strings: None,
+        no_repeat_ngram_size: None,
+        token_healing: false,
+        guidance_scale: None,
+        negative_prompt: None,
+        penalty_alpha: None,
+        seed: None,
+        stop_regexes: None,
+        include_stop_str_in_output: false,
     }
 }
 
-/// Handles sampling commands (\temperature, \topk, \topp) and updates the sampling_params accordingly.
+/// Handles sampling commands (\temperature, \topk, \topp, \minp) and updates the sampling_params accordingly.
 /// Returns true if the prompt was a handled sampling command, otherwise false.
 fn handle_sampling_command(prompt: &str, sampling_params: &mut SamplingParams) -> bool {
     let trimmed = prompt.trim();
@@ -225,6 +247,24 @@ fn handle_sampling_command(prompt: &str, sampling_params: &mut SamplingParams) -
         }
         return true;
     }
+    if trimmed.starts_with(MINP_CMD) {
+        let parts: Vec<&str> = trimmed.splitn(2, ' ').collect();
+        if let [_, value] = parts.as_slice() {
+            match value.trim().parse::<f64>() {
+                Ok(v) if (0.0..1.0).contains(&v) => {
+                    sampling_params.min_p = Some(v);
+                    info!("Set min-p to {v}");
+                }
+                Ok(_) => {
+                    println!("Error: min-p must be in [0.0, 1.0)");
+                }
+                Err(_) => println!("Error: format is `{MINP_CMD} <float>`"),
+            }
+        } else {
+            println!("Error: format is `{MINP_CMD} <float>`");
+        }
+        return true;
+    }
     false
 }
 
@@ -329,7 +369,10 @@ async fn text_interactive_mode(
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: do_search.then(WebSearchOptions::default),
+            adapters: None,
             model_id: None,
+            tenant: None,
+            deadline: None,
         }));
         sender.send(req).await.unwrap();
         let start_ttft = Instant::now();
@@ -458,7 +501,10 @@ async fn vision_interactive_mode(
         ModelCategory::Text
         | ModelCategory::Diffusion
         | ModelCategory::Speech
-        | ModelCategory::Audio => {
+        | ModelCategory::Audio
+        | ModelCategory::Embedding
+        | ModelCategory::Reranker
+        | ModelCategory::Transcription => {
             panic!("`add_image_message` expects a vision model.")
         }
     };
@@ -634,7 +680,10 @@ async fn vision_interactive_mode(
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: do_search.then(WebSearchOptions::default),
+            adapters: None,
             model_id: None,
+            tenant: None,
+            deadline: None,
         }));
         sender.send(req).await.unwrap();
         let start_ttft = Instant::now();
@@ -793,7 +842,10 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>, do_search: bool)
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: do_search.then(WebSearchOptions::default),
+            adapters: None,
             model_id: None,
+            tenant: None,
+            deadline: None,
         }));
 
         let start = Instant::now();
@@ -881,7 +933,10 @@ async fn speech_interactive_mode(mistralrs: Arc<MistralRs>, do_search: bool) {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: do_search.then(WebSearchOptions::default),
+            adapters: None,
             model_id: None,
+            tenant: None,
+            deadline: None,
         }));
 
         let start = Instant::now();
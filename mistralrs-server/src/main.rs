@@ -1,19 +1,26 @@
 use anyhow::Result;
 use clap::Parser;
 use mistralrs_core::{
-    initialize_logging, McpClientConfig, ModelSelected, PagedCacheType, TokenSource,
+    initialize_logging, KvQuota, KvQuotaConfig, McpClientConfig, ModelSelected, PagedCacheType,
+    SchedulingPolicy, TokenSource,
 };
 use rust_mcp_sdk::schema::LATEST_PROTOCOL_VERSION;
-use std::collections::HashMap;
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
 use tokio::join;
 use tracing::{error, info};
 
 use mistralrs_server_core::{
+    admin::AdminConfig,
+    auth::ApiKeys,
+    completion_cache::CompletionCacheConfig,
+    health::LoadingRouter,
     mistralrs_for_server_builder::{
         configure_paged_attn_from_flags, defaults, get_bert_model, MistralRsForServerBuilder,
         ModelConfig,
     },
     mistralrs_server_router_builder::MistralRsServerRouterBuilder,
+    rate_limit::RateLimitConfig,
+    tenants::Tenants,
 };
 
 mod interactive_mode;
@@ -80,6 +87,34 @@ struct Args {
     #[arg(long, default_value_t = defaults::PREFIX_CACHE_N)]
     prefix_cache_n: usize,
 
+    /// Schedule the requests of each client fairly, interleaving them by the tokens each has
+    /// consumed so one heavy client cannot starve the others. Clients are identified by the
+    /// `user` field of the request, or else by their API key.
+    #[arg(long, default_value_t = false)]
+    fair_share: bool,
+
+    /// Process prompts in chunks of at most this many tokens, running the decoding of other
+    /// requests between the chunks so long prompts do not stall them. Requires `--no-paged-attn`
+    /// on CUDA and Metal.
+    #[arg(long)]
+    prefill_chunk_size: Option<usize>,
+
+    /// Reject new requests with HTTP 429 once this many sequences are waiting to run, instead of
+    /// queueing them without bound.
+    #[arg(long)]
+    max_queue_len: Option<usize>,
+
+    /// Finish a sequence with the finish reason `kv_quota` once it holds this many tokens in the KV
+    /// cache, so a single long request cannot take the whole KV cache from the others.
+    #[arg(long)]
+    max_seq_kv_tokens: Option<usize>,
+
+    /// Make the output of each request depend only on the request, for reproducible evaluations.
+    /// Requests without a seed use the same seed, each sequence samples with its own random number
+    /// generator, batches run in a fixed order, and the prefix cache is not used.
+    #[arg(long)]
+    deterministic: bool,
+
     /// NOTE: This can be omitted to use automatic device mapping!
     /// Number of device layers to load and run on GPU(s). All others will be on the CPU.
     /// If one GPU is used, then this value should be an integer. Otherwise, it follows the following pattern:
@@ -92,36 +127,46 @@ struct Args {
     in_situ_quant: Option<String>,
 
     /// GPU memory to allocate for KV cache with PagedAttention in MBs.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     #[arg(long = "pa-gpu-mem")]
     paged_attn_gpu_mem: Option<usize>,
 
     /// Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
     /// If this is not set and the device is CUDA, it will default to `0.9`.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     #[arg(long = "pa-gpu-mem-usage")]
     paged_attn_gpu_mem_usage: Option<f32>,
 
     /// Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold).
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     /// This is the default setting, and it defaults to the `max-seq-len` specified in after the model type.
     #[arg(long = "pa-ctxt-len")]
     paged_ctxt_len: Option<usize>,
 
-    /// PagedAttention KV cache type (auto or f8e4m3).
+    /// PagedAttention KV cache type (auto, f8e4m3 or int8).
     /// Defaults to `auto`.
     #[arg(long = "pa-cache-type", value_parser = parse_cache_type)]
     cache_type: Option<PagedCacheType>,
 
     /// Block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA, it will default to 32.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA and Metal.
     #[arg(long = "pa-blk-size")]
     paged_attn_block_size: Option<usize>,
 
-    /// Disable PagedAttention on CUDA. Because PagedAttention is already disabled on Metal, this is only applicable on CUDA.
+    /// Host memory in MB to swap the KV cache of sequences preempted by PagedAttention to, so they
+    /// are resumed without recomputing their prompt. Defaults to 0, which recomputes them.
+    #[arg(long = "pa-swap-space", default_value_t = 0)]
+    paged_attn_swap_space: usize,
+
+    /// Fraction of the PagedAttention GPU blocks to keep free when admitting new sequences, so
+    /// running sequences can grow without being preempted right away. Defaults to 0.
+    #[arg(long = "pa-watermark", default_value_t = 0.)]
+    paged_attn_watermark: f32,
+
+    /// Disable PagedAttention on CUDA and Metal.
     #[arg(
         long = "no-paged-attn",
         default_value_t = false,
@@ -129,7 +174,7 @@ struct Args {
     )]
     no_paged_attn: bool,
 
-    /// Enable PagedAttention on Metal. Because PagedAttention is already enabled on CUDA, this is only applicable on Metal.
+    /// Enable PagedAttention. Because PagedAttention is already enabled on CUDA and Metal, this flag is not needed there.
     #[arg(
         long = "paged-attn",
         default_value_t = false,
@@ -157,9 +202,47 @@ struct Args {
     #[arg(long)]
     mcp_port: Option<u16>,
 
+    /// Port to serve the gRPC inference API on, with the API keys and rate limits of the HTTP API.
+    /// Requires the `grpc` feature.
+    #[arg(long)]
+    grpc_port: Option<u16>,
+
     /// MCP client configuration file path
     #[arg(long)]
     mcp_config: Option<String>,
+
+    /// Serve the admin endpoints, which load and unload models at runtime, authorized with this
+    /// API key as a bearer token.
+    #[arg(long)]
+    admin_key: Option<String>,
+
+    /// JSON file mapping the API keys which requests must be authorized with to their policies:
+    /// the models they may use, the maximum context of a request, and the requests per minute.
+    #[arg(long)]
+    api_keys: Option<String>,
+
+    /// Limit the requests per minute of each client, identified by its API key or IP address.
+    #[arg(long)]
+    requests_per_minute: Option<usize>,
+
+    /// Limit the prompt and generated tokens per minute of each client, identified by its API key
+    /// or IP address.
+    #[arg(long)]
+    tokens_per_minute: Option<usize>,
+
+    /// JSON file mapping the tenants, named by the `x-tenant-id` header or the API key policy, to
+    /// their default model, system prompt, and sampling parameters.
+    #[arg(long)]
+    tenants: Option<String>,
+
+    /// Serve the responses of identical chat completion and completion requests which are not
+    /// streamed, and have a seed or sample greedily, from a cache for this many seconds.
+    #[arg(long)]
+    response_cache_ttl: Option<u64>,
+
+    /// The most responses in the cache of `--response-cache-ttl`.
+    #[arg(long, default_value_t = 1024)]
+    response_cache_size: usize,
 }
 
 fn parse_token_source(s: &str) -> Result<TokenSource, String> {
@@ -336,10 +419,47 @@ async fn main() -> Result<()> {
 
     // Load MCP configuration if provided
     let mcp_config = load_mcp_config(args.mcp_config.as_deref())?;
+    let api_keys = args
+        .api_keys
+        .as_deref()
+        .map(ApiKeys::from_file)
+        .transpose()?;
+    let tenants = args
+        .tenants
+        .as_deref()
+        .map(Tenants::from_file)
+        .transpose()?;
 
     let paged_attn = configure_paged_attn_from_flags(args.paged_attn, args.no_paged_attn)?;
 
-    let mistralrs = match args.model {
+    let scheduling_policy = if args.fair_share {
+        SchedulingPolicy::FairShare
+    } else {
+        SchedulingPolicy::Fcfs
+    };
+
+    // The port is bound before the models load, so that `/healthz` and `/readyz` report the loading
+    let loading = LoadingRouter::new();
+    let oai_port = if let Some(port) = args.port {
+        let ip = args
+            .serve_ip
+            .clone()
+            .unwrap_or_else(|| "0.0.0.0".to_string());
+        let listener = tokio::net::TcpListener::bind(format!("{ip}:{port}")).await?;
+        info!("OpenAI-compatible server listening on http://{ip}:{port}, loading the models.");
+
+        let app = loading.router();
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            if let Err(e) = axum::serve(listener, app).await {
+                eprintln!("OpenAI server error: {e}");
+            }
+        })
+    } else {
+        tokio::spawn(async {})
+    };
+
+    let (mistralrs, server_builder) = match args.model {
         ModelSelected::MultiModel {
             config,
             default_model_id,
@@ -354,13 +474,23 @@ async fn main() -> Result<()> {
                 .with_token_source(args.token_source)
                 .with_interactive_mode(args.interactive_mode)
                 .with_prefix_cache_n(args.prefix_cache_n)
+                .with_scheduling_policy(scheduling_policy)
+                .with_prefill_chunk_size_optional(args.prefill_chunk_size)
+                .with_max_queue_len_optional(args.max_queue_len)
+                .with_kv_quota_optional(
+                    args.max_seq_kv_tokens
+                        .map(|tokens| KvQuotaConfig::new(KvQuota::Tokens(tokens))),
+                )
+                .with_deterministic(args.deterministic)
                 .set_paged_attn(paged_attn)
                 .with_cpu(args.cpu)
                 .with_enable_search(args.enable_search)
                 .with_seed_optional(args.seed)
                 .with_log_optional(args.log)
                 .with_mcp_config_optional(mcp_config)
-                .with_paged_attn_cache_type(args.cache_type.unwrap_or_default());
+                .with_paged_attn_cache_type(args.cache_type.unwrap_or_default())
+                .with_paged_attn_swap_space(args.paged_attn_swap_space)
+                .with_paged_attn_watermark(args.paged_attn_watermark);
 
             // Add models to builder
             for config in model_configs {
@@ -372,11 +502,11 @@ async fn main() -> Result<()> {
                 builder = builder.with_default_model_id(default_id);
             }
 
-            builder.build_multi_model().await?
+            (builder.clone().build_multi_model().await?, builder)
         }
         model => {
             // Single-model mode
-            MistralRsForServerBuilder::new()
+            let builder = MistralRsForServerBuilder::new()
                 .with_truncate_sequence(args.truncate_sequence)
                 .with_model(model)
                 .with_max_seqs(args.max_seqs)
@@ -384,6 +514,14 @@ async fn main() -> Result<()> {
                 .with_token_source(args.token_source)
                 .with_interactive_mode(args.interactive_mode)
                 .with_prefix_cache_n(args.prefix_cache_n)
+                .with_scheduling_policy(scheduling_policy)
+                .with_prefill_chunk_size_optional(args.prefill_chunk_size)
+                .with_max_queue_len_optional(args.max_queue_len)
+                .with_kv_quota_optional(
+                    args.max_seq_kv_tokens
+                        .map(|tokens| KvQuotaConfig::new(KvQuota::Tokens(tokens))),
+                )
+                .with_deterministic(args.deterministic)
                 .set_paged_attn(paged_attn)
                 .with_cpu(args.cpu)
                 .with_enable_search(args.enable_search)
@@ -399,8 +537,9 @@ async fn main() -> Result<()> {
                 .with_paged_attn_block_size_optional(args.paged_attn_block_size)
                 .with_mcp_config_optional(mcp_config)
                 .with_paged_attn_cache_type(args.cache_type.unwrap_or_default())
-                .build()
-                .await?
+                .with_paged_attn_swap_space(args.paged_attn_swap_space)
+                .with_paged_attn_watermark(args.paged_attn_watermark);
+            (builder.clone().build().await?, builder)
         }
     };
 
@@ -417,7 +556,11 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    if !args.interactive_mode && args.port.is_none() && args.mcp_port.is_none() {
+    if !args.interactive_mode
+        && args.port.is_none()
+        && args.mcp_port.is_none()
+        && args.grpc_port.is_none()
+    {
         anyhow::bail!("Interactive mode was not specified, so expected port to be specified. Perhaps you forgot `-i` or `--port` or `--mcp-port`?")
     }
 
@@ -439,32 +582,71 @@ async fn main() -> Result<()> {
         tokio::spawn(async {})
     };
 
-    let oai_port = if let Some(port) = args.port {
-        let ip = args
-            .serve_ip
-            .clone()
-            .unwrap_or_else(|| "0.0.0.0".to_string());
-
-        // Create listener early to validate address before model loading
-        let listener = tokio::net::TcpListener::bind(format!("{ip}:{port}")).await?;
-
-        let app = MistralRsServerRouterBuilder::new()
-            .with_mistralrs(mistralrs)
-            .build()
-            .await?;
+    let grpc_port = if let Some(port) = args.grpc_port {
+        #[cfg(not(feature = "grpc"))]
+        anyhow::bail!(
+            "`--grpc-port {port}` requires mistralrs-server to be built with the `grpc` feature."
+        );
 
-        info!("OpenAI-compatible server listening on http://{ip}:{port}.");
-
-        tokio::spawn(async move {
-            if let Err(e) = axum::serve(listener, app).await {
-                eprintln!("OpenAI server error: {e}");
-            }
-        })
+        #[cfg(feature = "grpc")]
+        {
+            let ip = args
+                .serve_ip
+                .clone()
+                .unwrap_or_else(|| "0.0.0.0".to_string());
+            let address: SocketAddr = format!("{ip}:{port}").parse()?;
+            info!("gRPC server listening on {address}.");
+            let mistralrs = mistralrs.clone();
+            let api_keys = api_keys.clone();
+            let rate_limit = (args.requests_per_minute.is_some()
+                || args.tokens_per_minute.is_some())
+            .then_some(RateLimitConfig {
+                requests_per_minute: args.requests_per_minute,
+                tokens_per_minute: args.tokens_per_minute,
+            });
+
+            tokio::spawn(async move {
+                if let Err(e) = mistralrs_server_core::grpc::serve_grpc(
+                    mistralrs, address, api_keys, rate_limit,
+                )
+                .await
+                {
+                    eprintln!("gRPC server error: {e}");
+                }
+            })
+        }
     } else {
         tokio::spawn(async {})
     };
 
-    let (_, _) = join!(oai_port, mcp_port);
+    if args.port.is_some() {
+        let mut router_builder = MistralRsServerRouterBuilder::new().with_mistralrs(mistralrs);
+        if let Some(admin_key) = args.admin_key {
+            router_builder = router_builder.with_admin(AdminConfig::new(admin_key, server_builder));
+        }
+        if let Some(api_keys) = api_keys {
+            router_builder = router_builder.with_api_keys(api_keys);
+        }
+        if let Some(tenants) = tenants {
+            router_builder = router_builder.with_tenants(tenants);
+        }
+        if let Some(ttl) = args.response_cache_ttl {
+            router_builder = router_builder.with_completion_cache(CompletionCacheConfig {
+                ttl: Duration::from_secs(ttl),
+                max_entries: args.response_cache_size,
+            });
+        }
+        if args.requests_per_minute.is_some() || args.tokens_per_minute.is_some() {
+            router_builder = router_builder.with_rate_limit(RateLimitConfig {
+                requests_per_minute: args.requests_per_minute,
+                tokens_per_minute: args.tokens_per_minute,
+            });
+        }
+        loading.set_app(router_builder.build().await?);
+        info!("OpenAI-compatible server ready.");
+    }
+
+    let (_, _, _) = join!(oai_port, mcp_port, grpc_port);
 
     Ok(())
 }
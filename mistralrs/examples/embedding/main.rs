@@ -0,0 +1,31 @@
+use anyhow::Result;
+use mistralrs::{EmbeddingModelBuilder, EmbeddingPooling};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = EmbeddingModelBuilder::new("BAAI/bge-small-en-v1.5")
+        .with_pooling(EmbeddingPooling::Cls)
+        .with_logging()
+        .build()
+        .await?;
+
+    let embeddings = model
+        .embed([
+            "mistral.rs is a fast LLM inference platform.",
+            "mistral.rs runs models quickly.",
+            "The weather is nice today.",
+        ])
+        .await?;
+
+    // The embeddings are normalized, so the dot product is the cosine similarity.
+    for (i, embedding) in embeddings.iter().enumerate().skip(1) {
+        let similarity: f32 = embeddings[0]
+            .iter()
+            .zip(embedding)
+            .map(|(a, b)| a * b)
+            .sum();
+        println!("Similarity of text 0 and text {i}: {similarity:.3}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use anyhow::Result;
+use mistralrs::{IsqType, LoadEvent, TextMessageRole, TextMessages, TextModelBuilder};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = TextModelBuilder::new("microsoft/Phi-3.5-mini-instruct")
+        .with_isq(IsqType::Q8_0)
+        .with_progress_callback(|event| match event {
+            LoadEvent::Download {
+                file,
+                downloaded,
+                total,
+            } => println!("Downloading {file}: {downloaded}/{total} bytes"),
+            LoadEvent::LoadShard { loaded, total } => {
+                println!("Loaded {loaded}/{total} weight files")
+            }
+            LoadEvent::Isq { quantized, total } => {
+                println!("Quantized {quantized}/{total} tensors")
+            }
+            LoadEvent::DeviceMap { device, layers } => println!("{layers} layers on {device}"),
+        })
+        .build()
+        .await?;
+
+    let messages =
+        TextMessages::new().add_message(TextMessageRole::User, "Hello! How are you today?");
+
+    let response = model.send_chat_request(messages).await?;
+
+    println!("{}", response.choices[0].message.content.as_ref().unwrap());
+
+    Ok(())
+}
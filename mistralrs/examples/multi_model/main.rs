@@ -0,0 +1,51 @@
+use anyhow::Result;
+use mistralrs::{EmbeddingModelBuilder, IsqType, TextMessageRole, TextMessages, TextModelBuilder};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = TextModelBuilder::new("Qwen/Qwen3-4B")
+        .with_isq(IsqType::Q4K)
+        .with_logging()
+        .build()
+        .await?;
+
+    // Serve a second chat model and an embedding model from the same runner.
+    model.add_model(
+        "phi",
+        TextModelBuilder::new("microsoft/Phi-3.5-mini-instruct")
+            .with_isq(IsqType::Q4K)
+            .build()
+            .await?,
+    )?;
+    model.add_model(
+        "embedder",
+        EmbeddingModelBuilder::new("BAAI/bge-small-en-v1.5")
+            .build()
+            .await?,
+    )?;
+    println!("Registered models: {:?}", model.list_models()?);
+
+    let messages =
+        TextMessages::new().add_message(TextMessageRole::User, "What is a vector database?");
+
+    // Requests without a model ID go to the default model, which is the first one.
+    let response = model.send_chat_request(messages.clone()).await?;
+    println!(
+        "Qwen: {}",
+        response.choices[0].message.content.as_ref().unwrap()
+    );
+
+    let response = model.for_model("phi").send_chat_request(messages).await?;
+    println!(
+        "Phi: {}",
+        response.choices[0].message.content.as_ref().unwrap()
+    );
+
+    let embeddings = model
+        .for_model("embedder")
+        .embed(["A vector database stores embeddings."])
+        .await?;
+    println!("Embedding dimension: {}", embeddings[0].len());
+
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use anyhow::Result;
+use mistralrs::{speech_utils, SpeechGenerationConfig, SpeechLoaderType, SpeechModelBuilder};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = SpeechModelBuilder::new("parler-tts/parler-tts-mini-v1", SpeechLoaderType::Parler)
+        .with_generation_config(SpeechGenerationConfig::Parler {
+            max_tokens: None,
+            temperature: 1.,
+            description: "Jon's voice is monotone yet slightly fast in delivery, with a very close recording that almost has no background noise.".to_string(),
+        })
+        .with_logging()
+        .build()
+        .await?;
+
+    let text = "mistral.rs is a fast LLM inference engine. It can run text and vision models, and now it can speak, too! Each sentence is generated as soon as the previous one is done.";
+
+    // Chunks are generated one sentence at a time, so playback could start after the first one.
+    let mut stream = model.stream_synthesize(text).await?;
+    let mut pcm = Vec::new();
+    let (mut rate, mut channels) = (0, 0);
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        println!("Generated {:?}", chunk.text);
+        pcm.extend_from_slice(&chunk.pcm);
+        (rate, channels) = (chunk.rate, chunk.channels);
+    }
+
+    let mut output = std::fs::File::create("out.wav")?;
+    speech_utils::write_pcm_as_wav(&mut output, &pcm, rate as u32, channels as u16)?;
+    println!("Audio saved at `out.wav`.");
+
+    Ok(())
+}
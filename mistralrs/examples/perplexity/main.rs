@@ -50,7 +50,10 @@ async fn process_chunk(runner: &MistralRs, chunk: Vec<u32>) -> anyhow::Result<(T
         logits_processors: None,
         return_raw_logits: true,
         web_search_options: None,
+        adapters: None,
         model_id: None,
+        tenant: None,
+        deadline: None,
     }));
 
     runner.get_sender(None)?.send(request).await?;
@@ -0,0 +1,23 @@
+use anyhow::Result;
+use mistralrs::RerankerModelBuilder;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = RerankerModelBuilder::new("BAAI/bge-reranker-base")
+        .with_logging()
+        .build()
+        .await?;
+
+    let documents = [
+        "The giant panda is a bear species endemic to China.",
+        "Paris is the capital of France.",
+        "Pandas mostly eat bamboo.",
+    ];
+    let ranked = model.rerank("What do pandas eat?", documents).await?;
+
+    for (index, score) in ranked {
+        println!("{score:.3}: {}", documents[index]);
+    }
+
+    Ok(())
+}
@@ -9,35 +9,12 @@ async fn main() -> Result<()> {
     let model = TextModelBuilder::new("microsoft/Phi-3.5-mini-instruct")
         .with_isq(IsqType::Q8_0)
         .with_topology(
-            Topology::empty()
-                .with_range(
-                    0..8,
-                    LayerTopology {
-                        isq: Some(IsqType::Q3K),
-                        device: None,
-                    },
-                )
-                .with_range(
-                    8..16,
-                    LayerTopology {
-                        isq: Some(IsqType::Q4K),
-                        device: None,
-                    },
-                )
-                .with_range(
-                    16..24,
-                    LayerTopology {
-                        isq: Some(IsqType::Q6K),
-                        device: None,
-                    },
-                )
-                .with_range(
-                    24..32,
-                    LayerTopology {
-                        isq: Some(IsqType::Q8_0),
-                        device: None,
-                    },
-                ),
+            Topology::builder()
+                .range(0..8, LayerTopology::new().isq(IsqType::Q3K))
+                .range(8..16, LayerTopology::new().isq(IsqType::Q4K))
+                .range(16..24, LayerTopology::new().isq(IsqType::Q6K))
+                .range(24..32, LayerTopology::new().isq(IsqType::Q8_0))
+                .build(),
         )
         .with_logging()
         .with_paged_attn(|| PagedAttentionMetaBuilder::default().build())?
@@ -0,0 +1,27 @@
+use anyhow::Result;
+use mistralrs::{AudioInput, SpeechLoaderType, SpeechModelBuilder};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = SpeechModelBuilder::new("openai/whisper-tiny", SpeechLoaderType::Whisper)
+        .with_logging()
+        .build()
+        .await?;
+
+    let path = std::env::args()
+        .nth(1)
+        .expect("Usage: whisper <path to audio file>");
+    let audio = AudioInput::from_bytes(&std::fs::read(path)?)?;
+
+    // Segments are printed as they are decoded. Pass e.g. `Some("en")` to skip language detection.
+    let mut stream = model.stream_transcribe(&audio, None).await?;
+    while let Some(segment) = stream.next().await {
+        let segment = segment?;
+        println!(
+            "[{:6.2}s -> {:6.2}s] {}",
+            segment.start, segment.end, segment.text
+        );
+    }
+
+    Ok(())
+}
@@ -1,20 +1,15 @@
-use std::fs::File;
-
 use anyhow::Result;
 use mistralrs::{TextMessageRole, TextMessages, TextModelBuilder, XLoraModelBuilder};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let model =
-        XLoraModelBuilder::from_text_model_builder(
-            TextModelBuilder::new("HuggingFaceH4/zephyr-7b-beta").with_logging(),
-            "lamm-mit/x-lora",
-            serde_json::from_reader(File::open("my-ordering-file.json").unwrap_or_else(|_| {
-                panic!("Could not load ordering file at my-ordering-file.json")
-            }))?,
-        )
-        .build()
-        .await?;
+    let model = XLoraModelBuilder::from_ordering_file(
+        TextModelBuilder::new("HuggingFaceH4/zephyr-7b-beta").with_logging(),
+        "lamm-mit/x-lora",
+        "my-ordering-file.json",
+    )?
+    .build()
+    .await?;
 
     let messages =
         TextMessages::new().add_message(TextMessageRole::User, "Hello! What is graphene.");
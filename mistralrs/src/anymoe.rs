@@ -48,9 +48,13 @@ impl AnyMoeModelBuilder {
             from_uqff: self.base.from_uqff,
             imatrix: None,
             calibration_file: None,
+            calibration_data: None,
+            mixed_precision_isq: None,
             hf_cache_path: self.base.hf_cache_path,
             matformer_config_path: None,
             matformer_slice_name: None,
+            isq_skip: self.base.isq_skip,
+            sliding_window: self.base.sliding_window,
         };
 
         if self.base.with_logging {
@@ -99,12 +103,17 @@ impl AnyMoeModelBuilder {
                     .get_metadata()
                     .cache_config
                     .as_ref()
-                    .unwrap()
-                    .clone();
+                    .cloned();
 
-                SchedulerConfig::PagedAttentionMeta {
-                    max_num_seqs: self.base.max_num_seqs,
-                    config,
+                if let Some(config) = config {
+                    SchedulerConfig::PagedAttentionMeta {
+                        max_num_seqs: self.base.max_num_seqs,
+                        config,
+                    }
+                } else {
+                    SchedulerConfig::DefaultScheduler {
+                        method: DefaultSchedulerMethod::Fixed(self.base.max_num_seqs.try_into()?),
+                    }
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
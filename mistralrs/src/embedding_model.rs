@@ -0,0 +1,117 @@
+use mistralrs_core::*;
+
+use crate::{best_device, Model};
+
+/// Configure a dense embedding model (such as BGE, GTE, E5 or Nomic Embed) for computing
+/// embeddings with [`Model::embed`].
+pub struct EmbeddingModelBuilder {
+    // Loading model
+    pub(crate) model_id: String,
+    pub(crate) token_source: TokenSource,
+    pub(crate) hf_revision: Option<String>,
+
+    // Embedding
+    pub(crate) pooling: Option<EmbeddingPooling>,
+    pub(crate) normalize: bool,
+
+    // Model running
+    pub(crate) dtype: ModelDType,
+    pub(crate) force_cpu: bool,
+
+    // Other things
+    pub(crate) with_logging: bool,
+}
+
+impl EmbeddingModelBuilder {
+    /// A few defaults are applied here:
+    /// - Token source is from the cache (.cache/huggingface/token)
+    /// - Pooling is read from the sentence-transformers config (`1_Pooling/config.json`), or is
+    ///   mean pooling if there is none
+    /// - Embeddings are L2-normalized
+    pub fn new(model_id: impl ToString) -> Self {
+        Self {
+            model_id: model_id.to_string(),
+            token_source: TokenSource::CacheToken,
+            hf_revision: None,
+            pooling: None,
+            normalize: true,
+            dtype: ModelDType::Auto,
+            force_cpu: false,
+            with_logging: false,
+        }
+    }
+
+    /// Set how the token states are pooled into one embedding per text.
+    pub fn with_pooling(mut self, pooling: EmbeddingPooling) -> Self {
+        self.pooling = Some(pooling);
+        self
+    }
+
+    /// Set whether the embeddings are L2-normalized. This is the default.
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Load the model in a certain dtype.
+    pub fn with_dtype(mut self, dtype: ModelDType) -> Self {
+        self.dtype = dtype;
+        self
+    }
+
+    /// Force usage of the CPU device.
+    pub fn with_force_cpu(mut self) -> Self {
+        self.force_cpu = true;
+        self
+    }
+
+    /// Source of the Hugging Face token.
+    pub fn with_token_source(mut self, token_source: TokenSource) -> Self {
+        self.token_source = token_source;
+        self
+    }
+
+    /// Set the revision to use for a Hugging Face remote model.
+    pub fn with_hf_revision(mut self, revision: impl ToString) -> Self {
+        self.hf_revision = Some(revision.to_string());
+        self
+    }
+
+    /// Enable logging.
+    pub fn with_logging(mut self) -> Self {
+        self.with_logging = true;
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<Model> {
+        if self.with_logging {
+            initialize_logging();
+        }
+
+        let loader = EmbeddingLoader {
+            model_id: self.model_id,
+            pooling: self.pooling,
+            normalize: self.normalize,
+        };
+
+        // Load, into a Pipeline
+        let pipeline = loader.load_model_from_hf(
+            self.hf_revision,
+            self.token_source,
+            &self.dtype,
+            &best_device(self.force_cpu)?,
+            !self.with_logging,
+            DeviceMapSetting::Auto(AutoDeviceMapParams::default_text()),
+            None,
+            None,
+        )?;
+
+        let scheduler_method = SchedulerConfig::DefaultScheduler {
+            method: DefaultSchedulerMethod::Fixed(1.try_into()?),
+        };
+
+        let runner = MistralRsBuilder::new(pipeline, scheduler_method, false, None);
+
+        Ok(Model::new(runner.build().await))
+    }
+}
@@ -3,7 +3,7 @@ use mistralrs_core::*;
 use mistralrs_core::{SearchCallback, Tool, ToolCallback};
 use std::collections::HashMap;
 
-use crate::{best_device, Model};
+use crate::{best_device, model::with_load_hooks, Model};
 use std::sync::Arc;
 
 /// A tool callback with its associated Tool definition.
@@ -41,6 +41,8 @@ pub struct GgufModelBuilder {
     pub(crate) max_num_seqs: usize,
     pub(crate) no_kv_cache: bool,
     pub(crate) with_logging: bool,
+    pub(crate) progress_callback: Option<LoadProgressCallback>,
+    pub(crate) cancellation_token: Option<CancellationToken>,
     pub(crate) prefix_cache_n: Option<usize>,
 }
 
@@ -65,6 +67,8 @@ impl GgufModelBuilder {
             no_kv_cache: false,
             prefix_cache_n: Some(16),
             with_logging: false,
+            progress_callback: None,
+            cancellation_token: None,
             topology: None,
             tok_model_id: None,
             device_mapping: None,
@@ -209,12 +213,51 @@ impl GgufModelBuilder {
         self
     }
 
+    /// Receive a [`LoadEvent`] for each step of downloading and loading the model, for example to
+    /// display a progress bar.
+    pub fn with_progress_callback(
+        mut self,
+        callback: impl Fn(LoadEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress_callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Cancel loading the model when `token` is cancelled, for example on a shutdown signal.
+    /// Loading then stops before the next file download and `build` returns an error.
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
     /// Provide metadata to initialize the device mapper.
     pub fn with_device_mapping(mut self, device_mapping: DeviceMapSetting) -> Self {
         self.device_mapping = Some(device_mapping);
         self
     }
 
+    /// Place the given number of repeating layers on each device, in order, for example
+    /// `[(Device::new_cuda(0)?, 20), (Device::new_cuda(1)?, 20)]`. Layers which are not given to
+    /// a device are placed on the CPU, after the GPU layers. Unless a main device is set with
+    /// `with_device`, the first GPU becomes the main device.
+    pub fn with_device_layers(
+        mut self,
+        device_layers: impl IntoIterator<Item = (Device, usize)>,
+    ) -> Self {
+        let device_layers = device_layers.into_iter().collect::<Vec<_>>();
+        if self.device.is_none() {
+            self.device = device_layers
+                .iter()
+                .map(|(device, _)| device)
+                .find(|device| !device.is_cpu())
+                .cloned();
+        }
+        self.device_mapping = Some(DeviceMapSetting::Map(
+            DeviceMapMetadata::from_device_layers(device_layers),
+        ));
+        self
+    }
+
     /// Set the main device to load this model onto. Automatic device mapping will be performed starting with this device.
     pub fn with_device(mut self, device: Device) -> Self {
         self.device = Some(device);
@@ -242,17 +285,19 @@ impl GgufModelBuilder {
         .build();
 
         // Load, into a Pipeline
-        let pipeline = loader.load_model_from_hf(
-            self.hf_revision,
-            self.token_source,
-            &ModelDType::Auto,
-            &self.device.unwrap_or(best_device(self.force_cpu).unwrap()),
-            !self.with_logging,
-            self.device_mapping
-                .unwrap_or(DeviceMapSetting::Auto(AutoDeviceMapParams::default_text())),
-            None,
-            self.paged_attn_cfg,
-        )?;
+        let pipeline = with_load_hooks(self.progress_callback, self.cancellation_token, || {
+            loader.load_model_from_hf(
+                self.hf_revision,
+                self.token_source,
+                &ModelDType::Auto,
+                &self.device.unwrap_or(best_device(self.force_cpu).unwrap()),
+                !self.with_logging,
+                self.device_mapping
+                    .unwrap_or(DeviceMapSetting::Auto(AutoDeviceMapParams::default_text())),
+                None,
+                self.paged_attn_cfg,
+            )
+        })?;
 
         let scheduler_method = match self.paged_attn_cfg {
             Some(_) => {
@@ -262,12 +307,17 @@ impl GgufModelBuilder {
                     .get_metadata()
                     .cache_config
                     .as_ref()
-                    .unwrap()
-                    .clone();
-
-                SchedulerConfig::PagedAttentionMeta {
-                    max_num_seqs: self.max_num_seqs,
-                    config,
+                    .cloned();
+
+                if let Some(config) = config {
+                    SchedulerConfig::PagedAttentionMeta {
+                        max_num_seqs: self.max_num_seqs,
+                        config,
+                    }
+                } else {
+                    SchedulerConfig::DefaultScheduler {
+                        method: DefaultSchedulerMethod::Fixed(self.max_num_seqs.try_into()?),
+                    }
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
@@ -1,12 +1,22 @@
+use std::collections::HashMap;
+
 use mistralrs_core::*;
 
 use crate::{best_device, GgufModelBuilder, Model};
 
+enum GgufLoraAdapters {
+    Ordering {
+        lora_model_id: String,
+        ordering: Ordering,
+    },
+    Safetensors(Vec<String>),
+}
+
 /// Wrapper of [`GgufModelBuilder`] for LoRA models.
 pub struct GgufLoraModelBuilder {
     gguf_model: GgufModelBuilder,
-    lora_model_id: String,
-    ordering: Ordering,
+    adapters: GgufLoraAdapters,
+    lora_adapter_scales: HashMap<String, f64>,
 }
 
 impl GgufLoraModelBuilder {
@@ -17,11 +27,45 @@ impl GgufLoraModelBuilder {
     ) -> Self {
         Self {
             gguf_model,
-            lora_model_id: lora_model_id.to_string(),
-            ordering,
+            adapters: GgufLoraAdapters::Ordering {
+                lora_model_id: lora_model_id.to_string(),
+                ordering,
+            },
+            lora_adapter_scales: HashMap::new(),
         }
     }
 
+    /// Apply PEFT LoRA adapters in safetensors format on top of the GGUF model. The adapters are
+    /// kept unmerged next to the quantized layers, as with [`Model::load_lora_adapter`].
+    ///
+    /// Each adapter ID is either a Hugging Face model ID or a local directory containing
+    /// `adapter_config.json` and `adapter_model.safetensors`. This is supported for the Llama,
+    /// Qwen 2 and Qwen 3 architectures.
+    pub fn from_gguf_model_builder_with_adapters(
+        gguf_model: GgufModelBuilder,
+        lora_adapter_ids: impl IntoIterator<Item = impl ToString>,
+    ) -> Self {
+        Self {
+            gguf_model,
+            adapters: GgufLoraAdapters::Safetensors(
+                lora_adapter_ids
+                    .into_iter()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            lora_adapter_scales: HashMap::new(),
+        }
+    }
+
+    /// Scale the delta weights of the adapter `adapter_id` by `scale`, instead of the
+    /// `lora_alpha / r` from its `adapter_config.json`. Only applies to the adapters of
+    /// [`GgufLoraModelBuilder::from_gguf_model_builder_with_adapters`].
+    pub fn with_adapter_scale(mut self, adapter_id: impl ToString, scale: f64) -> Self {
+        self.lora_adapter_scales
+            .insert(adapter_id.to_string(), scale);
+        self
+    }
+
     pub async fn build(self) -> anyhow::Result<Model> {
         let config = GGUFSpecificConfig {
             topology: self.gguf_model.topology,
@@ -31,7 +75,7 @@ impl GgufLoraModelBuilder {
             initialize_logging();
         }
 
-        let loader = GGUFLoaderBuilder::new(
+        let mut loader = GGUFLoaderBuilder::new(
             self.gguf_model.chat_template,
             self.gguf_model.tok_model_id,
             self.gguf_model.model_id,
@@ -39,9 +83,16 @@ impl GgufLoraModelBuilder {
             config,
             self.gguf_model.no_kv_cache,
             self.gguf_model.jinja_explicit,
-        )
-        .with_lora(self.lora_model_id, self.ordering)
-        .build();
+        );
+        let mut lora_adapter_ids = Vec::new();
+        match self.adapters {
+            GgufLoraAdapters::Ordering {
+                lora_model_id,
+                ordering,
+            } => loader = loader.with_lora(lora_model_id, ordering),
+            GgufLoraAdapters::Safetensors(ids) => lora_adapter_ids = ids,
+        }
+        let loader = loader.build();
 
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
@@ -65,12 +116,19 @@ impl GgufLoraModelBuilder {
                     .get_metadata()
                     .cache_config
                     .as_ref()
-                    .unwrap()
-                    .clone();
+                    .cloned();
 
-                SchedulerConfig::PagedAttentionMeta {
-                    max_num_seqs: self.gguf_model.max_num_seqs,
-                    config,
+                if let Some(config) = config {
+                    SchedulerConfig::PagedAttentionMeta {
+                        max_num_seqs: self.gguf_model.max_num_seqs,
+                        config,
+                    }
+                } else {
+                    SchedulerConfig::DefaultScheduler {
+                        method: DefaultSchedulerMethod::Fixed(
+                            self.gguf_model.max_num_seqs.try_into()?,
+                        ),
+                    }
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
@@ -98,6 +156,17 @@ impl GgufLoraModelBuilder {
             runner = runner.with_prefix_cache_n(n)
         }
 
-        Ok(Model::new(runner.build().await))
+        let model = Model::new(runner.build().await);
+        for adapter_id in lora_adapter_ids {
+            match self.lora_adapter_scales.get(&adapter_id) {
+                Some(&scale) => {
+                    model
+                        .load_lora_adapter_with_scale(adapter_id, scale)
+                        .await?
+                }
+                None => model.load_lora_adapter(adapter_id).await?,
+            }
+        }
+        Ok(model)
     }
 }
@@ -127,6 +127,7 @@
 
 mod anymoe;
 mod diffusion_model;
+mod embedding_model;
 mod gguf;
 mod gguf_lora_model;
 mod gguf_xlora_model;
@@ -134,6 +135,7 @@ mod lora_model;
 mod messages;
 mod model;
 mod multi_model;
+mod reranker_model;
 mod speculative;
 mod speech_model;
 mod text_model;
@@ -142,6 +144,7 @@ mod xlora_model;
 
 pub use anymoe::AnyMoeModelBuilder;
 pub use diffusion_model::DiffusionModelBuilder;
+pub use embedding_model::EmbeddingModelBuilder;
 pub use gguf::GgufModelBuilder;
 pub use gguf_lora_model::GgufLoraModelBuilder;
 pub use gguf_xlora_model::GgufXLoraModelBuilder;
@@ -151,8 +154,9 @@ pub use mistralrs_core::{
     McpClient, McpClientConfig, McpServerConfig, McpServerSource, McpToolInfo,
 };
 pub use mistralrs_core::{SearchCallback, SearchResult, ToolCallback};
-pub use model::{best_device, Model};
+pub use model::{best_device, Model, SynthesisStream, TranscriptionStream};
 pub use multi_model::MultiModel;
+pub use reranker_model::RerankerModelBuilder;
 pub use speculative::TextSpeculativeBuilder;
 pub use speech_model::SpeechModelBuilder;
 pub use text_model::{PagedAttentionMetaBuilder, TextModelBuilder, UqffTextModelBuilder};
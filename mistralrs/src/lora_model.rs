@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use mistralrs_core::*;
 
 use crate::{best_device, Model, TextModelBuilder};
@@ -6,9 +8,15 @@ use crate::{best_device, Model, TextModelBuilder};
 pub struct LoraModelBuilder {
     text_model: TextModelBuilder,
     lora_adapter_ids: Vec<String>,
+    lora_adapter_scales: HashMap<String, f64>,
 }
 
 impl LoraModelBuilder {
+    /// Each adapter ID is either a Hugging Face model ID or a local directory containing
+    /// `adapter_config.json` and `adapter_model.safetensors`.
+    ///
+    /// If the text model uses ISQ, the adapters are applied unmerged on top of the quantized
+    /// base weights.
     pub fn from_text_model_builder(
         text_model: TextModelBuilder,
         lora_adapter_ids: impl IntoIterator<Item = impl ToString>,
@@ -19,9 +27,18 @@ impl LoraModelBuilder {
                 .into_iter()
                 .map(|x| x.to_string())
                 .collect(),
+            lora_adapter_scales: HashMap::new(),
         }
     }
 
+    /// Scale the delta weights of the adapter `adapter_id` by `scale`, instead of the
+    /// `lora_alpha / r` from its `adapter_config.json`.
+    pub fn with_adapter_scale(mut self, adapter_id: impl ToString, scale: f64) -> Self {
+        self.lora_adapter_scales
+            .insert(adapter_id.to_string(), scale);
+        self
+    }
+
     pub async fn build(self) -> anyhow::Result<Model> {
         let config = NormalSpecificConfig {
             topology: self.text_model.topology,
@@ -30,16 +47,20 @@ impl LoraModelBuilder {
             from_uqff: self.text_model.from_uqff,
             imatrix: None,
             calibration_file: None,
+            calibration_data: self.text_model.calibration_data,
+            mixed_precision_isq: None,
             hf_cache_path: self.text_model.hf_cache_path,
             matformer_config_path: None,
             matformer_slice_name: None,
+            isq_skip: self.text_model.isq_skip,
+            sliding_window: self.text_model.sliding_window,
         };
 
         if self.text_model.with_logging {
             initialize_logging();
         }
 
-        let loader = NormalLoaderBuilder::new(
+        let mut loader = NormalLoaderBuilder::new(
             config,
             self.text_model.chat_template,
             self.text_model.tokenizer_json,
@@ -48,7 +69,11 @@ impl LoraModelBuilder {
             self.text_model.jinja_explicit,
         )
         .with_lora(self.lora_adapter_ids)
-        .build(self.text_model.loader_type)?;
+        .with_lora_scales(self.lora_adapter_scales);
+        if let Some(shared_weights) = self.text_model.shared_weights {
+            loader = loader.with_shared_weights(shared_weights);
+        }
+        let loader = loader.build(self.text_model.loader_type)?;
 
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
@@ -72,12 +97,19 @@ impl LoraModelBuilder {
                     .get_metadata()
                     .cache_config
                     .as_ref()
-                    .unwrap()
-                    .clone();
+                    .cloned();
 
-                SchedulerConfig::PagedAttentionMeta {
-                    max_num_seqs: self.text_model.max_num_seqs,
-                    config,
+                if let Some(config) = config {
+                    SchedulerConfig::PagedAttentionMeta {
+                        max_num_seqs: self.text_model.max_num_seqs,
+                        config,
+                    }
+                } else {
+                    SchedulerConfig::DefaultScheduler {
+                        method: DefaultSchedulerMethod::Fixed(
+                            self.text_model.max_num_seqs.try_into()?,
+                        ),
+                    }
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
@@ -1,4 +1,9 @@
-use std::{collections::HashMap, fmt::Display, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use super::*;
 use either::Either;
@@ -12,13 +17,14 @@ pub trait RequestLike {
     fn images_ref(&self) -> &[DynamicImage];
     fn take_messages(&mut self) -> RequestMessage;
     fn take_logits_processors(&mut self) -> Option<Vec<Arc<dyn CustomLogitsProcessor>>>;
-    fn take_adapters(&mut self) -> Option<Vec<String>>;
+    fn take_adapters(&mut self) -> Option<Vec<(String, f64)>>;
     fn return_logprobs(&self) -> bool;
     fn enable_search(&self) -> Option<bool>;
     fn take_constraint(&mut self) -> Constraint;
     fn take_tools(&mut self) -> Option<(Vec<Tool>, ToolChoice)>;
     fn take_sampling_params(&mut self) -> SamplingParams;
     fn take_web_search_options(&mut self) -> Option<WebSearchOptions>;
+    fn deadline(&self) -> Option<Instant>;
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -114,7 +120,7 @@ impl RequestLike for TextMessages {
     fn take_logits_processors(&mut self) -> Option<Vec<Arc<dyn CustomLogitsProcessor>>> {
         None
     }
-    fn take_adapters(&mut self) -> Option<Vec<String>> {
+    fn take_adapters(&mut self) -> Option<Vec<(String, f64)>> {
         None
     }
     fn return_logprobs(&self) -> bool {
@@ -132,6 +138,9 @@ impl RequestLike for TextMessages {
     fn take_web_search_options(&mut self) -> Option<WebSearchOptions> {
         None
     }
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -205,7 +214,10 @@ impl VisionMessages {
             ModelCategory::Text
             | ModelCategory::Diffusion
             | ModelCategory::Speech
-            | ModelCategory::Audio => {
+            | ModelCategory::Audio
+            | ModelCategory::Embedding
+            | ModelCategory::Reranker
+            | ModelCategory::Transcription => {
                 anyhow::bail!("`add_image_message` expects a vision model.")
             }
         };
@@ -290,7 +302,7 @@ impl RequestLike for VisionMessages {
     fn take_logits_processors(&mut self) -> Option<Vec<Arc<dyn CustomLogitsProcessor>>> {
         None
     }
-    fn take_adapters(&mut self) -> Option<Vec<String>> {
+    fn take_adapters(&mut self) -> Option<Vec<(String, f64)>> {
         None
     }
     fn return_logprobs(&self) -> bool {
@@ -308,6 +320,9 @@ impl RequestLike for VisionMessages {
     fn take_web_search_options(&mut self) -> Option<WebSearchOptions> {
         None
     }
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -325,7 +340,7 @@ pub struct RequestBuilder {
     images: Vec<DynamicImage>,
     audios: Vec<AudioInput>,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
-    adapters: Vec<String>,
+    adapters: Vec<(String, f64)>,
     return_logprobs: bool,
     constraint: Constraint,
     tools: Vec<Tool>,
@@ -333,6 +348,7 @@ pub struct RequestBuilder {
     sampling_params: SamplingParams,
     web_search_options: Option<WebSearchOptions>,
     enable_thinking: Option<bool>,
+    deadline: Option<Instant>,
 }
 
 impl Default for RequestBuilder {
@@ -356,6 +372,7 @@ impl From<TextMessages> for RequestBuilder {
             sampling_params: SamplingParams::deterministic(),
             web_search_options: None,
             enable_thinking: None,
+            deadline: None,
         }
     }
 }
@@ -375,6 +392,7 @@ impl From<VisionMessages> for RequestBuilder {
             sampling_params: SamplingParams::deterministic(),
             web_search_options: None,
             enable_thinking: None,
+            deadline: None,
         }
     }
 }
@@ -394,6 +412,7 @@ impl RequestBuilder {
             sampling_params: SamplingParams::deterministic(),
             web_search_options: None,
             enable_thinking: None,
+            deadline: None,
         }
     }
 
@@ -497,7 +516,10 @@ impl RequestBuilder {
             ModelCategory::Text
             | ModelCategory::Diffusion
             | ModelCategory::Speech
-            | ModelCategory::Audio => {
+            | ModelCategory::Audio
+            | ModelCategory::Embedding
+            | ModelCategory::Reranker
+            | ModelCategory::Transcription => {
                 anyhow::bail!("`add_image_message` expects a vision model.")
             }
         };
@@ -546,7 +568,29 @@ impl RequestBuilder {
         self
     }
 
+    /// Stop this request at `deadline`. If it has not started by then, it fails with a
+    /// [`DeadlineExceeded`] error, otherwise the tokens generated so far are returned with the
+    /// finish reason `timeout`.
+    pub fn set_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Stop this request once `timeout` has passed from now, see
+    /// [`RequestBuilder::set_deadline`].
+    pub fn set_timeout(self, timeout: Duration) -> Self {
+        self.set_deadline(Instant::now() + timeout)
+    }
+
+    /// Run this request with only the given LoRA adapters. By default, all loaded adapters are used.
     pub fn set_adapters(mut self, adapters: Vec<String>) -> Self {
+        self.adapters = adapters.into_iter().map(|name| (name, 1.0)).collect();
+        self
+    }
+
+    /// Run this request with only the given LoRA adapters, scaling the output of each adapter by
+    /// its weight. This blends the adapters linearly, for example `[("style", 0.7), ("domain", 0.3)]`.
+    pub fn set_weighted_adapters(mut self, adapters: Vec<(String, f64)>) -> Self {
         self.adapters = adapters;
         self
     }
@@ -608,6 +652,11 @@ impl RequestBuilder {
         self
     }
 
+    pub fn set_sampler_typical_p(mut self, typical_p: f64) -> Self {
+        self.sampling_params.typical_p = Some(typical_p);
+        self
+    }
+
     pub fn set_sampler_topn_logprobs(mut self, top_n_logprobs: usize) -> Self {
         self.sampling_params.top_n_logprobs = top_n_logprobs;
         self
@@ -648,6 +697,77 @@ impl RequestBuilder {
         self
     }
 
+    pub fn set_sampler_xtc_params(mut self, xtc_params: XtcSamplingParams) -> Self {
+        self.sampling_params.xtc_params = Some(xtc_params);
+        self
+    }
+
+    /// Never generate `banned_string`, however it would be tokenized. This may be called several
+    /// times to ban several strings.
+    pub fn add_banned_string(mut self, banned_string: impl ToString) -> Self {
+        self.sampling_params
+            .banned_strings
+            .get_or_insert_with(Vec::new)
+            .push(banned_string.to_string());
+        self
+    }
+
+    /// Never generate an n-gram of `size` tokens which is already in the context, including the
+    /// prompt.
+    pub fn set_no_repeat_ngram_size(mut self, size: usize) -> Self {
+        self.sampling_params.no_repeat_ngram_size = Some(size);
+        self
+    }
+
+    /// Sample with a random number generator seeded with `seed`, so the output of the request does
+    /// not depend on the other requests.
+    pub fn set_seed(mut self, seed: u64) -> Self {
+        self.sampling_params.seed = Some(seed);
+        self
+    }
+
+    /// Stop the sequence once the text generated so far matches `stop_regex`. This may be called
+    /// several times to add several regexes.
+    pub fn add_stop_regex(mut self, stop_regex: impl ToString) -> Self {
+        self.sampling_params
+            .stop_regexes
+            .get_or_insert_with(Vec::new)
+            .push(stop_regex.to_string());
+        self
+    }
+
+    /// Keep the text which matched a stop string or regex at the end of the completion, instead
+    /// of removing it.
+    pub fn set_include_stop_str_in_output(mut self, include_stop_str_in_output: bool) -> Self {
+        self.sampling_params.include_stop_str_in_output = include_stop_str_in_output;
+        self
+    }
+
+    /// Remove the last token of the prompt and generate it again, so a prompt which ends in the
+    /// middle of a token does not force an unusual tokenization at the start of the completion.
+    pub fn set_token_healing(mut self, token_healing: bool) -> Self {
+        self.sampling_params.token_healing = token_healing;
+        self
+    }
+
+    /// Use classifier-free guidance with `scale`, moving the logits away from those after
+    /// `negative_prompt`, or after an empty prompt if it is `None`. The negative prompt of a chat
+    /// request is formatted as a user message.
+    pub fn set_guidance(mut self, scale: f32, negative_prompt: Option<String>) -> Self {
+        self.sampling_params.guidance_scale = Some(scale);
+        self.sampling_params.negative_prompt = negative_prompt;
+        self
+    }
+
+    /// Use contrastive search, choosing among the `top_k` most likely tokens by their probability
+    /// less `penalty_alpha` times the similarity of their hidden states to those of the previous
+    /// tokens.
+    pub fn set_contrastive_search(mut self, penalty_alpha: f32, top_k: usize) -> Self {
+        self.sampling_params.penalty_alpha = Some(penalty_alpha);
+        self.sampling_params.top_k = Some(top_k);
+        self
+    }
+
     pub fn enable_thinking(mut self, enable_thinking: bool) -> Self {
         self.enable_thinking = Some(enable_thinking);
         self
@@ -701,7 +821,7 @@ impl RequestLike for RequestBuilder {
         }
     }
 
-    fn take_adapters(&mut self) -> Option<Vec<String>> {
+    fn take_adapters(&mut self) -> Option<Vec<(String, f64)>> {
         if self.adapters.is_empty() {
             None
         } else {
@@ -744,4 +864,8 @@ impl RequestLike for RequestBuilder {
         std::mem::swap(&mut other, &mut self.web_search_options);
         other
     }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
 }
@@ -2,7 +2,7 @@ use anyhow::Context;
 use candle_core::{Device, Result, Tensor};
 use either::Either;
 use mistralrs_core::*;
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 use tokio::sync::mpsc::{channel, Receiver};
 
 use crate::{RequestLike, TextMessages};
@@ -22,6 +22,24 @@ pub fn best_device(force_cpu: bool) -> Result<Device> {
     }
 }
 
+/// Run `load` with `callback` set as the load progress callback and `cancellation_token` set as
+/// the load cancellation token, if there are any.
+pub(crate) fn with_load_hooks<T>(
+    callback: Option<LoadProgressCallback>,
+    cancellation_token: Option<CancellationToken>,
+    load: impl FnOnce() -> T,
+) -> T {
+    if callback.is_none() && cancellation_token.is_none() {
+        return load();
+    }
+    set_load_progress_callback(callback);
+    set_load_cancellation_token(cancellation_token);
+    let result = load();
+    set_load_progress_callback(None);
+    set_load_cancellation_token(None);
+    result
+}
+
 /// The object used to interact with the model. This can be used with many varietes of models, \
 /// and as such may be created with one of:
 /// - [`TextModelBuilder`]
@@ -45,22 +63,144 @@ pub fn best_device(force_cpu: bool) -> Result<Device> {
 ///
 pub struct Model {
     pub(crate) runner: Arc<MistralRs>,
+    /// The registered model which requests are sent to, or the default model if `None`.
+    model_id: Option<String>,
 }
 
+/// The responses of a streaming request. Dropping the stream cancels the request.
 pub struct Stream<'a> {
     _server: &'a Model,
     rx: Receiver<Response>,
+    request_id: usize,
 }
 
 impl Stream<'_> {
     pub async fn next(&mut self) -> Option<Response> {
         self.rx.recv().await
     }
+
+    /// The ID of the request, which can be passed to [`Model::cancel`].
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+}
+
+impl Drop for Stream<'_> {
+    fn drop(&mut self) {
+        // This does nothing if the request is already done
+        if let Ok(sender) = self
+            ._server
+            .runner
+            .get_sender(self._server.model_id.as_deref())
+        {
+            let _ = sender.try_send(Request::Cancel(self.request_id));
+        }
+    }
+}
+
+/// Segments of a transcription, as they are decoded.
+pub struct TranscriptionStream<'a> {
+    _server: &'a Model,
+    rx: Receiver<anyhow::Result<TranscriptionSegment>>,
+}
+
+impl TranscriptionStream<'_> {
+    pub async fn next(&mut self) -> Option<anyhow::Result<TranscriptionSegment>> {
+        self.rx.recv().await
+    }
+}
+
+/// Chunks of synthesized speech, as they are generated.
+pub struct SynthesisStream<'a> {
+    _server: &'a Model,
+    rx: Receiver<anyhow::Result<AudioChunk>>,
+}
+
+impl SynthesisStream<'_> {
+    pub async fn next(&mut self) -> Option<anyhow::Result<AudioChunk>> {
+        self.rx.recv().await
+    }
 }
 
 impl Model {
     pub fn new(runner: Arc<MistralRs>) -> Self {
-        Self { runner }
+        Self {
+            runner,
+            model_id: None,
+        }
+    }
+
+    /// Register `model` under `model_id`, so that it is served by the same runner as this model.
+    /// Requests are routed to it with [`Model::for_model`], or by the `model_id` of a request.
+    ///
+    /// ```no_run
+    /// # use mistralrs::{TextModelBuilder, EmbeddingModelBuilder};
+    /// # async fn run() -> anyhow::Result<()> {
+    /// let model = TextModelBuilder::new("Qwen/Qwen3-4B").build().await?;
+    /// let embedder = EmbeddingModelBuilder::new("BAAI/bge-small-en-v1.5").build().await?;
+    /// model.add_model("embedder", embedder)?;
+    ///
+    /// let embeddings = model.for_model("embedder").embed(vec!["Hello!"]).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn add_model(&self, model_id: impl ToString, model: Model) -> anyhow::Result<()> {
+        self.runner
+            .add_model_from(
+                model_id.to_string(),
+                &model.runner,
+                model.model_id.as_deref(),
+            )
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// A handle which sends all requests to the registered model `model_id`.
+    pub fn for_model(&self, model_id: impl ToString) -> Model {
+        Self {
+            runner: self.runner.clone(),
+            model_id: Some(model_id.to_string()),
+        }
+    }
+
+    /// List the IDs of the registered models.
+    pub fn list_models(&self) -> anyhow::Result<Vec<String>> {
+        self.runner.list_models().map_err(anyhow::Error::msg)
+    }
+
+    /// Set the model which requests without a model ID are sent to.
+    pub fn set_default_model(&self, model_id: &str) -> anyhow::Result<()> {
+        self.runner
+            .set_default_model_id(model_id)
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Unload the model, freeing its weights, KV cache and device memory. Once this returns, the
+    /// memory has been released, so the next model can be loaded. If this handle is for the
+    /// default model, another registered model becomes the default.
+    pub async fn unload(self) -> anyhow::Result<()> {
+        self.runner
+            .unload_model(self.model_id.as_deref())
+            .await
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// A snapshot of the state of the engine: the queued and running sequences, the use of the
+    /// KV cache and prefix cache, and the recent throughput.
+    pub async fn stats(&self) -> anyhow::Result<EngineStats> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(Request::Stats(StatsRequest { response: tx }))
+            .await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")
+    }
+
+    /// Shut down the runner without dropping requests: new requests are rejected with a
+    /// [`Draining`] error, and this returns once the queued and running requests of all registered
+    /// models have finished, or after `timeout`, when any left are dropped.
+    pub async fn drain(&self, timeout: Duration) -> anyhow::Result<()> {
+        self.runner.drain(timeout).await.map_err(anyhow::Error::msg)
     }
 
     /// Generate with the model.
@@ -75,13 +215,14 @@ impl Model {
         } else {
             (None, None)
         };
+        let request_id = self.runner.next_request_id();
         let request = Request::Normal(Box::new(NormalRequest {
             messages: request.take_messages(),
             sampling_params: request.take_sampling_params(),
             response: tx,
             return_logprobs: request.return_logprobs(),
             is_streaming: true,
-            id: 0,
+            id: request_id,
             constraint: request.take_constraint(),
             suffix: None,
             tools,
@@ -89,16 +230,72 @@ impl Model {
             logits_processors: request.take_logits_processors(),
             return_raw_logits: false,
             web_search_options: request.take_web_search_options(),
-            model_id: None,
+            adapters: request.take_adapters(),
+            model_id: self.model_id.clone(),
+            tenant: None,
+            deadline: request.deadline(),
         }));
 
-        self.runner.get_sender(None)?.send(request).await?;
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
 
-        let stream = Stream { _server: self, rx };
+        let stream = Stream {
+            _server: self,
+            rx,
+            request_id,
+        };
 
         Ok(stream)
     }
 
+    /// Cancel the request `request_id`, see [`Stream::request_id`]. If it is still queued, it is
+    /// removed and fails with a [`RequestCanceled`] error. Otherwise, it stops after its next step
+    /// and the tokens generated so far are returned with the finish reason `canceled`.
+    pub async fn cancel(&self, request_id: usize) -> anyhow::Result<()> {
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(Request::Cancel(request_id))
+            .await?;
+        Ok(())
+    }
+
+    /// Take a snapshot of the KV cache of a conversation after its turn `request_id` finished, see
+    /// [`Stream::request_id`]. Pass it to [`Model::resume_session`], on this or another instance of
+    /// the same model, to continue the conversation without prefilling its history again.
+    ///
+    /// This requires the prefix cache, and is not supported with PagedAttention, tensor
+    /// parallelism or images and audio. The snapshot is only available until the prefix cache
+    /// evicts the turn.
+    pub async fn snapshot_session(&self, request_id: usize) -> anyhow::Result<SessionBlob> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(Request::SnapshotSession(SnapshotSessionRequest {
+                request_id,
+                response: tx,
+            }))
+            .await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Restore a conversation taken with [`Model::snapshot_session`]. The next request which
+    /// continues the conversation reuses its KV cache through the prefix cache.
+    pub async fn resume_session(&self, session: SessionBlob) -> anyhow::Result<()> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(Request::ResumeSession(ResumeSessionRequest {
+                session,
+                response: tx,
+            }))
+            .await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
     /// Generate with the model.
     pub async fn send_chat_request<R: RequestLike>(
         &self,
@@ -117,7 +314,7 @@ impl Model {
             response: tx,
             return_logprobs: request.return_logprobs(),
             is_streaming: false,
-            id: 0,
+            id: self.runner.next_request_id(),
             constraint: request.take_constraint(),
             suffix: None,
             tools,
@@ -125,10 +322,16 @@ impl Model {
             logits_processors: request.take_logits_processors(),
             return_raw_logits: false,
             web_search_options: request.take_web_search_options(),
-            model_id: None,
+            adapters: request.take_adapters(),
+            model_id: self.model_id.clone(),
+            tenant: None,
+            deadline: request.deadline(),
         }));
 
-        self.runner.get_sender(None)?.send(request).await?;
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
 
         let ResponseOk::Done(response) = rx
             .recv()
@@ -162,7 +365,7 @@ impl Model {
             response: tx,
             return_logprobs: request.return_logprobs(),
             is_streaming: false,
-            id: 0,
+            id: self.runner.next_request_id(),
             constraint: request.take_constraint(),
             suffix: None,
             tools,
@@ -170,10 +373,16 @@ impl Model {
             logits_processors: request.take_logits_processors(),
             return_raw_logits: true,
             web_search_options: request.take_web_search_options(),
-            model_id: None,
+            adapters: request.take_adapters(),
+            model_id: self.model_id.clone(),
+            tenant: None,
+            deadline: request.deadline(),
         }));
 
-        self.runner.get_sender(None)?.send(request).await?;
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
 
         let ResponseOk::Raw {
             logits_chunks,
@@ -190,6 +399,53 @@ impl Model {
         Ok((logits_chunks, tokens))
     }
 
+    /// Run `max_batch` requests of `max_prompt_len` tokens at once, each generating a few tokens,
+    /// so that kernel compilation and allocator growth for the prefill and decode passes happen
+    /// before the first real request. Call this once after building the model.
+    pub async fn warmup(&self, max_prompt_len: usize, max_batch: usize) -> anyhow::Result<()> {
+        const DECODE_TOKENS: usize = 4;
+
+        let sender = self.runner.get_sender(self.model_id.as_deref())?;
+        let mut receivers = Vec::with_capacity(max_batch);
+        for i in 0..max_batch {
+            let (tx, rx) = channel(1);
+            // Each prompt is different, so that none of them is served from the prefix cache.
+            let request = Request::Normal(Box::new(NormalRequest {
+                id: 0,
+                messages: RequestMessage::CompletionTokens(vec![i as u32; max_prompt_len.max(1)]),
+                sampling_params: SamplingParams {
+                    max_len: Some(DECODE_TOKENS),
+                    ..SamplingParams::deterministic()
+                },
+                response: tx,
+                return_logprobs: false,
+                is_streaming: false,
+                suffix: None,
+                constraint: Constraint::None,
+                tool_choice: None,
+                tools: None,
+                logits_processors: None,
+                return_raw_logits: false,
+                web_search_options: None,
+                adapters: None,
+                model_id: self.model_id.clone(),
+                tenant: None,
+                deadline: None,
+            }));
+            sender.send(request).await?;
+            receivers.push(rx);
+        }
+
+        for mut rx in receivers {
+            rx.recv()
+                .await
+                .context("Channel was erroneously closed!")?
+                .as_result()?;
+        }
+
+        Ok(())
+    }
+
     pub async fn generate_image(
         &self,
         prompt: impl ToString,
@@ -216,10 +472,16 @@ impl Model {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
-            model_id: None,
+            adapters: None,
+            model_id: self.model_id.clone(),
+            tenant: None,
+            deadline: None,
         }));
 
-        self.runner.get_sender(None)?.send(request).await?;
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
 
         let ResponseOk::ImageGeneration(response) = rx
             .recv()
@@ -258,10 +520,16 @@ impl Model {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
-            model_id: None,
+            adapters: None,
+            model_id: self.model_id.clone(),
+            tenant: None,
+            deadline: None,
         }));
 
-        self.runner.get_sender(None)?.send(request).await?;
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
 
         let ResponseOk::Speech {
             pcm,
@@ -279,11 +547,305 @@ impl Model {
         Ok((pcm, rate, channels))
     }
 
+    /// Speak `text` with a text-to-speech model built with [`crate::SpeechModelBuilder`],
+    /// yielding each chunk of audio as soon as it is generated. Parler-TTS speaks the text one
+    /// sentence at a time, while Dia generates the whole text as one chunk.
+    pub async fn stream_synthesize(
+        &self,
+        text: impl ToString,
+    ) -> anyhow::Result<SynthesisStream<'_>> {
+        let (tx, rx) = channel(1);
+        let request = Request::Synthesis(SynthesisRequest {
+            text: text.to_string(),
+            response: tx,
+        });
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
+
+        Ok(SynthesisStream { _server: self, rx })
+    }
+
+    /// Speak `text` with a text-to-speech model built with [`crate::SpeechModelBuilder`],
+    /// returning all chunks of audio. See [`Model::stream_synthesize`].
+    pub async fn synthesize(&self, text: impl ToString) -> anyhow::Result<Vec<AudioChunk>> {
+        let mut stream = self.stream_synthesize(text).await?;
+        let mut chunks = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            chunks.push(chunk?);
+        }
+        Ok(chunks)
+    }
+
     /// Reapply ISQ to the model. This will be done on whatever device the model is already on.
     pub async fn re_isq_model(&self, isq_type: IsqType) -> anyhow::Result<()> {
         let request = Request::ReIsq(isq_type);
 
-        Ok(self.runner.get_sender(None)?.send(request).await?)
+        Ok(self
+            .runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?)
+    }
+
+    /// Quantize the model to `isq_type` from its original weights, for example to switch between
+    /// Q8 and Q4 as memory pressure changes without reloading the model.
+    ///
+    /// The first call copies the unquantized weights to the CPU, which needs enough system memory
+    /// to hold them, and every later call quantizes from that copy. If the model was quantized when
+    /// it was loaded, the original weights are not available and this behaves like
+    /// [`re_isq_model`](Self::re_isq_model), requantizing the quantized weights.
+    pub async fn requantize(&self, isq_type: IsqType) -> anyhow::Result<()> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::Requantize(RequantizeRequest {
+            isq_type,
+            response: tx,
+        });
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Collect an imatrix by running the model over the calibration `data`, and reapply ISQ to
+    /// the model with it. The collected imatrix is also saved to a `.cimatrix` file, which can be
+    /// passed to `with_imatrix` to skip the calibration next time.
+    ///
+    /// The layers of the model must be unquantized to collect the imatrix, so this is meant for
+    /// models loaded without ISQ.
+    pub async fn re_isq_model_calibrated(
+        &self,
+        isq_type: IsqType,
+        data: CalibrationData,
+    ) -> anyhow::Result<()> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::CalibratedIsq(CalibratedIsqRequest {
+            isq_type,
+            data,
+            response: tx,
+        });
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Load a LoRA adapter (a Hugging Face model ID) on top of the weights of the model.
+    ///
+    /// The adapter is not merged into the weights, so sequences selecting different adapters can
+    /// still be batched together. DoRA adapters are merged. Sequences which are currently running
+    /// will continue with the new adapter.
+    pub async fn load_lora_adapter(&self, adapter_id: impl ToString) -> anyhow::Result<()> {
+        self.send_lora_adapter_request(LoraAdapterAction::Load(adapter_id.to_string(), None))
+            .await?;
+        Ok(())
+    }
+
+    /// Load a LoRA adapter as with [`Model::load_lora_adapter`], scaling its delta weights by
+    /// `scale` instead of the `lora_alpha / r` from its `adapter_config.json`.
+    pub async fn load_lora_adapter_with_scale(
+        &self,
+        adapter_id: impl ToString,
+        scale: f64,
+    ) -> anyhow::Result<()> {
+        self.send_lora_adapter_request(LoraAdapterAction::Load(
+            adapter_id.to_string(),
+            Some(scale),
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// Remove a LoRA adapter which was loaded at runtime or when building the model.
+    ///
+    /// For quantized layers the base weights are recovered up to the quantization error.
+    pub async fn unload_lora_adapter(&self, adapter_id: impl ToString) -> anyhow::Result<()> {
+        self.send_lora_adapter_request(LoraAdapterAction::Unload(adapter_id.to_string()))
+            .await?;
+        Ok(())
+    }
+
+    /// Make the active LoRA adapters a permanent part of the base weights, removing the overhead of
+    /// applying them separately.
+    ///
+    /// All adapters are dropped afterwards, so they can no longer be unloaded or selected per
+    /// request. Adapters which are loaded later are applied on top of the merged weights.
+    pub async fn merge_lora(&self) -> anyhow::Result<()> {
+        self.send_lora_adapter_request(LoraAdapterAction::Merge)
+            .await?;
+        Ok(())
+    }
+
+    /// List the loaded LoRA adapters, in load order.
+    ///
+    /// An adapter is active if it is merged into the weights or used by a sequence which is
+    /// currently running.
+    pub async fn list_adapters(&self) -> anyhow::Result<Vec<LoraAdapterInfo>> {
+        self.send_lora_adapter_request(LoraAdapterAction::List)
+            .await
+    }
+
+    async fn send_lora_adapter_request(
+        &self,
+        action: LoraAdapterAction,
+    ) -> anyhow::Result<Vec<LoraAdapterInfo>> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::LoraAdapter(LoraAdapterRequest {
+            action,
+            response: tx,
+        });
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Write the model, with any merged LoRA adapters, to a UQFF file at `path`. Quantized layers
+    /// stay quantized. The result can be loaded with `from_uqff`, skipping the cost of merging
+    /// the adapters on startup.
+    ///
+    /// Adapters which are not merged must be merged with [`Model::merge_lora`] first.
+    pub async fn write_uqff(&self, path: impl Into<PathBuf>) -> anyhow::Result<()> {
+        self.send_export_request(path.into(), ExportFormat::Uqff)
+            .await
+    }
+
+    /// Write the model, with any merged LoRA adapters, to the directory `dir` as
+    /// `model.safetensors` along with its config and tokenizer, so it can be loaded as a normal
+    /// model. Only unquantized models can be exported this way.
+    ///
+    /// Adapters which are not merged must be merged with [`Model::merge_lora`] first.
+    pub async fn write_safetensors(&self, dir: impl Into<PathBuf>) -> anyhow::Result<()> {
+        self.send_export_request(dir.into(), ExportFormat::Safetensors)
+            .await
+    }
+
+    /// Write the model, with any merged LoRA adapters, to a GGUF file at `path` along with its
+    /// tokenizer and chat template, so it can be used with llama.cpp based tools. Layers are
+    /// quantized to `quant`, which must be a GGML type such as [`IsqType::Q4K`]. If it is not
+    /// given, layers quantized with ISQ to a GGML type keep their quantization and the others are
+    /// written in F16.
+    ///
+    /// Only Llama and Mistral models can be exported this way. Adapters which are not merged must
+    /// be merged with [`Model::merge_lora`] first.
+    pub async fn write_gguf(
+        &self,
+        path: impl Into<PathBuf>,
+        quant: Option<IsqType>,
+    ) -> anyhow::Result<()> {
+        self.send_export_request(path.into(), ExportFormat::Gguf(quant))
+            .await
+    }
+
+    async fn send_export_request(&self, path: PathBuf, format: ExportFormat) -> anyhow::Result<()> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::Export(ExportRequest {
+            path,
+            format,
+            response: tx,
+        });
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Compute one embedding vector for each of `texts`, in order. Only models built with
+    /// [`crate::EmbeddingModelBuilder`] support this.
+    pub async fn embed(
+        &self,
+        texts: impl IntoIterator<Item = impl ToString>,
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::Embedding(EmbeddingRequest {
+            texts: texts.into_iter().map(|text| text.to_string()).collect(),
+            response: tx,
+        });
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Score the relevance of each of `documents` to `query` with a reranker model built with
+    /// [`crate::RerankerModelBuilder`]. Returns the index of each document with its score, most
+    /// relevant first. The scores are the raw logits of the model; apply a sigmoid to map them to
+    /// `[0, 1]`.
+    pub async fn rerank(
+        &self,
+        query: impl ToString,
+        documents: impl IntoIterator<Item = impl ToString>,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::Rerank(RerankRequest {
+            query: query.to_string(),
+            documents: documents
+                .into_iter()
+                .map(|document| document.to_string())
+                .collect(),
+            response: tx,
+        });
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
+
+        let scores = rx
+            .recv()
+            .await
+            .context("Channel was erroneously closed!")??;
+        let mut ranked = scores.into_iter().enumerate().collect::<Vec<_>>();
+        ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        Ok(ranked)
+    }
+
+    /// Transcribe `audio` with a speech-to-text model built with [`crate::SpeechModelBuilder`],
+    /// yielding each 30 s segment as soon as it is decoded. `language` is a language code such as
+    /// `en`; if it is not given, it is detected from the start of the audio.
+    pub async fn stream_transcribe(
+        &self,
+        audio: &AudioInput,
+        language: Option<&str>,
+    ) -> anyhow::Result<TranscriptionStream<'_>> {
+        let (tx, rx) = channel(1);
+        let request = Request::Transcription(TranscriptionRequest {
+            samples: audio.to_mono(),
+            sample_rate: audio.sample_rate,
+            language: language.map(ToString::to_string),
+            response: tx,
+        });
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
+
+        Ok(TranscriptionStream { _server: self, rx })
+    }
+
+    /// Transcribe `audio` with a speech-to-text model built with [`crate::SpeechModelBuilder`],
+    /// returning all segments. See [`Model::stream_transcribe`].
+    pub async fn transcribe(
+        &self,
+        audio: &AudioInput,
+        language: Option<&str>,
+    ) -> anyhow::Result<Vec<TranscriptionSegment>> {
+        let mut stream = self.stream_transcribe(audio, language).await?;
+        let mut segments = Vec::new();
+        while let Some(segment) = stream.next().await {
+            segments.push(segment?);
+        }
+        Ok(segments)
     }
 
     /// Tokenize some text or messages.
@@ -305,7 +867,10 @@ impl Model {
             response: tx,
             enable_thinking,
         });
-        self.runner.get_sender(None)?.send(request).await?;
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
 
         rx.recv().await.context("Channel was erroneously closed!")?
     }
@@ -322,14 +887,17 @@ impl Model {
             skip_special_tokens,
             response: tx,
         });
-        self.runner.get_sender(None)?.send(request).await?;
+        self.runner
+            .get_sender(self.model_id.as_deref())?
+            .send(request)
+            .await?;
 
         rx.recv().await.context("Channel was erroneously closed!")?
     }
 
     /// Retrieve some information about this model.
     pub fn config(&self) -> std::result::Result<MistralRsConfig, String> {
-        self.runner.config(None)
+        self.runner.config(self.model_id.as_deref())
     }
 
     pub fn inner(&self) -> &MistralRs {
@@ -86,7 +86,10 @@ impl MultiModel {
             logits_processors: request.take_logits_processors(),
             return_raw_logits: false,
             web_search_options: request.take_web_search_options(),
+            adapters: request.take_adapters(),
             model_id: model_id.map(|s| s.to_string()),
+            tenant: None,
+            deadline: request.deadline(),
         }));
 
         self.runner.get_sender(model_id)?.send(request).await?;
@@ -46,9 +46,13 @@ impl TextSpeculativeBuilder {
             from_uqff: builder.from_uqff,
             imatrix: builder.imatrix,
             calibration_file: builder.calibration_file,
+            calibration_data: builder.calibration_data,
+            mixed_precision_isq: builder.mixed_precision_isq,
             hf_cache_path: builder.hf_cache_path,
             matformer_config_path: None,
             matformer_slice_name: None,
+            isq_skip: Vec::new(),
+            sliding_window: None,
         };
 
         if builder.with_logging {
@@ -2,7 +2,10 @@ use mistralrs_core::*;
 
 use crate::{best_device, Model};
 
-/// Configure a text model with the various parameters for loading, running, and other inference behaviors.
+/// Configure a speech model with the various parameters for loading, running, and other inference behaviors.
+///
+/// Text-to-speech models (Dia, Parler-TTS) speak text with [`Model::synthesize`], and
+/// speech-to-text models (Whisper) transcribe audio with [`Model::transcribe`].
 pub struct SpeechModelBuilder {
     // Loading model
     pub(crate) model_id: String,
@@ -41,12 +44,19 @@ impl SpeechModelBuilder {
     }
 
     /// DAC Model ID to load from. If not provided, this is automatically downloaded from the default path for the model.
-    /// This may be a HF hub repo or a local path.
+    /// This may be a HF hub repo or a local path. Transcription models do not use a DAC model.
     pub fn with_dac_model_id(mut self, dac_model_id: String) -> Self {
         self.dac_model_id = Some(dac_model_id);
         self
     }
 
+    /// Set the speech generation config. This must match the loader type; for Parler-TTS, it
+    /// includes the description of the voice.
+    pub fn with_generation_config(mut self, cfg: SpeechGenerationConfig) -> Self {
+        self.cfg = Some(cfg);
+        self
+    }
+
     /// Load the model in a certain dtype.
     pub fn with_dtype(mut self, dtype: ModelDType) -> Self {
         self.dtype = dtype;
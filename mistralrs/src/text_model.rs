@@ -1,14 +1,15 @@
+use anyhow::Context;
 use candle_core::Device;
 use mistralrs_core::*;
 use mistralrs_core::{SearchCallback, Tool, ToolCallback};
 use std::collections::HashMap;
 use std::{
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
-use crate::{best_device, Model};
+use crate::{best_device, model::with_load_hooks, Model};
 
 /// A tool callback with its associated Tool definition.
 #[derive(Clone)]
@@ -28,11 +29,14 @@ pub struct TextModelBuilder {
     pub(crate) from_uqff: Option<Vec<PathBuf>>,
     pub(crate) imatrix: Option<PathBuf>,
     pub(crate) calibration_file: Option<PathBuf>,
+    pub(crate) calibration_data: Option<CalibrationData>,
+    pub(crate) mixed_precision_isq: Option<MixedPrecisionIsq>,
     pub(crate) chat_template: Option<String>,
     pub(crate) jinja_explicit: Option<String>,
     pub(crate) tokenizer_json: Option<String>,
     pub(crate) device_mapping: Option<DeviceMapSetting>,
     pub(crate) hf_cache_path: Option<PathBuf>,
+    pub(crate) shared_weights: Option<SharedWeights>,
     pub(crate) search_bert_model: Option<BertEmbeddingModel>,
     pub(crate) search_callback: Option<Arc<SearchCallback>>,
     pub(crate) tool_callbacks: HashMap<String, Arc<ToolCallback>>,
@@ -41,6 +45,9 @@ pub struct TextModelBuilder {
     pub(crate) device: Option<Device>,
     pub(crate) matformer_config_path: Option<PathBuf>,
     pub(crate) matformer_slice_name: Option<String>,
+    pub(crate) isq_skip: Vec<String>,
+    pub(crate) sliding_window: Option<SlidingWindowOverride>,
+    pub(crate) in_memory_files: Option<HashMap<String, Arc<[u8]>>>,
 
     // Model running
     pub(crate) topology: Option<Topology>,
@@ -56,7 +63,17 @@ pub struct TextModelBuilder {
     pub(crate) max_num_seqs: usize,
     pub(crate) no_kv_cache: bool,
     pub(crate) with_logging: bool,
+    pub(crate) progress_callback: Option<LoadProgressCallback>,
+    pub(crate) cancellation_token: Option<CancellationToken>,
     pub(crate) prefix_cache_n: Option<usize>,
+    pub(crate) prefix_cache_dir: Option<PathBuf>,
+    pub(crate) h2o_config: Option<H2oConfig>,
+    pub(crate) attention_sinks: Option<AttentionSinkConfig>,
+    pub(crate) kv_quota: Option<KvQuotaConfig>,
+    pub(crate) logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+    pub(crate) deterministic: bool,
+    pub(crate) prefill_chunk_size: Option<usize>,
+    pub(crate) prefill_device: Option<Device>,
 }
 
 /// Builder for PagedAttention metadata.
@@ -64,6 +81,9 @@ pub struct PagedAttentionMetaBuilder {
     block_size: Option<usize>,
     mem_gpu: MemoryGpuConfig,
     cache_type: PagedCacheType,
+    swap_space: usize,
+    host_offload_bytes: usize,
+    watermark: f32,
 }
 
 impl Default for PagedAttentionMetaBuilder {
@@ -72,6 +92,9 @@ impl Default for PagedAttentionMetaBuilder {
             block_size: None,
             mem_gpu: MemoryGpuConfig::ContextSize(4096),
             cache_type: PagedCacheType::Auto,
+            swap_space: 0,
+            host_offload_bytes: 0,
+            watermark: 0.,
         }
     }
 }
@@ -87,13 +110,44 @@ impl PagedAttentionMetaBuilder {
         self
     }
 
+    /// Set the KV cache dtype. [`PagedCacheType::F8E4M3`] halves the KV cache memory of a
+    /// 16 bit model, with per-layer scales calibrated during the first forward passes.
+    /// [`PagedCacheType::Int8`] also halves it, with a scale for each token and KV head which is
+    /// stored in the cache blocks.
     pub fn with_paged_cache_type(mut self, cache_type: PagedCacheType) -> Self {
         self.cache_type = cache_type;
         self
     }
 
+    /// Host memory in MB to swap the KV cache of preempted sequences to, so they are resumed
+    /// without recomputing their prompt when the KV cache is full.
+    pub fn with_swap_space(mut self, swap_space: usize) -> Self {
+        self.swap_space = swap_space;
+        self
+    }
+
+    /// Host memory in bytes to offload cold shared prefix blocks to, instead of evicting them when
+    /// the KV cache is full. They are copied back to the GPU when a new request starts with their
+    /// tokens, so idle conversations stay resident without holding GPU memory.
+    pub fn with_host_offload(mut self, host_offload_bytes: usize) -> Self {
+        self.host_offload_bytes = host_offload_bytes;
+        self
+    }
+
+    /// The fraction of the GPU blocks to keep free when admitting new requests, so running
+    /// requests can grow without being preempted. Defaults to 0.
+    pub fn with_watermark(mut self, watermark: f32) -> Self {
+        self.watermark = watermark;
+        self
+    }
+
     pub fn build(self) -> anyhow::Result<PagedAttentionConfig> {
-        PagedAttentionConfig::new(self.block_size, self.mem_gpu, self.cache_type)
+        Ok(
+            PagedAttentionConfig::new(self.block_size, self.mem_gpu, self.cache_type)?
+                .with_swap_space(self.swap_space)
+                .with_host_offload(self.host_offload_bytes)
+                .with_watermark(self.watermark),
+        )
     }
 }
 
@@ -124,13 +178,26 @@ impl TextModelBuilder {
             max_num_seqs: 32,
             no_kv_cache: false,
             prefix_cache_n: Some(16),
+            prefix_cache_dir: None,
+            h2o_config: None,
+            attention_sinks: None,
+            kv_quota: None,
+            logits_processors: Vec::new(),
+            deterministic: false,
+            prefill_chunk_size: None,
+            prefill_device: None,
             with_logging: false,
+            progress_callback: None,
+            cancellation_token: None,
             device_mapping: None,
             imatrix: None,
             calibration_file: None,
+            calibration_data: None,
+            mixed_precision_isq: None,
             jinja_explicit: None,
             throughput_logging: false,
             hf_cache_path: None,
+            shared_weights: None,
             search_bert_model: None,
             search_callback: None,
             tool_callbacks: HashMap::new(),
@@ -139,9 +206,47 @@ impl TextModelBuilder {
             device: None,
             matformer_config_path: None,
             matformer_slice_name: None,
+            isq_skip: Vec::new(),
+            sliding_window: None,
+            in_memory_files: None,
         }
     }
 
+    /// Load a model from a local directory containing the safetensors weights, `config.json`,
+    /// `tokenizer.json` and `tokenizer_config.json` (or a chat template). Every file is resolved
+    /// in this directory and the Hugging Face hub is never contacted, so no HF token is used.
+    /// The same defaults as [`TextModelBuilder::new`] are applied.
+    ///
+    /// Note that [`TextModelBuilder::with_search`] still fetches its embedding model from the hub.
+    pub fn from_local_path(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let dir = std::fs::canonicalize(dir)
+            .with_context(|| format!("Model directory `{}` does not exist", dir.display()))?;
+        if !dir.is_dir() {
+            anyhow::bail!("Model path `{}` is not a directory", dir.display());
+        }
+        if !dir.join("config.json").is_file() {
+            anyhow::bail!("Model directory `{}` has no `config.json`", dir.display());
+        }
+
+        let mut builder = Self::new(dir.display());
+        builder.token_source = TokenSource::None;
+        Ok(builder)
+    }
+
+    /// Load a model from files held in memory, keyed by their names in the model repository, such
+    /// as weights fetched from an artifact store or decrypted in memory. The files never touch
+    /// disk; see [`InMemoryModelPaths`] for the files which are used. The same defaults as
+    /// [`TextModelBuilder::new`] are applied.
+    ///
+    /// [`TextModelBuilder::estimate_memory`] is not supported for in-memory files.
+    pub fn from_in_memory_files(files: HashMap<String, Arc<[u8]>>) -> Self {
+        let mut builder = Self::new("in-memory");
+        builder.token_source = TokenSource::None;
+        builder.in_memory_files = Some(files);
+        builder
+    }
+
     /// Enable searching compatible with the OpenAI `web_search_options` setting. This uses the BERT model specified or the default.
     pub fn with_search(mut self, search_bert_model: BertEmbeddingModel) -> Self {
         self.search_bert_model = Some(search_bert_model);
@@ -258,6 +363,25 @@ impl TextModelBuilder {
         self
     }
 
+    /// Keep the layers whose tensor names match the regex `pattern` (such as `^lm_head` or
+    /// `^model\.layers\.0\.`) at full precision when applying ISQ. This may be called several
+    /// times to skip several patterns.
+    ///
+    /// Skipped layers are matched while the weights are loaded, so this cannot be combined with an
+    /// imatrix, a calibration file or writing UQFF. An `lm_head` which is tied to the embeddings is
+    /// always quantized.
+    pub fn with_isq_skip(mut self, pattern: impl ToString) -> Self {
+        self.isq_skip.push(pattern.to_string());
+        self
+    }
+
+    /// Override the sliding window attention of the model config: disable it to use full
+    /// attention, or set the window size. Models without a sliding window ignore this.
+    pub fn with_sliding_window(mut self, sliding_window: SlidingWindowOverride) -> Self {
+        self.sliding_window = Some(sliding_window);
+        self
+    }
+
     /// Utilise this imatrix file during ISQ. Incompatible with specifying a calibration file.
     pub fn with_imatrix(mut self, path: PathBuf) -> Self {
         self.imatrix = Some(path);
@@ -270,6 +394,34 @@ impl TextModelBuilder {
         self
     }
 
+    /// Collect an imatrix from these calibration texts instead of a calibration file, for example
+    /// samples of the requests a service receives. The texts are tokenized without special tokens.
+    pub fn with_calibration_texts(
+        mut self,
+        texts: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.calibration_data = Some(CalibrationData::Texts(
+            texts.into_iter().map(Into::into).collect(),
+        ));
+        self
+    }
+
+    /// Collect an imatrix from these sequences of token IDs instead of a calibration file. The
+    /// sequences should not start with BOS, which is added to each chunk of calibration tokens.
+    pub fn with_calibration_tokens(mut self, seqs: impl IntoIterator<Item = Vec<u32>>) -> Self {
+        self.calibration_data = Some(CalibrationData::Tokens(seqs.into_iter().collect()));
+        self
+    }
+
+    /// Measure the sensitivity of each layer to quantization on the calibration data, and
+    /// quantize the most sensitive ones to `high` instead of the ISQ type, as long as the
+    /// quantized layers fit in `budget_bytes`. This requires a calibration file, calibration data
+    /// or an imatrix.
+    pub fn with_mixed_precision_isq(mut self, high: IsqType, budget_bytes: usize) -> Self {
+        self.mixed_precision_isq = Some(MixedPrecisionIsq { high, budget_bytes });
+        self
+    }
+
     /// Enable PagedAttention. Configure PagedAttention with a [`PagedAttentionConfig`] object, which
     /// can be created with sensible values with a [`PagedAttentionMetaBuilder`].
     ///
@@ -304,18 +456,123 @@ impl TextModelBuilder {
         self
     }
 
+    /// Persist the prefix cache in `dir` across restarts. The caches are written when the model
+    /// is shut down with [`Model::drain`], and loaded by the next run of the same model. This is
+    /// ignored with PagedAttention.
+    pub fn with_prefix_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.prefix_cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Bound the KV cache of each sequence to `budget` tokens, evicting the tokens with the lowest
+    /// attention (H2O) to run long generations with less memory. This is ignored with PagedAttention
+    /// and flash attention.
+    pub fn with_h2o_cache(mut self, h2o_config: H2oConfig) -> Self {
+        self.h2o_config = Some(h2o_config);
+        self
+    }
+
+    /// Keep generating past the maximum length of the model, keeping only the attention sinks and
+    /// the most recent tokens of each sequence in its context (StreamingLLM). Prompts which are
+    /// too long are shortened the same way instead of being rejected.
+    pub fn with_attention_sinks(mut self, attention_sinks: AttentionSinkConfig) -> Self {
+        self.attention_sinks = Some(attention_sinks);
+        self
+    }
+
+    /// Bound the KV cache each sequence may hold, so a single long request cannot take the whole
+    /// KV cache from the others. Sequences which reach the quota are finished with the finish
+    /// reason `kv_quota`, or keep only their attention sinks and recent tokens with
+    /// [`KvQuotaAction::Evict`].
+    pub fn with_kv_quota(mut self, kv_quota: KvQuotaConfig) -> Self {
+        self.kv_quota = Some(kv_quota);
+        self
+    }
+
+    /// Apply a custom logits processor to every request of this model, before the processors
+    /// added with [`RequestBuilder::add_logits_processor`](crate::RequestBuilder::add_logits_processor).
+    pub fn with_logits_processor(mut self, processor: Arc<dyn CustomLogitsProcessor>) -> Self {
+        self.logits_processors.push(processor);
+        self
+    }
+
+    /// Make the output of each request depend only on the request, for reproducible evaluations.
+    /// See [`MistralRsBuilder::with_deterministic`].
+    pub fn with_deterministic(mut self) -> Self {
+        self.deterministic = true;
+        self
+    }
+
+    /// Process prompts in chunks of at most `prefill_chunk_size` tokens, running the completions
+    /// of other requests between the chunks. This is not supported with PagedAttention, the engine
+    /// fails to start if both are set.
+    pub fn with_prefill_chunk_size(mut self, prefill_chunk_size: usize) -> Self {
+        self.prefill_chunk_size = Some(prefill_chunk_size);
+        self
+    }
+
+    /// Run the prompts on a second copy of the model loaded onto `prefill_device`, moving the KV
+    /// cache of each sequence to the main device once its prompt is processed. The prompts and
+    /// completions run in turn, not concurrently, so this offloads the compute and memory of the
+    /// prompts without overlapping them with the completions. This is not supported with
+    /// PagedAttention or in-memory files.
+    pub fn with_prefill_device(mut self, prefill_device: Device) -> Self {
+        self.prefill_device = Some(prefill_device);
+        self
+    }
+
     /// Enable logging.
     pub fn with_logging(mut self) -> Self {
         self.with_logging = true;
         self
     }
 
+    /// Receive a [`LoadEvent`] for each step of downloading and loading the model, for example to
+    /// display a progress bar.
+    pub fn with_progress_callback(
+        mut self,
+        callback: impl Fn(LoadEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress_callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Cancel loading the model when `token` is cancelled, for example on a shutdown signal.
+    /// Loading then stops at the next file download, weight tensor or ISQ tensor, the memory
+    /// allocated so far is freed, and `build` returns an error.
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
     /// Provide metadata to initialize the device mapper.
     pub fn with_device_mapping(mut self, device_mapping: DeviceMapSetting) -> Self {
         self.device_mapping = Some(device_mapping);
         self
     }
 
+    /// Place the given number of repeating layers on each device, in order, for example
+    /// `[(Device::new_cuda(0)?, 20), (Device::new_cuda(1)?, 20)]`. Layers which are not given to
+    /// a device are placed on the CPU, after the GPU layers. Unless a main device is set with
+    /// `with_device`, the first GPU becomes the main device.
+    pub fn with_device_layers(
+        mut self,
+        device_layers: impl IntoIterator<Item = (Device, usize)>,
+    ) -> Self {
+        let device_layers = device_layers.into_iter().collect::<Vec<_>>();
+        if self.device.is_none() {
+            self.device = device_layers
+                .iter()
+                .map(|(device, _)| device)
+                .find(|device| !device.is_cpu())
+                .cloned();
+        }
+        self.device_mapping = Some(DeviceMapSetting::Map(
+            DeviceMapMetadata::from_device_layers(device_layers),
+        ));
+        self
+    }
+
     #[deprecated(
         note = "Use `UqffTextModelBuilder` to load a UQFF model instead of the generic `from_uqff`"
     )]
@@ -352,6 +609,14 @@ impl TextModelBuilder {
         self
     }
 
+    /// Reuse the weights of other pipelines of the same model (ID, revision and dtype) which were
+    /// loaded with this [`SharedWeights`], for example to load the model with two sets of adapters
+    /// while holding one copy of the base weights.
+    pub fn with_shared_weights(mut self, shared_weights: SharedWeights) -> Self {
+        self.shared_weights = Some(shared_weights);
+        self
+    }
+
     /// Set the main device to load this model onto. Automatic device mapping will be performed starting with this device.
     pub fn with_device(mut self, device: Device) -> Self {
         self.device = Some(device);
@@ -370,45 +635,130 @@ impl TextModelBuilder {
         self
     }
 
-    pub async fn build(self) -> anyhow::Result<Model> {
+    fn loader(&self) -> anyhow::Result<Box<dyn Loader>> {
         let config = NormalSpecificConfig {
-            topology: self.topology,
+            topology: self.topology.clone(),
             organization: self.organization,
-            write_uqff: self.write_uqff,
-            from_uqff: self.from_uqff,
-            imatrix: self.imatrix,
-            calibration_file: self.calibration_file,
-            hf_cache_path: self.hf_cache_path,
-            matformer_config_path: self.matformer_config_path,
-            matformer_slice_name: self.matformer_slice_name,
+            write_uqff: self.write_uqff.clone(),
+            from_uqff: self.from_uqff.clone(),
+            imatrix: self.imatrix.clone(),
+            calibration_file: self.calibration_file.clone(),
+            calibration_data: self.calibration_data.clone(),
+            mixed_precision_isq: self.mixed_precision_isq,
+            hf_cache_path: self.hf_cache_path.clone(),
+            matformer_config_path: self.matformer_config_path.clone(),
+            matformer_slice_name: self.matformer_slice_name.clone(),
+            isq_skip: self.isq_skip.clone(),
+            sliding_window: self.sliding_window,
         };
 
+        let mut loader = NormalLoaderBuilder::new(
+            config,
+            self.chat_template.clone(),
+            self.tokenizer_json.clone(),
+            Some(self.model_id.clone()),
+            self.no_kv_cache,
+            self.jinja_explicit.clone(),
+        );
+        if let Some(shared_weights) = self.shared_weights.clone() {
+            loader = loader.with_shared_weights(shared_weights);
+        }
+        loader.build(self.loader_type.clone())
+    }
+
+    /// Project the VRAM/RAM used on each device by `build` with the current settings (dtype, ISQ,
+    /// device mapping and PagedAttention), without loading the weights. Only the model config is
+    /// downloaded, along with the UQFF files when loading from UQFF.
+    pub fn estimate_memory(&self) -> anyhow::Result<MemoryEstimate> {
+        if self.in_memory_files.is_some() {
+            anyhow::bail!(
+                "Memory estimation is not supported for models loaded from in-memory files."
+            );
+        }
         if self.with_logging {
             initialize_logging();
         }
 
-        let loader = NormalLoaderBuilder::new(
-            config,
-            self.chat_template,
-            self.tokenizer_json,
-            Some(self.model_id),
-            self.no_kv_cache,
-            self.jinja_explicit,
-        )
-        .build(self.loader_type)?;
-
-        // Load, into a Pipeline
-        let pipeline = loader.load_model_from_hf(
-            self.hf_revision,
-            self.token_source,
+        self.loader()?.estimate_memory_from_hf(
+            self.hf_revision.clone(),
+            self.token_source.clone(),
             &self.dtype,
-            &self.device.unwrap_or(best_device(self.force_cpu).unwrap()),
+            &self.device.clone().unwrap_or(best_device(self.force_cpu)?),
             !self.with_logging,
             self.device_mapping
+                .clone()
                 .unwrap_or(DeviceMapSetting::Auto(AutoDeviceMapParams::default_text())),
             self.isq,
             self.paged_attn_cfg,
-        )?;
+        )
+    }
+
+    pub async fn build(self) -> anyhow::Result<Model> {
+        if self.with_logging {
+            initialize_logging();
+        }
+
+        let loader = self.loader()?;
+
+        if self.prefill_device.is_some()
+            && (self.paged_attn_cfg.is_some() || self.in_memory_files.is_some())
+        {
+            anyhow::bail!(
+                "A prefill device is not supported with PagedAttention or in-memory files."
+            );
+        }
+        let prefill_source = (self.hf_revision.clone(), self.token_source.clone());
+
+        // Load, into a Pipeline
+        let device = self.device.unwrap_or(best_device(self.force_cpu).unwrap());
+        let mapper = self
+            .device_mapping
+            .unwrap_or(DeviceMapSetting::Auto(AutoDeviceMapParams::default_text()));
+        let pipeline =
+            with_load_hooks(
+                self.progress_callback,
+                self.cancellation_token,
+                || match self.in_memory_files {
+                    Some(files) => {
+                        let paths: Box<dyn ModelPaths> = Box::new(InMemoryModelPaths::new(files)?);
+                        loader.load_model_from_path(
+                            &paths,
+                            &self.dtype,
+                            &device,
+                            !self.with_logging,
+                            mapper,
+                            self.isq,
+                            self.paged_attn_cfg,
+                        )
+                    }
+                    None => loader.load_model_from_hf(
+                        self.hf_revision,
+                        self.token_source,
+                        &self.dtype,
+                        &device,
+                        !self.with_logging,
+                        mapper,
+                        self.isq,
+                        self.paged_attn_cfg,
+                    ),
+                },
+            )?;
+        let prefill_pipeline = match &self.prefill_device {
+            Some(prefill_device) => {
+                let (revision, token_source) = prefill_source;
+                Some(loader.load_model_from_hf(
+                    revision,
+                    token_source,
+                    &self.dtype,
+                    prefill_device,
+                    !self.with_logging,
+                    DeviceMapSetting::Auto(AutoDeviceMapParams::default_text()),
+                    self.isq,
+                    None,
+                )?)
+            }
+            None => None,
+        };
 
         let scheduler_method = match self.paged_attn_cfg {
             Some(_) => {
@@ -465,6 +815,28 @@ impl TextModelBuilder {
         if let Some(n) = self.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(prefix_cache_dir) = self.prefix_cache_dir {
+            runner = runner.with_prefix_cache_dir(prefix_cache_dir);
+        }
+        if let Some(h2o_config) = self.h2o_config {
+            runner = runner.with_h2o_cache(h2o_config);
+        }
+        if let Some(attention_sinks) = self.attention_sinks {
+            runner = runner.with_attention_sinks(attention_sinks);
+        }
+        if let Some(kv_quota) = self.kv_quota {
+            runner = runner.with_kv_quota(kv_quota);
+        }
+        for processor in self.logits_processors {
+            runner = runner.with_logits_processor(processor);
+        }
+        runner = runner.with_deterministic(self.deterministic);
+        if let Some(prefill_chunk_size) = self.prefill_chunk_size {
+            runner = runner.with_prefill_chunk_size(prefill_chunk_size);
+        }
+        if let Some(prefill_pipeline) = prefill_pipeline {
+            runner = runner.with_prefill_pipeline(prefill_pipeline);
+        }
 
         Ok(Model::new(runner.build().await))
     }
@@ -8,7 +8,7 @@ use std::{
     sync::Arc,
 };
 
-use crate::{best_device, Model};
+use crate::{best_device, model::with_load_hooks, Model};
 
 /// A tool callback with its associated Tool definition.
 #[derive(Clone)]
@@ -27,6 +27,8 @@ pub struct VisionModelBuilder {
     pub(crate) write_uqff: Option<PathBuf>,
     pub(crate) from_uqff: Option<Vec<PathBuf>>,
     pub(crate) calibration_file: Option<PathBuf>,
+    pub(crate) calibration_data: Option<CalibrationData>,
+    pub(crate) mixed_precision_isq: Option<MixedPrecisionIsq>,
     pub(crate) imatrix: Option<PathBuf>,
     pub(crate) chat_template: Option<String>,
     pub(crate) jinja_explicit: Option<String>,
@@ -34,6 +36,7 @@ pub struct VisionModelBuilder {
     pub(crate) device_mapping: Option<DeviceMapSetting>,
     pub(crate) max_edge: Option<u32>,
     pub(crate) hf_cache_path: Option<PathBuf>,
+    pub(crate) shared_weights: Option<SharedWeights>,
     pub(crate) search_bert_model: Option<BertEmbeddingModel>,
     pub(crate) search_callback: Option<Arc<SearchCallback>>,
     pub(crate) tool_callbacks: HashMap<String, Arc<ToolCallback>>,
@@ -41,6 +44,9 @@ pub struct VisionModelBuilder {
     pub(crate) device: Option<Device>,
     pub(crate) matformer_config_path: Option<PathBuf>,
     pub(crate) matformer_slice_name: Option<String>,
+    pub(crate) isq_skip: Vec<String>,
+    pub(crate) sliding_window: Option<SlidingWindowOverride>,
+    pub(crate) lora_adapter_ids: Option<Vec<String>>,
 
     // Model running
     pub(crate) topology: Option<Topology>,
@@ -54,6 +60,8 @@ pub struct VisionModelBuilder {
     pub(crate) paged_attn_cfg: Option<PagedAttentionConfig>,
     pub(crate) max_num_seqs: usize,
     pub(crate) with_logging: bool,
+    pub(crate) progress_callback: Option<LoadProgressCallback>,
+    pub(crate) cancellation_token: Option<CancellationToken>,
     pub(crate) prefix_cache_n: Option<usize>,
 }
 
@@ -80,13 +88,18 @@ impl VisionModelBuilder {
             isq: None,
             max_num_seqs: 32,
             with_logging: false,
+            progress_callback: None,
+            cancellation_token: None,
             device_mapping: None,
             calibration_file: None,
+            calibration_data: None,
+            mixed_precision_isq: None,
             imatrix: None,
             jinja_explicit: None,
             throughput_logging: false,
             paged_attn_cfg: None,
             hf_cache_path: None,
+            shared_weights: None,
             search_bert_model: None,
             search_callback: None,
             tool_callbacks: HashMap::new(),
@@ -94,6 +107,9 @@ impl VisionModelBuilder {
             device: None,
             matformer_config_path: None,
             matformer_slice_name: None,
+            isq_skip: Vec::new(),
+            sliding_window: None,
+            lora_adapter_ids: None,
             prefix_cache_n: None,
         }
     }
@@ -200,12 +216,59 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Keep the layers whose tensor names match the regex `pattern` (such as `^lm_head` or
+    /// `vision_tower`) at full precision when applying ISQ. This may be called several times to
+    /// skip several patterns.
+    ///
+    /// Skipped layers are matched while the weights are loaded, so this cannot be combined with an
+    /// imatrix, a calibration file or writing UQFF. An `lm_head` which is tied to the embeddings is
+    /// always quantized.
+    pub fn with_isq_skip(mut self, pattern: impl ToString) -> Self {
+        self.isq_skip.push(pattern.to_string());
+        self
+    }
+
+    /// Override the sliding window attention of the model config: disable it to use full
+    /// attention, or set the window size. Models without a sliding window ignore this.
+    pub fn with_sliding_window(mut self, sliding_window: SlidingWindowOverride) -> Self {
+        self.sliding_window = Some(sliding_window);
+        self
+    }
+
     /// Utilise this calibration_file file during ISQ
     pub fn with_calibration_file(mut self, path: PathBuf) -> Self {
         self.calibration_file = Some(path);
         self
     }
 
+    /// Collect an imatrix from these calibration texts instead of a calibration file, for example
+    /// samples of the requests a service receives. The texts are tokenized without special tokens.
+    pub fn with_calibration_texts(
+        mut self,
+        texts: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.calibration_data = Some(CalibrationData::Texts(
+            texts.into_iter().map(Into::into).collect(),
+        ));
+        self
+    }
+
+    /// Collect an imatrix from these sequences of token IDs instead of a calibration file. The
+    /// sequences should not start with BOS, which is added to each chunk of calibration tokens.
+    pub fn with_calibration_tokens(mut self, seqs: impl IntoIterator<Item = Vec<u32>>) -> Self {
+        self.calibration_data = Some(CalibrationData::Tokens(seqs.into_iter().collect()));
+        self
+    }
+
+    /// Measure the sensitivity of each layer to quantization on the calibration data, and
+    /// quantize the most sensitive ones to `high` instead of the ISQ type, as long as the
+    /// quantized layers fit in `budget_bytes`. This requires a calibration file, calibration data
+    /// or an imatrix.
+    pub fn with_mixed_precision_isq(mut self, high: IsqType, budget_bytes: usize) -> Self {
+        self.mixed_precision_isq = Some(MixedPrecisionIsq { high, budget_bytes });
+        self
+    }
+
     /// Enable PagedAttention. Configure PagedAttention with a [`PagedAttentionConfig`] object, which
     /// can be created with sensible values with a [`PagedAttentionMetaBuilder`](crate::PagedAttentionMetaBuilder).
     ///
@@ -240,12 +303,52 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Receive a [`LoadEvent`] for each step of downloading and loading the model, for example to
+    /// display a progress bar.
+    pub fn with_progress_callback(
+        mut self,
+        callback: impl Fn(LoadEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress_callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Cancel loading the model when `token` is cancelled, for example on a shutdown signal.
+    /// Loading then stops at the next file download, weight tensor or ISQ tensor, the memory
+    /// allocated so far is freed, and `build` returns an error.
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
     /// Provide metadata to initialize the device mapper.
     pub fn with_device_mapping(mut self, device_mapping: DeviceMapSetting) -> Self {
         self.device_mapping = Some(device_mapping);
         self
     }
 
+    /// Place the given number of repeating layers on each device, in order, for example
+    /// `[(Device::new_cuda(0)?, 20), (Device::new_cuda(1)?, 20)]`. Layers which are not given to
+    /// a device are placed on the CPU, after the GPU layers. Unless a main device is set with
+    /// `with_device`, the first GPU becomes the main device.
+    pub fn with_device_layers(
+        mut self,
+        device_layers: impl IntoIterator<Item = (Device, usize)>,
+    ) -> Self {
+        let device_layers = device_layers.into_iter().collect::<Vec<_>>();
+        if self.device.is_none() {
+            self.device = device_layers
+                .iter()
+                .map(|(device, _)| device)
+                .find(|device| !device.is_cpu())
+                .cloned();
+        }
+        self.device_mapping = Some(DeviceMapSetting::Map(
+            DeviceMapMetadata::from_device_layers(device_layers),
+        ));
+        self
+    }
+
     #[deprecated(
         note = "Use `UqffTextModelBuilder` to load a UQFF model instead of the generic `from_uqff`"
     )]
@@ -289,6 +392,14 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Reuse the weights of other pipelines of the same model (ID, revision and dtype) which were
+    /// loaded with this [`SharedWeights`], for example to load the model with two sets of adapters
+    /// while holding one copy of the base weights.
+    pub fn with_shared_weights(mut self, shared_weights: SharedWeights) -> Self {
+        self.shared_weights = Some(shared_weights);
+        self
+    }
+
     /// Set the main device to load this model onto. Automatic device mapping will be performed starting with this device.
     pub fn with_device(mut self, device: Device) -> Self {
         self.device = Some(device);
@@ -307,44 +418,99 @@ impl VisionModelBuilder {
         self
     }
 
-    pub async fn build(self) -> anyhow::Result<Model> {
+    /// Merge LoRA adapters into the model when loading it. Each adapter ID is either a Hugging
+    /// Face model ID or a local directory containing `adapter_config.json` and
+    /// `adapter_model.safetensors`.
+    ///
+    /// The adapters may target modules of both the language model and the vision tower (for
+    /// example the `qkv` and `proj` layers of the vision encoder), matched against the full
+    /// module names. LoRA adapters cannot be combined with [`Self::from_uqff`].
+    pub fn with_lora(mut self, lora_adapter_ids: impl IntoIterator<Item = impl ToString>) -> Self {
+        self.lora_adapter_ids = Some(
+            lora_adapter_ids
+                .into_iter()
+                .map(|x| x.to_string())
+                .collect(),
+        );
+        self
+    }
+
+    fn loader(&self) -> Box<dyn Loader> {
         let config = VisionSpecificConfig {
-            topology: self.topology,
-            write_uqff: self.write_uqff,
-            from_uqff: self.from_uqff,
+            topology: self.topology.clone(),
+            write_uqff: self.write_uqff.clone(),
+            from_uqff: self.from_uqff.clone(),
             max_edge: self.max_edge,
-            calibration_file: self.calibration_file,
-            imatrix: self.imatrix,
-            hf_cache_path: self.hf_cache_path,
-            matformer_config_path: self.matformer_config_path,
-            matformer_slice_name: self.matformer_slice_name,
+            calibration_file: self.calibration_file.clone(),
+            calibration_data: self.calibration_data.clone(),
+            mixed_precision_isq: self.mixed_precision_isq,
+            imatrix: self.imatrix.clone(),
+            hf_cache_path: self.hf_cache_path.clone(),
+            matformer_config_path: self.matformer_config_path.clone(),
+            matformer_slice_name: self.matformer_slice_name.clone(),
+            isq_skip: self.isq_skip.clone(),
+            sliding_window: self.sliding_window,
         };
 
+        let mut loader = VisionLoaderBuilder::new(
+            config,
+            self.chat_template.clone(),
+            self.tokenizer_json.clone(),
+            Some(self.model_id.clone()),
+            self.jinja_explicit.clone(),
+        );
+        if let Some(lora_adapter_ids) = self.lora_adapter_ids.clone() {
+            loader = loader.with_lora(lora_adapter_ids);
+        }
+        if let Some(shared_weights) = self.shared_weights.clone() {
+            loader = loader.with_shared_weights(shared_weights);
+        }
+        loader.build(self.loader_type.clone())
+    }
+
+    /// Project the VRAM/RAM used on each device by `build` with the current settings (dtype, ISQ,
+    /// device mapping and PagedAttention), without loading the weights. Only the model config is
+    /// downloaded, along with the UQFF files when loading from UQFF.
+    pub fn estimate_memory(&self) -> anyhow::Result<MemoryEstimate> {
         if self.with_logging {
             initialize_logging();
         }
 
-        let loader = VisionLoaderBuilder::new(
-            config,
-            self.chat_template,
-            self.tokenizer_json,
-            Some(self.model_id),
-            self.jinja_explicit,
-        )
-        .build(self.loader_type);
-
-        // Load, into a Pipeline
-        let pipeline = loader.load_model_from_hf(
-            self.hf_revision,
-            self.token_source,
+        self.loader().estimate_memory_from_hf(
+            self.hf_revision.clone(),
+            self.token_source.clone(),
             &self.dtype,
-            &self.device.unwrap_or(best_device(self.force_cpu).unwrap()),
+            &self.device.clone().unwrap_or(best_device(self.force_cpu)?),
             !self.with_logging,
             self.device_mapping
+                .clone()
                 .unwrap_or(DeviceMapSetting::Auto(AutoDeviceMapParams::default_vision())),
             self.isq,
             self.paged_attn_cfg,
-        )?;
+        )
+    }
+
+    pub async fn build(self) -> anyhow::Result<Model> {
+        if self.with_logging {
+            initialize_logging();
+        }
+
+        let loader = self.loader();
+
+        // Load, into a Pipeline
+        let pipeline = with_load_hooks(self.progress_callback, self.cancellation_token, || {
+            loader.load_model_from_hf(
+                self.hf_revision,
+                self.token_source,
+                &self.dtype,
+                &self.device.unwrap_or(best_device(self.force_cpu).unwrap()),
+                !self.with_logging,
+                self.device_mapping
+                    .unwrap_or(DeviceMapSetting::Auto(AutoDeviceMapParams::default_vision())),
+                self.isq,
+                self.paged_attn_cfg,
+            )
+        })?;
 
         let scheduler_method = match self.paged_attn_cfg {
             Some(_) => {
@@ -1,3 +1,9 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
 use mistralrs_core::*;
 
 use crate::{best_device, Model, TextModelBuilder};
@@ -8,9 +14,12 @@ pub struct XLoraModelBuilder {
     xlora_model_id: String,
     ordering: Ordering,
     tgt_non_granular_index: Option<usize>,
+    classifier_path: Option<PathBuf>,
 }
 
 impl XLoraModelBuilder {
+    /// The X-LoRA model contains the adapters named in the `ordering`, the X-LoRA config and the
+    /// classifier.
     pub fn from_text_model_builder(
         text_model: TextModelBuilder,
         xlora_model_id: impl ToString,
@@ -21,9 +30,41 @@ impl XLoraModelBuilder {
             xlora_model_id: xlora_model_id.to_string(),
             ordering,
             tgt_non_granular_index: None,
+            classifier_path: None,
         }
     }
 
+    /// Like [`XLoraModelBuilder::from_text_model_builder`], but reads the ordering from a JSON
+    /// ordering file.
+    pub fn from_ordering_file(
+        text_model: TextModelBuilder,
+        xlora_model_id: impl ToString,
+        ordering_file: impl AsRef<Path>,
+    ) -> anyhow::Result<Self> {
+        let ordering_file = ordering_file.as_ref();
+        let file = File::open(ordering_file).with_context(|| {
+            format!(
+                "Could not open ordering file at {}",
+                ordering_file.display()
+            )
+        })?;
+        let ordering = serde_json::from_reader(file)?;
+        Ok(Self::from_text_model_builder(
+            text_model,
+            xlora_model_id,
+            ordering,
+        ))
+    }
+
+    /// Load the X-LoRA classifier from this file instead of the `xlora_classifier.safetensors` of
+    /// the X-LoRA model.
+    pub fn with_classifier_path(mut self, classifier_path: impl Into<PathBuf>) -> Self {
+        self.classifier_path = Some(classifier_path.into());
+        self
+    }
+
+    /// Only run the X-LoRA classifier for the first `tgt_non_granular_idx` completion tokens, then
+    /// reuse its scalings.
     pub fn tgt_non_granular_index(mut self, tgt_non_granular_idx: usize) -> Self {
         self.tgt_non_granular_index = Some(tgt_non_granular_idx);
         self
@@ -37,16 +78,20 @@ impl XLoraModelBuilder {
             from_uqff: self.text_model.from_uqff,
             imatrix: None,
             calibration_file: None,
+            calibration_data: self.text_model.calibration_data,
+            mixed_precision_isq: None,
             hf_cache_path: self.text_model.hf_cache_path,
             matformer_config_path: None,
             matformer_slice_name: None,
+            isq_skip: self.text_model.isq_skip,
+            sliding_window: self.text_model.sliding_window,
         };
 
         if self.text_model.with_logging {
             initialize_logging();
         }
 
-        let loader = NormalLoaderBuilder::new(
+        let mut loader = NormalLoaderBuilder::new(
             config,
             self.text_model.chat_template,
             self.text_model.tokenizer_json,
@@ -59,8 +104,11 @@ impl XLoraModelBuilder {
             self.ordering,
             self.text_model.no_kv_cache,
             self.tgt_non_granular_index,
-        )
-        .build(self.text_model.loader_type)?;
+        );
+        if let Some(classifier_path) = self.classifier_path {
+            loader = loader.with_xlora_classifier_path(classifier_path);
+        }
+        let loader = loader.build(self.text_model.loader_type)?;
 
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
@@ -84,12 +132,19 @@ impl XLoraModelBuilder {
                     .get_metadata()
                     .cache_config
                     .as_ref()
-                    .unwrap()
-                    .clone();
+                    .cloned();
 
-                SchedulerConfig::PagedAttentionMeta {
-                    max_num_seqs: self.text_model.max_num_seqs,
-                    config,
+                if let Some(config) = config {
+                    SchedulerConfig::PagedAttentionMeta {
+                        max_num_seqs: self.text_model.max_num_seqs,
+                        config,
+                    }
+                } else {
+                    SchedulerConfig::DefaultScheduler {
+                        method: DefaultSchedulerMethod::Fixed(
+                            self.text_model.max_num_seqs.try_into()?,
+                        ),
+                    }
                 }
             }
             None => SchedulerConfig::DefaultScheduler {